@@ -0,0 +1,27 @@
+#!/bin/sh
+# 把内核的函数符号表写进预留的 .ksyms 段，panic 时回溯用它把地址翻译成函数名
+# 段的大小（config.rs 的 KSYMS_SIZE）不变，写入后其余地址都不受影响，不需要重新链接
+# 用法：ksyms.sh <kernel elf>
+set -e
+ELF=$1
+TABLE=$ELF.ksyms
+SIZE=$(rust-objdump -h "$ELF" | awk '$2 == ".ksyms" { print $3 }')
+if [ -z "$SIZE" ]; then
+	echo "ksyms.sh: no .ksyms section in $ELF" >&2
+	exit 1
+fi
+SIZE=$((0x$SIZE))
+# 第一行是标记，之后每行 "地址 函数名"，按地址升序；去掉 rustc 加在名字后面的哈希
+{
+	echo ksyms
+	rust-nm --defined-only --demangle --numeric-sort "$ELF" |
+		awk '$2 ~ /^[tT]$/ { addr = $1; $1 = $2 = ""; sub(/^ +/, ""); print addr, $0 }' |
+		sed 's/::h[0-9a-f]\{16\}$//'
+} > "$TABLE"
+# 至少留一个 NUL 作为表的结尾
+if [ "$(wc -c < "$TABLE")" -ge "$SIZE" ]; then
+	echo "ksyms.sh: symbol table does not fit in .ksyms, enlarge KSYMS_SIZE" >&2
+	exit 1
+fi
+truncate -s "$SIZE" "$TABLE"
+rust-objcopy --update-section .ksyms="$TABLE" "$ELF"
@@ -22,8 +22,22 @@ pub trait Jbd2: Send + Sync + Any + Debug {
 }
 
 pub trait BlockDevice: Send + Sync + Any {
-    fn read_offset(&self, offset: usize) -> Vec<u8>;
-    fn write_offset(&self, offset: usize, data: &[u8]);
+    /// Read the block at `offset`, EIO if the device failed
+    fn try_read_offset(&self, offset: usize) -> Result<Vec<u8>>;
+    /// Write `data` at `offset`, EIO if the device failed
+    fn try_write_offset(&self, offset: usize, data: &[u8]) -> Result<()>;
+    /// [`Self::try_read_offset`] for the metadata paths, which have no way to report
+    /// an error: a failed read returns zeroes
+    fn read_offset(&self, offset: usize) -> Vec<u8> {
+        self.try_read_offset(offset)
+            .unwrap_or_else(|_| vec![0u8; BLOCK_SIZE])
+    }
+    /// [`Self::try_write_offset`] for the metadata paths, a failed write is dropped
+    fn write_offset(&self, offset: usize, data: &[u8]) {
+        let _ = self.try_write_offset(offset, data);
+    }
+    /// Make the writes so far durable, for devices with a volatile write cache
+    fn flush(&self) {}
 }
 
 // impl dyn BlockDevice {
@@ -69,7 +69,7 @@ enum ColorCode {
 pub struct Disk {}
 
 impl BlockDevice for Disk {
-    fn read_offset(&self, offset: usize) -> Vec<u8> {
+    fn try_read_offset(&self, offset: usize) -> Result<Vec<u8>> {
         // log::info!("read_offset: {:x?}", offset);
         use std::fs::OpenOptions;
         use std::io::{Read, Seek};
@@ -82,10 +82,10 @@ impl BlockDevice for Disk {
         let _r = file.seek(std::io::SeekFrom::Start(offset as u64));
         let _r = file.read_exact(&mut buf);
 
-        buf
+        Ok(buf)
     }
 
-    fn write_offset(&self, offset: usize, data: &[u8]) {
+    fn try_write_offset(&self, offset: usize, data: &[u8]) -> Result<()> {
         use std::fs::OpenOptions;
         use std::io::{Seek, Write};
         let mut file = OpenOptions::new()
@@ -96,6 +96,7 @@ impl BlockDevice for Disk {
 
         let _r = file.seek(std::io::SeekFrom::Start(offset as u64));
         let _r = file.write_all(&data);
+        Ok(())
     }
 }
 
@@ -28,6 +28,7 @@ pub enum Cmd {
     SdSendOpCond,
     SetClrCardDetect,
     SendScr,
+    SwitchFunc,
     // Private
     ResetClock,
 }
@@ -62,6 +63,7 @@ impl Into<u8> for Cmd {
             Cmd::SdSendOpCond => 41,
             Cmd::SetClrCardDetect => 42,
             Cmd::SendScr => 51,
+            Cmd::SwitchFunc => 6,
             _ => {
                 panic!("Not implemented for cmd {:?}", self);
             }
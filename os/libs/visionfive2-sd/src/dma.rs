@@ -0,0 +1,155 @@
+//! Internal DMAC (IDMAC) transfers
+//!
+//! Data blocks are described by a chain of descriptors in 64-bit address mode, every
+//! descriptor covers a physically contiguous piece of the buffer (at most one page).
+//! When the buffer cannot be described (no address translation, misaligned, too many pieces)
+//! the caller falls back to PIO through the FIFO.
+use crate::register::*;
+use crate::utils::*;
+use core::mem::size_of;
+
+/// descriptors in the chain, enough for 16 pages
+pub const IDMAC_DESC_NUM: usize = 16;
+/// a piece never crosses a page
+const PAGE_SIZE: usize = 4096;
+
+/// des0 bits
+const DESC_OWN: u32 = 1 << 31;
+const DESC_CH: u32 = 1 << 4;
+const DESC_FS: u32 = 1 << 3;
+const DESC_LD: u32 = 1 << 2;
+const DESC_DIC: u32 = 1 << 1;
+
+/// Descriptor in 64-bit address mode
+#[repr(C, align(32))]
+#[derive(Debug, Copy, Clone, Default)]
+struct IdmacDesc {
+    flags: u32,
+    reserved0: u32,
+    /// buffer 1 size in bytes, bits 12:0
+    size: u32,
+    reserved1: u32,
+    buf_lo: u32,
+    buf_hi: u32,
+    next_lo: u32,
+    next_hi: u32,
+}
+
+pub struct Idmac {
+    desc: [IdmacDesc; IDMAC_DESC_NUM],
+}
+
+impl Idmac {
+    pub const fn new() -> Self {
+        const EMPTY: IdmacDesc = IdmacDesc {
+            flags: 0,
+            reserved0: 0,
+            size: 0,
+            reserved1: 0,
+            buf_lo: 0,
+            buf_hi: 0,
+            next_lo: 0,
+            next_hi: 0,
+        };
+        Self {
+            desc: [EMPTY; IDMAC_DESC_NUM],
+        }
+    }
+
+    /// Build the chain for `[vaddr, vaddr + len)`, returns the physical address of the first
+    /// descriptor, or `None` if the transfer has to use PIO
+    pub fn prepare<T: SDIo>(&mut self, io: &T, vaddr: usize, len: usize) -> Option<usize> {
+        // the bus is 64 bits wide
+        if vaddr % size_of::<u64>() != 0 || len % size_of::<u64>() != 0 || len == 0 {
+            return None;
+        }
+        let desc_base = self.desc.as_ptr() as usize;
+        let desc_phys = |i: usize| io.virt_to_phys(desc_base + i * size_of::<IdmacDesc>());
+        let mut offset = 0;
+        let mut count = 0;
+        while offset < len {
+            if count == IDMAC_DESC_NUM {
+                return None;
+            }
+            let start = vaddr + offset;
+            let size = (PAGE_SIZE - start % PAGE_SIZE).min(len - offset);
+            let phys = io.virt_to_phys(start)?;
+            self.desc[count] = IdmacDesc {
+                flags: DESC_OWN | DESC_CH | DESC_DIC,
+                size: size as u32,
+                buf_lo: phys as u32,
+                buf_hi: (phys >> 32) as u32,
+                ..Default::default()
+            };
+            offset += size;
+            count += 1;
+        }
+        for i in 0..count - 1 {
+            let next = desc_phys(i + 1)?;
+            self.desc[i].next_lo = next as u32;
+            self.desc[i].next_hi = (next >> 32) as u32;
+        }
+        self.desc[0].flags |= DESC_FS;
+        // only the last descriptor raises RI/TI
+        self.desc[count - 1].flags |= DESC_LD;
+        self.desc[count - 1].flags &= !DESC_DIC;
+        io.dma_sync(desc_base, count * size_of::<IdmacDesc>());
+        io.dma_sync(vaddr, len);
+        desc_phys(0)
+    }
+}
+
+/// Hand the chain at `desc_phys` to the IDMAC, before sending the data command
+pub fn start<T: SDIo>(io: &mut T, desc_phys: usize) {
+    write_reg(io, DBADDRL_REG, desc_phys as u32);
+    write_reg(io, DBADDRU_REG, (desc_phys >> 32) as u32);
+    // clear the old status and enable the completion interrupts
+    write_reg(io, IDSTS_REG, u32::MAX);
+    let inten = IdmacInterrupt::new()
+        .with_ti(true)
+        .with_ri(true)
+        .with_nis(true)
+        .with_ais(true)
+        .with_fbe(true)
+        .with_du(true)
+        .with_ces(true);
+    write_reg(io, IDINTEN_REG, inten.into());
+    let ctrl = ControlReg::from(read_reg(io, CTRL_REG))
+        .with_use_internal_dmac(true)
+        .with_dma_enable(true);
+    write_reg(io, CTRL_REG, ctrl.into());
+    let bus_mode = BusModeReg::from(read_reg(io, BUS_MODE_REG))
+        .with_de(true)
+        .with_fd(true);
+    write_reg(io, BUS_MODE_REG, bus_mode.into());
+    // any value makes the IDMAC fetch the first descriptor
+    write_reg(io, PLDMND_REG, 1);
+}
+
+/// Whether the IDMAC has finished (`Some(true)`) or failed (`Some(false)`)
+pub fn poll<T: SDIo>(io: &T) -> Option<bool> {
+    let status = IdmacInterrupt::from(read_reg(io, IDSTS_REG));
+    if status.ais() || status.fbe() || status.du() || status.ces() {
+        Some(false)
+    } else if status.ri() || status.ti() {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// Go back to PIO mode
+pub fn stop<T: SDIo>(io: &mut T) {
+    let status = read_reg(io, IDSTS_REG);
+    write_reg(io, IDSTS_REG, status);
+    write_reg(io, IDINTEN_REG, 0);
+    let ctrl = ControlReg::from(read_reg(io, CTRL_REG))
+        .with_use_internal_dmac(false)
+        .with_dma_enable(false)
+        .with_dma_reset(true);
+    write_reg(io, CTRL_REG, ctrl.into());
+    let bus_mode = BusModeReg::from(read_reg(io, BUS_MODE_REG))
+        .with_de(false)
+        .with_fd(false);
+    write_reg(io, BUS_MODE_REG, bus_mode.into());
+}
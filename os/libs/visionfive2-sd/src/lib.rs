@@ -3,16 +3,18 @@
 extern crate alloc;
 
 use crate::cmd::*;
+use crate::dma::Idmac;
 use crate::register::*;
 use crate::utils::*;
 use core::fmt::{Display, Formatter};
 use core::mem::size_of;
 use log::*;
-use preprint::pprintln;
+use preprint::{pdebug, pinfo, pwarn};
 
 pub use utils::{SDIo, SleepOps};
 
 mod cmd;
+mod dma;
 mod register;
 mod utils;
 
@@ -20,6 +22,8 @@ enum DataTransType<'a> {
     None,
     Read(&'a mut [u8]),
     Write(&'a [u8]),
+    /// `len` bytes moved by the IDMAC
+    Dma(usize),
 }
 
 fn wait_ms_util_can_send_cmd<T: SDIo, S: SleepOps>(io: &mut T) -> bool {
@@ -56,18 +60,25 @@ fn fifo_filled_cnt<T: SDIo>(io: &mut T) -> usize {
     status.fifo_count() as usize
 }
 
-fn send_cmd<T: SDIo, S: SleepOps>(
+/// Time allowed for the data phase of a transfer, 250ms per block
+fn data_timeout_ms(len: usize) -> usize {
+    250 * (len / BLOCK_SIZE).max(1)
+}
+
+/// Write a command and wait for its response, the data phase (if any) is left running
+fn issue_cmd<T: SDIo, S: SleepOps>(
     io: &mut T,
     cmd_type: Cmd,
     cmd: CmdReg,
     arg: CmdArg,
-    data_trans_type: DataTransType,
-) -> Option<[u32; 4]> {
-    let res = wait_ms_util_can_send_cmd::<_, S>(io);
-    assert!(res);
-    if cmd.data_expected() {
-        let res = wait_ms_util_can_send_data::<_, S>(io);
-        assert!(res)
+) -> Result<()> {
+    if !wait_ms_util_can_send_cmd::<_, S>(io) {
+        error!("controller busy before {:?}", cmd_type);
+        return Err(Vf2SdDriverError::TimeoutError);
+    }
+    if cmd.data_expected() && !wait_ms_util_can_send_data::<_, S>(io) {
+        error!("card busy before {:?}", cmd_type);
+        return Err(Vf2SdDriverError::TimeoutError);
     }
     // info!("send cmd type:{:?}, value:{:#?}", cmd_type, cmd);
     // write arg
@@ -82,13 +93,26 @@ fn send_cmd<T: SDIo, S: SleepOps>(
         // debug!("wait_ms_util_response:{:?}", res);
     }
 
+    Ok(())
+}
+
+fn send_cmd<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    cmd_type: Cmd,
+    cmd: CmdReg,
+    arg: CmdArg,
+    data_trans_type: DataTransType,
+) -> Result<[u32; 4]> {
+    issue_cmd::<_, S>(io, cmd_type, cmd, arg)?;
+
+    let mut dma_failed = false;
     if cmd.data_expected() {
         let mut fifo_addr = FIFO_DATA_REG;
         match data_trans_type {
             DataTransType::Read(buffer) => {
                 // trace!("data_expected read....");
                 let mut buf_offset = 0;
-                S::sleep_ms_until(250, || {
+                S::sleep_ms_until(data_timeout_ms(buffer.len()), || {
                     let raw_int_status_reg =
                         RawInterruptStatusReg::from(read_reg(io, RAW_INT_STATUS_REG));
                     let int = raw_int_status_reg.int_status();
@@ -114,7 +138,7 @@ fn send_cmd<T: SDIo, S: SleepOps>(
             }
             DataTransType::Write(buffer) => {
                 let mut buf_offset = 0;
-                S::sleep_ms_until(250, || {
+                S::sleep_ms_until(data_timeout_ms(buffer.len()), || {
                     let raw_int_status = read_reg(io, RAW_INT_STATUS_REG);
                     let mut raw_int_status = RawInterrupt::from(raw_int_status as u16);
                     if raw_int_status.txdr() {
@@ -134,12 +158,36 @@ fn send_cmd<T: SDIo, S: SleepOps>(
                 });
                 // info!("buf_offset:{}, send {} bytes", buf_offset, buf_offset * 8);
             }
+            DataTransType::Dma(len) => {
+                let mut dma_status = None;
+                S::sleep_ms_until(data_timeout_ms(len), || {
+                    let raw_int_status_reg =
+                        RawInterruptStatusReg::from(read_reg(io, RAW_INT_STATUS_REG));
+                    let mut raw_int_status = RawInterrupt::from(raw_int_status_reg.int_status());
+                    dma_status = dma::poll(io);
+                    raw_int_status.have_error()
+                        || dma_status == Some(false)
+                        || raw_int_status.dto() && dma_status.is_some()
+                });
+                if dma_status != Some(true) {
+                    error!(
+                        "idmac transfer failed {:#?}",
+                        IdmacInterrupt::from(read_reg(io, IDSTS_REG))
+                    );
+                    dma_failed = true;
+                }
+            }
             _ => {
                 panic!("Not implemented")
             }
         }
         // debug!("Current FIFO count: {}", fifo_filled_cnt(io));
     }
+    finish_cmd(io, cmd, dma_failed)
+}
+
+/// Acknowledge the interrupts of a finished command and check them for errors
+fn finish_cmd<T: SDIo>(io: &mut T, cmd: CmdReg, dma_failed: bool) -> Result<[u32; 4]> {
     // Clear interrupt by writing 1
     let raw_int_status = read_reg(io, RAW_INT_STATUS_REG);
     write_reg(io, RAW_INT_STATUS_REG, raw_int_status);
@@ -152,16 +200,40 @@ fn send_cmd<T: SDIo, S: SleepOps>(
         read_reg(io, RESP2_REG),
         read_reg(io, RESP3_REG),
     ];
-    if raw_int_status.have_error() {
+    let err = if let Some(err) = interrupt_error(&mut raw_int_status) {
+        Some(err)
+    } else if dma_failed {
+        Some(Vf2SdDriverError::DmaError)
+    } else if cmd.data_expected() && !raw_int_status.dto() {
+        // the data phase neither finished nor failed in time
+        Some(Vf2SdDriverError::TimeoutError)
+    } else {
+        None
+    };
+    if let Some(err) = err {
         error!("card has error {:#?}", raw_int_status);
         error!("cmd {:#?}", cmd);
         error!("resp {:x?}", resp[0]);
-        return None;
+        return Err(err);
     }
-    Some(resp)
+    Ok(resp)
 }
 
+/// Frequency of the card interface unit clock feeding the divider
+pub const CIU_CLK_HZ: usize = 50_000_000;
+/// Clock divider for the default speed mode (25 MHz)
+const CLK_DIVIDER_25MHZ: u8 = 1;
+/// Clock divider for the high speed mode (50 MHz, bypassed)
+const CLK_DIVIDER_50MHZ: u8 = 0;
+
 fn reset_clock<T: SDIo, S: SleepOps>(io: &mut T) {
+    // set clock divider to 400kHz (low)
+    set_clock::<_, S>(io, 4);
+    pdebug!("reset clock success");
+}
+
+/// Reprogram CLK_DIVIDER_REG, the card clock is `CIU_CLK_HZ / (2 * divider)`
+fn set_clock<T: SDIo, S: SleepOps>(io: &mut T, divider: u8) {
     // disable clock
     let mut clock_enable = ClockEnableReg::from(0);
     // write to CLOCK_ENABLE_REG
@@ -171,22 +243,20 @@ fn reset_clock<T: SDIo, S: SleepOps>(io: &mut T) {
         .with_start_cmd(true)
         .with_wait_prvdata_complete(true)
         .with_update_clock_registers_only(true);
-    send_cmd::<_, S>(
+    let _ = send_cmd::<_, S>(
         io,
         Cmd::ResetClock,
         clock_cmd,
         CmdArg::new(0),
         DataTransType::None,
     );
-    // set clock divider to 400kHz (low)
-    let clock_divider = ClockDividerReg::new().with_clk_divider0(4);
+    let clock_divider = ClockDividerReg::new().with_clk_divider0(divider);
     write_reg(io, CLK_DIVIDER_REG, clock_divider.into());
-    // send_cmd(Cmd::ResetClock,clock_disable_cmd,CmdArg::new(0));
     // enable clock
     clock_enable.set_clk_enable(1);
     write_reg(io, CLOCK_ENABLE_REG, clock_enable.into());
     // send reset clock command
-    send_cmd::<_, S>(
+    let _ = send_cmd::<_, S>(
         io,
         Cmd::ResetClock,
         clock_cmd,
@@ -197,7 +267,6 @@ fn reset_clock<T: SDIo, S: SleepOps>(io: &mut T) {
     //     "now clk enable {:#?}",
     //     ClockEnableReg::from(read_reg(io, CLOCK_ENABLE_REG))
     // );
-    // pprintln!("reset clock success");
 }
 
 fn reset_fifo<T: SDIo>(io: &mut T) {
@@ -205,7 +274,7 @@ fn reset_fifo<T: SDIo>(io: &mut T) {
     // todo!(why write to fifo data)?
     // write_reg(CTRL_REG,ctrl.raw());
     write_reg(io, FIFO_DATA_REG, ctrl.into());
-    // pprintln!("reset fifo success");
+    pdebug!("reset fifo success");
 }
 
 fn reset_dma<T: SDIo>(io: &mut T) {
@@ -218,7 +287,7 @@ fn reset_dma<T: SDIo>(io: &mut T) {
         .with_use_internal_dmac(false);
     // ctrl.dma_enable().set(u1!(0));
     write_reg(io, CTRL_REG, ctrl.into());
-    // pprintln!("reset dma success");
+    pdebug!("reset dma success");
 }
 
 fn set_transaction_size<T: SDIo>(io: &mut T, blk_size: u32, byte_count: u32) {
@@ -228,8 +297,8 @@ fn set_transaction_size<T: SDIo>(io: &mut T, blk_size: u32, byte_count: u32) {
     write_reg(io, BYTE_CNT_REG, byte_count.into());
 }
 
-fn test_read<T: SDIo, S: SleepOps>(io: &mut T) {
-    // pprintln!("test read, try read 0 block");
+fn test_read<T: SDIo, S: SleepOps>(io: &mut T) -> Result<()> {
+    pdebug!("test read, try read 0 block");
     set_transaction_size(io, 512, 512);
     let cmd17 = CmdReg::from(Cmd::ReadSingleBlock);
     let arg = CmdArg::new(0);
@@ -240,11 +309,11 @@ fn test_read<T: SDIo, S: SleepOps>(io: &mut T) {
         cmd17,
         arg,
         DataTransType::Read(&mut buffer),
-    )
-    .unwrap();
+    )?;
     // info!("Current FIFO count: {}", fifo_filled_cnt(io));
     let byte_slice = buffer.as_slice();
-    // pprintln!("sd header 16bytes: {:x?}", &byte_slice[..2]);
+    pdebug!("sd header 16bytes: {:x?}", &byte_slice[..2]);
+    Ok(())
 }
 
 /// for test driver
@@ -285,17 +354,17 @@ fn test_write_read<T: SDIo, S: SleepOps>(io: &mut T) {
 }
 
 // send acmd51 to read csr reg
-fn check_bus_width<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> usize {
+fn check_bus_width<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> Result<u64> {
     let cmd55 = CmdReg::from(Cmd::AppCmd);
     let cmd_arg = CmdArg::new(rca << 16);
-    let _resp = send_cmd::<_, S>(io, Cmd::AppCmd, cmd55, cmd_arg, DataTransType::None).unwrap();
+    let _resp = send_cmd::<_, S>(io, Cmd::AppCmd, cmd55, cmd_arg, DataTransType::None)?;
     // send acmd51
     // 1. set transact size
     set_transaction_size(io, 8, 8);
     // 2. send command
     let acmd51 = CmdReg::from(Cmd::SendScr);
     let mut buffer: [u8; 512] = [0; 512]; // 512B
-    send_cmd::<_, S>(
+    let _ = send_cmd::<_, S>(
         io,
         Cmd::SendScr,
         acmd51,
@@ -304,12 +373,72 @@ fn check_bus_width<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> usize {
     );
     // info!("Current FIFO count: {}", fifo_filled_cnt(io)); //2
     let resp = u64::from_be(read_fifo(io, FIFO_DATA_REG));
-    // pprintln!("Bus width supported: {:b}", (resp >> 48) & 0xF);
+    pinfo!("Bus width supported: {:b}", (resp >> 48) & 0xF);
     // info!("Current FIFO count: {}", fifo_filled_cnt(io)); //0
-    0
+    Ok(resp)
+}
+
+/// SCR bit 50: the card supports the 4-bit bus
+const SCR_BUS_WIDTH_4: u64 = 1 << 50;
+/// SCR bits 59:56, CMD6 exists from version 1 (SD 1.10)
+const SCR_SD_SPEC_SHIFT: u64 = 56;
+
+// send acmd6 to switch the card and the controller to the 4-bit bus
+fn set_bus_width_4<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> Result<()> {
+    let cmd55 = CmdReg::from(Cmd::AppCmd);
+    let cmd_arg = CmdArg::new(rca << 16);
+    send_cmd::<_, S>(io, Cmd::AppCmd, cmd55, cmd_arg, DataTransType::None)?;
+    let acmd6 = CmdReg::from(Cmd::SetBusWidth);
+    // 0b10: 4 bits
+    send_cmd::<_, S>(io, Cmd::SetBusWidth, acmd6, CmdArg::new(2), DataTransType::None)?;
+    let ctype = CardTypeReg::from(0).with_card_width4_1(1);
+    write_reg(io, CTYPE_REG, ctype.into());
+    pinfo!("bus width: 4 bits");
+    Ok(())
 }
 
-fn check_csd<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) {
+// send cmd6 to switch function group 1 (access mode) to high speed,
+// returns false if the card does not support it
+fn switch_high_speed<T: SDIo, S: SleepOps>(io: &mut T) -> Result<bool> {
+    // the switch status is 512 bits
+    set_transaction_size(io, 64, 64);
+    let cmd6 = CmdReg::from(Cmd::SwitchFunc);
+    // mode 1 (switch), other groups unchanged, group 1 function 1
+    let arg = CmdArg::new(0x80ff_fff1);
+    let mut status = [0u8; 64];
+    send_cmd::<_, S>(
+        io,
+        Cmd::SwitchFunc,
+        cmd6,
+        arg,
+        DataTransType::Read(&mut status),
+    )?;
+    // bits 379:376 hold the function selected in group 1, 0xf means the switch failed
+    Ok(status[16] & 0xf == 1)
+}
+
+/// Leave the 400 kHz 1-bit identification mode
+fn speed_up<T: SDIo, S: SleepOps>(io: &mut T, rca: u32, scr: u64) {
+    if scr & SCR_BUS_WIDTH_4 != 0 {
+        if let Err(err) = set_bus_width_4::<_, S>(io, rca) {
+            pwarn!("switch to 4-bit bus failed: {}", err);
+        }
+    }
+    let high_speed = (scr >> SCR_SD_SPEC_SHIFT) & 0xf >= 1
+        && switch_high_speed::<_, S>(io).unwrap_or_else(|err| {
+            pwarn!("switch to high speed failed: {}", err);
+            false
+        });
+    if high_speed {
+        set_clock::<_, S>(io, CLK_DIVIDER_50MHZ);
+        pinfo!("clock: {} Hz (high speed)", CIU_CLK_HZ);
+    } else {
+        set_clock::<_, S>(io, CLK_DIVIDER_25MHZ);
+        pinfo!("clock: {} Hz", CIU_CLK_HZ / (2 * CLK_DIVIDER_25MHZ as usize));
+    }
+}
+
+fn check_csd<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> Result<()> {
     let cmd = CmdReg::from(Cmd::SendCsd);
     let resp = send_cmd::<_, S>(
         io,
@@ -317,21 +446,22 @@ fn check_csd<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) {
         cmd,
         CmdArg::new(rca << 16),
         DataTransType::None,
-    )
-    .unwrap();
+    )?;
     let status = resp[0];
-    // pprintln!("status: {:b}", status);
+    pdebug!("status: {:b}", status);
+    Ok(())
 }
 
-fn select_card<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) {
+fn select_card<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> Result<()> {
     let cmd7 = CmdReg::from(Cmd::SelectCard);
     let cmd_arg = CmdArg::new(rca << 16);
-    let resp = send_cmd::<_, S>(io, Cmd::SelectCard, cmd7, cmd_arg, DataTransType::None).unwrap();
+    let resp = send_cmd::<_, S>(io, Cmd::SelectCard, cmd7, cmd_arg, DataTransType::None)?;
     let r1 = resp[0];
     // info!("status: {:b}", r1);
+    Ok(())
 }
 
-fn check_rca<T: SDIo, S: SleepOps>(io: &mut T) -> u32 {
+fn check_rca<T: SDIo, S: SleepOps>(io: &mut T) -> Result<u32> {
     let cmd3 = CmdReg::from(Cmd::SendRelativeAddr);
     let resp = send_cmd::<_, S>(
         io,
@@ -339,15 +469,15 @@ fn check_rca<T: SDIo, S: SleepOps>(io: &mut T) -> u32 {
         cmd3,
         CmdArg::new(0),
         DataTransType::None,
-    )
-    .unwrap();
+    )?;
     let rca = resp[0] >> 16;
     // info!("rca: {:#x}", rca);
     // info!("card status: {:b}", resp[0] & 0xffff);
-    rca
+    Ok(rca)
 }
 
-fn check_cid<T: SDIo, S: SleepOps>(io: &mut T) {
+/// Read the CID of the card, `None` if the card does not answer
+fn check_cid<T: SDIo, S: SleepOps>(io: &mut T) -> Option<u128> {
     let cmd2 = CmdReg::from(Cmd::AllSendCid);
     let resp = send_cmd::<_, S>(
         io,
@@ -356,61 +486,68 @@ fn check_cid<T: SDIo, S: SleepOps>(io: &mut T) {
         CmdArg::new(0),
         DataTransType::None,
     );
-    if let Some(resp) = resp {
+    if let Ok(resp) = resp {
         // to 128 bit
         let resp = resp[0] as u128
             | (resp[1] as u128) << 32
             | (resp[2] as u128) << 64
             | (resp[3] as u128) << 96;
         let cid = Cid::new(resp);
-        // #[cfg(feature = "alloc")]
-        // pprintln!("cid: {}", cid.fmt());
-        // #[cfg(not(feature = "alloc"))]
-        // pprintln!("cid: {:?}", cid);
+        #[cfg(feature = "alloc")]
+        pinfo!("cid: {}", cid.fmt());
+        #[cfg(not(feature = "alloc"))]
+        pinfo!("cid: {:?}", cid);
+        return Some(resp);
     }
+    None
 }
 
-fn check_version<T: SDIo, S: SleepOps>(io: &mut T) -> u8 {
+fn check_version<T: SDIo, S: SleepOps>(io: &mut T) -> Result<u8> {
     // check voltage
     let cmd8 = CmdReg::from(Cmd::SendIfCond);
     let cmd8_arg = CmdArg::new(0x1aa);
-    let resp = send_cmd::<_, S>(io, Cmd::SendIfCond, cmd8, cmd8_arg, DataTransType::None).unwrap();
+    let resp = send_cmd::<_, S>(io, Cmd::SendIfCond, cmd8, cmd8_arg, DataTransType::None)?;
     if (resp[0] & 0xaa) == 0 {
         // error!("card {} unusable", 0);
-        // pprintln!("card version: 1.0");
-        return 1;
+        pwarn!("card version: 1.0");
+        return Ok(1);
     }
-    // pprintln!("card voltage: {:#x?}", resp[0]);
-    // pprintln!("card version: 2.0");
-    2
+    pdebug!("card voltage: {:#x?}", resp[0]);
+    pinfo!("card version: 2.0");
+    Ok(2)
 }
 
-fn check_big_support<T: SDIo, S: SleepOps>(io: &mut T) -> bool {
-    loop {
+/// ACMD41 polls 10ms apart
+const ACMD41_RETRIES: usize = 100;
+
+fn check_big_support<T: SDIo, S: SleepOps>(io: &mut T) -> Result<bool> {
+    // the card has 1s to finish its power up
+    for _ in 0..ACMD41_RETRIES {
         // send cmd55
         let cmd55 = CmdReg::from(Cmd::AppCmd);
-        send_cmd::<_, S>(io, Cmd::AppCmd, cmd55, CmdArg::new(0), DataTransType::None);
+        let _ = send_cmd::<_, S>(io, Cmd::AppCmd, cmd55, CmdArg::new(0), DataTransType::None);
         let cmd41 = CmdReg::from(Cmd::SdSendOpCond);
         let cmd41_arg = CmdArg::new((1 << 30) | (1 << 24) | 0xFF8000);
         let resp =
-            send_cmd::<_, S>(io, Cmd::SdSendOpCond, cmd41, cmd41_arg, DataTransType::None).unwrap();
+            send_cmd::<_, S>(io, Cmd::SdSendOpCond, cmd41, cmd41_arg, DataTransType::None)?;
         // info!("ocr: {:#x?}", resp[0]);
         let ocr = resp[0];
         if ocr.get_bit(31) {
-            // pprintln!("card is ready");
+            pdebug!("card is ready");
             if ocr.get_bit(30) {
-                // pprintln!("card is high capacity");
+                pinfo!("card is high capacity");
             } else {
-                // pprintln!("card is standard capacity");
+                pinfo!("card is standard capacity");
             }
-            break;
+            return Ok(true);
         }
         S::sleep_ms(10);
     }
-    true
+    Err(Vf2SdDriverError::TimeoutError)
 }
 
-fn init_sdcard<T: SDIo, S: SleepOps>(io: &mut T) {
+/// Identify and set up the card, return its CID
+fn init_sdcard<T: SDIo, S: SleepOps>(io: &mut T, conservative: bool) -> Result<Option<u128>> {
     // read DETECT_REG
     let detect = read_reg(io, CDETECT_REG);
     // info!("detect: {:#?}", CDetectReg::new(detect));
@@ -456,38 +593,41 @@ fn init_sdcard<T: SDIo, S: SleepOps>(io: &mut T) {
     // go idle state
     let cmd0 = CmdReg::from(Cmd::GoIdleState);
     // cmd0.response_expect().set(u1!(0));
-    send_cmd::<_, S>(
+    let _ = send_cmd::<_, S>(
         io,
         Cmd::GoIdleState,
         cmd0,
         CmdArg::new(0),
         DataTransType::None,
     );
-    // pprintln!("card is in idle state");
+    pdebug!("card is in idle state");
 
-    check_version::<_, S>(io);
+    check_version::<_, S>(io)?;
 
-    check_big_support::<T, S>(io);
+    check_big_support::<T, S>(io)?;
 
-    check_cid::<_, S>(io);
-    let rca = check_rca::<_, S>(io);
-    // pprintln!("rca: {:#x?}", rca);
-    check_csd::<_, S>(io, rca);
+    let cid = check_cid::<_, S>(io);
+    let rca = check_rca::<_, S>(io)?;
+    pdebug!("rca: {:#x?}", rca);
+    check_csd::<_, S>(io, rca)?;
 
     // let raw_int_status = RawInterruptStatusReg::from(read_reg(io,RAW_INT_STATUS_REG));
     // pprintln!("RAW_INT_STATUS_REG: {:#?}", raw_int_status);
 
     S::sleep_ms(1);
 
-    select_card::<_, S>(io, rca);
+    select_card::<_, S>(io, rca)?;
 
     let status = StatusReg::from(read_reg(io, STATUS_REG));
     // info!("Now FIFO Count is {}", status.fifo_count());
 
     // check bus width
-    check_bus_width::<_, S>(io, rca);
+    let scr = check_bus_width::<_, S>(io, rca)?;
+    if !conservative {
+        speed_up::<_, S>(io, rca, scr);
+    }
     // try read a block data
-    test_read::<_, S>(io);
+    test_read::<_, S>(io)?;
     // test_write_read();
 
     // info!("CTRL_REG: {:#?}", ControlReg::from(read_reg(io, CTRL_REG)));
@@ -496,15 +636,25 @@ fn init_sdcard<T: SDIo, S: SleepOps>(io: &mut T) {
     // Clear interrupt by writing 1
     write_reg(io, RAW_INT_STATUS_REG, raw_int_status.into());
 
-    // pprintln!("init sd success");
+    pinfo!("init sd success");
+    Ok(cid)
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Vf2SdDriverError {
     InitError,
     ReadError,
     WriteError,
+    /// no response, data or busy release from the card in time
     TimeoutError,
+    /// CRC mismatch of the data
+    CrcError,
+    /// the card answered with an error or a malformed frame
+    ResponseError,
+    /// the IDMAC reported a bus or descriptor error
+    DmaError,
+    /// no card in the slot
+    NoCardError,
     UnknownError,
 }
 
@@ -515,6 +665,10 @@ impl Display for Vf2SdDriverError {
             Vf2SdDriverError::ReadError => write!(f, "read error"),
             Vf2SdDriverError::WriteError => write!(f, "write error"),
             Vf2SdDriverError::TimeoutError => write!(f, "timeout error"),
+            Vf2SdDriverError::CrcError => write!(f, "crc error"),
+            Vf2SdDriverError::ResponseError => write!(f, "response error"),
+            Vf2SdDriverError::DmaError => write!(f, "dma error"),
+            Vf2SdDriverError::NoCardError => write!(f, "no card"),
             Vf2SdDriverError::UnknownError => write!(f, "unknown error"),
         }
     }
@@ -522,40 +676,297 @@ impl Display for Vf2SdDriverError {
 
 pub type Result<T> = core::result::Result<T, Vf2SdDriverError>;
 
-fn read_block<T: SDIo, S: SleepOps>(io: &mut T, block: usize, buf: &mut [u8]) -> Result<usize> {
+/// Classify the error bits of RAW_INT_STATUS_REG
+fn interrupt_error(raw_int_status: &mut RawInterrupt) -> Option<Vf2SdDriverError> {
+    if !raw_int_status.have_error() {
+        None
+    } else if raw_int_status.rto() || raw_int_status.drto() {
+        Some(Vf2SdDriverError::TimeoutError)
+    } else if raw_int_status.dcrc() {
+        Some(Vf2SdDriverError::CrcError)
+    } else {
+        Some(Vf2SdDriverError::ResponseError)
+    }
+}
+
+/// Whether CDETECT_REG reports a card in slot 0 (the bit is active low)
+fn card_present<T: SDIo>(io: &T) -> bool {
+    read_reg(io, CDETECT_REG) & 1 == 0
+}
+
+/// Change of the card detect state reported by [`Vf2SdDriver::poll_card`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CardEvent {
+    /// a card was inserted, identify it with [`Vf2SdDriver::init`]
+    Inserted,
+    /// the card was removed, transfers fail with [`Vf2SdDriverError::NoCardError`]
+    Removed,
+}
+
+/// Time for the card to settle in the slot before it is identified
+pub const CARD_DEBOUNCE_MS: usize = 50;
+
+/// Attempts of a transfer before the error is returned
+pub const MAX_RETRIES: usize = 3;
+
+/// size of a data block in bytes
+pub const BLOCK_SIZE: usize = 512;
+
+fn read_block<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    idmac: &mut Idmac,
+    block: usize,
+    buf: &mut [u8],
+) -> Result<usize> {
     assert_eq!(buf.len(), 512);
     set_transaction_size(io, 512, 512);
     let cmd17 = CmdReg::from(Cmd::ReadSingleBlock);
     let arg = CmdArg::new(block as u32);
-    let _resp = send_cmd::<_, S>(
+    let _resp = send_data_cmd::<_, S>(
         io,
+        idmac,
         Cmd::ReadSingleBlock,
         cmd17,
         arg,
         DataTransType::Read(buf),
-    )
-    .unwrap();
+    )?;
     // info!("Current FIFO count: {}", fifo_filled_cnt(io));
     Ok(buf.len())
 }
 
-fn write_block<T: SDIo, S: SleepOps>(io: &mut T, block: usize, buf: &[u8]) -> Result<usize> {
+fn write_block<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    idmac: &mut Idmac,
+    block: usize,
+    buf: &[u8],
+) -> Result<usize> {
     assert_eq!(buf.len(), 512);
     set_transaction_size(io, 512, 512);
     let cmd24 = CmdReg::from(Cmd::WriteSingleBlock);
     let arg = CmdArg::new(block as u32);
-    let _resp = send_cmd::<_, S>(
+    let _resp = send_data_cmd::<_, S>(
         io,
+        idmac,
         Cmd::WriteSingleBlock,
         cmd24,
         arg,
         DataTransType::Write(buf),
-    )
-    .unwrap();
+    )?;
     // info!("Current FIFO count: {}", fifo_filled_cnt(io));
     Ok(buf.len())
 }
 
+/// Bring the controller back to a clean state after a failed transfer
+fn recover<T: SDIo, S: SleepOps>(io: &mut T) {
+    let ctrl = ControlReg::from(read_reg(io, CTRL_REG)).with_fifo_reset(true);
+    write_reg(io, CTRL_REG, ctrl.into());
+    S::sleep_ms_until(10, || !ControlReg::from(read_reg(io, CTRL_REG)).fifo_reset());
+    let raw_int_status = read_reg(io, RAW_INT_STATUS_REG);
+    write_reg(io, RAW_INT_STATUS_REG, raw_int_status);
+}
+
+/// Send a data command, through the IDMAC if it can describe the buffer, else by PIO
+fn send_data_cmd<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    idmac: &mut Idmac,
+    cmd_type: Cmd,
+    cmd: CmdReg,
+    arg: CmdArg,
+    data_trans_type: DataTransType,
+) -> Result<[u32; 4]> {
+    let (vaddr, len, read) = match &data_trans_type {
+        DataTransType::Read(buf) => (buf.as_ptr() as usize, buf.len(), true),
+        DataTransType::Write(buf) => (buf.as_ptr() as usize, buf.len(), false),
+        _ => panic!("Not a data transfer"),
+    };
+    let desc = match idmac.prepare(io, vaddr, len) {
+        Some(desc) => desc,
+        None => return send_cmd::<_, S>(io, cmd_type, cmd, arg, data_trans_type),
+    };
+    dma::start(io, desc);
+    let resp = send_cmd::<_, S>(io, cmd_type, cmd, arg, DataTransType::Dma(len));
+    dma::stop(io);
+    if read {
+        // drop the lines the CPU may have fetched during the transfer
+        io.dma_sync(vaddr, len);
+    }
+    resp
+}
+
+/// Stop a multiple block transfer which did not finish, the auto stop is not sent on errors
+fn stop_transmission<T: SDIo, S: SleepOps>(io: &mut T) {
+    let cmd12 = CmdReg::from(Cmd::StopTransmission);
+    let _resp = send_cmd::<_, S>(
+        io,
+        Cmd::StopTransmission,
+        cmd12,
+        CmdArg::new(0),
+        DataTransType::None,
+    );
+}
+
+/// Read `buf.len() / 512` blocks starting at `block` with CMD18
+fn read_blocks<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    idmac: &mut Idmac,
+    block: usize,
+    buf: &mut [u8],
+) -> Result<usize> {
+    assert!(!buf.is_empty() && buf.len() % BLOCK_SIZE == 0);
+    if buf.len() == BLOCK_SIZE {
+        return read_block::<_, S>(io, idmac, block, buf);
+    }
+    set_transaction_size(io, BLOCK_SIZE as u32, buf.len() as u32);
+    // the controller sends CMD12 after the last block
+    let cmd18 = CmdReg::from(Cmd::ReadMultipleBlock);
+    let arg = CmdArg::new(block as u32);
+    let len = buf.len();
+    let resp = send_data_cmd::<_, S>(
+        io,
+        idmac,
+        Cmd::ReadMultipleBlock,
+        cmd18,
+        arg,
+        DataTransType::Read(buf),
+    );
+    if let Err(err) = resp {
+        stop_transmission::<_, S>(io);
+        return Err(err);
+    }
+    Ok(len)
+}
+
+/// Write `buf.len() / 512` blocks starting at `block` with CMD25
+fn write_blocks<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    idmac: &mut Idmac,
+    block: usize,
+    buf: &[u8],
+) -> Result<usize> {
+    assert!(!buf.is_empty() && buf.len() % BLOCK_SIZE == 0);
+    if buf.len() == BLOCK_SIZE {
+        return write_block::<_, S>(io, idmac, block, buf);
+    }
+    set_transaction_size(io, BLOCK_SIZE as u32, buf.len() as u32);
+    let cmd25 = CmdReg::from(Cmd::WriteMultipleBlock);
+    let arg = CmdArg::new(block as u32);
+    let resp = send_data_cmd::<_, S>(
+        io,
+        idmac,
+        Cmd::WriteMultipleBlock,
+        cmd25,
+        arg,
+        DataTransType::Write(buf),
+    );
+    if let Err(err) = resp {
+        stop_transmission::<_, S>(io);
+        return Err(err);
+    }
+    Ok(buf.len())
+}
+
+/// Data direction of a [`Vf2SdDriver::submit`]ted transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// A transfer whose data phase is run by the IDMAC in the background
+struct InFlight {
+    cmd: CmdReg,
+    vaddr: usize,
+    len: usize,
+    dir: Direction,
+}
+
+/// Interrupts that end the data phase of a submitted transfer
+fn transfer_int_mask() -> RawInterrupt {
+    RawInterrupt::new()
+        .with_dto(true)
+        .with_ebe(true)
+        .with_sbe(true)
+        .with_frun(true)
+        .with_hto(true)
+        .with_drto(true)
+        .with_rto(true)
+        .with_dcrc(true)
+        .with_rcrc(true)
+        .with_response_err(true)
+}
+
+/// Start the IDMAC and send the read or write command for `len / 512` blocks at `block`,
+/// without waiting for the data phase
+fn submit<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    idmac: &mut Idmac,
+    block: usize,
+    vaddr: usize,
+    len: usize,
+    dir: Direction,
+) -> Result<InFlight> {
+    assert!(len != 0 && len % BLOCK_SIZE == 0);
+    let cmd_type = match (dir, len == BLOCK_SIZE) {
+        (Direction::Read, true) => Cmd::ReadSingleBlock,
+        (Direction::Read, false) => Cmd::ReadMultipleBlock,
+        (Direction::Write, true) => Cmd::WriteSingleBlock,
+        (Direction::Write, false) => Cmd::WriteMultipleBlock,
+    };
+    // buffers the IDMAC cannot describe are left to the blocking path
+    let desc = idmac
+        .prepare(io, vaddr, len)
+        .ok_or(Vf2SdDriverError::DmaError)?;
+    set_transaction_size(io, BLOCK_SIZE as u32, len as u32);
+    let cmd = CmdReg::from(cmd_type);
+    dma::start(io, desc);
+    if let Err(err) = issue_cmd::<_, S>(io, cmd_type, cmd, CmdArg::new(block as u32)) {
+        dma::stop(io);
+        recover::<_, S>(io);
+        return Err(err);
+    }
+    // the line stays low for the polled commands
+    write_reg(io, INT_MASK_REG, u16::from(transfer_int_mask()) as u32);
+    Ok(InFlight {
+        cmd,
+        vaddr,
+        len,
+        dir,
+    })
+}
+
+/// Whether the data phase of the submitted transfer is over, successfully or not
+fn transfer_over<T: SDIo>(io: &T) -> bool {
+    let raw_int_status_reg = RawInterruptStatusReg::from(read_reg(io, RAW_INT_STATUS_REG));
+    let mut raw_int_status = RawInterrupt::from(raw_int_status_reg.int_status());
+    raw_int_status.dto() || raw_int_status.have_error()
+}
+
+/// Tear down a submitted transfer and collect its result
+fn finish<T: SDIo, S: SleepOps>(io: &mut T, req: &InFlight) -> Result<usize> {
+    write_reg(io, INT_MASK_REG, 0);
+    // the IDMAC may report the last descriptor slightly after DTO
+    S::sleep_ms_until(1, || dma::poll(io).is_some());
+    let dma_status = dma::poll(io);
+    if dma_status != Some(true) {
+        error!(
+            "idmac transfer failed {:#?}",
+            IdmacInterrupt::from(read_reg(io, IDSTS_REG))
+        );
+    }
+    dma::stop(io);
+    if req.dir == Direction::Read {
+        io.dma_sync(req.vaddr, req.len);
+    }
+    let result = finish_cmd(io, req.cmd, dma_status != Some(true));
+    if result.is_err() {
+        if req.len > BLOCK_SIZE {
+            stop_transmission::<_, S>(io);
+        }
+        recover::<_, S>(io);
+    }
+    result.map(|_| req.len)
+}
+
 /// Vf2SdDriver
 ///
 /// # Example
@@ -563,13 +974,29 @@ fn write_block<T: SDIo, S: SleepOps>(io: &mut T, block: usize, buf: &[u8]) -> Re
 /// fn sleep(ms:usize){}
 /// use visionfive2_sd::Vf2SdDriver;
 /// let driver = Vf2SdDriver::new(sleep);
-/// driver.init();
+/// driver.init().unwrap();
 /// let mut buf = [0u8;512];
 /// driver.read_block(0,&mut buf);
 /// driver.write_block(0,&buf);
+/// let mut buf = [0u8;4096];
+/// driver.read_blocks(0,&mut buf);
+/// driver.write_blocks(0,&buf);
 /// ```
 pub struct Vf2SdDriver<T, S> {
     io: T,
+    idmac: Idmac,
+    /// stay at 400 kHz on the 1-bit bus, for debugging
+    conservative: bool,
+    /// a card is in the slot and was identified
+    present: bool,
+    /// CDETECT_REG reported a card at the last [`Self::poll_card`] or [`Self::init`]
+    detected: bool,
+    /// the transfer started by [`Self::submit`]
+    in_flight: Option<InFlight>,
+    /// result of the finished transfer, until [`Self::complete`] takes it
+    done: Option<Result<usize>>,
+    /// CID of the card identified last
+    cid: Option<u128>,
     _sleep: core::marker::PhantomData<S>,
 }
 
@@ -577,16 +1004,159 @@ impl<T: SDIo, S: SleepOps> Vf2SdDriver<T, S> {
     pub fn new(io: T) -> Self {
         Self {
             io,
+            idmac: Idmac::new(),
+            conservative: false,
+            present: false,
+            detected: false,
+            in_flight: None,
+            done: None,
+            cid: None,
             _sleep: core::marker::PhantomData,
         }
     }
-    pub fn init(&mut self) {
-        init_sdcard::<T, S>(&mut self.io);
+    /// Keep the identification mode (1-bit bus, 400 kHz) after init, call before [`Self::init`]
+    pub fn set_conservative(&mut self, conservative: bool) {
+        self.conservative = conservative;
+    }
+    pub fn init(&mut self) -> Result<()> {
+        self.present = false;
+        self.detected = card_present(&self.io);
+        if !self.detected {
+            return Err(Vf2SdDriverError::NoCardError);
+        }
+        self.cid = init_sdcard::<T, S>(&mut self.io, self.conservative)?;
+        self.present = true;
+        Ok(())
+    }
+    /// CID register of the card, read by [`Self::init`]
+    pub fn cid(&self) -> Option<u128> {
+        self.cid
+    }
+    /// Whether a card is in the slot now
+    pub fn card_present(&self) -> bool {
+        card_present(&self.io)
+    }
+    /// Check CDETECT_REG for an insertion or removal since the last call
+    ///
+    /// Call it periodically, or from the SDIO interrupt handler when the card detect
+    /// interrupt fires; the card detect bit of RAW_INT_STATUS_REG is cleared here.
+    /// It only reads registers: after [`CardEvent::Inserted`], wait [`CARD_DEBOUNCE_MS`]
+    /// and identify the card with [`Self::init`], which takes up to a second.
+    pub fn poll_card(&mut self) -> Option<CardEvent> {
+        if self.busy() {
+            // 不打断正在进行的传输，下次再检查
+            return None;
+        }
+        let raw_int_status = RawInterrupt::new().with_card_dectect(true);
+        write_reg(&mut self.io, RAW_INT_STATUS_REG, u16::from(raw_int_status) as u32);
+        let detected = card_present(&self.io);
+        if detected == self.detected {
+            return None;
+        }
+        self.detected = detected;
+        // 新插入的卡识别之前同样不能读写
+        self.present = false;
+        if detected {
+            pinfo!("sd card inserted");
+            Some(CardEvent::Inserted)
+        } else {
+            pwarn!("sd card removed");
+            Some(CardEvent::Removed)
+        }
+    }
+    /// Run `f` until it succeeds, at most [`MAX_RETRIES`] times
+    fn retry<R>(&mut self, mut f: impl FnMut(&mut T, &mut Idmac) -> Result<R>) -> Result<R> {
+        if !self.present {
+            return Err(Vf2SdDriverError::NoCardError);
+        }
+        let mut result = f(&mut self.io, &mut self.idmac);
+        for _ in 1..MAX_RETRIES {
+            match result {
+                Ok(_) => break,
+                Err(err) => warn!("sd transfer failed: {}, retrying", err),
+            }
+            recover::<_, S>(&mut self.io);
+            result = f(&mut self.io, &mut self.idmac);
+        }
+        result
+    }
+    pub fn read_block(&mut self, block: usize, buf: &mut [u8]) -> Result<usize> {
+        self.retry(|io, idmac| read_block::<_, S>(io, idmac, block, buf))
+    }
+    pub fn write_block(&mut self, block: usize, buf: &[u8]) -> Result<usize> {
+        self.retry(|io, idmac| write_block::<_, S>(io, idmac, block, buf))
+    }
+    /// Read consecutive blocks, `buf.len()` must be a multiple of 512
+    pub fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<usize> {
+        self.retry(|io, idmac| read_blocks::<_, S>(io, idmac, block, buf))
+    }
+    /// Write consecutive blocks, `buf.len()` must be a multiple of 512
+    pub fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<usize> {
+        self.retry(|io, idmac| write_blocks::<_, S>(io, idmac, block, buf))
+    }
+    /// Let the controller raise its interrupt line, the line is only unmasked while a
+    /// [`Self::submit`]ted transfer runs
+    pub fn enable_interrupts(&mut self) {
+        write_reg(&mut self.io, INT_MASK_REG, 0);
+        let ctrl = ControlReg::from(read_reg(&self.io, CTRL_REG)).with_int_enable(true);
+        write_reg(&mut self.io, CTRL_REG, ctrl.into());
+    }
+    /// Start moving `len` bytes between `vaddr` and the card at `block` through the IDMAC,
+    /// returning once the command is accepted
+    ///
+    /// The end of the transfer is signalled by the SDIO interrupt: call [`Self::handle_interrupt`]
+    /// from the handler, then take the result with [`Self::complete`]. Nothing is retried,
+    /// `DmaError` before anything was sent means the buffer cannot be used for DMA.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must stay valid and must not be accessed until [`Self::complete`] returns
+    /// its result.
+    pub unsafe fn submit(
+        &mut self,
+        block: usize,
+        vaddr: usize,
+        len: usize,
+        dir: Direction,
+    ) -> Result<()> {
+        assert!(self.idle(), "result of the last sd transfer not taken");
+        if !self.present {
+            return Err(Vf2SdDriverError::NoCardError);
+        }
+        let req = submit::<_, S>(&mut self.io, &mut self.idmac, block, vaddr, len, dir)?;
+        self.in_flight = Some(req);
+        Ok(())
+    }
+    /// Acknowledge the SDIO interrupt, true if the submitted transfer finished
+    ///
+    /// It only reads the raw status, so it can also be polled when an interrupt may be lost.
+    pub fn handle_interrupt(&mut self) -> bool {
+        if self.in_flight.is_none() || !transfer_over(&self.io) {
+            return false;
+        }
+        let req = self.in_flight.take().unwrap();
+        self.done = Some(finish::<_, S>(&mut self.io, &req));
+        true
+    }
+    /// Take the result of the submitted transfer, `None` while it is still running
+    pub fn complete(&mut self) -> Option<Result<usize>> {
+        self.done.take()
+    }
+    /// Abandon the submitted transfer whose interrupt never came, [`Self::complete`]
+    /// then returns `TimeoutError`
+    pub fn cancel(&mut self) {
+        if let Some(req) = self.in_flight.take() {
+            warn!("sd transfer of {} bytes timed out", req.len);
+            let _ = finish::<_, S>(&mut self.io, &req);
+            self.done = Some(Err(Vf2SdDriverError::TimeoutError));
+        }
     }
-    pub fn read_block(&mut self, block: usize, buf: &mut [u8]) {
-        read_block::<_, S>(&mut self.io, block, buf).unwrap();
+    /// Whether a submitted transfer is running, the blocking methods must not be used meanwhile
+    pub fn busy(&self) -> bool {
+        self.in_flight.is_some()
     }
-    pub fn write_block(&mut self, block: usize, buf: &[u8]) {
-        write_block::<_, S>(&mut self.io, block, buf).unwrap();
+    /// Whether the last submitted transfer finished and its result was taken
+    pub fn idle(&self) -> bool {
+        self.in_flight.is_none() && self.done.is_none()
     }
 }
@@ -13,6 +13,7 @@ pub const RESP0_REG: usize = SDIO_BASE + 0x30;
 pub const RESP1_REG: usize = SDIO_BASE + 0x34;
 pub const RESP2_REG: usize = SDIO_BASE + 0x38;
 pub const RESP3_REG: usize = SDIO_BASE + 0x3c;
+pub const INT_MASK_REG: usize = SDIO_BASE + 0x24;
 pub const STATUS_REG: usize = SDIO_BASE + 0x48;
 pub const CDETECT_REG: usize = SDIO_BASE + 0x50;
 pub const BUS_MODE_REG: usize = SDIO_BASE + 0x80;
@@ -20,6 +21,9 @@ pub const CTYPE_REG: usize = SDIO_BASE + 0x18;
 pub const CLOCK_ENABLE_REG: usize = SDIO_BASE + 0x10;
 pub const DBADDRL_REG: usize = SDIO_BASE + 0x88; // DMA DES Address Lower
 pub const DBADDRU_REG: usize = SDIO_BASE + 0x8c; // DMA DES Address Upper
+pub const PLDMND_REG: usize = SDIO_BASE + 0x84; // Poll Demand
+pub const IDSTS_REG: usize = SDIO_BASE + 0x90; // Internal DMAC Status
+pub const IDINTEN_REG: usize = SDIO_BASE + 0x94; // Internal DMAC Interrupt Enable
 pub const CLK_DIVIDER_REG: usize = SDIO_BASE + 0x08;
 pub const RAW_INT_STATUS_REG: usize = SDIO_BASE + 0x44;
 pub const FIFO_DATA_REG: usize = SDIO_BASE + 0x600;
@@ -400,6 +404,36 @@ pub struct RawInterrupt {
     pub card_dectect: bool,
 }
 
+/// Layout of IDSTS_REG and IDINTEN_REG (in 64-bit address mode)
+#[bitfield(u32,order = Msb)]
+pub struct IdmacInterrupt {
+    #[bits(15)]
+    reserved: u16,
+    /// DMAC FSM present state, only in IDSTS
+    #[bits(4)]
+    pub fsm: u8,
+    /// Error bits of a fatal bus error, only in IDSTS
+    #[bits(3)]
+    pub eb: u8,
+    /// Abnormal interrupt summary (AIS)
+    pub ais: bool,
+    /// Normal interrupt summary (NIS)
+    pub nis: bool,
+    #[bits(2)]
+    reserved1: u8,
+    /// Card error summary (CES)
+    pub ces: bool,
+    /// Descriptor unavailable (DU)
+    pub du: bool,
+    reserved2: bool,
+    /// Fatal bus error (FBE)
+    pub fbe: bool,
+    /// Receive interrupt (RI)
+    pub ri: bool,
+    /// Transmit interrupt (TI)
+    pub ti: bool,
+}
+
 // mid:u8,
 // oid:u16,
 // pnm:u32,
@@ -541,7 +575,11 @@ impl From<Cmd> for CmdReg {
                     .with_response_length(true);
                 cmd2
             }
-            Cmd::SendScr | Cmd::ReadSingleBlock => {
+            Cmd::SetBusWidth => {
+                let acmd6 = CmdReg::with_no_data(0, value.into());
+                acmd6
+            }
+            Cmd::SendScr | Cmd::ReadSingleBlock | Cmd::SwitchFunc => {
                 let cmd = CmdReg::with_data(0, value.into());
                 cmd
             }
@@ -549,6 +587,23 @@ impl From<Cmd> for CmdReg {
                 let cmd = CmdReg::with_data(0, value.into()).with_transfer_dir(true);
                 cmd
             }
+            Cmd::ReadMultipleBlock => {
+                let cmd18 = CmdReg::with_data(0, value.into()).with_send_auto_stop(true);
+                cmd18
+            }
+            Cmd::WriteMultipleBlock => {
+                let cmd25 = CmdReg::with_data(0, value.into())
+                    .with_transfer_dir(true)
+                    .with_send_auto_stop(true);
+                cmd25
+            }
+            Cmd::StopTransmission => {
+                // stop the current transfer at once
+                let cmd12 = CmdReg::with_no_data(0, value.into())
+                    .with_stop_abort_cmd(true)
+                    .with_wait_prvdata_complete(false);
+                cmd12
+            }
             _ => {
                 panic!("Not implemented")
             }
@@ -21,6 +21,13 @@ pub trait SDIo {
     fn write_reg_at(&mut self, offset: usize, val: u32);
     fn read_data_at(&self, offset: usize) -> u64;
     fn write_data_at(&mut self, offset: usize, val: u64);
+    /// Physical address of `vaddr` for the internal DMAC, `None` keeps the driver in PIO mode
+    fn virt_to_phys(&self, _vaddr: usize) -> Option<usize> {
+        None
+    }
+    /// Write back and invalidate the cache lines of `[vaddr, vaddr + len)`,
+    /// needed when DMA is not coherent with the CPU caches
+    fn dma_sync(&self, _vaddr: usize, _len: usize) {}
 }
 
 pub trait SleepOps {
@@ -0,0 +1,15 @@
+#!/bin/sh
+# cargo test 的 runner：把测试内核转成 bin 后在 QEMU 中运行，QEMU 的退出码即测试结果
+# 用法：qemu-test.sh <kernel elf>，文件系统镜像可通过 FS_IMG 指定
+set -e
+ELF=$1
+BIN=$ELF.bin
+FS_IMG=${FS_IMG:-../sdcard-riscv.img}
+rust-objcopy --binary-architecture=riscv64 "$ELF" --strip-all -O binary "$BIN"
+exec qemu-system-riscv64 \
+	-M 128m \
+	-machine virt \
+	-nographic \
+	-kernel "$BIN" \
+	-drive file="$FS_IMG",if=none,format=raw,id=x0 \
+	-device virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0
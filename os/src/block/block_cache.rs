@@ -1,17 +1,23 @@
 //! Block Cache Layer
 //! Implements about the disk block cache functionality
-use alloc::{collections::VecDeque, sync::Arc, vec, vec::Vec};
+use alloc::{collections::VecDeque, format, string::String, sync::Arc, vec, vec::Vec};
 
 use lazy_static::*;
 use spin::Mutex;
 
-use super::{block_dev::BlockDevice, BLOCK_SZ};
+use super::{
+    block_dev::{BlockDevice, RamDisk},
+    BLOCK_SZ,
+};
+use crate::timer::{get_time, get_time_ms, sleep_until, TimeSpec};
 /// BlockCache is a cache for a block in disk.
 pub struct BlockCache {
     cache:        Vec<u8>,
     block_id:     usize,
     block_device: Arc<dyn BlockDevice>,
     modified:     bool,
+    /// when the block was first modified after the last write-back, in ms
+    dirty_since:  usize,
 }
 
 impl BlockCache {
@@ -25,6 +31,7 @@ impl BlockCache {
             block_id,
             block_device,
             modified: false,
+            dirty_since: 0,
         }
     }
     /// Get the slice in the block cache according to the offset.
@@ -44,7 +51,10 @@ impl BlockCache {
     where T: Sized {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
-        self.modified = true;
+        if !self.modified {
+            self.modified = true;
+            self.dirty_since = get_time_ms();
+        }
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
@@ -71,76 +81,346 @@ impl Drop for BlockCache {
     }
 }
 
-const BLOCK_CACHE_SIZE: usize = 16;
+/// capacity of the global block cache, in blocks
+pub const BLOCK_CACHE_SIZE: usize = 16;
+/// dirty blocks older than this are written back in the background, in ms
+pub const DIRTY_EXPIRE_MS: usize = 3000;
+/// max blocks written back by one background pass, bounding the time spent in it
+const WRITEBACK_BATCH: usize = 8;
+/// interval between two background write-back passes, in ms
+const WRITEBACK_INTERVAL_MS: usize = 500;
+
+/// Counters of a [`BlockCacheManager`], for tuning its capacity
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockCacheStats {
+    pub hits:            usize,
+    pub misses:          usize,
+    pub evictions:       usize,
+    /// evicted blocks that had to be written back first
+    pub dirty_evictions: usize,
+    /// blocks written back because they stayed dirty for [`DIRTY_EXPIRE_MS`]
+    pub writebacks:      usize,
+}
+
+/// (device, block_id), the device is identified by the address of its object
+type CacheKey = (usize, usize);
+
+fn cache_key(block_id: usize, block_device: &Arc<dyn BlockDevice>) -> CacheKey {
+    (Arc::as_ptr(block_device) as *const () as usize, block_id)
+}
 
 /// BlockCacheManager is a manager for BlockCache.
+///
+/// 按 LRU 替换：队头是最久未使用的块，命中的块移到队尾；换出时跳过仍被持有的块。
 pub struct BlockCacheManager {
-    /// (block_id, block_cache)
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    capacity: usize,
+    /// ((device, block_id), block_cache), least recently used first
+    queue:    VecDeque<(CacheKey, Arc<Mutex<BlockCache>>)>,
+    stats:    BlockCacheStats,
 }
 
 impl Default for BlockCacheManager {
     fn default() -> Self {
-        Self::new()
+        Self::new(BLOCK_CACHE_SIZE)
     }
 }
 
 impl BlockCacheManager {
-    /// Create a new BlockCacheManager with an empty queue (block_id, block_cache)
-    pub fn new() -> Self {
+    /// Create a new BlockCacheManager holding at most `capacity` blocks
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
         Self {
-            queue: VecDeque::new(),
+            capacity,
+            queue: VecDeque::with_capacity(capacity),
+            stats: BlockCacheStats::default(),
         }
     }
     /// Get a block cache from the queue. according to the block_id.
     pub fn get_block_cache(
         &mut self, block_id: usize, block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
-            Arc::clone(&pair.1)
-        } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self
-                    .queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
-                {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
+        let key = cache_key(block_id, &block_device);
+        if let Some(block_cache) = self.lookup(key) {
+            return block_cache;
+        }
+        drop(self.miss());
+        // load block into mem and push back
+        let block_cache = Arc::new(Mutex::new(BlockCache::new(block_id, block_device)));
+        self.insert(key, block_cache)
+    }
+    /// The cached block at `key`, moved to the back of the queue
+    fn lookup(&mut self, key: CacheKey) -> Option<Arc<Mutex<BlockCache>>> {
+        let idx = self.queue.iter().position(|pair| pair.0 == key)?;
+        self.stats.hits += 1;
+        let pair = self.queue.remove(idx).unwrap();
+        let block_cache = Arc::clone(&pair.1);
+        self.queue.push_back(pair);
+        Some(block_cache)
+    }
+    /// Count a miss and make room for the block about to be loaded, returning the
+    /// evicted block, which is written back when dropped
+    fn miss(&mut self) -> Option<Arc<Mutex<BlockCache>>> {
+        self.stats.misses += 1;
+        (self.queue.len() >= self.capacity).then(|| self.evict())
+    }
+    /// Cache `block_cache` loaded for `key`, unless another task loaded it meanwhile
+    fn insert(
+        &mut self, key: CacheKey, block_cache: Arc<Mutex<BlockCache>>,
+    ) -> Arc<Mutex<BlockCache>> {
+        if let Some((_, cached)) = self.queue.iter().find(|pair| pair.0 == key) {
+            return Arc::clone(cached);
+        }
+        self.queue.push_back((key, Arc::clone(&block_cache)));
+        block_cache
+    }
+    /// Remove the least recently used block nobody holds, it is written back when the
+    /// returned last reference is dropped
+    fn evict(&mut self) -> Arc<Mutex<BlockCache>> {
+        let idx = self
+            .queue
+            .iter()
+            .position(|pair| Arc::strong_count(&pair.1) == 1)
+            .expect("Run out of BlockCache!");
+        let (_, block_cache) = self.queue.remove(idx).unwrap();
+        self.stats.evictions += 1;
+        if block_cache.lock().modified {
+            self.stats.dirty_evictions += 1;
+        }
+        block_cache
+    }
+    /// Change the capacity, evicting the least recently used blocks that no longer fit
+    ///
+    /// 仍被持有的块不能换出，这时缓存暂时超出容量，之后的换出会把它收回来。
+    pub fn set_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0);
+        self.capacity = capacity;
+        while self.queue.len() > capacity
+            && self
+                .queue
+                .iter()
+                .any(|pair| Arc::strong_count(&pair.1) == 1)
+        {
+            drop(self.evict());
+        }
+    }
+    /// Write back up to `max` blocks that have been dirty since before `expire`
+    ///
+    /// 正被其他任务使用的块跳过，留到下一次。
+    pub fn writeback_expired(&mut self, expire: usize, max: usize) {
+        let mut written = 0;
+        for (_, block_cache) in self.queue.iter() {
+            if written == max {
+                break;
+            }
+            let Some(mut block_cache) = block_cache.try_lock() else {
+                continue;
+            };
+            if block_cache.modified && block_cache.dirty_since <= expire {
+                block_cache.sync();
+                written += 1;
             }
-            // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(BlockCache::new(
-                block_id,
-                Arc::clone(&block_device),
-            )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
         }
+        self.stats.writebacks += written;
+    }
+    /// The dirty blocks for which `filter` holds, in ascending block order of each device
+    ///
+    /// 按设备和块号顺序写回，磁盘上是顺序写。
+    fn dirty_blocks(&self, filter: impl Fn(&CacheKey) -> bool) -> Vec<Arc<Mutex<BlockCache>>> {
+        let mut dirty: Vec<_> = self
+            .queue
+            .iter()
+            .filter(|(key, block_cache)| filter(key) && block_cache.lock().modified)
+            .collect();
+        dirty.sort_unstable_by_key(|pair| pair.0);
+        dirty
+            .into_iter()
+            .map(|(_, block_cache)| Arc::clone(block_cache))
+            .collect()
+    }
+    /// Forget the blocks of `block_device` without writing them back, after its media
+    /// was removed or replaced
+    ///
+    /// 仍被持有的块也移出缓存并标记为干净，释放时不会写到新插入的卡上。
+    pub fn drop_device(&mut self, block_device: &Arc<dyn BlockDevice>) {
+        let device = cache_key(0, block_device).0;
+        self.queue.retain(|(key, block_cache)| {
+            if key.0 != device {
+                return true;
+            }
+            block_cache.lock().modified = false;
+            false
+        });
+    }
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Counters since creation or the last [`Self::reset_stats`]
+    pub fn stats(&self) -> BlockCacheStats {
+        self.stats
+    }
+    pub fn reset_stats(&mut self) {
+        self.stats = BlockCacheStats::default();
     }
 }
 
 lazy_static! {
     /// BLOCK_CACHE_MANAGER: Glocal instance of BlockCacheManager.
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> =
-        Mutex::new(BlockCacheManager::new());
+        Mutex::new(BlockCacheManager::new(BLOCK_CACHE_SIZE));
 }
 /// Get a block cache from the queue. according to the block_id.
+///
+/// 读写盘时可能睡眠（见 [`crate::drivers::IoLock`]），不持有管理器的锁：换出的块在解锁后写回，
+/// 再读入新块。同一设备的块由文件系统的锁串行访问，写回期间不会有人读入同一块。
 pub fn get_block_cache(
     block_id: usize, block_device: Arc<dyn BlockDevice>,
 ) -> Arc<Mutex<BlockCache>> {
-    BLOCK_CACHE_MANAGER
-        .lock()
-        .get_block_cache(block_id, block_device)
+    let key = cache_key(block_id, &block_device);
+    let evicted = {
+        let mut manager = BLOCK_CACHE_MANAGER.lock();
+        if let Some(block_cache) = manager.lookup(key) {
+            return block_cache;
+        }
+        manager.miss()
+    };
+    drop(evicted);
+    let block_cache = Arc::new(Mutex::new(BlockCache::new(block_id, block_device)));
+    BLOCK_CACHE_MANAGER.lock().insert(key, block_cache)
+}
+/// Write back `block_cache` if it is dirty, without holding its lock during the write
+///
+/// 先复制出内容并标记为干净，写盘期间再次修改的块重新变成脏块，由下一次写回处理。
+fn write_back(block_cache: &Mutex<BlockCache>) {
+    let mut cache = block_cache.lock();
+    if !cache.modified {
+        return;
+    }
+    cache.modified = false;
+    let (block_id, block_device) = (cache.block_id, Arc::clone(&cache.block_device));
+    let data = cache.cache.clone();
+    drop(cache);
+    block_device.write_block(block_id, &data);
 }
 /// Sync(write) all the block cache to disk.
 pub fn block_cache_sync_all() {
+    let dirty = BLOCK_CACHE_MANAGER.lock().dirty_blocks(|_| true);
+    dirty.iter().for_each(|block_cache| write_back(block_cache));
+}
+/// Write back the dirty blocks of `block_device` among `block_ids`, for fsync
+pub fn block_cache_sync_blocks(block_device: &Arc<dyn BlockDevice>, mut block_ids: Vec<usize>) {
+    let device = cache_key(0, block_device).0;
+    block_ids.sort_unstable();
+    let dirty = BLOCK_CACHE_MANAGER
+        .lock()
+        .dirty_blocks(|key| key.0 == device && block_ids.binary_search(&key.1).is_ok());
+    dirty.iter().for_each(|block_cache| write_back(block_cache));
+}
+/// Drop the cached blocks of `block_device`, see [`BlockCacheManager::drop_device`]
+pub fn block_cache_drop_device(block_device: &Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER.lock().drop_device(block_device);
+}
+/// Background write-back of blocks dirty for longer than [`DIRTY_EXPIRE_MS`]
+///
+/// 缓存的锁可能被等待磁盘的任务持有着，拿不到锁就等下一轮。
+fn block_cache_writeback() {
+    let Some(expire) = get_time_ms().checked_sub(DIRTY_EXPIRE_MS) else {
+        return;
+    };
+    if let Some(mut manager) = BLOCK_CACHE_MANAGER.try_lock() {
+        manager.writeback_expired(expire, WRITEBACK_BATCH);
+    }
+}
+/// Kernel thread calling [`block_cache_writeback`] every [`WRITEBACK_INTERVAL_MS`]
+///
+/// 写盘可能要等待很久，不能放在时钟中断里。
+pub extern "C" fn block_cache_flusher(_arg: usize) -> ! {
+    loop {
+        let expire = get_time() + TimeSpec::from_ms(WRITEBACK_INTERVAL_MS).to_tick();
+        while !sleep_until(expire) {}
+        block_cache_writeback();
+    }
+}
+/// Set the capacity of the global block cache, in blocks
+pub fn set_block_cache_capacity(capacity: usize) {
+    BLOCK_CACHE_MANAGER.lock().set_capacity(capacity);
+}
+
+/// Contents of `/proc/block_cache`
+pub fn render_stats() -> String {
     let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
-        cache.lock().sync();
+    let stats = manager.stats();
+    format!(
+        "capacity: {}\ncached: {}\nhits: {}\nmisses: {}\nevictions: {}\ndirty_evictions: \
+         {}\nwritebacks: {}\n",
+        manager.capacity,
+        manager.queue.len(),
+        stats.hits,
+        stats.misses,
+        stats.evictions,
+        stats.dirty_evictions,
+        stats.writebacks
+    )
+}
+
+#[allow(unused)]
+/// Test the block cache: write through the cache, evict it, then read the disk
+pub fn block_cache_test() {
+    let blocks = BLOCK_CACHE_SIZE * 2;
+    let ram_disk = Arc::new(RamDisk::new(blocks));
+    let block_device: Arc<dyn BlockDevice> = ram_disk.clone();
+    // 使用独立的 manager，不影响全局缓存
+    let mut manager = BlockCacheManager::new(BLOCK_CACHE_SIZE);
+    for block_id in 0..blocks {
+        manager
+            .get_block_cache(block_id, block_device.clone())
+            .lock()
+            .modify(0, |v: &mut usize| *v = block_id + 1);
+    }
+    // 超过容量的访问会换出最久未使用的块，换出时写回磁盘
+    assert_eq!(manager.queue.len(), BLOCK_CACHE_SIZE);
+    assert_eq!(manager.stats().dirty_evictions, blocks - BLOCK_CACHE_SIZE);
+    for block_id in 0..blocks - BLOCK_CACHE_SIZE {
+        let mut buf = [0u8; BLOCK_SZ];
+        ram_disk.read_block(block_id, &mut buf);
+        assert_eq!(usize::from_ne_bytes(buf[..8].try_into().unwrap()), block_id + 1);
     }
+    for block_id in 0..blocks {
+        let value = manager
+            .get_block_cache(block_id, block_device.clone())
+            .lock()
+            .read(0, |v: &usize| *v);
+        assert_eq!(value, block_id + 1);
+    }
+
+    // 命中的块移到队尾，不会被下一次换出
+    let mut manager = BlockCacheManager::new(2);
+    manager.get_block_cache(0, block_device.clone());
+    manager.get_block_cache(1, block_device.clone());
+    manager.get_block_cache(0, block_device.clone());
+    manager.get_block_cache(2, block_device.clone());
+    let cached = |block_id| {
+        let key = cache_key(block_id, &block_device);
+        manager.queue.iter().any(|pair| pair.0 == key)
+    };
+    assert!(cached(0) && !cached(1));
+    let stats = manager.stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 3, 1));
+
+    // 过期的脏块在后台写回，之后不再算脏块
+    manager
+        .get_block_cache(0, block_device.clone())
+        .lock()
+        .modify(0, |v: &mut usize| *v = 42);
+    manager.writeback_expired(usize::MAX, WRITEBACK_BATCH);
+    let mut buf = [0u8; BLOCK_SZ];
+    ram_disk.read_block(0, &mut buf);
+    assert_eq!(usize::from_ne_bytes(buf[..8].try_into().unwrap()), 42);
+    assert_eq!(manager.stats().writebacks, 1);
+    manager.writeback_expired(usize::MAX, WRITEBACK_BATCH);
+    assert_eq!(manager.stats().writebacks, 1);
+
+    // 缩小容量时换出最久未使用的块
+    manager.set_capacity(1);
+    assert_eq!(manager.queue.len(), 1);
+    info!("block cache test passed!");
 }
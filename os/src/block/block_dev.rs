@@ -2,8 +2,13 @@
 //!
 //! Define the block read-write interface [BlockDevice] that the device driver needs to implement
 
+use alloc::{vec, vec::Vec};
 use core::any::Any;
 
+use spin::Mutex;
+
+use super::BLOCK_SZ;
+
 /// Block device interface.
 pub trait BlockDevice: Send + Sync + Any {
     /// Read a block from the block device.
@@ -11,3 +16,24 @@ pub trait BlockDevice: Send + Sync + Any {
     /// Write a block to the block device.
     fn write_block(&self, block_id: usize, buf: &[u8]);
 }
+
+/// In-memory block device, for building file system images and for tests
+pub struct RamDisk(Mutex<Vec<u8>>);
+
+impl RamDisk {
+    /// A zeroed disk of `blocks` blocks
+    pub fn new(blocks: usize) -> Self {
+        Self(Mutex::new(vec![0u8; blocks * BLOCK_SZ]))
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let start = block_id * BLOCK_SZ;
+        buf.copy_from_slice(&self.0.lock()[start..start + BLOCK_SZ]);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let start = block_id * BLOCK_SZ;
+        self.0.lock()[start..start + BLOCK_SZ].copy_from_slice(buf);
+    }
+}
@@ -1,6 +1,7 @@
 //! Block device and block cache module
 pub mod block_cache;
 pub mod block_dev;
+pub mod partition;
 
 /// Block size in bytes
 pub const BLOCK_SZ: usize = 512;
@@ -0,0 +1,366 @@
+//! Partitions of the block device
+//!
+//! 第一次使用时解析块设备开头的分区表：类型为 0xEE 的保护性 MBR 之后是 GPT，否则按 MBR
+//! 的四个主分区解析（不跟随扩展分区里的逻辑分区）。开头是文件系统的引导扇区或者没有分区表时，
+//! 整个设备就是唯一的分区。每个分区是一个独立的块设备，读写时加上分区的起始扇区。
+//!
+//! 根文件系统所在的分区由内核命令行的 `root=` 选择：`root=2` 是编号为 2 的分区（MBR 按表项
+//! 位置、GPT 按分区项的顺序从 1 编号），`root=PARTUUID=<guid>` 是 GUID 为 `<guid>` 的 GPT 分区。
+//! 没有指定或找不到时，取第一个能认出 ext4 或 FAT32 的分区。
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+
+use ext4_rs::Ext4Error;
+use lazy_static::*;
+use spin::Mutex;
+
+use super::{block_dev::BlockDevice, BLOCK_SZ};
+use crate::drivers::BLOCK_DEVICE;
+
+/// MBR partition type of the protective entry in front of a GPT
+const GPT_PROTECTIVE: u8 = 0xEE;
+/// MBR partition types of extended partitions
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// GPT entries beyond this are ignored
+const MAX_GPT_ENTRIES: usize = 128;
+
+/// A GPT GUID, in its on-disk byte order
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Parse the textual form `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`
+    ///
+    /// 前三段在磁盘上按小端存放，后两段按原顺序存放。
+    pub fn parse(s: &str) -> Option<Self> {
+        let fields: Vec<&str> = s.split('-').collect();
+        let lens: Vec<usize> = fields.iter().map(|field| field.len()).collect();
+        if lens != [8, 4, 4, 4, 12] {
+            return None;
+        }
+        let mut bytes = [0u8; 16];
+        let mut pos = 0;
+        for (i, field) in fields.iter().enumerate() {
+            let mut field_bytes = (0..field.len())
+                .step_by(2)
+                .map(|j| u8::from_str_radix(field.get(j..j + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            if i < 3 {
+                field_bytes.reverse();
+            }
+            bytes[pos..pos + field_bytes.len()].copy_from_slice(&field_bytes);
+            pos += field_bytes.len();
+        }
+        Some(Self(bytes))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6]
+        )?;
+        write!(f, "{:02x}{:02x}-", b[8], b[9])?;
+        b[10..]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// An entry of the partition table
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PartitionEntry {
+    /// 1-based number of the partition
+    pub index:   usize,
+    /// first sector on the disk
+    pub start:   usize,
+    /// length in sectors
+    pub sectors: usize,
+    /// unique partition GUID, only for GPT partitions
+    pub guid:    Option<Guid>,
+}
+
+fn u32_at(sector: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+fn u64_at(sector: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(sector[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+/// Whether `sector` is the boot sector of a FAT file system, which also ends with 0x55AA
+fn is_fat_boot_sector(sector: &[u8; BLOCK_SZ]) -> bool {
+    &sector[54..57] == b"FAT" || &sector[82..87] == b"FAT32"
+}
+
+/// Parse the partition table of a disk, `read_sector` reads one sector of it
+///
+/// Return an empty list if the disk has no partition table.
+pub fn parse_partition_table(read_sector: &dyn Fn(usize) -> [u8; BLOCK_SZ]) -> Vec<PartitionEntry> {
+    let mbr = read_sector(0);
+    if mbr[510..] != [0x55, 0xAA] || is_fat_boot_sector(&mbr) {
+        return Vec::new();
+    }
+    let records: Vec<&[u8]> = mbr[446..510].chunks(16).collect();
+    // 引导标志只能是 0 或 0x80，否则这不是 MBR
+    if records.iter().any(|record| record[0] & 0x7F != 0) {
+        return Vec::new();
+    }
+    if records.iter().any(|record| record[4] == GPT_PROTECTIVE) {
+        return parse_gpt(read_sector).unwrap_or_default();
+    }
+    records
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| record[4] != 0 && !MBR_EXTENDED.contains(&record[4]))
+        .map(|(i, record)| PartitionEntry {
+            index:   i + 1,
+            start:   u32_at(record, 8),
+            sectors: u32_at(record, 12),
+            guid:    None,
+        })
+        .filter(|entry| entry.start != 0 && entry.sectors != 0)
+        .collect()
+}
+
+/// Parse the GPT whose header is in sector 1, the CRCs are not checked
+fn parse_gpt(read_sector: &dyn Fn(usize) -> [u8; BLOCK_SZ]) -> Option<Vec<PartitionEntry>> {
+    let header = read_sector(1);
+    if &header[..8] != b"EFI PART" {
+        return None;
+    }
+    let entries_lba = u64_at(&header, 72);
+    let entry_cnt = u32_at(&header, 80).min(MAX_GPT_ENTRIES);
+    let entry_size = u32_at(&header, 84);
+    if entry_size < 128 || entry_size > BLOCK_SZ || BLOCK_SZ % entry_size != 0 {
+        return None;
+    }
+    let per_sector = BLOCK_SZ / entry_size;
+    let mut entries = Vec::new();
+    for sector_idx in 0..(entry_cnt + per_sector - 1) / per_sector {
+        let sector = read_sector(entries_lba + sector_idx);
+        for (i, entry) in sector.chunks(entry_size).enumerate() {
+            let index = sector_idx * per_sector + i + 1;
+            // 类型 GUID 全 0 的是空闲表项
+            if index > entry_cnt || entry[..16].iter().all(|byte| *byte == 0) {
+                continue;
+            }
+            let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+            if last < first {
+                continue;
+            }
+            entries.push(PartitionEntry {
+                index,
+                start: first,
+                sectors: last - first + 1,
+                guid: Some(Guid(entry[16..32].try_into().unwrap())),
+            });
+        }
+    }
+    Some(entries)
+}
+
+/// A partition of [`BLOCK_DEVICE`], usable as a block device of its own
+pub struct Partition {
+    pub entry: PartitionEntry,
+    disk:      Arc<dyn ext4_rs::BlockDevice>,
+}
+
+impl Partition {
+    /// Whether the partition starts with an ext4 or FAT32 file system
+    fn has_filesystem(&self) -> bool {
+        let mut sector = [0u8; BLOCK_SZ];
+        self.read_block(0, &mut sector);
+        if is_fat_boot_sector(&sector) {
+            return true;
+        }
+        // ext4 超级块从 1024 字节开始，魔数在其中的 0x38 处
+        self.read_block(2, &mut sector);
+        sector[0x38..0x3A] == [0x53, 0xEF]
+    }
+
+    fn disk_offset(&self, offset: usize) -> usize {
+        self.entry.start * BLOCK_SZ + offset
+    }
+}
+
+impl ext4_rs::BlockDevice for Partition {
+    fn try_read_offset(&self, offset: usize) -> Result<Vec<u8>, Ext4Error> {
+        self.disk.try_read_offset(self.disk_offset(offset))
+    }
+    fn try_write_offset(&self, offset: usize, data: &[u8]) -> Result<(), Ext4Error> {
+        self.disk.try_write_offset(self.disk_offset(offset), data)
+    }
+    fn flush(&self) {
+        self.disk.flush();
+    }
+}
+
+impl BlockDevice for Partition {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let data = self.disk.read_offset(self.disk_offset(block_id * BLOCK_SZ));
+        buf.copy_from_slice(&data[..BLOCK_SZ]);
+    }
+    /// SD 卡驱动一次写一个 ext4 块，所以先读出从这个扇区开始的整块，改写第一个扇区后再写回
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let offset = self.disk_offset(block_id * BLOCK_SZ);
+        let mut data = self.disk.read_offset(offset);
+        data[..BLOCK_SZ].copy_from_slice(buf);
+        self.disk.write_offset(offset, &data);
+    }
+}
+
+/// Which partition holds the root file system, from `root=` in the kernel command line
+#[derive(Clone, Copy)]
+enum RootSpec {
+    Index(usize),
+    Guid(Guid),
+}
+
+static ROOT_SPEC: Mutex<Option<RootSpec>> = Mutex::new(None);
+
+lazy_static! {
+    /// Partitions of [`BLOCK_DEVICE`], the whole device if it has no partition table
+    pub static ref PARTITIONS: Vec<Arc<Partition>> = scan(BLOCK_DEVICE.clone());
+}
+
+fn scan(disk: Arc<dyn ext4_rs::BlockDevice>) -> Vec<Arc<Partition>> {
+    let read_sector = |lba: usize| {
+        let mut sector = [0u8; BLOCK_SZ];
+        sector.copy_from_slice(&disk.read_offset(lba * BLOCK_SZ)[..BLOCK_SZ]);
+        sector
+    };
+    let mut entries = parse_partition_table(&read_sector);
+    if entries.is_empty() {
+        entries.push(PartitionEntry {
+            index:   1,
+            start:   0,
+            sectors: 0,
+            guid:    None,
+        });
+    }
+    entries
+        .into_iter()
+        .map(|entry| {
+            match entry.guid {
+                Some(guid) => info!(
+                    "block: partition {} at sector {}, {} sectors, PARTUUID={}",
+                    entry.index, entry.start, entry.sectors, guid
+                ),
+                None => info!(
+                    "block: partition {} at sector {}, {} sectors",
+                    entry.index, entry.start, entry.sectors
+                ),
+            }
+            Arc::new(Partition {
+                entry,
+                disk: Arc::clone(&disk),
+            })
+        })
+        .collect()
+}
+
+/// Apply `root=N` or `root=PARTUUID=<guid>` in the kernel command line
+pub fn init_from_bootargs(bootargs: &str) {
+    let Some(spec) = bootargs
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("root="))
+    else {
+        return;
+    };
+    let root = match spec.strip_prefix("PARTUUID=") {
+        Some(guid) => Guid::parse(guid).map(RootSpec::Guid),
+        None => spec.parse().ok().map(RootSpec::Index),
+    };
+    if root.is_none() {
+        warn!("unknown root partition {}", spec);
+    }
+    *ROOT_SPEC.lock() = root;
+}
+
+/// The partition the root file system is mounted from
+pub fn root_partition() -> Arc<Partition> {
+    let spec = *ROOT_SPEC.lock();
+    let chosen = PARTITIONS.iter().find(|part| match spec {
+        Some(RootSpec::Index(index)) => part.entry.index == index,
+        Some(RootSpec::Guid(guid)) => part.entry.guid == Some(guid),
+        None => false,
+    });
+    if chosen.is_none() && spec.is_some() {
+        warn!("root partition not found, using the first one with a file system");
+    }
+    chosen
+        .or_else(|| PARTITIONS.iter().find(|part| part.has_filesystem()))
+        .unwrap_or(&PARTITIONS[0])
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec};
+
+    use super::*;
+
+    fn put(disk: &mut [[u8; BLOCK_SZ]], lba: usize, offset: usize, bytes: &[u8]) {
+        disk[lba][offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// An MBR partition record
+    fn record(boot: u8, type_: u8, start: u32, sectors: u32) -> [u8; 16] {
+        let mut record = [0u8; 16];
+        record[0] = boot;
+        record[4] = type_;
+        record[8..12].copy_from_slice(&start.to_le_bytes());
+        record[12..].copy_from_slice(&sectors.to_le_bytes());
+        record
+    }
+
+    #[test_case]
+    fn mbr_partitions() {
+        let mut disk = vec![[0u8; BLOCK_SZ]; 1];
+        // 第 1 项为空，第 2 项是 FAT32（LBA），第 3 项是扩展分区
+        put(&mut disk, 0, 462, &record(0x80, 0x0C, 2048, 65536));
+        put(&mut disk, 0, 478, &record(0, 0x05, 4096, 4096));
+        put(&mut disk, 0, 510, &[0x55, 0xAA]);
+        let entries = parse_partition_table(&|lba| disk[lba]);
+        let expected = PartitionEntry {
+            index:   2,
+            start:   2048,
+            sectors: 65536,
+            guid:    None,
+        };
+        assert_eq!(entries, [expected]);
+    }
+
+    #[test_case]
+    fn gpt_partitions() {
+        let guid = Guid::parse("0FC63DAF-8483-4772-8E79-3D69D8477DE4").unwrap();
+        // 第一段按小端存放
+        assert_eq!(guid.0[..4], [0xAF, 0x3D, 0xC6, 0x0F]);
+        assert_eq!(guid.to_string(), "0fc63daf-8483-4772-8e79-3d69d8477de4");
+        let mut disk = vec![[0u8; BLOCK_SZ]; 3];
+        put(&mut disk, 0, 446, &record(0, GPT_PROTECTIVE, 1, 0xFFFF));
+        put(&mut disk, 0, 510, &[0x55, 0xAA]);
+        put(&mut disk, 1, 0, b"EFI PART");
+        put(&mut disk, 1, 72, &2u64.to_le_bytes());
+        put(&mut disk, 1, 80, &4u32.to_le_bytes());
+        put(&mut disk, 1, 84, &128u32.to_le_bytes());
+        // 第 1 项为空，第 2 项从 2048 到 4095
+        put(&mut disk, 2, 128, &[1; 16]);
+        put(&mut disk, 2, 144, &guid.0);
+        put(&mut disk, 2, 160, &2048u64.to_le_bytes());
+        put(&mut disk, 2, 168, &4095u64.to_le_bytes());
+        let entries = parse_partition_table(&|lba| disk[lba]);
+        let expected = PartitionEntry {
+            index:   2,
+            start:   2048,
+            sectors: 2048,
+            guid:    Some(guid),
+        };
+        assert_eq!(entries, [expected]);
+    }
+}
@@ -15,12 +15,32 @@ pub const PERMISSION_RW: MapPermission = MapPermission::union(MapPermission::R,
 pub const MMIO: &[(usize, usize, MapPermission)] = &[
     (0x10000000, 0x1000, PERMISSION_RW),   // UART
     (0x10001000, 0x1000, PERMISSION_RW),   // VIRTIO
+    (0x10002000, 0x1000, PERMISSION_RW),   // VIRTIO console
+    (0x10003000, 0x1000, PERMISSION_RW),   // VIRTIO net
     (0x02000000, 0x10000, PERMISSION_RW),  // CLINT
     (0x0C000000, 0x400000, PERMISSION_RW), // PLIC
 ];
 
 pub type BlockDeviceImpl = crate::drivers::block::VirtIOBlock;
 
+pub const PLIC_BASE: usize = 0xc00_0000;
+
+/// PLIC context of the S mode of `hart`
+pub fn plic_context(hart: usize) -> usize {
+    hart * 2 + 1
+}
+
+/// ns16550a UART
+pub const UART_BASE: usize = 0x1000_0000;
+/// UART registers are byte aligned
+pub const UART_REG_SHIFT: usize = 0;
+/// virtio-mmio slot probed for a virtio-console
+/// (`-device virtio-serial-device,bus=virtio-mmio-bus.1 -device virtconsole,...`)
+pub const VIRTIO_CONSOLE: Option<usize> = Some(0x1000_2000);
+/// virtio-mmio slot probed for a virtio-net
+/// (`-netdev user,id=net0 -device virtio-net-device,netdev=net0,bus=virtio-mmio-bus.2`)
+pub const VIRTIO_NET: Option<usize> = Some(0x1000_3000);
+
 //ref:: https://github.com/andre-richter/qemu-exit
 use core::arch::asm;
 
@@ -107,3 +127,8 @@ pub fn shutdown() -> ! {
     //todo 处理更多的退出情况
     QEMU_EXIT_HANDLE.exit_success();
 }
+
+/// Exit qemu with `EXIT_FAILURE`
+pub fn shutdown_failure() -> ! {
+    QEMU_EXIT_HANDLE.exit_failure();
+}
@@ -12,11 +12,36 @@ pub const MMIO: &[(usize, usize, MapPermission)] = &[
     (0xc000000, 0x4000000, PERMISSION_RW),    //PLIC
     (0x00_1000_0000, 0x10000, PERMISSION_RW), // UART
     (0x16020000, 0x10000, PERMISSION_RW),     // sdio1
+    (0x2010000, 0x4000, PERMISSION_RW),       // L2 cache controller
 ];
 
 pub type BlockDeviceImpl = crate::drivers::block::SDCard;
 
+pub const PLIC_BASE: usize = 0xc00_0000;
+/// PLIC interrupt source of sdio1
+pub const SDIO_IRQ: usize = 75;
+
+/// PLIC context of the S mode of `hart`, hart 0 (S7) only has M mode
+pub fn plic_context(hart: usize) -> usize {
+    hart * 2
+}
+
+/// dw-apb-uart (8250 compatible)
+pub const UART_BASE: usize = 0x1000_0000;
+/// UART registers are 32 bits wide
+pub const UART_REG_SHIFT: usize = 2;
+/// no virtio devices on the board
+pub const VIRTIO_CONSOLE: Option<usize> = None;
+/// the on-board ethernet has no driver yet
+pub const VIRTIO_NET: Option<usize> = None;
+
 pub fn shutdown() -> ! {
     // 直接死循环
     loop {}
 }
+
+/// Exit with `EXIT_FAILURE`, for failed tests
+pub fn shutdown_failure() -> ! {
+    // 板子上没有退出设备，同样死循环
+    loop {}
+}
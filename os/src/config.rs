@@ -4,10 +4,14 @@
 
 /// user app's stack size
 pub const USER_STACK_SIZE: usize = 4096 * 20;
+/// largest user stack exec maps when RLIMIT_STACK is raised
+pub const MAX_USER_STACK_SIZE: usize = 8 * 1024 * 1024;
 /// kernel stack size
 pub const KERNEL_STACK_SIZE: usize = 4096 * 8;
 /// kernel heap size
 pub const KERNEL_HEAP_SIZE: usize = PAGE_SIZE * 0x500;
+/// space reserved for the kernel symbol table written by `ksyms.sh`
+pub const KSYMS_SIZE: usize = 0x10_0000;
 /// physical memory end address
 #[cfg(feature = "qemu")]
 pub const MEMORY_END: usize = 0xffff_ffc0_88000000;
@@ -45,6 +49,18 @@ pub const SYS_VERSION: &str = "#1-Chaos RISC-V 64bit Version 0.0.1";
 pub const STACK_TOP: usize = 0x1_0000_0000;
 ///
 pub const MMAP_BASE: usize = 0x2000_0000;
+/// load base of position-independent (ET_DYN) executables
+pub const ELF_DYN_BASE: usize = 0x1000_0000;
+/// max random pages added to `ELF_DYN_BASE` when aslr is enabled
+pub const ELF_DYN_ASLR_PAGES: usize = 0x400;
+/// load base of the dynamic linker (PT_INTERP), far above the heap and the mmap region
+pub const INTERP_BASE: usize = 0x30_0000_0000;
+/// user stacks of the threads cloned without one, a stack and a guard page per tid
+pub const THREAD_STACK_BASE: usize = 0x20_0000_0000;
+/// shut down through SBI after a panic, otherwise spin so the state can be inspected with gdb
+pub const PANIC_SHUTDOWN: bool = true;
+/// max number of harts
+pub const MAX_HARTS: usize = 4;
 /// SV39
 pub const PAGE_TABLE_LEVEL: usize = 3;
 /// kernel space offset
@@ -52,8 +68,12 @@ pub const KERNEL_SPACE_OFFSET: usize = 0xffff_ffc0_0000_0;
 
 pub const TRAP_CONTEXT_TRAMPOLINE: usize = 0xFFFF_FFFF_FFFF_E000;
 
-/// user trampoline
-pub const USER_TRAMPOLINE: usize = 0x191_9810;
+/// start of the window used by the `guard_heap` feature
+pub const GUARD_AREA_BASE: usize = 0xFFFF_FFFF_8000_0000;
+
+/// user page holding the sigreturn trampoline, the return address of signal handlers
+/// without SA_RESTORER
+pub const USER_TRAMPOLINE: usize = 0x191_9000;
 
 #[no_mangle]
 #[inline(never)]
@@ -1,15 +1,138 @@
 //! SBI console driver, for text output
+//!
+//! Output goes through the early console (raw SBI putchar, no lock, no
+//! allocation) until [`init`] switches to the main console. The panic handler
+//! always uses the early console so it works at any point of boot.
+//!
+//! The main console fans out to every enabled sink: SBI console, the UART
+//! driven directly, and virtio-console when qemu provides one.
+//!
+//! Text for the main console is first copied into a per-hart staging buffer
+//! with interrupts off, then drained by whoever gets the console lock. A
+//! writer never spins on the console lock, so printing from an interrupt
+//! handler that preempted the lock holder cannot deadlock.
 use core::{
     arch::asm,
+    cell::UnsafeCell,
     fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
 };
 
-use crate::sbi::console_putchar;
+use crate::{
+    config::MAX_HARTS,
+    drivers::{uart, virtio_console},
+    logging::kmsg::{self, DEFAULT_MESSAGE_LEVEL},
+    sbi::console_putchar,
+    sync::{mutex::SpinNoIrqLock, IrqGuard},
+    task::hart_id,
+};
+
+bitflags! {
+    /// Sinks of the main console
+    pub struct ConsoleSinks: u8 {
+        /// SBI console_putchar
+        const SBI = 1 << 0;
+        /// UART registers written directly
+        const UART = 1 << 1;
+        /// virtio-console
+        const VIRTIO = 1 << 2;
+    }
+}
 
 struct Stdout;
 
-impl Write for Stdout {
-    /// write str to console
+impl Stdout {
+    /// write a byte to every enabled sink
+    fn putchar(&mut self, c: u8) {
+        let sinks = ConsoleSinks::from_bits_truncate(SINKS.load(Ordering::Relaxed));
+        if sinks.contains(ConsoleSinks::SBI) {
+            console_putchar(c as usize);
+        }
+        if sinks.contains(ConsoleSinks::UART) {
+            uart::putchar(c);
+        }
+        if sinks.contains(ConsoleSinks::VIRTIO) {
+            virtio_console::putchar(c);
+        }
+    }
+}
+
+/// size of a per-hart staging buffer
+const STAGING_LEN: usize = 4096;
+
+/// Single producer (its hart, with interrupts off) single consumer (the
+/// console lock holder) byte ring
+struct StagingBuffer {
+    buf:     UnsafeCell<[u8; STAGING_LEN]>,
+    /// next position to write, only moved by the producer
+    head:    AtomicUsize,
+    /// next position to read, only moved by the consumer
+    tail:    AtomicUsize,
+    /// bytes dropped because the buffer was full
+    dropped: AtomicUsize,
+}
+
+unsafe impl Sync for StagingBuffer {}
+
+impl StagingBuffer {
+    const fn new() -> Self {
+        Self {
+            buf:     UnsafeCell::new([0; STAGING_LEN]),
+            head:    AtomicUsize::new(0),
+            tail:    AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+    /// Producer side, bytes that do not fit are dropped
+    fn push(&self, bytes: &[u8]) {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let len = bytes.len().min(STAGING_LEN - (head - tail));
+        let buf = unsafe { &mut *self.buf.get() };
+        for (i, &byte) in bytes[..len].iter().enumerate() {
+            buf[(head + i) % STAGING_LEN] = byte;
+        }
+        self.head.store(head + len, Ordering::Release);
+        if len < bytes.len() {
+            self.dropped.fetch_add(bytes.len() - len, Ordering::Relaxed);
+        }
+    }
+    /// Consumer side, must hold the console lock
+    fn drain(&self, stdout: &mut Stdout) {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Relaxed);
+        let buf = unsafe { &*self.buf.get() };
+        for pos in tail..head {
+            stdout.putchar(buf[pos % STAGING_LEN]);
+        }
+        self.tail.store(head, Ordering::Release);
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped != 0 {
+            for c in b"\n[console] staging buffer full, output dropped\n" {
+                stdout.putchar(*c);
+            }
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+impl Write for &StagingBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+const EMPTY_STAGING: StagingBuffer = StagingBuffer::new();
+/// per-hart staging buffers
+static STAGING: [StagingBuffer; MAX_HARTS] = [EMPTY_STAGING; MAX_HARTS];
+
+/// Early console, writes straight to SBI putchar
+struct EarlyConsole;
+
+impl Write for EarlyConsole {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for c in s.chars() {
             console_putchar(c as usize);
@@ -17,9 +140,100 @@ impl Write for Stdout {
         Ok(())
     }
 }
-/// print to the host console using the format string and arguments.
+
+/// whether output still goes through the early console
+static EARLY_CONSOLE: AtomicBool = AtomicBool::new(true);
+
+/// main console, drained from the staging buffers by the lock holder
+static STDOUT: SpinNoIrqLock<Stdout> = SpinNoIrqLock::new(Stdout);
+
+/// enabled `ConsoleSinks`
+static SINKS: AtomicU8 = AtomicU8::new(ConsoleSinks::SBI.bits);
+
+/// Probe console devices and switch from the early console to the main console
+///
+/// SBI 和直接写 UART 在同一个串口上输出，默认只启用 SBI，
+/// 存在 virtio-console 时同时输出到 qemu 窗口
+pub fn init() {
+    if virtio_console::init() {
+        enable_sinks(ConsoleSinks::VIRTIO, true);
+    }
+    EARLY_CONSOLE.store(false, Ordering::Release);
+}
+
+/// Enable or disable sinks of the main console
+pub fn enable_sinks(sinks: ConsoleSinks, enable: bool) {
+    if enable {
+        SINKS.fetch_or(sinks.bits, Ordering::Relaxed);
+    } else {
+        SINKS.fetch_and(!sinks.bits, Ordering::Relaxed);
+    }
+}
+
+/// Apply `console=sbi,uart,virtio` in the kernel command line
+pub fn init_from_bootargs(bootargs: &str) {
+    let Some(spec) = bootargs
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("console="))
+    else {
+        return;
+    };
+    let mut sinks = ConsoleSinks::empty();
+    for name in spec.split(',') {
+        match name {
+            "sbi" => sinks |= ConsoleSinks::SBI,
+            "uart" => sinks |= ConsoleSinks::UART,
+            "virtio" => sinks |= ConsoleSinks::VIRTIO,
+            _ => warn!("unknown console {}", name),
+        }
+    }
+    // 至少保留一个输出
+    if !sinks.is_empty() {
+        SINKS.store(sinks.bits, Ordering::Relaxed);
+    }
+}
+
+/// print to the host console using the format string and arguments,
+/// and record it into the kernel log buffer.
 pub fn print(args: fmt::Arguments) {
-    Stdout.write_fmt(args).unwrap();
+    console_print(args);
+    kmsg::record(DEFAULT_MESSAGE_LEVEL, args);
+}
+
+/// print to the host console only.
+pub fn console_print(args: fmt::Arguments) {
+    if EARLY_CONSOLE.load(Ordering::Acquire) {
+        early_print(args);
+        return;
+    }
+    {
+        // 关中断后本 hart 是暂存缓冲区唯一的写者
+        let _guard = IrqGuard::new();
+        let _ = (&STAGING[hart_id() % MAX_HARTS]).write_fmt(args);
+    }
+    flush();
+}
+
+/// Drain all staging buffers if nobody else holds the console lock
+pub fn flush() {
+    loop {
+        match STDOUT.try_lock() {
+            Some(mut stdout) => STAGING
+                .iter()
+                .for_each(|staging| staging.drain(&mut stdout)),
+            // 持锁者释放前会再检查一遍暂存缓冲区
+            None => return,
+        }
+        // 释放锁之后其他写者可能刚刚暂存了数据但没拿到锁
+        if STAGING.iter().all(StagingBuffer::is_empty) {
+            return;
+        }
+    }
+}
+
+/// print through the early console, never blocks on a lock
+pub fn early_print(args: fmt::Arguments) {
+    let _ = EarlyConsole.write_fmt(args);
 }
 
 /// Print! macro to the host console using the format string and arguments.
@@ -37,3 +251,11 @@ macro_rules! println {
         $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?))
     }
 }
+
+/// Println through the early console, usable before init and in the panic handler
+#[macro_export]
+macro_rules! early_println {
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::console::early_print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?))
+    }
+}
@@ -3,32 +3,219 @@
 mod vf2_sd;
 mod virtio_blk;
 
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(feature = "visionfive2")]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use lazy_static::*;
+use spin::Mutex;
 pub use vf2_sd::SDCard;
-pub use virtio_blk::VirtIOBlock;
+pub use virtio_blk::{VirtIOBlock, VirtioHal};
+#[cfg(feature = "visionfive2")]
+use visionfive2_sd::{CardEvent, CARD_DEBOUNCE_MS};
 
-use crate::{block::block_dev::BlockDevice, boards::BlockDeviceImpl};
+#[cfg(feature = "visionfive2")]
+use crate::timer::{get_time, sleep_until, TimeSpec};
+use crate::{
+    block::BLOCK_SZ,
+    boards::BlockDeviceImpl,
+    sync::{IrqGuard, SleepLock},
+    task::{current_task, TaskControlBlock},
+};
 
 lazy_static! {
+    /// The block device of the board, for device specific operations
+    static ref BLOCK_DEVICE_IMPL: Arc<BlockDeviceImpl> = Arc::new(BlockDeviceImpl::new());
     /// The global block device driver instance: BLOCK_DEVICE with BlockDevice trait
-    pub static ref BLOCK_DEVICE: Arc<dyn ext4_rs::BlockDevice> = Arc::new(BlockDeviceImpl::new());
+    pub static ref BLOCK_DEVICE: Arc<dyn ext4_rs::BlockDevice> = BLOCK_DEVICE_IMPL.clone();
+    /// Tasks inside [`may_sleep_on_io`], by the address of their TCB
+    static ref IO_SLEEPERS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
 }
 
+/// The card detect state changed, see [`handle_media_change`]
+#[cfg(feature = "visionfive2")]
+static MEDIA_CHANGED: AtomicBool = AtomicBool::new(false);
+/// The last change was an insertion, the card has to be identified
+#[cfg(feature = "visionfive2")]
+static CARD_INSERTED: AtomicBool = AtomicBool::new(false);
+
+/// Run `f`, letting the block transfers it makes sleep until their interrupt
+///
+/// 等待传输时其他任务会运行，文件系统通过 [`IoLock::run`] 进入这里，
+/// 两个任务不会交错修改同一个位图之类的元数据。
+pub fn may_sleep_on_io<R>(f: impl FnOnce() -> R) -> R {
+    let Some(task) = current_task() else {
+        return f();
+    };
+    let id = Arc::as_ptr(&task) as usize;
+    IO_SLEEPERS.lock().push(id);
+    let ret = f();
+    let mut sleepers = IO_SLEEPERS.lock();
+    if let Some(pos) = sleepers.iter().position(|&t| t == id) {
+        sleepers.swap_remove(pos);
+    }
+    ret
+}
+
+/// Whether `task` may sleep in a block transfer now
+///
+/// 持有 SpinNoIrqLock 时（如 mmap 借用着任务的 inner 和地址空间读文件页）不能睡眠，传输忙等。
+fn io_may_sleep(task: &Arc<TaskControlBlock>) -> bool {
+    IrqGuard::depth() == 0 && IO_SLEEPERS.lock().contains(&(Arc::as_ptr(task) as usize))
+}
+
+/// Serializes the I/O of one filesystem, so that its block transfers may sleep
+///
+/// 持锁的任务等待传输时，其他任务进入同一个文件系统会在锁上睡眠。
+/// 同一任务可以嵌套进入，比如 rename 中删除被替换的文件。
+/// 文件系统自己的自旋锁（inode 锁等）只在锁内获取，睡眠时持有它们不会让别的任务空转。
+pub struct IoLock {
+    lock:  SleepLock<()>,
+    /// the task holding `lock`, by the address of its TCB
+    owner: AtomicUsize,
+}
+
+impl IoLock {
+    pub fn new() -> Self {
+        Self {
+            lock:  SleepLock::new(()),
+            owner: AtomicUsize::new(0),
+        }
+    }
+
+    /// Run `f` holding the lock, its block transfers sleep until their interrupt
+    ///
+    /// 持有 SpinNoIrqLock 的调用者不能睡眠，只能忙等持锁的任务在其他 hart 上完成传输后释放锁。
+    /// 只有 SD 卡的传输会睡眠，单核的 QEMU 上锁不会被争用。
+    pub fn run<R>(&self, f: impl FnOnce() -> R) -> R {
+        let id = current_task().map_or(0, |task| Arc::as_ptr(&task) as usize);
+        if id != 0 && self.owner.load(Ordering::Acquire) == id {
+            return f();
+        }
+        let _guard = if IrqGuard::depth() == 0 {
+            self.lock.lock()
+        } else {
+            loop {
+                if let Some(guard) = self.lock.try_lock() {
+                    break guard;
+                }
+                poll_block_io();
+                core::hint::spin_loop();
+            }
+        };
+        self.owner.store(id, Ordering::Release);
+        let ret = may_sleep_on_io(f);
+        self.owner.store(0, Ordering::Release);
+        ret
+    }
+}
+
+impl Default for IoLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Check the SD slot for a removed or inserted card, called on timer interrupts
+///
+/// 这里只记下卡检测寄存器的变化，识别新卡要忙等上百毫秒，留给 [`handle_media_change`]。
+#[cfg(feature = "visionfive2")]
+pub fn poll_media_change() {
+    match BLOCK_DEVICE_IMPL.poll_card() {
+        Some(CardEvent::Removed) => {
+            warn!("block: media removed");
+            CARD_INSERTED.store(false, Ordering::Relaxed);
+            MEDIA_CHANGED.store(true, Ordering::Release);
+        }
+        Some(CardEvent::Inserted) => {
+            info!("block: media inserted");
+            CARD_INSERTED.store(true, Ordering::Relaxed);
+            MEDIA_CHANGED.store(true, Ordering::Release);
+        }
+        None => {}
+    }
+}
+
+/// Identify a newly inserted card and let the file systems drop their caches, after
+/// [`poll_media_change`] saw the media change
+///
+/// 在系统调用返回前调用：可以睡眠，也不持有任何锁。
+#[cfg(feature = "visionfive2")]
+pub fn handle_media_change() {
+    if !MEDIA_CHANGED.swap(false, Ordering::Acquire) {
+        return;
+    }
+    if CARD_INSERTED.swap(false, Ordering::Relaxed) {
+        // 等卡在卡槽里插稳再识别
+        let expire = get_time() + TimeSpec::from_ms(CARD_DEBOUNCE_MS).to_tick();
+        while !sleep_until(expire) {}
+        match BLOCK_DEVICE_IMPL.identify() {
+            Ok(()) => info!("block: inserted card identified"),
+            Err(err) => error!("block: inserted card unusable: {}", err),
+        }
+    }
+    crate::fs::media_changed();
+}
+
+/// virtio 块设备不会被拔出
+#[cfg(feature = "qemu")]
+pub fn poll_media_change() {}
+
+#[cfg(feature = "qemu")]
+pub fn handle_media_change() {}
+
+/// Let the SD controller finish transfers with its interrupt
+#[cfg(feature = "visionfive2")]
+pub fn enable_irq() {
+    BLOCK_DEVICE_IMPL.enable_irq();
+}
+
+/// SDIO interrupt: finish the transfer in flight and wake the tasks waiting for it
+#[cfg(feature = "visionfive2")]
+pub fn handle_irq() {
+    BLOCK_DEVICE_IMPL.handle_irq();
+}
+
+/// Whether a task is blocked on a block transfer, the scheduler must not give up then
+#[cfg(feature = "visionfive2")]
+pub fn block_io_pending() -> bool {
+    BLOCK_DEVICE_IMPL.io_pending()
+}
+
+/// virtio 块设备的读写是同步的
+#[cfg(feature = "qemu")]
+pub fn block_io_pending() -> bool {
+    false
+}
+
+/// Finish a block transfer whose interrupt was lost, or give it up after its timeout,
+/// called on timer interrupts and when idle
+#[cfg(feature = "visionfive2")]
+pub fn poll_block_io() {
+    BLOCK_DEVICE_IMPL.poll_io();
+}
+
+#[cfg(feature = "qemu")]
+pub fn poll_block_io() {}
+
 #[allow(unused)]
 /// Test the block device
+///
+/// 文件系统镜像已经挂在该设备上：每块写入测试数据并读回后立即恢复原来的内容，
+/// 检查放在恢复之后，断言失败也不会破坏磁盘内容
 pub fn block_device_test() {
     let block_device = BLOCK_DEVICE.clone();
-    let mut write_buffer = [0u8; 512];
-    let mut read_buffer = [0u8; 512];
-    for i in 0..512 {
-        for byte in write_buffer.iter_mut() {
-            *byte = i as u8;
-        }
-        // block_device.write_block(i as usize, &write_buffer);
-        // block_device.read_block(i as usize, &mut read_buffer);
-        assert_eq!(write_buffer, read_buffer);
+    for i in 0..16 {
+        let offset = i * BLOCK_SZ;
+        let origin = block_device.read_offset(offset);
+        let pattern: Vec<u8> = (0..BLOCK_SZ).map(|j| (i + j) as u8 ^ 0x5a).collect();
+        block_device.write_offset(offset, &pattern);
+        let written = block_device.read_offset(offset);
+        block_device.write_offset(offset, &origin[..BLOCK_SZ]);
+        let restored = block_device.read_offset(offset);
+        assert_eq!(pattern[..], written[..BLOCK_SZ]);
+        assert_eq!(origin[..BLOCK_SZ], restored[..BLOCK_SZ]);
     }
-    println!("block device test passed!");
+    info!("block device test passed!");
 }
@@ -1,16 +1,28 @@
-use alloc::vec::Vec;
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use ext4_rs::{BlockDevice, BLOCK_SIZE};
+use ext4_rs::{BlockDevice, Errnum, Ext4Error, BLOCK_SIZE};
 use spin::Mutex;
 use visionfive2_sd::*;
 
+use super::io_may_sleep;
 use crate::{
     block::BLOCK_SZ,
-    timer::{sleep_ms, sleep_ms_until},
+    config::{KERNEL_SPACE_OFFSET, PAGE_SIZE},
+    drivers::random,
+    mm::{VirtAddr, KERNEL_SPACE},
+    task::{block_current_in_syscall, current_task, wakeup_task, TaskControlBlock},
+    timer::{get_time_ms, sleep_ms, sleep_ms_until},
 };
 
 pub struct SdIoImpl;
 pub const SDIO_BASE: usize = 0xffffffc016020000;
+/// Flush64 register of the L2 cache controller, writing a physical address
+/// writes back and invalidates its line
+const CCACHE_FLUSH64: usize = 0x0201_0200 + KERNEL_SPACE_OFFSET * PAGE_SIZE;
+const CACHE_LINE_SIZE: usize = 64;
+/// A submitted transfer without its interrupt after this long is given up
+const TRANSFER_TIMEOUT_MS: usize = 1000;
 
 impl SDIo for SdIoImpl {
     fn read_data_at(&self, offset: usize) -> u64 {
@@ -29,6 +41,30 @@ impl SDIo for SdIoImpl {
         let addr = (SDIO_BASE + offset) as *mut u32;
         unsafe { addr.write_volatile(val) }
     }
+    fn virt_to_phys(&self, vaddr: usize) -> Option<usize> {
+        // 内核栈不在线性映射区内，统一查内核页表
+        KERNEL_SPACE
+            .lock()
+            .page_table
+            .translate_va(VirtAddr::from(vaddr))
+            .map(|pa| pa.0)
+    }
+    fn dma_sync(&self, vaddr: usize, len: usize) {
+        // JH7110 的 DMA 与 cache 不一致，按行刷 L2（L2 包含 L1）
+        let start = vaddr & !(CACHE_LINE_SIZE - 1);
+        unsafe { core::arch::asm!("fence rw, rw") };
+        let mut page = None;
+        for line in (start..vaddr + len).step_by(CACHE_LINE_SIZE) {
+            if line == start || line % PAGE_SIZE == 0 {
+                page = self.virt_to_phys(line & !(PAGE_SIZE - 1));
+            }
+            if let Some(pa) = page {
+                let pa = pa + line % PAGE_SIZE;
+                unsafe { (CCACHE_FLUSH64 as *mut u64).write_volatile(pa as u64) };
+            }
+        }
+        unsafe { core::arch::asm!("fence rw, rw") };
+    }
 }
 
 pub struct SleepOpsImpl;
@@ -42,41 +78,191 @@ impl SleepOps for SleepOpsImpl {
     }
 }
 
-pub struct SDCard(Mutex<Vf2SdDriver<SdIoImpl, SleepOpsImpl>>);
+type Driver = Vf2SdDriver<SdIoImpl, SleepOpsImpl>;
+
+pub struct SDCard {
+    driver:   Mutex<Driver>,
+    /// tasks waiting for the submitted transfer to finish or for the driver to be free
+    waiters:  Mutex<VecDeque<Arc<TaskControlBlock>>>,
+    /// time in ms when the submitted transfer is given up
+    deadline: AtomicUsize,
+}
 
 impl SDCard {
     pub fn new() -> Self {
         debug!("SDCard::new()");
         let mut sd = Vf2SdDriver::<_, SleepOpsImpl>::new(SdIoImpl);
-        sd.init();
-        Self(Mutex::new(sd))
+        sd.set_conservative(cfg!(feature = "sd_slow"));
+        sd.init().expect("SDCard: init failed");
+        // CID 含有每张卡不同的序列号
+        if let Some(cid) = sd.cid() {
+            random::add_entropy(&cid.to_le_bytes());
+        }
+        Self {
+            driver:   Mutex::new(sd),
+            waiters:  Mutex::new(VecDeque::new()),
+            deadline: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl SDCard {
+    /// Poll the card detect state, `None` if nothing changed or the driver is busy
+    ///
+    /// 只读寄存器，可以在时钟中断里调用；插入的卡由 [`Self::identify`] 识别。
+    pub fn poll_card(&self) -> Option<CardEvent> {
+        self.driver.try_lock()?.poll_card()
+    }
+
+    /// Identify the card inserted in the slot, busy-waiting for up to a second
+    pub fn identify(&self) -> Result<()> {
+        let mut driver = self.driver.lock();
+        driver.init()?;
+        if let Some(cid) = driver.cid() {
+            random::add_entropy(&cid.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    /// Unmask the SDIO interrupt line for submitted transfers
+    pub fn enable_irq(&self) {
+        self.driver.lock().enable_interrupts();
+    }
+
+    /// Move `len` bytes at `vaddr` from or to the card, sleeping until the SDIO interrupt
+    /// if the caller is inside [`super::may_sleep_on_io`]
+    ///
+    /// 其他情况（初始化阶段、缓冲区不能 DMA、传输出错）在持有驱动锁时改用会重试的忙等读写
+    /// `blocking`。
+    fn transfer(
+        &self, block: usize, vaddr: usize, len: usize, dir: Direction,
+        blocking: impl FnOnce(&mut Driver) -> Result<usize>,
+    ) -> Result<usize> {
+        let Some(task) = current_task().filter(io_may_sleep) else {
+            return self.transfer_blocking(blocking);
+        };
+        let mut driver = loop {
+            let driver = self.driver.lock();
+            if driver.idle() {
+                break driver;
+            }
+            drop(driver);
+            self.sleep(&task);
+        };
+        // 缓冲区在 complete 返回前不会被访问
+        if unsafe { driver.submit(block, vaddr, len, dir) }.is_err() {
+            return blocking(&mut driver);
+        }
+        self.deadline
+            .store(get_time_ms() + TRANSFER_TIMEOUT_MS, Ordering::Relaxed);
+        // 内核态关中断，释放锁和阻塞之间不会错过唤醒
+        let result = loop {
+            if let Some(result) = driver.complete() {
+                break result;
+            }
+            drop(driver);
+            self.sleep(&task);
+            driver = self.driver.lock();
+        };
+        random::add_timing_entropy();
+        let result = result.or_else(|err| {
+            warn!("SDCard: transfer failed: {}, retrying", err);
+            blocking(&mut driver)
+        });
+        drop(driver);
+        self.wake_waiters();
+        result
+    }
+
+    /// Busy-wait for the transfer, after the one another task submitted
+    fn transfer_blocking(
+        &self, blocking: impl FnOnce(&mut Driver) -> Result<usize>,
+    ) -> Result<usize> {
+        let mut driver = self.driver.lock();
+        if driver.busy() {
+            // 提交它的任务在我们返回前不会运行，只能在这里等它结束，结果留给它取
+            sleep_ms_until(TRANSFER_TIMEOUT_MS, || driver.handle_interrupt());
+            driver.cancel();
+            self.wake_waiters();
+        }
+        blocking(&mut driver)
+    }
+
+    /// Block the current task until [`Self::wake_waiters`]
+    ///
+    /// 调用者（如 sys_read）可能正在直接访问用户缓冲区。
+    fn sleep(&self, task: &Arc<TaskControlBlock>) {
+        self.waiters.lock().push_back(task.clone());
+        block_current_in_syscall();
+    }
+
+    fn wake_waiters(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        waiters.into_iter().for_each(wakeup_task);
+    }
+
+    /// SDIO interrupt handler
+    pub fn handle_irq(&self) {
+        // 中断只在用户态或空闲时处理，此时没有任务持有驱动锁
+        let Some(mut driver) = self.driver.try_lock() else {
+            return;
+        };
+        if driver.handle_interrupt() {
+            drop(driver);
+            self.wake_waiters();
+        }
+    }
+
+    /// Poll the submitted transfer in case its interrupt was lost, and give it up
+    /// after [`TRANSFER_TIMEOUT_MS`]
+    pub fn poll_io(&self) {
+        let Some(mut driver) = self.driver.try_lock() else {
+            return;
+        };
+        let finished = driver.handle_interrupt();
+        let expired = driver.busy() && get_time_ms() >= self.deadline.load(Ordering::Relaxed);
+        if expired {
+            driver.cancel();
+        }
+        drop(driver);
+        if finished || expired {
+            self.wake_waiters();
+        }
+    }
+
+    /// Whether a task is blocked in [`Self::transfer`]
+    pub fn io_pending(&self) -> bool {
+        !self.waiters.lock().is_empty()
     }
 }
 
 impl BlockDevice for SDCard {
-    fn read_offset(&self, offset: usize) -> Vec<u8> {
+    fn try_read_offset(&self, offset: usize) -> core::result::Result<Vec<u8>, Ext4Error> {
         let mut buf = [0u8; BLOCK_SIZE];
-        let mut block_id = offset / BLOCK_SZ;
-        let mut buf_offset = 0;
-        for _ in 0..8 {
-            self.0
-                .lock()
-                .read_block(block_id, buf[buf_offset..buf_offset + BLOCK_SZ].as_mut());
-            block_id += 1;
-            buf_offset += BLOCK_SZ;
+        let block = offset / BLOCK_SZ;
+        let vaddr = buf.as_ptr() as usize;
+        // 一次 CMD18 读出整个 ext4 块
+        let res = self.transfer(block, vaddr, BLOCK_SIZE, Direction::Read, |driver| {
+            driver.read_blocks(block, &mut buf)
+        });
+        if let Err(err) = res {
+            error!("SDCard: read at {:#x} failed: {}", offset, err);
+            return Err(Ext4Error::new(Errnum::EIO));
         }
         // debug!("read_offset = {:#x}, buf = {:x?}", offset, buf);
-        buf[offset % BLOCK_SZ..].to_vec()
-    }
-    fn write_offset(&self, offset: usize, data: &[u8]) {
-        let mut block_id = offset / BLOCK_SZ;
-        let mut data_offset = 0;
-        for _ in 0..8 {
-            self.0
-                .lock()
-                .write_block(block_id, data[data_offset..data_offset + BLOCK_SZ].as_ref());
-            block_id += 1;
-            data_offset += BLOCK_SZ;
+        Ok(buf[offset % BLOCK_SZ..].to_vec())
+    }
+    fn try_write_offset(&self, offset: usize, data: &[u8]) -> core::result::Result<(), Ext4Error> {
+        let block = offset / BLOCK_SZ;
+        let data = &data[..BLOCK_SIZE];
+        let vaddr = data.as_ptr() as usize;
+        let res = self.transfer(block, vaddr, BLOCK_SIZE, Direction::Write, |driver| {
+            driver.write_blocks(block, data)
+        });
+        if let Err(err) = res {
+            error!("SDCard: write at {:#x} failed: {}", offset, err);
+            return Err(Ext4Error::new(Errnum::EIO));
         }
+        Ok(())
     }
 }
@@ -1,7 +1,7 @@
 use alloc::vec::Vec;
 use core::ptr::NonNull;
 
-use ext4_rs::BLOCK_SIZE;
+use ext4_rs::{Errnum, Ext4Error, BLOCK_SIZE};
 use lazy_static::*;
 use spin::Mutex;
 use virtio_drivers::{
@@ -12,10 +12,10 @@ use virtio_drivers::{
 };
 
 // use virtio_drivers::{Hal, VirtIOBlk, VirtIOHeader};
-use super::BlockDevice;
 use crate::{
-    block::BLOCK_SZ,
+    block::{block_dev::BlockDevice, BLOCK_SZ},
     config::{KERNEL_SPACE_OFFSET, PAGE_SIZE},
+    drivers::random,
     mm::{
         frame_alloc_contiguous,
         frame_dealloc,
@@ -26,7 +26,7 @@ use crate::{
         VirtAddr,
         KERNEL_SPACE,
     },
-    sync::UPSafeCell,
+    sync::SpinNoIrqLock,
 };
 
 #[allow(unused)]
@@ -36,7 +36,7 @@ pub struct VirtIOBlock(Mutex<VirtIOBlk<VirtioHal, MmioTransport>>);
 
 lazy_static! {
     /// The global io data queue for virtio_blk device
-    static ref QUEUE_FRAMES: UPSafeCell<Vec<FrameTracker>> = unsafe { UPSafeCell::new(Vec::new()) };
+    static ref QUEUE_FRAMES: SpinNoIrqLock<Vec<FrameTracker>> = SpinNoIrqLock::new(Vec::new());
 }
 
 unsafe impl Send for VirtIOBlock {}
@@ -78,19 +78,28 @@ impl BlockDevice for VirtIOBlock {
 }
 
 impl ext4_rs::BlockDevice for VirtIOBlock {
-    fn read_offset(&self, offset: usize) -> Vec<u8> {
+    fn try_read_offset(&self, offset: usize) -> Result<Vec<u8>, Ext4Error> {
         // debug!("read_offset: offset = {:#x}", offset);
         let mut buf = [0u8; BLOCK_SIZE];
         self.0
             .lock()
             .read_blocks(offset / BLOCK_SZ, &mut buf)
-            .expect("Error when reading VirtIOBlk");
+            .map_err(|err| {
+                error!("VirtIOBlk: read at {:#x} failed: {:?}", offset, err);
+                Ext4Error::new(Errnum::EIO)
+            })?;
+        // QEMU 上没有外部中断，用读盘完成的时刻补充熵池
+        random::add_timing_entropy();
         // debug!("read_offset = {:#x}, buf = {:x?}", offset, buf);
-        buf[offset % BLOCK_SZ..].to_vec()
+        Ok(buf[offset % BLOCK_SZ..].to_vec())
     }
-    fn write_offset(&self, offset: usize, data: &[u8]) {
+    fn try_write_offset(&self, offset: usize, data: &[u8]) -> Result<(), Ext4Error> {
         debug!("write_offset: offset = {:#x}", offset);
         //     debug!("data len = {:#x}", data.len());
+        let io_error = |err| {
+            error!("VirtIOBlk: write at {:#x} failed: {:?}", offset, err);
+            Ext4Error::new(Errnum::EIO)
+        };
         let mut write_size = 0;
         while write_size < data.len() {
             let block_id = (offset + write_size) / BLOCK_SZ;
@@ -100,15 +109,22 @@ impl ext4_rs::BlockDevice for VirtIOBlock {
             self.0
                 .lock()
                 .read_blocks(block_id, &mut buf)
-                .expect("Error when reading VirtIOBlk");
+                .map_err(io_error)?;
             buf[block_offset..block_offset + copy_size]
                 .copy_from_slice(&data[write_size..write_size + copy_size]);
             self.0
                 .lock()
                 .write_blocks(block_id, &buf)
-                .expect("Error when writing VirtIOBlk");
+                .map_err(io_error)?;
             write_size += copy_size;
         }
+        Ok(())
+    }
+    /// 宿主机的磁盘镜像可能开着写缓存，fsync 时要求设备落盘
+    fn flush(&self) {
+        if self.0.lock().flush().is_err() {
+            error!("Error when flushing VirtIOBlk");
+        }
     }
 }
 
@@ -172,7 +188,7 @@ unsafe impl Hal for VirtioHal {
     unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> virtio_drivers::PhysAddr {
         unsafe {
             KERNEL_SPACE
-                .exclusive_access(file!(), line!())
+                .lock()
                 .page_table
                 .translate_va(VirtAddr::from(buffer.as_ptr() as *const usize as usize))
                 .unwrap()
@@ -1,5 +1,51 @@
-//! block device driver
+//! device drivers
 
 pub mod block;
+pub mod net;
+pub mod plic;
+pub mod random;
+pub mod uart;
+pub mod virtio_console;
 
-pub use block::BLOCK_DEVICE;
+pub use block::{
+    block_io_pending,
+    handle_media_change,
+    poll_block_io,
+    poll_media_change,
+    IoLock,
+    BLOCK_DEVICE,
+};
+
+/// Route the device interrupts of the board to this hart
+///
+/// 内核态始终关中断，外部中断只在用户态或空闲等待时处理。
+pub fn init_irq() {
+    #[cfg(feature = "visionfive2")]
+    {
+        plic::enable(crate::boards::SDIO_IRQ);
+        block::enable_irq();
+        unsafe { riscv::register::sie::set_sext() };
+    }
+}
+
+/// Claim and dispatch all pending external interrupts
+pub fn handle_irq() {
+    while let Some(irq) = plic::claim() {
+        random::add_timing_entropy();
+        match irq {
+            #[cfg(feature = "visionfive2")]
+            crate::boards::SDIO_IRQ => block::handle_irq(),
+            _ => warn!("unexpected irq {}", irq),
+        }
+        plic::complete(irq);
+    }
+}
+
+/// Wait for an interrupt when every task is blocked on a block transfer
+///
+/// wfi 不受 sstatus.SIE 影响，sie 中打开的中断到来时返回。
+pub fn wait_for_irq() {
+    unsafe { riscv::asm::wfi() };
+    handle_irq();
+    poll_block_io();
+}
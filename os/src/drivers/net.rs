@@ -0,0 +1,87 @@
+//! Network devices
+//!
+//! 目前只有 QEMU 的 virtio-net。设备没有接中断，协议栈在时钟中断和套接字操作时轮询收包。
+
+use alloc::{sync::Arc, vec::Vec};
+use core::ptr::NonNull;
+
+use lazy_static::*;
+use spin::Mutex;
+use virtio_drivers::{
+    device::net::{TxBuffer, VirtIONet},
+    transport::{
+        mmio::{MmioTransport, VirtIOHeader},
+        DeviceType,
+        Transport,
+    },
+};
+
+use super::block::VirtioHal;
+use crate::{
+    boards::VIRTIO_NET,
+    config::{KERNEL_SPACE_OFFSET, PAGE_SIZE},
+};
+
+/// An ethernet device
+pub trait NetDevice: Send + Sync {
+    fn mac(&self) -> [u8; 6];
+    /// Send one frame, dropped if the device is busy
+    fn send(&self, frame: &[u8]);
+    /// The next received frame, `None` if there is none yet
+    fn recv(&self) -> Option<Vec<u8>>;
+}
+
+lazy_static! {
+    /// The network device, `None` if the board has none
+    pub static ref NET_DEVICE: Option<Arc<dyn NetDevice>> = probe();
+}
+
+/// Buffers in each virtqueue
+const NET_QUEUE_SIZE: usize = 16;
+/// Size of each receive buffer, room for a full frame and the virtio-net header
+const NET_BUF_LEN: usize = 2048;
+
+/// The virtio-net device behind a lock, its MMIO pointers are only used with the lock held
+struct VirtIONetDevice(Mutex<VirtIONet<VirtioHal, MmioTransport, NET_QUEUE_SIZE>>);
+
+unsafe impl Send for VirtIONetDevice {}
+unsafe impl Sync for VirtIONetDevice {}
+
+/// Probe the virtio-mmio slot reserved for the network device
+fn probe() -> Option<Arc<dyn NetDevice>> {
+    let base = VIRTIO_NET? + KERNEL_SPACE_OFFSET * PAGE_SIZE;
+    let header = NonNull::new(base as *mut VirtIOHeader)?;
+    let transport = unsafe { MmioTransport::new(header) }.ok()?;
+    if transport.device_type() != DeviceType::Network {
+        return None;
+    }
+    let net = VirtIONet::new(transport, NET_BUF_LEN).ok()?;
+    info!("virtio-net: mac {:x?}", net.mac_address());
+    Some(Arc::new(VirtIONetDevice(Mutex::new(net))))
+}
+
+impl NetDevice for VirtIONetDevice {
+    fn mac(&self) -> [u8; 6] {
+        self.0.lock().mac_address()
+    }
+
+    fn send(&self, frame: &[u8]) {
+        let mut net = self.0.lock();
+        if !net.can_send() {
+            warn!("virtio-net: tx queue full, frame dropped");
+            return;
+        }
+        if net.send(TxBuffer::from(frame)).is_err() {
+            warn!("virtio-net: failed to send a frame");
+        }
+    }
+
+    fn recv(&self) -> Option<Vec<u8>> {
+        let mut net = self.0.lock();
+        let rx = net.receive().ok()?;
+        let frame = rx.packet().to_vec();
+        // 缓冲区要还给设备，否则收满 NET_QUEUE_SIZE 个包后就收不到了
+        let _ = net.recycle_rx_buffer(rx);
+        Some(frame)
+    }
+}
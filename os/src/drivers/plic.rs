@@ -0,0 +1,47 @@
+//! Platform-Level Interrupt Controller
+//!
+//! 只使用内核所在 hart 的 S 模式上下文，所有中断源优先级为 1、阈值为 0。
+
+use crate::{
+    boards::{plic_context, PLIC_BASE},
+    config::{KERNEL_SPACE_OFFSET, PAGE_SIZE},
+    task::hart_id,
+};
+
+const PLIC_VA: usize = PLIC_BASE + KERNEL_SPACE_OFFSET * PAGE_SIZE;
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+fn reg(offset: usize) -> *mut u32 {
+    (PLIC_VA + offset) as *mut u32
+}
+
+fn context_reg(offset: usize) -> *mut u32 {
+    reg(CONTEXT + plic_context(hart_id()) * CONTEXT_STRIDE + offset)
+}
+
+/// Let `irq` interrupt the current hart in S mode
+pub fn enable(irq: usize) {
+    let enable = reg(ENABLE + plic_context(hart_id()) * ENABLE_STRIDE + irq / 32 * 4);
+    unsafe {
+        reg(PRIORITY + irq * 4).write_volatile(1);
+        enable.write_volatile(enable.read_volatile() | 1 << (irq % 32));
+        context_reg(THRESHOLD).write_volatile(0);
+    }
+}
+
+/// Take the highest priority pending interrupt, `None` if there is none
+pub fn claim() -> Option<usize> {
+    let irq = unsafe { context_reg(CLAIM).read_volatile() };
+    (irq != 0).then_some(irq as usize)
+}
+
+/// Tell the PLIC that `irq` from [`claim`] was handled
+pub fn complete(irq: usize) {
+    unsafe { context_reg(CLAIM).write_volatile(irq as u32) };
+}
@@ -0,0 +1,139 @@
+//! Kernel entropy pool, behind getrandom and `/dev/urandom`
+//!
+//! 随机数由 ChaCha20 生成，密钥就是熵池：每次输出之后用新生成的一块替换密钥，从当前状态推不出
+//! 之前的输出。熵来自启动时的周期计数器、SD 卡的 CID，以及外部中断和块设备读写到来时刻的抖动，
+//! 数量不多，够 libc 的哈希种子和临时文件名使用，但不适合生成长期密钥。
+
+use core::arch::asm;
+
+use lazy_static::*;
+
+use crate::{sync::SpinNoIrqLock, timer::get_time};
+
+/// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+/// nonce of the blocks that become the next key, the output uses nonce 0
+const REKEY_NONCE: u64 = u64::MAX;
+/// rounds of the timing loop that seeds the pool at boot
+const BOOT_JITTER_ROUNDS: usize = 64;
+
+/// The cycle counter, the vendored riscv crate only wraps the machine-mode `mcycle`
+fn read_cycle() -> usize {
+    let cycle: usize;
+    unsafe {
+        asm!("rdcycle {}", out(reg) cycle);
+    }
+    cycle
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// One 64-byte ChaCha20 block
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: u64) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter as u32;
+    state[13] = (counter >> 32) as u32;
+    state[14] = nonce as u32;
+    state[15] = (nonce >> 32) as u32;
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+    let mut block = [0u8; 64];
+    for (i, chunk) in block.chunks_mut(4).enumerate() {
+        chunk.copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    block
+}
+
+struct EntropyPool {
+    key:     [u32; 8],
+    counter: u64,
+}
+
+impl EntropyPool {
+    /// Replace the key with a block derived from it
+    fn rekey(&mut self) {
+        let block = chacha20_block(&self.key, self.counter, REKEY_NONCE);
+        self.counter = self.counter.wrapping_add(1);
+        for (word, bytes) in self.key.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+    }
+
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (word, bytes) in self.key.iter_mut().zip(chunk.chunks(4)) {
+                let mut word_bytes = [0u8; 4];
+                word_bytes[..bytes.len()].copy_from_slice(bytes);
+                *word ^= u32::from_le_bytes(word_bytes);
+            }
+            self.rekey();
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(64) {
+            let block = chacha20_block(&self.key, self.counter, 0);
+            self.counter = self.counter.wrapping_add(1);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        self.rekey();
+    }
+}
+
+lazy_static! {
+    static ref POOL: SpinNoIrqLock<EntropyPool> = SpinNoIrqLock::new(boot_pool());
+}
+
+/// A pool seeded from the clock and the jitter of a timing loop
+///
+/// 同一段代码每次执行的周期数受缓存和流水线状态影响，低位有少量不可预测性。
+fn boot_pool() -> EntropyPool {
+    let mut pool = EntropyPool {
+        key:     [0; 8],
+        counter: 0,
+    };
+    pool.mix(&(get_time() as u64).to_le_bytes());
+    let mut samples = [0u8; BOOT_JITTER_ROUNDS];
+    for sample in samples.iter_mut() {
+        let start = read_cycle();
+        pool.rekey();
+        *sample = read_cycle().wrapping_sub(start) as u8;
+    }
+    pool.mix(&samples);
+    pool
+}
+
+/// Mix `data` into the pool, unpredictable or not
+pub fn add_entropy(data: &[u8]) {
+    POOL.lock().mix(data);
+}
+
+/// Mix the time of an event whose timing is hard to predict, such as an interrupt
+pub fn add_timing_entropy() {
+    add_entropy(&(read_cycle() as u64).to_le_bytes());
+}
+
+/// Fill `buf` with random bytes
+pub fn fill(buf: &mut [u8]) {
+    POOL.lock().fill(buf);
+}
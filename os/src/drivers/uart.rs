@@ -0,0 +1,47 @@
+//! Minimal polled ns16550 compatible UART, used as a console sink
+
+use crate::{
+    boards::{UART_BASE, UART_REG_SHIFT},
+    config::{KERNEL_SPACE_OFFSET, PAGE_SIZE},
+};
+
+/// Transmit holding register
+const THR: usize = 0;
+/// Line status register
+const LSR: usize = 5;
+/// LSR: transmit holding register empty
+const LSR_THRE: u32 = 1 << 5;
+
+/// kernel virtual address of the UART registers
+const UART_VA: usize = UART_BASE + KERNEL_SPACE_OFFSET * PAGE_SIZE;
+
+fn read_reg(reg: usize) -> u32 {
+    let addr = UART_VA + (reg << UART_REG_SHIFT);
+    unsafe {
+        // qemu 的 16550 按字节访问，vf2 的 8250 寄存器为 32 位宽
+        if UART_REG_SHIFT == 0 {
+            (addr as *const u8).read_volatile() as u32
+        } else {
+            (addr as *const u32).read_volatile()
+        }
+    }
+}
+
+fn write_reg(reg: usize, val: u32) {
+    let addr = UART_VA + (reg << UART_REG_SHIFT);
+    unsafe {
+        if UART_REG_SHIFT == 0 {
+            (addr as *mut u8).write_volatile(val as u8)
+        } else {
+            (addr as *mut u32).write_volatile(val)
+        }
+    }
+}
+
+/// Write a byte, spinning until the transmitter is ready
+pub fn putchar(c: u8) {
+    while read_reg(LSR) & LSR_THRE == 0 {
+        core::hint::spin_loop();
+    }
+    write_reg(THR, c as u32);
+}
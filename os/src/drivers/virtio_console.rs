@@ -0,0 +1,58 @@
+//! virtio-console device driver, used as a console sink when qemu provides one
+
+use core::ptr::NonNull;
+
+use lazy_static::*;
+use spin::Mutex;
+use virtio_drivers::{
+    device::console::VirtIOConsole,
+    transport::{
+        mmio::{MmioTransport, VirtIOHeader},
+        DeviceType,
+        Transport,
+    },
+};
+
+use super::block::VirtioHal;
+use crate::{
+    boards::VIRTIO_CONSOLE,
+    config::{KERNEL_SPACE_OFFSET, PAGE_SIZE},
+};
+
+lazy_static! {
+    /// The virtio-console device, `None` if the board has no such device
+    static ref VIRTIO_CONS: Option<VirtIOCons> = probe();
+}
+
+/// The console device behind a lock, its MMIO pointers are only used with the lock held
+struct VirtIOCons(Mutex<VirtIOConsole<VirtioHal, MmioTransport>>);
+
+unsafe impl Send for VirtIOCons {}
+unsafe impl Sync for VirtIOCons {}
+
+/// Probe the virtio-mmio slot reserved for the console
+fn probe() -> Option<VirtIOCons> {
+    let base = VIRTIO_CONSOLE? + KERNEL_SPACE_OFFSET * PAGE_SIZE;
+    let header = NonNull::new(base as *mut VirtIOHeader)?;
+    // 空的 virtio-mmio 槽位 device id 为 0，MmioTransport::new 会返回错误
+    let transport = unsafe { MmioTransport::new(header) }.ok()?;
+    if transport.device_type() != DeviceType::Console {
+        return None;
+    }
+    // 这里不能打印日志：console 可能正在等待该设备初始化
+    VirtIOConsole::new(transport)
+        .ok()
+        .map(|console| VirtIOCons(Mutex::new(console)))
+}
+
+/// Initialize the device, returns whether it is present
+pub fn init() -> bool {
+    VIRTIO_CONS.is_some()
+}
+
+/// Write a byte if the device is present
+pub fn putchar(c: u8) {
+    if let Some(console) = VIRTIO_CONS.as_ref() {
+        let _ = console.0.lock().send(c);
+    }
+}
@@ -0,0 +1,350 @@
+//! cgroup2-style pseudo filesystem configuring [`crate::task::cgroup`]
+//!
+//! 挂载在 `/sys/fs/cgroup`，根目录下每个子目录是一个组，`mkdir`/`rmdir` 创建和删除组。
+//! 组目录中的文件：
+//! - `cgroup.procs`：读出组内进程的 pid，写入一个 pid 把该进程移入本组；
+//! - `cpu.weight`：CPU 权重，1..=10000，默认 100；
+//! - `memory.max`：内存上限（字节），`max` 表示不限制；
+//! - `memory.current`：已计入本组的内存（字节），只读。
+//!
+//! 根组只有 `cgroup.procs` 和 `memory.current`。
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{
+    dentry::Dentry,
+    file::File,
+    fs::{FileSystem, FileSystemType},
+    inode::{Inode, InodePerm, InodeType, Stat, StatMode},
+};
+use crate::{
+    config::PAGE_SIZE,
+    sync::SpinNoIrqLock,
+    task::{
+        cgroup::{self, Cgroup, MEMORY_UNLIMITED, ROOT_CGROUP},
+        current_task,
+    },
+};
+
+pub struct CgroupFS;
+
+impl FileSystem for CgroupFS {
+    fn fs_type(&self) -> FileSystemType {
+        FileSystemType::CGROUP2
+    }
+    fn root_inode(self: Arc<Self>) -> Arc<dyn Inode> {
+        Arc::new(CgroupInode::new(CgroupNode::Root))
+    }
+}
+
+/// Interface files of a group
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CgroupAttr {
+    Procs,
+    CpuWeight,
+    MemoryMax,
+    MemoryCurrent,
+}
+
+impl CgroupAttr {
+    const ALL: [Self; 4] = [
+        Self::Procs,
+        Self::CpuWeight,
+        Self::MemoryMax,
+        Self::MemoryCurrent,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Procs => "cgroup.procs",
+            Self::CpuWeight => "cpu.weight",
+            Self::MemoryMax => "memory.max",
+            Self::MemoryCurrent => "memory.current",
+        }
+    }
+
+    /// the root group has no limits to configure
+    fn exists_in(&self, group: &Cgroup) -> bool {
+        !group.is_root() || matches!(self, Self::Procs | Self::MemoryCurrent)
+    }
+
+    fn writable(&self) -> bool {
+        *self != Self::MemoryCurrent
+    }
+
+    /// Names of the files in `group`
+    fn names(group: &Cgroup) -> Vec<String> {
+        Self::ALL
+            .iter()
+            .filter(|attr| attr.exists_in(group))
+            .map(|attr| attr.name().to_string())
+            .collect()
+    }
+
+    fn lookup(group: &Cgroup, name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|attr| attr.name() == name && attr.exists_in(group))
+            .copied()
+    }
+
+    fn show(&self, group: &Arc<Cgroup>) -> String {
+        match self {
+            Self::Procs => {
+                // pid 按读者所在的 pid 命名空间翻译，看不到的进程不列出
+                let ns = current_task().unwrap().pid_ns();
+                group
+                    .procs()
+                    .into_iter()
+                    .filter_map(|pid| ns.pid_of(pid))
+                    .map(|pid| format!("{}\n", pid))
+                    .collect()
+            }
+            Self::CpuWeight => format!("{}\n", group.cpu_weight()),
+            Self::MemoryMax => match group.mem_max() {
+                MEMORY_UNLIMITED => "max\n".to_string(),
+                pages => format!("{}\n", pages * PAGE_SIZE),
+            },
+            Self::MemoryCurrent => format!("{}\n", group.mem_pages() * PAGE_SIZE),
+        }
+    }
+
+    /// Apply a value written to the file, false if it is malformed
+    fn store(&self, group: &Arc<Cgroup>, value: &str) -> bool {
+        let value = value.trim();
+        match self {
+            Self::Procs => {
+                let ns = current_task().unwrap().pid_ns();
+                match value.parse().ok().and_then(|pid| ns.find(pid)) {
+                    Some(task) => {
+                        group.attach(&task);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Self::CpuWeight => value
+                .parse()
+                .map_or(false, |weight| group.set_cpu_weight(weight)),
+            Self::MemoryMax => {
+                let pages = if value == "max" {
+                    MEMORY_UNLIMITED
+                } else {
+                    match value.parse::<usize>() {
+                        Ok(bytes) => (bytes + PAGE_SIZE - 1) / PAGE_SIZE,
+                        Err(_) => return false,
+                    }
+                };
+                group.set_mem_max(pages);
+                true
+            }
+            Self::MemoryCurrent => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum CgroupNode {
+    /// the mount point, holds the root group's files and a directory per group
+    Root,
+    Group(Arc<Cgroup>),
+    Attr(Arc<Cgroup>, CgroupAttr),
+}
+
+pub struct CgroupInode {
+    node:  CgroupNode,
+    inner: SpinNoIrqLock<CgroupInodeInner>,
+}
+
+struct CgroupInodeInner {
+    fpos: usize,
+}
+
+impl CgroupInode {
+    fn new(node: CgroupNode) -> Self {
+        Self {
+            node,
+            inner: SpinNoIrqLock::new(CgroupInodeInner { fpos: 0 }),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        !matches!(self.node, CgroupNode::Attr(..))
+    }
+
+    /// Look up a single path component
+    fn lookup_one(&self, name: &str) -> Option<CgroupNode> {
+        match &self.node {
+            CgroupNode::Root => match CgroupAttr::lookup(&ROOT_CGROUP, name) {
+                Some(attr) => Some(CgroupNode::Attr(ROOT_CGROUP.clone(), attr)),
+                None => Some(CgroupNode::Group(cgroup::find(name)?)),
+            },
+            CgroupNode::Group(group) => {
+                let attr = CgroupAttr::lookup(group, name)?;
+                Some(CgroupNode::Attr(group.clone(), attr))
+            }
+            CgroupNode::Attr(..) => None,
+        }
+    }
+}
+
+impl Inode for CgroupInode {
+    fn fstype(&self) -> FileSystemType {
+        FileSystemType::CGROUP2
+    }
+
+    fn lookup(self: Arc<Self>, name: &str) -> Option<Arc<Dentry>> {
+        let mut node = self.node.clone();
+        for component in name.split('/').filter(|s| !s.is_empty() && *s != ".") {
+            node = CgroupInode::new(node).lookup_one(component)?;
+        }
+        let dentry = Dentry::new(name, Arc::new(CgroupInode::new(node)));
+        Some(Arc::new(dentry))
+    }
+
+    fn create(self: Arc<Self>, name: &str, type_: InodeType) -> Option<Arc<Dentry>> {
+        // 只能在根目录下创建组
+        if type_ != InodeType::Directory || !self.mkdir(name) {
+            return None;
+        }
+        let group = cgroup::find(name)?;
+        Some(Arc::new(Dentry::new(
+            name,
+            Arc::new(CgroupInode::new(CgroupNode::Group(group))),
+        )))
+    }
+
+    fn unlink(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn link(self: Arc<Self>, _name: &str, _target: Arc<Dentry>) -> bool {
+        false
+    }
+
+    fn rename(self: Arc<Self>, _old_name: &str, _new_name: &str) -> bool {
+        false
+    }
+
+    fn mkdir(self: Arc<Self>, name: &str) -> bool {
+        match self.node {
+            // 组名不能和根组的接口文件重名
+            CgroupNode::Root => {
+                !name.contains('/')
+                    && CgroupAttr::lookup(&ROOT_CGROUP, name).is_none()
+                    && cgroup::create(name).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    fn rmdir(self: Arc<Self>, name: &str) -> bool {
+        match self.node {
+            CgroupNode::Root => cgroup::remove(name),
+            _ => false,
+        }
+    }
+
+    fn ls(&self) -> Vec<String> {
+        match &self.node {
+            CgroupNode::Root => {
+                let mut names = CgroupAttr::names(&ROOT_CGROUP);
+                names.extend(cgroup::names());
+                names
+            }
+            CgroupNode::Group(group) => CgroupAttr::names(group),
+            CgroupNode::Attr(..) => Vec::new(),
+        }
+    }
+
+    fn clear(&self) {}
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let content = match &self.node {
+            CgroupNode::Attr(group, attr) => attr.show(group),
+            _ => return 0,
+        };
+        let content = content.as_bytes();
+        if offset >= content.len() {
+            return 0;
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        len
+    }
+
+    /// 每次写入都是一个完整的值，忽略 `offset`；格式不对时返回 0
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> usize {
+        let (group, attr) = match &self.node {
+            CgroupNode::Attr(group, attr) => (group, attr),
+            _ => return 0,
+        };
+        match core::str::from_utf8(buf) {
+            Ok(value) if attr.store(group, value) => buf.len(),
+            _ => 0,
+        }
+    }
+
+    /// owned by root, so only root may configure groups
+    fn perm(&self) -> Option<InodePerm> {
+        let mode = match &self.node {
+            CgroupNode::Attr(_, attr) if attr.writable() => 0o644,
+            CgroupNode::Attr(..) => 0o444,
+            _ => 0o755,
+        };
+        Some(InodePerm {
+            mode,
+            uid: 0,
+            gid: 0,
+        })
+    }
+}
+
+impl File for CgroupInode {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        match &self.node {
+            CgroupNode::Attr(_, attr) => attr.writable(),
+            _ => false,
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let mut inner = self.inner.lock();
+        let read_size = self.read_at(inner.fpos, buf);
+        inner.fpos += read_size;
+        read_size
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        match &self.node {
+            CgroupNode::Attr(group, attr) => attr.show(group).into_bytes(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        self.write_at(0, buf)
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        let st_mode = if self.is_dir() {
+            StatMode::DIR.bits()
+        } else {
+            StatMode::FILE.bits()
+        };
+        Some(Stat::new(0, 0, st_mode, 1, 0, 0, 0, 0, 0))
+    }
+
+    fn hang_up(&self) -> bool {
+        false
+    }
+}
@@ -1,5 +1,7 @@
 use bitflags::bitflags;
 
+use crate::task::cred::{MAY_READ, MAY_WRITE};
+
 bitflags! {
     pub struct OpenFlags: i32 {
         const O_RDONLY    = 0o0;
@@ -28,6 +30,21 @@ bitflags! {
     }
 }
 
+impl OpenFlags {
+    /// `MAY_*` access to the file these flags ask for
+    pub fn access(&self) -> u16 {
+        let mut access = match self.bits & 0o3 {
+            0 => MAY_READ,
+            1 => MAY_WRITE,
+            _ => MAY_READ | MAY_WRITE,
+        };
+        if self.contains(Self::O_TRUNC) {
+            access |= MAY_WRITE;
+        }
+        access
+    }
+}
+
 bitflags! {
     pub struct FileMode: u32 {
         const S_IRWXU = 0o700;  // 用户（所有者）读、写、执行权限
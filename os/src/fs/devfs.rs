@@ -0,0 +1,255 @@
+//! Device filesystem, mounted at `/dev`
+//!
+//! 根目录下的每个文件是 [`DEVICES`] 中的一个字符设备，读写直接交给设备的函数，忽略文件偏移：
+//! - `null`：读到文件末尾，写入的数据被丢弃；
+//! - `zero`：读出全 0，写入的数据被丢弃；
+//! - `random`、`urandom`：读出内核熵池生成的随机数，写入的数据混入熵池；
+//! - `tty`：控制台。
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{
+    dentry::Dentry,
+    file::File,
+    fs::{FileSystem, FileSystemType},
+    inode::{Inode, InodePerm, InodeType, Stat, StatMode},
+    tty::{tty_ioctl, tty_read},
+};
+use crate::{drivers::random, syscall::errno::ENOTTY};
+
+/// A character device in `/dev`
+struct CharDevice {
+    name:  &'static str,
+    major: u32,
+    minor: u32,
+    /// fill `buf`, return the number of bytes read, 0 at end of file
+    read:  fn(&mut [u8]) -> Result<usize, isize>,
+    /// consume `buf`, return the number of bytes written
+    write: fn(&[u8]) -> usize,
+    /// ioctl `(request, arg)`, `None` if the device takes no requests
+    ioctl: Option<fn(usize, usize) -> isize>,
+}
+
+impl CharDevice {
+    /// `st_rdev` in the encoding of glibc's `makedev`
+    fn rdev(&self) -> u64 {
+        let (major, minor) = (self.major as u64, self.minor as u64);
+        ((major & 0xfff) << 8) | ((major & !0xfff) << 32) | (minor & 0xff) | ((minor & !0xff) << 12)
+    }
+}
+
+const DEVICES: &[CharDevice] = &[
+    CharDevice {
+        name:  "null",
+        major: 1,
+        minor: 3,
+        read:  |_| Ok(0),
+        write: |buf| buf.len(),
+        ioctl: None,
+    },
+    CharDevice {
+        name:  "zero",
+        major: 1,
+        minor: 5,
+        read:  |buf| {
+            buf.fill(0);
+            Ok(buf.len())
+        },
+        write: |buf| buf.len(),
+        ioctl: None,
+    },
+    // 熵池不估计熵的多少，random 和 urandom 一样从不阻塞
+    CharDevice {
+        name:  "random",
+        major: 1,
+        minor: 8,
+        read:  random_read,
+        write: random_write,
+        ioctl: None,
+    },
+    CharDevice {
+        name:  "urandom",
+        major: 1,
+        minor: 9,
+        read:  random_read,
+        write: random_write,
+        ioctl: None,
+    },
+    CharDevice {
+        name:  "tty",
+        major: 5,
+        minor: 0,
+        read:  tty_read,
+        write: tty_write,
+        ioctl: Some(tty_ioctl),
+    },
+];
+
+fn random_read(buf: &mut [u8]) -> Result<usize, isize> {
+    random::fill(buf);
+    Ok(buf.len())
+}
+
+fn random_write(buf: &[u8]) -> usize {
+    random::add_entropy(buf);
+    buf.len()
+}
+
+fn tty_write(buf: &[u8]) -> usize {
+    print!("{}", String::from_utf8_lossy(buf));
+    buf.len()
+}
+
+pub struct DevFS;
+
+impl FileSystem for DevFS {
+    fn fs_type(&self) -> FileSystemType {
+        FileSystemType::DEVTMPFS
+    }
+    fn root_inode(self: Arc<Self>) -> Arc<dyn Inode> {
+        Arc::new(DevInode::new(None))
+    }
+}
+
+pub struct DevInode {
+    /// `None` for the root directory
+    device: Option<&'static CharDevice>,
+}
+
+impl DevInode {
+    fn new(device: Option<&'static CharDevice>) -> Self {
+        Self { device }
+    }
+}
+
+impl Inode for DevInode {
+    fn fstype(&self) -> FileSystemType {
+        FileSystemType::DEVTMPFS
+    }
+
+    fn lookup(self: Arc<Self>, name: &str) -> Option<Arc<Dentry>> {
+        let name = name.trim_matches('/');
+        if self.device.is_some() {
+            return None;
+        }
+        let device = if name.is_empty() || name == "." {
+            None
+        } else {
+            Some(DEVICES.iter().find(|device| device.name == name)?)
+        };
+        let dentry = Dentry::new(name, Arc::new(DevInode::new(device)));
+        Some(Arc::new(dentry))
+    }
+
+    fn create(self: Arc<Self>, _name: &str, _type: InodeType) -> Option<Arc<Dentry>> {
+        None
+    }
+
+    fn unlink(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn link(self: Arc<Self>, _name: &str, _target: Arc<Dentry>) -> bool {
+        false
+    }
+
+    fn rename(self: Arc<Self>, _old_name: &str, _new_name: &str) -> bool {
+        false
+    }
+
+    fn mkdir(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn rmdir(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn ls(&self) -> Vec<String> {
+        match self.device {
+            None => DEVICES
+                .iter()
+                .map(|device| device.name.to_string())
+                .collect(),
+            Some(_) => Vec::new(),
+        }
+    }
+
+    /// 以 O_TRUNC 打开设备时调用，设备没有内容可清空
+    fn clear(&self) {}
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        self.try_read_at(offset, buf).unwrap_or(0)
+    }
+
+    fn try_read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, isize> {
+        self.device.map_or(Ok(0), |device| (device.read)(buf))
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> usize {
+        self.device.map_or(0, |device| (device.write)(buf))
+    }
+
+    fn perm(&self) -> Option<InodePerm> {
+        let mode = self.device.map_or(0o755, |_| 0o666);
+        Some(InodePerm {
+            mode,
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    /// `zero` 和 `urandom` 读不到文件末尾，不能整个读入内存
+    fn read_all(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+impl File for DevInode {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        self.device.is_some()
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        self.read_at(0, buf)
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> Result<usize, isize> {
+        self.try_read_at(0, buf)
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        self.write_at(0, buf)
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        let (st_mode, st_rdev) = match self.device {
+            None => (StatMode::DIR.bits(), 0),
+            Some(device) => (StatMode::CHAR_DEVICE.bits(), device.rdev()),
+        };
+        Some(Stat::new(0, 0, st_mode, 1, st_rdev, 0, 0, 0, 0))
+    }
+
+    fn hang_up(&self) -> bool {
+        false
+    }
+
+    fn ioctl(&self, request: usize, arg: usize) -> isize {
+        match self.device.and_then(|device| device.ioctl) {
+            Some(ioctl) => ioctl(request, arg),
+            None => ENOTTY,
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! epoll instances
+//!
+//! 兴趣列表以 fd 为键，记录被监视的文件（弱引用，文件关闭后自动移出）、关心的事件和用户数据。
+//! 就绪状态不缓存，每次检查时由文件的 `r_ready`/`w_ready`/`hang_up` 重新计算；
+//! 边沿触发（EPOLLET）只报告上次检查之后新出现的事件，EPOLLONESHOT 报告一次后停用，
+//! 直到 EPOLL_CTL_MOD 重新启用。
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use bitflags::bitflags;
+
+use super::{file::File, inode::Stat};
+use crate::{
+    sync::SpinNoIrqLock,
+    syscall::errno::{EEXIST, ENOENT},
+};
+
+bitflags! {
+    /// Events of an `epoll_event`
+    pub struct EpollEvents: u32 {
+        const EPOLLIN        = 0x001;
+        const EPOLLPRI       = 0x002;
+        const EPOLLOUT       = 0x004;
+        const EPOLLERR       = 0x008;
+        const EPOLLHUP       = 0x010;
+        const EPOLLRDNORM    = 0x040;
+        const EPOLLWRNORM    = 0x100;
+        const EPOLLRDHUP     = 0x2000;
+        const EPOLLEXCLUSIVE = 1 << 28;
+        const EPOLLWAKEUP    = 1 << 29;
+        const EPOLLONESHOT   = 1 << 30;
+        const EPOLLET        = 1 << 31;
+    }
+}
+
+/// `struct epoll_event`, not packed on RISC-V
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct EpollEvent {
+    pub events: u32,
+    pub data:   u64,
+}
+
+/// A file on the interest list
+struct EpollItem {
+    file:   Weak<dyn File>,
+    events: EpollEvents,
+    data:   u64,
+    /// cleared when an EPOLLONESHOT item is reported
+    armed:  bool,
+    /// events found by the last check, for EPOLLET
+    last:   EpollEvents,
+}
+
+impl EpollItem {
+    fn new(file: &Arc<dyn File>, event: &EpollEvent) -> Self {
+        Self {
+            file:   Arc::downgrade(file),
+            events: EpollEvents::from_bits_truncate(event.events),
+            data:   event.data,
+            armed:  true,
+            last:   EpollEvents::empty(),
+        }
+    }
+
+    /// Events of the file this item asks for, EPOLLERR and EPOLLHUP are always asked for
+    fn poll(&self) -> EpollEvents {
+        let Some(file) = self.file.upgrade() else {
+            return EpollEvents::empty();
+        };
+        if !self.armed {
+            return EpollEvents::empty();
+        }
+        let mut events = EpollEvents::empty();
+        if file.readable() && file.r_ready() {
+            events |= EpollEvents::EPOLLIN | EpollEvents::EPOLLRDNORM;
+        }
+        if file.writable() && file.w_ready() {
+            events |= EpollEvents::EPOLLOUT | EpollEvents::EPOLLWRNORM;
+        }
+        if file.hang_up() {
+            // 写端看到读端关闭是错误，读端看到写端关闭是挂断
+            events |= if file.readable() {
+                EpollEvents::EPOLLHUP | EpollEvents::EPOLLRDHUP
+            } else {
+                EpollEvents::EPOLLERR
+            };
+        }
+        events & (self.events | EpollEvents::EPOLLERR | EpollEvents::EPOLLHUP)
+    }
+
+    /// Events to report now, without the ones an edge-triggered item already reported
+    fn pending(&self, events: EpollEvents) -> EpollEvents {
+        if self.events.contains(EpollEvents::EPOLLET) {
+            events - self.last
+        } else {
+            events
+        }
+    }
+}
+
+/// The file behind an epoll file descriptor
+pub struct EpollInstance {
+    items: SpinNoIrqLock<BTreeMap<usize, EpollItem>>,
+}
+
+impl EpollInstance {
+    pub fn new() -> Self {
+        Self {
+            items: SpinNoIrqLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// EPOLL_CTL_ADD, EEXIST if `fd` is already on the interest list
+    pub fn add(&self, fd: usize, file: &Arc<dyn File>, event: &EpollEvent) -> Result<(), isize> {
+        let mut items = self.items.lock();
+        // 旧文件已经关闭、fd 被复用时，旧的表项不再算数
+        let stale = items
+            .get(&fd)
+            .map_or(true, |item| item.file.upgrade().is_none());
+        if !stale {
+            return Err(EEXIST);
+        }
+        items.insert(fd, EpollItem::new(file, event));
+        Ok(())
+    }
+
+    /// EPOLL_CTL_MOD, which also rearms an EPOLLONESHOT item
+    pub fn modify(&self, fd: usize, event: &EpollEvent) -> Result<(), isize> {
+        let mut items = self.items.lock();
+        let item = items.get_mut(&fd).ok_or(ENOENT)?;
+        item.events = EpollEvents::from_bits_truncate(event.events);
+        item.data = event.data;
+        item.armed = true;
+        item.last = EpollEvents::empty();
+        Ok(())
+    }
+
+    /// EPOLL_CTL_DEL
+    pub fn remove(&self, fd: usize) -> Result<(), isize> {
+        let mut items = self.items.lock();
+        items.remove(&fd).map(|_| ()).ok_or(ENOENT)
+    }
+
+    /// Take at most `max` ready events, updating the state of EPOLLET and EPOLLONESHOT items
+    pub fn take_events(&self, max: usize) -> Vec<EpollEvent> {
+        let mut items = self.items.lock();
+        items.retain(|_, item| item.file.strong_count() > 0);
+        let mut ready = Vec::new();
+        for item in items.values_mut() {
+            if ready.len() == max {
+                break;
+            }
+            let events = item.poll();
+            let pending = item.pending(events);
+            item.last = events;
+            if pending.is_empty() {
+                continue;
+            }
+            if item.events.contains(EpollEvents::EPOLLONESHOT) {
+                item.armed = false;
+            }
+            ready.push(EpollEvent {
+                events: pending.bits(),
+                data:   item.data,
+            });
+        }
+        ready
+    }
+}
+
+impl File for EpollInstance {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn write(&self, _buf: &[u8]) -> usize {
+        0
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        None
+    }
+
+    fn is_dir(&self) -> bool {
+        false
+    }
+
+    fn hang_up(&self) -> bool {
+        false
+    }
+
+    /// 嵌套在其他 epoll 或 ppoll 中时，有事件可报告就算可读
+    fn r_ready(&self) -> bool {
+        let items = self.items.lock();
+        items
+            .values()
+            .any(|item| !item.pending(item.poll()).is_empty())
+    }
+
+    fn w_ready(&self) -> bool {
+        false
+    }
+}
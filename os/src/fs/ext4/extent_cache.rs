@@ -0,0 +1,106 @@
+//! Cache of resolved extent mappings
+//!
+//! ext4_rs 每读一个块都要从 inode 重新遍历一次 extent 树（每次都是一次磁盘读），
+//! 读 ELF 这类大文件时开销很大。这里按 inode 号缓存整棵树展开后的映射和文件大小，
+//! 写入、删除文件后丢弃对应的缓存。缓存里找不到的块（例如树太深没有展开）仍交给 ext4_rs 查找。
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+
+use ext4_rs::{Ext4, Ext4InodeRef, EXT4_EXTENT_MAGIC};
+use spin::Mutex;
+
+/// inodes whose mappings are kept at most
+const EXTENT_CACHE_INODES: usize = 128;
+
+/// `len` blocks starting at logical block `lblock` are stored from physical block `pblock`
+#[derive(Clone, Copy)]
+struct Mapping {
+    lblock:    u32,
+    len:       u32,
+    pblock:    u64,
+    /// preallocated but never written, reads as zeros
+    unwritten: bool,
+}
+
+/// Where a logical block of a file lives
+pub enum BlockLocation {
+    Physical(u64),
+    /// a hole or an unwritten extent
+    Zero,
+    /// not in the cached mappings, ask ext4_rs
+    Unknown,
+}
+
+/// Extent mappings and size of one inode
+pub struct ExtentMap {
+    pub size: u64,
+    /// sorted by `lblock`
+    mappings: Vec<Mapping>,
+}
+
+impl ExtentMap {
+    fn load(ext4: &Arc<Ext4>, ino: u32) -> Self {
+        let inode_ref = Ext4InodeRef::get_inode_ref(Arc::downgrade(ext4), ino);
+        let size = inode_ref.inner.inode.inode_get_size();
+        let mut mappings = Vec::new();
+        // 不是 extent 格式的 inode（块映射、内联数据）不缓存，全部交给 ext4_rs
+        if inode_ref.inner.inode.block[0] as u16 == EXT4_EXTENT_MAGIC {
+            let mut extents = Vec::new();
+            inode_ref.ext4_find_all_extent(&mut extents);
+            mappings.extend(extents.iter().map(|extent| Mapping {
+                lblock:    extent.first_block,
+                len:       extent.get_actual_len() as u32,
+                pblock:    extent.start_lo as u64 | (extent.start_hi as u64) << 32,
+                unwritten: extent.is_unwritten(),
+            }));
+            mappings.sort_unstable_by_key(|mapping| mapping.lblock);
+        }
+        Self { size, mappings }
+    }
+
+    pub fn lookup(&self, lblock: u32) -> BlockLocation {
+        let idx = self
+            .mappings
+            .partition_point(|mapping| mapping.lblock <= lblock);
+        match idx.checked_sub(1).map(|idx| self.mappings[idx]) {
+            Some(mapping) if lblock - mapping.lblock < mapping.len => match mapping.unwritten {
+                true => BlockLocation::Zero,
+                false => BlockLocation::Physical(mapping.pblock + (lblock - mapping.lblock) as u64),
+            },
+            _ => BlockLocation::Unknown,
+        }
+    }
+}
+
+/// Per-filesystem cache, indexed by inode number
+#[derive(Default)]
+pub struct ExtentCache {
+    maps: Mutex<BTreeMap<u32, Arc<ExtentMap>>>,
+}
+
+impl ExtentCache {
+    /// Mappings of `ino`, loaded from disk on a miss
+    pub fn get(&self, ext4: &Arc<Ext4>, ino: u32) -> Arc<ExtentMap> {
+        if let Some(map) = self.maps.lock().get(&ino) {
+            return map.clone();
+        }
+        // 展开 extent 树要读盘，不持有锁
+        let map = Arc::new(ExtentMap::load(ext4, ino));
+        let mut maps = self.maps.lock();
+        if maps.len() >= EXTENT_CACHE_INODES {
+            maps.pop_first();
+        }
+        maps.insert(ino, map.clone());
+        map
+    }
+
+    /// Forget `ino` after its size or block mapping changed
+    pub fn invalidate(&self, ino: u32) {
+        self.maps.lock().remove(&ino);
+    }
+
+    /// Forget everything, for changes whose inode is not known
+    pub fn clear(&self) {
+        self.maps.lock().clear();
+    }
+}
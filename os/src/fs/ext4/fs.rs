@@ -4,24 +4,33 @@ use ext4_rs::{BlockDevice, Ext4};
 
 use super::{
     defs::ROOT_INO,
+    extent_cache::ExtentCache,
     inode::{Ext4Inode, Ext4InodeInner},
 };
 use crate::{
+    drivers::IoLock,
     fs::{
         fs::{FileSystem, FileSystemType},
         inode::Inode,
     },
-    sync::UPSafeCell,
+    sync::SleepLock,
 };
 
 pub struct Ext4FS {
-    pub ext4: Arc<Ext4>,
+    pub ext4:         Arc<Ext4>,
+    pub extent_cache: ExtentCache,
+    /// held by every operation on the filesystem, ext4_rs has no locking of its own
+    pub io:           IoLock,
 }
 
 impl Ext4FS {
     pub fn new(block_dev: Arc<dyn BlockDevice>) -> Self {
         let ext4 = Ext4::open(block_dev);
-        Self { ext4 }
+        Self {
+            ext4,
+            extent_cache: ExtentCache::default(),
+            io: IoLock::new(),
+        }
     }
 }
 
@@ -33,8 +42,11 @@ impl FileSystem for Ext4FS {
         let inode = Ext4Inode {
             fs:    self.clone(),
             ino:   ROOT_INO,
-            inner: unsafe { UPSafeCell::new(Ext4InodeInner { fpos: 0 }) },
+            inner: SleepLock::new(Ext4InodeInner { fpos: 0 }),
         };
         Arc::new(inode)
     }
+    fn invalidate(&self) {
+        self.extent_cache.clear();
+    }
 }
@@ -1,116 +1,537 @@
-use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::cmp::min;
 
-use ext4_rs::{Ext4File, Ext4InodeRef};
+use ext4_rs::{
+    DirEntryType,
+    Ext4File,
+    Ext4InodeRef,
+    BLOCK_SIZE,
+    EXT4_INODE_FLAG_EXTENTS,
+    EXT4_INODE_MODE_DIRECTORY,
+    EXT4_INODE_MODE_SOFTLINK,
+    EXT4_INODE_MODE_TYPE_MASK,
+    O_CREAT,
+};
 
-use super::fs::Ext4FS;
+use super::{extent_cache::BlockLocation, fs::Ext4FS};
 use crate::{
     fs::{
         dentry::Dentry,
         file::File,
         fs::FileSystemType,
-        inode::{Inode, InodeType, Stat},
+        inode::{Inode, InodePerm, InodeType, Stat},
     },
-    sync::UPSafeCell,
+    sync::SleepLock,
+    syscall::errno::EIO,
 };
 
 pub struct Ext4Inode {
     pub fs:    Arc<Ext4FS>,
     pub ino:   u32,
-    pub inner: UPSafeCell<Ext4InodeInner>,
+    /// 读写时一直持有，文件位置的更新和读写是原子的
+    pub inner: SleepLock<Ext4InodeInner>,
 }
 
 pub struct Ext4InodeInner {
     pub fpos: usize,
 }
 
+/// Symbolic link targets shorter than this are kept in `i_block` of the inode
+const FAST_SYMLINK_MAX: usize = 60;
+
+/// bytes after the 128-byte inode covering i_ctime_extra, i_mtime_extra and i_atime_extra
+const EXTRA_TIMES_SIZE: usize = 16;
+
+fn is_dir_mode(mode: u16) -> bool {
+    mode & EXT4_INODE_MODE_TYPE_MASK == EXT4_INODE_MODE_DIRECTORY as u16
+}
+
+impl Ext4Inode {
+    /// Inode `ino` of the same filesystem
+    fn sibling(&self, ino: u32) -> Arc<Ext4Inode> {
+        Arc::new(Ext4Inode {
+            fs: self.fs.clone(),
+            ino,
+            inner: SleepLock::new(Ext4InodeInner { fpos: 0 }),
+        })
+    }
+
+    /// A dentry `name` for inode `ino` of the same filesystem
+    fn dentry(&self, name: &str, ino: u32) -> Arc<Dentry> {
+        Arc::new(Dentry::new(name, self.sibling(ino)))
+    }
+
+    /// Remove `name` from this directory to make room for a renamed entry
+    fn remove_replaced(self: &Arc<Self>, name: &str) -> bool {
+        let Some(replaced) = self.find(name) else {
+            return true;
+        };
+        let replaced = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), replaced);
+        if is_dir_mode(replaced.inner.inode.mode) {
+            self.clone().rmdir(name)
+        } else {
+            self.clone().unlink(name)
+        }
+    }
+
+    /// Inode number of the entry `name` in this directory
+    fn find(&self, name: &str) -> Option<u32> {
+        let mut parent = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+        let entry = self
+            .fs
+            .ext4
+            .ext4_dir_find_entry_new(&mut parent, name)
+            .ok()?;
+        (entry.inode != 0).then_some(entry.inode)
+    }
+
+    /// Create `name` in this directory as an inode of `type_`, return its number
+    fn create_inode(&self, name: &str, type_: InodeType) -> Option<u32> {
+        let ftype = match type_ {
+            InodeType::Regular => DirEntryType::EXT4_DE_REG_FILE,
+            InodeType::Directory => DirEntryType::EXT4_DE_DIR,
+            InodeType::SymLink => DirEntryType::EXT4_DE_SYMLINK,
+            _ => return None,
+        };
+        let mut file = Ext4File::new();
+        let mut name_off = 0;
+        self.fs.extent_cache.invalidate(self.ino);
+        self.fs
+            .ext4
+            .ext4_generic_open_from(
+                &mut file,
+                name,
+                O_CREAT,
+                ftype.bits(),
+                self.ino,
+                &mut name_off,
+            )
+            .ok()?;
+        Some(file.inode)
+    }
+}
+
 impl Inode for Ext4Inode {
     fn fstype(&self) -> FileSystemType {
         FileSystemType::EXT4
     }
+    /// 截断为空文件，释放所有数据块
     fn clear(&self) {
-        todo!()
+        self.fs.io.run(|| {
+            let mut inode_ref =
+                Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            let _ = inode_ref.truncate_inode(0);
+            self.fs.extent_cache.invalidate(self.ino);
+        })
     }
-    fn create(self: Arc<Self>, _name: &str, _type_: InodeType) -> Option<Arc<Dentry>> {
-        todo!()
+    fn create(self: Arc<Self>, name: &str, type_: InodeType) -> Option<Arc<Dentry>> {
+        self.fs.io.run(|| {
+            if self.find(name).is_some() {
+                return None;
+            }
+            let ino = self.create_inode(name, type_)?;
+            Some(self.dentry(name, ino))
+        })
     }
 
     fn lookup(self: Arc<Self>, name: &str) -> Option<Arc<Dentry>> {
-        let mut file = Ext4File::new();
-        self.fs
-            .ext4
-            .ext4_open_from(self.ino, &mut file, name, "r", false)
-            .ok()?;
-        let inode = Ext4Inode {
-            fs:    self.fs.clone(),
-            ino:   file.inode,
-            inner: unsafe { UPSafeCell::new(Ext4InodeInner { fpos: 0 }) },
-        };
-        let dentry = Dentry::new(name, Arc::new(inode));
-        Some(Arc::new(dentry))
+        self.fs.io.run(|| {
+            let mut file = Ext4File::new();
+            self.fs
+                .ext4
+                .ext4_open_from(self.ino, &mut file, name, "r", false)
+                .ok()?;
+            Some(self.dentry(name, file.inode))
+        })
     }
 
+    /// 删除目录项并减少链接数，最后一个链接删除时释放数据块和 inode
+    ///
+    /// 还没有孤儿 inode，仍被打开的文件也会立即释放。
     fn unlink(self: Arc<Self>, name: &str) -> bool {
-        self.fs.ext4.ext4_file_remove(self.ino, name).is_ok()
+        self.fs.io.run(|| {
+            let Some(ino) = self.find(name) else {
+                return false;
+            };
+            let mut parent = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            let mut child = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), ino);
+            if is_dir_mode(child.inner.inode.mode) {
+                return false;
+            }
+            self.fs
+                .ext4
+                .ext4_dir_remove_entry_new(&mut parent, name, name.len() as u32);
+            let links = child.inner.inode.links_count.saturating_sub(1);
+            child.inner.inode.ext4_inode_set_links_cnt(links);
+            if links == 0 {
+                let _ = child.truncate_inode(0);
+                child.write_back_inode();
+                self.fs.ext4.ext4_ialloc_free_inode(ino, false);
+            } else {
+                child.write_back_inode();
+            }
+            self.fs.extent_cache.invalidate(ino);
+            self.fs.extent_cache.invalidate(self.ino);
+            true
+        })
+    }
+
+    /// 只能链接同一文件系统中的非目录文件
+    fn link(self: Arc<Self>, name: &str, target: Arc<Dentry>) -> bool {
+        self.fs.io.run(|| {
+            let Some((fs, ino)) = target.inode().cache_key() else {
+                return false;
+            };
+            if fs != Arc::as_ptr(&self.fs) as usize || self.find(name).is_some() {
+                return false;
+            }
+            let mut parent = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            let mut child = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), ino as u32);
+            if is_dir_mode(child.inner.inode.mode) {
+                return false;
+            }
+            self.fs
+                .ext4
+                .ext4_link(&mut parent, &mut child, name, name.len() as u32);
+            parent.write_back_inode();
+            child.write_back_inode();
+            self.fs.extent_cache.invalidate(self.ino);
+            true
+        })
     }
 
-    fn link(self: Arc<Self>, _name: &str, _target: Arc<Dentry>) -> bool {
-        todo!()
+    /// Rename an entry of this directory, replacing `new_name` if it exists
+    fn rename(self: Arc<Self>, old_name: &str, new_name: &str) -> bool {
+        self.fs.io.run(|| {
+            let Some(ino) = self.find(old_name) else {
+                return false;
+            };
+            if old_name == new_name {
+                return true;
+            }
+            if !self.remove_replaced(new_name) {
+                return false;
+            }
+            let mut parent = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            let mut child = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), ino);
+            // 先加新名字再删旧名字，中途失败时文件不会丢失
+            self.fs.ext4.ext4_dir_add_entry(
+                &mut parent,
+                &mut child,
+                new_name,
+                new_name.len() as u32,
+            );
+            self.fs
+                .ext4
+                .ext4_dir_remove_entry_new(&mut parent, old_name, old_name.len() as u32);
+            parent.write_back_inode();
+            self.fs.extent_cache.invalidate(self.ino);
+            true
+        })
     }
 
-    fn rename(self: Arc<Self>, _old_name: &str, _new_name: &str) -> bool {
-        todo!()
+    /// Move an entry into another directory of this filesystem, a moved directory's `..`
+    /// is pointed at its new parent
+    fn rename_to(self: Arc<Self>, old_name: &str, new_dir: Arc<dyn Inode>, new_name: &str) -> bool {
+        self.fs.io.run(|| {
+            let Some((fs, new_ino)) = new_dir.cache_key() else {
+                return false;
+            };
+            if fs != Arc::as_ptr(&self.fs) as usize {
+                return false;
+            }
+            if new_ino as u32 == self.ino {
+                return self.clone().rename(old_name, new_name);
+            }
+            let Some(ino) = self.find(old_name) else {
+                return false;
+            };
+            let new_dir = self.sibling(new_ino as u32);
+            // 新名字是同一个 inode 的另一个硬链接时什么也不做
+            if new_dir.find(new_name) == Some(ino) {
+                return true;
+            }
+            if !new_dir.remove_replaced(new_name) {
+                return false;
+            }
+            let ext4 = &self.fs.ext4;
+            let mut old_parent = Ext4InodeRef::get_inode_ref(Arc::downgrade(ext4), self.ino);
+            let mut new_parent = Ext4InodeRef::get_inode_ref(Arc::downgrade(ext4), new_dir.ino);
+            let mut child = Ext4InodeRef::get_inode_ref(Arc::downgrade(ext4), ino);
+            ext4.ext4_dir_add_entry(&mut new_parent, &mut child, new_name, new_name.len() as u32);
+            ext4.ext4_dir_remove_entry_new(&mut old_parent, old_name, old_name.len() as u32);
+            if is_dir_mode(child.inner.inode.mode) {
+                // 子目录的 .. 算作父目录的一个链接，随目录一起转移
+                ext4.ext4_dir_remove_entry_new(&mut child, "..", 2);
+                ext4.ext4_dir_add_entry(&mut child, &mut new_parent, "..", 2);
+                let links = old_parent.inner.inode.links_count.saturating_sub(1);
+                old_parent.inner.inode.ext4_inode_set_links_cnt(links);
+                let links = new_parent.inner.inode.links_count + 1;
+                new_parent.inner.inode.ext4_inode_set_links_cnt(links);
+                child.write_back_inode();
+            }
+            old_parent.write_back_inode();
+            new_parent.write_back_inode();
+            self.fs.extent_cache.invalidate(self.ino);
+            self.fs.extent_cache.invalidate(new_dir.ino);
+            self.fs.extent_cache.invalidate(ino);
+            true
+        })
     }
 
     fn mkdir(self: Arc<Self>, name: &str) -> bool {
-        self.fs.ext4.ext4_dir_mk(self.ino, name).is_ok()
+        self.fs.io.run(|| {
+            self.fs.extent_cache.invalidate(self.ino);
+            self.fs.ext4.ext4_dir_mk(self.ino, name).is_ok()
+        })
     }
 
     fn rmdir(self: Arc<Self>, name: &str) -> bool {
-        self.fs.ext4.ext4_dir_remove(self.ino, name).is_ok()
+        self.fs.io.run(|| {
+            self.fs.extent_cache.clear();
+            self.fs.ext4.ext4_dir_remove(self.ino, name).is_ok()
+        })
     }
 
     fn ls(&self) -> Vec<String> {
-        self.fs
-            .ext4
-            .read_dir_entry(self.ino as u64)
-            .iter()
-            .map(|x| x.get_name())
-            .collect()
+        self.fs.io.run(|| {
+            self.fs
+                .ext4
+                .read_dir_entry(self.ino as u64)
+                .iter()
+                .map(|x| x.get_name())
+                .collect()
+        })
     }
 
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let mut file = Ext4File::new();
-        file.inode = self.ino;
-        file.fpos = offset;
-        file.fsize = offset as u64;
-        let mut read_size = 0;
-        let _ = self
-            .fs
-            .ext4
-            .ext4_file_read(&mut file, buf, buf.len(), &mut read_size);
-        read_size
+        self.try_read_at(offset, buf).unwrap_or(0)
+    }
+
+    /// 读盘失败时返回已经读到的长度，一个字节也没读到才报告 EIO
+    fn try_read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, isize> {
+        self.fs.io.run(|| {
+            let map = self.fs.extent_cache.get(&self.fs.ext4, self.ino);
+            let end = min(offset + buf.len(), map.size as usize);
+            let mut pos = offset;
+            while pos < end {
+                let lblock = (pos / BLOCK_SIZE) as u32;
+                let in_block = pos % BLOCK_SIZE;
+                let len = min(BLOCK_SIZE - in_block, end - pos);
+                let dst = &mut buf[pos - offset..pos - offset + len];
+                let pblock = match map.lookup(lblock) {
+                    BlockLocation::Physical(pblock) => pblock,
+                    BlockLocation::Zero => 0,
+                    BlockLocation::Unknown => {
+                        let mut inode_ref =
+                            Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+                        let (mut iblock, mut fblock) = (lblock, 0);
+                        inode_ref.get_inode_dblk_idx(&mut iblock, &mut fblock, false);
+                        fblock
+                    }
+                };
+                if pblock == 0 {
+                    dst.fill(0);
+                } else {
+                    let data = self
+                        .fs
+                        .ext4
+                        .block_device
+                        .try_read_offset(pblock as usize * BLOCK_SIZE);
+                    let Ok(data) = data else {
+                        return if pos > offset {
+                            Ok(pos - offset)
+                        } else {
+                            Err(EIO)
+                        };
+                    };
+                    dst.copy_from_slice(&data[in_block..in_block + len]);
+                }
+                pos += len;
+            }
+            Ok(end.saturating_sub(offset))
+        })
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let inode_ref = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
-        let mut file = Ext4File::new();
-        file.fpos = offset;
-        file.fsize = inode_ref.inner.inode.inode_get_size();
-        self.fs.ext4.ext4_file_write(&mut file, buf, buf.len());
-        buf.len()
+        self.try_write_at(offset, buf).unwrap_or(0)
+    }
+
+    /// 逐块写入，空洞和文件末尾之后的块按需分配，写到文件末尾之后时更新文件大小
+    ///
+    /// 写盘失败时停在失败的块，返回已经写入的长度，一个字节也没写入才报告 EIO。
+    fn try_write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, isize> {
+        self.fs.io.run(|| {
+            let mut inode_ref =
+                Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            let device = &self.fs.ext4.block_device;
+            let end = offset + buf.len();
+            let mut pos = offset;
+            let mut failed = false;
+            while pos < end {
+                let lblock = (pos / BLOCK_SIZE) as u32;
+                let in_block = pos % BLOCK_SIZE;
+                let len = min(BLOCK_SIZE - in_block, end - pos);
+                let (mut pblock, mut count) = (0, 0);
+                inode_ref.get_blocks(lblock, 1, &mut pblock, false, &mut count);
+                let allocated = pblock == 0;
+                if allocated {
+                    inode_ref.get_blocks(lblock, 1, &mut pblock, true, &mut count);
+                    if pblock == 0 {
+                        break;
+                    }
+                }
+                let addr = pblock as usize * BLOCK_SIZE;
+                // 新分配的块里是旧数据，没有写到的部分要清零
+                let data = if allocated || len == BLOCK_SIZE {
+                    Ok(vec![0u8; BLOCK_SIZE])
+                } else {
+                    device.try_read_offset(addr)
+                };
+                let written = data.and_then(|mut data| {
+                    data[in_block..in_block + len]
+                        .copy_from_slice(&buf[pos - offset..pos - offset + len]);
+                    device.try_write_offset(addr, &data)
+                });
+                if written.is_err() {
+                    failed = true;
+                    break;
+                }
+                pos += len;
+            }
+            if pos as u64 > inode_ref.inner.inode.inode_get_size() {
+                inode_ref.inner.inode.ext4_inode_set_size(pos as u64);
+            }
+            inode_ref.write_back_inode();
+            self.fs.extent_cache.invalidate(self.ino);
+            if failed && pos == offset {
+                return Err(EIO);
+            }
+            Ok(pos - offset)
+        })
+    }
+
+    /// 只创建快速符号链接，目标存放在 inode 的 `i_block` 中，不占数据块
+    fn symlink(self: Arc<Self>, name: &str, target: &str) -> bool {
+        self.fs.io.run(|| {
+            if target.len() >= FAST_SYMLINK_MAX {
+                return false;
+            }
+            let Some(ino) = self.create_inode(name, InodeType::SymLink) else {
+                return false;
+            };
+            let mut inode_ref = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), ino);
+            let mut bytes = [0u8; FAST_SYMLINK_MAX];
+            bytes[..target.len()].copy_from_slice(target.as_bytes());
+            let inode = &mut inode_ref.inner.inode;
+            for (word, chunk) in inode.block.iter_mut().zip(bytes.chunks(4)) {
+                *word = u32::from_le_bytes(chunk.try_into().unwrap());
+            }
+            inode.ext4_inode_set_size(target.len() as u64);
+            inode_ref.write_back_inode();
+            true
+        })
+    }
+
+    fn read_link(&self) -> Option<String> {
+        self.fs.io.run(|| {
+            let inode_ref = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            let inode = &inode_ref.inner.inode;
+            if inode.mode & EXT4_INODE_MODE_TYPE_MASK != EXT4_INODE_MODE_SOFTLINK as u16 {
+                return None;
+            }
+            let size = inode.inode_get_size() as usize;
+            let target =
+                if inode.flags & EXT4_INODE_FLAG_EXTENTS as u32 == 0 && size < FAST_SYMLINK_MAX {
+                    inode
+                        .block
+                        .iter()
+                        .flat_map(|word| word.to_le_bytes())
+                        .take(size)
+                        .collect()
+                } else {
+                    let mut buf = vec![0u8; size];
+                    let len = self.read_at(0, &mut buf);
+                    buf.truncate(len);
+                    buf
+                };
+            String::from_utf8(target).ok()
+        })
+    }
+
+    fn perm(&self) -> Option<InodePerm> {
+        self.fs.io.run(|| {
+            let inode_ref = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            let inode = &inode_ref.inner.inode;
+            // uid/gid 的高 16 位存放在 osd2 中
+            Some(InodePerm {
+                mode: inode.mode & 0o7777,
+                uid:  inode.uid as u32 | (inode.osd2.l_i_uid_high as u32) << 16,
+                gid:  inode.gid as u32 | (inode.osd2.l_i_gid_high as u32) << 16,
+            })
+        })
+    }
+
+    fn cache_key(&self) -> Option<(usize, usize)> {
+        Some((Arc::as_ptr(&self.fs) as usize, self.ino as usize))
+    }
+
+    /// ext4_rs 不经过块缓存，每次修改都直接写盘，只需让设备把写缓存落盘
+    fn sync(&self) {
+        self.fs.io.run(|| {
+            self.fs.ext4.block_device.flush();
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.fs.io.run(|| {
+            let inode_ref = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            inode_ref.inner.inode.inode_get_size() as usize
+        })
     }
 }
 
 impl File for Ext4Inode {
     fn fstat(&self) -> Option<Stat> {
-        todo!()
+        self.fs.io.run(|| {
+            let inode_ref = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            let inode = &inode_ref.inner.inode;
+            // ext4 的文件类型位与 st_mode 的一致
+            let mut stat = Stat::new(
+                0,
+                self.ino as u64,
+                inode.mode as u32,
+                inode.links_count as u32,
+                0,
+                inode.inode_get_size() as i64,
+                inode.atime as i64,
+                inode.mtime as i64,
+                inode.ctime as i64,
+            );
+            if let Some(perm) = self.perm() {
+                stat.set_perm(perm);
+            }
+            stat.set_blocks(inode.blocks as u64 | (inode.osd2.l_i_blocks_high as u64) << 32);
+            // 大 inode 才有 *_extra 字段，低 2 位是秒的扩展位，其余是纳秒
+            if inode.i_extra_isize as usize >= EXTRA_TIMES_SIZE {
+                stat.set_nsec(
+                    (inode.i_atime_extra >> 2) as usize,
+                    (inode.i_mtime_extra >> 2) as usize,
+                    (inode.i_ctime_extra >> 2) as usize,
+                );
+            }
+            Some(stat)
+        })
     }
     fn is_dir(&self) -> bool {
-        todo!()
+        self.fs.io.run(|| {
+            let inode_ref = Ext4InodeRef::get_inode_ref(Arc::downgrade(&self.fs.ext4), self.ino);
+            is_dir_mode(inode_ref.inner.inode.mode)
+        })
     }
     fn read(&self, buf: &mut [u8]) -> usize {
         // TODO: 暂时不考虑 pos
-        let mut inner = self.inner.exclusive_access(file!(), line!());
+        let mut inner = self.inner.lock();
         let read_size = self.read_at(inner.fpos, buf);
         inner.fpos += read_size;
         read_size
@@ -122,14 +543,15 @@ impl File for Ext4Inode {
         true
     }
     fn write(&self, buf: &[u8]) -> usize {
-        // 暂时不考虑 pos
-        let write_size = self.write_at(0, buf);
+        let mut inner = self.inner.lock();
+        let write_size = self.write_at(inner.fpos, buf);
+        inner.fpos += write_size;
         write_size
     }
     fn read_all(&self) -> Vec<u8> {
-        todo!()
+        Inode::read_all(self)
     }
     fn hang_up(&self) -> bool {
-        todo!()
+        false
     }
 }
@@ -3,5 +3,6 @@ use alloc::vec::Vec;
 use crate::block::{block_dev::BlockDevice, BLOCK_SZ};
 
 mod defs;
+pub mod extent_cache;
 pub mod fs;
 pub mod inode;
@@ -1,4 +1,5 @@
-use alloc::{string::String, sync::Arc};
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::cmp::min;
 
 use super::fat::FAT;
 use crate::block::{block_cache::get_block_cache, block_dev::BlockDevice};
@@ -45,20 +46,22 @@ impl Fat32Dentry {
         }
     }
 
+    // 属性都在短目录项里，长文件名的目录项要先走到最后的短目录项
+
     pub fn is_system(&self) -> bool {
-        self.read_dentry().attr() == FileAttributes::SYSTEM
+        self.attr().contains(FileAttributes::SYSTEM)
     }
 
     pub fn is_dir(&self) -> bool {
-        self.read_dentry().attr() == FileAttributes::DIRECTORY
+        self.attr().contains(FileAttributes::DIRECTORY)
     }
 
     pub fn is_volume_id(&self) -> bool {
-        self.read_dentry().attr() == FileAttributes::VOLUME_ID
+        self.attr().contains(FileAttributes::VOLUME_ID)
     }
 
     pub fn is_file(&self) -> bool {
-        !self.is_dir() && !self.is_volume_id() && !self.is_system()
+        !self.is_dir() && !self.is_volume_id()
     }
 
     pub fn is_deleted(&self) -> bool {
@@ -66,7 +69,7 @@ impl Fat32Dentry {
     }
 
     pub fn file_size(&self) -> usize {
-        let (sector_id, offset) = self.to_end();
+        let (sector_id, offset) = self.short_entry();
         get_block_cache(sector_id, self.bdev.clone())
             .lock()
             .read(offset, |layout: &Fat32DentryLayout| {
@@ -75,7 +78,7 @@ impl Fat32Dentry {
     }
 
     pub fn set_file_size(&self, size: usize) {
-        let (sector_id, offset) = self.to_end();
+        let (sector_id, offset) = self.short_entry();
         get_block_cache(sector_id, self.bdev.clone()).lock().modify(
             offset,
             |layout: &mut Fat32DentryLayout| {
@@ -88,33 +91,42 @@ impl Fat32Dentry {
         self.read_dentry().is_long()
     }
 
+    /// The long name if there is one, otherwise the 8.3 name
+    ///
+    /// 长目录项按序号从大到小存放，第一项是名字的最后一段，逐项读到短目录项为止，再反过来拼接。
     pub fn name(&self) -> String {
-        if self.is_long() {
-            let mut name = String::new();
-            let mut sector_id = self.sector_id;
-            let mut offset = self.sector_offset;
-            loop {
-                let layout = get_block_cache(sector_id, self.bdev.clone())
-                    .lock()
-                    .read(offset, |layout: &Fat32LDentryLayout| *layout);
-                name.insert_str(0, &layout.name());
-                if layout.is_end() {
-                    break;
-                }
-                (sector_id, offset) = self.fat.next_dentry_id(sector_id, offset).unwrap();
-            }
-            name
-        } else {
-            get_block_cache(self.sector_id, self.bdev.clone())
+        if !self.is_long() {
+            return self.read_dentry().name();
+        }
+        let mut parts = Vec::new();
+        let mut sector_id = self.sector_id;
+        let mut offset = self.sector_offset;
+        loop {
+            let layout = get_block_cache(sector_id, self.bdev.clone())
                 .lock()
-                .read(self.sector_offset, |layout: &Fat32DentryLayout| {
-                    layout.name()
-                })
+                .read(offset, |layout: &Fat32LDentryLayout| *layout);
+            if !layout.is_long() {
+                break;
+            }
+            parts.push(layout.units());
+            (sector_id, offset) = self.fat.next_dentry_id(sector_id, offset).unwrap();
         }
+        let units = parts.into_iter().rev().flatten();
+        char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+
+    /// Attributes in the short entry, also valid for long-name entries
+    pub fn attr(&self) -> FileAttributes {
+        let (sector_id, offset) = self.short_entry();
+        get_block_cache(sector_id, self.bdev.clone())
+            .lock()
+            .read(offset, |layout: &Fat32DentryLayout| layout.attr())
     }
 
     pub fn start_cluster_id(&self) -> usize {
-        let (sector_id, offset) = self.to_end();
+        let (sector_id, offset) = self.short_entry();
         get_block_cache(sector_id, self.bdev.clone())
             .lock()
             .read(offset, |layout: &Fat32DentryLayout| {
@@ -122,22 +134,31 @@ impl Fat32Dentry {
             })
     }
 
-    fn to_end(&self) -> (usize, usize) {
-        if !self.is_long() {
-            return (self.sector_id, self.sector_offset);
-        }
+    /// Give a file created empty by another system its first cluster
+    pub fn set_start_cluster_id(&self, cluster_id: usize) {
+        let (sector_id, offset) = self.short_entry();
+        get_block_cache(sector_id, self.bdev.clone()).lock().modify(
+            offset,
+            |layout: &mut Fat32DentryLayout| {
+                layout.start_cluster_high = (cluster_id >> 16) as u16;
+                layout.start_cluster_low = cluster_id as u16;
+            },
+        );
+    }
+
+    /// Position of the short entry
+    pub fn short_entry(&self) -> (usize, usize) {
         let mut sector_id = self.sector_id;
         let mut offset = self.sector_offset;
         loop {
             let layout = get_block_cache(sector_id, self.bdev.clone())
                 .lock()
-                .read(offset, |layout: &Fat32LDentryLayout| *layout);
-            (sector_id, offset) = self.fat.next_dentry_id(sector_id, offset).unwrap();
-            if layout.is_end() {
-                break;
+                .read(offset, |layout: &Fat32DentryLayout| *layout);
+            if !layout.is_long() {
+                return (sector_id, offset);
             }
+            (sector_id, offset) = self.fat.next_dentry_id(sector_id, offset).unwrap();
         }
-        (sector_id, offset)
     }
 
     fn read_dentry(&self) -> Fat32DentryLayout {
@@ -145,14 +166,77 @@ impl Fat32Dentry {
             .lock()
             .read(self.sector_offset, |layout: &Fat32DentryLayout| *layout)
     }
+}
 
-    fn write_dentry(&self, layout: &Fat32DentryLayout) {
-        get_block_cache(self.sector_id, self.bdev.clone())
-            .lock()
-            .modify(self.sector_offset, |l: &mut Fat32DentryLayout| {
-                *l = *layout;
-            });
+/// Whether `c` may appear in an 8.3 name
+fn is_short_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// The 8.3 name stored for `name` and the case flags of the short entry, `None` if `name`
+/// needs a long name
+///
+/// 和 Linux 的 vfat 一样，主名和扩展名各自全小写的名字只用短目录项保存，靠保留字节里的
+/// 大小写标志还原。
+pub fn short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let mut short = [b' '; 11];
+    if name == "." || name == ".." {
+        short[..name.len()].copy_from_slice(name.as_bytes());
+        return Some((short, 0));
+    }
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+    let mut case = 0;
+    for (part, start, lower_flag) in [(base, 0, 0x08), (ext, 8, 0x10)] {
+        if !part.bytes().all(is_short_char) {
+            return None;
+        }
+        let lower = part.bytes().any(|c| c.is_ascii_lowercase());
+        if lower && part.bytes().any(|c| c.is_ascii_uppercase()) {
+            return None;
+        }
+        if lower {
+            case |= lower_flag;
+        }
+        short[start..start + part.len()].copy_from_slice(part.to_ascii_uppercase().as_bytes());
     }
+    Some((short, case))
+}
+
+/// The `n`th 8.3 name generated for a long name, in the form `BASIS~N.EXT`
+pub fn numbered_short_name(name: &str, n: usize) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+    // 空格和点直接去掉，其他不能出现在短名字里的字符换成下划线
+    let convert = |c: char| match c {
+        ' ' | '.' => None,
+        c if c.is_ascii() && is_short_char(c as u8) => Some(c.to_ascii_uppercase() as u8),
+        _ => Some(b'_'),
+    };
+    let tail = format!("~{}", n);
+    let basis: Vec<u8> = base
+        .chars()
+        .filter_map(convert)
+        .take(8 - tail.len())
+        .collect();
+    short[..basis.len()].copy_from_slice(&basis);
+    short[basis.len()..basis.len() + tail.len()].copy_from_slice(tail.as_bytes());
+    let ext: Vec<u8> = ext.chars().filter_map(convert).take(3).collect();
+    short[8..8 + ext.len()].copy_from_slice(&ext);
+    short
+}
+
+/// Checksum of an 8.3 name, kept in each of its long-name entries
+pub fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -174,40 +258,28 @@ pub struct Fat32DentryLayout {
 }
 
 impl Fat32DentryLayout {
+    /// A short entry named `file_name`, which is turned into an 8.3 name if it is not one
     pub fn new(
         file_name: &str, attr: FileAttributes, start_cluster: usize, file_size: u32,
+    ) -> Self {
+        let (name, case) =
+            short_name(file_name).unwrap_or_else(|| (numbered_short_name(file_name, 1), 0));
+        Self::with_short_name(name, case, attr, start_cluster, file_size)
+    }
+
+    /// A short entry with the raw 8.3 name `short_name` and the case flags `case`
+    pub fn with_short_name(
+        short_name: [u8; 11], case: u8, attr: FileAttributes, start_cluster: usize, file_size: u32,
     ) -> Self {
         let mut name = [0u8; 8];
         let mut ext = [0u8; 3];
-        let mut name_capital = false;
-        let mut ext_capital = false;
-        let mut i = 0;
-        for c in file_name.chars() {
-            if c == '.' {
-                i = 8;
-                continue;
-            }
-            if i < 8 {
-                name[i] = c as u8;
-                if c.is_ascii_uppercase() {
-                    name_capital = true;
-                }
-            } else if i < 11 {
-                ext[i - 8] = c as u8;
-                if c.is_ascii_uppercase() {
-                    ext_capital = true;
-                }
-            } else {
-                break;
-            }
-            i += 1;
-        }
+        name.copy_from_slice(&short_name[..8]);
+        ext.copy_from_slice(&short_name[8..]);
         Self {
             name,
             ext,
             attr: attr.bits(),
-            reserved: if name_capital { 0x08 } else { 0x00 }
-                | if ext_capital { 0x10 } else { 0x00 },
+            reserved: case,
             create_time_ms: 0,
             create_time: 0,
             create_date: 0,
@@ -248,20 +320,28 @@ impl Fat32DentryLayout {
         self.name[0] = 0xE5;
     }
 
+    /// The raw 8.3 name, padded with spaces
+    pub fn short_name(&self) -> [u8; 11] {
+        let mut short = [0u8; 11];
+        short[..8].copy_from_slice(&self.name);
+        short[8..].copy_from_slice(&self.ext);
+        short
+    }
+
     pub fn name(&self) -> String {
-        let mut name = String::new();
-        for i in self.name.iter() {
-            if *i == 0x20 {
-                break;
-            }
-            name.push(char::from_u32(*i as u32).unwrap());
-        }
-        name.push('.');
-        for i in self.ext.iter() {
-            if *i == 0x20 {
-                break;
-            }
-            name.push(char::from_u32(*i as u32).unwrap());
+        // 保留字节的 0x08 和 0x10 分别表示主名和扩展名以小写显示
+        let part = |bytes: &[u8], lower: bool| -> String {
+            bytes
+                .iter()
+                .take_while(|c| **c != 0x20 && **c != 0x00)
+                .map(|c| char::from(if lower { c.to_ascii_lowercase() } else { *c }))
+                .collect()
+        };
+        let mut name = part(&self.name, self.reserved & 0x08 != 0);
+        let ext = part(&self.ext, self.reserved & 0x10 != 0);
+        if !ext.is_empty() {
+            name.push('.');
+            name.push_str(&ext);
         }
         name
     }
@@ -282,21 +362,27 @@ pub struct Fat32LDentryLayout {
 }
 
 impl Fat32LDentryLayout {
-    pub fn new(mut order: u8, name: &str, is_end: bool) -> Self {
+    pub fn new(order: u8, name: &str, is_end: bool) -> Self {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        Self::from_units(order, &units, is_end, 0)
+    }
+
+    /// An entry holding up to 13 UTF-16 units of a long name
+    ///
+    /// 不足 13 个字符时以 0x0000 结尾，其余位置填 0xFFFF。
+    pub fn from_units(mut order: u8, units: &[u16], is_end: bool, checksum: u8) -> Self {
+        let len = min(units.len(), 13);
+        let mut chars = [0xFFFFu16; 13];
+        chars[..len].copy_from_slice(&units[..len]);
+        if len < 13 {
+            chars[len] = 0x0000;
+        }
         let mut name1 = [0u16; 5];
         let mut name2 = [0u16; 6];
         let mut name3 = [0u16; 2];
-        let mut i = 0;
-        for c in name.chars() {
-            if i < 5 {
-                name1[i] = c as u16;
-            } else if i < 11 {
-                name2[i - 5] = c as u16;
-            } else {
-                name3[i - 11] = c as u16;
-            }
-            i += 1;
-        }
+        name1.copy_from_slice(&chars[..5]);
+        name2.copy_from_slice(&chars[5..11]);
+        name3.copy_from_slice(&chars[11..]);
         if is_end {
             order |= 0x40;
         }
@@ -305,12 +391,13 @@ impl Fat32LDentryLayout {
             name1,
             attr: 0x0F,
             reserved: 0,
-            checksum: 0,
+            checksum,
             name2,
             start_cluster: 0,
             name3,
         }
     }
+
     pub fn from_short_layout(layout: &Fat32DentryLayout) -> Option<Self> {
         if layout.attr & 0x0F != 0x0F {
             return None;
@@ -356,35 +443,76 @@ impl Fat32LDentryLayout {
         !self.is_end() && !self.is_deleted() && !self.is_empty()
     }
 
+    pub fn is_long(&self) -> bool {
+        self.attr & 0x0F == 0x0F
+    }
+
+    /// UTF-16 units of the part of the name in this entry
+    pub fn units(&self) -> Vec<u16> {
+        let (name1, name2, name3) = (self.name1, self.name2, self.name3);
+        name1
+            .into_iter()
+            .chain(name2)
+            .chain(name3)
+            .take_while(|c| *c != 0x0000)
+            .collect()
+    }
+
     pub fn name(&self) -> String {
-        let mut name = String::new();
-        let mut finished = false;
-        for i in self.name1 {
-            if i == 0x0000 {
-                finished = true;
-                break;
-            }
-            name.push(char::from_u32(i as u32).unwrap());
-        }
-        if finished {
-            return name;
-        }
-        for i in self.name2 {
-            if i == 0x0000 {
-                finished = true;
-                break;
-            }
-            name.push(char::from_u32(i as u32).unwrap());
-        }
-        if finished {
-            return name;
-        }
-        for i in self.name3 {
-            if i == 0x0000 {
-                break;
-            }
-            name.push(char::from_u32(i as u32).unwrap());
-        }
-        name
+        char::decode_utf16(self.units())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem::size_of;
+
+    use super::*;
+
+    #[test_case]
+    fn dentry_layout_size() {
+        assert_eq!(size_of::<Fat32DentryLayout>(), 32);
+        assert_eq!(size_of::<Fat32LDentryLayout>(), 32);
+    }
+
+    #[test_case]
+    fn short_dentry_layout() {
+        let mut layout =
+            Fat32DentryLayout::new("BOOT.TXT", FileAttributes::ARCHIVE, 0x12_3456, 100);
+        assert_eq!(layout.start_cluster_id(), 0x12_3456);
+        assert_eq!(layout.file_size(), 100);
+        assert_eq!(layout.attr(), FileAttributes::ARCHIVE);
+        assert!(!layout.is_long() && !layout.is_deleted() && !layout.is_empty());
+        layout.set_deleted();
+        assert!(layout.is_deleted());
+    }
+
+    #[test_case]
+    fn long_dentry_layout() {
+        let layout = Fat32LDentryLayout::new(1, "hello.txt", true);
+        assert!(layout.is_end());
+        assert_eq!(layout.name(), "hello.txt");
+        // 长目录项按短目录项的布局读出后能还原
+        let short: Fat32DentryLayout = unsafe { core::mem::transmute(layout) };
+        assert!(short.is_long());
+        let long = Fat32LDentryLayout::from_short_layout(&short).unwrap();
+        assert_eq!(long.name(), "hello.txt");
+    }
+
+    #[test_case]
+    fn short_names() {
+        assert_eq!(short_name("BOOT.TXT"), Some((*b"BOOT    TXT", 0)));
+        assert_eq!(short_name("readme"), Some((*b"README     ", 0x08)));
+        assert_eq!(short_name("Readme.txt"), None);
+        assert_eq!(short_name("archive.tar.gz"), None);
+        assert_eq!(&numbered_short_name("archive.tar.gz", 1), b"ARCHIV~1GZ ");
+        // 大小写标志还原出小写的名字
+        let (short, case) = short_name("readme").unwrap();
+        let layout = Fat32DentryLayout::with_short_name(short, case, FileAttributes::ARCHIVE, 2, 0);
+        assert_eq!(layout.name(), "readme");
+        let dotdot = Fat32DentryLayout::new("..", FileAttributes::DIRECTORY, 0, 0);
+        assert_eq!(dotdot.name(), "..");
     }
 }
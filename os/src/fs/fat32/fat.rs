@@ -1,12 +1,20 @@
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use super::super_block::Fat32SB;
 use crate::block::{block_cache::get_block_cache, block_dev::BlockDevice, BLOCK_SZ};
 
+/// FAT entry of the last cluster of a chain
+pub const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+/// FAT 表项只有低 28 位有效
+const ENTRY_MASK: u32 = 0x0FFF_FFFF;
+
 pub struct FAT {
     pub start_sector: usize,
     pub sb:           Arc<Fat32SB>,
     pub bdev:         Arc<dyn BlockDevice>,
+    /// where the search for a free cluster starts, just after the last allocated one
+    next_free:        AtomicUsize,
 }
 
 impl FAT {
@@ -15,89 +23,114 @@ impl FAT {
             start_sector: sb.reserved_sectors_cnt as usize,
             sb,
             bdev: Arc::clone(bdev),
+            next_free: AtomicUsize::new(2),
         }
     }
 
-    /// allocate a new cluster
-    pub fn alloc_new_cluster(&self) -> Option<usize> {
-        let mut offset = self.start_sector * BLOCK_SZ + 3 * 4;
-        let mut cluster_id = 0;
-        loop {
-            let sector_id = offset / BLOCK_SZ;
-            let sector_offset = offset % BLOCK_SZ;
-            get_block_cache(sector_id, Arc::clone(&self.bdev))
+    fn read_entry(&self, cluster: usize) -> u32 {
+        let fat_offset = self.start_sector * BLOCK_SZ + cluster * 4;
+        get_block_cache(fat_offset / BLOCK_SZ, Arc::clone(&self.bdev))
+            .lock()
+            .read(fat_offset % BLOCK_SZ, |data: &[u8; 4]| {
+                u32::from_le_bytes(*data) & ENTRY_MASK
+            })
+    }
+
+    /// Set the entry of `cluster` in every copy of the FAT
+    fn write_entry(&self, cluster: usize, value: u32) {
+        for fat in 0..self.sb.fat_cnt as usize {
+            let fat_start = self.start_sector + fat * self.sb.fat_size_32 as usize;
+            let fat_offset = fat_start * BLOCK_SZ + cluster * 4;
+            get_block_cache(fat_offset / BLOCK_SZ, Arc::clone(&self.bdev))
                 .lock()
-                .read(sector_offset, |num: &u32| {
-                    if *num == 0 {
-                        cluster_id = (offset - self.start_sector * BLOCK_SZ) / 4;
-                    }
+                .modify(fat_offset % BLOCK_SZ, |data: &mut [u8; 4]| {
+                    // 高 4 位保留，写入时保持原值
+                    let old = u32::from_le_bytes(*data);
+                    *data = ((old & !ENTRY_MASK) | (value & ENTRY_MASK)).to_le_bytes();
                 });
-            if cluster_id != 0 {
-                break;
-            }
-            offset += 4;
         }
-        let sector_id = offset / BLOCK_SZ;
-        let sector_offset = offset % BLOCK_SZ;
-        get_block_cache(sector_id, Arc::clone(&self.bdev))
-            .lock()
-            .modify(sector_offset, |num: &mut u32| {
-                *num = 0x0FFFFFFFu32;
-            });
+    }
+
+    /// Sectors holding the entry of `cluster` in every copy of the FAT
+    pub fn entry_sectors(&self, cluster: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.sb.fat_cnt as usize).map(move |fat| {
+            let fat_start = self.start_sector + fat * self.sb.fat_size_32 as usize;
+            fat_start + cluster * 4 / BLOCK_SZ
+        })
+    }
+
+    /// allocate a new cluster, `None` if the disk is full
+    pub fn alloc_new_cluster(&self) -> Option<usize> {
+        let cluster_cnt = self.sb.cluster_cnt();
+        let start = self.next_free.load(Ordering::Relaxed).clamp(2, cluster_cnt);
+        let cluster_id = (start..cluster_cnt)
+            .chain(2..start)
+            .find(|cluster| self.read_entry(*cluster) == 0)?;
+        self.write_entry(cluster_id, END_OF_CHAIN);
+        self.next_free.store(cluster_id + 1, Ordering::Relaxed);
         Some(cluster_id)
     }
 
+    /// Append a new cluster to the chain ending at `cluster_id`
     pub fn increase_cluster(&self, cluster_id: usize) -> Option<usize> {
         let new_cluster_id = self.alloc_new_cluster()?;
-        let fat_offset = self.start_sector * BLOCK_SZ + cluster_id * 4;
-        let fat_sector = fat_offset / BLOCK_SZ;
-        let fat_offset_in_sector = fat_offset % BLOCK_SZ;
-        get_block_cache(fat_sector, Arc::clone(&self.bdev))
-            .lock()
-            .modify(fat_offset_in_sector, |num: &mut u32| {
-                *num = new_cluster_id as u32;
-            });
+        self.write_entry(cluster_id, new_cluster_id as u32);
         Some(new_cluster_id)
     }
 
+    /// Free every cluster of the chain starting at `cluster_id`
+    pub fn free_chain(&self, cluster_id: usize) {
+        let mut cluster = Some(cluster_id).filter(|cluster| *cluster >= 2);
+        while let Some(cluster_id) = cluster {
+            cluster = self.next_cluster_id(cluster_id);
+            self.write_entry(cluster_id, 0);
+            self.next_free.fetch_min(cluster_id, Ordering::Relaxed);
+        }
+    }
+
+    /// Make `cluster_id` the last cluster of its chain, freeing the clusters after it
+    pub fn truncate_chain(&self, cluster_id: usize) {
+        if let Some(next) = self.next_cluster_id(cluster_id) {
+            self.write_entry(cluster_id, END_OF_CHAIN);
+            self.free_chain(next);
+        }
+    }
+
     /// get next cluster number
     pub fn next_cluster_id(&self, cluster: usize) -> Option<usize> {
-        let fat_offset = self.start_sector * BLOCK_SZ + cluster * 4;
-        let fat_sector = fat_offset / BLOCK_SZ;
-        let fat_offset_in_sector = fat_offset % BLOCK_SZ;
-        let mut next_cluster = 0;
-        get_block_cache(fat_sector, Arc::clone(&self.bdev))
-            .lock()
-            .read(fat_offset_in_sector, |data: &[u8; 4]| {
-                next_cluster = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-            });
-        if next_cluster >= 0x0FFFFFF8 {
+        if cluster < 2 || cluster >= self.sb.cluster_cnt() {
+            return None;
+        }
+        let next_cluster = self.read_entry(cluster) as usize;
+        // 空闲、保留和坏簇标记都不是合法的后继
+        if next_cluster < 2 || next_cluster >= self.sb.cluster_cnt() {
             None
         } else {
-            Some(next_cluster as usize)
+            Some(next_cluster)
         }
     }
 
-    /// get next dentry sector id and offset
+    /// get next dentry sector id and offset, `None` at the end of the directory's chain
     pub fn next_dentry_id(&self, sector_id: usize, offset: usize) -> Option<(usize, usize)> {
         if offset >= 512 || offset % 32 != 0 {
             return None;
         }
         let next_offset = offset + 32;
-        if next_offset >= 512 {
-            let next_sector_id = sector_id + 1;
-            if next_sector_id % self.sb.sectors_per_cluster as usize == 0 {
-                self.next_cluster_id(sector_id)
-                    .map(|next_sector_id| (next_sector_id, 0))
-            } else {
-                Some((next_sector_id, 0))
-            }
+        if next_offset < 512 {
+            return Some((sector_id, next_offset));
+        }
+        let next_sector_id = sector_id + 1;
+        let cluster_offset = next_sector_id.checked_sub(self.sb.root_sector())?;
+        if cluster_offset % self.sb.sectors_per_cluster as usize == 0 {
+            let cluster = self.sector_id_to_cluster_id(sector_id)?;
+            let next_cluster = self.next_cluster_id(cluster)?;
+            self.cluster_id_to_sector_id(next_cluster)
+                .map(|next_sector_id| (next_sector_id, 0))
         } else {
-            Some((sector_id, next_offset))
+            Some((next_sector_id, 0))
         }
     }
 
-    #[allow(unused)]
     /// cluster id to sector id
     pub fn cluster_id_to_sector_id(&self, cluster: usize) -> Option<usize> {
         if cluster < 2 {
@@ -107,7 +140,6 @@ impl FAT {
         Some(res)
     }
 
-    #[allow(unused)]
     /// sector id to cluster id
     pub fn sector_id_to_cluster_id(&self, sector: usize) -> Option<usize> {
         if sector < self.sb.root_sector() {
@@ -1,24 +1,48 @@
-use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
 use core::cmp::min;
 
+use spin::{Mutex, RwLock};
+
 use super::{
-    dentry::{Fat32Dentry, Fat32DentryLayout, Fat32LDentryLayout, FileAttributes},
+    dentry::{
+        checksum,
+        numbered_short_name,
+        short_name,
+        Fat32Dentry,
+        Fat32DentryLayout,
+        Fat32LDentryLayout,
+        FileAttributes,
+    },
     fat::FAT,
     inode::{Fat32Inode, Fat32InodeType},
     super_block::{Fat32SB, Fat32SBLayout},
 };
 use crate::{
     block::{block_cache::get_block_cache, block_dev::BlockDevice, BLOCK_SZ},
+    drivers::IoLock,
     fs::{
         fs::{FileSystem, FileSystemType},
         inode::Inode,
     },
 };
 
+/// Longest long name, in UTF-16 units
+const MAX_NAME_LEN: usize = 255;
+
 pub struct Fat32FS {
-    pub sb:   Fat32SB,
-    pub fat:  Arc<FAT>,
-    pub bdev: Arc<dyn BlockDevice>,
+    pub sb:      Fat32SB,
+    pub fat:     Arc<FAT>,
+    pub bdev:    Arc<dyn BlockDevice>,
+    /// start cluster -> lock shared by all inodes of that file, see [`Fat32FS::inode_lock`]
+    inode_locks: Mutex<BTreeMap<usize, Weak<RwLock<()>>>>,
+    /// held by every inode operation outside the inode locks, so they may sleep on the disk
+    pub io:      IoLock,
 }
 
 impl FileSystem for Fat32FS {
@@ -27,19 +51,23 @@ impl FileSystem for Fat32FS {
     }
     fn root_inode(self: Arc<Self>) -> Arc<dyn Inode> {
         let start_cluster = self.sb.root_cluster as usize;
-        let bdev = Arc::clone(&self.bdev);
-        let fat32_inode = Fat32Inode {
-            type_: Fat32InodeType::Dir,
+        Arc::new(Fat32Inode::new(
+            Fat32InodeType::Dir,
             start_cluster,
-            fs: self.clone(),
-            bdev: Arc::clone(&bdev),
-            dentry: None,
-        };
-        Arc::new(fat32_inode)
+            None,
+            &self,
+        ))
     }
 }
 
 impl Fat32FS {
+    /// Whether the device holds a fat32 file system that [`Fat32FS::load`] accepts
+    pub fn probe(bdev: &Arc<dyn BlockDevice>) -> bool {
+        get_block_cache(0, Arc::clone(bdev))
+            .lock()
+            .read(0, |sb_layout: &Fat32SBLayout| sb_layout.is_valid())
+    }
+
     /// load a exist fat32 file system from block device
     pub fn load(bdev: Arc<dyn BlockDevice>) -> Arc<Self> {
         get_block_cache(0, Arc::clone(&bdev))
@@ -53,11 +81,28 @@ impl Fat32FS {
                         &bdev,
                     )),
                     bdev,
+                    inode_locks: Mutex::new(BTreeMap::new()),
+                    io: IoLock::new(),
                 };
                 Arc::new(fat32fs)
             })
     }
 
+    /// Lock of the file starting at `start_cluster`
+    ///
+    /// lookup 每次都会创建新的 [`Fat32Inode`]，锁按起始簇共享，同一文件的所有 inode 拿到同一把锁。
+    /// 加锁顺序：先目录后目录项；同时锁多个目录项时按起始簇从小到大加锁。
+    pub fn inode_lock(&self, start_cluster: usize) -> Arc<RwLock<()>> {
+        let mut locks = self.inode_locks.lock();
+        if let Some(lock) = locks.get(&start_cluster).and_then(Weak::upgrade) {
+            return lock;
+        }
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        let lock = Arc::new(RwLock::new(()));
+        locks.insert(start_cluster, Arc::downgrade(&lock));
+        lock
+    }
+
     /// get cluster chain
     pub fn cluster_chain(&self, start_cluster: usize) -> Vec<usize> {
         let mut cluster_chain = Vec::new();
@@ -73,166 +118,189 @@ impl Fat32FS {
         cluster_chain
     }
 
-    /// read a cluster
-    pub fn read_cluster(&self, cluster: usize, buf: &mut [u8; 4096]) {
-        let cluster_offset =
-            self.sb.root_sector() + (cluster - 2) * self.sb.sectors_per_cluster as usize;
-        let cluster_size = self.sb.bytes_per_sector as usize * self.sb.sectors_per_cluster as usize;
-        let mut read_size = 0;
-        for i in 0..self.sb.sectors_per_cluster {
-            get_block_cache(cluster_offset + i as usize, Arc::clone(&self.bdev))
+    pub fn cluster_size(&self) -> usize {
+        self.sb.cluster_size()
+    }
+
+    /// read a cluster into `buf`, which holds [`Fat32FS::cluster_size`] bytes
+    pub fn read_cluster(&self, cluster: usize, buf: &mut [u8]) {
+        let cluster_offset = self.fat.cluster_id_to_sector_id(cluster).unwrap();
+        for (i, chunk) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            get_block_cache(cluster_offset + i, Arc::clone(&self.bdev))
                 .lock()
                 .read(0, |data: &[u8; BLOCK_SZ]| {
-                    let copy_size = core::cmp::min(cluster_size - read_size, data.len());
-                    buf[read_size..read_size + copy_size].copy_from_slice(&data[..copy_size]);
-                    read_size += copy_size;
+                    chunk.copy_from_slice(&data[..chunk.len()]);
                 });
         }
     }
 
-    /// write a cluster
-    pub fn write_cluster(&self, cluster: usize, buf: &[u8; 4096]) {
-        let cluster_offset =
-            self.sb.root_sector() + (cluster - 2) * self.sb.sectors_per_cluster as usize;
-        let cluster_size = self.sb.bytes_per_sector as usize * self.sb.sectors_per_cluster as usize;
-        let mut write_size = 0;
-        for i in 0..self.sb.sectors_per_cluster {
-            get_block_cache(cluster_offset + i as usize, Arc::clone(&self.bdev))
+    /// write a cluster from `buf`, which holds [`Fat32FS::cluster_size`] bytes
+    pub fn write_cluster(&self, cluster: usize, buf: &[u8]) {
+        let cluster_offset = self.fat.cluster_id_to_sector_id(cluster).unwrap();
+        for (i, chunk) in buf.chunks(BLOCK_SZ).enumerate() {
+            get_block_cache(cluster_offset + i, Arc::clone(&self.bdev))
                 .lock()
                 .modify(0, |data: &mut [u8; BLOCK_SZ]| {
-                    let copy_size = core::cmp::min(cluster_size - write_size, data.len());
-                    data[..copy_size].copy_from_slice(&buf[write_size..write_size + copy_size]);
-                    write_size += copy_size;
+                    data[..chunk.len()].copy_from_slice(chunk);
                 });
         }
     }
 
-    /// get next dentry sector id and offset
+    pub fn zero_cluster(&self, cluster: usize) {
+        self.write_cluster(cluster, &vec![0u8; self.cluster_size()]);
+    }
+
+    /// get next dentry sector id and offset, `None` at the end of the directory
     pub fn next_dentry_id(&self, sector_id: usize, offset: usize) -> Option<(usize, usize)> {
-        if offset >= 512 || offset % 32 != 0 {
-            return None;
-        }
-        let next_offset = offset + 32;
-        if next_offset >= 512 {
-            let next_sector_id = sector_id + 1;
-            if next_sector_id % self.sb.sectors_per_cluster as usize == 0 {
-                self.fat
-                    .next_cluster_id(sector_id)
-                    .map(|next_sector_id| (next_sector_id, 0))
-            } else {
-                Some((next_sector_id, 0))
-            }
-        } else {
-            Some((sector_id, next_offset))
-        }
+        self.fat.next_dentry_id(sector_id, offset)
     }
 
-    /// get a dentry with sector id and offset
+    /// get a dentry with sector id and offset, and move them to the next dentry
+    ///
+    /// 已删除的目录项返回 `is_deleted()` 为真的目录项，调用者应当跳过；目录的最后一项之后，
+    /// 把 offset 置为无效值，之后的调用都返回 `None`。
     pub fn get_dentry(&self, sector_id: &mut usize, offset: &mut usize) -> Option<Fat32Dentry> {
         if *offset >= 512 || *offset % 32 != 0 {
             return None;
         }
-        let mut is_long_entry = false;
-        let dentry = get_block_cache(*sector_id, Arc::clone(&self.bdev))
+        let layout = get_block_cache(*sector_id, Arc::clone(&self.bdev))
             .lock()
-            .read(*offset, |layout: &Fat32DentryLayout| {
-                if layout.is_empty() {
-                    return None;
-                }
-                if layout.is_deleted() {
-                    return Some(Fat32Dentry::new_deleted(&self.bdev, &self.fat));
-                }
-                if layout.is_long() {
-                    is_long_entry = true;
-                }
-                Some(Fat32Dentry::new(*sector_id, *offset, &self.bdev, &self.fat))
-            });
-        if is_long_entry {
-            let mut is_end = false;
-            loop {
-                get_block_cache(*sector_id, Arc::clone(&self.bdev))
-                    .lock()
-                    .read(*offset, |layout: &Fat32LDentryLayout| {
-                        if layout.is_end() {
-                            is_end = true;
-                        }
-                    });
-                (*sector_id, *offset) = self.next_dentry_id(*sector_id, *offset).unwrap();
-                if is_end {
-                    break;
+            .read(*offset, |layout: &Fat32DentryLayout| *layout);
+        if layout.is_empty() {
+            return None;
+        }
+        let dentry = if layout.is_deleted() {
+            Fat32Dentry::new_deleted(&self.bdev, &self.fat)
+        } else {
+            let dentry = Fat32Dentry::new(*sector_id, *offset, &self.bdev, &self.fat);
+            // 跳过长目录项，停在短目录项上
+            if layout.is_long() {
+                (*sector_id, *offset) = dentry.short_entry();
+            }
+            dentry
+        };
+        (*sector_id, *offset) = self
+            .next_dentry_id(*sector_id, *offset)
+            .unwrap_or((*sector_id, 512));
+        Some(dentry)
+    }
+
+    /// Raw 8.3 names in the directory starting at `cluster_id`
+    fn short_names(&self, cluster_id: usize) -> Vec<[u8; 11]> {
+        let mut names = Vec::new();
+        let mut pos = self
+            .fat
+            .cluster_id_to_sector_id(cluster_id)
+            .map(|sector_id| (sector_id, 0));
+        while let Some((sector_id, offset)) = pos {
+            let layout = get_block_cache(sector_id, Arc::clone(&self.bdev))
+                .lock()
+                .read(offset, |layout: &Fat32DentryLayout| *layout);
+            if layout.is_empty() {
+                break;
+            }
+            if !layout.is_deleted() && !layout.is_long() {
+                names.push(layout.short_name());
+            }
+            pos = self.next_dentry_id(sector_id, offset);
+        }
+        names
+    }
+
+    /// Find `count` consecutive free dentries in the directory starting at `cluster_id`,
+    /// growing the directory by a cluster when it is full
+    fn alloc_dentries(&self, cluster_id: usize, count: usize) -> Option<Vec<(usize, usize)>> {
+        let mut run = Vec::new();
+        let mut pos = (self.fat.cluster_id_to_sector_id(cluster_id)?, 0);
+        loop {
+            let free = get_block_cache(pos.0, Arc::clone(&self.bdev))
+                .lock()
+                .read(pos.1, |layout: &Fat32DentryLayout| {
+                    layout.is_empty() || layout.is_deleted()
+                });
+            if free {
+                run.push(pos);
+                if run.len() == count {
+                    return Some(run);
                 }
+            } else {
+                run.clear();
             }
+            pos = match self.next_dentry_id(pos.0, pos.1) {
+                Some(next) => next,
+                None => {
+                    // 新簇清零，全是空目录项，目录的结束标记随之后移
+                    let last_cluster = self.fat.sector_id_to_cluster_id(pos.0)?;
+                    let new_cluster = self.fat.increase_cluster(last_cluster)?;
+                    self.zero_cluster(new_cluster);
+                    (self.fat.cluster_id_to_sector_id(new_cluster)?, 0)
+                }
+            };
         }
-        (*sector_id, *offset) = self.next_dentry_id(*sector_id, *offset).unwrap();
-        dentry
     }
 
+    /// Add an entry named `name` to the directory starting at `cluster_id`
+    ///
+    /// 能用 8.3 名字表示的只写短目录项，否则生成一个目录内唯一的 `BASIS~N` 短名字，
+    /// 长目录项按序号从大到小写在它前面，最前面一项带 0x40 标志，每项都记录短名字的校验和。
+    /// 返回的目录项指向第一个长目录项（没有长名字时就是短目录项）。
     pub fn insert_dentry(
         &self, cluster_id: usize, name: String, attr: FileAttributes, file_size: u32,
         start_cluster: usize,
     ) -> Option<Fat32Dentry> {
-        let mut sector_id = self.fat.cluster_id_to_sector_id(cluster_id).unwrap();
-        let mut offset = 0;
-        loop {
-            let found = get_block_cache(sector_id, self.bdev.clone())
-                .lock()
-                .read(offset, |layout: &Fat32DentryLayout| layout.is_empty());
-            if found {
-                break;
+        let existing = self.short_names(cluster_id);
+        let (short, case, units) = match short_name(&name) {
+            Some((short, case)) if !existing.contains(&short) => (short, case, Vec::new()),
+            _ => {
+                let units: Vec<u16> = name.encode_utf16().collect();
+                if units.len() > MAX_NAME_LEN {
+                    return None;
+                }
+                let short = (1..1_000_000)
+                    .map(|n| numbered_short_name(&name, n))
+                    .find(|short| !existing.contains(short))?;
+                (short, 0, units)
             }
-            (sector_id, offset) = self.next_dentry_id(sector_id, offset).unwrap();
-        }
-        let mut order = 1;
-        let mut pos = 0;
-        while pos < name.len() {
-            let copy_len = min(13, name.len() - pos);
+        };
+        let long_cnt = (units.len() + 12) / 13;
+        let slots = self.alloc_dentries(cluster_id, long_cnt + 1)?;
+        let sum = checksum(&short);
+        for (i, &(sector_id, offset)) in slots[..long_cnt].iter().enumerate() {
+            let order = long_cnt - i;
+            let part = &units[(order - 1) * 13..min(order * 13, units.len())];
             get_block_cache(sector_id, Arc::clone(&self.bdev))
                 .lock()
                 .modify(offset, |layout: &mut Fat32LDentryLayout| {
-                    *layout = Fat32LDentryLayout::new(
-                        order,
-                        &name[pos..pos + copy_len],
-                        pos + copy_len == name.len(),
-                    );
+                    *layout = Fat32LDentryLayout::from_units(order as u8, part, i == 0, sum);
                 });
-            order += 1;
-            pos += copy_len;
-            (sector_id, offset) = self.next_dentry_id(sector_id, offset).unwrap();
         }
+        let (sector_id, offset) = slots[long_cnt];
         get_block_cache(sector_id, self.bdev.clone()).lock().modify(
             offset,
             |layout: &mut Fat32DentryLayout| {
-                *layout = Fat32DentryLayout::new(name.as_str(), attr, start_cluster, file_size);
+                *layout =
+                    Fat32DentryLayout::with_short_name(short, case, attr, start_cluster, file_size);
             },
         );
+        let (sector_id, offset) = slots[0];
         Some(Fat32Dentry::new(sector_id, offset, &self.bdev, &self.fat))
     }
 
     pub fn remove_dentry(&self, dentry: &Fat32Dentry) {
         let mut sector_id = dentry.sector_id;
         let mut offset = dentry.sector_offset;
-        if dentry.is_long() {
-            let mut is_end = false;
-            loop {
-                get_block_cache(sector_id, Arc::clone(&self.bdev))
-                    .lock()
-                    .modify(offset, |layout: &mut Fat32LDentryLayout| {
-                        layout.order = 0xE5;
-                        if layout.is_end() {
-                            is_end = true;
-                        }
-                    });
-                (sector_id, offset) = self.next_dentry_id(sector_id, offset).unwrap();
-                if is_end {
-                    break;
-                }
+        loop {
+            let is_long = get_block_cache(sector_id, Arc::clone(&self.bdev))
+                .lock()
+                .modify(offset, |layout: &mut Fat32DentryLayout| {
+                    let is_long = layout.is_long();
+                    layout.set_deleted();
+                    is_long
+                });
+            if !is_long {
+                break;
             }
+            (sector_id, offset) = self.next_dentry_id(sector_id, offset).unwrap();
         }
-        get_block_cache(sector_id, self.bdev.clone()).lock().modify(
-            offset,
-            |layout: &mut Fat32DentryLayout| {
-                layout.set_deleted();
-            },
-        );
     }
 }
@@ -1,17 +1,19 @@
 use alloc::{
     string::{String, ToString},
     sync::Arc,
+    vec,
     vec::Vec,
 };
 use core::cmp::min;
 
+use spin::RwLock;
+
 use super::{
     dentry::{Fat32Dentry, FileAttributes},
     fs::Fat32FS,
-    CLUSTER_SIZE,
 };
 use crate::{
-    block::block_dev::BlockDevice,
+    block::{block_cache::block_cache_sync_blocks, block_dev::BlockDevice},
     fs::{
         dentry::Dentry,
         file::File,
@@ -27,6 +29,9 @@ pub struct Fat32Inode {
     pub start_cluster: usize,
     pub bdev:          Arc<dyn BlockDevice>,
     pub fs:            Arc<Fat32FS>,
+    /// shared by every inode of this file: readers of the data or the directory take it shared,
+    /// writers and directory updates take it exclusive
+    lock:              Arc<RwLock<()>>,
 }
 
 impl Inode for Fat32Inode {
@@ -34,10 +39,9 @@ impl Inode for Fat32Inode {
         FileSystemType::VFAT
     }
     fn lookup(self: Arc<Self>, name: &str) -> Option<Arc<Dentry>> {
-        let fs = self.fs.as_ref();
-        let mut sector_id = fs.fat.cluster_id_to_sector_id(self.start_cluster).unwrap();
-        let mut offset = 0;
-        while let Some(dentry) = fs.get_dentry(&mut sector_id, &mut offset) {
+        self.fs.io.run(|| {
+            let _guard = self.lock.read();
+            let dentry = self.find_dentry(name)?;
             let type_ = if dentry.is_file() {
                 Fat32InodeType::File
             } else if dentry.is_dir() {
@@ -45,50 +49,56 @@ impl Inode for Fat32Inode {
             } else {
                 Fat32InodeType::VolumeId
             };
-            // found the dentry
-            if dentry.name() == name {
-                let fat32inode = Fat32Inode {
-                    type_,
-                    start_cluster: dentry.start_cluster_id(),
-                    fs: Arc::clone(&self.fs),
-                    bdev: Arc::clone(&self.bdev),
-                    dentry: Some(Arc::new(dentry)),
-                };
-                let dentry = Dentry::new(name, Arc::new(fat32inode));
-                return Some(Arc::new(dentry));
+            let mut start_cluster = dentry.start_cluster_id();
+            if type_ == Fat32InodeType::Dir && start_cluster == 0 {
+                // 指向根目录的 ".." 记录的起始簇是 0
+                start_cluster = self.fs.sb.root_cluster as usize;
             }
-        }
-        None
+            let fat32inode = Fat32Inode::new(type_, start_cluster, Some(dentry), &self.fs);
+            Some(Arc::new(Dentry::new(name, Arc::new(fat32inode))))
+        })
     }
 
     fn create(self: Arc<Self>, name: &str, type_: InodeType) -> Option<Arc<Dentry>> {
-        if self.clone().lookup(name).is_some() {
-            return None;
-        }
-        let fs = self.fs.as_ref();
-        let attr = match type_ {
-            InodeType::Regular => FileAttributes::ARCHIVE,
-            InodeType::Directory => FileAttributes::DIRECTORY,
-            _ => FileAttributes::ARCHIVE,
-        };
-        let start_cluster = fs.fat.alloc_new_cluster().unwrap();
-        let dentry = fs
-            .insert_dentry(self.start_cluster, name.to_string(), attr, 0, start_cluster)
-            .unwrap();
-        let type_ = if type_ == InodeType::Regular {
-            Fat32InodeType::File
-        } else {
-            Fat32InodeType::Dir
-        };
-        let fat32inode = Fat32Inode {
-            type_,
-            start_cluster,
-            fs: Arc::clone(&self.fs),
-            bdev: Arc::clone(&self.bdev),
-            dentry: Some(Arc::new(dentry)),
-        };
-        let dentry = Dentry::new(name, Arc::new(fat32inode));
-        Some(Arc::new(dentry))
+        self.fs.io.run(|| {
+            let _guard = self.lock.write();
+            if self.find_dentry(name).is_some() {
+                return None;
+            }
+            let fs = self.fs.as_ref();
+            let attr = match type_ {
+                InodeType::Regular => FileAttributes::ARCHIVE,
+                InodeType::Directory => FileAttributes::DIRECTORY,
+                _ => FileAttributes::ARCHIVE,
+            };
+            // 簇里可能是旧数据，清零后新目录是空的，文件末尾之后也都是 0
+            let start_cluster = fs.fat.alloc_new_cluster()?;
+            fs.zero_cluster(start_cluster);
+            if type_ == InodeType::Directory {
+                let parent_cluster = if self.is_root() {
+                    0
+                } else {
+                    self.start_cluster
+                };
+                for (name, cluster) in [(".", start_cluster), ("..", parent_cluster)] {
+                    fs.insert_dentry(start_cluster, name.to_string(), attr, 0, cluster)?;
+                }
+            }
+            let Some(dentry) =
+                fs.insert_dentry(self.start_cluster, name.to_string(), attr, 0, start_cluster)
+            else {
+                fs.fat.free_chain(start_cluster);
+                return None;
+            };
+            let type_ = if type_ == InodeType::Directory {
+                Fat32InodeType::Dir
+            } else {
+                Fat32InodeType::File
+            };
+            let fat32inode = Fat32Inode::new(type_, start_cluster, Some(dentry), &self.fs);
+            let dentry = Dentry::new(name, Arc::new(fat32inode));
+            Some(Arc::new(dentry))
+        })
     }
 
     fn link(self: Arc<Self>, _name: &str, _target: Arc<Dentry>) -> bool {
@@ -96,103 +106,223 @@ impl Inode for Fat32Inode {
         false
     }
 
+    /// Remove a file and free its clusters, directories are removed by rmdir
     fn unlink(self: Arc<Self>, name: &str) -> bool {
-        let fs = self.fs.as_ref();
-        let mut sector_id = fs.fat.cluster_id_to_sector_id(self.start_cluster).unwrap();
-        let mut offset = 0;
-        while let Some(dentry) = fs.get_dentry(&mut sector_id, &mut offset) {
-            if dentry.name() == name {
-                fs.remove_dentry(&dentry);
-                return true;
+        self.fs.io.run(|| {
+            let _guard = self.lock.write();
+            match self.find_dentry(name) {
+                Some(dentry) if !dentry.is_dir() => {
+                    let start_cluster = dentry.start_cluster_id();
+                    let lock = self.fs.inode_lock(start_cluster);
+                    let _file_guard = lock.write();
+                    self.fs.remove_dentry(&dentry);
+                    self.fs.fat.free_chain(start_cluster);
+                    true
+                }
+                _ => false,
             }
-        }
-        false
+        })
     }
 
     fn ls(&self) -> Vec<String> {
-        let fs = self.fs.as_ref();
-        let mut v = Vec::new();
-        let mut sector_id = fs.fat.cluster_id_to_sector_id(self.start_cluster).unwrap();
-        let mut offset = 0;
-        while let Some(dentry) = fs.get_dentry(&mut sector_id, &mut offset) {
-            v.push(dentry.name());
-        }
-        v
+        self.fs.io.run(|| {
+            let _guard = self.lock.read();
+            self.dentries().iter().map(Fat32Dentry::name).collect()
+        })
     }
 
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let fs = self.fs.as_ref();
-        let cluster_id = self.start_cluster;
-        let cluster_chain = fs.cluster_chain(cluster_id);
-        let mut read_size = 0;
-        let mut pos = 0;
-        let mut cluster_buf = [0u8; CLUSTER_SIZE];
-        for cluster_id in cluster_chain {
-            if pos < offset {
-                let pass_size = min(CLUSTER_SIZE, offset - pos);
-                pos += pass_size;
-                if pass_size == CLUSTER_SIZE {
-                    continue;
-                }
+        self.fs.io.run(|| {
+            let _guard = self.lock.read();
+            let size = self.size();
+            if offset >= size {
+                return 0;
             }
-            let dentry = self.dentry.clone().unwrap();
-            fs.read_cluster(cluster_id, &mut cluster_buf);
-            let copy_size = min(dentry.file_size() - pos, buf.len() - read_size);
-            buf[read_size..read_size + copy_size]
-                .copy_from_slice(&cluster_buf[pos % CLUSTER_SIZE..pos % CLUSTER_SIZE + copy_size]);
-            read_size += copy_size;
-            pos += copy_size;
-            if read_size >= buf.len() || pos >= dentry.file_size() {
-                break;
+            let end = min(size, offset + buf.len());
+            let fs = self.fs.as_ref();
+            let cluster_size = fs.cluster_size();
+            let mut cluster_buf = vec![0u8; cluster_size];
+            let mut pos = offset;
+            let cluster_chain = fs.cluster_chain(self.first_cluster());
+            for (i, cluster_id) in (0..).zip(cluster_chain).skip(offset / cluster_size) {
+                if pos >= end {
+                    break;
+                }
+                let cluster_start = i * cluster_size;
+                let copy_end = min(end, cluster_start + cluster_size);
+                fs.read_cluster(cluster_id, &mut cluster_buf);
+                buf[pos - offset..copy_end - offset]
+                    .copy_from_slice(&cluster_buf[pos - cluster_start..copy_end - cluster_start]);
+                pos = copy_end;
             }
-        }
-        read_size
+            pos - offset
+        })
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        self.increase_size(offset + buf.len());
-        let fs = self.fs.as_ref();
-        let cluster_id = self.start_cluster;
-        let cluster_chain = fs.cluster_chain(cluster_id);
-        let mut write_size = 0;
-        let mut pos = 0;
-        let mut cluster_buf = [0u8; CLUSTER_SIZE];
-        for cluster_id in cluster_chain {
-            if pos < offset {
-                let pass_size = min(CLUSTER_SIZE, offset - pos);
-                pos += pass_size;
-                if pass_size == CLUSTER_SIZE {
-                    continue;
-                }
+        self.fs.io.run(|| {
+            let _guard = self.lock.write();
+            // 根目录没有目录项，记不下文件大小
+            if self.dentry.is_none() || buf.is_empty() || !self.increase_size(offset + buf.len()) {
+                return 0;
             }
-            fs.read_cluster(cluster_id, &mut cluster_buf);
-            let copy_size = min(buf.len() - write_size, CLUSTER_SIZE - pos % CLUSTER_SIZE);
-            cluster_buf[pos % CLUSTER_SIZE..pos % CLUSTER_SIZE + copy_size]
-                .copy_from_slice(&buf[write_size..write_size + copy_size]);
-            fs.write_cluster(cluster_id, &cluster_buf);
-            write_size += copy_size;
-            pos += copy_size;
-            if write_size >= buf.len() {
-                break;
+            let fs = self.fs.as_ref();
+            let cluster_size = fs.cluster_size();
+            let mut cluster_buf = vec![0u8; cluster_size];
+            let end = offset + buf.len();
+            let mut pos = offset;
+            let cluster_chain = fs.cluster_chain(self.first_cluster());
+            for (i, cluster_id) in (0..).zip(cluster_chain).skip(offset / cluster_size) {
+                if pos >= end {
+                    break;
+                }
+                let cluster_start = i * cluster_size;
+                let copy_end = min(end, cluster_start + cluster_size);
+                fs.read_cluster(cluster_id, &mut cluster_buf);
+                cluster_buf[pos - cluster_start..copy_end - cluster_start]
+                    .copy_from_slice(&buf[pos - offset..copy_end - offset]);
+                fs.write_cluster(cluster_id, &cluster_buf);
+                pos = copy_end;
             }
-        }
-        write_size
+            pos - offset
+        })
     }
 
+    /// Truncate to zero length, keeping only the first cluster
     fn clear(&self) {
-        self.set_file_size(0);
+        self.fs.io.run(|| {
+            let _guard = self.lock.write();
+            if self.dentry.is_none() {
+                return;
+            }
+            self.set_file_size(0);
+            let first_cluster = self.first_cluster();
+            if first_cluster >= 2 {
+                self.fs.fat.truncate_chain(first_cluster);
+                self.fs.zero_cluster(first_cluster);
+            }
+        })
     }
 
-    fn rename(self: Arc<Self>, _old_name: &str, _new_name: &str) -> bool {
-        todo!("FAT32 rename");
+    /// Rename within this directory, replacing a regular file named `new_name`
+    fn rename(self: Arc<Self>, old_name: &str, new_name: &str) -> bool {
+        self.fs.io.run(|| {
+            let _guard = self.lock.write();
+            let Some(old) = self.find_dentry(old_name) else {
+                return false;
+            };
+            if old_name == new_name {
+                return true;
+            }
+            let replaced = self.find_dentry(new_name);
+            if replaced.as_ref().map_or(false, |dentry| dentry.is_dir()) {
+                return false;
+            }
+            // 目录之后按起始簇从小到大锁住涉及的文件，见 Fat32FS::inode_lock
+            let mut clusters: Vec<usize> = core::iter::once(&old)
+                .chain(replaced.as_ref())
+                .map(|dentry| dentry.start_cluster_id())
+                .collect();
+            clusters.sort_unstable();
+            clusters.dedup();
+            let locks: Vec<_> = clusters
+                .iter()
+                .map(|cluster| self.fs.inode_lock(*cluster))
+                .collect();
+            let _guards: Vec<_> = locks.iter().map(|lock| lock.write()).collect();
+            let fs = self.fs.as_ref();
+            // 只是大小写不同时，名字相同的目录项就是 old 自己
+            let replaced = replaced.filter(|dentry| {
+                (dentry.sector_id, dentry.sector_offset) != (old.sector_id, old.sector_offset)
+            });
+            if fs
+                .insert_dentry(
+                    self.start_cluster,
+                    new_name.to_string(),
+                    old.attr(),
+                    old.file_size() as u32,
+                    old.start_cluster_id(),
+                )
+                .is_none()
+            {
+                return false;
+            }
+            fs.remove_dentry(&old);
+            if let Some(replaced) = replaced {
+                let start_cluster = replaced.start_cluster_id();
+                fs.remove_dentry(&replaced);
+                fs.fat.free_chain(start_cluster);
+            }
+            true
+        })
+    }
+
+    fn mkdir(self: Arc<Self>, name: &str) -> bool {
+        self.create(name, InodeType::Directory).is_some()
+    }
+
+    /// Remove an empty directory and free its clusters
+    fn rmdir(self: Arc<Self>, name: &str) -> bool {
+        self.fs.io.run(|| {
+            let _guard = self.lock.write();
+            let Some(dentry) = self.find_dentry(name) else {
+                return false;
+            };
+            if !dentry.is_dir() || name == "." || name == ".." {
+                return false;
+            }
+            let start_cluster = dentry.start_cluster_id();
+            let lock = self.fs.inode_lock(start_cluster);
+            let _dir_guard = lock.write();
+            let dir = Fat32Inode::new(Fat32InodeType::Dir, start_cluster, None, &self.fs);
+            let empty = dir
+                .dentries()
+                .iter()
+                .all(|dentry| matches!(dentry.name().as_str(), "." | ".."));
+            if !empty {
+                return false;
+            }
+            self.fs.remove_dentry(&dentry);
+            self.fs.fat.free_chain(start_cluster);
+            true
+        })
+    }
+
+    fn cache_key(&self) -> Option<(usize, usize)> {
+        // 空文件还没有起始簇，不同的空文件无法区分
+        (self.start_cluster != 0).then(|| (Arc::as_ptr(&self.fs) as usize, self.start_cluster))
     }
 
-    fn mkdir(self: Arc<Self>, _name: &str) -> bool {
-        todo!("FAT32 mkdir");
+    fn fs_id(&self) -> Option<usize> {
+        Some(Arc::as_ptr(&self.fs) as usize)
     }
 
-    fn rmdir(self: Arc<Self>, _name: &str) -> bool {
-        todo!("FAT32 rmdir");
+    /// 写回目录项、数据簇和簇链所在的 FAT 扇区，其他文件的脏块留给后台写回
+    fn sync(&self) {
+        self.fs.io.run(|| {
+            let _guard = self.lock.read();
+            let fs = self.fs.as_ref();
+            let mut sectors = Vec::new();
+            if let Some(dentry) = self.dentry.as_ref() {
+                sectors.push(dentry.short_entry().0);
+            }
+            let first_cluster = self.first_cluster();
+            if first_cluster >= 2 {
+                for cluster in fs.cluster_chain(first_cluster) {
+                    let start = fs.fat.cluster_id_to_sector_id(cluster).unwrap();
+                    sectors.extend(start..start + fs.sb.sectors_per_cluster as usize);
+                    sectors.extend(fs.fat.entry_sectors(cluster));
+                }
+            }
+            block_cache_sync_blocks(&self.bdev, sectors);
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.fs.io.run(|| {
+            // 根目录没有目录项
+            self.dentry.as_ref().map_or(0, |dentry| dentry.file_size())
+        })
     }
 }
 
@@ -213,18 +343,7 @@ impl File for Fat32Inode {
     }
 
     fn read_all(&self) -> Vec<u8> {
-        let mut buffer = [0u8; 512];
-        let mut v: Vec<u8> = Vec::new();
-        let mut total_read_size = 0;
-        loop {
-            let len = self.read_at(total_read_size, &mut buffer);
-            if len == 0 {
-                break;
-            }
-            total_read_size += len;
-            v.extend_from_slice(&buffer[..len]);
-        }
-        v
+        Inode::read_all(self)
     }
 
     fn write(&self, buf: &[u8]) -> usize {
@@ -234,29 +353,83 @@ impl File for Fat32Inode {
     }
 
     fn fstat(&self) -> Option<Stat> {
-        let st_mode = match self.type_ {
-            Fat32InodeType::File => StatMode::FILE.bits(),
-            Fat32InodeType::Dir => StatMode::DIR.bits(),
-            _ => StatMode::NULL.bits(),
-        };
-        Some(Stat::new(
-            0,
-            0,
-            st_mode,
-            1,
-            0,
-            self.dentry.as_ref().unwrap().file_size() as i64,
-            0,
-            0,
-            0,
-        ))
+        self.fs.io.run(|| {
+            let st_mode = match self.type_ {
+                Fat32InodeType::File => StatMode::FILE.bits(),
+                Fat32InodeType::Dir => StatMode::DIR.bits(),
+                _ => StatMode::NULL.bits(),
+            };
+            Some(Stat::new(
+                0,
+                self.first_cluster() as u64,
+                st_mode,
+                1,
+                0,
+                self.size() as i64,
+                0,
+                0,
+                0,
+            ))
+        })
     }
+
     fn hang_up(&self) -> bool {
-        todo!()
+        false
     }
 }
 
 impl Fat32Inode {
+    pub fn new(
+        type_: Fat32InodeType, start_cluster: usize, dentry: Option<Fat32Dentry>, fs: &Arc<Fat32FS>,
+    ) -> Self {
+        Self {
+            type_,
+            dentry: dentry.map(Arc::new),
+            start_cluster,
+            bdev: Arc::clone(&fs.bdev),
+            fs: Arc::clone(fs),
+            lock: fs.inode_lock(start_cluster),
+        }
+    }
+
+    fn is_root(&self) -> bool {
+        self.start_cluster == self.fs.sb.root_cluster as usize
+    }
+
+    /// First cluster of the data, read from the dentry since a file created empty by
+    /// another system gets its first cluster on the first write
+    fn first_cluster(&self) -> usize {
+        self.dentry
+            .as_ref()
+            .map_or(self.start_cluster, |dentry| dentry.start_cluster_id())
+    }
+
+    /// Entries of this directory, skipping deleted ones and the volume label, the caller
+    /// holds `self.lock`
+    fn dentries(&self) -> Vec<Fat32Dentry> {
+        let fs = self.fs.as_ref();
+        let mut dentries = Vec::new();
+        let Some(mut sector_id) = fs.fat.cluster_id_to_sector_id(self.start_cluster) else {
+            return dentries;
+        };
+        let mut offset = 0;
+        while let Some(dentry) = fs.get_dentry(&mut sector_id, &mut offset) {
+            if !dentry.is_deleted() && !dentry.is_volume_id() {
+                dentries.push(dentry);
+            }
+        }
+        dentries
+    }
+
+    /// Entry `name` in this directory, the caller holds `self.lock`
+    ///
+    /// 和其他系统一样，FAT 的名字不区分大小写。
+    fn find_dentry(&self, name: &str) -> Option<Fat32Dentry> {
+        self.dentries()
+            .into_iter()
+            .find(|dentry| dentry.name().eq_ignore_ascii_case(name))
+    }
+
     pub fn is_dir(&self) -> bool {
         self.type_ == Fat32InodeType::Dir
     }
@@ -273,20 +446,35 @@ impl Fat32Inode {
         self.dentry.as_ref().unwrap().set_file_size(size);
     }
 
-    pub fn increase_size(&self, size: usize) {
-        if size < self.file_size() {
-            return;
-        }
-        self.set_file_size(size);
+    /// Grow the file to `size` bytes, the caller holds `self.lock` for writing
+    ///
+    /// 新分配的簇都清零。磁盘满时返回 false，文件大小不变。
+    pub fn increase_size(&self, size: usize) -> bool {
         let fs = self.fs.as_ref();
-        let cluster_chain = fs.cluster_chain(self.start_cluster);
-        if cluster_chain.len() * CLUSTER_SIZE >= size {
-            return;
+        let mut first_cluster = self.first_cluster();
+        if first_cluster < 2 {
+            let Some(cluster) = fs.fat.alloc_new_cluster() else {
+                return false;
+            };
+            fs.zero_cluster(cluster);
+            self.dentry.as_ref().unwrap().set_start_cluster_id(cluster);
+            first_cluster = cluster;
         }
+        let cluster_chain = fs.cluster_chain(first_cluster);
+        let mut clusters = cluster_chain.len();
         let mut last_cluster_id = *cluster_chain.last().unwrap();
-        while cluster_chain.len() * CLUSTER_SIZE < size {
-            last_cluster_id = fs.fat.increase_cluster(last_cluster_id).unwrap();
+        while clusters * fs.cluster_size() < size {
+            let Some(cluster) = fs.fat.increase_cluster(last_cluster_id) else {
+                return false;
+            };
+            fs.zero_cluster(cluster);
+            last_cluster_id = cluster;
+            clusters += 1;
         }
+        if size > self.file_size() {
+            self.set_file_size(size);
+        }
+        true
     }
 }
 
@@ -0,0 +1,204 @@
+//! Create FAT32 images
+//!
+//! [`Fat32FS::format`] 在块设备上建立空文件系统，[`Fat32FS::create_from_files`]
+//! 再把 (路径, 内容) 逐个写入，内核和测试可以直接生成根文件系统镜像，不依赖外部的打包工具。
+//! 几何参数固定：512 字节扇区，每簇 [`CLUSTER_SIZE`] 字节，两份 FAT，根目录在 2 号簇。
+
+use alloc::sync::Arc;
+
+use super::{
+    fat::END_OF_CHAIN,
+    fs::Fat32FS,
+    super_block::{Fat32SBLayout, FAT32_FS_TYPE},
+    CLUSTER_SIZE,
+};
+use crate::{
+    block::{
+        block_cache::{block_cache_sync_all, get_block_cache},
+        block_dev::BlockDevice,
+        BLOCK_SZ,
+    },
+    fs::{fs::FileSystem, inode::InodeType},
+};
+
+const SECTORS_PER_CLUSTER: usize = CLUSTER_SIZE / BLOCK_SZ;
+const RESERVED_SECTORS: usize = 32;
+const FAT_CNT: usize = 2;
+const ROOT_CLUSTER: usize = 2;
+const FS_INFO_SECTOR: usize = 1;
+const BACKUP_BOOT_SECTOR: usize = 6;
+/// FAT entry 0 holds the media type
+const MEDIA_FIXED: u8 = 0xF8;
+
+fn zero_sector(bdev: &Arc<dyn BlockDevice>, sector_id: usize) {
+    get_block_cache(sector_id, Arc::clone(bdev))
+        .lock()
+        .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(0));
+}
+
+fn write_u32(bdev: &Arc<dyn BlockDevice>, sector_id: usize, offset: usize, value: u32) {
+    get_block_cache(sector_id, Arc::clone(bdev))
+        .lock()
+        .modify(offset, |data: &mut [u8; 4]| {
+            *data = value.to_le_bytes();
+        });
+}
+
+fn write_boot_sector(
+    bdev: &Arc<dyn BlockDevice>, sector_id: usize, total_sectors: usize, fat_size: usize,
+) {
+    zero_sector(bdev, sector_id);
+    let block_cache = get_block_cache(sector_id, Arc::clone(bdev));
+    let mut cache = block_cache.lock();
+    cache.modify(0, |layout: &mut Fat32SBLayout| {
+        *layout = Fat32SBLayout {
+            jump_code:            [0xEB, 0x58, 0x90],
+            oem_name:             *b"CHAOS   ",
+            bytes_per_sector:     (BLOCK_SZ as u16).to_le_bytes(),
+            sectors_per_cluster:  SECTORS_PER_CLUSTER as u8,
+            reserved_sectors_cnt: RESERVED_SECTORS as u16,
+            fat_cnt:              FAT_CNT as u8,
+            root_entry_cnt:       [0; 2],
+            total_sectors_16:     [0; 2],
+            media_type:           MEDIA_FIXED,
+            fat_size_16:          0,
+            sectors_per_track:    32,
+            head_cnt:             64,
+            hidden_sectors:       0,
+            total_sectors_32:     total_sectors as u32,
+            fat_size_32:          fat_size as u32,
+            ext_flags:            0,
+            fs_version:           0,
+            root_cluster:         ROOT_CLUSTER as u32,
+            fs_info:              FS_INFO_SECTOR as u16,
+            backup_boot_sector:   BACKUP_BOOT_SECTOR as u16,
+            reserved_0:           [0; 12],
+            drive_number:         0x80,
+            reserved_1:           0,
+            boot_signature:       0x29,
+            volume_id:            *b"CHAO",
+            volume_label:         *b"NO NAME    ",
+            fs_type:              FAT32_FS_TYPE,
+        };
+    });
+    cache.modify(510, |signature: &mut [u8; 2]| *signature = [0x55, 0xAA]);
+}
+
+impl Fat32FS {
+    /// Create an empty file system of `total_sectors` sectors on `bdev`
+    ///
+    /// 设备太小、放不下 FAT 和至少一个数据簇时返回 `None`。
+    pub fn format(bdev: Arc<dyn BlockDevice>, total_sectors: usize) -> Option<Arc<Self>> {
+        // FAT 大小的计算方法来自 Microsoft FAT 规范
+        let per_fat_sector = (256 * SECTORS_PER_CLUSTER + FAT_CNT) / 2;
+        let fat_size =
+            (total_sectors.checked_sub(RESERVED_SECTORS)? + per_fat_sector - 1) / per_fat_sector;
+        let data_start = RESERVED_SECTORS + FAT_CNT * fat_size;
+        if total_sectors < data_start + SECTORS_PER_CLUSTER || total_sectors > u32::MAX as usize {
+            return None;
+        }
+        for sector_id in RESERVED_SECTORS..data_start + SECTORS_PER_CLUSTER {
+            zero_sector(&bdev, sector_id);
+        }
+        write_boot_sector(&bdev, 0, total_sectors, fat_size);
+        write_boot_sector(&bdev, BACKUP_BOOT_SECTOR, total_sectors, fat_size);
+        // FSInfo：空闲簇数和下一个空闲簇都记为未知
+        zero_sector(&bdev, FS_INFO_SECTOR);
+        write_u32(&bdev, FS_INFO_SECTOR, 0, 0x4161_5252);
+        write_u32(&bdev, FS_INFO_SECTOR, 484, 0x6141_7272);
+        write_u32(&bdev, FS_INFO_SECTOR, 488, u32::MAX);
+        write_u32(&bdev, FS_INFO_SECTOR, 492, u32::MAX);
+        write_u32(&bdev, FS_INFO_SECTOR, 508, 0xAA55_0000);
+        for fat in 0..FAT_CNT {
+            let fat_start = RESERVED_SECTORS + fat * fat_size;
+            write_u32(&bdev, fat_start, 0, 0x0FFF_FF00 | MEDIA_FIXED as u32);
+            write_u32(&bdev, fat_start, 4, END_OF_CHAIN);
+            write_u32(&bdev, fat_start, ROOT_CLUSTER * 4, END_OF_CHAIN);
+        }
+        block_cache_sync_all();
+        Some(Self::load(bdev))
+    }
+
+    /// Format `bdev` and fill it with `files`, given as (absolute path, content)
+    ///
+    /// 缺少的父目录自动创建；同一路径出现多次时以最后一次的内容为准。
+    pub fn create_from_files<'a>(
+        bdev: Arc<dyn BlockDevice>, total_sectors: usize,
+        files: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+    ) -> Option<Arc<Self>> {
+        let fs = Self::format(bdev, total_sectors)?;
+        let root = Arc::clone(&fs).root_inode();
+        for (path, data) in files {
+            let mut names = path.split('/').filter(|name| !name.is_empty()).peekable();
+            let mut dir = Arc::clone(&root);
+            while let Some(name) = names.next() {
+                let type_ = if names.peek().is_some() {
+                    InodeType::Directory
+                } else {
+                    InodeType::Regular
+                };
+                let dentry = match Arc::clone(&dir).lookup(name) {
+                    Some(dentry) => dentry,
+                    None => dir.create(name, type_)?,
+                };
+                dir = dentry.inode();
+            }
+            dir.clear();
+            if dir.write_at(0, data) != data.len() {
+                return None;
+            }
+        }
+        block_cache_sync_all();
+        Some(fs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec::Vec};
+
+    use super::Fat32FS;
+    use crate::{
+        block::block_dev::{BlockDevice, RamDisk},
+        fs::fs::FileSystem,
+    };
+
+    #[test_case]
+    fn format_rejects_tiny_disk() {
+        let bdev: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(16));
+        assert!(Fat32FS::format(bdev, 16).is_none());
+    }
+
+    #[test_case]
+    fn create_from_files_round_trip() {
+        let big: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        let files: [(&str, &[u8]); 3] = [
+            ("/hello.txt", b"hello"),
+            ("/bin/big.bin", &big),
+            ("/bin/long file name.data", b"long"),
+        ];
+        let bdev: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(4096));
+        Fat32FS::create_from_files(Arc::clone(&bdev), 4096, files).unwrap();
+
+        let root = Fat32FS::load(bdev).root_inode();
+        let hello = root.clone().lookup("hello.txt").unwrap().inode();
+        let mut buf = [0u8; 16];
+        assert_eq!(hello.read_at(0, &mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+        let bin = root.lookup("bin").unwrap().inode();
+        let long = bin.clone().lookup("long file name.data").unwrap().inode();
+        assert_eq!(long.read_at(0, &mut buf), 4);
+        assert_eq!(&buf[..4], b"long");
+        let file = bin.lookup("big.bin").unwrap().inode();
+        let mut content = Vec::new();
+        let mut chunk = [0u8; 512];
+        loop {
+            let len = file.read_at(content.len(), &mut chunk);
+            if len == 0 {
+                break;
+            }
+            content.extend_from_slice(&chunk[..len]);
+        }
+        assert_eq!(content, big);
+    }
+}
@@ -2,6 +2,7 @@ mod dentry;
 mod fat;
 pub mod fs;
 pub mod inode;
+pub mod mkfs;
 mod super_block;
 
 const CLUSTER_SIZE: usize = 4096;
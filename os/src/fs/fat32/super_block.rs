@@ -1,5 +1,7 @@
 use core::fmt::Debug;
 
+use crate::block::BLOCK_SZ;
+
 /// the super block of a fat32 file system
 pub struct Fat32SB {
     pub bytes_per_sector:     u16,
@@ -30,6 +32,18 @@ impl Fat32SB {
     pub fn root_sector(&self) -> usize {
         self.reserved_sectors_cnt as usize + self.fat_cnt as usize * self.fat_size_32 as usize
     }
+
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector as usize * self.sectors_per_cluster as usize
+    }
+
+    /// Number of FAT entries that describe a cluster, including the two reserved ones
+    pub fn cluster_cnt(&self) -> usize {
+        let data_clusters = (self.total_sectors_32 as usize).saturating_sub(self.root_sector())
+            / self.sectors_per_cluster as usize;
+        let fat_entries = self.fat_size_32 as usize * self.bytes_per_sector as usize / 4;
+        (data_clusters + 2).min(fat_entries)
+    }
 }
 
 impl From<Fat32SBLayout> for Fat32SB {
@@ -80,8 +94,14 @@ pub struct Fat32SBLayout {
     pub fs_type:              [u8; 8],
 }
 
+/// `fs_type` of a FAT32 boot sector
+pub const FAT32_FS_TYPE: [u8; 8] = *b"FAT32   ";
+
 impl Fat32SBLayout {
+    /// 块缓存按 512 字节的扇区读写，扇区大小不同的卷无法挂载
     pub fn is_valid(&self) -> bool {
-        self.fs_type == *b"FAT32   "
+        self.fs_type == FAT32_FS_TYPE
+            && u16::from_le_bytes(self.bytes_per_sector) as usize == BLOCK_SZ
+            && self.sectors_per_cluster != 0
     }
 }
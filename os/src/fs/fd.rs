@@ -0,0 +1,141 @@
+//! Entries of the file descriptor table
+//!
+//! 描述符表的每一项除了打开的文件，还记录只属于这个描述符的 fd flags（FD_CLOEXEC），
+//! 以及 open 时给出、可以用 F_GETFL/F_SETFL 读写的文件状态标志。
+//! 以 CLONE_FILES 创建的任务共享同一张表。
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::ops::{Deref, DerefMut};
+
+use bitflags::bitflags;
+
+use super::{
+    defs::OpenFlags,
+    file::File,
+    stdio::{Stdin, Stdout},
+};
+
+bitflags! {
+    /// Flags of a descriptor, read and written by F_GETFD/F_SETFD
+    pub struct FdFlags: u32 {
+        const FD_CLOEXEC = 1;
+    }
+}
+
+/// An open file descriptor
+#[derive(Clone)]
+pub struct FdEntry {
+    pub file:         Arc<dyn File>,
+    pub fd_flags:     FdFlags,
+    /// access mode and file status flags, without the flags that only matter at open
+    pub status_flags: OpenFlags,
+}
+
+impl FdEntry {
+    /// A descriptor for `file` opened with `flags`, close-on-exec if O_CLOEXEC is given
+    pub fn new(file: Arc<dyn File>, flags: OpenFlags) -> Self {
+        file.set_nonblock(flags.contains(OpenFlags::O_NONBLOCK));
+        let fd_flags = if flags.contains(OpenFlags::O_CLOEXEC) {
+            FdFlags::FD_CLOEXEC
+        } else {
+            FdFlags::empty()
+        };
+        Self {
+            file,
+            fd_flags,
+            status_flags: flags - Self::creation_flags(),
+        }
+    }
+
+    /// A new descriptor for the same open file, as dup does
+    pub fn dup(&self, cloexec: bool) -> Self {
+        let mut entry = self.clone();
+        entry.fd_flags.set(FdFlags::FD_CLOEXEC, cloexec);
+        entry
+    }
+
+    pub fn cloexec(&self) -> bool {
+        self.fd_flags.contains(FdFlags::FD_CLOEXEC)
+    }
+
+    /// Flags that only affect how the file is opened and are not reported by F_GETFL
+    fn creation_flags() -> OpenFlags {
+        OpenFlags::O_CREAT
+            | OpenFlags::O_EXCL
+            | OpenFlags::O_NOCTTY
+            | OpenFlags::O_TRUNC
+            | OpenFlags::O_CLOEXEC
+            | OpenFlags::O_DIRECTORY
+            | OpenFlags::O_NOFOLLOW
+    }
+
+    /// Flags F_SETFL can change, the others in its argument are ignored
+    pub fn settable_flags() -> OpenFlags {
+        OpenFlags::O_APPEND
+            | OpenFlags::O_ASYNC
+            | OpenFlags::O_DIRECT
+            | OpenFlags::O_NOATIME
+            | OpenFlags::O_NONBLOCK
+    }
+}
+
+/// File descriptor table, indexed by fd
+#[derive(Clone, Default)]
+pub struct FdTable(Vec<Option<FdEntry>>);
+
+impl FdTable {
+    /// A table with stdin, stdout and stderr on the console
+    pub fn with_stdio() -> Self {
+        Self(vec![
+            // 0 -> stdin
+            Some(FdEntry::new(Arc::new(Stdin::new()), OpenFlags::O_RDONLY)),
+            // 1 -> stdout
+            Some(FdEntry::new(Arc::new(Stdout), OpenFlags::O_WRONLY)),
+            // 2 -> stderr
+            Some(FdEntry::new(Arc::new(Stdout), OpenFlags::O_WRONLY)),
+        ])
+    }
+
+    /// allocate a new file descriptor below `limit` (RLIMIT_NOFILE), `None` if all are in use
+    pub fn alloc_fd(&mut self, limit: usize) -> Option<usize> {
+        self.alloc_fd_from(0, limit)
+    }
+
+    /// allocate the lowest free file descriptor not less than `min`, for F_DUPFD
+    pub fn alloc_fd_from(&mut self, min: usize, limit: usize) -> Option<usize> {
+        let end = self.0.len().min(limit);
+        if let Some(fd) = (min..end).find(|fd| self.0[*fd].is_none()) {
+            Some(fd)
+        } else {
+            let fd = self.0.len().max(min);
+            if fd >= limit {
+                return None;
+            }
+            self.0.resize(fd + 1, None);
+            Some(fd)
+        }
+    }
+
+    /// Close the descriptors with FD_CLOEXEC set, on exec
+    pub fn close_on_exec(&mut self) {
+        for fd in self.0.iter_mut() {
+            if fd.as_ref().map_or(false, |entry| entry.cloexec()) {
+                *fd = None;
+            }
+        }
+    }
+}
+
+impl Deref for FdTable {
+    type Target = Vec<Option<FdEntry>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for FdTable {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
@@ -1,12 +1,22 @@
 use alloc::{sync::Arc, vec::Vec};
-use core::any::Any;
+use core::{
+    any::Any,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use super::{
+    cgroup::CgroupInode,
+    defs::OpenFlags,
+    devfs::DevInode,
+    epoll::EpollInstance,
     ext4::inode::Ext4Inode,
     fat32::inode::Fat32Inode,
     inode::{Inode, Stat},
+    io_uring::IoRing,
+    procfs::ProcInode,
+    tmpfs::TmpInode,
 };
-use crate::mm::UserBuffer;
+use crate::{mm::UserBuffer, sync::SpinNoIrqLock, syscall::errno::ENOTTY};
 
 /// trait File for all file types
 pub trait File: Any + Send + Sync {
@@ -31,12 +41,185 @@ pub trait File: Any + Send + Sync {
         }
     }
     fn hang_up(&self) -> bool;
+    /// [`Self::read`] reporting the errno of a read that moved nothing (EIO, EAGAIN,
+    /// EINTR...), for the syscalls
+    fn try_read(&self, buf: &mut [u8]) -> Result<usize, isize> {
+        Ok(self.read(buf))
+    }
+    /// [`Self::write`] reporting the errno of a write that moved nothing
+    fn try_write(&self, buf: &[u8]) -> Result<usize, isize> {
+        Ok(self.write(buf))
+    }
     fn r_ready(&self) -> bool {
         true
     }
     fn w_ready(&self) -> bool {
         true
     }
+    /// O_NONBLOCK was set or cleared on the file, by open or F_SETFL
+    fn set_nonblock(&self, _nonblock: bool) {}
+    /// device specific request `request` with argument `arg`, ENOTTY if the file takes none
+    fn ioctl(&self, _request: usize, _arg: usize) -> isize {
+        ENOTTY
+    }
+}
+
+/// An open file description: an inode with the offset and access mode of one open
+///
+/// dup 和 fork 得到的描述符共享同一个 `OpenFile`，也就共享偏移量。
+pub struct OpenFile {
+    inode:    Arc<dyn Inode>,
+    /// the same object as `inode`, for what only the filesystem knows (stat, ioctl)
+    file:     Arc<dyn File>,
+    readable: bool,
+    writable: bool,
+    /// O_APPEND, can be changed by F_SETFL
+    append:   AtomicBool,
+    offset:   SpinNoIrqLock<usize>,
+}
+
+impl OpenFile {
+    /// Open `inode` with the access mode and O_APPEND of `flags`, `None` if the inode
+    /// can not be read or written as a file
+    pub fn new(inode: Arc<dyn Inode>, flags: OpenFlags) -> Option<Arc<Self>> {
+        let file = cast_inode_to_file(inode.clone())?;
+        let (readable, writable) = match flags.bits() & 0o3 {
+            0 => (true, false),
+            1 => (false, true),
+            _ => (true, true),
+        };
+        Some(Arc::new(Self {
+            inode,
+            file,
+            readable,
+            writable,
+            append: AtomicBool::new(flags.contains(OpenFlags::O_APPEND)),
+            offset: SpinNoIrqLock::new(0),
+        }))
+    }
+
+    pub fn inode(&self) -> Arc<dyn Inode> {
+        self.inode.clone()
+    }
+
+    pub fn offset(&self) -> usize {
+        *self.offset.lock()
+    }
+
+    pub fn set_offset(&self, offset: usize) {
+        *self.offset.lock() = offset;
+    }
+
+    pub fn set_append(&self, append: bool) {
+        self.append.store(append, Ordering::Relaxed);
+    }
+}
+
+impl File for OpenFile {
+    fn readable(&self) -> bool {
+        self.readable && self.file.readable()
+    }
+
+    fn writable(&self) -> bool {
+        self.writable && self.file.writable()
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        self.try_read(buf).unwrap_or(0)
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> Result<usize, isize> {
+        // 读盘时可能切换到其他任务，不能一直借用偏移量
+        let offset = self.offset();
+        let read_size = self.inode.try_read_at(offset, buf)?;
+        self.set_offset(offset + read_size);
+        Ok(read_size)
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        self.inode.read_all()
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        self.try_write(buf).unwrap_or(0)
+    }
+
+    fn try_write(&self, buf: &[u8]) -> Result<usize, isize> {
+        let offset = if self.append.load(Ordering::Relaxed) {
+            self.inode.size()
+        } else {
+            self.offset()
+        };
+        let write_size = self.inode.try_write_at(offset, buf)?;
+        self.set_offset(offset + write_size);
+        Ok(write_size)
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        self.file.fstat()
+    }
+
+    fn is_dir(&self) -> bool {
+        self.file.is_dir()
+    }
+
+    fn hang_up(&self) -> bool {
+        self.file.hang_up()
+    }
+
+    fn r_ready(&self) -> bool {
+        self.file.r_ready()
+    }
+
+    fn w_ready(&self) -> bool {
+        self.file.w_ready()
+    }
+
+    fn ioctl(&self, request: usize, arg: usize) -> isize {
+        self.file.ioctl(request, arg)
+    }
+}
+
+/// The [`OpenFile`] behind `file`, `None` for pipes, sockets and the console
+pub fn cast_file_to_open_file(file: Arc<dyn File>) -> Option<Arc<OpenFile>> {
+    unsafe {
+        let file_ptr = Arc::into_raw(file);
+        let file_ref = &*(file_ptr as *const dyn Any);
+        if file_ref.is::<OpenFile>() {
+            Some(Arc::from_raw(file_ptr as *const OpenFile))
+        } else {
+            let _ = Arc::from_raw(file_ptr);
+            None
+        }
+    }
+}
+
+/// The [`EpollInstance`] behind `file`, `None` if it is not an epoll file descriptor
+pub fn cast_file_to_epoll(file: Arc<dyn File>) -> Option<Arc<EpollInstance>> {
+    unsafe {
+        let file_ptr = Arc::into_raw(file);
+        let file_ref = &*(file_ptr as *const dyn Any);
+        if file_ref.is::<EpollInstance>() {
+            Some(Arc::from_raw(file_ptr as *const EpollInstance))
+        } else {
+            let _ = Arc::from_raw(file_ptr);
+            None
+        }
+    }
+}
+
+/// The [`IoRing`] behind `file`, `None` if it is not an io_uring file descriptor
+pub fn cast_file_to_io_ring(file: Arc<dyn File>) -> Option<Arc<IoRing>> {
+    unsafe {
+        let file_ptr = Arc::into_raw(file);
+        let file_ref = &*(file_ptr as *const dyn Any);
+        if file_ref.is::<IoRing>() {
+            Some(Arc::from_raw(file_ptr as *const IoRing))
+        } else {
+            let _ = Arc::from_raw(file_ptr);
+            None
+        }
+    }
 }
 
 // TODO: 优化这个函数
@@ -44,10 +227,33 @@ pub fn cast_file_to_inode(file: Arc<dyn File>) -> Option<Arc<dyn Inode>> {
     unsafe {
         let file_ptr = Arc::into_raw(file);
         let file_ref = &*(file_ptr as *const dyn Any);
-        if file_ref.is::<Fat32Inode>() {
+        if file_ref.is::<OpenFile>() {
+            let open_file = Arc::from_raw(file_ptr as *const OpenFile);
+            Some(open_file.inode())
+        } else if file_ref.is::<Fat32Inode>() {
             let inode_ptr = file_ptr as *const Fat32Inode;
             let inode = Arc::from_raw(inode_ptr);
             Some(inode)
+        } else if file_ref.is::<Ext4Inode>() {
+            let inode_ptr = file_ptr as *const Ext4Inode;
+            let inode = Arc::from_raw(inode_ptr);
+            Some(inode)
+        } else if file_ref.is::<CgroupInode>() {
+            let inode_ptr = file_ptr as *const CgroupInode;
+            let inode = Arc::from_raw(inode_ptr);
+            Some(inode)
+        } else if file_ref.is::<ProcInode>() {
+            let inode_ptr = file_ptr as *const ProcInode;
+            let inode = Arc::from_raw(inode_ptr);
+            Some(inode)
+        } else if file_ref.is::<DevInode>() {
+            let inode_ptr = file_ptr as *const DevInode;
+            let inode = Arc::from_raw(inode_ptr);
+            Some(inode)
+        } else if file_ref.is::<TmpInode>() {
+            let inode_ptr = file_ptr as *const TmpInode;
+            let inode = Arc::from_raw(inode_ptr);
+            Some(inode)
         } else {
             // 如果转换失败，我们需要重新创建原始的 Arc 以避免内存泄漏
             let _ = Arc::from_raw(file_ptr);
@@ -68,6 +274,22 @@ pub fn cast_inode_to_file(inode: Arc<dyn Inode>) -> Option<Arc<dyn File>> {
             let file_ptr = inode_ptr as *const Ext4Inode;
             let file = Arc::from_raw(file_ptr);
             Some(file)
+        } else if inode_ref.is::<CgroupInode>() {
+            let file_ptr = inode_ptr as *const CgroupInode;
+            let file = Arc::from_raw(file_ptr);
+            Some(file)
+        } else if inode_ref.is::<ProcInode>() {
+            let file_ptr = inode_ptr as *const ProcInode;
+            let file = Arc::from_raw(file_ptr);
+            Some(file)
+        } else if inode_ref.is::<DevInode>() {
+            let file_ptr = inode_ptr as *const DevInode;
+            let file = Arc::from_raw(file_ptr);
+            Some(file)
+        } else if inode_ref.is::<TmpInode>() {
+            let file_ptr = inode_ptr as *const TmpInode;
+            let file = Arc::from_raw(file_ptr);
+            Some(file)
         } else {
             // 如果转换失败，我们需要重新创建原始的 Arc 以避免内存泄漏
             let _ = Arc::from_raw(inode_ptr);
@@ -1,26 +1,36 @@
-use alloc::{collections::BTreeMap, sync::Arc};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 
 use super::{inode::Inode, path::Path};
 
 pub trait FileSystem: Send + Sync {
     fn fs_type(&self) -> FileSystemType;
     fn root_inode(self: Arc<Self>) -> Arc<dyn Inode>;
+    /// Drop everything cached from the backing device, after its media changed
+    fn invalidate(&self) {}
 }
 
 /* File System Type */
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FileSystemType {
     VFAT,
     EXT4,
+    CGROUP2,
+    PROC,
+    DEVTMPFS,
+    TMPFS,
 }
 
 impl FileSystemType {
     pub fn from_str(name: &str) -> Option<Self> {
         match name {
-            "vfat" => Some(Self::VFAT),
+            "vfat" | "fat32" => Some(Self::VFAT),
             "ext4" => Some(Self::EXT4),
-            _ => panic!("[FileSystemType] unknown file system type"),
+            "cgroup2" => Some(Self::CGROUP2),
+            "proc" => Some(Self::PROC),
+            "devtmpfs" => Some(Self::DEVTMPFS),
+            "tmpfs" => Some(Self::TMPFS),
+            _ => None,
         }
     }
 
@@ -28,12 +38,17 @@ impl FileSystemType {
         match self {
             Self::VFAT => "vfat",
             Self::EXT4 => "ext4",
+            Self::CGROUP2 => "cgroup2",
+            Self::PROC => "proc",
+            Self::DEVTMPFS => "devtmpfs",
+            Self::TMPFS => "tmpfs",
         }
     }
 }
 
 /* File System Manager */
 
+#[derive(Clone)]
 pub struct FileSystemManager {
     pub mounted_fs: BTreeMap<Path, Arc<dyn FileSystem>>,
 }
@@ -51,17 +66,60 @@ impl FileSystemManager {
         }
     }
 
+    /// Mount `fs` at the absolute `path`, replacing what was mounted there
     pub fn mount(&mut self, fs: Arc<dyn FileSystem>, path: &str) {
-        let path = Path::new(path);
+        let path = Path::new(&Path::normalize(path));
         self.mounted_fs.insert(path, fs);
     }
 
-    pub fn unmount(&mut self, path: &str) {
-        let path = Path::new(path);
-        self.mounted_fs.remove(&path);
+    pub fn unmount(&mut self, path: &str) -> bool {
+        let path = Path::new(&Path::normalize(path));
+        self.mounted_fs.remove(&path).is_some()
+    }
+
+    /// The filesystem mounted exactly at `path`
+    pub fn fs_at(&self, path: &str) -> Option<Arc<dyn FileSystem>> {
+        self.mounted_fs
+            .get(&Path::new(&Path::normalize(path)))
+            .cloned()
+    }
+
+    /// The filesystem holding the absolute `path` and the path relative to its root
+    ///
+    /// 最长的挂载点前缀胜出，所以挂在子目录上的文件系统遮住下层文件系统中的同名目录。
+    pub fn resolve(&self, path: &str) -> Option<(Arc<dyn FileSystem>, String)> {
+        let path = Path::normalize(path);
+        let (mount_point, fs) = self
+            .mounted_fs
+            .iter()
+            .filter(|(mount_point, _)| mount_point.covers(&path))
+            .max_by_key(|(mount_point, _)| mount_point.as_str().len())?;
+        let rest = path[mount_point.as_str().len()..].trim_start_matches('/');
+        Some((fs.clone(), rest.into()))
+    }
+
+    /// Mount points strictly below `path`
+    pub fn mounts_below(&self, path: &str) -> Vec<String> {
+        let path = Path::new(&Path::normalize(path));
+        self.mounted_fs
+            .keys()
+            .filter(|mount_point| **mount_point != path && path.covers(mount_point.as_str()))
+            .map(|mount_point| mount_point.as_str().into())
+            .collect()
     }
 
     pub fn rootfs(&self) -> Arc<dyn FileSystem> {
         self.mounted_fs.get(&Path::new("/")).unwrap().clone()
     }
+
+    pub fn mount_points(&self) -> Vec<(String, FileSystemType)> {
+        self.mounted_fs
+            .iter()
+            .map(|(path, fs)| (path.as_str().into(), fs.fs_type()))
+            .collect()
+    }
+
+    pub fn filesystems(&self) -> Vec<Arc<dyn FileSystem>> {
+        self.mounted_fs.values().cloned().collect()
+    }
 }
@@ -18,6 +18,15 @@ pub trait Inode: Any + Send + Sync {
     fn link(self: Arc<Self>, name: &str, target: Arc<Dentry>) -> bool;
     /// rename an inode in the directory with the old name and new name
     fn rename(self: Arc<Self>, old_name: &str, new_name: &str) -> bool;
+    /// move `old_name` of the directory to `new_name` in `new_dir` on the same filesystem,
+    /// replacing what is there; only within one directory unless the filesystem overrides it
+    fn rename_to(self: Arc<Self>, old_name: &str, new_dir: Arc<dyn Inode>, new_name: &str) -> bool {
+        if self.cache_key().is_some() && self.cache_key() == new_dir.cache_key() {
+            self.rename(old_name, new_name)
+        } else {
+            false
+        }
+    }
     /// make a directory in the directory with the name
     fn mkdir(self: Arc<Self>, name: &str) -> bool;
     /// remove a directory in the directory with the name
@@ -30,6 +39,44 @@ pub trait Inode: Any + Send + Sync {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
     /// write at the offset of the inode
     fn write_at(&self, offset: usize, buf: &[u8]) -> usize;
+    /// [`Self::read_at`] reporting the errno of a failed read, such as EIO from the disk
+    fn try_read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, isize> {
+        Ok(self.read_at(offset, buf))
+    }
+    /// [`Self::write_at`] reporting the errno of a failed write
+    fn try_write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, isize> {
+        Ok(self.write_at(offset, buf))
+    }
+    /// size of the file in bytes, where `lseek(SEEK_END)` and O_APPEND writes go
+    fn size(&self) -> usize {
+        self.read_all().len()
+    }
+    /// owner and permission bits, `None` if the filesystem does not keep them
+    fn perm(&self) -> Option<InodePerm> {
+        None
+    }
+    /// (filesystem, file) the same for every inode of this file, `None` if its pages
+    /// cannot be shared by mappings through other inodes
+    fn cache_key(&self) -> Option<(usize, usize)> {
+        None
+    }
+    /// address of the filesystem instance holding the inode, `None` for pseudo
+    /// filesystems whose inodes do not depend on the mount; checked by umount
+    fn fs_id(&self) -> Option<usize> {
+        self.cache_key().map(|(fs, _)| fs)
+    }
+    /// create a symbolic link `name` to `target` in the directory, false if the
+    /// filesystem does not support symbolic links
+    fn symlink(self: Arc<Self>, _name: &str, _target: &str) -> bool {
+        false
+    }
+    /// target of the symbolic link, `None` if the inode is not one
+    fn read_link(&self) -> Option<String> {
+        None
+    }
+    /// write the data and metadata of the file the filesystem caches back to the disk,
+    /// for fsync; nothing to do for filesystems in memory
+    fn sync(&self) {}
     /// read all data from the inode in memory
     fn read_all(&self) -> Vec<u8> {
         trace!("kernel: OSInode::read_all");
@@ -48,6 +95,17 @@ pub trait Inode: Any + Send + Sync {
     }
 }
 
+/* Inode Permissions */
+
+/// Ownership of an inode, checked by `Credentials::permits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InodePerm {
+    /// permission bits including set-id and sticky, `mode & 0o7777`
+    pub mode: u16,
+    pub uid:  u32,
+    pub gid:  u32,
+}
+
 /* Inode Types */
 
 #[allow(dead_code)]
@@ -55,6 +113,7 @@ pub trait Inode: Any + Send + Sync {
 pub enum InodeType {
     Regular,
     Directory,
+    SymLink,
     BlockDevice,
     CharDevice,
     Pipe,
@@ -175,18 +234,128 @@ impl Stat {
             __unused: 0,
         }
     }
+
+    /// Set the permission bits and the owner, keeping the file type
+    pub fn set_perm(&mut self, perm: InodePerm) {
+        self.st_mode = (self.st_mode & StatMode::TYPE.bits()) | perm.mode as u32;
+        self.st_uid = perm.uid;
+        self.st_gid = perm.gid;
+    }
+
+    /// Set the number of 512-byte blocks really allocated, for filesystems that know it
+    pub fn set_blocks(&mut self, blocks: u64) {
+        self.st_blocks = blocks;
+    }
+
+    /// Set the nanosecond parts of the access, modification and status change times
+    pub fn set_nsec(&mut self, atime_nsec: usize, mtime_nsec: usize, ctime_nsec: usize) {
+        self.st_atime.tv_nsec = atime_nsec;
+        self.st_mtime.tv_nsec = mtime_nsec;
+        self.st_ctime.tv_nsec = ctime_nsec;
+    }
+
+    /// file type bits of `st_mode`
+    fn file_type(&self) -> StatMode {
+        StatMode::from_bits_truncate(self.st_mode & StatMode::TYPE.bits())
+    }
+
     /// check whether the inode is a directory
     pub fn is_dir(&self) -> bool {
-        StatMode::from_bits(self.st_mode)
-            .unwrap()
-            .contains(StatMode::DIR)
+        self.file_type() == StatMode::DIR
     }
 
     /// check whether the inode is a file
     pub fn is_file(&self) -> bool {
-        StatMode::from_bits(self.st_mode)
-            .unwrap()
-            .contains(StatMode::FILE)
+        self.file_type() == StatMode::FILE
+    }
+
+    /// check whether the inode is a character device
+    pub fn is_char_device(&self) -> bool {
+        self.file_type() == StatMode::CHAR_DEVICE
+    }
+}
+
+/// Timestamp in [`Statx`]
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct StatxTimestamp {
+    pub tv_sec:  i64,
+    pub tv_nsec: u32,
+    __reserved:  i32,
+}
+
+impl From<&TimeSpec> for StatxTimestamp {
+    fn from(time: &TimeSpec) -> Self {
+        Self {
+            tv_sec:     time.tv_sec as i64,
+            tv_nsec:    time.tv_nsec as u32,
+            __reserved: 0,
+        }
+    }
+}
+
+/// `struct statx` filled by the statx syscall
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Statx {
+    /// which of the fields are filled in, `STATX_*`
+    pub stx_mask:        u32,
+    stx_blksize:         u32,
+    stx_attributes:      u64,
+    stx_nlink:           u32,
+    stx_uid:             u32,
+    stx_gid:             u32,
+    stx_mode:            u16,
+    __spare0:            u16,
+    stx_ino:             u64,
+    stx_size:            u64,
+    stx_blocks:          u64,
+    stx_attributes_mask: u64,
+    stx_atime:           StatxTimestamp,
+    /// creation time, not filled in
+    stx_btime:           StatxTimestamp,
+    stx_ctime:           StatxTimestamp,
+    stx_mtime:           StatxTimestamp,
+    stx_rdev_major:      u32,
+    stx_rdev_minor:      u32,
+    stx_dev_major:       u32,
+    stx_dev_minor:       u32,
+    __spare2:            [u64; 14],
+}
+
+/// Fields of [`Statx`] that a [`Stat`] provides
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+
+/// (major, minor) of a device number in the encoding of glibc's `makedev`
+fn dev_split(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+impl From<&Stat> for Statx {
+    fn from(stat: &Stat) -> Self {
+        let (stx_rdev_major, stx_rdev_minor) = dev_split(stat.st_rdev);
+        let (stx_dev_major, stx_dev_minor) = dev_split(stat.st_dev);
+        Self {
+            stx_mask: STATX_BASIC_STATS,
+            stx_blksize: stat.st_blksize,
+            stx_nlink: stat.st_nlink,
+            stx_uid: stat.st_uid,
+            stx_gid: stat.st_gid,
+            stx_mode: stat.st_mode as u16,
+            stx_ino: stat.st_ino,
+            stx_size: stat.st_size as u64,
+            stx_blocks: stat.st_blocks,
+            stx_atime: (&stat.st_atime).into(),
+            stx_ctime: (&stat.st_ctime).into(),
+            stx_mtime: (&stat.st_mtime).into(),
+            stx_rdev_major,
+            stx_rdev_minor,
+            stx_dev_major,
+            stx_dev_minor,
+            ..Default::default()
+        }
     }
 }
 
@@ -196,9 +365,19 @@ bitflags! {
     pub struct StatMode: u32 {
         /// null
         const NULL  = 0;
+        /// mask of the file type bits
+        const TYPE  = 0o170000;
+        /// named pipe
+        const FIFO  = 0o010000;
+        /// character device
+        const CHAR_DEVICE = 0o020000;
         /// directory
         const DIR   = 0o040000;
         /// ordinary regular file
         const FILE  = 0o100000;
+        /// symbolic link
+        const SYMLINK = 0o120000;
+        /// socket
+        const SOCKET = 0o140000;
     }
 }
@@ -0,0 +1,637 @@
+//! io_uring style submission/completion rings
+//!
+//! io_uring_setup 创建一个环文件（[`IoRing`]）和服务它的内核线程。提交队列（SQ）和完成队列（CQ）
+//! 放在环文件的页里，用户以 `MAP_SHARED` 映射环文件后与内核看到同一批页帧：用户填写 SQE、
+//! 推进 SQ tail，io_uring_enter 把新的 SQE 交给内核线程；内核线程完成 read/write/fsync 后
+//! 写入 CQE、推进 CQ tail。提交者不必等 I/O 完成，单线程的服务端可以在 SD 卡读写期间继续
+//! 处理别的请求。
+//!
+//! 只支持 IORING_FEAT_SINGLE_MMAP 的布局：偏移 0 处依次是 SQ 环头、CQ 环头、SQ 下标数组和
+//! CQE 数组，偏移 [`IORING_OFF_SQES`] 处是 SQE 数组。用户拥有 SQ tail 和 CQ head，内核
+//! 拥有 SQ head 和 CQ tail。
+
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use super::{
+    dentry::Dentry,
+    file::{cast_file_to_inode, cast_file_to_open_file, File},
+    fs::FileSystemType,
+    inode::{Inode, InodeType, Stat},
+    sync_file,
+};
+use crate::{
+    config::PAGE_SIZE,
+    mm::{
+        get_page,
+        translated_byte_buffer,
+        user_range_ok,
+        CachedPage,
+        MemorySet,
+        StepByOne,
+        VirtAddr,
+    },
+    sync::{SpinNoIrqLock, WaitQueue},
+    syscall::errno::{EBADF, EFAULT, EINTR, EINVAL, ESPIPE},
+    task::{
+        cgroup::Cgroup,
+        current_task,
+        exit_current_and_run_next,
+        signal_pending,
+        TaskControlBlock,
+    },
+};
+
+/// mmap offset of the rings
+pub const IORING_OFF_SQ_RING: usize = 0;
+/// mmap offset of the SQE array
+pub const IORING_OFF_SQES: usize = 0x1000_0000;
+
+/// The SQ and CQ rings are mapped together at [`IORING_OFF_SQ_RING`]
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+/// io_uring_enter flag: wait for `min_complete` completions
+pub const IORING_ENTER_GETEVENTS: u32 = 1;
+/// Most SQ entries a ring may have
+const IORING_MAX_ENTRIES: u32 = 256;
+/// Most bytes one read or write SQE transfers, longer requests complete short like read(2)
+const IORING_MAX_RW: usize = 16 * PAGE_SIZE;
+
+pub const IORING_OP_NOP: u8 = 0;
+pub const IORING_OP_FSYNC: u8 = 3;
+pub const IORING_OP_READ: u8 = 22;
+pub const IORING_OP_WRITE: u8 = 23;
+
+// 环头各字段在偏移 0 处区域中的位置
+const SQ_HEAD: usize = 0;
+const SQ_TAIL: usize = 4;
+const SQ_RING_MASK: usize = 8;
+const SQ_RING_ENTRIES: usize = 12;
+const SQ_FLAGS: usize = 16;
+const SQ_DROPPED: usize = 20;
+const CQ_HEAD: usize = 64;
+const CQ_TAIL: usize = 68;
+const CQ_RING_MASK: usize = 72;
+const CQ_RING_ENTRIES: usize = 76;
+const CQ_OVERFLOW: usize = 80;
+const CQ_FLAGS: usize = 84;
+const SQ_ARRAY: usize = 128;
+
+/// `struct io_sqring_offsets`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SqRingOffsets {
+    pub head:         u32,
+    pub tail:         u32,
+    pub ring_mask:    u32,
+    pub ring_entries: u32,
+    pub flags:        u32,
+    pub dropped:      u32,
+    pub array:        u32,
+    pub resv1:        u32,
+    pub user_addr:    u64,
+}
+
+/// `struct io_cqring_offsets`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CqRingOffsets {
+    pub head:         u32,
+    pub tail:         u32,
+    pub ring_mask:    u32,
+    pub ring_entries: u32,
+    pub overflow:     u32,
+    pub cqes:         u32,
+    pub flags:        u32,
+    pub resv1:        u32,
+    pub user_addr:    u64,
+}
+
+/// `struct io_uring_params`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IoUringParams {
+    pub sq_entries:     u32,
+    pub cq_entries:     u32,
+    pub flags:          u32,
+    pub sq_thread_cpu:  u32,
+    pub sq_thread_idle: u32,
+    pub features:       u32,
+    pub wq_fd:          u32,
+    pub resv:           [u32; 3],
+    pub sq_off:         SqRingOffsets,
+    pub cq_off:         CqRingOffsets,
+}
+
+/// `struct io_uring_sqe`, the fields of the supported operations
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct IoUringSqe {
+    pub opcode:    u8,
+    pub flags:     u8,
+    pub ioprio:    u16,
+    pub fd:        i32,
+    /// file offset, `u64::MAX` for the current file position
+    pub off:       u64,
+    pub addr:      u64,
+    pub len:       u32,
+    pub rw_flags:  u32,
+    pub user_data: u64,
+    pub pad:       [u64; 3],
+}
+
+/// `struct io_uring_cqe`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct IoUringCqe {
+    pub user_data: u64,
+    pub res:       i32,
+    pub flags:     u32,
+}
+
+/// A submitted SQE waiting for the worker
+struct Job {
+    sqe:        IoUringSqe,
+    /// the file at `sqe.fd` when it was submitted, `None` if the fd was not open
+    file:       Option<Arc<dyn File>>,
+    /// address space of the submitter, where `sqe.addr` points
+    memory_set: Arc<SpinNoIrqLock<MemorySet>>,
+    /// cgroup of the submitter, charged for the pages its buffer faults in
+    cgroup:     Arc<Cgroup>,
+}
+
+impl Job {
+    fn execute(&self) -> isize {
+        if self.sqe.opcode == IORING_OP_NOP {
+            return 0;
+        }
+        let Some(file) = self.file.clone() else {
+            return EBADF;
+        };
+        match self.sqe.opcode {
+            IORING_OP_READ => self.read(file),
+            IORING_OP_WRITE => self.write(file),
+            IORING_OP_FSYNC => match cast_file_to_inode(file) {
+                Some(inode) => {
+                    sync_file(&inode);
+                    0
+                }
+                None => EINVAL,
+            },
+            _ => EINVAL,
+        }
+    }
+
+    /// File offset of the operation, `None` for the current file position
+    fn offset(&self) -> Option<usize> {
+        (self.sqe.off != u64::MAX).then_some(self.sqe.off as usize)
+    }
+
+    /// The buffer of the SQE in the submitter's address space, `None` if it is not mapped
+    /// for the access
+    ///
+    /// 提交者 exec 或退出之后地址空间不再有人使用，写进去也没有影响。返回的切片只能在让出
+    /// CPU 之前使用。
+    fn user_buffer(&self, len: usize, write: bool) -> Option<Vec<&'static mut [u8]>> {
+        let mut memory_set = self.memory_set.lock();
+        let addr = self.sqe.addr as usize;
+        // 缓冲区不经过工作线程的缺页处理，保留页和写时复制页要先在提交者的地址空间里处理
+        let end = VirtAddr::from(addr.saturating_add(len)).ceil();
+        let mut vpn = VirtAddr::from(addr).floor();
+        while vpn < end {
+            memory_set.page_fault(vpn, write, &self.cgroup);
+            vpn.step();
+        }
+        let token = memory_set.token();
+        user_range_ok(token, addr, len, write)
+            .then(|| translated_byte_buffer(token, addr as *const u8, len))
+    }
+
+    fn read(&self, file: Arc<dyn File>) -> isize {
+        if !file.readable() {
+            return EBADF;
+        }
+        let len = (self.sqe.len as usize).min(IORING_MAX_RW);
+        // 缓冲区确实可写才按它的长度分配内核缓冲区
+        if self.user_buffer(len, true).is_none() {
+            return EFAULT;
+        }
+        let mut buf = vec![0u8; len];
+        let read = match self.offset() {
+            None => file.try_read(&mut buf),
+            Some(offset) => match cast_file_to_open_file(file) {
+                Some(open_file) => open_file.inode().try_read_at(offset, &mut buf),
+                None => return ESPIPE,
+            },
+        };
+        let read = match read {
+            Ok(read) => read,
+            Err(errno) => return errno,
+        };
+        // 读盘期间提交者可能解除了缓冲区的映射，拷贝前再检查
+        let Some(chunks) = self.user_buffer(read, true) else {
+            return EFAULT;
+        };
+        let mut copied = 0;
+        for chunk in chunks {
+            chunk.copy_from_slice(&buf[copied..copied + chunk.len()]);
+            copied += chunk.len();
+        }
+        read as isize
+    }
+
+    fn write(&self, file: Arc<dyn File>) -> isize {
+        if !file.writable() {
+            return EBADF;
+        }
+        let len = (self.sqe.len as usize).min(IORING_MAX_RW);
+        let Some(chunks) = self.user_buffer(len, false) else {
+            return EFAULT;
+        };
+        let mut buf = Vec::with_capacity(len);
+        for chunk in chunks {
+            buf.extend_from_slice(chunk);
+        }
+        let written = match self.offset() {
+            None => file.try_write(&buf),
+            Some(offset) => match cast_file_to_open_file(file) {
+                Some(open_file) => open_file.inode().try_write_at(offset, &buf),
+                None => return ESPIPE,
+            },
+        };
+        match written {
+            Ok(written) => written as isize,
+            Err(errno) => errno,
+        }
+    }
+}
+
+/// State shared by the ring file and its worker
+struct RingShared {
+    /// pages of the rings, in the page cache under the ring inode so that mmap finds them
+    ring:       Vec<Arc<CachedPage>>,
+    /// pages of the SQE array
+    sqes:       Vec<Arc<CachedPage>>,
+    sq_entries: u32,
+    cq_entries: u32,
+    /// offset of the CQE array in the rings
+    cqes:       usize,
+    /// SQEs taken off the SQ and not yet executed
+    jobs:       SpinNoIrqLock<VecDeque<Job>>,
+    /// where the worker sleeps while `jobs` is empty
+    work:       WaitQueue,
+    /// held while posting a CQE and while checking for completions before sleeping
+    cq_lock:    SpinNoIrqLock<()>,
+    /// where io_uring_enter sleeps for completions
+    completed:  WaitQueue,
+    /// the ring file was closed, the worker exits
+    closed:     AtomicBool,
+}
+
+impl RingShared {
+    /// Kernel address of the byte at `offset` in `pages`
+    ///
+    /// 各字段、SQE 和 CQE 都不跨页，按页找到页帧即可。
+    fn byte_at(pages: &[Arc<CachedPage>], offset: usize) -> *mut u8 {
+        let page = pages[offset / PAGE_SIZE].frame.ppn.get_bytes_array();
+        &mut page[offset % PAGE_SIZE] as *mut u8
+    }
+
+    fn field(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(Self::byte_at(&self.ring, offset) as *const AtomicU32) }
+    }
+
+    fn sqe(&self, index: u32) -> IoUringSqe {
+        let ptr = Self::byte_at(
+            &self.sqes,
+            index as usize * core::mem::size_of::<IoUringSqe>(),
+        );
+        unsafe { (ptr as *const IoUringSqe).read_volatile() }
+    }
+
+    /// CQEs posted and not yet consumed by the user
+    fn cq_ready(&self) -> u32 {
+        let tail = self.field(CQ_TAIL).load(Ordering::Relaxed);
+        tail.wrapping_sub(self.field(CQ_HEAD).load(Ordering::Acquire))
+    }
+
+    /// Post the result of an SQE, counting it as overflowed if the CQ is full
+    fn complete(&self, user_data: u64, res: isize) {
+        let guard = self.cq_lock.lock();
+        if self.cq_ready() >= self.cq_entries {
+            self.field(CQ_OVERFLOW).fetch_add(1, Ordering::Relaxed);
+        } else {
+            let tail = self.field(CQ_TAIL).load(Ordering::Relaxed);
+            let index = (tail & (self.cq_entries - 1)) as usize;
+            let ptr = Self::byte_at(
+                &self.ring,
+                self.cqes + index * core::mem::size_of::<IoUringCqe>(),
+            );
+            let cqe = IoUringCqe {
+                user_data,
+                res: res as i32,
+                flags: 0,
+            };
+            unsafe { (ptr as *mut IoUringCqe).write_volatile(cqe) };
+            // CQE 写完才能让用户看到新的 tail
+            self.field(CQ_TAIL)
+                .store(tail.wrapping_add(1), Ordering::Release);
+        }
+        drop(guard);
+        self.completed.wake_all();
+    }
+}
+
+/// Entry of the kernel thread serving a ring, `arg` is a leaked `Arc<RingShared>`
+extern "C" fn io_ring_worker(arg: usize) -> ! {
+    let shared = unsafe { Arc::from_raw(arg as *const RingShared) };
+    loop {
+        let mut jobs = shared.jobs.lock();
+        // 环关闭时还没执行的请求直接丢弃，提交者已经不在了
+        if shared.closed.load(Ordering::Acquire) {
+            break;
+        }
+        let Some(job) = jobs.pop_front() else {
+            shared.work.wait(jobs);
+            continue;
+        };
+        drop(jobs);
+        let res = job.execute();
+        shared.complete(job.sqe.user_data, res);
+    }
+    drop(shared);
+    exit_current_and_run_next(0);
+    unreachable!("io ring worker resumed after exit");
+}
+
+/// Ids telling the inodes of different rings apart in the page cache
+static NEXT_RING_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The inode mmap of a ring file maps, only its pages in the page cache matter
+///
+/// 页在创建环时由内核放入页缓存并一直持有，用户映射时拿到的是同一批页帧。读出全是零，
+/// 写回被丢弃。
+struct RingInode {
+    id: usize,
+}
+
+impl Inode for RingInode {
+    fn fstype(&self) -> FileSystemType {
+        FileSystemType::PROC
+    }
+
+    fn lookup(self: Arc<Self>, _name: &str) -> Option<Arc<Dentry>> {
+        None
+    }
+
+    fn create(self: Arc<Self>, _name: &str, _type_: InodeType) -> Option<Arc<Dentry>> {
+        None
+    }
+
+    fn unlink(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn link(self: Arc<Self>, _name: &str, _target: Arc<Dentry>) -> bool {
+        false
+    }
+
+    fn rename(self: Arc<Self>, _old_name: &str, _new_name: &str) -> bool {
+        false
+    }
+
+    fn mkdir(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn rmdir(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn ls(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn clear(&self) {}
+
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> usize {
+        0
+    }
+
+    /// 没有文件系统对象的地址会是 0，不会和真正的文件冲突
+    fn cache_key(&self) -> Option<(usize, usize)> {
+        Some((0, self.id))
+    }
+}
+
+/// The file behind an io_uring file descriptor
+pub struct IoRing {
+    shared: Arc<RingShared>,
+    inode:  Arc<dyn Inode>,
+}
+
+impl IoRing {
+    /// A ring with `entries` SQ entries, rounded up to a power of two, and twice as many
+    /// CQ entries, its pages charged to `cgroup`
+    pub fn new(entries: u32, cgroup: &Arc<Cgroup>) -> Result<Self, isize> {
+        if entries == 0 || entries > IORING_MAX_ENTRIES {
+            return Err(EINVAL);
+        }
+        let sq_entries = entries.next_power_of_two();
+        let cq_entries = sq_entries * 2;
+        let cqes = (SQ_ARRAY + sq_entries as usize * 4 + 15) & !15;
+        let ring_len = cqes + cq_entries as usize * core::mem::size_of::<IoUringCqe>();
+        let sqes_len = sq_entries as usize * core::mem::size_of::<IoUringSqe>();
+        let inode: Arc<dyn Inode> = Arc::new(RingInode {
+            id: NEXT_RING_ID.fetch_add(1, Ordering::Relaxed),
+        });
+        let pages = |offset: usize, len: usize| -> Result<Vec<Arc<CachedPage>>, isize> {
+            (0..len.div_ceil(PAGE_SIZE))
+                .map(|i| get_page(&inode, offset + i * PAGE_SIZE, cgroup))
+                .collect()
+        };
+        let shared = RingShared {
+            ring: pages(IORING_OFF_SQ_RING, ring_len)?,
+            sqes: pages(IORING_OFF_SQES, sqes_len)?,
+            sq_entries,
+            cq_entries,
+            cqes,
+            jobs: SpinNoIrqLock::new(VecDeque::new()),
+            work: WaitQueue::new(),
+            cq_lock: SpinNoIrqLock::new(()),
+            completed: WaitQueue::new(),
+            closed: AtomicBool::new(false),
+        };
+        shared
+            .field(SQ_RING_MASK)
+            .store(sq_entries - 1, Ordering::Relaxed);
+        shared
+            .field(SQ_RING_ENTRIES)
+            .store(sq_entries, Ordering::Relaxed);
+        shared
+            .field(CQ_RING_MASK)
+            .store(cq_entries - 1, Ordering::Relaxed);
+        shared
+            .field(CQ_RING_ENTRIES)
+            .store(cq_entries, Ordering::Relaxed);
+        Ok(Self {
+            shared: Arc::new(shared),
+            inode,
+        })
+    }
+
+    /// What io_uring_setup reports: the sizes of the rings and where their fields are
+    pub fn params(&self) -> IoUringParams {
+        let shared = &self.shared;
+        IoUringParams {
+            sq_entries: shared.sq_entries,
+            cq_entries: shared.cq_entries,
+            features: IORING_FEAT_SINGLE_MMAP,
+            sq_off: SqRingOffsets {
+                head: SQ_HEAD as u32,
+                tail: SQ_TAIL as u32,
+                ring_mask: SQ_RING_MASK as u32,
+                ring_entries: SQ_RING_ENTRIES as u32,
+                flags: SQ_FLAGS as u32,
+                dropped: SQ_DROPPED as u32,
+                array: SQ_ARRAY as u32,
+                ..Default::default()
+            },
+            cq_off: CqRingOffsets {
+                head: CQ_HEAD as u32,
+                tail: CQ_TAIL as u32,
+                ring_mask: CQ_RING_MASK as u32,
+                ring_entries: CQ_RING_ENTRIES as u32,
+                overflow: CQ_OVERFLOW as u32,
+                cqes: shared.cqes as u32,
+                flags: CQ_FLAGS as u32,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// The inode a `MAP_SHARED` mapping of the ring file maps
+    pub fn inode(&self) -> Arc<dyn Inode> {
+        self.inode.clone()
+    }
+
+    /// Start the kernel thread executing the SQEs, in the thread group of `task`
+    pub fn spawn_worker(&self, task: &Arc<TaskControlBlock>) {
+        let arg = Arc::into_raw(self.shared.clone()) as usize;
+        task.spawn_kernel_thread(io_ring_worker, arg);
+    }
+
+    /// Hand at most `to_submit` new SQEs to the worker, returning how many were taken
+    ///
+    /// 下标越界的 SQE 计入 dropped，也算作已取走。fd 在提交时解析，之后关闭 fd 不影响已提交的请求。
+    pub fn submit(&self, to_submit: u32) -> u32 {
+        let shared = &self.shared;
+        let head = shared.field(SQ_HEAD).load(Ordering::Relaxed);
+        // 读到 tail 之后才能读用户写好的 SQE
+        let tail = shared.field(SQ_TAIL).load(Ordering::Acquire);
+        let count = tail
+            .wrapping_sub(head)
+            .min(to_submit)
+            .min(shared.sq_entries);
+        let task = current_task().unwrap();
+        let (memory_set, cgroup) = {
+            let inner = task.inner_exclusive_access();
+            (inner.memory_set.clone(), inner.cgroup.clone())
+        };
+        let fd_table = task.fd_table();
+        let mut submitted = Vec::new();
+        for i in 0..count {
+            let slot = head.wrapping_add(i) & (shared.sq_entries - 1);
+            let index = shared
+                .field(SQ_ARRAY + slot as usize * 4)
+                .load(Ordering::Relaxed);
+            if index >= shared.sq_entries {
+                shared.field(SQ_DROPPED).fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let sqe = shared.sqe(index);
+            let file = usize::try_from(sqe.fd).ok().and_then(|fd| {
+                let entry = fd_table.lock().get(fd).cloned().flatten()?;
+                Some(entry.file)
+            });
+            submitted.push(Job {
+                sqe,
+                file,
+                memory_set: memory_set.clone(),
+                cgroup: cgroup.clone(),
+            });
+        }
+        shared
+            .field(SQ_HEAD)
+            .store(head.wrapping_add(count), Ordering::Release);
+        if !submitted.is_empty() {
+            shared.jobs.lock().extend(submitted);
+            shared.work.wake_all();
+        }
+        count
+    }
+
+    /// Sleep until at least `min_complete` CQEs are waiting, EINTR if a signal arrives first
+    pub fn wait_completions(&self, min_complete: u32) -> Result<(), isize> {
+        let shared = &self.shared;
+        loop {
+            let guard = shared.cq_lock.lock();
+            if shared.cq_ready() >= min_complete.min(shared.cq_entries) {
+                return Ok(());
+            }
+            if signal_pending() {
+                return Err(EINTR);
+            }
+            shared.completed.wait(guard);
+        }
+    }
+}
+
+impl Drop for IoRing {
+    fn drop(&mut self) {
+        // 在 jobs 的锁里设置，内核线程检查后再睡眠就不会错过唤醒
+        let jobs = self.shared.jobs.lock();
+        self.shared.closed.store(true, Ordering::Release);
+        drop(jobs);
+        self.shared.work.wake_all();
+    }
+}
+
+impl File for IoRing {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn write(&self, _buf: &[u8]) -> usize {
+        0
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        None
+    }
+
+    fn hang_up(&self) -> bool {
+        false
+    }
+
+    /// 有完成事件可取时算可读，可以放进 ppoll 等待
+    fn r_ready(&self) -> bool {
+        self.shared.cq_ready() > 0
+    }
+}
@@ -1,72 +1,276 @@
-use alloc::sync::Arc;
+use alloc::{string::String, sync::Arc};
 
+use cgroup::CgroupFS;
 use defs::OpenFlags;
 use dentry::Dentry;
+use devfs::DevFS;
 use ext4::fs::Ext4FS;
-use fs::FileSystemManager;
+use fat32::fs::Fat32FS;
+use file::cast_file_to_inode;
+pub use fs::{FileSystem, FileSystemType};
 use inode::{Inode, InodeType};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use namespace::{MountNamespace, INIT_MNT_NS};
+use path::Path;
+use procfs::ProcFS;
+use tmpfs::TmpFS;
 
-use crate::drivers::BLOCK_DEVICE;
+use crate::{
+    block::{
+        block_cache::{block_cache_drop_device, block_cache_sync_all},
+        block_dev::BlockDevice,
+        partition::{root_partition, PARTITIONS},
+    },
+    drivers::BLOCK_DEVICE,
+    mm::sync_pages,
+    syscall::errno::{EEXIST, ELOOP, ENOENT},
+    task::{
+        all_processes,
+        cred::{Credentials, MAY_EXEC, MAY_WRITE},
+        current_task,
+        INITPROC,
+    },
+};
 
+pub mod cgroup;
 pub mod defs;
 pub mod dentry;
+pub mod devfs;
+pub mod epoll;
 pub mod ext4;
-mod fat32;
+pub mod fat32;
+pub mod fd;
 pub mod file;
 mod fs;
 pub mod inode;
+pub mod io_uring;
+pub mod namespace;
 mod path;
 pub mod pipe;
+pub mod poll;
+pub mod procfs;
 pub mod stdio;
-
-lazy_static! {
-    pub static ref FS_MANAGER: Mutex<FileSystemManager> = Mutex::new(FileSystemManager::new());
-}
+pub mod tmpfs;
+pub mod tty;
 
 lazy_static! {
     pub static ref ROOT_INODE: Arc<dyn Inode> = {
-        let ext4fs = Arc::new(Ext4FS::new(BLOCK_DEVICE.clone()));
-        FS_MANAGER.lock().mount(ext4fs, "/");
-        FS_MANAGER.lock().rootfs().root_inode()
+        // 根分区的引导扇区是 FAT32 时以它为根文件系统，否则按 ext4 挂载
+        let root = root_partition();
+        let bdev: Arc<dyn BlockDevice> = root.clone();
+        let rootfs: Arc<dyn FileSystem> = if Fat32FS::probe(&bdev) {
+            Fat32FS::load(bdev)
+        } else {
+            Arc::new(Ext4FS::new(root))
+        };
+        INIT_MNT_NS.mount(rootfs, "/");
+        INIT_MNT_NS.rootfs().root_inode()
     };
 }
 
 pub fn init() {
     let _root = ROOT_INODE.clone();
+    INIT_MNT_NS.mount(Arc::new(ProcFS), "/proc");
+    INIT_MNT_NS.mount(Arc::new(DevFS), "/dev");
+    INIT_MNT_NS.mount(TmpFS::new(), "/dev/shm");
+    INIT_MNT_NS.mount(TmpFS::new(), "/tmp");
+    INIT_MNT_NS.mount(Arc::new(CgroupFS), "/sys/fs/cgroup");
+}
+
+/// The mount table paths are resolved in: the current task's, or the initial one
+/// while no task runs
+fn current_mnt_ns() -> Arc<MountNamespace> {
+    match current_task() {
+        // 调用者（如 sys_openat）可能正借用着当前任务的 inner，这里只复制挂载表的引用
+        Some(task) => unsafe { task.inner_unchecked().mnt_ns.clone() },
+        None => INIT_MNT_NS.clone(),
+    }
+}
+
+/// The root directory of the current mount namespace
+pub fn root_dentry() -> Arc<Dentry> {
+    Arc::new(Dentry::new("/", current_mnt_ns().rootfs().root_inode()))
+}
+
+/// Look up `path` from the directory `start`, following symbolic links
+///
+/// See [`path::resolve`], the dentry is named by the absolute path when it is known.
+pub fn lookup_path(start: &Dentry, path: &str) -> Result<Arc<Dentry>, isize> {
+    path::resolve(&current_mnt_ns(), start, path, true)
 }
 
-/// Open a file
-pub fn open_file(inode: Arc<dyn Inode>, name: &str, flags: OpenFlags) -> Option<Arc<Dentry>> {
+/// Look up `path` from the directory `start`, a symbolic link as its last component
+/// is returned itself
+pub fn lookup_link(start: &Dentry, path: &str) -> Result<Arc<Dentry>, isize> {
+    path::resolve(&current_mnt_ns(), start, path, false)
+}
+
+/// The directory holding the last component of `path`, and that component
+pub fn lookup_parent(start: &Dentry, path: &str) -> Result<(Arc<Dentry>, String), isize> {
+    path::resolve_parent(&current_mnt_ns(), start, path)
+}
+
+/// Open `path` from the directory `start`, creating it with O_CREAT
+///
+/// With O_NOFOLLOW a symbolic link as the last component fails with ELOOP.
+pub fn open_file(start: &Dentry, path: &str, flags: OpenFlags) -> Result<Arc<Dentry>, isize> {
     // TODO: read_write
     // let (readable, writable) = flags.read_write();
-    if flags.contains(OpenFlags::O_CREAT) {
-        if let Some(dentry) = inode.clone().lookup(name) {
-            // clear size
-            dentry.inode().clear();
-            Some(dentry)
-        } else {
-            // create file
+    let follow = !flags.contains(OpenFlags::O_NOFOLLOW);
+    match path::resolve(&current_mnt_ns(), start, path, follow) {
+        Ok(dentry) => {
+            if flags.contains(OpenFlags::O_CREAT | OpenFlags::O_EXCL) {
+                return Err(EEXIST);
+            }
+            if !follow && dentry.inode().read_link().is_some() {
+                return Err(ELOOP);
+            }
+            if flags.contains(OpenFlags::O_TRUNC) {
+                dentry.inode().clear();
+            }
+            Ok(dentry)
+        }
+        Err(ENOENT) if flags.contains(OpenFlags::O_CREAT) => {
+            let (dir, name) = lookup_parent(start, path)?;
             let type_ = if flags.contains(OpenFlags::O_DIRECTORY) {
                 InodeType::Directory
             } else {
                 InodeType::Regular
             };
-            let dentry = inode.create(name, type_)?;
-            Some(dentry)
-        }
-    } else if let Some(dentry) = inode.lookup(name) {
-        if flags.contains(OpenFlags::O_TRUNC) {
-            dentry.inode().clear();
+            let dentry = dir.inode().create(&name, type_).ok_or(ENOENT)?;
+            let path = Path::join(dir.name(), &name);
+            Ok(Arc::new(Dentry::new(&path, dentry.inode())))
         }
-        Some(dentry)
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether `cred` may [`open_file`] `path` from `start` with `flags`
+///
+/// An existing file is checked against the access in `flags`, a file to be
+/// created needs write and search permission on its directory. A missing file
+/// is allowed here and left to `open_file` to report.
+pub fn may_open(cred: &Credentials, start: &Dentry, path: &str, flags: OpenFlags) -> bool {
+    if let Ok(dentry) = lookup_path(start, path) {
+        cred.permits(dentry.inode().perm(), flags.access())
+    } else if flags.contains(OpenFlags::O_CREAT) {
+        may_modify(cred, start, path)
     } else {
-        None
+        true
     }
 }
 
+/// Whether `cred` may create or remove the last component of `path` from `start`
+pub fn may_modify(cred: &Credentials, start: &Dentry, path: &str) -> bool {
+    lookup_parent(start, path).map_or(true, |(dir, _)| {
+        cred.permits(dir.inode().perm(), MAY_WRITE | MAY_EXEC)
+    })
+}
+
+/// A filesystem of `fs_type` to mount, `None` if the block device does not hold one
+///
+/// ext4 和 vfat 复用已经挂载的实例，同一设备上的两个实例各自缓存元数据会互相覆盖；
+/// 还没有挂载的 vfat 取第一个 FAT32 分区。
+pub fn new_filesystem(fs_type: FileSystemType) -> Option<Arc<dyn FileSystem>> {
+    let fs: Arc<dyn FileSystem> = match fs_type {
+        FileSystemType::EXT4 | FileSystemType::VFAT => {
+            let mounted = current_mnt_ns()
+                .filesystems()
+                .into_iter()
+                .find(|fs| fs.fs_type() == fs_type);
+            match mounted {
+                Some(fs) => fs,
+                None if fs_type == FileSystemType::VFAT => {
+                    let bdev = PARTITIONS
+                        .iter()
+                        .map(|part| -> Arc<dyn BlockDevice> { part.clone() })
+                        .find(Fat32FS::probe)?;
+                    Fat32FS::load(bdev)
+                }
+                None => return None,
+            }
+        }
+        FileSystemType::TMPFS => TmpFS::new(),
+        FileSystemType::PROC => Arc::new(ProcFS),
+        FileSystemType::DEVTMPFS => Arc::new(DevFS),
+        FileSystemType::CGROUP2 => Arc::new(CgroupFS),
+    };
+    Some(fs)
+}
+
+/// Whether a task has a file open or its working directory on `fs`, which then can
+/// not be unmounted
+pub fn fs_in_use(fs: &Arc<dyn FileSystem>) -> bool {
+    let id = Arc::as_ptr(fs) as *const () as usize;
+    let on_fs = |inode: Arc<dyn Inode>| inode.fs_id() == Some(id);
+    let mut tasks = all_processes();
+    tasks.push(INITPROC.clone());
+    while let Some(task) = tasks.pop() {
+        let inner = task.inner_exclusive_access();
+        let busy = on_fs(inner.work_dir.inode())
+            || inner
+                .fd_table
+                .lock()
+                .iter()
+                .flatten()
+                .any(|entry| cast_file_to_inode(entry.file.clone()).map_or(false, on_fs));
+        if busy {
+            return true;
+        }
+        tasks.extend(inner.threads.iter().flatten().cloned());
+    }
+    false
+}
+
+/// Called by the block layer when the media of the block device was removed or inserted
+///
+/// 文件系统丢弃从设备读到的缓存，块缓存里这张卡上各分区的块也一并丢弃，之后的访问
+/// 重新读盘；卡拔出期间的读写返回 EIO。
+/// ext4 的超级块在挂载时读入，换了一张不同的卡需要重新挂载。
+/// 私有挂载命名空间与初始命名空间共享同一个文件系统对象，所以只需遍历初始命名空间。
+pub fn media_changed() {
+    for fs in INIT_MNT_NS.filesystems() {
+        fs.invalidate();
+    }
+    for part in PARTITIONS.iter() {
+        let bdev: Arc<dyn BlockDevice> = part.clone();
+        block_cache_drop_device(&bdev);
+    }
+}
+
+/// Write the data of `inode` back to the disk, for fsync
+///
+/// 映射的页写回之后，由文件系统写回这个文件的数据和元数据，见 [`Inode::sync`]。
+pub fn sync_file(inode: &Arc<dyn Inode>) {
+    sync_pages(Some(inode));
+    inode.sync();
+}
+
+/// Write back the mapped pages of every file and all dirty blocks, for sync and umount
+pub fn sync_all() {
+    sync_pages(None);
+    block_cache_sync_all();
+    // ext4 不经过块缓存，直接让设备落盘
+    BLOCK_DEVICE.flush();
+}
+
 pub struct Iovec {
     pub iov_base: usize,
     pub iov_len:  usize,
 }
+
+#[allow(unused)]
+/// Test the root file system: list the root directory and look up every entry
+pub fn fs_test() {
+    let names = ROOT_INODE.ls();
+    assert!(!names.is_empty(), "empty root directory");
+    for name in names.iter().filter(|name| *name != "." && *name != "..") {
+        let dentry = ROOT_INODE
+            .clone()
+            .lookup(name)
+            .unwrap_or_else(|| panic!("lookup {} failed", name));
+        let mut buf = [0u8; 16];
+        dentry.inode().read_at(0, &mut buf);
+    }
+    info!("fs test passed! {} entries in /", names.len());
+}
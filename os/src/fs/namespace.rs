@@ -0,0 +1,76 @@
+//! Mount namespaces, created by `clone(CLONE_NEWNS)`
+//!
+//! 每个进程持有一张挂载表的引用。fork 时与父进程共享同一张表，`CLONE_NEWNS` 时复制一份，
+//! 此后在新命名空间中的挂载和卸载不会影响原来的挂载树。
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use lazy_static::*;
+use spin::Mutex;
+
+use super::{
+    fs::{FileSystem, FileSystemManager, FileSystemType},
+    inode::Inode,
+};
+
+pub struct MountNamespace {
+    mounts: Mutex<FileSystemManager>,
+}
+
+lazy_static! {
+    /// The mount namespace of the initial process, holds the root filesystem
+    pub static ref INIT_MNT_NS: Arc<MountNamespace> = MountNamespace::new(FileSystemManager::new());
+}
+
+impl MountNamespace {
+    pub fn new(mounts: FileSystemManager) -> Arc<Self> {
+        Arc::new(Self {
+            mounts: Mutex::new(mounts),
+        })
+    }
+
+    /// A private copy of this mount table, filesystems stay shared
+    pub fn copy(&self) -> Arc<Self> {
+        Self::new(self.mounts.lock().clone())
+    }
+
+    pub fn mount(&self, fs: Arc<dyn FileSystem>, path: &str) {
+        self.mounts.lock().mount(fs, path);
+    }
+
+    /// Detach the filesystem mounted at `path`, false if nothing is mounted there
+    pub fn unmount(&self, path: &str) -> bool {
+        self.mounts.lock().unmount(path)
+    }
+
+    /// The filesystem mounted exactly at `path`
+    pub fn fs_at(&self, path: &str) -> Option<Arc<dyn FileSystem>> {
+        self.mounts.lock().fs_at(path)
+    }
+
+    /// The root inode of the filesystem holding the absolute `path`, and the rest of
+    /// the path relative to it
+    pub fn resolve(&self, path: &str) -> Option<(Arc<dyn Inode>, String)> {
+        let (fs, rest) = self.mounts.lock().resolve(path)?;
+        Some((fs.root_inode(), rest))
+    }
+
+    /// Mount points strictly below `path`
+    pub fn mounts_below(&self, path: &str) -> Vec<String> {
+        self.mounts.lock().mounts_below(path)
+    }
+
+    pub fn rootfs(&self) -> Arc<dyn FileSystem> {
+        self.mounts.lock().rootfs()
+    }
+
+    /// Mount points and filesystem types, in path order
+    pub fn mount_points(&self) -> Vec<(String, FileSystemType)> {
+        self.mounts.lock().mount_points()
+    }
+
+    /// Mounted filesystems, in path order
+    pub fn filesystems(&self) -> Vec<Arc<dyn FileSystem>> {
+        self.mounts.lock().filesystems()
+    }
+}
@@ -1,4 +1,19 @@
-use alloc::{borrow::ToOwned, string::String};
+//! Paths and the path walk shared by every syscall taking a path
+//!
+//! [`resolve`] 从起点目录逐个分量地查找：`.` 跳过，`..` 回到上一级目录，经过挂载点时进入挂载在那里的
+//! 文件系统的根目录，遇到符号链接时把链接内容接在剩下的分量前面继续查找。
+
+use alloc::{
+    borrow::ToOwned,
+    collections::VecDeque,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{dentry::Dentry, inode::Inode, namespace::MountNamespace};
+use crate::syscall::errno::{EEXIST, ELOOP, ENOENT};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Path {
@@ -17,6 +32,49 @@ impl Path {
     pub fn is_relative(&self) -> bool {
         !self.is_absolute()
     }
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    /// Collapse `.`, `..` and repeated slashes of an absolute path, `..` at the root
+    /// stays at the root
+    pub fn normalize(path: &str) -> String {
+        let mut components: Vec<&str> = Vec::new();
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
+                }
+                _ => components.push(component),
+            }
+        }
+        let mut normalized = String::new();
+        for component in components {
+            normalized.push('/');
+            normalized.push_str(component);
+        }
+        if normalized.is_empty() {
+            normalized.push('/');
+        }
+        normalized
+    }
+
+    /// `name` in the directory at `dir`
+    pub fn join(dir: &str, name: &str) -> String {
+        match dir {
+            "" => name.to_string(),
+            "/" => format!("/{}", name),
+            _ => format!("{}/{}", dir.trim_end_matches('/'), name),
+        }
+    }
+
+    /// Whether this mount point covers `path`, both normalized
+    pub fn covers(&self, path: &str) -> bool {
+        self.path == "/"
+            || path == self.path
+            || (path.starts_with(self.path.as_str()) && path.as_bytes()[self.path.len()] == b'/')
+    }
 }
 
 impl From<&str> for Path {
@@ -30,3 +88,167 @@ impl From<String> for Path {
         Self::new(&path)
     }
 }
+
+/// Symbolic links followed in one lookup before it fails with ELOOP, as in Linux
+const MAX_SYMLINKS: usize = 40;
+
+/// The non-empty components of `path`
+fn components(path: &str) -> VecDeque<String> {
+    path.split('/')
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// A walk down a path, one component at a time
+struct Walk<'a> {
+    mnt_ns:  &'a MountNamespace,
+    /// normalized absolute path of `dir`, `None` if the walk started at a directory
+    /// whose path is unknown (an open directory fd)
+    path:    Option<String>,
+    dir:     Arc<dyn Inode>,
+    /// directories the walk went down through, `..` climbs back up them
+    entered: Vec<Arc<dyn Inode>>,
+    links:   usize,
+}
+
+impl<'a> Walk<'a> {
+    /// Start at `start`, whose name is its absolute path if it begins with `/`
+    fn new(mnt_ns: &'a MountNamespace, start: &Dentry) -> Self {
+        let path = start
+            .name()
+            .starts_with('/')
+            .then(|| Path::normalize(start.name()));
+        Self {
+            mnt_ns,
+            path,
+            dir: start.inode(),
+            entered: Vec::new(),
+            links: 0,
+        }
+    }
+
+    fn restart_at_root(&mut self) {
+        self.path = Some("/".to_string());
+        self.dir = self.mnt_ns.rootfs().root_inode();
+        self.entered.clear();
+    }
+
+    /// Step to the parent directory
+    ///
+    /// 起点以上的目录没有走过，知道路径时按绝对路径从挂载表重新查找，
+    /// 否则交给文件系统的 `..` 目录项，此时不会退出挂载在起点之上的文件系统。
+    fn up(&mut self) -> Result<(), isize> {
+        let parent = self
+            .path
+            .as_deref()
+            .map(|path| Path::normalize(&format!("{}/..", path)));
+        if let Some(dir) = self.entered.pop() {
+            self.dir = dir;
+        } else if let Some(parent) = &parent {
+            let (mut dir, rest) = self.mnt_ns.resolve(parent).ok_or(ENOENT)?;
+            for name in components(&rest) {
+                dir = dir.lookup(&name).ok_or(ENOENT)?.inode();
+            }
+            self.dir = dir;
+        } else {
+            self.dir = self.dir.clone().lookup("..").ok_or(ENOENT)?.inode();
+        }
+        self.path = parent;
+        Ok(())
+    }
+
+    /// `name` in the current directory, or the root of the filesystem mounted over it,
+    /// and its path
+    fn child(&self, name: &str) -> Result<(Arc<dyn Inode>, Option<String>), isize> {
+        let path = self.path.as_deref().map(|dir| Path::join(dir, name));
+        if let Some(fs) = path.as_deref().and_then(|path| self.mnt_ns.fs_at(path)) {
+            return Ok((fs.root_inode(), path));
+        }
+        let inode = self.dir.clone().lookup(name).ok_or(ENOENT)?.inode();
+        Ok((inode, path))
+    }
+
+    /// Walk `path` from the current directory, a symbolic link as the last component
+    /// is followed only if `follow`
+    fn walk(&mut self, path: &str, follow: bool) -> Result<(), isize> {
+        if path.starts_with('/') {
+            self.restart_at_root();
+        }
+        let mut pending = components(path);
+        while let Some(name) = pending.pop_front() {
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    self.up()?;
+                    continue;
+                }
+                _ => {}
+            }
+            let (inode, path) = self.child(&name)?;
+            if !pending.is_empty() || follow {
+                if let Some(target) = inode.read_link() {
+                    self.links += 1;
+                    if self.links > MAX_SYMLINKS {
+                        return Err(ELOOP);
+                    }
+                    if target.starts_with('/') {
+                        self.restart_at_root();
+                    }
+                    for name in components(&target).into_iter().rev() {
+                        pending.push_front(name);
+                    }
+                    continue;
+                }
+            }
+            self.entered.push(core::mem::replace(&mut self.dir, inode));
+            self.path = path;
+        }
+        Ok(())
+    }
+
+    /// The dentry of where the walk stopped, named by its absolute path if known
+    fn dentry(self, path: &str) -> Arc<Dentry> {
+        let name = self.path.as_deref().unwrap_or(path);
+        Arc::new(Dentry::new(name, self.dir))
+    }
+}
+
+/// Look `path` up from the directory `start`, in the mount table `mnt_ns`
+///
+/// `start` is named by its absolute path, or by a relative name for a directory
+/// opened by fd; mount points are only crossed when the path is known. A symbolic
+/// link as the last component is returned itself unless `follow`.
+pub fn resolve(
+    mnt_ns: &MountNamespace, start: &Dentry, path: &str, follow: bool,
+) -> Result<Arc<Dentry>, isize> {
+    if path.is_empty() {
+        return Err(ENOENT);
+    }
+    let mut walk = Walk::new(mnt_ns, start);
+    walk.walk(path, follow)?;
+    Ok(walk.dentry(path))
+}
+
+/// Look up the directory holding the last component of `path`, return it with the
+/// last component
+///
+/// Fails with EEXIST if the last component is not a name (`/`, `.` or `..`).
+pub fn resolve_parent(
+    mnt_ns: &MountNamespace, start: &Dentry, path: &str,
+) -> Result<(Arc<Dentry>, String), isize> {
+    if path.is_empty() {
+        return Err(ENOENT);
+    }
+    let trimmed = path.trim_end_matches('/');
+    let (dir, name) = match trimmed.rfind('/') {
+        Some(pos) => (&trimmed[..pos + 1], &trimmed[pos + 1..]),
+        None => (".", trimmed),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(EEXIST);
+    }
+    let mut walk = Walk::new(mnt_ns, start);
+    walk.walk(dir, true)?;
+    Ok((walk.dentry(dir), name.to_string()))
+}
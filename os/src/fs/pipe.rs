@@ -1,53 +1,95 @@
+//! Pipes
+//!
+//! 两端共享一个 64 KiB 的环形缓冲区。缓冲区空时读者阻塞、满时写者阻塞，对端读写或关闭时唤醒；
+//! 写端全部关闭后读到文件末尾，读端全部关闭后写入返回 EPIPE 并给写者发 SIGPIPE。
+//! 设置了 O_NONBLOCK 的一端不阻塞，无法读写时返回 EAGAIN。
+
 use alloc::{
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
+use core::{
+    cmp::min,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{
+    file::File,
+    inode::{Stat, StatMode},
+    poll::notify_pollers,
+};
+use crate::{
+    sync::{SpinNoIrqLock, WaitQueue},
+    syscall::errno::{EAGAIN, EPIPE},
+    task::{current_add_signal, SignalFlags},
+};
 
-use super::{file::File, inode::Stat};
-use crate::{mm::UserBuffer, sync::UPSafeCell, task::suspend_current_and_run_next, trap};
+/// Capacity of a pipe
+const RING_BUFFER_SIZE: usize = 64 * 1024;
+/// Writes of at most this many bytes are not interleaved with other writes
+const PIPE_BUF: usize = 4096;
 
 /// IPC pipe
 pub struct Pipe {
     readable: bool,
     writable: bool,
-    buffer:   Arc<UPSafeCell<PipeRingBuffer>>,
+    buffer:   Arc<SpinNoIrqLock<PipeRingBuffer>>,
+    /// O_NONBLOCK of this end
+    nonblock: AtomicBool,
 }
 
 impl Pipe {
-    /// create readable pipe
-    pub fn read_end_with_buffer(buffer: Arc<UPSafeCell<PipeRingBuffer>>) -> Self {
+    fn new(buffer: Arc<SpinNoIrqLock<PipeRingBuffer>>, readable: bool) -> Self {
         Self {
-            readable: true,
-            writable: false,
+            readable,
+            writable: !readable,
             buffer,
+            nonblock: AtomicBool::new(false),
         }
     }
+    /// create readable pipe
+    pub fn read_end_with_buffer(buffer: Arc<SpinNoIrqLock<PipeRingBuffer>>) -> Self {
+        Self::new(buffer, true)
+    }
     /// create writable pipe
-    pub fn write_end_with_buffer(buffer: Arc<UPSafeCell<PipeRingBuffer>>) -> Self {
-        Self {
-            readable: false,
-            writable: true,
-            buffer,
-        }
+    pub fn write_end_with_buffer(buffer: Arc<SpinNoIrqLock<PipeRingBuffer>>) -> Self {
+        Self::new(buffer, false)
     }
 }
 
-const RING_BUFFER_SIZE: usize = 3200;
+/// `done` bytes were moved before hitting `errno`, which is reported only if none were
+fn stop(done: usize, errno: isize) -> Result<usize, isize> {
+    if done == 0 {
+        Err(errno)
+    } else {
+        Ok(done)
+    }
+}
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-enum RingBufferStatus {
-    Full,
-    Empty,
-    Normal,
+impl Drop for Pipe {
+    /// 最后一个读端或写端关闭时，阻塞在对端的任务要醒来看到 EOF 或 EPIPE
+    fn drop(&mut self) {
+        let ring_buffer = self.buffer.lock();
+        if self.readable {
+            ring_buffer.wake_writers();
+        } else {
+            ring_buffer.wake_readers();
+        }
+    }
 }
 
 pub struct PipeRingBuffer {
-    arr:       [u8; RING_BUFFER_SIZE],
+    arr:       Vec<u8>,
     head:      usize,
-    tail:      usize,
-    status:    RingBufferStatus,
-    write_end: Option<Weak<Pipe>>,
-    read_end:  Option<Weak<Pipe>>,
+    /// number of bytes in the buffer, starting at `head`
+    len:       usize,
+    write_end: Weak<Pipe>,
+    read_end:  Weak<Pipe>,
+    /// tasks waiting for data or for the write end to close
+    readers:   Arc<WaitQueue>,
+    /// tasks waiting for room or for the read end to close
+    writers:   Arc<WaitQueue>,
 }
 
 impl Default for PipeRingBuffer {
@@ -59,112 +101,109 @@ impl Default for PipeRingBuffer {
 impl PipeRingBuffer {
     pub fn new() -> Self {
         Self {
-            arr:       [0; RING_BUFFER_SIZE],
+            arr:       vec![0; RING_BUFFER_SIZE],
             head:      0,
-            tail:      0,
-            status:    RingBufferStatus::Empty,
-            write_end: None,
-            read_end:  None,
+            len:       0,
+            write_end: Weak::new(),
+            read_end:  Weak::new(),
+            readers:   Arc::new(WaitQueue::new()),
+            writers:   Arc::new(WaitQueue::new()),
         }
     }
     pub fn set_write_end(&mut self, write_end: &Arc<Pipe>) {
-        self.write_end = Some(Arc::downgrade(write_end));
-    }
-    pub fn write_byte(&mut self, byte: u8) {
-        self.status = RingBufferStatus::Normal;
-        self.arr[self.tail] = byte;
-        self.tail = (self.tail + 1) % RING_BUFFER_SIZE;
-        if self.tail == self.head {
-            self.status = RingBufferStatus::Full;
-        }
+        self.write_end = Arc::downgrade(write_end);
     }
-    pub fn read_byte(&mut self) -> u8 {
-        self.status = RingBufferStatus::Normal;
-        let c = self.arr[self.head];
-        self.head = (self.head + 1) % RING_BUFFER_SIZE;
-        if self.head == self.tail {
-            self.status = RingBufferStatus::Empty;
-        }
-        c
+    pub fn set_read_end(&mut self, read_end: &Arc<Pipe>) {
+        self.read_end = Arc::downgrade(read_end);
+    }
+    /// Move as many bytes as are buffered into `buf`, return the number moved
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = min(self.len, buf.len());
+        // 环形缓冲区中的数据最多分两段
+        let first = min(len, RING_BUFFER_SIZE - self.head);
+        buf[..first].copy_from_slice(&self.arr[self.head..self.head + first]);
+        buf[first..len].copy_from_slice(&self.arr[..len - first]);
+        self.head = (self.head + len) % RING_BUFFER_SIZE;
+        self.len -= len;
+        len
+    }
+    /// Append as much of `buf` as fits, return the number of bytes appended
+    fn write(&mut self, buf: &[u8]) -> usize {
+        let len = min(self.available_write(), buf.len());
+        let tail = (self.head + self.len) % RING_BUFFER_SIZE;
+        let first = min(len, RING_BUFFER_SIZE - tail);
+        self.arr[tail..tail + first].copy_from_slice(&buf[..first]);
+        self.arr[..len - first].copy_from_slice(&buf[first..len]);
+        self.len += len;
+        len
     }
     pub fn available_read(&self) -> usize {
-        if self.status == RingBufferStatus::Empty {
-            0
-        } else if self.tail > self.head {
-            self.tail - self.head
-        } else {
-            self.tail + RING_BUFFER_SIZE - self.head
-        }
+        self.len
     }
     pub fn available_write(&self) -> usize {
-        // error!("status: {:?}", self.status);
-        if self.status == RingBufferStatus::Full {
-            0
-        } else {
-            RING_BUFFER_SIZE - self.available_read()
-        }
+        RING_BUFFER_SIZE - self.len
     }
     pub fn all_write_ends_closed(&self) -> bool {
-        self.write_end.as_ref().unwrap().upgrade().is_none()
+        self.write_end.upgrade().is_none()
     }
     pub fn all_read_ends_closed(&self) -> bool {
-        self.read_end.as_ref().unwrap().upgrade().is_none()
+        self.read_end.upgrade().is_none()
+    }
+    /// 读端可能变得可读或挂断，ppoll 和 epoll 的等待者也要重新检查
+    fn wake_readers(&self) {
+        self.readers.wake_all();
+        notify_pollers();
+    }
+    fn wake_writers(&self) {
+        self.writers.wake_all();
+        notify_pollers();
     }
 }
 
 /// Return (read_end, write_end)
 pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
     trace!("kernel: make_pipe");
-    let buffer = Arc::new(unsafe { UPSafeCell::new(PipeRingBuffer::new()) });
+    let buffer = Arc::new(SpinNoIrqLock::new(PipeRingBuffer::new()));
     let read_end = Arc::new(Pipe::read_end_with_buffer(buffer.clone()));
     let write_end = Arc::new(Pipe::write_end_with_buffer(buffer.clone()));
-    buffer
-        .exclusive_access(file!(), line!())
-        .set_write_end(&write_end);
+    let mut ring_buffer = buffer.lock();
+    ring_buffer.set_read_end(&read_end);
+    ring_buffer.set_write_end(&write_end);
+    drop(ring_buffer);
     (read_end, write_end)
 }
 
 impl File for Pipe {
     fn readable(&self) -> bool {
-        // TODO: check if the write end is closed
-        true
+        self.readable
     }
     fn writable(&self) -> bool {
-        // TODO: check if the read end is closed
-        true
+        self.writable
     }
     fn read(&self, buf: &mut [u8]) -> usize {
+        self.try_read(buf).unwrap_or(0)
+    }
+    /// Read what is buffered, waiting only while the pipe is empty
+    fn try_read(&self, buf: &mut [u8]) -> Result<usize, isize> {
         trace!("kernel: Pipe::read");
-        assert!(self.readable());
-        let want_to_read = buf.len();
-        let mut buf_iter = buf.into_iter();
-        let mut already_read = 0usize;
+        if buf.is_empty() {
+            return Ok(0);
+        }
         loop {
-            let mut ring_buffer = self.buffer.exclusive_access(file!(), line!());
-            let loop_read = ring_buffer.available_read();
-            if loop_read == 0 {
-                if ring_buffer.all_write_ends_closed() {
-                    return already_read;
-                }
-                drop(ring_buffer);
-                debug!("kernel: Pipe::read suspend_current_and_run_next");
-                suspend_current_and_run_next();
-                trap::wait_return();
-                continue;
+            let mut ring_buffer = self.buffer.lock();
+            let len = ring_buffer.read(buf);
+            if len > 0 {
+                ring_buffer.wake_writers();
+                return Ok(len);
             }
-            for _ in 0..loop_read {
-                info!("kernel: start read byte from pipe");
-                if let Some(byte_ref) = buf_iter.next() {
-                    *byte_ref = ring_buffer.read_byte();
-                    warn!("read byte: {}", *byte_ref as char);
-                    already_read += 1;
-                    if already_read == want_to_read {
-                        return want_to_read;
-                    }
-                } else {
-                    return already_read;
-                }
+            if ring_buffer.all_write_ends_closed() {
+                return Ok(0);
             }
+            if self.nonblock.load(Ordering::Relaxed) {
+                return Err(EAGAIN);
+            }
+            let readers = ring_buffer.readers.clone();
+            readers.wait(ring_buffer);
         }
     }
     fn read_all(&self) -> Vec<u8> {
@@ -181,51 +220,65 @@ impl File for Pipe {
         v
     }
     fn write(&self, buf: &[u8]) -> usize {
+        self.try_write(buf).unwrap_or(0)
+    }
+    /// Write all of `buf`, waiting for room unless O_NONBLOCK is set
+    ///
+    /// 不超过 PIPE_BUF 字节的写入不会被拆开，缓冲区放不下时整个等待。
+    fn try_write(&self, buf: &[u8]) -> Result<usize, isize> {
         trace!("kernel: Pipe::write");
-        assert!(self.writable());
-        let want_to_write = buf.len();
-        let mut buf_iter = buf.into_iter();
-        let mut already_write = 0usize;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let atomic = buf.len() <= PIPE_BUF;
+        let mut written = 0;
         loop {
-            let mut ring_buffer = self.buffer.exclusive_access(file!(), line!());
-            let loop_write = ring_buffer.available_write();
-            if loop_write == 0 {
+            let mut ring_buffer = self.buffer.lock();
+            if ring_buffer.all_read_ends_closed() {
                 drop(ring_buffer);
-                debug!("kernel: Pipe::write suspend_current_and_run_next");
-                suspend_current_and_run_next();
-                continue;
+                current_add_signal(SignalFlags::SIGPIPE);
+                return stop(written, EPIPE);
             }
-            // write at most loop_write bytes
-            for _ in 0..loop_write {
-                if let Some(byte_ref) = buf_iter.next() {
-                    ring_buffer.write_byte(unsafe { *byte_ref });
-                    already_write += 1;
-                    if already_write == want_to_write {
-                        return want_to_write;
-                    }
-                } else {
-                    return already_write;
+            if !atomic || ring_buffer.available_write() >= buf.len() {
+                let len = ring_buffer.write(&buf[written..]);
+                if len > 0 {
+                    written += len;
+                    ring_buffer.wake_readers();
                 }
             }
+            if written == buf.len() {
+                return Ok(written);
+            }
+            if self.nonblock.load(Ordering::Relaxed) {
+                return stop(written, EAGAIN);
+            }
+            let writers = ring_buffer.writers.clone();
+            writers.wait(ring_buffer);
         }
     }
     fn fstat(&self) -> Option<Stat> {
-        panic!("Pipe::fstat not implemented");
+        let st_mode = StatMode::FIFO.bits() | 0o600;
+        Some(Stat::new(0, 0, st_mode, 1, 0, 0, 0, 0, 0))
     }
     fn hang_up(&self) -> bool {
-        let mut ring_buffer = self.buffer.exclusive_access(file!(), line!());
+        let ring_buffer = self.buffer.lock();
         if self.readable {
             ring_buffer.all_write_ends_closed()
         } else {
             ring_buffer.all_read_ends_closed()
         }
     }
+    /// 有数据可读，或者写端已经关闭、读会立即返回 0
     fn r_ready(&self) -> bool {
-        let ring_buffer = self.buffer.exclusive_access(file!(), line!());
-        ring_buffer.status != RingBufferStatus::Empty
+        let ring_buffer = self.buffer.lock();
+        ring_buffer.available_read() > 0 || ring_buffer.all_write_ends_closed()
     }
+    /// 能立即写入 PIPE_BUF 字节，或者读端已经关闭、写会立即失败
     fn w_ready(&self) -> bool {
-        let ring_buffer = self.buffer.exclusive_access(file!(), line!());
-        ring_buffer.status != RingBufferStatus::Full
+        let ring_buffer = self.buffer.lock();
+        ring_buffer.available_write() >= PIPE_BUF || ring_buffer.all_read_ends_closed()
+    }
+    fn set_nonblock(&self, nonblock: bool) {
+        self.nonblock.store(nonblock, Ordering::Relaxed);
     }
 }
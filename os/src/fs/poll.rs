@@ -0,0 +1,50 @@
+//! Waiting for files to become ready, for ppoll and epoll_pwait
+//!
+//! 文件变得可读、可写或挂断时调用 [`notify_pollers`]，唤醒所有等待者重新检查。控制台输入
+//! 没有中断，不会通知，所以等待者最多睡 [`POLL_INTERVAL_MS`] 就重新检查一次。
+
+use alloc::{sync::Arc, vec::Vec};
+
+use lazy_static::*;
+
+use crate::{
+    config::CLOCK_FREQ,
+    sync::SpinNoIrqLock,
+    task::{block_current_in_syscall, current_task, wakeup_task, TaskControlBlock, TaskStatus},
+    timer::{add_timer, get_time, remove_timer},
+};
+
+/// Longest sleep between two checks of the files
+pub const POLL_INTERVAL_MS: usize = 10;
+
+lazy_static! {
+    /// tasks blocked in [`wait_for_poll`]
+    static ref POLLERS: SpinNoIrqLock<Vec<Arc<TaskControlBlock>>> =
+        SpinNoIrqLock::new(Vec::new());
+}
+
+/// Wake every task waiting in [`wait_for_poll`]
+pub fn notify_pollers() {
+    let pollers = core::mem::take(&mut *POLLERS.lock());
+    for task in pollers {
+        // 定时器先到、已经醒来的等待者不能再唤醒一次
+        let blocked = task.inner_exclusive_access().task_status == TaskStatus::Blocked;
+        if blocked {
+            remove_timer(task.clone());
+            wakeup_task(task);
+        }
+    }
+}
+
+/// Block the current task until [`notify_pollers`], `deadline` in clock ticks or the next
+/// periodic check, whichever comes first
+pub fn wait_for_poll(deadline: Option<usize>) {
+    let task = current_task().unwrap();
+    let next_check = get_time() + POLL_INTERVAL_MS * CLOCK_FREQ / 1000;
+    let wakeup = deadline.map_or(next_check, |deadline| deadline.min(next_check));
+    POLLERS.lock().push(task.clone());
+    add_timer(wakeup, task.clone());
+    block_current_in_syscall();
+    remove_timer(task.clone());
+    POLLERS.lock().retain(|poller| !Arc::ptr_eq(poller, &task));
+}
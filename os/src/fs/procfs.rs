@@ -0,0 +1,546 @@
+//! Pseudo filesystem exporting kernel state, mounted at `/proc`
+//!
+//! 根目录下的普通文件对应 [`PROC_ENTRIES`] 中的一项，读取时现场生成内容；
+//! 可写的文件把写入的内容交给该项的 `store`。
+//! `kmsg` 不在其中，它和 syslog(2) 一样消耗式地读内核日志，没有内容时阻塞。
+//! 每个进程有一个以 pid 命名的目录，包含 `stat` 和 `status`，`self` 指向读者所在的进程。
+//! pid 按读者所在的 pid 命名空间翻译，看不到的进程不列出。
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use super::{
+    dentry::Dentry,
+    file::File,
+    fs::{FileSystem, FileSystemType},
+    inode::{Inode, InodePerm, InodeType, Stat, StatMode},
+};
+use crate::{
+    block::block_cache::{self, BLOCK_CACHE_MANAGER},
+    config::{CLOCK_FREQ, PAGE_SIZE},
+    logging::{self, kmsg::KMSG},
+    mm::{frame_stats, PrivateRegion},
+    profile,
+    sync::SpinNoIrqLock,
+    syscall::{audit, syslog::read_kmsg},
+    task::{
+        all_processes,
+        current_task,
+        pid2process,
+        TaskControlBlock,
+        TaskControlBlockInner,
+        TaskStatus,
+        INITPROC,
+    },
+};
+
+/// A file in `/proc`
+struct ProcEntry {
+    name:  &'static str,
+    /// permission bits, the files belong to root
+    mode:  u16,
+    show:  fn() -> String,
+    /// `None` for read-only files, false if the written value is rejected
+    store: Option<fn(&str) -> bool>,
+}
+
+const PROC_ENTRIES: &[ProcEntry] = &[
+    ProcEntry {
+        name:  "profile",
+        mode:  0o644,
+        show:  profile::render,
+        store: Some(|_| {
+            profile::reset();
+            true
+        }),
+    },
+    ProcEntry {
+        name:  "meminfo",
+        mode:  0o444,
+        show:  render_meminfo,
+        store: None,
+    },
+    ProcEntry {
+        name:  "mounts",
+        mode:  0o444,
+        show:  render_mounts,
+        store: None,
+    },
+    ProcEntry {
+        name:  "block_cache",
+        mode:  0o644,
+        show:  block_cache::render_stats,
+        store: Some(|_| {
+            BLOCK_CACHE_MANAGER.lock().reset_stats();
+            true
+        }),
+    },
+    ProcEntry {
+        name:  "block_cache_capacity",
+        mode:  0o644,
+        show:  || format!("{}\n", BLOCK_CACHE_MANAGER.lock().capacity()),
+        store: Some(|value| match value.trim().parse::<usize>() {
+            Ok(capacity) if capacity > 0 => {
+                block_cache::set_block_cache_capacity(capacity);
+                true
+            }
+            _ => false,
+        }),
+    },
+    #[cfg(feature = "debug_heap")]
+    ProcEntry {
+        name:  "heap",
+        mode:  0o444,
+        show:  crate::mm::debug_heap::render,
+        store: None,
+    },
+    // 写入与 bootargs 中 loglevel= 相同格式的设置，例如 `echo warn,mm=debug > /proc/loglevel`
+    ProcEntry {
+        name:  "loglevel",
+        mode:  0o644,
+        show:  logging::filter::render,
+        store: Some(|spec| logging::apply_level_spec(spec.trim())),
+    },
+    // 审计记录可能包含其他用户的参数，只对 root 开放
+    ProcEntry {
+        name:  "audit",
+        mode:  0o600,
+        show:  audit::render,
+        store: Some(|_| {
+            audit::clear();
+            true
+        }),
+    },
+];
+
+fn render_meminfo() -> String {
+    let (total, free) = frame_stats();
+    let kb = |pages: usize| pages * PAGE_SIZE / 1024;
+    format!(
+        "MemTotal:{:>16} kB\nMemFree:{:>17} kB\nMemAvailable:{:>12} kB\n",
+        kb(total),
+        kb(free),
+        kb(free)
+    )
+}
+
+/// Mounts of the reader's mount namespace, in the `/proc/mounts` format
+fn render_mounts() -> String {
+    let task = current_task().unwrap();
+    let mnt_ns = task.inner_exclusive_access().mnt_ns.clone();
+    mnt_ns
+        .mount_points()
+        .into_iter()
+        .map(|(path, fs_type)| {
+            // 没有块设备名，和 Linux 的伪文件系统一样用文件系统类型代替
+            format!("{0} {1} {0} rw 0 0\n", fs_type.to_str(), path)
+        })
+        .collect()
+}
+
+/// A live process by global pid, the init process is not in the pid table
+fn find_process(pid: usize) -> Option<Arc<TaskControlBlock>> {
+    if pid == INITPROC.pid.0 {
+        return Some(INITPROC.clone());
+    }
+    pid2process(pid)
+}
+
+/// Global pids of the processes visible from the reader's pid namespace, with their
+/// pids in it
+fn visible_processes() -> Vec<(usize, usize)> {
+    let ns = current_task().unwrap().pid_ns();
+    let mut pids: Vec<(usize, usize)> = all_processes()
+        .iter()
+        .map(|process| process.pid.0)
+        .chain(core::iter::once(INITPROC.pid.0))
+        .filter_map(|pid| Some((pid, ns.pid_of(pid)?)))
+        .collect();
+    pids.sort_by_key(|&(_, nr)| nr);
+    pids.dedup();
+    pids
+}
+
+/// Files in a process directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PidAttr {
+    Stat,
+    Status,
+}
+
+impl PidAttr {
+    const ALL: [Self; 2] = [Self::Stat, Self::Status];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Stat => "stat",
+            Self::Status => "status",
+        }
+    }
+
+    fn lookup(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|attr| attr.name() == name).copied()
+    }
+
+    fn show(&self, process: &Arc<TaskControlBlock>) -> String {
+        let ns = current_task().unwrap().pid_ns();
+        let nr = |pid: usize| ns.pid_of(pid).unwrap_or(0);
+        let inner = process.inner_exclusive_access();
+        let ppid = inner
+            .parent
+            .as_ref()
+            .and_then(|parent| parent.upgrade())
+            .map_or(0, |parent| nr(parent.pid.0));
+        let threads = 1 + inner.threads.iter().flatten().count();
+        let (vm_pages, rss_pages) = memory_pages(&inner);
+        match self {
+            Self::Stat => {
+                // 字段顺序和 Linux 的 /proc/<pid>/stat 一致，输出到 rss 为止，没有维护的字段填 0
+                let to_clock_ticks = |tick: usize| tick * USER_HZ / CLOCK_FREQ;
+                let (utime, stime) = (
+                    to_clock_ticks(inner.user_clock),
+                    to_clock_ticks(inner.kernel_clock),
+                );
+                let start_time = inner.first_time.map_or(0, |ms| ms * USER_HZ / 1000);
+                let head = [
+                    ppid,
+                    nr(inner.pgid),
+                    nr(inner.sid),
+                    0, // tty_nr
+                    0, // tpgid
+                    0, // flags
+                    0, // minflt
+                    0, // cminflt
+                    0, // majflt
+                    0, // cmajflt
+                    utime,
+                    stime,
+                    0, // cutime
+                    0, // cstime
+                ];
+                let tail = [
+                    threads,
+                    0, // itrealvalue
+                    start_time,
+                    vm_pages * PAGE_SIZE,
+                    rss_pages,
+                ];
+                let mut stat = format!(
+                    "{} ({}) {}",
+                    nr(process.pid.0),
+                    inner.comm,
+                    state_of(&inner).0
+                );
+                for field in head {
+                    stat += &format!(" {}", field);
+                }
+                // priority 和 nice 可能为负
+                stat += &format!(" {} {}", 20 + inner.sched.nice, inner.sched.nice);
+                for field in tail {
+                    stat += &format!(" {}", field);
+                }
+                stat + "\n"
+            }
+            Self::Status => {
+                let cred = &inner.cred;
+                let groups: Vec<String> = cred.groups.iter().map(|gid| gid.to_string()).collect();
+                let (state, state_name) = state_of(&inner);
+                let pid = nr(process.pid.0);
+                let mut status = String::new();
+                let _ = writeln!(status, "Name:\t{}", inner.comm);
+                let _ = writeln!(status, "State:\t{} ({})", state, state_name);
+                let _ = writeln!(status, "Tgid:\t{}\nPid:\t{}\nPPid:\t{}", pid, pid, ppid);
+                let _ = writeln!(
+                    status,
+                    "Uid:\t{}\t{}\t{}\t{}",
+                    cred.uid, cred.euid, cred.suid, cred.euid
+                );
+                let _ = writeln!(
+                    status,
+                    "Gid:\t{}\t{}\t{}\t{}",
+                    cred.gid, cred.egid, cred.sgid, cred.egid
+                );
+                let _ = writeln!(status, "Groups:\t{}", groups.join(" "));
+                let _ = writeln!(status, "VmSize:\t{} kB", vm_pages * PAGE_SIZE / 1024);
+                let _ = writeln!(status, "VmRSS:\t{} kB", rss_pages * PAGE_SIZE / 1024);
+                let _ = writeln!(status, "Threads:\t{}", threads);
+                let _ = writeln!(status, "SigPnd:\t{:016x}", inner.signals.bits());
+                let _ = writeln!(status, "SigBlk:\t{:016x}", inner.signal_mask.bits());
+                status
+            }
+        }
+    }
+}
+
+/// clock ticks per second reported in `/proc/<pid>/stat`, `sysconf(_SC_CLK_TCK)`
+const USER_HZ: usize = 100;
+
+/// The state letter and name shown in `/proc/<pid>/stat` and `/proc/<pid>/status`
+fn state_of(inner: &TaskControlBlockInner) -> (char, &'static str) {
+    if inner.is_zombie {
+        return ('Z', "zombie");
+    }
+    match inner.task_status {
+        TaskStatus::Ready | TaskStatus::Running => ('R', "running"),
+        TaskStatus::Blocked => ('S', "sleeping"),
+        TaskStatus::Stopped => ('T', "stopped"),
+        TaskStatus::Zombie => ('Z', "zombie"),
+        TaskStatus::Exit => ('X', "dead"),
+    }
+}
+
+/// Pages of the address space user code can access, and how many of them have frames
+fn memory_pages(inner: &TaskControlBlockInner) -> (usize, usize) {
+    let memory_set = inner.memory_set.lock();
+    let (mut vm, mut rss) = (0, 0);
+    for area in memory_set.user_areas() {
+        vm += area.vpn_range.get_end().0 - area.vpn_range.get_start().0;
+        rss += area.data_frames.len();
+    }
+    // 堆和 mmap 区域的页在缺页时才分配，只统计已经映射的
+    let private = memory_set.private_pages(PrivateRegion::Heap).count()
+        + memory_set.private_pages(PrivateRegion::Mmap).count();
+    (vm + private, rss + private)
+}
+
+pub struct ProcFS;
+
+impl FileSystem for ProcFS {
+    fn fs_type(&self) -> FileSystemType {
+        FileSystemType::PROC
+    }
+    fn root_inode(self: Arc<Self>) -> Arc<dyn Inode> {
+        Arc::new(ProcInode::new(ProcNode::Root))
+    }
+}
+
+#[derive(Clone)]
+enum ProcNode {
+    Root,
+    Entry(&'static ProcEntry),
+    /// `/proc/<pid>`, the process is kept by global pid and looked up on each access
+    Process(usize),
+    ProcessAttr(usize, PidAttr),
+    /// `/proc/kmsg`, reads consume the kernel log like `SYSLOG_ACTION_READ`
+    Kmsg,
+}
+
+pub struct ProcInode {
+    node:  ProcNode,
+    inner: SpinNoIrqLock<ProcInodeInner>,
+}
+
+struct ProcInodeInner {
+    fpos: usize,
+}
+
+impl ProcInode {
+    fn new(node: ProcNode) -> Self {
+        Self {
+            node,
+            inner: SpinNoIrqLock::new(ProcInodeInner { fpos: 0 }),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.node, ProcNode::Root | ProcNode::Process(_))
+    }
+
+    /// Look up a single path component
+    fn lookup_one(&self, name: &str) -> Option<ProcNode> {
+        match &self.node {
+            ProcNode::Root => {
+                if let Some(entry) = PROC_ENTRIES.iter().find(|entry| entry.name == name) {
+                    return Some(ProcNode::Entry(entry));
+                }
+                if name == "kmsg" {
+                    return Some(ProcNode::Kmsg);
+                }
+                let task = current_task().unwrap();
+                let pid = if name == "self" {
+                    task.group_leader().pid.0
+                } else {
+                    task.pid_ns().global_pid(name.parse().ok()?)?
+                };
+                find_process(pid)?;
+                Some(ProcNode::Process(pid))
+            }
+            ProcNode::Process(pid) => Some(ProcNode::ProcessAttr(*pid, PidAttr::lookup(name)?)),
+            _ => None,
+        }
+    }
+
+    fn content(&self) -> Option<String> {
+        match &self.node {
+            ProcNode::Entry(entry) => Some((entry.show)()),
+            ProcNode::ProcessAttr(pid, attr) => Some(attr.show(&find_process(*pid)?)),
+            _ => None,
+        }
+    }
+
+    fn entry(&self) -> Option<&'static ProcEntry> {
+        match self.node {
+            ProcNode::Entry(entry) => Some(entry),
+            _ => None,
+        }
+    }
+}
+
+impl Inode for ProcInode {
+    fn fstype(&self) -> FileSystemType {
+        FileSystemType::PROC
+    }
+
+    fn lookup(self: Arc<Self>, name: &str) -> Option<Arc<Dentry>> {
+        let mut node = self.node.clone();
+        for component in name.split('/').filter(|s| !s.is_empty() && *s != ".") {
+            node = ProcInode::new(node).lookup_one(component)?;
+        }
+        let dentry = Dentry::new(name, Arc::new(ProcInode::new(node)));
+        Some(Arc::new(dentry))
+    }
+
+    fn create(self: Arc<Self>, _name: &str, _type: InodeType) -> Option<Arc<Dentry>> {
+        None
+    }
+
+    fn unlink(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn link(self: Arc<Self>, _name: &str, _target: Arc<Dentry>) -> bool {
+        false
+    }
+
+    fn rename(self: Arc<Self>, _old_name: &str, _new_name: &str) -> bool {
+        false
+    }
+
+    fn mkdir(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn rmdir(self: Arc<Self>, _name: &str) -> bool {
+        false
+    }
+
+    fn ls(&self) -> Vec<String> {
+        match self.node {
+            ProcNode::Root => {
+                let mut names: Vec<String> = PROC_ENTRIES
+                    .iter()
+                    .map(|entry| entry.name.to_string())
+                    .collect();
+                names.push("kmsg".to_string());
+                names.push("self".to_string());
+                names.extend(
+                    visible_processes()
+                        .into_iter()
+                        .map(|(_, nr)| nr.to_string()),
+                );
+                names
+            }
+            ProcNode::Process(_) => PidAttr::ALL
+                .iter()
+                .map(|attr| attr.name().to_string())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn clear(&self) {}
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        if let ProcNode::Kmsg = self.node {
+            // 日志没有偏移量可言，读过的内容不再出现
+            return read_kmsg(buf);
+        }
+        let content = match self.content() {
+            Some(content) => content,
+            None => return 0,
+        };
+        let content = content.as_bytes();
+        if offset >= content.len() {
+            return 0;
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        len
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> usize {
+        let store = match self.entry().and_then(|entry| entry.store) {
+            Some(store) => store,
+            None => return 0,
+        };
+        match core::str::from_utf8(buf) {
+            Ok(value) if store(value) => buf.len(),
+            _ => 0,
+        }
+    }
+
+    fn perm(&self) -> Option<InodePerm> {
+        let mode = match self.node {
+            ProcNode::Entry(entry) => entry.mode,
+            ProcNode::ProcessAttr(..) => 0o444,
+            // 和 Linux 一样只有 root 能读
+            ProcNode::Kmsg => 0o400,
+            _ => 0o555,
+        };
+        Some(InodePerm {
+            mode,
+            uid: 0,
+            gid: 0,
+        })
+    }
+}
+
+impl File for ProcInode {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        self.entry().map_or(false, |entry| entry.store.is_some())
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let mut inner = self.inner.lock();
+        let read_size = self.read_at(inner.fpos, buf);
+        inner.fpos += read_size;
+        read_size
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        self.content().map_or_else(Vec::new, String::into_bytes)
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        self.write_at(0, buf)
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        let st_mode = if self.is_dir() {
+            StatMode::DIR.bits()
+        } else {
+            StatMode::FILE.bits()
+        };
+        Some(Stat::new(0, 0, st_mode, 1, 0, 0, 0, 0, 0))
+    }
+
+    fn hang_up(&self) -> bool {
+        false
+    }
+
+    fn r_ready(&self) -> bool {
+        match self.node {
+            ProcNode::Kmsg => KMSG.lock().unread() > 0,
+            _ => true,
+        }
+    }
+}
@@ -1,11 +1,37 @@
 use riscv::register::sstatus;
 
-use super::{file::File, inode::Stat};
-use crate::{mm::UserBuffer, sbi::console_getchar, task::suspend_current_and_run_next};
+use super::{
+    file::File,
+    inode::Stat,
+    tty::{tty_ioctl, tty_read, tty_readable},
+};
+use crate::{mm::UserBuffer, sbi::console_getchar};
 
-/// stdin file for getting chars from console
+/// The next character typed on the console, `None` if there is none yet
+///
+/// 只给终端的行规程使用，其他地方从 [`super::tty`] 读入。
+pub fn console_try_getchar() -> Option<u8> {
+    match console_getchar() {
+        0 => None,
+        c => Some(c as u8),
+    }
+}
+
+/// stdin file for getting chars from console, through the line discipline of the terminal
 pub struct Stdin;
 
+impl Stdin {
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+impl Default for Stdin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// stdout file for putting chars to console
 pub struct Stdout;
 
@@ -17,28 +43,18 @@ impl File for Stdin {
         false
     }
     fn read(&self, user_buf: &mut [u8]) -> usize {
-        // assert_eq!(user_buf.len(), 1);
-        // busy loop
+        self.try_read(user_buf).unwrap_or(0)
+    }
+    /// EINTR if a signal came before any character
+    fn try_read(&self, user_buf: &mut [u8]) -> Result<usize, isize> {
         unsafe {
             sstatus::set_sum();
         }
-        let mut c: usize;
-        loop {
-            c = console_getchar();
-            if c == 0 {
-                debug!("stdin: no char, suspend and run next");
-                suspend_current_and_run_next();
-                continue;
-            } else {
-                break;
-            }
-        }
-        let ch = c as u8;
-        user_buf[0] = ch;
+        let result = tty_read(user_buf);
         unsafe {
             sstatus::clear_sum();
         }
-        1
+        result
     }
     fn read_all(&self) -> alloc::vec::Vec<u8> {
         panic!("Stdin::read_all not implemented");
@@ -50,7 +66,13 @@ impl File for Stdin {
         None
     }
     fn hang_up(&self) -> bool {
-        todo!()
+        false
+    }
+    fn r_ready(&self) -> bool {
+        tty_readable()
+    }
+    fn ioctl(&self, request: usize, arg: usize) -> isize {
+        tty_ioctl(request, arg)
     }
 }
 
@@ -79,6 +101,9 @@ impl File for Stdout {
         None
     }
     fn hang_up(&self) -> bool {
-        todo!()
+        false
+    }
+    fn ioctl(&self, request: usize, arg: usize) -> isize {
+        tty_ioctl(request, arg)
     }
 }
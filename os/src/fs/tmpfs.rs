@@ -0,0 +1,464 @@
+//! In-memory filesystem, mounted at `/tmp` and `/dev/shm`
+//!
+//! 目录树和文件内容都只存在内存里，卸载或关机后丢失。普通文件的内容按页存放在从页帧分配器
+//! 分配的物理页中，写入超出已有页时才分配新页，截断时释放。
+//! 每次 lookup 返回一个新的 [`TmpInode`]，各自维护文件偏移，共享同一个 [`TmpNode`]。
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{
+    dentry::Dentry,
+    file::File,
+    fs::{FileSystem, FileSystemType},
+    inode::{Inode, InodePerm, InodeType, Stat, StatMode},
+};
+use crate::{
+    config::PAGE_SIZE,
+    mm::{frame_alloc, FrameTracker},
+    sync::SpinNoIrqLock,
+    task::current_task,
+};
+
+pub struct TmpFS {
+    root:     Arc<TmpNode>,
+    next_ino: AtomicUsize,
+}
+
+impl TmpFS {
+    /// An empty filesystem, the root directory is world-writable and sticky like `/tmp`
+    pub fn new() -> Arc<Self> {
+        let root_perm = InodePerm {
+            mode: 0o1777,
+            uid:  0,
+            gid:  0,
+        };
+        Arc::new(Self {
+            root:     TmpNode::new(1, root_perm, TmpData::Dir(BTreeMap::new()), Weak::new()),
+            next_ino: AtomicUsize::new(2),
+        })
+    }
+
+    fn alloc_ino(&self) -> usize {
+        self.next_ino.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl FileSystem for TmpFS {
+    fn fs_type(&self) -> FileSystemType {
+        FileSystemType::TMPFS
+    }
+    fn root_inode(self: Arc<Self>) -> Arc<dyn Inode> {
+        let root = self.root.clone();
+        Arc::new(TmpInode::new(self, root))
+    }
+}
+
+enum TmpData {
+    /// contents in whole pages, bytes past `size` are zero
+    File {
+        pages: Vec<FrameTracker>,
+        size:  usize,
+    },
+    Dir(BTreeMap<String, Arc<TmpNode>>),
+}
+
+/// A file or directory in the tree
+struct TmpNode {
+    ino:   usize,
+    inner: SpinNoIrqLock<TmpNodeInner>,
+}
+
+struct TmpNodeInner {
+    perm:   InodePerm,
+    data:   TmpData,
+    /// the directory holding this node, dangling for the root
+    parent: Weak<TmpNode>,
+}
+
+impl TmpNode {
+    fn new(ino: usize, perm: InodePerm, data: TmpData, parent: Weak<TmpNode>) -> Arc<Self> {
+        Arc::new(Self {
+            ino,
+            inner: SpinNoIrqLock::new(TmpNodeInner { perm, data, parent }),
+        })
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.inner.lock().data, TmpData::Dir(_))
+    }
+
+    /// The entry `name` of this directory, `.` and `..` included
+    fn child(self: &Arc<Self>, name: &str) -> Option<Arc<TmpNode>> {
+        let inner = self.inner.lock();
+        let TmpData::Dir(children) = &inner.data else {
+            return None;
+        };
+        match name {
+            "." => Some(self.clone()),
+            ".." => Some(inner.parent.upgrade().unwrap_or_else(|| self.clone())),
+            _ => children.get(name).cloned(),
+        }
+    }
+
+    /// Follow a relative path, a leading `/` is relative to this node too
+    fn walk(self: &Arc<Self>, path: &str) -> Option<Arc<TmpNode>> {
+        let mut node = self.clone();
+        for component in path.split('/').filter(|s| !s.is_empty() && *s != ".") {
+            node = node.child(component)?;
+        }
+        Some(node)
+    }
+
+    /// The directory holding the last component of `path`, and that component
+    fn walk_parent<'a>(self: &Arc<Self>, path: &'a str) -> Option<(Arc<TmpNode>, &'a str)> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = match path.rsplit_once('/') {
+            Some((dir, name)) => (self.walk(dir)?, name),
+            None => (self.clone(), path),
+        };
+        if name.is_empty() || name == "." || name == ".." || !dir.is_dir() {
+            return None;
+        }
+        Some((dir, name))
+    }
+
+    /// Whether `self` is `ancestor` or lies below it
+    fn is_descendant_of(self: &Arc<Self>, ancestor: &Arc<TmpNode>) -> bool {
+        let mut node = Some(self.clone());
+        while let Some(current) = node {
+            if Arc::ptr_eq(&current, ancestor) {
+                return true;
+            }
+            node = current.inner.lock().parent.upgrade();
+        }
+        false
+    }
+
+    /// The node numbered `ino` in the subtree of this directory
+    fn find_ino(self: &Arc<Self>, ino: usize) -> Option<Arc<TmpNode>> {
+        if self.ino == ino {
+            return Some(self.clone());
+        }
+        match &self.inner.lock().data {
+            TmpData::Dir(children) => children.values().find_map(|child| child.find_ino(ino)),
+            TmpData::File { .. } => None,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match &self.inner.lock().data {
+            TmpData::File { size, .. } => *size,
+            TmpData::Dir(_) => 0,
+        }
+    }
+}
+
+pub struct TmpInode {
+    fs:    Arc<TmpFS>,
+    node:  Arc<TmpNode>,
+    inner: SpinNoIrqLock<TmpInodeInner>,
+}
+
+struct TmpInodeInner {
+    fpos: usize,
+}
+
+impl TmpInode {
+    fn new(fs: Arc<TmpFS>, node: Arc<TmpNode>) -> Self {
+        Self {
+            fs,
+            node,
+            inner: SpinNoIrqLock::new(TmpInodeInner { fpos: 0 }),
+        }
+    }
+
+    fn dentry(&self, name: &str, node: Arc<TmpNode>) -> Arc<Dentry> {
+        let inode = TmpInode::new(self.fs.clone(), node);
+        Arc::new(Dentry::new(name, Arc::new(inode)))
+    }
+
+    /// Add an empty file or directory at `path`, owned by the current task
+    fn add(&self, path: &str, dir: bool) -> Option<Arc<TmpNode>> {
+        let (parent, name) = self.node.walk_parent(path)?;
+        let perm = {
+            let task = current_task().unwrap();
+            let inner = task.inner_exclusive_access();
+            InodePerm {
+                mode: if dir { 0o755 } else { 0o644 },
+                uid:  inner.cred.euid,
+                gid:  inner.cred.egid,
+            }
+        };
+        let data = if dir {
+            TmpData::Dir(BTreeMap::new())
+        } else {
+            TmpData::File {
+                pages: Vec::new(),
+                size:  0,
+            }
+        };
+        let mut parent_inner = parent.inner.lock();
+        let TmpData::Dir(children) = &mut parent_inner.data else {
+            return None;
+        };
+        if children.contains_key(name) {
+            return None;
+        }
+        let node = TmpNode::new(self.fs.alloc_ino(), perm, data, Arc::downgrade(&parent));
+        children.insert(name.to_string(), node.clone());
+        Some(node)
+    }
+
+    /// Remove the entry at `path` if it is a directory exactly when `dir` is set,
+    /// directories only when empty
+    fn remove(&self, path: &str, dir: bool) -> bool {
+        let Some((parent, name)) = self.node.walk_parent(path) else {
+            return false;
+        };
+        let Some(node) = parent.child(name) else {
+            return false;
+        };
+        match &node.inner.lock().data {
+            TmpData::Dir(children) if dir && children.is_empty() => {}
+            TmpData::File { .. } if !dir => {}
+            _ => return false,
+        }
+        let mut parent_inner = parent.inner.lock();
+        match &mut parent_inner.data {
+            TmpData::Dir(children) => children.remove(name).is_some(),
+            TmpData::File { .. } => false,
+        }
+    }
+}
+
+impl Inode for TmpInode {
+    fn fstype(&self) -> FileSystemType {
+        FileSystemType::TMPFS
+    }
+
+    fn lookup(self: Arc<Self>, name: &str) -> Option<Arc<Dentry>> {
+        let node = self.node.walk(name)?;
+        Some(self.dentry(name, node))
+    }
+
+    fn create(self: Arc<Self>, name: &str, type_: InodeType) -> Option<Arc<Dentry>> {
+        let node = match type_ {
+            InodeType::Regular => self.add(name, false)?,
+            InodeType::Directory => self.add(name, true)?,
+            _ => return None,
+        };
+        Some(self.dentry(name, node))
+    }
+
+    fn unlink(self: Arc<Self>, name: &str) -> bool {
+        self.remove(name, false)
+    }
+
+    fn link(self: Arc<Self>, _name: &str, _target: Arc<Dentry>) -> bool {
+        false
+    }
+
+    fn rename(self: Arc<Self>, old_name: &str, new_name: &str) -> bool {
+        let (Some((old_dir, old)), Some((new_dir, new))) = (
+            self.node.walk_parent(old_name),
+            self.node.walk_parent(new_name),
+        ) else {
+            return false;
+        };
+        move_node(&old_dir, old, &new_dir, new)
+    }
+
+    fn rename_to(self: Arc<Self>, old_name: &str, new_dir: Arc<dyn Inode>, new_name: &str) -> bool {
+        let Some((fs, ino)) = new_dir.cache_key() else {
+            return false;
+        };
+        if fs != Arc::as_ptr(&self.fs) as usize {
+            return false;
+        }
+        let Some((old_dir, old)) = self.node.walk_parent(old_name) else {
+            return false;
+        };
+        let Some(new_dir) = self.fs.root.find_ino(ino) else {
+            return false;
+        };
+        move_node(&old_dir, old, &new_dir, new_name)
+    }
+
+    fn mkdir(self: Arc<Self>, name: &str) -> bool {
+        self.add(name, true).is_some()
+    }
+
+    fn rmdir(self: Arc<Self>, name: &str) -> bool {
+        self.remove(name, true)
+    }
+
+    fn ls(&self) -> Vec<String> {
+        match &self.node.inner.lock().data {
+            TmpData::Dir(children) => [".", ".."]
+                .into_iter()
+                .map(String::from)
+                .chain(children.keys().cloned())
+                .collect(),
+            TmpData::File { .. } => Vec::new(),
+        }
+    }
+
+    /// truncate the file to zero length, freeing its pages
+    fn clear(&self) {
+        if let TmpData::File { pages, size } = &mut self.node.inner.lock().data {
+            pages.clear();
+            *size = 0;
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let inner = self.node.inner.lock();
+        let TmpData::File { pages, size } = &inner.data else {
+            return 0;
+        };
+        let end = (offset + buf.len()).min(*size);
+        let mut pos = offset;
+        while pos < end {
+            let page_offset = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - page_offset).min(end - pos);
+            let page = pages[pos / PAGE_SIZE].ppn.get_bytes_array();
+            buf[pos - offset..pos - offset + len]
+                .copy_from_slice(&page[page_offset..page_offset + len]);
+            pos += len;
+        }
+        end.saturating_sub(offset)
+    }
+
+    /// 页帧耗尽时只写入已分配到页的部分
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let mut inner = self.node.inner.lock();
+        let TmpData::File { pages, size } = &mut inner.data else {
+            return 0;
+        };
+        let end = offset + buf.len();
+        while pages.len() * PAGE_SIZE < end {
+            match frame_alloc() {
+                Some(frame) => pages.push(frame),
+                None => break,
+            }
+        }
+        let end = end.min(pages.len() * PAGE_SIZE);
+        let mut pos = offset;
+        while pos < end {
+            let page_offset = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - page_offset).min(end - pos);
+            let page = pages[pos / PAGE_SIZE].ppn.get_bytes_array();
+            page[page_offset..page_offset + len]
+                .copy_from_slice(&buf[pos - offset..pos - offset + len]);
+            pos += len;
+        }
+        *size = (*size).max(end);
+        end.saturating_sub(offset)
+    }
+
+    fn perm(&self) -> Option<InodePerm> {
+        Some(self.node.inner.lock().perm)
+    }
+
+    fn cache_key(&self) -> Option<(usize, usize)> {
+        Some((Arc::as_ptr(&self.fs) as usize, self.node.ino))
+    }
+
+    fn size(&self) -> usize {
+        self.node.size()
+    }
+}
+
+impl File for TmpInode {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        !self.node.is_dir()
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let mut inner = self.inner.lock();
+        let read_size = self.read_at(inner.fpos, buf);
+        inner.fpos += read_size;
+        read_size
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; self.node.size()];
+        let len = self.read_at(0, &mut buf);
+        buf.truncate(len);
+        buf
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        let mut inner = self.inner.lock();
+        let write_size = self.write_at(inner.fpos, buf);
+        inner.fpos += write_size;
+        write_size
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        let st_mode = if self.node.is_dir() {
+            StatMode::DIR.bits()
+        } else {
+            StatMode::FILE.bits()
+        };
+        Some(Stat::new(
+            0,
+            self.node.ino as u64,
+            st_mode,
+            1,
+            0,
+            self.node.size() as i64,
+            0,
+            0,
+            0,
+        ))
+    }
+
+    fn hang_up(&self) -> bool {
+        false
+    }
+}
+
+/// Move the entry `old` of `old_dir` to `new` in `new_dir`
+///
+/// 目标已存在时替换它：文件只能替换文件，目录只能替换空目录。
+fn move_node(old_dir: &Arc<TmpNode>, old: &str, new_dir: &Arc<TmpNode>, new: &str) -> bool {
+    let Some(node) = old_dir.child(old) else {
+        return false;
+    };
+    // 目录不能移到自己的子树里
+    if node.is_dir() && new_dir.is_descendant_of(&node) {
+        return false;
+    }
+    if let Some(replaced) = new_dir.child(new) {
+        if Arc::ptr_eq(&replaced, &node) {
+            return true;
+        }
+        let replaceable = match &replaced.inner.lock().data {
+            TmpData::Dir(children) => node.is_dir() && children.is_empty(),
+            TmpData::File { .. } => !node.is_dir(),
+        };
+        if !replaceable {
+            return false;
+        }
+    }
+    if let TmpData::Dir(children) = &mut old_dir.inner.lock().data {
+        children.remove(old);
+    }
+    if let TmpData::Dir(children) = &mut new_dir.inner.lock().data {
+        children.insert(new.to_string(), node.clone());
+    }
+    node.inner.lock().parent = Arc::downgrade(new_dir);
+    true
+}
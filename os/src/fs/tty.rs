@@ -0,0 +1,559 @@
+//! The console terminal: termios, window size, foreground process group and line discipline
+//!
+//! 只有一个终端，标准输入输出和 `/dev/tty` 共用这里的状态，ioctl 都交给 [`tty_ioctl`]。
+//!
+//! 控制台输入经过行规程再给读者：规范模式（ICANON）下按行编辑，VERASE/VWERASE/VKILL 删除，
+//! 换行或 VEOF 后整行才能读到；非规范模式下每个字符都能立即读到。ISIG 打开时 VINTR、VQUIT、
+//! VSUSP 给前台进程组发 SIGINT、SIGQUIT、SIGTSTP。ECHO 控制回显。后台进程组读终端不会收到 SIGTTIN。
+
+use alloc::{collections::VecDeque, string::String, vec, vec::Vec};
+use core::mem;
+
+use bitflags::bitflags;
+use lazy_static::*;
+use riscv::register::sstatus;
+
+use super::stdio::console_try_getchar;
+use crate::{
+    mm::user_range_ok,
+    sync::SpinNoIrqLock,
+    syscall::errno::{EFAULT, EINTR, EINVAL, ENOTTY, EPERM, ESRCH, SUCCESS},
+    task::{
+        current_task,
+        current_user_token,
+        processes_in_group,
+        send_signal,
+        signal_pending,
+        suspend_current_and_run_next,
+        SignalFlags,
+        INITPROC,
+    },
+};
+
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TCSETSW: usize = 0x5403;
+pub const TCSETSF: usize = 0x5404;
+pub const TIOCGPGRP: usize = 0x540f;
+pub const TIOCSPGRP: usize = 0x5410;
+pub const TIOCGWINSZ: usize = 0x5413;
+pub const TIOCSWINSZ: usize = 0x5414;
+
+/// number of control characters in `struct termios`
+const NCCS: usize = 19;
+/// most characters the edited line and the unread input hold, as N_TTY_BUF_SIZE
+const TTY_BUF_SIZE: usize = 4096;
+
+/// indexes of the control characters in `c_cc`
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSTART: usize = 8;
+pub const VSTOP: usize = 9;
+pub const VSUSP: usize = 10;
+pub const VEOL: usize = 11;
+pub const VREPRINT: usize = 12;
+pub const VDISCARD: usize = 13;
+pub const VWERASE: usize = 14;
+pub const VLNEXT: usize = 15;
+pub const VEOL2: usize = 16;
+
+bitflags! {
+    /// `c_iflag`
+    pub struct InputFlags: u32 {
+        const IGNBRK = 0o1;
+        const BRKINT = 0o2;
+        const IGNPAR = 0o4;
+        const INLCR = 0o100;
+        const IGNCR = 0o200;
+        const ICRNL = 0o400;
+        const IXON = 0o2000;
+        const IXOFF = 0o10000;
+        const IUTF8 = 0o40000;
+    }
+
+    /// `c_oflag`
+    pub struct OutputFlags: u32 {
+        const OPOST = 0o1;
+        const ONLCR = 0o4;
+    }
+
+    /// `c_lflag`
+    pub struct LocalFlags: u32 {
+        const ISIG = 0o1;
+        const ICANON = 0o2;
+        const ECHO = 0o10;
+        const ECHOE = 0o20;
+        const ECHOK = 0o40;
+        const ECHONL = 0o100;
+        const NOFLSH = 0o200;
+        const TOSTOP = 0o400;
+        const ECHOCTL = 0o1000;
+        const ECHOKE = 0o4000;
+        const IEXTEN = 0o100000;
+    }
+}
+
+/// B38400 | CS8 | CREAD | HUPCL, the control modes are kept but mean nothing to the console
+const DEFAULT_CFLAG: u32 = 0o17 | 0o60 | 0o200 | 0o2000;
+
+/// `struct termios` of the kernel, taken by TCGETS and TCSETS
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line:  u8,
+    pub c_cc:    [u8; NCCS],
+}
+
+impl Default for Termios {
+    /// `tty_std_termios` of Linux: canonical mode with echo and signals
+    fn default() -> Self {
+        let mut c_cc = [0; NCCS];
+        c_cc[VINTR] = 0x03; // ^C
+        c_cc[VQUIT] = 0x1c; // ^\
+        c_cc[VERASE] = 0x7f; // DEL
+        c_cc[VKILL] = 0x15; // ^U
+        c_cc[VEOF] = 0x04; // ^D
+        c_cc[VMIN] = 1;
+        c_cc[VSTART] = 0x11; // ^Q
+        c_cc[VSTOP] = 0x13; // ^S
+        c_cc[VSUSP] = 0x1a; // ^Z
+        c_cc[VREPRINT] = 0x12; // ^R
+        c_cc[VDISCARD] = 0x0f; // ^O
+        c_cc[VWERASE] = 0x17; // ^W
+        c_cc[VLNEXT] = 0x16; // ^V
+        Self {
+            c_iflag: (InputFlags::ICRNL | InputFlags::IXON).bits(),
+            c_oflag: (OutputFlags::OPOST | OutputFlags::ONLCR).bits(),
+            c_cflag: DEFAULT_CFLAG,
+            c_lflag: (LocalFlags::ISIG
+                | LocalFlags::ICANON
+                | LocalFlags::ECHO
+                | LocalFlags::ECHOE
+                | LocalFlags::ECHOK
+                | LocalFlags::ECHOCTL
+                | LocalFlags::ECHOKE
+                | LocalFlags::IEXTEN)
+                .bits(),
+            c_line: 0,
+            c_cc,
+        }
+    }
+}
+
+/// `struct winsize`, taken by TIOCGWINSZ and TIOCSWINSZ
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct WinSize {
+    pub ws_row:    u16,
+    pub ws_col:    u16,
+    pub ws_xpixel: u16,
+    pub ws_ypixel: u16,
+}
+
+impl Default for WinSize {
+    fn default() -> Self {
+        Self {
+            ws_row:    24,
+            ws_col:    80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+/// State of the console terminal
+pub struct Tty {
+    termios: Termios,
+    winsize: WinSize,
+    /// foreground process group by global pid, the group of init until a shell takes it
+    fg_pgrp: usize,
+    /// input that can be read: whole lines in canonical mode, anything typed otherwise;
+    /// an empty line is an end of file typed with VEOF
+    ready:   VecDeque<Vec<u8>>,
+    /// the line being edited in canonical mode
+    line:    Vec<u8>,
+    /// characters to echo, printed after the lock is released
+    echo:    Vec<u8>,
+}
+
+lazy_static! {
+    static ref TTY: SpinNoIrqLock<Tty> = SpinNoIrqLock::new(Tty {
+        termios: Termios::default(),
+        winsize: WinSize::default(),
+        fg_pgrp: INITPROC.inner_exclusive_access().pgid,
+        ready:   VecDeque::new(),
+        line:    Vec::new(),
+        echo:    Vec::new(),
+    });
+}
+
+impl Tty {
+    fn iflag(&self) -> InputFlags {
+        InputFlags::from_bits_truncate(self.termios.c_iflag)
+    }
+
+    fn lflag(&self) -> LocalFlags {
+        LocalFlags::from_bits_truncate(self.termios.c_lflag)
+    }
+
+    /// Whether `c` is the control character `index`, a 0 in `c_cc` disables it
+    fn is_cc(&self, c: u8, index: usize) -> bool {
+        self.termios.c_cc[index] != 0 && c == self.termios.c_cc[index]
+    }
+
+    fn canonical(&self) -> bool {
+        self.lflag().contains(LocalFlags::ICANON)
+    }
+
+    fn set_termios(&mut self, termios: Termios) {
+        self.termios = termios;
+        // 离开规范模式时正在编辑的行立即可读
+        if !self.canonical() && !self.line.is_empty() {
+            let line = mem::take(&mut self.line);
+            self.ready.push_back(line);
+        }
+    }
+
+    fn flush_input(&mut self) {
+        self.ready.clear();
+        self.line.clear();
+    }
+
+    fn unread(&self) -> usize {
+        self.ready.iter().map(Vec::len).sum::<usize>() + self.line.len()
+    }
+
+    /// Take a character typed on the console, returning the signal it generates
+    fn receive(&mut self, mut c: u8) -> Option<SignalFlags> {
+        let iflag = self.iflag();
+        let lflag = self.lflag();
+        if c == b'\r' {
+            if iflag.contains(InputFlags::IGNCR) {
+                return None;
+            }
+            if iflag.contains(InputFlags::ICRNL) {
+                c = b'\n';
+            }
+        } else if c == b'\n' && iflag.contains(InputFlags::INLCR) {
+            c = b'\r';
+        }
+        if lflag.contains(LocalFlags::ISIG) {
+            let signal = if self.is_cc(c, VINTR) {
+                Some(SignalFlags::SIGINT)
+            } else if self.is_cc(c, VQUIT) {
+                Some(SignalFlags::SIGQUIT)
+            } else if self.is_cc(c, VSUSP) {
+                Some(SignalFlags::SIGTSTP)
+            } else {
+                None
+            };
+            if signal.is_some() {
+                if !lflag.contains(LocalFlags::NOFLSH) {
+                    self.flush_input();
+                }
+                self.echo_char(c);
+                return signal;
+            }
+        }
+        if !lflag.contains(LocalFlags::ICANON) {
+            if self.unread() < TTY_BUF_SIZE {
+                match self.ready.back_mut() {
+                    Some(input) if !input.is_empty() => input.push(c),
+                    _ => self.ready.push_back(vec![c]),
+                }
+                self.echo_char(c);
+            }
+            return None;
+        }
+        if self.is_cc(c, VERASE) {
+            self.erase_char();
+        } else if lflag.contains(LocalFlags::IEXTEN) && self.is_cc(c, VWERASE) {
+            while self.line.last().map_or(false, u8::is_ascii_whitespace) {
+                self.erase_char();
+            }
+            while self.line.last().map_or(false, |c| !c.is_ascii_whitespace()) {
+                self.erase_char();
+            }
+        } else if self.is_cc(c, VKILL) {
+            if lflag.contains(LocalFlags::ECHOKE) {
+                while !self.line.is_empty() {
+                    self.erase_char();
+                }
+            } else {
+                self.line.clear();
+                if lflag.contains(LocalFlags::ECHO) && lflag.contains(LocalFlags::ECHOK) {
+                    self.echo.push(b'\n');
+                }
+            }
+        } else if self.is_cc(c, VEOF) {
+            // 行首的 VEOF 产生一个空行，读到 0
+            let line = mem::take(&mut self.line);
+            self.ready.push_back(line);
+        } else if c == b'\n' || self.is_cc(c, VEOL) || self.is_cc(c, VEOL2) {
+            self.line.push(c);
+            if lflag.contains(LocalFlags::ECHONL) && c == b'\n' {
+                self.echo.push(c);
+            } else {
+                self.echo_char(c);
+            }
+            let line = mem::take(&mut self.line);
+            self.ready.push_back(line);
+        } else if self.unread() < TTY_BUF_SIZE - 1 {
+            // 留一个位置给换行
+            self.line.push(c);
+            self.echo_char(c);
+        }
+        None
+    }
+
+    /// Remove the last character of the line being edited, and from the screen with ECHOE
+    fn erase_char(&mut self) {
+        // UTF-8 的多字节字符整个删除
+        let Some(mut c) = self.line.pop() else {
+            return;
+        };
+        while c & 0xc0 == 0x80 {
+            match self.line.pop() {
+                Some(lead) => c = lead,
+                None => break,
+            }
+        }
+        let lflag = self.lflag();
+        if lflag.contains(LocalFlags::ECHO) && lflag.contains(LocalFlags::ECHOE) {
+            // ECHOCTL 把控制字符回显成两个字符
+            let width = if lflag.contains(LocalFlags::ECHOCTL) && is_control(c) {
+                2
+            } else {
+                1
+            };
+            for _ in 0..width {
+                self.echo.extend_from_slice(b"\x08 \x08");
+            }
+        }
+    }
+
+    /// Echo `c` if ECHO is set, a control character as `^X` with ECHOCTL
+    fn echo_char(&mut self, c: u8) {
+        let lflag = self.lflag();
+        if !lflag.contains(LocalFlags::ECHO) {
+            return;
+        }
+        if lflag.contains(LocalFlags::ECHOCTL) && is_control(c) {
+            self.echo.extend_from_slice(&[b'^', c ^ 0x40]);
+        } else {
+            self.echo.push(c);
+        }
+    }
+
+    /// Move unread input into `buf`, `None` if a read has to wait
+    ///
+    /// 规范模式下一次最多读一行，读不完的部分留给下次；非规范模式下 VMIN 为 0 时不等待，
+    /// VTIME 被忽略。
+    fn take_input(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let canonical = self.canonical();
+        let mut len = 0;
+        while len < buf.len() {
+            let Some(input) = self.ready.front_mut() else {
+                break;
+            };
+            if input.is_empty() {
+                // 文件末尾单独读出
+                if len == 0 {
+                    self.ready.pop_front();
+                    return Some(0);
+                }
+                break;
+            }
+            let count = input.len().min(buf.len() - len);
+            buf[len..len + count].copy_from_slice(&input[..count]);
+            input.drain(..count);
+            len += count;
+            if input.is_empty() {
+                self.ready.pop_front();
+                if canonical {
+                    break;
+                }
+            }
+        }
+        if len == 0 && (canonical || self.termios.c_cc[VMIN] != 0) {
+            return None;
+        }
+        Some(len)
+    }
+}
+
+/// Characters echoed as `^X` with ECHOCTL, except newline and tab
+fn is_control(c: u8) -> bool {
+    (c < 0x20 && c != b'\n' && c != b'\t') || c == 0x7f
+}
+
+/// Pass the characters typed on the console through the line discipline, echo them and send
+/// the signals they generate to the foreground process group
+///
+/// 控制台没有输入中断，在时钟中断和读终端时轮询，前台进程不读终端时 Ctrl-C 也能送达。
+pub fn poll_input() {
+    let mut signals = SignalFlags::empty();
+    let (echo, fg_pgrp) = {
+        let mut tty = TTY.lock();
+        while let Some(c) = console_try_getchar() {
+            if let Some(signal) = tty.receive(c) {
+                signals |= signal;
+            }
+        }
+        (mem::take(&mut tty.echo), tty.fg_pgrp)
+    };
+    if !echo.is_empty() {
+        print!("{}", String::from_utf8_lossy(&echo));
+    }
+    if !signals.is_empty() {
+        for process in processes_in_group(fg_pgrp) {
+            send_signal(&process, signals);
+        }
+    }
+}
+
+/// Read from the terminal, waiting until there is input, EINTR if a signal comes first
+pub fn tty_read(buf: &mut [u8]) -> Result<usize, isize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    loop {
+        poll_input();
+        if let Some(len) = TTY.lock().take_input(buf) {
+            return Ok(len);
+        }
+        if signal_pending() {
+            return Err(EINTR);
+        }
+        suspend_current_and_run_next();
+    }
+}
+
+/// Whether a read of the terminal returns at once
+pub fn tty_readable() -> bool {
+    poll_input();
+    let tty = TTY.lock();
+    !tty.ready.is_empty() || (!tty.canonical() && tty.termios.c_cc[VMIN] == 0)
+}
+
+/// ioctl on the console, for stdin, stdout and `/dev/tty`
+pub fn tty_ioctl(request: usize, arg: usize) -> isize {
+    match request {
+        TCGETS => {
+            let termios = TTY.lock().termios;
+            write_user(arg as *mut Termios, termios)
+        }
+        // 输出同步写到控制台，TCSETSW 不用等待；TCSETSF 还要丢弃没有读走的输入
+        TCSETS | TCSETSW | TCSETSF => {
+            let Some(termios) = read_user(arg as *const Termios) else {
+                return EFAULT;
+            };
+            let mut tty = TTY.lock();
+            if request == TCSETSF {
+                while console_try_getchar().is_some() {}
+                tty.flush_input();
+            }
+            tty.set_termios(termios);
+            SUCCESS
+        }
+        TIOCGWINSZ => {
+            let winsize = TTY.lock().winsize;
+            write_user(arg as *mut WinSize, winsize)
+        }
+        TIOCSWINSZ => {
+            let Some(winsize) = read_user(arg as *const WinSize) else {
+                return EFAULT;
+            };
+            TTY.lock().winsize = winsize;
+            SUCCESS
+        }
+        TIOCGPGRP => {
+            let fg_pgrp = TTY.lock().fg_pgrp;
+            // 前台进程组在调用者的 pid 命名空间之外时返回 0
+            let pgrp = current_task()
+                .unwrap()
+                .pid_ns()
+                .pid_of(fg_pgrp)
+                .unwrap_or(0);
+            write_user(arg as *mut i32, pgrp as i32)
+        }
+        TIOCSPGRP => {
+            let Some(pgrp) = read_user(arg as *const i32) else {
+                return EFAULT;
+            };
+            set_foreground(pgrp)
+        }
+        _ => ENOTTY,
+    }
+}
+
+/// Make `pgrp` of the caller's pid namespace the foreground process group
+///
+/// 进程组必须存在，并且和调用者在同一个会话中。
+fn set_foreground(pgrp: i32) -> isize {
+    if pgrp < 0 {
+        return EINVAL;
+    }
+    let task = current_task().unwrap();
+    let Some(pgid) = task.pid_ns().global_pid(pgrp as usize) else {
+        return ESRCH;
+    };
+    let members = processes_in_group(pgid);
+    if members.is_empty() {
+        return ESRCH;
+    }
+    let sid = task.group_leader().inner_exclusive_access().sid;
+    if !members
+        .iter()
+        .any(|member| member.inner_exclusive_access().sid == sid)
+    {
+        return EPERM;
+    }
+    TTY.lock().fg_pgrp = pgid;
+    SUCCESS
+}
+
+/// Whether the `T` at `ptr` is mapped for the caller, and writable if `write`
+fn user_ptr_ok<T>(ptr: *const T, write: bool) -> bool {
+    user_range_ok(
+        current_user_token(),
+        ptr as usize,
+        mem::size_of::<T>(),
+        write,
+    )
+}
+
+/// Copy a `T` from the caller's `ptr`, `None` if it is not mapped
+fn read_user<T: Copy>(ptr: *const T) -> Option<T> {
+    if !user_ptr_ok(ptr, false) {
+        return None;
+    }
+    unsafe {
+        sstatus::set_sum();
+        let value = ptr.read_unaligned();
+        sstatus::clear_sum();
+        Some(value)
+    }
+}
+
+/// Copy `value` to the caller's `ptr`, EFAULT if it is not mapped writable
+fn write_user<T>(ptr: *mut T, value: T) -> isize {
+    if !user_ptr_ok(ptr, true) {
+        return EFAULT;
+    }
+    unsafe {
+        sstatus::set_sum();
+        ptr.write_unaligned(value);
+        sstatus::clear_sum();
+    }
+    SUCCESS
+}
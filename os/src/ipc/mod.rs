@@ -0,0 +1,72 @@
+//! System V IPC
+//!
+//! 目前实现了消息队列（[`msg`]）。对象用 key 查找、用 id 访问，
+//! 访问权限和文件一样按有效 uid/gid 检查，只有所有者、创建者或 root 能修改和删除对象。
+
+pub mod msg;
+
+use crate::{
+    fs::inode::InodePerm,
+    task::cred::{Credentials, MAY_READ, MAY_WRITE},
+};
+
+/// key that always creates a new object, which cannot be looked up by key
+pub const IPC_PRIVATE: i32 = 0;
+/// create the object if the key does not exist
+pub const IPC_CREAT: usize = 0o1000;
+/// with IPC_CREAT, fail if the key exists
+pub const IPC_EXCL: usize = 0o2000;
+/// return EAGAIN/ENOMSG instead of blocking
+pub const IPC_NOWAIT: usize = 0o4000;
+
+/// ctl commands
+pub const IPC_RMID: usize = 0;
+pub const IPC_SET: usize = 1;
+pub const IPC_STAT: usize = 2;
+/// set by libc to ask for the 64-bit structures, which are the only ones here
+pub const IPC_64: usize = 0x100;
+
+/// Ownership and permissions of an IPC object, `struct ipc64_perm`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct IpcPerm {
+    pub key:  i32,
+    pub uid:  u32,
+    pub gid:  u32,
+    pub cuid: u32,
+    pub cgid: u32,
+    /// only the low 9 bits are used
+    pub mode: u32,
+    pub seq:  u16,
+    _pad:     u16,
+    _unused:  [usize; 2],
+}
+
+impl IpcPerm {
+    pub fn new(key: i32, mode: u32, cred: &Credentials) -> Self {
+        Self {
+            key,
+            uid: cred.euid,
+            gid: cred.egid,
+            cuid: cred.euid,
+            cgid: cred.egid,
+            mode: mode & 0o777,
+            ..Default::default()
+        }
+    }
+
+    /// Whether `cred` may read (`write` false) or write the object
+    pub fn permits(&self, cred: &Credentials, write: bool) -> bool {
+        let perm = InodePerm {
+            mode: (self.mode & 0o777) as u16,
+            uid:  self.uid,
+            gid:  self.gid,
+        };
+        cred.permits(Some(perm), if write { MAY_WRITE } else { MAY_READ })
+    }
+
+    /// Whether `cred` may change or remove the object
+    pub fn is_owner(&self, cred: &Credentials) -> bool {
+        cred.is_root() || cred.euid == self.uid || cred.euid == self.cuid
+    }
+}
@@ -0,0 +1,290 @@
+//! System V message queues
+//!
+//! 每个队列中消息的总字节数不超过 `msg_qbytes`（默认 [`MSGMNB`]），单条消息不超过 [`MSGMAX`]。
+//! 队列满时的发送者、没有合适消息时的接收者挂在队列的等待队列上阻塞（除非 IPC_NOWAIT），
+//! 队列有变化时唤醒所有等待者重新检查；队列被删除时等待者返回 EIDRM。
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use super::{IpcPerm, IPC_CREAT, IPC_EXCL, IPC_NOWAIT, IPC_PRIVATE};
+use crate::{
+    syscall::errno::{E2BIG, EACCES, EAGAIN, EEXIST, EIDRM, EINVAL, ENOENT, ENOMSG, ENOSPC, EPERM},
+    task::{
+        block_current_and_run_next,
+        cred::Credentials,
+        current_task,
+        wakeup_task,
+        TaskControlBlock,
+    },
+    timer::get_time_ms,
+};
+
+/// largest message
+pub const MSGMAX: usize = 8192;
+/// default limit of the bytes in a queue, only root may raise it
+pub const MSGMNB: usize = 16384;
+/// most queues in the system
+pub const MSGMNI: usize = 32000;
+
+/// msgrcv: truncate messages longer than the buffer instead of failing with E2BIG
+pub const MSG_NOERROR: usize = 0o10000;
+/// msgrcv: with a positive type, take the first message of any other type
+pub const MSG_EXCEPT: usize = 0o20000;
+
+/// `struct msqid64_ds`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct MsqidDs {
+    pub msg_perm:   IpcPerm,
+    /// time of the last msgsnd, msgrcv and change, in seconds
+    pub msg_stime:  i64,
+    pub msg_rtime:  i64,
+    pub msg_ctime:  i64,
+    /// bytes in the queue
+    pub msg_cbytes: usize,
+    /// messages in the queue
+    pub msg_qnum:   usize,
+    /// most bytes allowed in the queue
+    pub msg_qbytes: usize,
+    /// pid of the last msgsnd and msgrcv
+    pub msg_lspid:  i32,
+    pub msg_lrpid:  i32,
+    _unused:        [usize; 2],
+}
+
+pub struct Message {
+    pub mtype: isize,
+    pub text:  Vec<u8>,
+}
+
+pub struct MsgQueue {
+    inner: Mutex<MsgQueueInner>,
+}
+
+struct MsgQueueInner {
+    ds:        MsqidDs,
+    messages:  VecDeque<Message>,
+    /// tasks waiting for room in the queue
+    senders:   VecDeque<Arc<TaskControlBlock>>,
+    /// tasks waiting for a message
+    receivers: VecDeque<Arc<TaskControlBlock>>,
+    removed:   bool,
+}
+
+fn now() -> i64 {
+    (get_time_ms() / 1000) as i64
+}
+
+fn wake_all(waiters: &mut VecDeque<Arc<TaskControlBlock>>) {
+    for task in waiters.drain(..) {
+        wakeup_task(task);
+    }
+}
+
+impl MsgQueueInner {
+    /// Index of the message msgrcv takes for `msgtyp`
+    fn find(&self, msgtyp: isize, except: bool) -> Option<usize> {
+        let mut messages = self.messages.iter().enumerate();
+        let found = if msgtyp == 0 {
+            messages.next()
+        } else if msgtyp > 0 {
+            messages.find(|(_, msg)| (msg.mtype == msgtyp) != except)
+        } else {
+            // 类型不大于 |msgtyp| 的消息中类型最小的第一条
+            messages
+                .filter(|(_, msg)| msg.mtype <= -msgtyp)
+                .min_by_key(|(_, msg)| msg.mtype)
+        };
+        found.map(|(idx, _)| idx)
+    }
+}
+
+impl MsgQueue {
+    fn new(perm: IpcPerm) -> Self {
+        Self {
+            inner: Mutex::new(MsgQueueInner {
+                ds:        MsqidDs {
+                    msg_perm: perm,
+                    msg_ctime: now(),
+                    msg_qbytes: MSGMNB,
+                    ..Default::default()
+                },
+                messages:  VecDeque::new(),
+                senders:   VecDeque::new(),
+                receivers: VecDeque::new(),
+                removed:   false,
+            }),
+        }
+    }
+
+    /// msgsnd: append a message, blocking while the queue is full
+    pub fn send(&self, cred: &Credentials, msg: Message, flags: usize) -> Result<(), isize> {
+        if msg.mtype <= 0 || msg.text.len() > MSGMAX {
+            return Err(EINVAL);
+        }
+        let task = current_task().unwrap();
+        loop {
+            let mut inner = self.inner.lock();
+            if inner.removed {
+                return Err(EIDRM);
+            }
+            if !inner.ds.msg_perm.permits(cred, true) {
+                return Err(EACCES);
+            }
+            if inner.ds.msg_cbytes + msg.text.len() <= inner.ds.msg_qbytes {
+                inner.ds.msg_cbytes += msg.text.len();
+                inner.ds.msg_qnum += 1;
+                inner.ds.msg_lspid = task.pid.0 as i32;
+                inner.ds.msg_stime = now();
+                inner.messages.push_back(msg);
+                wake_all(&mut inner.receivers);
+                return Ok(());
+            }
+            if flags & IPC_NOWAIT != 0 {
+                return Err(EAGAIN);
+            }
+            inner.senders.push_back(task.clone());
+            drop(inner);
+            block_current_and_run_next();
+        }
+    }
+
+    /// msgrcv: take a message of `msgtyp` with at most `max_len` bytes of text,
+    /// blocking until there is one
+    pub fn receive(
+        &self, cred: &Credentials, max_len: usize, msgtyp: isize, flags: usize,
+    ) -> Result<Message, isize> {
+        let task = current_task().unwrap();
+        loop {
+            let mut inner = self.inner.lock();
+            if inner.removed {
+                return Err(EIDRM);
+            }
+            if !inner.ds.msg_perm.permits(cred, false) {
+                return Err(EACCES);
+            }
+            if let Some(idx) = inner.find(msgtyp, flags & MSG_EXCEPT != 0) {
+                // 太长又没有 MSG_NOERROR 时消息留在队列中
+                if inner.messages[idx].text.len() > max_len && flags & MSG_NOERROR == 0 {
+                    return Err(E2BIG);
+                }
+                let mut msg = inner.messages.remove(idx).unwrap();
+                inner.ds.msg_cbytes -= msg.text.len();
+                inner.ds.msg_qnum -= 1;
+                inner.ds.msg_lrpid = task.pid.0 as i32;
+                inner.ds.msg_rtime = now();
+                msg.text.truncate(max_len);
+                wake_all(&mut inner.senders);
+                return Ok(msg);
+            }
+            if flags & IPC_NOWAIT != 0 {
+                return Err(ENOMSG);
+            }
+            inner.receivers.push_back(task.clone());
+            drop(inner);
+            block_current_and_run_next();
+        }
+    }
+
+    /// msgctl(IPC_STAT)
+    pub fn stat(&self, cred: &Credentials) -> Result<MsqidDs, isize> {
+        let inner = self.inner.lock();
+        if !inner.ds.msg_perm.permits(cred, false) {
+            return Err(EACCES);
+        }
+        Ok(inner.ds)
+    }
+
+    /// msgctl(IPC_SET): change the owner, mode and size limit
+    pub fn set(&self, cred: &Credentials, ds: &MsqidDs) -> Result<(), isize> {
+        let mut inner = self.inner.lock();
+        if !inner.ds.msg_perm.is_owner(cred) {
+            return Err(EPERM);
+        }
+        if ds.msg_qbytes > inner.ds.msg_qbytes && ds.msg_qbytes > MSGMNB && !cred.is_root() {
+            return Err(EPERM);
+        }
+        inner.ds.msg_perm.uid = ds.msg_perm.uid;
+        inner.ds.msg_perm.gid = ds.msg_perm.gid;
+        inner.ds.msg_perm.mode = ds.msg_perm.mode & 0o777;
+        inner.ds.msg_qbytes = ds.msg_qbytes;
+        inner.ds.msg_ctime = now();
+        // 上限可能变大了
+        wake_all(&mut inner.senders);
+        Ok(())
+    }
+}
+
+struct MsgTable {
+    queues:  BTreeMap<usize, Arc<MsgQueue>>,
+    next_id: usize,
+}
+
+static MSG_TABLE: Mutex<MsgTable> = Mutex::new(MsgTable {
+    queues:  BTreeMap::new(),
+    next_id: 0,
+});
+
+/// msgget: id of the queue of `key`, creating it as asked by `flags`
+pub fn msgget(key: i32, flags: usize, cred: &Credentials) -> Result<usize, isize> {
+    let mut table = MSG_TABLE.lock();
+    if key != IPC_PRIVATE {
+        let found = table
+            .queues
+            .iter()
+            .find(|(_, queue)| queue.inner.lock().ds.msg_perm.key == key);
+        if let Some((&id, queue)) = found {
+            if flags & IPC_CREAT != 0 && flags & IPC_EXCL != 0 {
+                return Err(EEXIST);
+            }
+            // 请求的权限位必须是已有权限的子集
+            let requested = (flags & 0o777) as u32;
+            let perm = queue.inner.lock().ds.msg_perm;
+            if requested & 0o444 != 0 && !perm.permits(cred, false)
+                || requested & 0o222 != 0 && !perm.permits(cred, true)
+            {
+                return Err(EACCES);
+            }
+            return Ok(id);
+        }
+        if flags & IPC_CREAT == 0 {
+            return Err(ENOENT);
+        }
+    }
+    if table.queues.len() >= MSGMNI {
+        return Err(ENOSPC);
+    }
+    let id = table.next_id;
+    table.next_id += 1;
+    let perm = IpcPerm::new(key, flags as u32, cred);
+    table.queues.insert(id, Arc::new(MsgQueue::new(perm)));
+    Ok(id)
+}
+
+/// The queue with `id`
+pub fn msg_queue(id: usize) -> Result<Arc<MsgQueue>, isize> {
+    MSG_TABLE.lock().queues.get(&id).cloned().ok_or(EINVAL)
+}
+
+/// msgctl(IPC_RMID): remove the queue, waking everyone blocked on it
+pub fn msg_remove(id: usize, cred: &Credentials) -> Result<(), isize> {
+    let mut table = MSG_TABLE.lock();
+    let queue = table.queues.get(&id).ok_or(EINVAL)?;
+    let mut inner = queue.inner.lock();
+    if !inner.ds.msg_perm.is_owner(cred) {
+        return Err(EPERM);
+    }
+    inner.removed = true;
+    inner.messages.clear();
+    wake_all(&mut inner.senders);
+    wake_all(&mut inner.receivers);
+    drop(inner);
+    table.queues.remove(&id);
+    Ok(())
+}
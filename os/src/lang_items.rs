@@ -1,40 +1,247 @@
 //! The panic handler and backtrace
 
-use core::{arch::asm, panic::PanicInfo};
+use core::{
+    arch::asm,
+    fmt,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use crate::{sbi::shutdown, task::current_kstack_top};
+use riscv::register::{satp, scause, sepc, stval};
+
+use crate::{
+    config::{KERNEL_STACK_SIZE, KSYMS_SIZE},
+    console::early_print,
+    logging::kmsg,
+    task::{hart_id, try_current_task},
+};
+
+/// max depth of the backtrace
+const BACKTRACE_DEPTH: usize = 16;
+
+/// set by the first panic, a panic inside the panic handler stops at once
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// first line of the symbol table
+const KSYMS_MAGIC: &[u8] = b"ksyms";
+
+/// Space of the symbol table, filled by `ksyms.sh` after linking
+///
+/// 初值只有标记行：不能全为 0，否则会被当成 NOBITS 段，objcopy 无法写入。
+/// 读取时通过链接脚本的 sksyms/eksyms，编译器不会把初值当常量用。
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = {
+    let mut table = [0; KSYMS_SIZE];
+    let mut i = 0;
+    while i < KSYMS_MAGIC.len() {
+        table[i] = KSYMS_MAGIC[i];
+        i += 1;
+    }
+    table[i] = b'\n';
+    table
+};
 
 #[panic_handler]
 /// panic handler
 fn panic(info: &PanicInfo) -> ! {
+    // 使用 early console：panic 可能发生在 console 初始化之前，或者 console 锁被持有时
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        early_print(format_args!(
+            "[kernel] Panicked while panicking: {}\n",
+            info.message().unwrap()
+        ));
+        panic_exit();
+    }
     if let Some(location) = info.location() {
-        println!(
-            "[kernel] Panicked at {}:{} {}",
+        panic_print(format_args!(
+            "[kernel] Panicked at {}:{} {}\n",
             location.file(),
             location.line(),
             info.message().unwrap()
-        );
+        ));
     } else {
-        println!("[kernel] Panicked: {}", info.message().unwrap());
+        panic_print(format_args!(
+            "[kernel] Panicked: {}\n",
+            info.message().unwrap()
+        ));
+    }
+    dump_state();
+    unsafe {
+        backtrace();
+    }
+    #[cfg(feature = "debug_heap")]
+    crate::mm::debug_heap::dump();
+    panic_exit()
+}
+
+/// Print through the early console and record into the kernel log if possible
+fn panic_print(args: fmt::Arguments) {
+    early_print(args);
+    kmsg::try_record(0, args);
+}
+
+/// Print the hart, the current task, trap CSRs and the saved TrapContext
+fn dump_state() {
+    panic_print(format_args!(
+        "hart {}, scause {:?}, sepc {}, stval {:#x}, satp {:#x}\n",
+        hart_id(),
+        scause::read().cause(),
+        Symbolized(sepc::read()),
+        stval::read(),
+        satp::read().bits()
+    ));
+    // 所有访问都不能再次 panic，借用失败时跳过
+    let Some(task) = try_current_task() else {
+        panic_print(format_args!("no current task\n"));
+        return;
+    };
+    panic_print(format_args!("current pid {}, tid {}\n", task.pid.0, task.tid));
+    let Some(inner) = task.try_inner_exclusive_access() else {
+        return;
+    };
+    // trap context 通过用户地址访问，只有当前页表是该任务的页表时才能读取
+    let Some(memory_set) = inner.memory_set.try_lock() else {
+        return;
+    };
+    if memory_set.token() != satp::read().bits() {
+        return;
+    }
+    drop(memory_set);
+    drop(inner);
+    let trap_cx = task.get_trap_cx();
+    panic_print(format_args!(
+        "trap context: sepc {:#x}, sstatus {:#x}, kernel_sp {:#x}\n",
+        trap_cx.sepc,
+        trap_cx.sstatus.bits(),
+        trap_cx.kernel_sp
+    ));
+    for (i, regs) in trap_cx.x.chunks(4).enumerate() {
+        panic_print(format_args!(
+            "x{:<2}: {:#018x} {:#018x} {:#018x} {:#018x}\n",
+            i * 4,
+            regs[0],
+            regs[1],
+            regs[2],
+            regs[3]
+        ));
+    }
+}
+
+/// Exit with the failure code, the test runner reports the panic as a failed test
+#[cfg(any(test, feature = "selftest"))]
+fn panic_exit() -> ! {
+    crate::boards::shutdown_failure()
+}
+
+/// Shut down or spin according to `PANIC_SHUTDOWN`
+#[cfg(not(any(test, feature = "selftest")))]
+fn panic_exit() -> ! {
+    if crate::config::PANIC_SHUTDOWN {
+        crate::sbi::shutdown()
     }
-    // unsafe {
-    //     backtrace();
-    // }
-    shutdown()
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Bounds of the stack `fp` lies on: the boot stack, the emergency stack of kernel traps
+/// or the current kernel stack
+fn stack_bounds(fp: usize) -> Option<(usize, usize)> {
+    extern "C" {
+        fn boot_stack_lower_bound();
+        fn boot_stack_top();
+        fn __emergency();
+        fn __emergency_end();
+    }
+    let boot_stack = (boot_stack_lower_bound as usize, boot_stack_top as usize);
+    let emergency_stack = (__emergency as usize, __emergency_end as usize);
+    for stack in [boot_stack, emergency_stack] {
+        if (stack.0..=stack.1).contains(&fp) {
+            return Some(stack);
+        }
+    }
+    let top = try_current_task()?.kstack.get_top();
+    Some((top - KERNEL_STACK_SIZE, top))
 }
+
 /// backtrace function
-#[allow(unused)]
+///
+/// The kernel is built with `-Cforce-frame-pointers=yes`: `fp - 8` holds the
+/// return address and `fp - 16` the caller's frame pointer.
 unsafe fn backtrace() {
     let mut fp: usize;
-    let stop = current_kstack_top();
     asm!("mv {}, s0", out(reg) fp);
-    println!("---START BACKTRACE---");
-    for i in 0..10 {
-        if fp == stop {
+    panic_print(format_args!("---START BACKTRACE---\n"));
+    for i in 0..BACKTRACE_DEPTH {
+        // 每一帧重新确定所在的栈：内核 trap 在应急栈上处理，要从应急栈回到出错的内核栈
+        let Some((bottom, top)) = stack_bounds(fp) else {
+            break;
+        };
+        // 离开已知的栈或者 fp 未对齐说明到达栈底（或栈已损坏）
+        if fp <= bottom + 16 || fp > top || fp % 8 != 0 {
             break;
         }
-        println!("#{}:ra={:#x}", i, *((fp - 8) as *const usize));
+        let ra = *((fp - 8) as *const usize);
+        panic_print(format_args!("#{}:ra={}\n", i, Symbolized(ra)));
         fp = *((fp - 16) as *const usize);
     }
-    println!("---END   BACKTRACE---");
+    panic_print(format_args!("---END   BACKTRACE---\n"));
+}
+
+/// Function holding `addr` and the offset into it, from the symbol table in `.ksyms`
+///
+/// 表是文本：第一行是标记，之后每行 "十六进制地址 函数名"，按地址升序，以 NUL 结束。
+/// 没有运行 ksyms.sh（比如 cargo test 的内核）时表是空的。
+fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    extern "C" {
+        fn stext();
+        fn etext();
+        fn sksyms();
+        fn eksyms();
+    }
+    if !(stext as usize..etext as usize).contains(&addr) {
+        return None;
+    }
+    let table = unsafe {
+        core::slice::from_raw_parts(
+            sksyms as usize as *const u8,
+            eksyms as usize - sksyms as usize,
+        )
+    };
+    let len = table.iter().position(|&byte| byte == 0)?;
+    let mut lines = table[..len].split(|&byte| byte == b'\n');
+    if lines.next()? != KSYMS_MAGIC {
+        return None;
+    }
+    let mut found = None;
+    for line in lines {
+        let Some((start, name)) = core::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.split_once(' '))
+        else {
+            continue;
+        };
+        let Ok(start) = usize::from_str_radix(start, 16) else {
+            continue;
+        };
+        if start > addr {
+            break;
+        }
+        found = Some((name, addr - start));
+    }
+    found
+}
+
+/// An address printed with the function it lies in, like `0x80201234 <rust_main+0x34>`
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)?;
+        if let Some((name, offset)) = symbolize(self.0) {
+            write!(f, " <{}+{:#x}>", name, offset)?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,137 @@
+//! Per-module log filter
+//!
+//! 每个模块（例如 `mm`、`fs::ext4`、`task`）可以单独设置日志级别，
+//! 匹配规则为最长前缀匹配，没有匹配项时使用默认级别。
+//! 表格大小固定，因为解析 bootargs 时堆还没有初始化。
+
+use alloc::string::String;
+use core::fmt::Write;
+
+use log::{LevelFilter, Metadata};
+use spin::Mutex;
+
+/// max number of module filters
+const MAX_FILTERS: usize = 16;
+/// max length of a module path in the filter table
+const MAX_TARGET_LEN: usize = 32;
+/// crate name prepended to the record target by `log`
+const CRATE_PREFIX: &str = "os::";
+
+#[derive(Clone, Copy)]
+struct ModuleFilter {
+    target: [u8; MAX_TARGET_LEN],
+    len:    usize,
+    level:  LevelFilter,
+}
+
+impl ModuleFilter {
+    const EMPTY: Self = Self {
+        target: [0; MAX_TARGET_LEN],
+        len:    0,
+        level:  LevelFilter::Off,
+    };
+    fn target(&self) -> &str {
+        core::str::from_utf8(&self.target[..self.len]).unwrap()
+    }
+    /// `mm` matches `mm` and `mm::memory_set`, but not `mmio`
+    fn matches(&self, module: &str) -> bool {
+        let target = self.target();
+        module.starts_with(target)
+            && (module.len() == target.len() || module[target.len()..].starts_with("::"))
+    }
+}
+
+/// Default level plus a table of module filters
+pub struct LogFilter {
+    default: LevelFilter,
+    filters: [ModuleFilter; MAX_FILTERS],
+    count:   usize,
+}
+
+impl LogFilter {
+    const fn new() -> Self {
+        Self {
+            default: LevelFilter::Error,
+            filters: [ModuleFilter::EMPTY; MAX_FILTERS],
+            count:   0,
+        }
+    }
+    /// Level of the most specific filter matching `target`
+    fn level_of(&self, target: &str) -> LevelFilter {
+        let module = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        self.filters[..self.count]
+            .iter()
+            .filter(|filter| filter.matches(module))
+            .max_by_key(|filter| filter.len)
+            .map_or(self.default, |filter| filter.level)
+    }
+    /// Set the level of `target`, replacing an existing filter of the same target
+    fn set(&mut self, target: &str, level: LevelFilter) -> bool {
+        if let Some(filter) = self.filters[..self.count]
+            .iter_mut()
+            .find(|filter| filter.target() == target)
+        {
+            filter.level = level;
+            return true;
+        }
+        if self.count == MAX_FILTERS || target.len() > MAX_TARGET_LEN {
+            return false;
+        }
+        let filter = &mut self.filters[self.count];
+        filter.target[..target.len()].copy_from_slice(target.as_bytes());
+        filter.len = target.len();
+        filter.level = level;
+        self.count += 1;
+        true
+    }
+    /// The most verbose level of all filters, used as `log::max_level`
+    fn max_level(&self) -> LevelFilter {
+        self.filters[..self.count]
+            .iter()
+            .map(|filter| filter.level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+static FILTER: Mutex<LogFilter> = Mutex::new(LogFilter::new());
+
+/// Check a record against the filter table
+pub fn enabled(metadata: &Metadata) -> bool {
+    metadata.level() <= FILTER.lock().level_of(metadata.target())
+}
+
+/// Set the default level
+pub fn set_default(level: LevelFilter) {
+    let mut filter = FILTER.lock();
+    filter.default = level;
+    log::set_max_level(filter.max_level());
+}
+
+/// Set the level of a module, e.g. `mm` or `fs::ext4`
+pub fn set_module(target: &str, level: LevelFilter) -> bool {
+    let mut filter = FILTER.lock();
+    let ok = filter.set(target, level);
+    log::set_max_level(filter.max_level());
+    ok
+}
+
+/// Default level
+pub fn default_level() -> LevelFilter {
+    FILTER.lock().default
+}
+
+/// The filter table as a level spec like `warn,mm=debug`, which `apply_level_spec` accepts
+pub fn render() -> String {
+    let filter = FILTER.lock();
+    let mut spec = filter.default.as_str().to_ascii_lowercase();
+    for module in filter.filters[..filter.count].iter() {
+        let _ = write!(
+            spec,
+            ",{}={}",
+            module.target(),
+            module.level.as_str().to_ascii_lowercase()
+        );
+    }
+    spec.push('\n');
+    spec
+}
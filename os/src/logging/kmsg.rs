@@ -0,0 +1,166 @@
+//! Kernel log ring buffer
+//!
+//! 所有 `println!` 和 `log` 输出都会按行记录在一个固定大小的环形缓冲区中，
+//! 每一行带有形如 `<level>[seq] ` 的前缀，缓冲区满时覆盖最早的记录。
+//! 用户态通过 `sys_syslog` 读取（参考 Linux syslog(2)）。
+
+use core::fmt::{self, Write};
+
+use spin::Mutex;
+
+use crate::sync::WaitQueue;
+
+/// size of the kernel log buffer in bytes
+pub const LOG_BUF_LEN: usize = 1 << 16;
+
+/// syslog level of messages printed by `println!`
+pub const DEFAULT_MESSAGE_LEVEL: u8 = 6;
+
+/// Fixed size ring buffer of log lines
+///
+/// `start`, `end` and `read` are absolute byte positions, the position in `buf`
+/// is `pos % LOG_BUF_LEN`.
+pub struct LogBuffer {
+    buf:        [u8; LOG_BUF_LEN],
+    /// position of the oldest byte still in the buffer
+    start:      usize,
+    /// position of the next byte to write
+    end:        usize,
+    /// position of the next byte `SYSLOG_ACTION_READ` returns
+    read:       usize,
+    /// position of the oldest byte `SYSLOG_ACTION_READ_ALL` returns
+    clear:      usize,
+    /// sequence number of the oldest line in the buffer
+    first_seq:  u64,
+    /// sequence number of the next line
+    next_seq:   u64,
+    /// whether the next byte starts a new line
+    line_start: bool,
+    /// level of the line being written
+    level:      u8,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        Self {
+            buf:        [0; LOG_BUF_LEN],
+            start:      0,
+            end:        0,
+            read:       0,
+            clear:      0,
+            first_seq:  0,
+            next_seq:   0,
+            line_start: true,
+            level:      DEFAULT_MESSAGE_LEVEL,
+        }
+    }
+    /// Append one byte, dropping the oldest line if the buffer is full
+    fn push_byte(&mut self, byte: u8) {
+        if self.end - self.start == LOG_BUF_LEN {
+            self.drop_oldest_line();
+        }
+        self.buf[self.end % LOG_BUF_LEN] = byte;
+        self.end += 1;
+    }
+    fn drop_oldest_line(&mut self) {
+        while self.start < self.end {
+            let byte = self.buf[self.start % LOG_BUF_LEN];
+            self.start += 1;
+            if byte == b'\n' {
+                break;
+            }
+        }
+        self.first_seq += 1;
+        self.read = self.read.max(self.start);
+        self.clear = self.clear.max(self.start);
+    }
+    /// Append formatted text at `level`, a new line gets a `<level>[seq] ` prefix
+    pub fn record(&mut self, level: u8, args: fmt::Arguments) {
+        self.level = level;
+        let _ = self.write_fmt(args);
+    }
+    /// Copy bytes in `[from, self.end)` into `buf`, return the number of bytes copied
+    fn copy_from(&self, from: usize, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.end - from);
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.buf[(from + i) % LOG_BUF_LEN];
+        }
+        len
+    }
+    /// Destructive read from the last read position (`SYSLOG_ACTION_READ`)
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = self.copy_from(self.read, buf);
+        self.read += len;
+        len
+    }
+    /// Read the last `buf.len()` bytes without consuming them (`SYSLOG_ACTION_READ_ALL`)
+    pub fn read_all(&self, buf: &mut [u8]) -> usize {
+        let from = self.clear.max(self.end.saturating_sub(buf.len()));
+        self.copy_from(from, buf)
+    }
+    /// Forget all lines for `read_all` (`SYSLOG_ACTION_CLEAR`)
+    pub fn clear(&mut self) {
+        self.clear = self.end;
+    }
+    /// Bytes not consumed by `read` yet
+    pub fn unread(&self) -> usize {
+        self.end - self.read
+    }
+    /// Sequence numbers of the lines in the buffer, `first..next`
+    pub fn seq_range(&self) -> (u64, u64) {
+        (self.first_seq, self.next_seq)
+    }
+}
+
+impl Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.line_start {
+                self.line_start = false;
+                let (level, seq) = (self.level, self.next_seq);
+                self.next_seq += 1;
+                write!(PrefixWriter(self), "<{}>[{}] ", level, seq)?;
+            }
+            self.push_byte(byte);
+            if byte == b'\n' {
+                self.line_start = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the line prefix without recursing into `LogBuffer::write_str`
+struct PrefixWriter<'a>(&'a mut LogBuffer);
+
+impl Write for PrefixWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.0.push_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// The global kernel log buffer, a const static so the 64K buffer is not built
+/// on the kernel stack
+pub static KMSG: Mutex<LogBuffer> = Mutex::new(LogBuffer::new());
+
+/// Readers sleeping in `SYSLOG_ACTION_READ` or on `/proc/kmsg` until the log is nonempty
+pub static KMSG_READERS: WaitQueue = WaitQueue::new();
+
+/// Record formatted text into the kernel log buffer, waking the sleeping readers
+pub fn record(level: u8, args: fmt::Arguments) {
+    KMSG.lock().record(level, args);
+    // 唤醒时 wakeup_task 自己也会打日志，这时队列已经取空，不会再递归唤醒
+    if !KMSG_READERS.is_empty() {
+        KMSG_READERS.wake_all();
+    }
+}
+
+/// Like [`record`], but gives up if the buffer is locked (e.g. panicked while recording)
+pub fn try_record(level: u8, args: fmt::Arguments) {
+    if let Some(mut kmsg) = KMSG.try_lock() {
+        kmsg.record(level, args);
+    }
+}
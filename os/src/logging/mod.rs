@@ -0,0 +1,188 @@
+//! Global logger
+
+pub mod filter;
+pub mod kmsg;
+
+use alloc::string::{String, ToString};
+use core::fmt;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+
+use crate::{
+    console::console_print,
+    task::{current_pid, current_task, current_tid, hart_id},
+    timer::get_time_us,
+};
+
+/// Add escape sequence to print with color in Linux console
+macro_rules! with_color {
+    ($args: ident, $color_code: ident) => {{
+        format_args!("\u{1B}[{}m{}\u{1B}[0m", $color_code as u8, $args)
+    }};
+}
+
+/// Print msg with color
+pub fn print_in_color(args: fmt::Arguments, color_code: u8) {
+    // use crate::arch::io;
+    // let _guard = LOG_LOCK.lock();
+    // io::putfmt(with_color!(args, color_code));
+    console_print(with_color!(args, color_code));
+}
+
+/// syslog level of a log level, see `<linux/kern_levels.h>`
+fn syslog_level(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Console loglevel set by syslog(2), filtering only what is printed to the console
+///
+/// 内核日志缓冲区不受它影响，仍按过滤表（默认是编译时的 `LOG`）记录，关掉控制台后 dmesg 照样能读到。
+static CONSOLE_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Trace);
+
+/// Print a log line to the console and record it into the kernel log buffer
+fn log_line(level: Level, color: u8, args: fmt::Arguments) {
+    if level <= *CONSOLE_LEVEL.lock() {
+        print_in_color(args, color);
+    }
+    kmsg::record(syslog_level(level), args);
+}
+
+/// a simple logger
+struct SimpleLogger;
+
+impl Log for SimpleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        filter::enabled(metadata)
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let color = match record.level() {
+            Level::Error => 31, // Red
+            Level::Warn => 93,  // BrightYellow
+            Level::Info => 34,  // Blue
+            Level::Debug => 32, // Green
+            Level::Trace => 90, // BrightBlack
+        };
+        let pid: isize;
+        if let Some(res) = current_pid() {
+            pid = res as isize;
+        } else {
+            pid = -1; // -1 代表当前没有在任何进程内
+        }
+        // let tid = current_tid().map_or_else(|| "None".to_string(), |tid| tid.to_string());
+        // 单调时间（秒.微秒）和 hart id，多核与中断驱动的输出交织时用于排序
+        let us = get_time_us();
+        log_line(
+            record.level(),
+            color,
+            format_args!(
+                "[{:>5}.{:06}][hart {}][{:>5}][{}:{}][{}] {}\n",
+                us / 1_000_000,
+                us % 1_000_000,
+                hart_id(),
+                record.level(),
+                record.file().unwrap(),
+                record.line().unwrap(),
+                pid,
+                // tid,
+                record.args()
+            ),
+        );
+    }
+    fn flush(&self) {}
+}
+
+/// initiate logger
+pub fn init() {
+    static LOGGER: SimpleLogger = SimpleLogger;
+    log::set_logger(&LOGGER).unwrap();
+    filter::set_default(
+        option_env!("LOG")
+            .and_then(parse_level)
+            .unwrap_or(LevelFilter::Error),
+    );
+}
+
+/// Parse a level name (`error`, `WARN`, ...) or a console loglevel (`0`..`8`)
+pub fn parse_level(level: &str) -> Option<LevelFilter> {
+    if let Ok(console_level) = level.parse::<usize>() {
+        return Some(console_level_to_filter(console_level));
+    }
+    // 在堆初始化之前就会被调用，不能分配内存
+    LevelFilter::iter().find(|filter| filter.as_str().eq_ignore_ascii_case(level))
+}
+
+/// Linux console loglevel: messages whose syslog level is less than it are printed
+pub fn console_level_to_filter(console_level: usize) -> LevelFilter {
+    match console_level {
+        0..=3 => LevelFilter::Off,
+        4 => LevelFilter::Error,
+        5 | 6 => LevelFilter::Warn,
+        7 => LevelFilter::Info,
+        8 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Change the default log level at runtime
+pub fn set_level(level: LevelFilter) {
+    filter::set_default(level);
+    info!("log level set to {}", level);
+}
+
+/// Console loglevel, see [`set_console_level`]
+pub fn console_level() -> LevelFilter {
+    *CONSOLE_LEVEL.lock()
+}
+
+/// Change the level of messages printed to the console, the log buffer still records
+/// everything the filter table lets through
+pub fn set_console_level(level: LevelFilter) {
+    *CONSOLE_LEVEL.lock() = level;
+}
+
+/// Apply a level spec like `info`, `mm=debug,fs=warn` or `warn,task=trace`, false if
+/// some item is ignored
+pub fn apply_level_spec(spec: &str) -> bool {
+    let mut ok = true;
+    for item in spec.split(',').filter(|item| !item.is_empty()) {
+        let (module, level) = match item.split_once('=') {
+            Some((module, level)) => (Some(module), level),
+            None => (None, item),
+        };
+        let Some(level) = parse_level(level) else {
+            warn!("unknown log level {}", item);
+            ok = false;
+            continue;
+        };
+        match module {
+            Some(module) => {
+                if filter::set_module(module, level) {
+                    info!("log level of {} set to {}", module, level);
+                } else {
+                    warn!("log filter table full, {} ignored", item);
+                    ok = false;
+                }
+            }
+            None => set_level(level),
+        }
+    }
+    ok
+}
+
+/// Apply `loglevel=` in the kernel command line
+pub fn init_from_bootargs(bootargs: &str) {
+    for arg in bootargs.split_whitespace() {
+        if let Some(spec) = arg.strip_prefix("loglevel=") {
+            apply_level_spec(spec);
+        }
+    }
+}
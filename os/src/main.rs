@@ -7,7 +7,7 @@
 //! - [`task`]: Task management
 //! - [`syscall`]: System call handling and implementation
 //! - [`mm`]: Address map using SV39
-//! - [`sync`]: Wrap a static data structure inside it so that we are able to access it without any `unsafe`.
+//! - [`sync`]: Spin and sleep locks for data shared between harts and tasks
 //! - [`fs`]: Separate user from file system with some structures
 //!
 //! The operating system also starts in this module. Kernel code starts
@@ -26,6 +26,9 @@
 #![feature(trait_upcasting)]
 #![feature(ascii_char)]
 #![feature(negative_impls)]
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::selftest::test_runner))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
 
 use core::arch::{asm, global_asm};
 
@@ -46,10 +49,16 @@ pub mod config;
 pub mod drivers;
 // pub mod fs;
 pub mod fs;
+pub mod ipc;
 pub mod lang_items;
 pub mod logging;
 pub mod mm;
+pub mod net;
+pub mod profile;
 pub mod sbi;
+#[cfg(any(test, feature = "selftest"))]
+mod selftest;
+pub mod smp;
 pub mod sync;
 pub mod syscall;
 pub mod task;
@@ -128,16 +137,24 @@ pub fn rust_main() -> ! {
     #[cfg(feature = "qemu")]
     init_dtb(None);
     let machine_info = machine_info();
+    if let Some(bootargs) = machine_info.bootargs() {
+        logging::init_from_bootargs(bootargs);
+    }
     #[cfg(feature = "visionfive2")]
     mm::init(machine_info.memory.end);
     #[cfg(feature = "qemu")]
     mm::init(MEMORY_END);
     info!("mm init done");
-    mm::remap_test();
-    info!("mm remap test done");
+    console::init();
+    if let Some(bootargs) = machine_info.bootargs() {
+        console::init_from_bootargs(bootargs);
+        block::partition::init_from_bootargs(bootargs);
+    }
+    info!("console init done");
     trap::init();
     info!("trap init done");
     trap::enable_timer_interrupt();
+    trap::enable_soft_interrupt();
     info!("timer interrupt enabled");
     timer::set_next_trigger();
     info!("timer set next trigger done");
@@ -147,6 +164,14 @@ pub fn rust_main() -> ! {
     // }
     info!("init file system");
     fs::init();
+    drivers::init_irq();
+    info!("device interrupts enabled");
+    #[cfg(feature = "selftest")]
+    selftest::run();
+    #[cfg(test)]
+    test_main();
+    smp::start_secondary_harts();
+    info!("{} harts online", smp::online_harts());
     info!("adding initproc");
     task::add_initproc();
     info!("running tasks");
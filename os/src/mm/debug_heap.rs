@@ -0,0 +1,263 @@
+//! Debug kernel heap, enabled by the `debug_heap` feature
+//!
+//! 包装 buddy 分配器，帮助定位 use-after-free 和越界写：
+//! - 每块内存前后各有一段红区，填充 [`POISON_REDZONE`]，释放时检查，被改写则 panic；
+//! - 新分配的内存填充 [`POISON_INUSE`]，释放的内存填充 [`POISON_FREE`]，
+//!   释放后继续使用的指针会读到 `0x6b6b...`，出错地址一眼就能认出；
+//! - 记录所有未释放的分配（地址、大小、序号），panic 时或读 `/proc/heap` 时输出，
+//!   用 [`mark`] 和 [`dump_since`] 可以找出某段代码泄漏的内存。
+//!
+//! 记录表本身不从堆上分配，超出 [`MAX_TRACKED`] 的分配只计数不记录。
+//!
+//! 打开 `guard_heap` 时，较大的分配改由 [`super::guard_heap`] 放在两侧有保护页的独立页上。
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt::Write,
+    ops::Deref,
+    ptr::{self, NonNull},
+};
+
+use buddy_system_allocator::LockedHeap;
+use spin::Mutex;
+
+use crate::console::early_print;
+
+/// byte pattern of fresh allocations, as in Linux slab debugging
+pub const POISON_INUSE: u8 = 0x5a;
+/// byte pattern of freed memory
+pub const POISON_FREE: u8 = 0x6b;
+/// byte pattern of the red zones around each allocation
+pub const POISON_REDZONE: u8 = 0xbb;
+/// size of the red zone after each allocation, the one before is at least this large
+const REDZONE: usize = 16;
+/// live allocations recorded at most
+pub const MAX_TRACKED: usize = 4096;
+/// allocations printed by [`dump`] at most
+const DUMP_LIMIT: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AllocRecord {
+    /// address handed out, 0 for an empty slot
+    pub addr: usize,
+    pub size: usize,
+    /// order of the allocation since boot
+    pub seq:  usize,
+}
+
+impl AllocRecord {
+    const EMPTY: Self = Self {
+        addr: 0,
+        size: 0,
+        seq:  0,
+    };
+}
+
+struct AllocTable {
+    records:   [AllocRecord; MAX_TRACKED],
+    live:      usize,
+    /// live allocations that did not fit into `records`
+    untracked: usize,
+    next_seq:  usize,
+}
+
+impl AllocTable {
+    fn insert(&mut self, addr: usize, size: usize) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        match self.records.iter_mut().find(|record| record.addr == 0) {
+            Some(slot) => {
+                *slot = AllocRecord { addr, size, seq };
+                self.live += 1;
+            }
+            None => self.untracked += 1,
+        }
+    }
+
+    fn remove(&mut self, addr: usize) {
+        match self.records.iter_mut().find(|record| record.addr == addr) {
+            Some(slot) => {
+                *slot = AllocRecord::EMPTY;
+                self.live -= 1;
+            }
+            None => self.untracked = self.untracked.saturating_sub(1),
+        }
+    }
+}
+
+pub struct DebugHeap {
+    heap:  LockedHeap,
+    table: Mutex<AllocTable>,
+}
+
+impl DebugHeap {
+    pub const fn empty() -> Self {
+        Self {
+            heap:  LockedHeap::empty(),
+            table: Mutex::new(AllocTable {
+                records:   [AllocRecord::EMPTY; MAX_TRACKED],
+                live:      0,
+                untracked: 0,
+                next_seq:  0,
+            }),
+        }
+    }
+
+    /// Layout of the block backing `layout` and the offset of the user part in it
+    fn padded(layout: Layout) -> (Layout, usize) {
+        let front = layout.align().max(REDZONE);
+        let size = front + layout.size() + REDZONE;
+        (Layout::from_size_align(size, front).unwrap(), front)
+    }
+}
+
+/// `init_heap` goes through the inner allocator
+impl Deref for DebugHeap {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        &self.heap
+    }
+}
+
+unsafe impl GlobalAlloc for DebugHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "guard_heap")]
+        if let Some(user) = super::guard_heap::alloc(layout) {
+            ptr::write_bytes(user, POISON_INUSE, layout.size());
+            self.table.lock().insert(user as usize, layout.size());
+            return user;
+        }
+        let (padded, front) = Self::padded(layout);
+        let block = match self.heap.lock().alloc(padded) {
+            Ok(block) => block.as_ptr(),
+            Err(_) => return ptr::null_mut(),
+        };
+        let user = block.add(front);
+        ptr::write_bytes(block, POISON_REDZONE, front);
+        ptr::write_bytes(user, POISON_INUSE, layout.size());
+        ptr::write_bytes(user.add(layout.size()), POISON_REDZONE, REDZONE);
+        self.table.lock().insert(user as usize, layout.size());
+        user
+    }
+
+    unsafe fn dealloc(&self, user: *mut u8, layout: Layout) {
+        #[cfg(feature = "guard_heap")]
+        if super::guard_heap::dealloc(user, layout) {
+            self.table.lock().remove(user as usize);
+            return;
+        }
+        let (padded, front) = Self::padded(layout);
+        let block = user.sub(front);
+        let front_zone = core::slice::from_raw_parts(block, front);
+        let back_zone = core::slice::from_raw_parts(user.add(layout.size()), REDZONE);
+        if let Some(pos) = front_zone.iter().position(|&b| b != POISON_REDZONE) {
+            panic!(
+                "[debug_heap] underflow before {:#x} (size {}): byte {} of the red zone is {:#x}",
+                user as usize,
+                layout.size(),
+                pos,
+                front_zone[pos]
+            );
+        }
+        if let Some(pos) = back_zone.iter().position(|&b| b != POISON_REDZONE) {
+            panic!(
+                "[debug_heap] overflow after {:#x} (size {}): byte {} of the red zone is {:#x}",
+                user as usize,
+                layout.size(),
+                pos,
+                back_zone[pos]
+            );
+        }
+        self.table.lock().remove(user as usize);
+        ptr::write_bytes(block, POISON_FREE, padded.size());
+        self.heap
+            .lock()
+            .dealloc(NonNull::new_unchecked(block), padded);
+    }
+}
+
+/// The kernel heap, see `heap_allocator`
+fn heap() -> &'static DebugHeap {
+    &super::heap_allocator::HEAP_ALLOCATOR
+}
+
+/// Sequence number of the next allocation, pass to [`dump_since`]
+pub fn mark() -> usize {
+    heap().table.lock().next_seq
+}
+
+/// Live allocations made at or after `mark`, oldest first
+pub fn live_since(mark: usize) -> Vec<AllocRecord> {
+    // 先分配好结果的空间，持有记录表的锁时不能再分配
+    let mut records = Vec::with_capacity(MAX_TRACKED);
+    let table = heap().table.lock();
+    records.extend(
+        table
+            .records
+            .iter()
+            .filter(|record| record.addr != 0 && record.seq >= mark)
+            .copied(),
+    );
+    drop(table);
+    records.sort_unstable_by_key(|record| record.seq);
+    records
+}
+
+/// Print the live allocations made at or after `mark`
+pub fn dump_since(mark: usize) {
+    for record in live_since(mark) {
+        println!(
+            "[debug_heap] #{} {:#x} size {}",
+            record.seq, record.addr, record.size
+        );
+    }
+}
+
+/// Contents of `/proc/heap`
+pub fn render() -> String {
+    let records = live_since(0);
+    let (live, untracked) = {
+        let table = heap().table.lock();
+        (table.live, table.untracked)
+    };
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "live allocations: {}, bytes: {}, untracked: {}",
+        live,
+        records.iter().map(|record| record.size).sum::<usize>(),
+        untracked
+    );
+    for record in records {
+        let _ = writeln!(out, "#{} {:#x} {}", record.seq, record.addr, record.size);
+    }
+    out
+}
+
+/// Print the live allocations from the panic handler, at most [`DUMP_LIMIT`] of them
+///
+/// 不分配内存；记录表被持有时（panic 发生在分配器内部）直接跳过。
+pub fn dump() {
+    let Some(table) = heap().table.try_lock() else {
+        early_print(format_args!("[debug_heap] allocation table busy\n"));
+        return;
+    };
+    early_print(format_args!(
+        "[debug_heap] {} live allocations, {} untracked, next #{}\n",
+        table.live, table.untracked, table.next_seq
+    ));
+    let mut printed = 0;
+    for record in table.records.iter().rev().filter(|record| record.addr != 0) {
+        if printed == DUMP_LIMIT {
+            early_print(format_args!("[debug_heap] ...\n"));
+            break;
+        }
+        early_print(format_args!(
+            "[debug_heap] #{} {:#x} size {}\n",
+            record.seq, record.addr, record.size
+        ));
+        printed += 1;
+    }
+}
@@ -1,17 +1,24 @@
 //! Physical page frame allocator
 
-use alloc::vec::Vec;
+use alloc::{sync::Arc, vec::Vec};
 use core::fmt::{self, Debug, Formatter};
 
 use lazy_static::*;
 
 use super::{PhysAddr, PhysPageNum};
-use crate::{config::MEMORY_END, mm::address::KernelAddr, sync::UPSafeCell};
+use crate::{
+    config::MEMORY_END,
+    mm::address::KernelAddr,
+    sync::SpinNoIrqLock,
+    task::cgroup::Cgroup,
+};
 
 /// tracker for physical page frame allocation and deallocation
 pub struct FrameTracker {
     /// physical page number
-    pub ppn: PhysPageNum,
+    pub ppn:     PhysPageNum,
+    /// the cgroup this frame is charged to, uncharged on drop
+    pub charged: Option<Arc<Cgroup>>,
 }
 
 impl FrameTracker {
@@ -24,7 +31,7 @@ impl FrameTracker {
             *i = 0;
         }
         // debug!("new FrameTracker::new: ppn={:?}", ppn);
-        Self { ppn }
+        Self { ppn, charged: None }
     }
 }
 
@@ -36,6 +43,9 @@ impl Debug for FrameTracker {
 
 impl Drop for FrameTracker {
     fn drop(&mut self) {
+        if let Some(cgroup) = self.charged.take() {
+            cgroup.uncharge(1);
+        }
         frame_dealloc(self.ppn);
     }
 }
@@ -48,6 +58,7 @@ trait FrameAllocator {
 }
 
 pub struct StackFrameAllocator {
+    start:    usize,
     current:  usize,
     end:      usize,
     recycled: Vec<usize>,
@@ -55,14 +66,23 @@ pub struct StackFrameAllocator {
 
 impl StackFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.start = l.0;
         self.current = l.0;
         self.end = r.0;
         // trace!("last {} Physical Frames.", self.end - self.current);
     }
+
+    /// Frames managed and frames that can still be allocated
+    ///
+    /// 回收的页帧目前不会再分配出去，不算作空闲。
+    pub fn stats(&self) -> (usize, usize) {
+        (self.end - self.start, self.end - self.current)
+    }
 }
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
         Self {
+            start:    0,
             current:  0,
             end:      0,
             recycled: Vec::new(),
@@ -116,8 +136,8 @@ impl FrameAllocator for StackFrameAllocator {
 type FrameAllocatorImpl = StackFrameAllocator;
 
 lazy_static! {
-    pub static ref FRAME_ALLOCATOR: UPSafeCell<FrameAllocatorImpl> =
-        unsafe { UPSafeCell::new(FrameAllocatorImpl::new()) };
+    pub static ref FRAME_ALLOCATOR: SpinNoIrqLock<FrameAllocatorImpl> =
+        SpinNoIrqLock::new(FrameAllocatorImpl::new());
 }
 
 pub fn init_frame_allocator(memory_end: usize) {
@@ -136,25 +156,50 @@ pub fn init_frame_allocator(memory_end: usize) {
         "PhysAddr::from(MEMORY_END)={:?}",
         PhysAddr::from(memory_end)
     );
-    FRAME_ALLOCATOR.exclusive_access(file!(), line!()).init(
+    FRAME_ALLOCATOR.lock().init(
         PhysAddr::from(KernelAddr::from(ekernel as usize)).ceil(),
         PhysAddr::from(KernelAddr::from(memory_end)).floor(),
     );
 }
 
+/// Total and free physical page frames
+pub fn frame_stats() -> (usize, usize) {
+    FRAME_ALLOCATOR.lock().stats()
+}
+
 /// Allocate a physical page frame in FrameTracker style
 pub fn frame_alloc() -> Option<FrameTracker> {
-    FRAME_ALLOCATOR
-        .exclusive_access(file!(), line!())
-        .alloc()
-        .map(FrameTracker::new)
+    FRAME_ALLOCATOR.lock().alloc().map(FrameTracker::new)
+}
+
+/// Allocate a frame without a tracker, `None` also when the allocator is in use
+///
+/// 供全局分配器使用：分配内存时可能正持有 FRAME_ALLOCATOR（例如 `recycled` 扩容）。
+#[cfg(feature = "guard_heap")]
+pub(super) fn frame_alloc_raw() -> Option<PhysPageNum> {
+    FRAME_ALLOCATOR.try_lock()?.alloc()
+}
+
+/// Allocate a frame on behalf of `cgroup`
+///
+/// The page must already be charged with [`Cgroup::try_charge`] or
+/// [`Cgroup::charge`], the frame carries the charge from then on.
+pub fn frame_alloc_charged(cgroup: &Arc<Cgroup>) -> Option<FrameTracker> {
+    match frame_alloc() {
+        Some(mut frame) => {
+            frame.charged = Some(cgroup.clone());
+            Some(frame)
+        }
+        None => {
+            cgroup.uncharge(1);
+            None
+        }
+    }
 }
 
 /// Allocate n contiguous physical page frames in FrameTracker style
 pub fn frame_alloc_contiguous(num: usize) -> (Vec<FrameTracker>, PhysPageNum) {
-    let (frames, root_ppn) = FRAME_ALLOCATOR
-        .exclusive_access(file!(), line!())
-        .alloc_contiguous(num);
+    let (frames, root_ppn) = FRAME_ALLOCATOR.lock().alloc_contiguous(num);
     let frame_trackers: Vec<FrameTracker> = frames.iter().map(|&p| FrameTracker::new(p)).collect();
     (frame_trackers, root_ppn)
 }
@@ -162,9 +207,7 @@ pub fn frame_alloc_contiguous(num: usize) -> (Vec<FrameTracker>, PhysPageNum) {
 /// Deallocate a physical page frame with a given ppn
 pub fn frame_dealloc(ppn: PhysPageNum) {
     // debug!("dealloc a page: ppn={:#x}", ppn.0);
-    FRAME_ALLOCATOR
-        .exclusive_access(file!(), line!())
-        .dealloc(ppn);
+    FRAME_ALLOCATOR.lock().dealloc(ppn);
 }
 
 #[allow(unused)]
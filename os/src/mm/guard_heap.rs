@@ -0,0 +1,200 @@
+//! Guard-page allocations, enabled by the `guard_heap` feature
+//!
+//! 不小于 [`GUARD_MIN_SIZE`] 的内核对象不从堆上分配，而是放进内核地址空间中
+//! [`GUARD_AREA_BASE`] 开始的一段窗口：窗口分成若干槽位，每个槽位使用独立的页帧，
+//! 槽位之间是不映射的保护页。对象紧贴槽位末尾放置，越界写在出错的那条指令上就触发缺页，
+//! 不会悄悄改坏相邻的 TaskControlBlock；释放时取消映射，释放后再访问同样会缺页。
+//! 对象前面页内剩余的空间填充红区，释放时检查。
+//!
+//! 窗口的页表在启动时建好，映射和取消映射只改写叶子页表项，分配过程不再分配内存。
+//! 槽位轮流使用，刚释放的槽位尽量晚复用；页帧在槽位第一次使用时分配，之后一直留给该槽位。
+//! 对象太大、槽位用完或页帧分配器正被占用时返回 `None`，由调用者回退到普通的调试堆。
+
+use core::{alloc::Layout, arch::asm, ptr};
+
+use spin::Mutex;
+
+use super::{
+    debug_heap::POISON_REDZONE,
+    frame_allocator::frame_alloc_raw,
+    PTEFlags,
+    PageTable,
+    PageTableEntry,
+    PhysPageNum,
+    VirtAddr,
+    KERNEL_SPACE,
+};
+use crate::config::{GUARD_AREA_BASE, PAGE_SIZE};
+
+/// allocations at least this large get their own pages
+pub const GUARD_MIN_SIZE: usize = 1024;
+/// data pages of a slot, larger allocations stay on the heap
+const SLOT_PAGES: usize = 8;
+const SLOTS: usize = 512;
+/// every slot is preceded by a guard page
+const SLOT_STRIDE: usize = (SLOT_PAGES + 1) * PAGE_SIZE;
+/// the last slot is followed by one more guard page
+const AREA_SIZE: usize = SLOTS * SLOT_STRIDE + PAGE_SIZE;
+
+#[derive(Clone, Copy)]
+struct Slot {
+    /// frames backing the data pages, 0 until first used
+    frames: [usize; SLOT_PAGES],
+    /// the live allocation, 0 if the slot is free
+    addr:   usize,
+}
+
+impl Slot {
+    const EMPTY: Self = Self {
+        frames: [0; SLOT_PAGES],
+        addr:   0,
+    };
+}
+
+struct GuardPool {
+    /// token of the kernel page table, 0 before [`init`]
+    token: usize,
+    slots: [Slot; SLOTS],
+    /// slot to try first
+    next:  usize,
+}
+
+static POOL: Mutex<GuardPool> = Mutex::new(GuardPool {
+    token: 0,
+    slots: [Slot::EMPTY; SLOTS],
+    next:  0,
+});
+
+fn slot_start(idx: usize) -> usize {
+    GUARD_AREA_BASE + PAGE_SIZE + idx * SLOT_STRIDE
+}
+
+fn slot_end(idx: usize) -> usize {
+    slot_start(idx) + SLOT_PAGES * PAGE_SIZE
+}
+
+fn flush_tlb(va: usize) {
+    unsafe {
+        asm!("sfence.vma {}, zero", in(reg) va);
+    }
+}
+
+/// Create the page tables of the guard area, after the kernel space is activated
+///
+/// 进程页表共享内核的一级页表项，所以要在创建第一个进程之前调用。
+pub fn init() {
+    let mut kernel_space = KERNEL_SPACE.lock();
+    for va in (GUARD_AREA_BASE..GUARD_AREA_BASE + AREA_SIZE).step_by(PAGE_SIZE) {
+        kernel_space
+            .page_table
+            .find_pte_create(VirtAddr::from(va).floor())
+            .unwrap();
+    }
+    POOL.lock().token = kernel_space.page_table.token();
+}
+
+/// Place `layout` at the end of a free slot
+pub fn alloc(layout: Layout) -> Option<*mut u8> {
+    if layout.size() < GUARD_MIN_SIZE
+        || layout.size() > SLOT_PAGES * PAGE_SIZE
+        || layout.align() > PAGE_SIZE
+    {
+        return None;
+    }
+    let mut pool = POOL.lock();
+    if pool.token == 0 {
+        return None;
+    }
+    let idx = (0..SLOTS)
+        .map(|i| (pool.next + i) % SLOTS)
+        .find(|&idx| pool.slots[idx].addr == 0)?;
+    let page_table = PageTable::from_token(pool.token);
+    let addr = (slot_end(idx) - layout.size()) & !(layout.align() - 1);
+    let first_page = addr & !(PAGE_SIZE - 1);
+    let first = (first_page - slot_start(idx)) / PAGE_SIZE;
+    let slot = &mut pool.slots[idx];
+    for frame in slot.frames[first..].iter_mut().filter(|frame| **frame == 0) {
+        *frame = frame_alloc_raw()?.0;
+    }
+    for (i, va) in (first_page..slot_end(idx)).step_by(PAGE_SIZE).enumerate() {
+        let pte = page_table.find_pte(VirtAddr::from(va).floor()).unwrap();
+        *pte = PageTableEntry::new(
+            PhysPageNum(slot.frames[first + i]),
+            PTEFlags::V | PTEFlags::R | PTEFlags::W,
+        );
+        flush_tlb(va);
+    }
+    slot.addr = addr;
+    pool.next = (idx + 1) % SLOTS;
+    unsafe {
+        ptr::write_bytes(first_page as *mut u8, POISON_REDZONE, addr - first_page);
+    }
+    Some(addr as *mut u8)
+}
+
+/// Free `user` if it came from [`alloc`], returns false for heap allocations
+pub fn dealloc(user: *mut u8, layout: Layout) -> bool {
+    let addr = user as usize;
+    if !(GUARD_AREA_BASE..GUARD_AREA_BASE + AREA_SIZE).contains(&addr) {
+        return false;
+    }
+    let mut pool = POOL.lock();
+    let idx = (addr - GUARD_AREA_BASE - PAGE_SIZE) / SLOT_STRIDE;
+    assert!(
+        pool.slots[idx].addr == addr,
+        "[guard_heap] free of {:#x} (size {}), not a live allocation",
+        addr,
+        layout.size()
+    );
+    let first_page = addr & !(PAGE_SIZE - 1);
+    let front_zone =
+        unsafe { core::slice::from_raw_parts(first_page as *const u8, addr - first_page) };
+    if let Some(pos) = front_zone.iter().position(|&b| b != POISON_REDZONE) {
+        panic!(
+            "[guard_heap] underflow before {:#x} (size {}): byte {} of the red zone is {:#x}",
+            addr,
+            layout.size(),
+            pos,
+            front_zone[pos]
+        );
+    }
+    let page_table = PageTable::from_token(pool.token);
+    for va in (first_page..slot_end(idx)).step_by(PAGE_SIZE) {
+        *page_table.find_pte(VirtAddr::from(va).floor()).unwrap() = PageTableEntry::empty();
+        flush_tlb(va);
+    }
+    pool.slots[idx].addr = 0;
+    true
+}
+
+/// Explain a kernel page fault at `stval` if it hit the guard area
+pub fn report_fault(stval: usize) {
+    if !(GUARD_AREA_BASE..GUARD_AREA_BASE + AREA_SIZE).contains(&stval) {
+        return;
+    }
+    if stval < GUARD_AREA_BASE + PAGE_SIZE {
+        error!("[guard_heap] {:#x} is before the first slot", stval);
+        return;
+    }
+    // 缺页时可能正持有锁（例如在 dealloc 中检查红区），此时只报告槽位
+    let idx = (stval - GUARD_AREA_BASE - PAGE_SIZE) / SLOT_STRIDE;
+    let addr = POOL.try_lock().map(|pool| pool.slots[idx].addr);
+    match addr {
+        Some(0) => error!(
+            "[guard_heap] {:#x}: use after free in slot {} [{:#x}, {:#x})",
+            stval,
+            idx,
+            slot_start(idx),
+            slot_end(idx)
+        ),
+        Some(addr) if stval >= slot_end(idx) => error!(
+            "[guard_heap] {:#x}: overflow past the end of the allocation at {:#x}",
+            stval, addr
+        ),
+        Some(addr) => error!(
+            "[guard_heap] {:#x}: underflow before the allocation at {:#x}",
+            stval, addr
+        ),
+        None => error!("[guard_heap] {:#x}: fault in slot {}", stval, idx),
+    }
+}
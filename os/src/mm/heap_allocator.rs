@@ -1,12 +1,21 @@
 //! The heap allocator.
 
+#[cfg(not(feature = "debug_heap"))]
 use buddy_system_allocator::LockedHeap;
 
+#[cfg(feature = "debug_heap")]
+use super::debug_heap::DebugHeap;
 use crate::config::KERNEL_HEAP_SIZE;
 
+#[cfg(not(feature = "debug_heap"))]
 #[global_allocator]
 static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
 
+/// 带红区、内存毒化和分配记录的调试堆
+#[cfg(feature = "debug_heap")]
+#[global_allocator]
+pub(super) static HEAP_ALLOCATOR: DebugHeap = DebugHeap::empty();
+
 #[alloc_error_handler]
 pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
     panic!("Heap allocation error, layout = {:?}", layout);
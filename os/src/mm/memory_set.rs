@@ -11,6 +11,7 @@ use core::{
     arch::asm,
     fmt::{Display, Formatter},
     ptr,
+    sync::atomic::{AtomicPtr, Ordering},
 };
 
 use lazy_static::*;
@@ -19,8 +20,11 @@ use riscv::register::{satp, sstatus};
 use super::{
     config::*,
     frame_alloc,
+    frame_alloc_charged,
+    page_cache::{self, CachedPage},
     translated_refmut,
     FrameTracker,
+    KernelAddr,
     PTEFlags,
     PageTable,
     PageTableEntry,
@@ -33,20 +37,27 @@ use super::{
 use crate::{
     boards::CLOCK_FREQ,
     config::{
+        ELF_DYN_BASE,
+        INTERP_BASE,
         KERNEL_SPACE_OFFSET,
+        MAX_HARTS,
         MEMORY_END,
         MMAP_BASE,
         MMIO,
         PAGE_SIZE,
         PAGE_SIZE_BITS,
-        USER_STACK_SIZE,
         USER_TRAMPOLINE,
     },
-    fs::{defs::OpenFlags, ROOT_INODE},
+    fs::{defs::OpenFlags, inode::Inode, open_file, root_dentry},
     mm::config::AT_PHENT,
-    sync::UPSafeCell,
-    syscall::errno::SUCCESS,
-    task::process::Flags,
+    sync::SpinNoIrqLock,
+    syscall::errno::{EACCES, EINVAL, ELIBBAD, ENOEXEC, ENOMEM, SUCCESS},
+    task::{
+        cgroup::Cgroup,
+        hart_id,
+        process::{Flags, MmapProt},
+        try_current_task,
+    },
     utils::string::c_ptr_to_string,
 };
 
@@ -63,17 +74,69 @@ extern "C" {
     fn sbss_with_stack();
     fn ebss();
     fn ekernel();
+    fn ssigreturn();
 }
 
 lazy_static! {
     /// The kernel's initial memory mapping(kernel address space)
-    pub static ref KERNEL_SPACE: Arc<UPSafeCell<MemorySet>> =
-        Arc::new(unsafe { UPSafeCell::new(MemorySet::new_kernel()) });
+    pub static ref KERNEL_SPACE: Arc<SpinNoIrqLock<MemorySet>> =
+        Arc::new(SpinNoIrqLock::new(MemorySet::new_kernel()));
 }
 
 /// the kernel token
 pub fn kernel_token() -> usize {
-    KERNEL_SPACE.exclusive_access(file!(), line!()).token()
+    KERNEL_SPACE.lock().token()
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_LENT: AtomicPtr<MemorySet> = AtomicPtr::new(ptr::null_mut());
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CGROUP: AtomicPtr<Arc<Cgroup>> = AtomicPtr::new(ptr::null_mut());
+/// address space each hart lends to page faults on user addresses, see [`lend_user_space`]
+static LENT_SPACE: [AtomicPtr<MemorySet>; MAX_HARTS] = [NOT_LENT; MAX_HARTS];
+/// cgroup charged for frames mapped in the lent address space
+static LENT_CGROUP: [AtomicPtr<Arc<Cgroup>>; MAX_HARTS] = [NO_CGROUP; MAX_HARTS];
+
+/// Run `f`, which touches the current task's user memory, while holding the lock of
+/// its address space `space`
+///
+/// `f` 里的缺页由 [`handle_user_fault`] 借用调用者的守卫处理，不再去拿那把锁。
+pub fn lend_user_space<R>(space: &mut MemorySet, cgroup: &Arc<Cgroup>, f: impl FnOnce() -> R) -> R {
+    let hart = hart_id() % MAX_HARTS;
+    let old_space = LENT_SPACE[hart].swap(space, Ordering::Relaxed);
+    let old_cgroup = LENT_CGROUP[hart].swap(cgroup as *const _ as *mut _, Ordering::Relaxed);
+    let ret = f();
+    LENT_SPACE[hart].store(old_space, Ordering::Relaxed);
+    LENT_CGROUP[hart].store(old_cgroup, Ordering::Relaxed);
+    ret
+}
+
+/// Resolve a page fault at `va` of the current task, whose address space must be
+/// `token`
+///
+/// 内核访问用户内存时若持有当前任务 inner 或地址空间的锁，必须用 [`lend_user_space`]
+/// 把地址空间借出来，否则缺页无法处理。
+pub fn handle_user_fault(token: usize, va: VirtAddr, write: bool) -> bool {
+    let hart = hart_id() % MAX_HARTS;
+    let lent = LENT_SPACE[hart].load(Ordering::Relaxed);
+    if !lent.is_null() {
+        // 调用者把守卫的独占借用交给了 lend_user_space，它返回前没有别人用这个地址空间
+        let (space, cgroup) = unsafe { (&mut *lent, &*LENT_CGROUP[hart].load(Ordering::Relaxed)) };
+        return space.token() == token && space.page_fault(va.floor(), write, cgroup);
+    }
+    let Some(task) = try_current_task() else {
+        return false;
+    };
+    let Some(inner) = task.inner_unless_held() else {
+        return false;
+    };
+    let (space, cgroup) = (inner.memory_set.clone(), inner.cgroup.clone());
+    drop(inner);
+    // CLONE_VM 的线程可能在别的 hart 上持有这个地址空间，等它释放
+    let Some(mut space) = space.lock_unless_held() else {
+        return false;
+    };
+    space.token() == token && space.page_fault(va.floor(), write, &cgroup)
 }
 
 /// address space
@@ -83,28 +146,70 @@ pub struct MemorySet {
     /// areas
     pub areas:      Vec<MapArea>,
     /// heap
-    heap_area:      BTreeMap<VirtPageNum, FrameTracker>,
+    heap_area:      BTreeMap<VirtPageNum, Arc<FrameTracker>>,
     // The memory area formed by mmap does not need to be modified
     // we can use MapArea in Vec to hold FramTracker
     // we set a fixed address as the start address for mmap_area
     // the virtual memorySet is big enough to use it that doesnt concern address conflicts
-    pub mmap_area:  BTreeMap<VirtPageNum, FrameTracker>,
+    pub mmap_area:  BTreeMap<VirtPageNum, Arc<FrameTracker>>,
     // mmap_base will never change
     pub mmap_base:  VirtAddr,
     // always aligh to PAGE_SIZE
     pub mmap_end:   VirtAddr,
+    /// start of the heap, above the user stack of the program
+    pub heap_base:  VirtAddr,
+    /// program break, moved by brk
+    pub heap_end:   VirtAddr,
+    /// ranges reserved by brk and anonymous mmap, their pages get frames on first touch
+    lazy_areas:     Vec<LazyArea>,
+    /// pages of `MAP_SHARED` file mappings, in the mmap region too
+    shared_area:    BTreeMap<VirtPageNum, SharedPage>,
+}
+
+/// A page of a `MAP_SHARED` file mapping
+#[derive(Clone)]
+struct SharedPage {
+    page:      Arc<CachedPage>,
+    /// the file was opened for writing, mprotect may make the page writable
+    may_write: bool,
+}
+
+/// A range of the heap or the mmap region without frames until its pages are touched
+#[derive(Copy, Clone, Debug)]
+struct LazyArea {
+    start:  VirtPageNum,
+    end:    VirtPageNum,
+    region: PrivateRegion,
+    /// flags the pages are mapped with, changed by mprotect
+    perm:   PTEFlags,
+}
+
+/// A frame for a copy of `src`, charged to the same cgroup
+fn alloc_like(src: &FrameTracker) -> Option<FrameTracker> {
+    match &src.charged {
+        Some(cgroup) => {
+            // 写时复制出的页不受限额约束，但照常计入
+            cgroup.charge(1);
+            frame_alloc_charged(cgroup)
+        }
+        None => frame_alloc(),
+    }
 }
 
 impl MemorySet {
     /// Create a new empty `MemorySet`.
     pub fn new_bare() -> Self {
         Self {
-            page_table: PageTable::new(),
-            areas:      Vec::new(),
-            heap_area:  BTreeMap::new(),
-            mmap_area:  BTreeMap::new(),
-            mmap_base:  MMAP_BASE.into(),
-            mmap_end:   MMAP_BASE.into(),
+            page_table:  PageTable::new(),
+            areas:       Vec::new(),
+            heap_area:   BTreeMap::new(),
+            mmap_area:   BTreeMap::new(),
+            mmap_base:   MMAP_BASE.into(),
+            mmap_end:    MMAP_BASE.into(),
+            heap_base:   0.into(),
+            heap_end:    0.into(),
+            lazy_areas:  Vec::new(),
+            shared_area: BTreeMap::new(),
         }
     }
     /// Create a new `MemorySet` with the same page table as the kernel.
@@ -118,7 +223,23 @@ impl MemorySet {
             mmap_area: BTreeMap::new(),
             mmap_base: MMAP_BASE.into(),
             mmap_end: MMAP_BASE.into(),
+            heap_base: 0.into(),
+            heap_end: 0.into(),
+            lazy_areas: Vec::new(),
+            shared_area: BTreeMap::new(),
         }
+        .with_sigreturn_trampoline()
+    }
+    /// Map the kernel page holding the sigreturn trampoline at [`USER_TRAMPOLINE`]
+    ///
+    /// 页帧属于内核代码段，不放进 areas，fork 时也不复制，每个新地址空间各自映射一次。
+    fn with_sigreturn_trampoline(mut self) -> Self {
+        self.page_table.map(
+            VirtAddr::from(USER_TRAMPOLINE).into(),
+            PhysPageNum::from(KernelAddr::from(ssigreturn as usize)),
+            PTEFlags::R | PTEFlags::X | PTEFlags::U,
+        );
+        self
     }
     /// Get he page table token
     pub fn token(&self) -> usize {
@@ -142,6 +263,33 @@ impl MemorySet {
             Some(data),
         );
     }
+    /// Map a framed area whose frames are charged to `cgroup`, ENOMEM if that would go
+    /// over `memory.max` or the frames run out
+    pub fn insert_charged_area(
+        &mut self, start_va: VirtAddr, end_va: VirtAddr, permission: MapPermission,
+        cgroup: &Arc<Cgroup>,
+    ) -> Result<(), isize> {
+        let mut map_area = MapArea::new(start_va, end_va, MapType::Framed, permission);
+        let pages = map_area.vpn_range.get_end().0 - map_area.vpn_range.get_start().0;
+        if !cgroup.try_charge(pages) {
+            return Err(ENOMEM);
+        }
+        let flags = PTEFlags::from_bits(permission.bits() as u16).unwrap();
+        for (mapped, vpn) in map_area.vpn_range.into_iter().enumerate() {
+            let Some(frame) = frame_alloc_charged(cgroup) else {
+                // 已分配的页帧随区域释放时退还计费，这一页 frame_alloc_charged 已经退还
+                cgroup.uncharge(pages - mapped - 1);
+                for &vpn in map_area.data_frames.keys() {
+                    self.page_table.unmap(vpn);
+                }
+                return Err(ENOMEM);
+            };
+            self.page_table.map(vpn, frame.ppn, flags);
+            map_area.data_frames.insert(vpn, Arc::new(frame));
+        }
+        self.areas.push(map_area);
+        Ok(())
+    }
     /// check if exist areas conflict with given virtial address
     pub fn is_conflict_with_va(&self, start_va: VirtAddr, end_va: VirtAddr) -> bool {
         self.areas
@@ -310,13 +458,22 @@ impl MemorySet {
     }
     /// Include sections in elf and trampoline and TrapContext and user stack,
     /// also returns user_sp_base and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize, usize, Vec<AuxHeader>) {
+    ///
+    /// 在程序之上留出 `stack_size` 的用户栈，堆从栈顶之上开始。
+    /// 程序不是 ELF 或者 PT_INTERP 段超出文件时返回 ENOEXEC，PT_INTERP 指定的动态链接器
+    /// 打不开或者不是 ELF 时返回打开它的错误（通常是 ENOENT）或 ELIBBAD。
+    pub fn from_elf(
+        elf_data: &[u8], stack_size: usize,
+    ) -> Result<(Self, usize, usize, Vec<AuxHeader>), isize> {
         let mut memory_set = Self::new_process();
         // map trampoline
         // memory_set.map_trampoline();
         // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
+        let elf = xmas_elf::ElfFile::new(elf_data).map_err(|_| ENOEXEC)?;
         let elf_header = elf.header;
+        // PIE 程序的段地址从 0 开始，需要加上加载偏移
+        let load_bias = Self::elf_load_bias(&elf);
+        let entry = elf_header.pt2.entry_point() as usize + load_bias;
 
         // auxv
         let mut auxv = vec![
@@ -324,7 +481,7 @@ impl MemorySet {
             AuxHeader::new(AT_PHNUM, elf_header.pt2.ph_count() as usize),
             AuxHeader::new(AT_PAGESIZE, PAGE_SIZE as usize),
             AuxHeader::new(AT_FLAGS, 0),
-            AuxHeader::new(AT_ENTRY, elf_header.pt2.entry_point() as usize),
+            AuxHeader::new(AT_ENTRY, entry),
             AuxHeader::new(AT_UID, 0),
             AuxHeader::new(AT_EUID, 0),
             AuxHeader::new(AT_GID, 0),
@@ -336,162 +493,339 @@ impl MemorySet {
             AuxHeader::new(AT_NOELF, 0x112d),
         ];
 
-        let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
-        let ph_count = elf_header.pt2.ph_count();
-        let mut max_end_vpn = VirtPageNum(0);
-        let mut head_va: usize = 0;
+        let max_end_vpn = memory_set.map_elf(&elf, load_bias);
         let mut interp_entry: Option<usize> = None;
         let mut interp_base: Option<usize> = None;
+        let mut phdr: Option<usize> = None;
 
-        for i in 0..ph_count {
-            let ph = elf.program_header(i).unwrap();
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
-                let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
-                let page_offset = start_va.page_offset();
-                let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
-                let mut map_perm = MapPermission::U;
-                let ph_flags = ph.flags();
-                if head_va == 0 {
-                    head_va = start_va.0;
-                }
-                if ph_flags.is_read() {
-                    map_perm |= MapPermission::R;
-                }
-                if ph_flags.is_write() {
-                    map_perm |= MapPermission::W;
+        for ph in elf.program_iter() {
+            match ph.get_type() {
+                Ok(xmas_elf::program::Type::Phdr) => {
+                    phdr = Some(ph.virtual_addr() as usize + load_bias);
                 }
-                if ph_flags.is_execute() {
-                    map_perm |= MapPermission::X;
+                Ok(xmas_elf::program::Type::Interp) => {
+                    // 段越界时是坏的 ELF，不能让它打出 panic
+                    let path = ph
+                        .offset()
+                        .checked_add(ph.file_size())
+                        .and_then(|end| elf.input.get(ph.offset() as usize..end as usize))
+                        .ok_or(ENOEXEC)?;
+                    let path = String::from_utf8_lossy(path);
+                    let path = path.trim_end_matches('\0');
+                    let (entry, base) = memory_set.load_interp(path).map_err(|err| {
+                        error!("[from_elf] cannot load interpreter {}", path);
+                        err
+                    })?;
+                    interp_entry = Some(entry);
+                    interp_base = Some(base);
                 }
-                let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
-                max_end_vpn = map_area.vpn_range.get_end();
-
-                if page_offset == 0 {
-                    memory_set.push(
-                        map_area,
-                        Some(
-                            &elf.input
-                                [ph.offset() as usize..(ph.offset() + ph.file_size()) as usize],
-                        ),
-                    )
-                } else {
-                    memory_set.push_with_offset(
-                        map_area,
-                        page_offset,
-                        Some(
-                            &elf.input
-                                [ph.offset() as usize..(ph.offset() + ph.file_size()) as usize],
-                        ),
-                    );
-                }
-            } else if ph.get_type().unwrap() == xmas_elf::program::Type::Interp {
-                // // log!("[from_elf] .interp")
-                // let mut path = String::from_utf8_lossy(
-                //     &elf.input[ph.offset() as usize..(ph.offset() + ph.file_size() - 1) as usize],
-                // )
-                // .to_string();
-                // match ROOT_INODE.open(&path, OpenFlags::O_RDONLY, false) {
-                //     Ok(file) => {
-                //         // let elf_data = file.read_all();
-                //         let elf_data = file.map_to_kernel_space(SECOND_MMAP_BASE);
-                //         let (entry, base) = memory_set.load_interp(elf_data);
-                //         crate::mm::KERNEL_SPACE
-                //             .exclusive_access()
-                //             .remove_area_with_start_vpn(VirtAddr::from(SECOND_MMAP_BASE).floor());
-                //         interp_entry = Some(entry);
-                //         interp_base = Some(base);
-                //     }
-                //     Err(errno) => {
-                //         panic!("[from_elf] Unkonwn interpreter path = {}", path);
-                //     }
-                // }
-                todo!("interpreter not supported yet");
+                _ => {}
             }
         }
 
-        if let Some(base) = interp_base {
-            auxv.push(AuxHeader::new(AT_BASE, base));
-        } else {
-            auxv.push(AuxHeader::new(AT_BASE, 0));
-        }
-
-        auxv.push(AuxHeader::new(
-            AT_PHDR,
-            head_va + elf_header.pt2.ph_offset() as usize,
-        ));
+        auxv.push(AuxHeader::new(AT_BASE, interp_base.unwrap_or(0)));
+        // 没有 PT_PHDR 时，程序头表在包含它的文件偏移的 LOAD 段中
+        let phdr = phdr.or_else(|| {
+            let ph_offset = elf_header.pt2.ph_offset();
+            elf.program_iter()
+                .filter(|ph| ph.get_type() == Ok(xmas_elf::program::Type::Load))
+                .find(|ph| ph.offset() <= ph_offset && ph_offset < ph.offset() + ph.file_size())
+                .map(|ph| (ph.virtual_addr() + ph_offset - ph.offset()) as usize + load_bias)
+        });
+        auxv.push(AuxHeader::new(AT_PHDR, phdr.unwrap_or(0)));
 
         // map user stack with U flags
         let max_end_va: VirtAddr = max_end_vpn.into();
         let mut user_stack_bottom: usize = max_end_va.into();
         user_stack_bottom += PAGE_SIZE;
-        let user_stack_top: usize = user_stack_bottom + USER_STACK_SIZE;
+        let user_stack_top: usize = user_stack_bottom + stack_size;
         debug!("user_stack_bottom: {:#x}", user_stack_bottom);
-        let user_heap_base: usize = user_stack_top + PAGE_SIZE;
+        memory_set.heap_base = (user_stack_top + PAGE_SIZE).into();
+        memory_set.heap_end = memory_set.heap_base;
         debug!("elf read completed!");
-        (
-            memory_set,
-            user_heap_base,
-            user_stack_top,
-            elf.header.pt2.entry_point() as usize,
-            auxv,
-        )
-    }
-    /// Create a new address space by copy code&data from a exited process's address space.
-    pub fn from_existed_user(user_space: &Self) -> Self {
+        let entry = interp_entry.unwrap_or(entry);
+        Ok((memory_set, user_stack_top, entry, auxv))
+    }
+    /// Map the LOAD segments of `elf` at `load_bias`, returning the end of the last one
+    fn map_elf(&mut self, elf: &xmas_elf::ElfFile, load_bias: usize) -> VirtPageNum {
+        let mut max_end_vpn = VirtPageNum(0);
+        for ph in elf.program_iter() {
+            if ph.get_type() != Ok(xmas_elf::program::Type::Load) {
+                continue;
+            }
+            let start_va: VirtAddr = (ph.virtual_addr() as usize + load_bias).into();
+            let page_offset = start_va.page_offset();
+            let end_va: VirtAddr =
+                ((ph.virtual_addr() + ph.mem_size()) as usize + load_bias).into();
+            let mut map_perm = MapPermission::U;
+            let ph_flags = ph.flags();
+            if ph_flags.is_read() {
+                map_perm |= MapPermission::R;
+            }
+            if ph_flags.is_write() {
+                map_perm |= MapPermission::W;
+            }
+            if ph_flags.is_execute() {
+                map_perm |= MapPermission::X;
+            }
+            let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
+            max_end_vpn = map_area.vpn_range.get_end();
+            let data = &elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize];
+            if page_offset == 0 {
+                self.push(map_area, Some(data))
+            } else {
+                self.push_with_offset(map_area, page_offset, Some(data));
+            }
+        }
+        max_end_vpn
+    }
+    /// Map the dynamic linker at `path`, at [`INTERP_BASE`] if it is position-independent,
+    /// returning its entry point and load base; ELIBBAD if it is not an ELF file
+    fn load_interp(&mut self, path: &str) -> Result<(usize, usize), isize> {
+        let dentry = open_file(&root_dentry(), path, OpenFlags::O_RDONLY)?;
+        let elf_data = dentry.inode().read_all();
+        let elf = xmas_elf::ElfFile::new(&elf_data).map_err(|_| ELIBBAD)?;
+        let base = match elf.header.pt2.type_().as_type() {
+            xmas_elf::header::Type::SharedObject => INTERP_BASE,
+            _ => 0,
+        };
+        self.map_elf(&elf, base);
+        Ok((elf.header.pt2.entry_point() as usize + base, base))
+    }
+    /// Load bias of an elf: 0 for ET_EXEC, `ELF_DYN_BASE` (plus a random
+    /// page offset from the entropy pool with the `aslr` feature) for ET_DYN.
+    fn elf_load_bias(elf: &xmas_elf::ElfFile) -> usize {
+        match elf.header.pt2.type_().as_type() {
+            xmas_elf::header::Type::SharedObject => {
+                #[cfg(feature = "aslr")]
+                let offset = {
+                    let mut bytes = [0u8; core::mem::size_of::<usize>()];
+                    crate::drivers::random::fill(&mut bytes);
+                    (usize::from_le_bytes(bytes) % crate::config::ELF_DYN_ASLR_PAGES) * PAGE_SIZE
+                };
+                #[cfg(not(feature = "aslr"))]
+                let offset = 0;
+                ELF_DYN_BASE + offset
+            }
+            _ => 0,
+        }
+    }
+    /// Create a new address space for a fork of `user_space`, sharing its user pages
+    /// copy-on-write
+    pub fn from_existed_user(user_space: &mut Self) -> Self {
         let mut memory_set = Self::new_process();
         // map trampoline
         // memory_set.map_trampoline();
         // copy mmap
         memory_set.mmap_end = user_space.mmap_end;
-        // copy data sections/trap_context/user_stack
+        memory_set.heap_base = user_space.heap_base;
+        memory_set.heap_end = user_space.heap_end;
+        memory_set.lazy_areas = user_space.lazy_areas.clone();
+        // share data sections/user_stack, copy trap_context
         for area in user_space.areas.iter() {
             // skip kernel space, cause it's already mapped
             if area.vpn_range.get_start().0 > KERNEL_SPACE_OFFSET {
                 continue;
             }
-            let new_area = MapArea::from_another(area);
-            memory_set.push(new_area, None);
-            // copy data from another space
-            for vpn in area.vpn_range {
-                let src_ppn = user_space.translate(vpn).unwrap().ppn();
-                let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
-                dst_ppn
-                    .get_bytes_array()
-                    .copy_from_slice(src_ppn.get_bytes_array());
+            let mut new_area = MapArea::from_another(area);
+            if !area.map_perm.contains(MapPermission::U) {
+                // 中断上下文由内核在陷入时直接写入，不能写时复制
+                memory_set.push(new_area, None);
+                // copy data from another space
+                for vpn in area.vpn_range {
+                    let src_ppn = user_space.translate(vpn).unwrap().ppn();
+                    let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
+                    dst_ppn
+                        .get_bytes_array()
+                        .copy_from_slice(src_ppn.get_bytes_array());
+                }
+                continue;
+            }
+            for (vpn, frame) in area.data_frames.iter() {
+                let flags = user_space.page_table.share_cow(*vpn);
+                memory_set.page_table.map(*vpn, frame.ppn, flags);
+                new_area.data_frames.insert(*vpn, frame.clone());
             }
+            memory_set.areas.push(new_area);
+        }
+        // share heap_area
+        for (vpn, frame) in user_space.heap_area.iter() {
+            let flags = user_space.page_table.share_cow(*vpn);
+            memory_set.page_table.map(*vpn, frame.ppn, flags);
+            memory_set.heap_area.insert(*vpn, frame.clone());
         }
-        // copy heap_area
-        for (vpn, src_frame) in user_space.heap_area.iter() {
-            let dst_frame = frame_alloc().unwrap();
-            let dst_ppn = dst_frame.ppn;
+        // share mmap_area
+        for (vpn, frame) in user_space.mmap_area.iter() {
+            let flags = user_space.page_table.share_cow(*vpn);
+            memory_set.page_table.map(*vpn, frame.ppn, flags);
+            memory_set.mmap_area.insert(*vpn, frame.clone());
+        }
+        // 共享映射在子进程中仍然共享
+        for (vpn, shared) in user_space.shared_area.iter() {
+            let flags = user_space.translate(*vpn).unwrap().flags();
             memory_set
                 .page_table
-                .map(*vpn, dst_ppn, PTEFlags::U | PTEFlags::R | PTEFlags::W);
-            memory_set.heap_area.insert(*vpn, dst_frame);
+                .map(*vpn, shared.page.frame.ppn, flags);
+            memory_set.shared_area.insert(*vpn, shared.clone());
+        }
+        // 父进程的页表正在使用，去掉的写权限要立即生效
+        unsafe {
+            asm!("sfence.vma");
+        }
+        memory_set
+    }
 
-            let src_ppn = src_frame.ppn;
-            // copy data
-            dst_ppn
-                .get_bytes_array()
-                .copy_from_slice(src_ppn.get_bytes_array());
+    /// Resolve a page fault at `vpn`, false if it is a real access violation
+    ///
+    /// 未分配页帧的保留页在第一次访问时分配（计入 `cgroup`），写时复制页在写入时复制。
+    pub fn page_fault(&mut self, vpn: VirtPageNum, write: bool, cgroup: &Arc<Cgroup>) -> bool {
+        if write && self.cow_fault(vpn) {
+            return true;
         }
-        // copy mmap_area
-        for (vpn, src_frame) in user_space.mmap_area.iter() {
-            let dst_frame = frame_alloc().unwrap();
-            let dst_ppn = dst_frame.ppn;
-            memory_set
-                .page_table
-                .map(*vpn, dst_ppn, PTEFlags::U | PTEFlags::R | PTEFlags::W);
-            memory_set.mmap_area.insert(*vpn, dst_frame);
+        let Some(area) = self
+            .lazy_areas
+            .iter()
+            .find(|area| area.start <= vpn && vpn < area.end)
+        else {
+            return false;
+        };
+        // mprotect 成不可访问的保留页不必分配
+        if !area.perm.contains(PTEFlags::U)
+            || self.translate(vpn).map_or(false, |pte| pte.is_valid())
+        {
+            return false;
+        }
+        self.map_private_frame(area.region, vpn, area.perm, cgroup)
+            .is_ok()
+    }
+
+    /// Reserve `start..end` of the heap or the mmap region, mapped on first touch
+    pub fn reserve_private(&mut self, region: PrivateRegion, start: VirtPageNum, end: VirtPageNum) {
+        if start >= end {
+            return;
+        }
+        // 与相邻或重叠的同类区间合并
+        let (mut start, mut end) = (start, end);
+        let perm = region.flags();
+        self.lazy_areas.retain(|area| {
+            let touches = area.region == region
+                && area.perm == perm
+                && area.start <= end
+                && start <= area.end;
+            if touches {
+                start = start.min(area.start);
+                end = end.max(area.end);
+            }
+            !touches
+        });
+        self.lazy_areas.push(LazyArea {
+            start,
+            end,
+            region,
+            perm,
+        });
+    }
+
+    /// Drop `start..end` from the reserved ranges of `region`
+    fn release_private(&mut self, region: PrivateRegion, start: VirtPageNum, end: VirtPageNum) {
+        let mut kept = Vec::new();
+        for area in self.lazy_areas.drain(..) {
+            if area.region != region || area.end <= start || end <= area.start {
+                kept.push(area);
+                continue;
+            }
+            if area.start < start {
+                kept.push(LazyArea { end: start, ..area });
+            }
+            if end < area.end {
+                kept.push(LazyArea { start: end, ..area });
+            }
+        }
+        self.lazy_areas = kept;
+    }
+
+    /// Set the flags of the reserved pages in `start..end` of both regions to `perm`
+    fn protect_reserved(&mut self, start: VirtPageNum, end: VirtPageNum, perm: PTEFlags) {
+        let mut kept = Vec::new();
+        for area in self.lazy_areas.drain(..) {
+            if area.end <= start || end <= area.start {
+                kept.push(area);
+                continue;
+            }
+            if area.start < start {
+                kept.push(LazyArea { end: start, ..area });
+            }
+            kept.push(LazyArea {
+                start: area.start.max(start),
+                end: area.end.min(end),
+                perm,
+                ..area
+            });
+            if end < area.end {
+                kept.push(LazyArea { start: end, ..area });
+            }
+        }
+        self.lazy_areas = kept;
+    }
+
+    /// Whether `vpn` is reserved to be mapped on first touch
+    fn is_reserved(&self, vpn: VirtPageNum) -> bool {
+        self.lazy_areas
+            .iter()
+            .any(|area| area.start <= vpn && vpn < area.end)
+    }
 
-            let src_ppn = src_frame.ppn;
-            // copy data
-            dst_ppn
+    /// Ranges of the heap or the mmap region mapped on first touch
+    pub fn reserved_ranges(
+        &self, region: PrivateRegion,
+    ) -> impl Iterator<Item = (VirtPageNum, VirtPageNum)> + '_ {
+        self.lazy_areas
+            .iter()
+            .filter(move |area| area.region == region)
+            .map(|area| (area.start, area.end))
+    }
+
+    /// Make the copy-on-write page at `vpn` writable, copying its frame if it is still shared
+    ///
+    /// Returns false if the page is not copy-on-write or no frame is left for the copy.
+    pub fn cow_fault(&mut self, vpn: VirtPageNum) -> bool {
+        let Some(pte) = self.page_table.find_pte(vpn) else {
+            return false;
+        };
+        if !pte.is_valid() || !pte.flags().contains(PTEFlags::COW) {
+            return false;
+        }
+        let frame = if let Some(frame) = self.heap_area.get_mut(&vpn) {
+            frame
+        } else if let Some(frame) = self.mmap_area.get_mut(&vpn) {
+            frame
+        } else if let Some(frame) = self
+            .areas
+            .iter_mut()
+            .find_map(|area| area.data_frames.get_mut(&vpn))
+        {
+            frame
+        } else {
+            return false;
+        };
+        // 其他地址空间都已放弃这一页时不必复制
+        if Arc::strong_count(frame) > 1 {
+            let Some(copy) = alloc_like(frame) else {
+                return false;
+            };
+            copy.ppn
                 .get_bytes_array()
-                .copy_from_slice(src_ppn.get_bytes_array());
+                .copy_from_slice(frame.ppn.get_bytes_array());
+            *frame = Arc::new(copy);
         }
-        memory_set
+        let mut flags = pte.flags();
+        flags.remove(PTEFlags::COW);
+        flags.insert(PTEFlags::W);
+        *pte = PageTableEntry::new(frame.ppn, flags);
+        unsafe {
+            asm!("sfence.vma {}", in(reg) VirtAddr::from(vpn).0);
+        }
+        true
     }
     /// Change page table by writing satp CSR Register.
     pub fn activate(&self) {
@@ -509,9 +843,18 @@ impl MemorySet {
         self.page_table.translate(vpn)
     }
 
-    ///Remove all `MapArea`
+    ///Remove all `MapArea`, writing shared file mappings back
     pub fn recycle_data_pages(&mut self) {
         self.areas.clear();
+        for (vpn, shared) in core::mem::take(&mut self.shared_area) {
+            shared.page.sync();
+            self.page_table.unmap(vpn);
+        }
+    }
+
+    /// Whether there are `MAP_SHARED` file mappings
+    pub fn has_shared_pages(&self) -> bool {
+        !self.shared_area.is_empty()
     }
 
     /// shrink the area to new_end
@@ -544,59 +887,139 @@ impl MemorySet {
         }
     }
 
-    /// map new heap area
-    pub fn map_heap(&mut self, mut current_addr: VirtAddr, aim_addr: VirtAddr) -> isize {
+    /// Areas user code can access: ELF segments and user stacks, not trap contexts
+    pub fn user_areas(&self) -> impl Iterator<Item = &MapArea> {
+        self.areas
+            .iter()
+            .filter(|area| area.map_perm.contains(MapPermission::U))
+    }
+
+    /// Pages user code can access, mapped or reserved, which RLIMIT_AS limits
+    pub fn vm_pages(&self) -> usize {
+        let areas: usize = self
+            .user_areas()
+            .map(|area| area.vpn_range.get_end().0 - area.vpn_range.get_start().0)
+            .sum();
+        let reserved: usize = self
+            .lazy_areas
+            .iter()
+            .map(|area| area.end.0 - area.start.0)
+            .sum();
+        // 匿名页的页帧也在保留区间里，不重复计算
+        let mapped = self
+            .heap_area
+            .keys()
+            .chain(self.mmap_area.keys())
+            .chain(self.shared_area.keys())
+            .filter(|vpn| !self.is_reserved(**vpn))
+            .count();
+        areas + reserved + mapped
+    }
+
+    /// Whether `pages` more pages keep the address space within `limit` bytes
+    pub fn fits_vm(&self, pages: usize, limit: usize) -> bool {
+        self.vm_pages() + pages <= limit / PAGE_SIZE
+    }
+
+    /// Pages of the heap or the mmap region with their frames
+    pub fn private_pages(
+        &self, region: PrivateRegion,
+    ) -> impl Iterator<Item = (VirtPageNum, PhysPageNum)> + '_ {
+        let pages = match region {
+            PrivateRegion::Heap => &self.heap_area,
+            PrivateRegion::Mmap => &self.mmap_area,
+        };
+        pages.iter().map(|(vpn, frame)| (*vpn, frame.ppn))
+    }
+
+    /// Map a fresh page into the heap or the mmap region, charged to `cgroup`
+    pub fn map_private_page(
+        &mut self, region: PrivateRegion, vpn: VirtPageNum, cgroup: &Arc<Cgroup>,
+    ) -> Result<PhysPageNum, isize> {
+        self.map_private_frame(region, vpn, region.flags(), cgroup)
+    }
+
+    fn map_private_frame(
+        &mut self, region: PrivateRegion, vpn: VirtPageNum, flags: PTEFlags, cgroup: &Arc<Cgroup>,
+    ) -> Result<PhysPageNum, isize> {
+        if !cgroup.try_charge(1) {
+            return Err(ENOMEM);
+        }
+        let frame = frame_alloc_charged(cgroup).ok_or(ENOMEM)?;
+        let ppn = frame.ppn;
+        self.page_table.map(vpn, ppn, flags);
+        match region {
+            PrivateRegion::Heap => self.heap_area.insert(vpn, Arc::new(frame)),
+            PrivateRegion::Mmap => self.mmap_area.insert(vpn, Arc::new(frame)),
+        };
+        Ok(ppn)
+    }
+
+    /// Grow the heap to `aim_addr`, its pages are mapped on first touch and charged to
+    /// `cgroup` then
+    pub fn map_heap(
+        &mut self, current_addr: VirtAddr, aim_addr: VirtAddr, cgroup: &Arc<Cgroup>,
+    ) -> isize {
         // log!("[map_heap] start_addr = {:#x}, end_addr = {:#x}", current_addr.0, aim_addr.0);
-        loop {
-            if current_addr.0 >= aim_addr.0 {
-                break;
-            }
-            // We use BTreeMap to save FrameTracker which makes management quite easy
-            // alloc a new FrameTracker
-            let frame = frame_alloc().unwrap();
-            let ppn = frame.ppn;
-            let vpn: VirtPageNum = current_addr.floor();
-            // log!("[map_heap] map vpn = {:#x}, ppn = {:#x}", vpn.0, ppn.0);
-            self.page_table
-                .map(vpn, ppn, PTEFlags::U | PTEFlags::R | PTEFlags::W);
-            self.heap_area.insert(vpn, frame);
-            current_addr = VirtAddr::from(current_addr.0 + PAGE_SIZE);
+        let pages = (aim_addr.0.saturating_sub(current_addr.0) + PAGE_SIZE - 1) / PAGE_SIZE;
+        if !cgroup.fits(pages) {
+            return ENOMEM;
         }
+        self.reserve_private(PrivateRegion::Heap, current_addr.floor(), aim_addr.ceil());
         0
     }
 
-    /// mmap
+    /// mmap, the frames are charged to `cgroup`
+    ///
+    /// 匿名映射只记录区间，页帧在第一次访问时分配；文件映射已经读出了内容，立即分配并填入。
     pub fn mmap(
         &mut self, start_addr: usize, len: usize, offset: usize, context: Vec<u8>, flags: Flags,
+        cgroup: &Arc<Cgroup>,
     ) -> isize {
-        let start_addr_align: usize;
-        let end_addr_align: usize;
-        if flags.contains(Flags::MAP_FIXED) && start_addr != 0 {
-            // MAP_FIXED
-            // alloc page one by one
-            start_addr_align = ((start_addr) + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
-            end_addr_align = ((start_addr + len) + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
-        } else {
-            start_addr_align = ((self.mmap_end.0) + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
-            end_addr_align = ((self.mmap_end.0 + len) + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
-        }
-        self.mmap_end = (end_addr_align + PAGE_SIZE).into();
+        let fixed = flags.contains(Flags::MAP_FIXED) && start_addr != 0;
+        let (start_addr_align, end_addr_align) = self.mmap_range(start_addr, len, fixed);
         let vpn_range = VPNRange::new(
             VirtAddr::from(start_addr_align).floor(),
             VirtAddr::from(end_addr_align).floor(),
         );
-        if flags.contains(Flags::MAP_FIXED) && start_addr != 0 {
+        if fixed {
+            // 原来的共享映射先解除，下面按私有页处理
+            self.unmap_shared(vpn_range);
+        }
+        let pages = vpn_range
+            .into_iter()
+            .filter(|vpn| !fixed || !self.mmap_area.contains_key(vpn))
+            .count();
+        if flags.contains(Flags::MAP_ANONYMOUS) {
+            if !cgroup.fits(pages) {
+                return ENOMEM;
+            }
+            self.mmap_end = (end_addr_align + PAGE_SIZE).into();
+            self.reserve_private(
+                PrivateRegion::Mmap,
+                vpn_range.get_start(),
+                vpn_range.get_end(),
+            );
+            return start_addr_align as isize;
+        }
+        if !cgroup.try_charge(pages) {
+            return ENOMEM;
+        }
+        self.mmap_end = (end_addr_align + PAGE_SIZE).into();
+        if fixed {
             // alloc memory
             for vpn in vpn_range {
                 // let frame = frame_alloc().unwrap();
                 match self.mmap_area.get(&vpn) {
                     Some(_) => {
                         debug!("[mmap] vpn = {:#x} has been mapped, skip", vpn.0);
+                        // 下面直接按物理页写入文件内容，不能写到共享的页帧里
+                        self.cow_fault(vpn);
                     }
                     None => {
-                        let frame = frame_alloc().unwrap();
+                        let frame = frame_alloc_charged(cgroup).unwrap();
                         let ppn = frame.ppn;
-                        self.mmap_area.insert(vpn, frame);
+                        self.mmap_area.insert(vpn, Arc::new(frame));
                         self.page_table.map(
                             vpn,
                             ppn,
@@ -608,9 +1031,9 @@ impl MemorySet {
         } else {
             // alloc memory
             for vpn in vpn_range {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc_charged(cgroup).unwrap();
                 let ppn = frame.ppn;
-                self.mmap_area.insert(vpn, frame);
+                self.mmap_area.insert(vpn, Arc::new(frame));
                 self.page_table.map(
                     vpn,
                     ppn,
@@ -625,25 +1048,22 @@ impl MemorySet {
             len
         );
 
-        // MAP_ANONYMOUS标志代表不与文件关联的匿名映射
-        if !flags.contains(Flags::MAP_ANONYMOUS) {
-            let mut start: usize = offset;
-            let mut current_vpn = vpn_range.get_start();
-            loop {
-                let src = &context[start..len.min(start + PAGE_SIZE)];
-                let dst = &mut self
-                    .page_table
-                    .translate(current_vpn)
-                    .unwrap()
-                    .ppn()
-                    .get_bytes_array()[..src.len()];
-                dst.copy_from_slice(src);
-                start += PAGE_SIZE;
-                if start >= len {
-                    break;
-                }
-                current_vpn.step();
+        let mut start: usize = offset;
+        let mut current_vpn = vpn_range.get_start();
+        loop {
+            let src = &context[start..len.min(start + PAGE_SIZE)];
+            let dst = &mut self
+                .page_table
+                .translate(current_vpn)
+                .unwrap()
+                .ppn()
+                .get_bytes_array()[..src.len()];
+            dst.copy_from_slice(src);
+            start += PAGE_SIZE;
+            if start >= len {
+                break;
             }
+            current_vpn.step();
         }
         debug!(
             "[mmap] start_addr_align = {:#x}, end_addr_align = {:#x}",
@@ -652,6 +1072,163 @@ impl MemorySet {
         start_addr_align as isize
     }
 
+    /// Page aligned `start..end` for a mapping of `len` bytes, at `start_addr` if `fixed`
+    fn mmap_range(&self, start_addr: usize, len: usize, fixed: bool) -> (usize, usize) {
+        let start = if fixed { start_addr } else { self.mmap_end.0 };
+        let start_addr_align = (start + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
+        let end_addr_align = ((start + len) + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
+        (start_addr_align, end_addr_align)
+    }
+
+    /// `MAP_SHARED` mmap of `inode` from `offset` with `prot`, the pages read from the
+    /// file are charged to `cgroup`
+    ///
+    /// 同一文件的共享映射使用同一组页帧，见 [`page_cache`](super::page_cache)。
+    /// `may_write` 表示文件以读写方式打开，否则不能映射为可写，之后也不能用 mprotect 改为可写。
+    pub fn mmap_shared(
+        &mut self, start_addr: usize, len: usize, offset: usize, inode: Arc<dyn Inode>,
+        prot: MmapProt, may_write: bool, fixed: bool, cgroup: &Arc<Cgroup>,
+    ) -> isize {
+        if offset % PAGE_SIZE != 0 {
+            return EINVAL;
+        }
+        if prot.contains(MmapProt::PROT_WRITE) && !may_write {
+            return EACCES;
+        }
+        let fixed = fixed && start_addr != 0;
+        let (start_addr_align, end_addr_align) = self.mmap_range(start_addr, len, fixed);
+        let vpn_range = VPNRange::new(
+            VirtAddr::from(start_addr_align).floor(),
+            VirtAddr::from(end_addr_align).floor(),
+        );
+        let mut pages = Vec::new();
+        for (i, _) in vpn_range.into_iter().enumerate() {
+            match page_cache::get_page(&inode, offset + i * PAGE_SIZE, cgroup) {
+                Ok(page) => pages.push(page),
+                Err(err) => return err,
+            }
+        }
+        if fixed {
+            self.munmap(start_addr_align, end_addr_align - start_addr_align);
+        }
+        self.mmap_end = self.mmap_end.0.max(end_addr_align + PAGE_SIZE).into();
+        let flags = prot.pte_flags();
+        for (vpn, page) in vpn_range.into_iter().zip(pages) {
+            if flags.contains(PTEFlags::W) {
+                page.allow_write();
+            }
+            self.page_table.map(vpn, page.frame.ppn, flags);
+            self.shared_area.insert(vpn, SharedPage { page, may_write });
+        }
+        start_addr_align as isize
+    }
+
+    /// Drop the shared file pages in `vpn_range`, writing them back
+    fn unmap_shared(&mut self, vpn_range: VPNRange) {
+        for vpn in vpn_range {
+            if let Some(shared) = self.shared_area.remove(&vpn) {
+                shared.page.sync();
+                self.page_table.unmap(vpn);
+            }
+        }
+    }
+
+    /// Write the shared file pages in `start_addr..start_addr + len` back
+    ///
+    /// ENOMEM if part of the range is not mapped.
+    pub fn msync(&self, start_addr: usize, len: usize) -> isize {
+        if start_addr % PAGE_SIZE != 0 {
+            return EINVAL;
+        }
+        let vpn_range = VPNRange::new(
+            VirtAddr::from(start_addr).floor(),
+            VirtAddr::from(start_addr + len).ceil(),
+        );
+        if vpn_range.into_iter().any(|vpn| {
+            !self.translate(vpn).map_or(false, |pte| pte.is_valid()) && !self.is_reserved(vpn)
+        }) {
+            return ENOMEM;
+        }
+        for vpn in vpn_range {
+            if let Some(shared) = self.shared_area.get(&vpn) {
+                shared.page.sync();
+            }
+        }
+        SUCCESS
+    }
+
+    /// Whether the frame of the user page at `vpn` may be shared copy-on-write, `None` if
+    /// no frame is mapped there
+    fn user_frame_shared(&self, vpn: VirtPageNum) -> Option<bool> {
+        if self.shared_area.contains_key(&vpn) {
+            return Some(false);
+        }
+        let frame = self
+            .heap_area
+            .get(&vpn)
+            .or_else(|| self.mmap_area.get(&vpn))
+            .or_else(|| {
+                self.user_areas()
+                    .find_map(|area| area.data_frames.get(&vpn))
+            })?;
+        Some(Arc::strong_count(frame) > 1)
+    }
+
+    /// mprotect, ENOMEM if part of the range is not mapped, EACCES if it makes a shared
+    /// mapping of a file not opened for writing writable
+    ///
+    /// 共享着页帧的页加上写权限后仍然写时复制；不可访问的页去掉 U 位，保留页帧和内容。
+    pub fn mprotect(&mut self, start_addr: usize, len: usize, prot: MmapProt) -> isize {
+        if start_addr % PAGE_SIZE != 0 {
+            return EINVAL;
+        }
+        let vpn_range = VPNRange::new(
+            VirtAddr::from(start_addr).floor(),
+            VirtAddr::from(start_addr + len).ceil(),
+        );
+        if vpn_range
+            .into_iter()
+            .any(|vpn| self.user_frame_shared(vpn).is_none() && !self.is_reserved(vpn))
+        {
+            return ENOMEM;
+        }
+        let flags = prot.pte_flags();
+        if flags.contains(PTEFlags::W)
+            && vpn_range.into_iter().any(|vpn| {
+                self.shared_area
+                    .get(&vpn)
+                    .map_or(false, |shared| !shared.may_write)
+            })
+        {
+            return EACCES;
+        }
+        for vpn in vpn_range {
+            let Some(shared) = self.user_frame_shared(vpn) else {
+                continue;
+            };
+            if flags.contains(PTEFlags::W) {
+                if let Some(shared) = self.shared_area.get(&vpn) {
+                    shared.page.allow_write();
+                }
+            }
+            let pte = self.page_table.find_pte(vpn).unwrap();
+            let mut page_flags = flags;
+            if flags.contains(PTEFlags::W) && (shared || pte.flags().contains(PTEFlags::COW)) {
+                page_flags.remove(PTEFlags::W);
+                page_flags.insert(PTEFlags::COW);
+            }
+            *pte = PageTableEntry::new(
+                pte.ppn(),
+                page_flags | PTEFlags::V | PTEFlags::A | PTEFlags::D,
+            );
+        }
+        self.protect_reserved(vpn_range.get_start(), vpn_range.get_end(), flags);
+        unsafe {
+            asm!("sfence.vma");
+        }
+        SUCCESS
+    }
+
     ///munmap
     pub fn munmap(&mut self, start_addr: usize, len: usize) -> isize {
         let start_addr_align = ((start_addr) + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
@@ -661,8 +1238,16 @@ impl MemorySet {
             VirtAddr::from(end_addr_align).floor(),
         );
         for vpn in vpn_range {
-            self.mmap_area.remove(&vpn);
+            if self.mmap_area.remove(&vpn).is_some() {
+                self.page_table.unmap(vpn);
+            }
         }
+        self.unmap_shared(vpn_range);
+        self.release_private(
+            PrivateRegion::Mmap,
+            vpn_range.get_start(),
+            vpn_range.get_end(),
+        );
         SUCCESS
     }
 
@@ -878,7 +1463,7 @@ impl MemorySet {
 
 pub struct MapArea {
     pub vpn_range:   VPNRange,
-    pub data_frames: BTreeMap<VirtPageNum, FrameTracker>,
+    pub data_frames: BTreeMap<VirtPageNum, Arc<FrameTracker>>,
     pub map_type:    MapType,
     pub map_perm:    MapPermission,
 }
@@ -914,10 +1499,10 @@ impl MapArea {
             MapType::Framed => {
                 let frame = frame_alloc().unwrap();
                 ppn = frame.ppn;
-                self.data_frames.insert(vpn, frame);
+                self.data_frames.insert(vpn, Arc::new(frame));
             }
         }
-        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits as u16).unwrap();
         page_table.map(vpn, ppn, pte_flags);
         // debug!(
         //     "map_one vpn: {:#x}, ppn: {:#x}, page_table: {:#x}",
@@ -1004,6 +1589,25 @@ impl MapArea {
     }
 }
 
+/// Page-granular regions of a [`MemorySet`] kept outside of `areas`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PrivateRegion {
+    /// grown by brk
+    Heap,
+    /// anonymous and file mmap
+    Mmap,
+}
+
+impl PrivateRegion {
+    /// flags its pages are mapped with unless changed by mprotect
+    fn flags(self) -> PTEFlags {
+        match self {
+            PrivateRegion::Heap => PTEFlags::U | PTEFlags::R | PTEFlags::W,
+            PrivateRegion::Mmap => PTEFlags::U | PTEFlags::R | PTEFlags::W | PTEFlags::X,
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum MapType {
     ///vpn - offset = ppn ;only for kernel space
@@ -1029,7 +1633,7 @@ bitflags! {
 /// test map function in page table
 #[allow(unused)]
 pub fn remap_test() {
-    let mut kernel_space = KERNEL_SPACE.exclusive_access(file!(), line!());
+    let mut kernel_space = KERNEL_SPACE.lock();
     let mid_text: VirtAddr = (stext as usize + (etext as usize - stext as usize) / 2).into();
     let mid_rodata: VirtAddr =
         (srodata as usize + (erodata as usize - srodata as usize) / 2).into();
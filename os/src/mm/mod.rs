@@ -8,21 +8,45 @@
 
 mod address;
 mod config;
+#[cfg(feature = "debug_heap")]
+pub mod debug_heap;
 mod frame_allocator;
+#[cfg(feature = "guard_heap")]
+pub mod guard_heap;
 mod heap_allocator;
 mod memory_set;
+mod page_cache;
 mod page_table;
 
 use address::VPNRange;
 pub use address::{KernelAddr, PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
-pub use frame_allocator::{frame_alloc, frame_alloc_contiguous, frame_dealloc, FrameTracker};
+pub use frame_allocator::{
+    frame_alloc,
+    frame_alloc_charged,
+    frame_alloc_contiguous,
+    frame_dealloc,
+    frame_stats,
+    FrameTracker,
+};
 pub use heap_allocator::init_heap;
-pub use memory_set::{kernel_token, remap_test, MapPermission, MemorySet, KERNEL_SPACE};
+pub use memory_set::{
+    handle_user_fault,
+    kernel_token,
+    lend_user_space,
+    remap_test,
+    MapPermission,
+    MemorySet,
+    PrivateRegion,
+    KERNEL_SPACE,
+};
+pub use page_cache::{get_page, sync_pages, CachedPage};
 pub use page_table::{
     translated_byte_buffer,
     translated_ref,
     translated_refmut,
     translated_str,
+    translated_user_pa,
+    user_range_ok,
     PTEFlags,
     PageTable,
     PageTableEntry,
@@ -37,5 +61,7 @@ pub fn init(memory_end: usize) {
     debug!("frame allocator initialize");
     frame_allocator::init_frame_allocator(memory_end);
     debug!("kernel space initialize");
-    KERNEL_SPACE.exclusive_access(file!(), line!()).activate();
+    KERNEL_SPACE.lock().activate();
+    #[cfg(feature = "guard_heap")]
+    guard_heap::init();
 }
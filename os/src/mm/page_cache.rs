@@ -0,0 +1,105 @@
+//! Pages of files mapped with `MAP_SHARED`
+//!
+//! 同一文件同一页的所有共享映射使用同一个页帧，修改互相可见；munmap、msync 和进程退出时
+//! 写回文件，只有曾经被可写地映射过的页才会写回。缓存只持有弱引用，最后一个映射解除后页帧随之释放。
+//! read/write 不经过这里，映射期间它们看到的是磁盘上的内容。
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::*;
+use spin::Mutex;
+
+use super::{frame_alloc_charged, FrameTracker};
+use crate::{config::PAGE_SIZE, fs::inode::Inode, syscall::errno::ENOMEM, task::cgroup::Cgroup};
+
+/// A page of a file shared by its mappings
+pub struct CachedPage {
+    pub frame: FrameTracker,
+    inode:     Arc<dyn Inode>,
+    /// file offset of the page
+    offset:    usize,
+    /// bytes of the page inside the file, write-back never grows the file
+    len:       usize,
+    /// some mapping has been writable, a page only ever mapped read-only is not written back
+    writable:  AtomicBool,
+}
+
+impl CachedPage {
+    /// A mapping may write the page from now on
+    pub fn allow_write(&self) {
+        self.writable.store(true, Ordering::Relaxed);
+    }
+
+    /// Write the page back to the file if it may have been modified
+    pub fn sync(&self) {
+        if self.len > 0 && self.writable.load(Ordering::Relaxed) {
+            let data = &self.frame.ppn.get_bytes_array()[..self.len];
+            self.inode.write_at(self.offset, data);
+        }
+    }
+}
+
+lazy_static! {
+    /// (file, page index) to the page, for files with [`Inode::cache_key`]
+    static ref PAGE_CACHE: Mutex<BTreeMap<((usize, usize), usize), Weak<CachedPage>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// The page of `inode` at `offset` (page aligned), read from the file and charged to
+/// `cgroup` if no mapping holds it yet
+pub fn get_page(
+    inode: &Arc<dyn Inode>, offset: usize, cgroup: &Arc<Cgroup>,
+) -> Result<Arc<CachedPage>, isize> {
+    let key = inode.cache_key().map(|file| (file, offset / PAGE_SIZE));
+    if let Some(page) = key.and_then(|key| PAGE_CACHE.lock().get(&key)?.upgrade()) {
+        return Ok(page);
+    }
+    if !cgroup.try_charge(1) {
+        return Err(ENOMEM);
+    }
+    let frame = frame_alloc_charged(cgroup).ok_or(ENOMEM)?;
+    // 读盘时可能切换到其他任务，不能持有缓存的锁
+    let len = inode.read_at(offset, frame.ppn.get_bytes_array());
+    let page = Arc::new(CachedPage {
+        frame,
+        inode: inode.clone(),
+        offset,
+        len,
+        writable: AtomicBool::new(false),
+    });
+    let Some(key) = key else {
+        return Ok(page);
+    };
+    let mut cache = PAGE_CACHE.lock();
+    // 读盘期间其他任务可能已经映射了这一页，以先放入的为准
+    if let Some(page) = cache.get(&key).and_then(Weak::upgrade) {
+        return Ok(page);
+    }
+    cache.retain(|_, page| page.strong_count() > 0);
+    cache.insert(key, Arc::downgrade(&page));
+    Ok(page)
+}
+
+/// Write back the mapped pages of `inode`, or of every file if `inode` is `None`
+pub fn sync_pages(inode: Option<&Arc<dyn Inode>>) {
+    let file = inode.map(|inode| inode.cache_key());
+    if file == Some(None) {
+        // 没有缓存键的文件的页不在缓存里，只能由映射它的进程写回
+        return;
+    }
+    // 写回时可能切换到其他任务，不能持有缓存的锁
+    let pages: Vec<Arc<CachedPage>> = PAGE_CACHE
+        .lock()
+        .iter()
+        .filter(|((key, _), _)| file.map_or(true, |file| file == Some(*key)))
+        .filter_map(|(_, page)| page.upgrade())
+        .collect();
+    for page in pages {
+        page.sync();
+    }
+}
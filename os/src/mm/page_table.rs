@@ -3,12 +3,24 @@ use alloc::{string::String, vec, vec::Vec};
 
 use bitflags::*;
 
-use super::{frame_alloc, FrameTracker, PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
-use crate::{config::KERNEL_SPACE_OFFSET, mm::KERNEL_SPACE};
+use super::{
+    frame_alloc,
+    handle_user_fault,
+    FrameTracker,
+    PhysAddr,
+    PhysPageNum,
+    StepByOne,
+    VirtAddr,
+    VirtPageNum,
+};
+use crate::{
+    config::{KERNEL_SPACE_OFFSET, PAGE_SIZE},
+    mm::KERNEL_SPACE,
+};
 
 bitflags! {
     /// page table entry flags
-    pub struct PTEFlags: u8 {
+    pub struct PTEFlags: u16 {
         const V = 1 << 0;
         const R = 1 << 1;
         const W = 1 << 2;
@@ -17,6 +29,8 @@ bitflags! {
         const G = 1 << 5;
         const A = 1 << 6;
         const D = 1 << 7;
+        /// 软件保留位：写时复制，写入时缺页再复制页帧
+        const COW = 1 << 8;
     }
 }
 
@@ -45,7 +59,7 @@ impl PageTableEntry {
     }
     /// Get the flags from the page table entry
     pub fn flags(&self) -> PTEFlags {
-        PTEFlags::from_bits(self.bits as u8).unwrap()
+        PTEFlags::from_bits_truncate(self.bits as u16)
     }
     /// The page pointered by page table entry is valid?
     pub fn is_valid(&self) -> bool {
@@ -110,11 +124,8 @@ impl PageTable {
 
         //to keep kernel part the same, we only first level of page table
         frame.ppn.get_pte_array()[kernel_root_vpn.indexes()[0]..].copy_from_slice(
-            &KERNEL_SPACE
-                .exclusive_access(file!(), line!())
-                .page_table
-                .root_ppn
-                .get_pte_array()[kernel_root_vpn.indexes()[0]..],
+            &KERNEL_SPACE.lock().page_table.root_ppn.get_pte_array()
+                [kernel_root_vpn.indexes()[0]..],
         );
 
         PageTable {
@@ -122,7 +133,7 @@ impl PageTable {
             frames:   vec![frame],
         }
     }
-    fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    pub(super) fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
         //debug!("find_pte_create: vpn = {:?}", vpn);
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
@@ -146,7 +157,7 @@ impl PageTable {
         }
         result
     }
-    fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    pub(super) fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
         //debug!("find_pte: vpn = {:?}", vpn);
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
@@ -206,6 +217,31 @@ impl PageTable {
     pub fn token(&self) -> usize {
         8usize << 60 | self.root_ppn.0
     }
+    /// Write-protect a page about to be shared by fork, returning the flags to map it with
+    ///
+    /// 可写页清掉 W 并打上 COW 标记，只读页原样共享。
+    pub fn share_cow(&mut self, vpn: VirtPageNum) -> PTEFlags {
+        let pte = self.find_pte(vpn).unwrap();
+        let mut flags = pte.flags();
+        if flags.contains(PTEFlags::W) {
+            flags.remove(PTEFlags::W);
+            flags.insert(PTEFlags::COW);
+            *pte = PageTableEntry::new(pte.ppn(), flags);
+        }
+        flags
+    }
+    /// Like `translate`, but first map a page reserved for the current task and give it
+    /// its own copy of a copy-on-write page if `write`, for the kernel to access the page
+    /// through its physical address
+    fn translate_user(&self, vpn: VirtPageNum, write: bool) -> Option<PageTableEntry> {
+        let fault = self.translate(vpn).map_or(true, |pte| {
+            !pte.is_valid() || write && pte.flags().contains(PTEFlags::COW)
+        });
+        if fault {
+            handle_user_fault(self.token(), vpn.into(), write);
+        }
+        self.translate(vpn)
+    }
 }
 
 /// Create mutable `Vec<u8>` slice in kernel space from ptr in other address space. NOTICE: the content pointed to by the pointer `ptr` can cross physical pages.
@@ -217,7 +253,7 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
     while start < end {
         let start_va = VirtAddr::from(start);
         let mut vpn = start_va.floor();
-        let ppn = page_table.translate(vpn).unwrap().ppn();
+        let ppn = page_table.translate_user(vpn, true).unwrap().ppn();
         vpn.step();
         let mut end_va: VirtAddr = vpn.into();
         end_va = end_va.min(VirtAddr::from(end));
@@ -250,20 +286,47 @@ pub fn translated_str(token: usize, ptr: *const u8) -> String {
 /// translate a pointer `ptr` in other address space to a immutable u8 slice in kernel address space. NOTICE: the content pointed to by the pointer `ptr` cannot cross physical pages, otherwise translated_byte_buffer should be used.
 pub fn translated_ref<T>(token: usize, ptr: *const T) -> &'static T {
     let page_table = PageTable::from_token(token);
-    page_table
-        .translate_va(VirtAddr::from(ptr as usize))
-        .unwrap()
-        .get_ref()
+    let va = VirtAddr::from(ptr as usize);
+    let pte = page_table.translate_user(va.floor(), false).unwrap();
+    PhysAddr(PhysAddr::from(pte.ppn()).0 + va.page_offset()).get_ref()
 }
 
 /// translate a pointer `ptr` in other address space to a mutable u8 slice in kernel address space. NOTICE: the content pointed to by the pointer `ptr` cannot cross physical pages, otherwise translated_byte_buffer should be used.
 pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> &'static mut T {
     let page_table = PageTable::from_token(token);
-    let va = ptr as usize;
-    page_table
-        .translate_va(VirtAddr::from(va))
-        .unwrap()
-        .get_mut()
+    let va = VirtAddr::from(ptr as usize);
+    let pte = page_table.translate_user(va.floor(), true).unwrap();
+    PhysAddr(PhysAddr::from(pte.ppn()).0 + va.page_offset()).get_mut()
+}
+
+/// Physical address of the user pointer `ptr`, `None` if it is not mapped for user access,
+/// or not writable if `write`
+///
+/// 和 `translated_refmut` 一样先处理缺页，`write` 时写时复制的页换成私有页帧后才返回。
+pub fn translated_user_pa(token: usize, ptr: usize, write: bool) -> Option<PhysAddr> {
+    let page_table = PageTable::from_token(token);
+    let va = VirtAddr::from(ptr);
+    let pte = page_table.translate_user(va.floor(), write)?;
+    if !pte.is_valid() || !pte.flags().contains(PTEFlags::U) || write && !pte.writable() {
+        return None;
+    }
+    Some(PhysAddr(PhysAddr::from(pte.ppn()).0 + va.page_offset()))
+}
+
+/// Whether every page of `[addr, addr + len)` is mapped for user access, and writable
+/// if `write`
+pub fn user_range_ok(token: usize, addr: usize, len: usize, write: bool) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        if translated_user_pa(token, page.max(addr), write).is_none() {
+            return false;
+        }
+        page += PAGE_SIZE;
+    }
+    true
 }
 
 /// An abstraction over a buffer passed from user space to kernel space
@@ -323,3 +386,41 @@ impl Iterator for UserBufferIterator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn pte_flags() {
+        let pte = PageTableEntry::new(
+            PhysPageNum(0x80400),
+            PTEFlags::V | PTEFlags::R | PTEFlags::W,
+        );
+        assert_eq!(pte.ppn(), PhysPageNum(0x80400));
+        assert!(pte.is_valid() && pte.readable() && pte.writable());
+        assert!(!pte.executable());
+        assert!(!PageTableEntry::empty().is_valid());
+    }
+
+    #[test_case]
+    fn map_translate_unmap() {
+        let mut page_table = PageTable::new();
+        let frame = frame_alloc().unwrap();
+        let vpn = VirtPageNum(0x12345);
+        page_table.map(vpn, frame.ppn, PTEFlags::R | PTEFlags::U);
+        let pte = page_table.translate(vpn).unwrap();
+        assert_eq!(pte.ppn(), frame.ppn);
+        assert!(pte.readable() && !pte.writable());
+        // 页内偏移保持不变
+        let va = VirtAddr(VirtAddr::from(vpn).0 + 0x10);
+        let pa = PhysAddr(PhysAddr::from(frame.ppn).0 + 0x10);
+        assert_eq!(page_table.translate_va(va), Some(pa));
+        page_table.unmap(vpn);
+        assert!(!page_table.translate(vpn).unwrap().is_valid());
+        // 未建立中间页表的地址查不到
+        assert!(page_table
+            .translate(VirtPageNum(0x7_ffff_f000 >> 12))
+            .is_none());
+    }
+}
@@ -0,0 +1,277 @@
+//! Network interfaces, ARP and IPv4 routing
+//!
+//! 网卡上只配一个地址，使用 QEMU user 网络的默认配置：10.0.2.15/24，网关 10.0.2.2。
+//! 目的地在子网内直接发送，否则发给网关；下一跳的 MAC 地址还不知道时先把报文放进队列，
+//! 发出 ARP 请求，收到应答后再发送。
+//!
+//! 发给 127.0.0.0/8 和本机地址的报文走回环接口：放进队列，下一次 [`poll`] 时当作收到的报文处理。
+//! 回环接口不依赖网卡，没有网卡的板子上也能用。
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+
+use lazy_static::*;
+
+use super::{
+    ip::{
+        build_ipv4,
+        checksum,
+        sum_words,
+        Ipv4Addr,
+        Ipv4Packet,
+        IPPROTO_ICMP,
+        IPPROTO_TCP,
+        IPPROTO_UDP,
+        IPV4_HEADER_LEN,
+    },
+    tcp,
+    udp,
+};
+use crate::{
+    drivers::net::{NetDevice, NET_DEVICE},
+    fs::poll::notify_pollers,
+    sync::SpinNoIrqLock,
+    syscall::errno::{EMSGSIZE, ENETUNREACH},
+};
+
+/// Largest IPv4 packet an ethernet frame carries
+pub const ETH_MTU: usize = 1500;
+/// Largest IPv4 packet on the loopback interface
+pub const LOOPBACK_MTU: usize = 65535;
+const ETH_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+/// packets kept while waiting for ARP replies, older ones are dropped
+const MAX_PENDING: usize = 64;
+
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+const PREFIX_LEN: u32 = 24;
+const GATEWAY: Ipv4Addr = Ipv4Addr([10, 0, 2, 2]);
+
+const LOOPBACK_NET: Ipv4Addr = Ipv4Addr([127, 0, 0, 0]);
+const LOOPBACK_ADDR: Ipv4Addr = Ipv4Addr([127, 0, 0, 1]);
+
+/// The ethernet interface
+struct EthIface {
+    dev:     Arc<dyn NetDevice>,
+    mac:     [u8; 6],
+    addr:    Ipv4Addr,
+    /// IPv4 address to MAC address of the hosts heard from
+    arp:     BTreeMap<Ipv4Addr, [u8; 6]>,
+    /// (next hop, packet) waiting for the MAC address of the next hop
+    pending: VecDeque<(Ipv4Addr, Vec<u8>)>,
+}
+
+lazy_static! {
+    static ref ETH: Option<SpinNoIrqLock<EthIface>> = NET_DEVICE.clone().map(|dev| {
+        let iface = EthIface {
+            mac: dev.mac(),
+            dev,
+            addr: LOCAL_ADDR,
+            arp: BTreeMap::new(),
+            pending: VecDeque::new(),
+        };
+        SpinNoIrqLock::new(iface)
+    });
+    /// packets sent on the loopback interface, received on the next poll
+    static ref LOOPBACK: SpinNoIrqLock<VecDeque<Vec<u8>>> =
+        SpinNoIrqLock::new(VecDeque::new());
+}
+
+impl EthIface {
+    fn send_frame(&self, dst: [u8; 6], ethertype: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(ETH_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.dev.send(&frame);
+    }
+
+    fn send_arp(&self, oper: u16, target_mac: [u8; 6], target: Ipv4Addr) {
+        let mut arp = Vec::with_capacity(28);
+        arp.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+        arp.extend_from_slice(&oper.to_be_bytes());
+        arp.extend_from_slice(&self.mac);
+        arp.extend_from_slice(&self.addr.0);
+        arp.extend_from_slice(&target_mac);
+        arp.extend_from_slice(&target.0);
+        let dst = if oper == ARP_REQUEST {
+            BROADCAST_MAC
+        } else {
+            target_mac
+        };
+        self.send_frame(dst, ETHERTYPE_ARP, &arp);
+    }
+
+    /// Send `packet` to the next hop towards `dst`
+    fn send_ipv4(&mut self, dst: Ipv4Addr, packet: Vec<u8>) {
+        if dst == Ipv4Addr::BROADCAST {
+            self.send_frame(BROADCAST_MAC, ETHERTYPE_IPV4, &packet);
+            return;
+        }
+        let next_hop = if dst.in_subnet(self.addr, PREFIX_LEN) {
+            dst
+        } else {
+            GATEWAY
+        };
+        if let Some(mac) = self.arp.get(&next_hop).copied() {
+            self.send_frame(mac, ETHERTYPE_IPV4, &packet);
+            return;
+        }
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((next_hop, packet));
+        self.send_arp(ARP_REQUEST, [0; 6], next_hop);
+    }
+
+    /// Learn the sender of an ARP packet, answer requests for our address
+    fn handle_arp(&mut self, arp: &[u8]) {
+        if arp.len() < 28 || arp[0..6] != [0, 1, 0x08, 0x00, 6, 4] {
+            return;
+        }
+        let oper = u16::from_be_bytes([arp[6], arp[7]]);
+        let mut sender_mac = [0u8; 6];
+        sender_mac.copy_from_slice(&arp[8..14]);
+        let sender = Ipv4Addr([arp[14], arp[15], arp[16], arp[17]]);
+        let target = Ipv4Addr([arp[24], arp[25], arp[26], arp[27]]);
+        self.arp.insert(sender, sender_mac);
+        // 发送等这个地址的报文
+        let (ready, waiting) = self
+            .pending
+            .drain(..)
+            .partition::<VecDeque<_>, _>(|(next_hop, _)| *next_hop == sender);
+        self.pending = waiting;
+        for (_, packet) in ready {
+            self.send_frame(sender_mac, ETHERTYPE_IPV4, &packet);
+        }
+        if oper == ARP_REQUEST && target == self.addr {
+            self.send_arp(ARP_REPLY, sender_mac, sender);
+        }
+    }
+}
+
+fn is_loopback(addr: Ipv4Addr) -> bool {
+    addr.in_subnet(LOOPBACK_NET, 8)
+}
+
+/// The address of the ethernet interface, `None` without a network device
+fn eth_addr() -> Option<Ipv4Addr> {
+    ETH.as_ref().map(|eth| eth.lock().addr)
+}
+
+/// Whether `addr` belongs to an interface, for bind
+pub fn is_local(addr: Ipv4Addr) -> bool {
+    is_loopback(addr) || eth_addr() == Some(addr)
+}
+
+/// Source address of packets to `dst`, ENETUNREACH if no interface reaches it
+pub fn source_for(dst: Ipv4Addr) -> Result<Ipv4Addr, isize> {
+    if is_loopback(dst) {
+        return Ok(LOOPBACK_ADDR);
+    }
+    eth_addr().ok_or(ENETUNREACH)
+}
+
+/// Largest payload of a packet to `dst` with a `header_len`-byte transport header
+pub fn max_payload(dst: Ipv4Addr, header_len: usize) -> usize {
+    let mtu = if is_local(dst) { LOOPBACK_MTU } else { ETH_MTU };
+    mtu - IPV4_HEADER_LEN - header_len
+}
+
+/// Send `payload` from `src` to `dst` in an IPv4 packet
+pub fn ip_output(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), isize> {
+    let local = is_local(dst);
+    let mtu = if local { LOOPBACK_MTU } else { ETH_MTU };
+    if IPV4_HEADER_LEN + payload.len() > mtu {
+        return Err(EMSGSIZE);
+    }
+    let packet = build_ipv4(src, dst, protocol, payload);
+    if local {
+        LOOPBACK.lock().push_back(packet);
+        // 等着的套接字醒来调用 poll 就能收到，不必等下一次定时检查
+        notify_pollers();
+        return Ok(());
+    }
+    let eth = ETH.as_ref().ok_or(ENETUNREACH)?;
+    eth.lock().send_ipv4(dst, packet);
+    Ok(())
+}
+
+/// Handle the packets received since the last call
+pub fn poll() {
+    poll_eth();
+    // 处理时可能又发出回环报文，每次只取一个
+    loop {
+        let packet = LOOPBACK.lock().pop_front();
+        let Some(packet) = packet else {
+            break;
+        };
+        if let Some(packet) = Ipv4Packet::parse(&packet) {
+            ip_input(&packet);
+        }
+    }
+}
+
+/// Handle the frames received by the ethernet interface
+fn poll_eth() {
+    let Some(eth) = ETH.as_ref() else {
+        return;
+    };
+    let (dev, addr) = {
+        let eth = eth.lock();
+        (eth.dev.clone(), eth.addr)
+    };
+    // 处理报文时可能要发送应答，不能一直借用网卡
+    while let Some(frame) = dev.recv() {
+        if frame.len() < ETH_HEADER_LEN {
+            continue;
+        }
+        let payload = &frame[ETH_HEADER_LEN..];
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP => eth.lock().handle_arp(payload),
+            ETHERTYPE_IPV4 => {
+                if let Some(packet) = Ipv4Packet::parse(payload) {
+                    if packet.dst == addr || packet.dst == Ipv4Addr::BROADCAST {
+                        ip_input(&packet);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Deliver a packet addressed to us
+fn ip_input(packet: &Ipv4Packet) {
+    match packet.protocol {
+        IPPROTO_ICMP => icmp_input(packet),
+        IPPROTO_TCP => tcp::input(packet.src, packet.dst, packet.payload),
+        IPPROTO_UDP => udp::input(packet.src, packet.dst, packet.payload),
+        _ => {}
+    }
+}
+
+/// Answer echo requests, so the kernel can be pinged
+fn icmp_input(packet: &Ipv4Packet) {
+    const ECHO_REPLY: u8 = 0;
+    const ECHO_REQUEST: u8 = 8;
+    let icmp = packet.payload;
+    if icmp.len() < 8 || icmp[0] != ECHO_REQUEST || checksum(sum_words(icmp)) != 0 {
+        return;
+    }
+    let mut reply = icmp.to_vec();
+    reply[0] = ECHO_REPLY;
+    reply[2..4].fill(0);
+    let sum = checksum(sum_words(&reply));
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    let _ = ip_output(packet.dst, packet.src, IPPROTO_ICMP, &reply);
+}
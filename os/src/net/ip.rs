@@ -0,0 +1,141 @@
+//! IPv4 addresses, headers and checksums
+//!
+//! 不支持分片：收到的分片直接丢弃，发送超过 MTU 的报文返回 EMSGSIZE。
+
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+/// Length of an IPv4 header without options
+pub const IPV4_HEADER_LEN: usize = 20;
+/// TTL of the packets sent
+const DEFAULT_TTL: u8 = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Self = Self([0, 0, 0, 0]);
+    pub const BROADCAST: Self = Self([255, 255, 255, 255]);
+
+    pub fn is_unspecified(&self) -> bool {
+        *self == Self::UNSPECIFIED
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    /// Whether the address is in the network `net`/`prefix_len`
+    pub fn in_subnet(self, net: Ipv4Addr, prefix_len: u32) -> bool {
+        let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+        self.to_u32() & mask == net.to_u32() & mask
+    }
+}
+
+/// An IPv4 address and port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Endpoint {
+    pub addr: Ipv4Addr,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn new(addr: Ipv4Addr, port: u16) -> Self {
+        Self { addr, port }
+    }
+}
+
+impl fmt::Debug for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// Sum of the 16-bit big-endian words of `data`, to be folded by [`checksum`]
+pub fn sum_words(data: &[u8]) -> u32 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+            [hi] => u16::from_be_bytes([*hi, 0]),
+            _ => unreachable!(),
+        };
+        sum = sum.wrapping_add(word as u32);
+    }
+    sum
+}
+
+/// The internet checksum of words summed to `sum`
+pub fn checksum(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Checksum of a TCP or UDP `segment` with the IPv4 pseudo header
+pub fn transport_checksum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, segment: &[u8]) -> u16 {
+    let sum = sum_words(&src.0)
+        + sum_words(&dst.0)
+        + protocol as u32
+        + segment.len() as u32
+        + sum_words(segment);
+    checksum(sum)
+}
+
+/// A received IPv4 packet
+pub struct Ipv4Packet<'a> {
+    pub src:      Ipv4Addr,
+    pub dst:      Ipv4Addr,
+    pub protocol: u8,
+    pub payload:  &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// Parse `data`, `None` if it is malformed, damaged or a fragment
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < IPV4_HEADER_LEN || data[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (data[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if header_len < IPV4_HEADER_LEN || total_len < header_len || total_len > data.len() {
+            return None;
+        }
+        if checksum(sum_words(&data[..header_len])) != 0 {
+            return None;
+        }
+        // MF 置位或偏移不为 0 都是分片
+        let fragment = u16::from_be_bytes([data[6], data[7]]) & 0x3fff;
+        if fragment != 0 {
+            return None;
+        }
+        Some(Self {
+            src:      Ipv4Addr([data[12], data[13], data[14], data[15]]),
+            dst:      Ipv4Addr([data[16], data[17], data[18], data[19]]),
+            protocol: data[9],
+            payload:  &data[header_len..total_len],
+        })
+    }
+}
+
+/// An IPv4 packet carrying `payload`
+pub fn build_ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let total_len = (IPV4_HEADER_LEN + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(total_len as usize);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    // 不分片，标识随便填；DF 置位
+    packet.extend_from_slice(&[0, 0, 0x40, 0, DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&src.0);
+    packet.extend_from_slice(&dst.0);
+    let sum = checksum(sum_words(&packet));
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
@@ -0,0 +1,190 @@
+//! Sockets
+//!
+//! 每种套接字都是一个 [`File`]：read/write 就是不带地址的收发，就绪状态供 ppoll 和 epoll 检查。
+//! socket 系列系统调用通过 [`cast_file_to_socket`] 找到描述符背后的 [`Socket`]。
+
+pub mod iface;
+pub mod ip;
+pub mod tcp;
+pub mod udp;
+pub mod unix;
+
+use alloc::{sync::Arc, vec::Vec};
+use core::any::Any;
+
+use ip::{Endpoint, Ipv4Addr};
+use lazy_static::*;
+use tcp::TcpSocket;
+use udp::UdpSocket;
+use unix::UnixSocket;
+
+use crate::{
+    fs::file::File,
+    sync::SpinNoIrqLock,
+    syscall::errno::{EADDRINUSE, EAFNOSUPPORT, EINVAL},
+};
+
+pub const AF_UNIX: u16 = 1;
+pub const AF_INET: u16 = 2;
+
+pub const SOCK_STREAM: i32 = 1;
+pub const SOCK_DGRAM: i32 = 2;
+
+/// `how` of shutdown
+pub const SHUT_RD: i32 = 0;
+pub const SHUT_WR: i32 = 1;
+pub const SHUT_RDWR: i32 = 2;
+
+/// Longest `sun_path` of a `sockaddr_un`
+const UNIX_PATH_MAX: usize = 108;
+/// Size of a `sockaddr_in`
+const SOCKADDR_IN_LEN: usize = 16;
+
+/// Ports bind picks for sockets bound to port 0
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// Address of a socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SockAddr {
+    /// `sun_path` without its trailing NULs, empty for an unnamed socket; an abstract
+    /// name starts with a NUL
+    Unix(Vec<u8>),
+    Inet(Endpoint),
+}
+
+impl SockAddr {
+    /// Parse the `sockaddr` in `bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, isize> {
+        if bytes.len() < 2 {
+            return Err(EINVAL);
+        }
+        match u16::from_ne_bytes([bytes[0], bytes[1]]) {
+            AF_UNIX => {
+                let path = &bytes[2..bytes.len().min(2 + UNIX_PATH_MAX)];
+                // 路径名到第一个 NUL 为止，抽象名字用满给出的长度
+                let path = match path.iter().position(|c| *c == 0) {
+                    Some(0) => path,
+                    Some(end) => &path[..end],
+                    None => path,
+                };
+                Ok(Self::Unix(path.to_vec()))
+            }
+            AF_INET => {
+                if bytes.len() < SOCKADDR_IN_LEN {
+                    return Err(EINVAL);
+                }
+                // sin_port 和 sin_addr 都是网络字节序
+                let port = u16::from_be_bytes([bytes[2], bytes[3]]);
+                let addr = Ipv4Addr([bytes[4], bytes[5], bytes[6], bytes[7]]);
+                Ok(Self::Inet(Endpoint::new(addr, port)))
+            }
+            _ => Err(EAFNOSUPPORT),
+        }
+    }
+
+    /// The `sockaddr` form of the address, with the length getsockname reports
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Unix(path) => {
+                let mut bytes = AF_UNIX.to_ne_bytes().to_vec();
+                bytes.extend_from_slice(path);
+                if path.first().map_or(false, |c| *c != 0) {
+                    bytes.push(0);
+                }
+                bytes
+            }
+            Self::Inet(endpoint) => {
+                let mut bytes = AF_INET.to_ne_bytes().to_vec();
+                bytes.extend_from_slice(&endpoint.port.to_be_bytes());
+                bytes.extend_from_slice(&endpoint.addr.0);
+                bytes.resize(SOCKADDR_IN_LEN, 0);
+                bytes
+            }
+        }
+    }
+}
+
+/// `done` bytes were moved before hitting `errno`, which is reported only if none were
+fn partial(done: usize, errno: isize) -> Result<usize, isize> {
+    if done > 0 {
+        Ok(done)
+    } else {
+        Err(errno)
+    }
+}
+
+/// Operations of the socket syscalls, errors are negative errnos
+///
+/// `nonblock` 为真时，需要等待的操作返回 EAGAIN。
+pub trait Socket: File {
+    fn bind(&self, addr: SockAddr) -> Result<(), isize>;
+    fn listen(&self, backlog: usize) -> Result<(), isize>;
+    /// Take a pending connection, return the connected socket and the address of its peer
+    fn accept(&self, nonblock: bool) -> Result<(Arc<dyn File>, SockAddr), isize>;
+    fn connect(&self, addr: SockAddr, nonblock: bool) -> Result<(), isize>;
+    /// Send `buf` to `addr`, or to the peer if `None`, return the number of bytes sent
+    fn send_to(&self, buf: &[u8], addr: Option<SockAddr>, nonblock: bool) -> Result<usize, isize>;
+    /// Receive into `buf`, return the number of bytes received and the sender if known
+    fn recv_from(&self, buf: &mut [u8], nonblock: bool)
+        -> Result<(usize, Option<SockAddr>), isize>;
+    fn shutdown(&self, how: i32) -> Result<(), isize>;
+    fn local_addr(&self) -> SockAddr;
+    /// ENOTCONN if the socket is not connected
+    fn peer_addr(&self) -> Result<SockAddr, isize>;
+    /// O_NONBLOCK of the socket
+    fn nonblock(&self) -> bool;
+    /// SOCK_STREAM or SOCK_DGRAM
+    fn sock_type(&self) -> i32;
+    /// Take the pending error of the socket, for SO_ERROR
+    fn sock_error(&self) -> Option<isize> {
+        None
+    }
+}
+
+/// The [`Socket`] behind `file`, `None` if it is not a socket
+pub fn cast_file_to_socket(file: Arc<dyn File>) -> Option<Arc<dyn Socket>> {
+    unsafe {
+        let file_ptr = Arc::into_raw(file);
+        let file_ref = &*(file_ptr as *const dyn Any);
+        if file_ref.is::<UnixSocket>() {
+            Some(Arc::from_raw(file_ptr as *const UnixSocket))
+        } else if file_ref.is::<UdpSocket>() {
+            Some(Arc::from_raw(file_ptr as *const UdpSocket))
+        } else if file_ref.is::<TcpSocket>() {
+            Some(Arc::from_raw(file_ptr as *const TcpSocket))
+        } else {
+            let _ = Arc::from_raw(file_ptr);
+            None
+        }
+    }
+}
+
+lazy_static! {
+    /// where [`ephemeral_port`] starts looking
+    static ref NEXT_EPHEMERAL: SpinNoIrqLock<u16> = SpinNoIrqLock::new(*EPHEMERAL_PORTS.start());
+}
+
+/// A free ephemeral port, EADDRINUSE if every one is `in_use`
+pub fn ephemeral_port(in_use: impl Fn(u16) -> bool) -> Result<u16, isize> {
+    let mut next = NEXT_EPHEMERAL.lock();
+    for _ in EPHEMERAL_PORTS {
+        let port = *next;
+        *next = if port == *EPHEMERAL_PORTS.end() {
+            *EPHEMERAL_PORTS.start()
+        } else {
+            port + 1
+        };
+        if !in_use(port) {
+            return Ok(port);
+        }
+    }
+    Err(EADDRINUSE)
+}
+
+/// Drive the network stack: handle received packets and TCP timers
+///
+/// 网卡没有中断，时钟中断和等待网络的套接字都会调用它。
+pub fn poll() {
+    iface::poll();
+    tcp::on_tick();
+}
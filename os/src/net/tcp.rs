@@ -0,0 +1,1140 @@
+//! TCP
+//!
+//! 每个连接的状态在一个 [`Tcb`] 中，按 (本地端点, 远端端点) 登记在 [`CONNS`] 里；套接字关闭后
+//! Tcb 留在表中，直到 FIN 交换完成或超时。实现尽量简单：只接收按序到达的报文，乱序的丢弃并重复
+//! 确认；超时后从第一个未确认的字节开始全部重传，重传太多次就放弃连接。不支持窗口缩放、
+//! 选择确认和紧急数据。
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    cmp::min,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use lazy_static::*;
+
+use super::{
+    ephemeral_port,
+    iface,
+    ip::{transport_checksum, Endpoint, Ipv4Addr, IPPROTO_TCP},
+    partial,
+    poll,
+    SockAddr,
+    Socket,
+    SHUT_RD,
+    SHUT_RDWR,
+    SHUT_WR,
+    SOCK_STREAM,
+};
+use crate::{
+    fs::{
+        file::File,
+        inode::{Stat, StatMode},
+        poll::{notify_pollers, wait_for_poll},
+    },
+    sync::SpinNoIrqLock,
+    syscall::errno::{
+        EADDRINUSE,
+        EADDRNOTAVAIL,
+        EAGAIN,
+        EALREADY,
+        ECONNREFUSED,
+        ECONNRESET,
+        EINPROGRESS,
+        EINVAL,
+        EISCONN,
+        ENOTCONN,
+        EPIPE,
+        ETIMEDOUT,
+    },
+    task::{current_add_signal, SignalFlags},
+    timer::{get_time, get_time_ms},
+};
+
+const TCP_HEADER_LEN: usize = 20;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Size of the send and the receive buffer of a connection
+const TCP_BUF_SIZE: usize = 64 * 1024;
+/// MSS assumed when the peer announces none
+const DEFAULT_MSS: usize = 536;
+/// First retransmission timeout, doubled on each retry
+const RTO_MS: usize = 500;
+/// Retransmissions before the connection is given up
+const MAX_RETRIES: u32 = 8;
+/// How long a connection stays in TIME_WAIT, much shorter than 2 MSL
+const TIME_WAIT_MS: usize = 1000;
+/// How long a closed socket's connection waits in FIN_WAIT_2 for the peer's FIN
+const FIN_WAIT_2_MS: usize = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// `a` comes before `b` in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// A received segment
+struct Segment<'a> {
+    src_port: u16,
+    dst_port: u16,
+    seq:      u32,
+    ack:      u32,
+    flags:    u8,
+    window:   u16,
+    /// the MSS option of a SYN
+    mss:      Option<usize>,
+    payload:  &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < TCP_HEADER_LEN {
+            return None;
+        }
+        let header_len = (data[12] >> 4) as usize * 4;
+        if header_len < TCP_HEADER_LEN || header_len > data.len() {
+            return None;
+        }
+        let mut mss = None;
+        let options = &data[TCP_HEADER_LEN..header_len];
+        let mut i = 0;
+        while i < options.len() {
+            match options[i] {
+                0 => break,
+                1 => i += 1,
+                kind => {
+                    let len = *options.get(i + 1)? as usize;
+                    if len < 2 || i + len > options.len() {
+                        break;
+                    }
+                    if kind == 2 && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[i + 2], options[i + 3]]) as usize);
+                    }
+                    i += len;
+                }
+            }
+        }
+        Some(Self {
+            src_port: u16::from_be_bytes([data[0], data[1]]),
+            dst_port: u16::from_be_bytes([data[2], data[3]]),
+            seq: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            ack: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            flags: data[13],
+            window: u16::from_be_bytes([data[14], data[15]]),
+            mss,
+            payload: &data[header_len..],
+        })
+    }
+
+    /// Sequence space taken by the segment
+    fn seq_len(&self) -> u32 {
+        let flags = (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32;
+        self.payload.len() as u32 + flags
+    }
+}
+
+/// Send a segment from `local` to `remote`, with an MSS option if `mss` is given
+fn send_segment(
+    local: Endpoint, remote: Endpoint, seq: u32, ack: u32, flags: u8, window: u16,
+    mss: Option<u16>, payload: &[u8],
+) {
+    let header_len = TCP_HEADER_LEN + if mss.is_some() { 4 } else { 0 };
+    let mut segment = Vec::with_capacity(header_len + payload.len());
+    segment.extend_from_slice(&local.port.to_be_bytes());
+    segment.extend_from_slice(&remote.port.to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[(header_len / 4) as u8 * 16, flags]);
+    segment.extend_from_slice(&window.to_be_bytes());
+    // 校验和，紧急指针
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = mss {
+        segment.extend_from_slice(&[2, 4]);
+        segment.extend_from_slice(&mss.to_be_bytes());
+    }
+    segment.extend_from_slice(payload);
+    let sum = transport_checksum(local.addr, remote.addr, IPPROTO_TCP, &segment);
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    // 发不出去和丢包一样，靠重传
+    let _ = iface::ip_output(local.addr, remote.addr, IPPROTO_TCP, &segment);
+}
+
+/// Answer a segment that belongs to no connection
+fn send_reset(local: Endpoint, remote: Endpoint, seg: &Segment) {
+    if seg.flags & RST != 0 {
+        return;
+    }
+    if seg.flags & ACK != 0 {
+        send_segment(local, remote, seg.ack, 0, RST, 0, None, &[]);
+    } else {
+        let ack = seg.seq.wrapping_add(seg.seq_len());
+        send_segment(local, remote, 0, ack, RST | ACK, 0, None, &[]);
+    }
+}
+
+/// Our MSS towards `remote`
+fn local_mss(remote: Ipv4Addr) -> usize {
+    iface::max_payload(remote, TCP_HEADER_LEN)
+}
+
+/// A listening port
+struct Listener {
+    local:     Endpoint,
+    backlog:   usize,
+    /// established connections not accepted yet
+    ready:     VecDeque<Arc<SpinNoIrqLock<Tcb>>>,
+    /// connections still in SYN_RECEIVED
+    half_open: usize,
+}
+
+/// Transmission control block, the state of one connection
+struct Tcb {
+    state:        TcpState,
+    local:        Endpoint,
+    remote:       Endpoint,
+    iss:          u32,
+    /// oldest unacknowledged sequence number
+    snd_una:      u32,
+    /// next sequence number to send
+    snd_nxt:      u32,
+    /// highest sequence number sent, `snd_nxt` goes back below it on retransmission
+    snd_max:      u32,
+    /// the peer's receive window
+    snd_wnd:      usize,
+    mss:          usize,
+    rcv_nxt:      u32,
+    /// bytes from `snd_una` on, sent or not
+    send_buf:     VecDeque<u8>,
+    recv_buf:     VecDeque<u8>,
+    /// writing was shut down, a FIN follows `send_buf`
+    fin_queued:   bool,
+    /// the FIN was sent since the last retransmission
+    fin_sent:     bool,
+    /// the peer's FIN was received
+    peer_fin:     bool,
+    read_shut:    bool,
+    /// the socket was closed, only the connection is left
+    orphan:       bool,
+    /// why the connection failed, for the next socket call
+    error:        Option<isize>,
+    /// when to retransmit, in ms
+    rto_deadline: Option<usize>,
+    retries:      u32,
+    /// end of TIME_WAIT or of the FIN_WAIT_2 of an orphan, in ms
+    deadline:     Option<usize>,
+    /// the listener that accepts the connection once established
+    listener:     Option<Weak<SpinNoIrqLock<Listener>>>,
+}
+
+lazy_static! {
+    /// connections by (local, remote)
+    static ref CONNS: SpinNoIrqLock<BTreeMap<(Endpoint, Endpoint), Arc<SpinNoIrqLock<Tcb>>>> =
+        SpinNoIrqLock::new(BTreeMap::new());
+    /// listening ports
+    static ref LISTENERS: SpinNoIrqLock<BTreeMap<u16, Arc<SpinNoIrqLock<Listener>>>> =
+        SpinNoIrqLock::new(BTreeMap::new());
+    /// bound sockets by local port
+    static ref BOUND: SpinNoIrqLock<BTreeMap<u16, Weak<TcpSocket>>> =
+        SpinNoIrqLock::new(BTreeMap::new());
+}
+
+impl Tcb {
+    fn new(state: TcpState, local: Endpoint, remote: Endpoint) -> Self {
+        // 用时钟作初始序列号，新旧连接的序号不容易重叠
+        let iss = get_time() as u32;
+        Self {
+            state,
+            local,
+            remote,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_max: iss.wrapping_add(1),
+            snd_wnd: 0,
+            mss: DEFAULT_MSS,
+            rcv_nxt: 0,
+            send_buf: VecDeque::new(),
+            recv_buf: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            peer_fin: false,
+            read_shut: false,
+            orphan: false,
+            error: None,
+            rto_deadline: None,
+            retries: 0,
+            deadline: None,
+            listener: None,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        min(TCP_BUF_SIZE - self.recv_buf.len(), u16::MAX as usize) as u16
+    }
+
+    fn rto(&self) -> usize {
+        RTO_MS << self.retries
+    }
+
+    fn send(&self, seq: u32, flags: u8, payload: &[u8]) {
+        let window = self.window();
+        send_segment(
+            self.local,
+            self.remote,
+            seq,
+            self.rcv_nxt,
+            flags,
+            window,
+            None,
+            payload,
+        );
+    }
+
+    fn send_ack(&self) {
+        self.send(self.snd_nxt, ACK, &[]);
+    }
+
+    /// Send SYN, or SYN-ACK in SYN_RECEIVED
+    fn send_syn(&mut self) {
+        let flags = if self.state == TcpState::SynSent {
+            SYN
+        } else {
+            SYN | ACK
+        };
+        let mss = local_mss(self.remote.addr) as u16;
+        let window = self.window();
+        send_segment(
+            self.local,
+            self.remote,
+            self.iss,
+            self.rcv_nxt,
+            flags,
+            window,
+            Some(mss),
+            &[],
+        );
+        let rto = self.rto();
+        self.rto_deadline.get_or_insert(get_time_ms() + rto);
+    }
+
+    /// Send what the peer's window allows of `send_buf`, and then the FIN if queued
+    fn output(&mut self) {
+        use TcpState::*;
+        if !matches!(
+            self.state,
+            Established | CloseWait | FinWait1 | Closing | LastAck
+        ) {
+            return;
+        }
+        loop {
+            let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            if self.fin_sent || offset >= self.send_buf.len() {
+                break;
+            }
+            let len = min(self.mss, self.send_buf.len() - offset)
+                .min(self.snd_wnd.saturating_sub(offset));
+            if len == 0 {
+                break;
+            }
+            let payload: Vec<u8> = self.send_buf.range(offset..offset + len).copied().collect();
+            self.send(self.snd_nxt, ACK | PSH, &payload);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+        let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        if self.fin_queued && !self.fin_sent && offset == self.send_buf.len() {
+            self.send(self.snd_nxt, FIN | ACK, &[]);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+            self.state = match self.state {
+                Established => FinWait1,
+                CloseWait => LastAck,
+                state => state,
+            };
+        }
+        if seq_lt(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+        // 对方窗口为 0 时也要定时，超时后发一个字节试探
+        if self.snd_nxt != self.snd_una || !self.send_buf.is_empty() {
+            let rto = self.rto();
+            self.rto_deadline.get_or_insert(get_time_ms() + rto);
+        }
+    }
+
+    /// The retransmission timer expired
+    fn on_timeout(&mut self) {
+        use TcpState::*;
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.fail(ETIMEDOUT);
+            return;
+        }
+        self.rto_deadline = Some(get_time_ms() + self.rto());
+        match self.state {
+            SynSent | SynReceived => self.send_syn(),
+            _ => {
+                self.snd_nxt = self.snd_una;
+                self.fin_sent = false;
+                if self.snd_wnd == 0 && !self.send_buf.is_empty() {
+                    let probe = [self.send_buf[0]];
+                    self.send(self.snd_nxt, ACK, &probe);
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                } else {
+                    self.output();
+                }
+            }
+        }
+    }
+
+    /// Forget the connection
+    fn close(&mut self) {
+        if self.state == TcpState::SynReceived {
+            if let Some(listener) = self.listener.as_ref().and_then(Weak::upgrade) {
+                listener.lock().half_open -= 1;
+            }
+        }
+        self.state = TcpState::Closed;
+        self.rto_deadline = None;
+        self.deadline = None;
+        CONNS.lock().remove(&(self.local, self.remote));
+    }
+
+    /// The connection failed with `errno`
+    fn fail(&mut self, errno: isize) {
+        self.error = Some(errno);
+        self.close();
+    }
+
+    /// Reset the connection
+    fn abort(&mut self) {
+        if self.state != TcpState::Closed {
+            self.send(self.snd_nxt, RST | ACK, &[]);
+            self.close();
+        }
+    }
+
+    /// The socket was closed: finish sending and then close, or reset if data is left unread
+    fn release(&mut self) {
+        use TcpState::*;
+        self.orphan = true;
+        self.read_shut = true;
+        match self.state {
+            SynSent => self.close(),
+            Established | CloseWait if !self.recv_buf.is_empty() => self.abort(),
+            Established | CloseWait => {
+                self.fin_queued = true;
+                self.output();
+            }
+            FinWait2 => self.deadline = Some(get_time_ms() + FIN_WAIT_2_MS),
+            _ => {}
+        }
+    }
+
+    fn syn_sent_input(&mut self, seg: &Segment) {
+        let ack_ok = seg.flags & ACK != 0 && seg.ack == self.iss.wrapping_add(1);
+        if seg.flags & ACK != 0 && !ack_ok {
+            send_reset(self.local, self.remote, seg);
+            return;
+        }
+        if seg.flags & RST != 0 {
+            if ack_ok {
+                self.fail(ECONNREFUSED);
+            }
+            return;
+        }
+        if seg.flags & SYN == 0 {
+            return;
+        }
+        self.rcv_nxt = seg.seq.wrapping_add(1);
+        self.snd_wnd = seg.window as usize;
+        self.mss = min(seg.mss.unwrap_or(DEFAULT_MSS), local_mss(self.remote.addr));
+        self.rto_deadline = None;
+        self.retries = 0;
+        if ack_ok {
+            self.snd_una = seg.ack;
+            self.state = TcpState::Established;
+            self.send_ack();
+            self.output();
+        } else {
+            // 双方同时打开
+            self.state = TcpState::SynReceived;
+            self.send_syn();
+        }
+    }
+
+    /// Handle a segment of the connection, return whether it completed the handshake
+    fn segment_arrives(&mut self, seg: &Segment) -> bool {
+        use TcpState::*;
+        if self.state == SynSent {
+            self.syn_sent_input(seg);
+            return false;
+        }
+        if seg.flags & SYN != 0 {
+            if seg.seq.wrapping_add(1) == self.rcv_nxt {
+                // 重传的 SYN：对方没收到我们的 SYN-ACK 或 ACK
+                if self.state == SynReceived {
+                    self.send_syn();
+                } else {
+                    self.send_ack();
+                }
+            } else if seg.flags & RST == 0 {
+                // 连接中途的新 SYN 说明对方已经不记得这个连接了
+                send_reset(self.local, self.remote, seg);
+                self.fail(ECONNRESET);
+            }
+            return false;
+        }
+        let mut payload = seg.payload;
+        let mut fin = seg.flags & FIN != 0;
+        // 去掉已经收到过的部分，还没轮到的报文丢弃
+        let dup = self.rcv_nxt.wrapping_sub(seg.seq) as i32;
+        if dup > 0 {
+            let dup = dup as usize;
+            if dup > payload.len() || (dup == payload.len() && !fin) {
+                if seg.flags & RST == 0 {
+                    self.send_ack();
+                }
+                return false;
+            }
+            payload = &payload[dup..];
+        } else if dup < 0 {
+            if seg.flags & RST == 0 {
+                self.send_ack();
+            }
+            return false;
+        }
+        if seg.flags & RST != 0 {
+            self.fail(ECONNRESET);
+            return false;
+        }
+        if seg.flags & ACK == 0 {
+            return false;
+        }
+        let mut established = false;
+        if self.state == SynReceived {
+            if seg.ack != self.iss.wrapping_add(1) {
+                send_reset(self.local, self.remote, seg);
+                return false;
+            }
+            self.snd_una = seg.ack;
+            self.state = Established;
+            self.rto_deadline = None;
+            self.retries = 0;
+            established = true;
+        } else {
+            let acked = seg.ack.wrapping_sub(self.snd_una);
+            if acked > self.snd_max.wrapping_sub(self.snd_una) {
+                // 确认了还没发送的数据
+                self.send_ack();
+                return false;
+            }
+            if acked > 0 {
+                let data = min(acked as usize, self.send_buf.len());
+                self.send_buf.drain(..data);
+                let fin_acked = acked as usize > data;
+                self.snd_una = seg.ack;
+                if seq_lt(self.snd_nxt, self.snd_una) {
+                    self.snd_nxt = self.snd_una;
+                }
+                self.retries = 0;
+                self.rto_deadline = None;
+                if fin_acked {
+                    match self.state {
+                        FinWait1 => {
+                            self.state = FinWait2;
+                            if self.orphan {
+                                self.deadline = Some(get_time_ms() + FIN_WAIT_2_MS);
+                            }
+                        }
+                        Closing => {
+                            self.state = TimeWait;
+                            self.deadline = Some(get_time_ms() + TIME_WAIT_MS);
+                        }
+                        LastAck => {
+                            self.close();
+                            return false;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        self.snd_wnd = seg.window as usize;
+        let mut need_ack = false;
+        if matches!(self.state, Established | FinWait1 | FinWait2) {
+            if !payload.is_empty() {
+                let len = min(TCP_BUF_SIZE - self.recv_buf.len(), payload.len());
+                // 关闭读之后收到的数据直接丢掉
+                if !self.read_shut {
+                    self.recv_buf.extend(&payload[..len]);
+                }
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+                // 放不下的部分等对方重传，FIN 也一样
+                fin &= len == payload.len();
+                need_ack = true;
+            }
+            if fin {
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                self.peer_fin = true;
+                need_ack = true;
+                match self.state {
+                    Established => self.state = CloseWait,
+                    FinWait1 => self.state = Closing,
+                    _ => {
+                        self.state = TimeWait;
+                        self.deadline = Some(get_time_ms() + TIME_WAIT_MS);
+                    }
+                }
+            }
+        }
+        if need_ack {
+            self.send_ack();
+        }
+        self.output();
+        established
+    }
+}
+
+/// A SYN arrived at a listening port
+fn listen_input(
+    listener: &Arc<SpinNoIrqLock<Listener>>, local: Endpoint, remote: Endpoint, seg: &Segment,
+) {
+    if seg.flags & RST != 0 {
+        return;
+    }
+    if seg.flags & ACK != 0 {
+        send_reset(local, remote, seg);
+        return;
+    }
+    if seg.flags & SYN == 0 {
+        return;
+    }
+    let mut listener_inner = listener.lock();
+    // 队列满了就不理会 SYN，对方会重传
+    if listener_inner.ready.len() + listener_inner.half_open > listener_inner.backlog {
+        return;
+    }
+    listener_inner.half_open += 1;
+    drop(listener_inner);
+    let mut tcb = Tcb::new(TcpState::SynReceived, local, remote);
+    tcb.rcv_nxt = seg.seq.wrapping_add(1);
+    tcb.snd_wnd = seg.window as usize;
+    tcb.mss = min(seg.mss.unwrap_or(DEFAULT_MSS), local_mss(remote.addr));
+    tcb.listener = Some(Arc::downgrade(listener));
+    tcb.send_syn();
+    let tcb = Arc::new(SpinNoIrqLock::new(tcb));
+    CONNS.lock().insert((local, remote), tcb);
+}
+
+/// Handle a received TCP segment
+pub fn input(src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8]) {
+    if transport_checksum(src, dst, IPPROTO_TCP, segment) != 0 {
+        return;
+    }
+    let Some(seg) = Segment::parse(segment) else {
+        return;
+    };
+    let local = Endpoint::new(dst, seg.dst_port);
+    let remote = Endpoint::new(src, seg.src_port);
+    let tcb = CONNS.lock().get(&(local, remote)).cloned();
+    if let Some(tcb) = tcb {
+        let mut inner = tcb.lock();
+        if inner.segment_arrives(&seg) {
+            // 握手完成，交给监听者等 accept；监听者已经关闭就重置连接
+            if let Some(listener) = inner.listener.take() {
+                match listener.upgrade() {
+                    Some(listener) => {
+                        let mut listener = listener.lock();
+                        listener.half_open -= 1;
+                        listener.ready.push_back(tcb.clone());
+                    }
+                    None => inner.abort(),
+                }
+            }
+        }
+    } else {
+        let listener = LISTENERS.lock().get(&seg.dst_port).cloned();
+        let listener = listener.filter(|listener| {
+            let addr = listener.lock().local.addr;
+            addr.is_unspecified() || addr == dst
+        });
+        match listener {
+            Some(listener) => listen_input(&listener, local, remote, &seg),
+            None => send_reset(local, remote, &seg),
+        }
+    }
+    notify_pollers();
+}
+
+/// Run the retransmission and TIME_WAIT timers
+pub fn on_tick() {
+    let conns: Vec<_> = {
+        let conns = CONNS.lock();
+        if conns.is_empty() {
+            return;
+        }
+        conns.values().cloned().collect()
+    };
+    let now = get_time_ms();
+    let mut changed = false;
+    for tcb in conns {
+        let mut tcb = tcb.lock();
+        if tcb.deadline.map_or(false, |deadline| deadline <= now) {
+            tcb.close();
+            changed = true;
+        } else if tcb.rto_deadline.map_or(false, |deadline| deadline <= now) {
+            tcb.on_timeout();
+            changed = true;
+        }
+    }
+    if changed {
+        notify_pollers();
+    }
+}
+
+enum SockState {
+    /// neither listening nor connected
+    Idle,
+    Listening(Arc<SpinNoIrqLock<Listener>>),
+    /// connecting, connected or shut down
+    Connected(Arc<SpinNoIrqLock<Tcb>>),
+}
+
+/// An AF_INET SOCK_STREAM socket
+pub struct TcpSocket {
+    state:    SpinNoIrqLock<SockState>,
+    /// the address bound to, by bind or when listening or connecting
+    local:    SpinNoIrqLock<Option<Endpoint>>,
+    /// O_NONBLOCK of the socket
+    nonblock: AtomicBool,
+    this:     Weak<TcpSocket>,
+}
+
+impl TcpSocket {
+    pub fn new() -> Arc<Self> {
+        Self::with_state(SockState::Idle, None)
+    }
+
+    fn with_state(state: SockState, local: Option<Endpoint>) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            state:    SpinNoIrqLock::new(state),
+            local:    SpinNoIrqLock::new(local),
+            nonblock: AtomicBool::new(false),
+            this:     this.clone(),
+        })
+    }
+
+    /// Bind to `local`, a free ephemeral port if its port is 0
+    fn bind_to(&self, local: Endpoint) -> Result<Endpoint, isize> {
+        if !local.addr.is_unspecified() && !iface::is_local(local.addr) {
+            return Err(EADDRNOTAVAIL);
+        }
+        let mut bound = BOUND.lock();
+        let taken = |port: u16| {
+            bound
+                .get(&port)
+                .map_or(false, |socket| socket.strong_count() > 0)
+        };
+        let port = match local.port {
+            // 临时端口还要避开仍在 TIME_WAIT 等状态的连接
+            0 => ephemeral_port(|port| {
+                taken(port) || CONNS.lock().keys().any(|(local, _)| local.port == port)
+            })?,
+            port if taken(port) => return Err(EADDRINUSE),
+            port => port,
+        };
+        bound.insert(port, self.this.clone());
+        let local = Endpoint::new(local.addr, port);
+        *self.local.lock() = Some(local);
+        Ok(local)
+    }
+
+    /// The local address, binding to an ephemeral port first if unbound
+    fn local_or_bind(&self) -> Result<Endpoint, isize> {
+        let local = *self.local.lock();
+        local.map_or_else(|| self.bind_to(Endpoint::default()), Ok)
+    }
+
+    fn tcb(&self) -> Option<Arc<SpinNoIrqLock<Tcb>>> {
+        match &*self.state.lock() {
+            SockState::Connected(tcb) => Some(tcb.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl Socket for TcpSocket {
+    fn bind(&self, addr: SockAddr) -> Result<(), isize> {
+        let SockAddr::Inet(local) = addr else {
+            return Err(EINVAL);
+        };
+        if self.local.lock().is_some() {
+            return Err(EINVAL);
+        }
+        self.bind_to(local).map(|_| ())
+    }
+
+    fn listen(&self, backlog: usize) -> Result<(), isize> {
+        let mut state = self.state.lock();
+        match &*state {
+            SockState::Listening(listener) => {
+                listener.lock().backlog = backlog;
+                return Ok(());
+            }
+            SockState::Connected(_) => return Err(EINVAL),
+            SockState::Idle => {}
+        }
+        let local = self.local_or_bind()?;
+        let listener = Listener {
+            local,
+            backlog,
+            ready: VecDeque::new(),
+            half_open: 0,
+        };
+        let listener = Arc::new(SpinNoIrqLock::new(listener));
+        LISTENERS.lock().insert(local.port, listener.clone());
+        *state = SockState::Listening(listener);
+        Ok(())
+    }
+
+    fn accept(&self, nonblock: bool) -> Result<(Arc<dyn File>, SockAddr), isize> {
+        let listener = match &*self.state.lock() {
+            SockState::Listening(listener) => listener.clone(),
+            _ => return Err(EINVAL),
+        };
+        loop {
+            poll();
+            let conn = listener.lock().ready.pop_front();
+            if let Some(tcb) = conn {
+                let (local, remote) = {
+                    let tcb = tcb.lock();
+                    (tcb.local, tcb.remote)
+                };
+                let socket = Self::with_state(SockState::Connected(tcb), Some(local));
+                return Ok((socket, SockAddr::Inet(remote)));
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            wait_for_poll(None);
+        }
+    }
+
+    fn connect(&self, addr: SockAddr, nonblock: bool) -> Result<(), isize> {
+        let SockAddr::Inet(remote) = addr else {
+            return Err(EINVAL);
+        };
+        let mut state = self.state.lock();
+        match &*state {
+            SockState::Listening(_) => return Err(EINVAL),
+            SockState::Connected(tcb) => {
+                let connecting = tcb.lock().state == TcpState::SynSent;
+                return Err(if connecting { EALREADY } else { EISCONN });
+            }
+            SockState::Idle => {}
+        }
+        let bound = self.local_or_bind()?;
+        let src = if bound.addr.is_unspecified() {
+            iface::source_for(remote.addr)?
+        } else {
+            bound.addr
+        };
+        let local = Endpoint::new(src, bound.port);
+        let tcb = {
+            let mut conns = CONNS.lock();
+            if conns.contains_key(&(local, remote)) {
+                return Err(EADDRINUSE);
+            }
+            let tcb = Arc::new(SpinNoIrqLock::new(Tcb::new(
+                TcpState::SynSent,
+                local,
+                remote,
+            )));
+            conns.insert((local, remote), tcb.clone());
+            tcb
+        };
+        tcb.lock().send_syn();
+        *state = SockState::Connected(tcb.clone());
+        drop(state);
+        if nonblock {
+            return Err(EINPROGRESS);
+        }
+        loop {
+            poll();
+            let mut tcb = tcb.lock();
+            match tcb.state {
+                TcpState::SynSent | TcpState::SynReceived => {}
+                TcpState::Closed => return Err(tcb.error.take().unwrap_or(ECONNREFUSED)),
+                _ => return Ok(()),
+            }
+            drop(tcb);
+            wait_for_poll(None);
+        }
+    }
+
+    /// 已连接的流套接字忽略 `addr`
+    fn send_to(&self, buf: &[u8], _addr: Option<SockAddr>, nonblock: bool) -> Result<usize, isize> {
+        let Some(tcb) = self.tcb() else {
+            return Err(ENOTCONN);
+        };
+        let mut written = 0;
+        loop {
+            poll();
+            let mut tcb = tcb.lock();
+            if let Some(errno) = tcb.error.take() {
+                return partial(written, errno);
+            }
+            match tcb.state {
+                TcpState::SynSent | TcpState::SynReceived => {}
+                TcpState::Established | TcpState::CloseWait if !tcb.fin_queued => {
+                    let len = min(TCP_BUF_SIZE - tcb.send_buf.len(), buf.len() - written);
+                    tcb.send_buf.extend(&buf[written..written + len]);
+                    written += len;
+                    tcb.output();
+                    if written == buf.len() {
+                        return Ok(written);
+                    }
+                }
+                _ => {
+                    drop(tcb);
+                    current_add_signal(SignalFlags::SIGPIPE);
+                    return partial(written, EPIPE);
+                }
+            }
+            if nonblock {
+                return partial(written, EAGAIN);
+            }
+            drop(tcb);
+            wait_for_poll(None);
+        }
+    }
+
+    fn recv_from(
+        &self, buf: &mut [u8], nonblock: bool,
+    ) -> Result<(usize, Option<SockAddr>), isize> {
+        let Some(tcb) = self.tcb() else {
+            return Err(ENOTCONN);
+        };
+        loop {
+            poll();
+            let mut tcb = tcb.lock();
+            if !tcb.recv_buf.is_empty() {
+                let before = tcb.window() as usize;
+                let len = min(buf.len(), tcb.recv_buf.len());
+                for (dst, src) in buf.iter_mut().zip(tcb.recv_buf.drain(..len)) {
+                    *dst = src;
+                }
+                // 窗口重新打开一半时告诉对方，免得它一直等
+                let opened = before < TCP_BUF_SIZE / 2 && tcb.window() as usize >= TCP_BUF_SIZE / 2;
+                if opened && !tcb.peer_fin {
+                    tcb.send_ack();
+                }
+                return Ok((len, None));
+            }
+            if let Some(errno) = tcb.error.take() {
+                return Err(errno);
+            }
+            if tcb.peer_fin || tcb.read_shut || tcb.state == TcpState::Closed || buf.is_empty() {
+                return Ok((0, None));
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            drop(tcb);
+            wait_for_poll(None);
+        }
+    }
+
+    fn shutdown(&self, how: i32) -> Result<(), isize> {
+        let (read, write) = match how {
+            SHUT_RD => (true, false),
+            SHUT_WR => (false, true),
+            SHUT_RDWR => (true, true),
+            _ => return Err(EINVAL),
+        };
+        let Some(tcb) = self.tcb() else {
+            return Err(ENOTCONN);
+        };
+        let mut tcb = tcb.lock();
+        tcb.read_shut |= read;
+        if write && !tcb.fin_queued {
+            tcb.fin_queued = true;
+            tcb.output();
+        }
+        drop(tcb);
+        notify_pollers();
+        Ok(())
+    }
+
+    fn local_addr(&self) -> SockAddr {
+        let local = match self.tcb() {
+            Some(tcb) => tcb.lock().local,
+            None => self.local.lock().unwrap_or_default(),
+        };
+        SockAddr::Inet(local)
+    }
+
+    fn peer_addr(&self) -> Result<SockAddr, isize> {
+        let tcb = self.tcb().ok_or(ENOTCONN)?;
+        let tcb = tcb.lock();
+        if matches!(tcb.state, TcpState::SynSent | TcpState::Closed) {
+            return Err(ENOTCONN);
+        }
+        Ok(SockAddr::Inet(tcb.remote))
+    }
+
+    fn nonblock(&self) -> bool {
+        self.nonblock.load(Ordering::Relaxed)
+    }
+
+    fn sock_type(&self) -> i32 {
+        SOCK_STREAM
+    }
+
+    fn sock_error(&self) -> Option<isize> {
+        let tcb = self.tcb()?;
+        let error = tcb.lock().error.take();
+        error
+    }
+}
+
+impl Drop for TcpSocket {
+    /// 连接继续完成 FIN 交换；还没被 accept 的连接随监听者一起重置
+    fn drop(&mut self) {
+        let state = core::mem::replace(&mut *self.state.lock(), SockState::Idle);
+        match state {
+            SockState::Connected(tcb) => tcb.lock().release(),
+            SockState::Listening(listener) => {
+                let (port, ready) = {
+                    let mut listener = listener.lock();
+                    (listener.local.port, core::mem::take(&mut listener.ready))
+                };
+                LISTENERS.lock().remove(&port);
+                for tcb in ready {
+                    tcb.lock().abort();
+                }
+            }
+            SockState::Idle => {}
+        }
+        let local = *self.local.lock();
+        if let Some(local) = local {
+            let mut bound = BOUND.lock();
+            if bound
+                .get(&local.port)
+                .map_or(false, |socket| socket.ptr_eq(&self.this))
+            {
+                bound.remove(&local.port);
+            }
+        }
+        notify_pollers();
+    }
+}
+
+impl File for TcpSocket {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        self.try_read(buf).unwrap_or(0)
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> Result<usize, isize> {
+        let nonblock = self.nonblock();
+        self.recv_from(buf, nonblock).map(|(len, _)| len)
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        self.try_write(buf).unwrap_or(0)
+    }
+
+    fn try_write(&self, buf: &[u8]) -> Result<usize, isize> {
+        let nonblock = self.nonblock();
+        self.send_to(buf, None, nonblock)
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        let st_mode = StatMode::SOCKET.bits() | 0o777;
+        Some(Stat::new(0, 0, st_mode, 1, 0, 0, 0, 0, 0))
+    }
+
+    fn is_dir(&self) -> bool {
+        false
+    }
+
+    /// 连接被重置，或者两个方向都已关闭
+    fn hang_up(&self) -> bool {
+        let Some(tcb) = self.tcb() else {
+            return false;
+        };
+        let tcb = tcb.lock();
+        tcb.state == TcpState::Closed || (tcb.peer_fin && tcb.fin_queued)
+    }
+
+    /// 有连接可以 accept、有数据可读，或者读会立即返回
+    fn r_ready(&self) -> bool {
+        poll();
+        match &*self.state.lock() {
+            SockState::Idle => false,
+            SockState::Listening(listener) => !listener.lock().ready.is_empty(),
+            SockState::Connected(tcb) => {
+                let tcb = tcb.lock();
+                !tcb.recv_buf.is_empty()
+                    || tcb.peer_fin
+                    || tcb.read_shut
+                    || tcb.error.is_some()
+                    || tcb.state == TcpState::Closed
+            }
+        }
+    }
+
+    /// 连接建立后发送缓冲区有空位，或者写会立即失败
+    fn w_ready(&self) -> bool {
+        poll();
+        let Some(tcb) = self.tcb() else {
+            return false;
+        };
+        let tcb = tcb.lock();
+        match tcb.state {
+            TcpState::SynSent | TcpState::SynReceived => false,
+            TcpState::Established | TcpState::CloseWait if !tcb.fin_queued => {
+                tcb.send_buf.len() < TCP_BUF_SIZE
+            }
+            _ => true,
+        }
+    }
+
+    fn set_nonblock(&self, nonblock: bool) {
+        self.nonblock.store(nonblock, Ordering::Relaxed);
+    }
+}
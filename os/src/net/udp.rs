@@ -0,0 +1,363 @@
+//! UDP sockets
+//!
+//! 每个端口只能绑定一个套接字。收到的报文按目的端口找到套接字放进它的接收队列，队列满时丢弃；
+//! 发送不会阻塞，直接交给网卡。
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    cmp::min,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use lazy_static::*;
+
+use super::{
+    ephemeral_port,
+    iface,
+    ip::{transport_checksum, Endpoint, IPPROTO_UDP},
+    poll,
+    SockAddr,
+    Socket,
+    SHUT_RD,
+    SHUT_RDWR,
+    SHUT_WR,
+    SOCK_DGRAM,
+};
+use crate::{
+    fs::{
+        file::File,
+        inode::{Stat, StatMode},
+        poll::{notify_pollers, wait_for_poll},
+    },
+    sync::SpinNoIrqLock,
+    syscall::errno::{
+        EADDRINUSE,
+        EADDRNOTAVAIL,
+        EAGAIN,
+        EDESTADDRREQ,
+        EINVAL,
+        EMSGSIZE,
+        ENOTCONN,
+        EOPNOTSUPP,
+        EPIPE,
+    },
+};
+
+const UDP_HEADER_LEN: usize = 8;
+/// Most bytes of datagrams waiting in a socket
+const UDP_RX_LIMIT: usize = 64 * 1024;
+
+lazy_static! {
+    /// bound sockets by local port
+    static ref PORTS: SpinNoIrqLock<BTreeMap<u16, Weak<UdpSocket>>> =
+        SpinNoIrqLock::new(BTreeMap::new());
+}
+
+struct UdpInner {
+    local:      Option<Endpoint>,
+    /// destination of send and the only source accepted, set by connect
+    peer:       Option<Endpoint>,
+    /// received datagrams and their senders
+    rx:         VecDeque<(Vec<u8>, Endpoint)>,
+    /// bytes in `rx`
+    queued:     usize,
+    read_shut:  bool,
+    write_shut: bool,
+}
+
+/// An AF_INET SOCK_DGRAM socket
+pub struct UdpSocket {
+    inner:    SpinNoIrqLock<UdpInner>,
+    /// O_NONBLOCK of the socket
+    nonblock: AtomicBool,
+    this:     Weak<UdpSocket>,
+}
+
+impl UdpSocket {
+    pub fn new() -> Arc<Self> {
+        let inner = UdpInner {
+            local:      None,
+            peer:       None,
+            rx:         VecDeque::new(),
+            queued:     0,
+            read_shut:  false,
+            write_shut: false,
+        };
+        Arc::new_cyclic(|this| Self {
+            inner:    SpinNoIrqLock::new(inner),
+            nonblock: AtomicBool::new(false),
+            this:     this.clone(),
+        })
+    }
+
+    /// Bind to `local`, a free ephemeral port if its port is 0
+    fn bind_to(&self, local: Endpoint) -> Result<Endpoint, isize> {
+        if !local.addr.is_unspecified() && !iface::is_local(local.addr) {
+            return Err(EADDRNOTAVAIL);
+        }
+        let mut ports = PORTS.lock();
+        let in_use = |port: u16| {
+            ports
+                .get(&port)
+                .map_or(false, |socket| socket.strong_count() > 0)
+        };
+        let port = match local.port {
+            0 => ephemeral_port(in_use)?,
+            port if in_use(port) => return Err(EADDRINUSE),
+            port => port,
+        };
+        ports.insert(port, self.this.clone());
+        let local = Endpoint::new(local.addr, port);
+        self.inner.lock().local = Some(local);
+        Ok(local)
+    }
+
+    /// The local address, binding to an ephemeral port first if unbound
+    fn local_or_bind(&self) -> Result<Endpoint, isize> {
+        let local = self.inner.lock().local;
+        local.map_or_else(|| self.bind_to(Endpoint::default()), Ok)
+    }
+}
+
+impl Socket for UdpSocket {
+    fn bind(&self, addr: SockAddr) -> Result<(), isize> {
+        let SockAddr::Inet(local) = addr else {
+            return Err(EINVAL);
+        };
+        if self.inner.lock().local.is_some() {
+            return Err(EINVAL);
+        }
+        self.bind_to(local).map(|_| ())
+    }
+
+    fn listen(&self, _backlog: usize) -> Result<(), isize> {
+        Err(EOPNOTSUPP)
+    }
+
+    fn accept(&self, _nonblock: bool) -> Result<(Arc<dyn File>, SockAddr), isize> {
+        Err(EOPNOTSUPP)
+    }
+
+    fn connect(&self, addr: SockAddr, _nonblock: bool) -> Result<(), isize> {
+        let SockAddr::Inet(peer) = addr else {
+            return Err(EINVAL);
+        };
+        self.local_or_bind()?;
+        self.inner.lock().peer = Some(peer);
+        Ok(())
+    }
+
+    fn send_to(&self, buf: &[u8], addr: Option<SockAddr>, _nonblock: bool) -> Result<usize, isize> {
+        let (peer, write_shut) = {
+            let inner = self.inner.lock();
+            (inner.peer, inner.write_shut)
+        };
+        if write_shut {
+            return Err(EPIPE);
+        }
+        let dst = match addr {
+            Some(SockAddr::Inet(dst)) => dst,
+            Some(_) => return Err(EINVAL),
+            None => peer.ok_or(EDESTADDRREQ)?,
+        };
+        if buf.len() > iface::max_payload(dst.addr, UDP_HEADER_LEN) {
+            return Err(EMSGSIZE);
+        }
+        let local = self.local_or_bind()?;
+        let src = if local.addr.is_unspecified() {
+            iface::source_for(dst.addr)?
+        } else {
+            local.addr
+        };
+        let len = (UDP_HEADER_LEN + buf.len()) as u16;
+        let mut datagram = Vec::with_capacity(len as usize);
+        datagram.extend_from_slice(&local.port.to_be_bytes());
+        datagram.extend_from_slice(&dst.port.to_be_bytes());
+        datagram.extend_from_slice(&len.to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(buf);
+        // 校验和为 0 表示没有校验和，算出 0 时发送全 1
+        let sum = match transport_checksum(src, dst.addr, IPPROTO_UDP, &datagram) {
+            0 => 0xffff,
+            sum => sum,
+        };
+        datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+        iface::ip_output(src, dst.addr, IPPROTO_UDP, &datagram)?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(
+        &self, buf: &mut [u8], nonblock: bool,
+    ) -> Result<(usize, Option<SockAddr>), isize> {
+        loop {
+            poll();
+            let mut inner = self.inner.lock();
+            if let Some((data, sender)) = inner.rx.pop_front() {
+                inner.queued -= data.len();
+                let len = min(buf.len(), data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                return Ok((len, Some(SockAddr::Inet(sender))));
+            }
+            if inner.read_shut {
+                return Ok((0, None));
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            drop(inner);
+            wait_for_poll(None);
+        }
+    }
+
+    fn shutdown(&self, how: i32) -> Result<(), isize> {
+        let (read, write) = match how {
+            SHUT_RD => (true, false),
+            SHUT_WR => (false, true),
+            SHUT_RDWR => (true, true),
+            _ => return Err(EINVAL),
+        };
+        let mut inner = self.inner.lock();
+        if inner.peer.is_none() {
+            return Err(ENOTCONN);
+        }
+        inner.read_shut |= read;
+        inner.write_shut |= write;
+        drop(inner);
+        notify_pollers();
+        Ok(())
+    }
+
+    fn local_addr(&self) -> SockAddr {
+        let local = self.inner.lock().local;
+        SockAddr::Inet(local.unwrap_or_default())
+    }
+
+    fn peer_addr(&self) -> Result<SockAddr, isize> {
+        let peer = self.inner.lock().peer;
+        peer.map(SockAddr::Inet).ok_or(ENOTCONN)
+    }
+
+    fn nonblock(&self) -> bool {
+        self.nonblock.load(Ordering::Relaxed)
+    }
+
+    fn sock_type(&self) -> i32 {
+        SOCK_DGRAM
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let local = self.inner.lock().local;
+        if let Some(local) = local {
+            let mut ports = PORTS.lock();
+            if ports
+                .get(&local.port)
+                .map_or(false, |socket| socket.ptr_eq(&self.this))
+            {
+                ports.remove(&local.port);
+            }
+        }
+    }
+}
+
+impl File for UdpSocket {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        self.try_read(buf).unwrap_or(0)
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> Result<usize, isize> {
+        let nonblock = self.nonblock();
+        self.recv_from(buf, nonblock).map(|(len, _)| len)
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        self.try_write(buf).unwrap_or(0)
+    }
+
+    fn try_write(&self, buf: &[u8]) -> Result<usize, isize> {
+        let nonblock = self.nonblock();
+        self.send_to(buf, None, nonblock)
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        let st_mode = StatMode::SOCKET.bits() | 0o777;
+        Some(Stat::new(0, 0, st_mode, 1, 0, 0, 0, 0, 0))
+    }
+
+    fn is_dir(&self) -> bool {
+        false
+    }
+
+    fn hang_up(&self) -> bool {
+        false
+    }
+
+    fn r_ready(&self) -> bool {
+        poll();
+        let inner = self.inner.lock();
+        !inner.rx.is_empty() || inner.read_shut
+    }
+
+    fn set_nonblock(&self, nonblock: bool) {
+        self.nonblock.store(nonblock, Ordering::Relaxed);
+    }
+}
+
+/// Queue a received datagram on the socket bound to its destination port
+pub fn input(src: super::ip::Ipv4Addr, dst: super::ip::Ipv4Addr, datagram: &[u8]) {
+    if datagram.len() < UDP_HEADER_LEN {
+        return;
+    }
+    let src_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let dst_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    let sum = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if len < UDP_HEADER_LEN || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if sum != 0 && transport_checksum(src, dst, IPPROTO_UDP, datagram) != 0 {
+        return;
+    }
+    let socket = PORTS
+        .lock()
+        .get(&dst_port)
+        .and_then(|socket| socket.upgrade());
+    let Some(socket) = socket else {
+        return;
+    };
+    let sender = Endpoint::new(src, src_port);
+    let mut inner = socket.inner.lock();
+    let local = inner.local.unwrap_or_default();
+    if !local.addr.is_unspecified() && local.addr != dst {
+        return;
+    }
+    if inner.peer.map_or(false, |peer| peer != sender) {
+        return;
+    }
+    let payload = &datagram[UDP_HEADER_LEN..];
+    if inner.queued + payload.len() > UDP_RX_LIMIT {
+        return;
+    }
+    inner.queued += payload.len();
+    inner.rx.push_back((payload.to_vec(), sender));
+    drop(inner);
+    notify_pollers();
+}
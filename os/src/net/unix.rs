@@ -0,0 +1,623 @@
+//! UNIX domain sockets
+//!
+//! 每个套接字有自己的接收缓冲区，发送就是写进对端的接收缓冲区：流套接字是 64 KiB 的字节队列，
+//! 数据报套接字是带发送者地址的报文队列。connect 为每个连接新建一个服务端套接字放进监听者的
+//! 连接队列，accept 取走它。缓冲区、连接队列或对端状态变化时唤醒等在这个套接字上的所有任务。
+//!
+//! 路径名地址在 bind 时创建同名文件并按它的 inode 登记，相对路径和符号链接都能找到同一个套接字；
+//! 以 NUL 开头的抽象名字只登记在内核表中。
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    cmp::min,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use lazy_static::*;
+
+use super::{partial, SockAddr, Socket, SHUT_RD, SHUT_RDWR, SHUT_WR, SOCK_DGRAM, SOCK_STREAM};
+use crate::{
+    fs::{
+        defs::OpenFlags,
+        file::File,
+        inode::{Stat, StatMode},
+        lookup_path,
+        open_file,
+        poll::notify_pollers,
+    },
+    sync::SpinNoIrqLock,
+    syscall::errno::{
+        EADDRINUSE,
+        EAGAIN,
+        ECONNREFUSED,
+        EEXIST,
+        EINVAL,
+        EISCONN,
+        EMSGSIZE,
+        ENOTCONN,
+        EOPNOTSUPP,
+        EPIPE,
+        EPROTOTYPE,
+    },
+    task::{
+        block_current_in_syscall,
+        current_add_signal,
+        current_task,
+        wakeup_task,
+        SignalFlags,
+        TaskControlBlock,
+    },
+};
+
+/// Capacity of the receive buffer of a socket
+const SOCK_BUF_SIZE: usize = 64 * 1024;
+
+/// Key of a bound socket in [`NAMES`]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Name {
+    /// (filesystem, file) of the socket file
+    Inode((usize, usize)),
+    Abstract(Vec<u8>),
+}
+
+lazy_static! {
+    /// bound sockets
+    static ref NAMES: SpinNoIrqLock<BTreeMap<Name, Weak<UnixSocket>>> =
+        SpinNoIrqLock::new(BTreeMap::new());
+}
+
+impl Name {
+    /// The name of the address `path`, creating the socket file if `create`
+    fn resolve(path: &[u8], create: bool) -> Result<Self, isize> {
+        if path.first() == Some(&0) {
+            return Ok(Self::Abstract(path.to_vec()));
+        }
+        let path = core::str::from_utf8(path).map_err(|_| EINVAL)?;
+        let task = current_task().unwrap();
+        let work_dir = task.inner_exclusive_access().work_dir.clone();
+        let dentry = if create {
+            let flags = OpenFlags::O_CREAT | OpenFlags::O_EXCL;
+            open_file(&work_dir, path, flags).map_err(|errno| {
+                if errno == EEXIST {
+                    EADDRINUSE
+                } else {
+                    errno
+                }
+            })?
+        } else {
+            lookup_path(&work_dir, path)?
+        };
+        let key = dentry.inode().cache_key();
+        key.map(Self::Inode)
+            .ok_or(if create { EOPNOTSUPP } else { ECONNREFUSED })
+    }
+
+    /// The live socket bound to this name
+    fn socket(&self) -> Option<Arc<UnixSocket>> {
+        NAMES.lock().get(self)?.upgrade()
+    }
+}
+
+enum State {
+    Unconnected,
+    Listening {
+        backlog: usize,
+        /// connections not accepted yet, the server side of each
+        pending: VecDeque<Arc<UnixSocket>>,
+    },
+    /// 数据报套接字 connect 后也是这个状态，只是记下默认的目的地
+    Connected(Weak<UnixSocket>),
+}
+
+struct UnixInner {
+    state:      State,
+    name:       Option<Name>,
+    /// `sun_path` the socket is bound to, empty if unnamed
+    addr:       Vec<u8>,
+    /// received bytes, for a stream socket
+    stream:     VecDeque<u8>,
+    /// received datagrams and the addresses of their senders
+    datagrams:  VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// bytes in `datagrams`
+    queued:     usize,
+    /// the peer shut down writing, reads return 0 once the buffer is empty
+    eof:        bool,
+    read_shut:  bool,
+    write_shut: bool,
+    /// tasks waiting for the buffer, the connection queue or the peer of this socket
+    waiters:    VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl UnixInner {
+    fn wake(&mut self) {
+        self.waiters.drain(..).for_each(wakeup_task);
+        notify_pollers();
+    }
+
+    /// The connected peer is closed
+    fn peer_closed(&self) -> bool {
+        matches!(&self.state, State::Connected(peer) if peer.strong_count() == 0)
+    }
+}
+
+/// An AF_UNIX socket
+pub struct UnixSocket {
+    sock_type: i32,
+    inner:     SpinNoIrqLock<UnixInner>,
+    /// O_NONBLOCK of the socket
+    nonblock:  AtomicBool,
+    /// this socket, handed to its peers
+    this:      Weak<UnixSocket>,
+}
+
+impl UnixSocket {
+    /// A new unnamed socket, `None` for types other than SOCK_STREAM and SOCK_DGRAM
+    pub fn new(sock_type: i32) -> Option<Arc<Self>> {
+        if sock_type != SOCK_STREAM && sock_type != SOCK_DGRAM {
+            return None;
+        }
+        let inner = UnixInner {
+            state:      State::Unconnected,
+            name:       None,
+            addr:       Vec::new(),
+            stream:     VecDeque::new(),
+            datagrams:  VecDeque::new(),
+            queued:     0,
+            eof:        false,
+            read_shut:  false,
+            write_shut: false,
+            waiters:    VecDeque::new(),
+        };
+        Some(Arc::new_cyclic(|this| Self {
+            sock_type,
+            inner: SpinNoIrqLock::new(inner),
+            nonblock: AtomicBool::new(false),
+            this: this.clone(),
+        }))
+    }
+
+    /// Two sockets connected to each other, for socketpair
+    pub fn pair(sock_type: i32) -> Option<(Arc<Self>, Arc<Self>)> {
+        let (a, b) = (Self::new(sock_type)?, Self::new(sock_type)?);
+        a.inner.lock().state = State::Connected(Arc::downgrade(&b));
+        b.inner.lock().state = State::Connected(Arc::downgrade(&a));
+        Some((a, b))
+    }
+
+    fn is_stream(&self) -> bool {
+        self.sock_type == SOCK_STREAM
+    }
+
+    /// The connected peer, `Err(true)` if it is closed, `Err(false)` if never connected
+    fn peer(&self) -> Result<Arc<UnixSocket>, bool> {
+        match &self.inner.lock().state {
+            State::Connected(peer) => peer.upgrade().ok_or(true),
+            _ => Err(false),
+        }
+    }
+
+    /// Append as much of `buf` as fits to the peer's buffer, waiting for room unless
+    /// `nonblock`
+    fn send_stream(&self, buf: &[u8], nonblock: bool) -> Result<usize, isize> {
+        let mut written = 0;
+        loop {
+            let write_shut = self.inner.lock().write_shut;
+            let peer = match self.peer() {
+                Ok(peer) if !write_shut => peer,
+                Err(false) => return Err(ENOTCONN),
+                _ => {
+                    current_add_signal(SignalFlags::SIGPIPE);
+                    return partial(written, EPIPE);
+                }
+            };
+            let mut peer_inner = peer.inner.lock();
+            if peer_inner.read_shut {
+                drop(peer_inner);
+                current_add_signal(SignalFlags::SIGPIPE);
+                return partial(written, EPIPE);
+            }
+            let len = min(SOCK_BUF_SIZE - peer_inner.stream.len(), buf.len() - written);
+            if len > 0 {
+                peer_inner.stream.extend(&buf[written..written + len]);
+                written += len;
+                peer_inner.wake();
+            }
+            if written == buf.len() {
+                return Ok(written);
+            }
+            if nonblock {
+                return partial(written, EAGAIN);
+            }
+            peer_inner.waiters.push_back(current_task().unwrap());
+            drop(peer_inner);
+            drop(peer);
+            block_current_in_syscall();
+        }
+    }
+
+    /// Move what is buffered into `buf`, waiting only while the buffer is empty
+    fn recv_stream(&self, buf: &mut [u8], nonblock: bool) -> Result<usize, isize> {
+        loop {
+            let mut inner = self.inner.lock();
+            if !inner.stream.is_empty() {
+                let len = min(buf.len(), inner.stream.len());
+                for (dst, src) in buf.iter_mut().zip(inner.stream.drain(..len)) {
+                    *dst = src;
+                }
+                inner.wake();
+                return Ok(len);
+            }
+            if !matches!(inner.state, State::Connected(_)) {
+                return Err(ENOTCONN);
+            }
+            if inner.eof || inner.read_shut || inner.peer_closed() || buf.is_empty() {
+                return Ok(0);
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            inner.waiters.push_back(current_task().unwrap());
+            drop(inner);
+            block_current_in_syscall();
+        }
+    }
+
+    /// Queue `buf` as one datagram on the socket at `addr` or the connected peer
+    fn send_datagram(
+        &self, buf: &[u8], addr: Option<SockAddr>, nonblock: bool,
+    ) -> Result<usize, isize> {
+        if buf.len() > SOCK_BUF_SIZE {
+            return Err(EMSGSIZE);
+        }
+        let (write_shut, sender) = {
+            let inner = self.inner.lock();
+            (inner.write_shut, inner.addr.clone())
+        };
+        if write_shut {
+            return Err(EPIPE);
+        }
+        let peer = match addr {
+            Some(SockAddr::Unix(path)) => {
+                Name::resolve(&path, false)?.socket().ok_or(ECONNREFUSED)?
+            }
+            Some(_) => return Err(EINVAL),
+            None => match self.peer() {
+                Ok(peer) => peer,
+                Err(true) => return Err(ECONNREFUSED),
+                Err(false) => return Err(ENOTCONN),
+            },
+        };
+        if peer.sock_type != self.sock_type {
+            return Err(EPROTOTYPE);
+        }
+        // 等待时不能拿着对端，否则它关闭时不会唤醒我们
+        let peer = Arc::downgrade(&peer);
+        loop {
+            let peer = peer.upgrade().ok_or(ECONNREFUSED)?;
+            let mut peer_inner = peer.inner.lock();
+            if peer_inner.read_shut {
+                return Err(EPIPE);
+            }
+            if peer_inner.queued + buf.len() <= SOCK_BUF_SIZE {
+                peer_inner.datagrams.push_back((buf.to_vec(), sender));
+                peer_inner.queued += buf.len();
+                peer_inner.wake();
+                return Ok(buf.len());
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            peer_inner.waiters.push_back(current_task().unwrap());
+            drop(peer_inner);
+            drop(peer);
+            block_current_in_syscall();
+        }
+    }
+
+    /// Take the next datagram, the part that does not fit in `buf` is discarded
+    fn recv_datagram(
+        &self, buf: &mut [u8], nonblock: bool,
+    ) -> Result<(usize, Option<SockAddr>), isize> {
+        loop {
+            let mut inner = self.inner.lock();
+            if let Some((data, sender)) = inner.datagrams.pop_front() {
+                inner.queued -= data.len();
+                inner.wake();
+                let len = min(buf.len(), data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                return Ok((len, Some(SockAddr::Unix(sender))));
+            }
+            if inner.eof || inner.read_shut {
+                return Ok((0, None));
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            inner.waiters.push_back(current_task().unwrap());
+            drop(inner);
+            block_current_in_syscall();
+        }
+    }
+}
+
+impl Socket for UnixSocket {
+    fn bind(&self, addr: SockAddr) -> Result<(), isize> {
+        let SockAddr::Unix(path) = addr else {
+            return Err(EINVAL);
+        };
+        if path.is_empty() || self.inner.lock().name.is_some() {
+            return Err(EINVAL);
+        }
+        let name = Name::resolve(&path, true)?;
+        let mut names = NAMES.lock();
+        if names
+            .get(&name)
+            .map_or(false, |socket| socket.strong_count() > 0)
+        {
+            return Err(EADDRINUSE);
+        }
+        names.insert(name.clone(), self.this.clone());
+        drop(names);
+        let mut inner = self.inner.lock();
+        inner.name = Some(name);
+        inner.addr = path;
+        Ok(())
+    }
+
+    fn listen(&self, backlog: usize) -> Result<(), isize> {
+        if !self.is_stream() {
+            return Err(EOPNOTSUPP);
+        }
+        let mut inner = self.inner.lock();
+        if let State::Listening { backlog: old, .. } = &mut inner.state {
+            *old = backlog;
+            return Ok(());
+        }
+        if inner.name.is_none() || !matches!(inner.state, State::Unconnected) {
+            return Err(EINVAL);
+        }
+        inner.state = State::Listening {
+            backlog,
+            pending: VecDeque::new(),
+        };
+        Ok(())
+    }
+
+    fn accept(&self, nonblock: bool) -> Result<(Arc<dyn File>, SockAddr), isize> {
+        if !self.is_stream() {
+            return Err(EOPNOTSUPP);
+        }
+        loop {
+            let mut inner = self.inner.lock();
+            let State::Listening { pending, .. } = &mut inner.state else {
+                return Err(EINVAL);
+            };
+            if let Some(conn) = pending.pop_front() {
+                // 连接队列有了空位，等着的 connect 可以继续
+                inner.wake();
+                drop(inner);
+                let peer = conn.peer_addr().unwrap_or(SockAddr::Unix(Vec::new()));
+                return Ok((conn, peer));
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            inner.waiters.push_back(current_task().unwrap());
+            drop(inner);
+            block_current_in_syscall();
+        }
+    }
+
+    fn connect(&self, addr: SockAddr, nonblock: bool) -> Result<(), isize> {
+        let SockAddr::Unix(path) = addr else {
+            return Err(EINVAL);
+        };
+        let target = Name::resolve(&path, false)?.socket().ok_or(ECONNREFUSED)?;
+        if target.sock_type != self.sock_type {
+            return Err(EPROTOTYPE);
+        }
+        if !self.is_stream() {
+            let peer = Arc::downgrade(&target);
+            self.inner.lock().state = State::Connected(peer);
+            return Ok(());
+        }
+        match self.inner.lock().state {
+            State::Connected(_) => return Err(EISCONN),
+            State::Listening { .. } => return Err(EINVAL),
+            State::Unconnected => {}
+        }
+        loop {
+            let mut target_inner = target.inner.lock();
+            let target_addr = target_inner.addr.clone();
+            let State::Listening { backlog, pending } = &mut target_inner.state else {
+                return Err(ECONNREFUSED);
+            };
+            if pending.len() <= *backlog {
+                let conn = UnixSocket::new(SOCK_STREAM).unwrap();
+                let mut conn_inner = conn.inner.lock();
+                conn_inner.state = State::Connected(self.this.clone());
+                conn_inner.addr = target_addr;
+                drop(conn_inner);
+                let peer = Arc::downgrade(&conn);
+                self.inner.lock().state = State::Connected(peer);
+                pending.push_back(conn);
+                target_inner.wake();
+                return Ok(());
+            }
+            if nonblock {
+                return Err(EAGAIN);
+            }
+            target_inner.waiters.push_back(current_task().unwrap());
+            drop(target_inner);
+            block_current_in_syscall();
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: Option<SockAddr>, nonblock: bool) -> Result<usize, isize> {
+        if self.is_stream() {
+            self.send_stream(buf, nonblock)
+        } else {
+            self.send_datagram(buf, addr, nonblock)
+        }
+    }
+
+    fn recv_from(
+        &self, buf: &mut [u8], nonblock: bool,
+    ) -> Result<(usize, Option<SockAddr>), isize> {
+        if self.is_stream() {
+            self.recv_stream(buf, nonblock).map(|len| (len, None))
+        } else {
+            self.recv_datagram(buf, nonblock)
+        }
+    }
+
+    fn shutdown(&self, how: i32) -> Result<(), isize> {
+        let (read, write) = match how {
+            SHUT_RD => (true, false),
+            SHUT_WR => (false, true),
+            SHUT_RDWR => (true, true),
+            _ => return Err(EINVAL),
+        };
+        let mut inner = self.inner.lock();
+        let State::Connected(peer) = &inner.state else {
+            return Err(ENOTCONN);
+        };
+        let peer = peer.upgrade();
+        inner.read_shut |= read;
+        inner.write_shut |= write;
+        inner.wake();
+        drop(inner);
+        // 对端的读者读完缓冲区后看到文件末尾，写者看到 EPIPE
+        if let Some(peer) = peer {
+            let mut peer_inner = peer.inner.lock();
+            peer_inner.eof |= write;
+            peer_inner.wake();
+        }
+        Ok(())
+    }
+
+    fn local_addr(&self) -> SockAddr {
+        SockAddr::Unix(self.inner.lock().addr.clone())
+    }
+
+    fn peer_addr(&self) -> Result<SockAddr, isize> {
+        let peer = self.peer().map_err(|_| ENOTCONN)?;
+        let addr = peer.inner.lock().addr.clone();
+        Ok(SockAddr::Unix(addr))
+    }
+
+    fn nonblock(&self) -> bool {
+        self.nonblock.load(Ordering::Relaxed)
+    }
+
+    fn sock_type(&self) -> i32 {
+        self.sock_type
+    }
+}
+
+impl Drop for UnixSocket {
+    /// 对端的读者要看到文件末尾、写者要看到 EPIPE；还没被 accept 的连接随监听者一起关闭
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        if let Some(name) = inner.name.take() {
+            let mut names = NAMES.lock();
+            if names
+                .get(&name)
+                .map_or(false, |socket| socket.ptr_eq(&self.this))
+            {
+                names.remove(&name);
+            }
+        }
+        let state = core::mem::replace(&mut inner.state, State::Unconnected);
+        inner.wake();
+        drop(inner);
+        if let State::Connected(peer) = state {
+            if let Some(peer) = peer.upgrade() {
+                peer.inner.lock().wake();
+            }
+        }
+    }
+}
+
+impl File for UnixSocket {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        self.try_read(buf).unwrap_or(0)
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> Result<usize, isize> {
+        let nonblock = self.nonblock();
+        self.recv_from(buf, nonblock).map(|(len, _)| len)
+    }
+
+    fn read_all(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn write(&self, buf: &[u8]) -> usize {
+        self.try_write(buf).unwrap_or(0)
+    }
+
+    fn try_write(&self, buf: &[u8]) -> Result<usize, isize> {
+        let nonblock = self.nonblock();
+        self.send_to(buf, None, nonblock)
+    }
+
+    fn fstat(&self) -> Option<Stat> {
+        let st_mode = StatMode::SOCKET.bits() | 0o777;
+        Some(Stat::new(0, 0, st_mode, 1, 0, 0, 0, 0, 0))
+    }
+
+    fn is_dir(&self) -> bool {
+        false
+    }
+
+    fn hang_up(&self) -> bool {
+        let inner = self.inner.lock();
+        self.is_stream() && (inner.peer_closed() || (inner.eof && inner.write_shut))
+    }
+
+    /// 有连接可以 accept、有数据可读，或者读会立即返回 0
+    fn r_ready(&self) -> bool {
+        let inner = self.inner.lock();
+        match &inner.state {
+            State::Listening { pending, .. } => !pending.is_empty(),
+            _ => {
+                !inner.stream.is_empty()
+                    || !inner.datagrams.is_empty()
+                    || inner.eof
+                    || inner.read_shut
+                    || inner.peer_closed()
+            }
+        }
+    }
+
+    /// 对端的接收缓冲区有空位，或者写会立即失败
+    fn w_ready(&self) -> bool {
+        let Ok(peer) = self.peer() else {
+            return true;
+        };
+        let peer_inner = peer.inner.lock();
+        let used = if self.is_stream() {
+            peer_inner.stream.len()
+        } else {
+            peer_inner.queued
+        };
+        used < SOCK_BUF_SIZE || peer_inner.read_shut
+    }
+
+    fn set_nonblock(&self, nonblock: bool) {
+        self.nonblock.store(nonblock, Ordering::Relaxed);
+    }
+}
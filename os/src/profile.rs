@@ -0,0 +1,151 @@
+//! Syscall latency and trap profiling counters
+//!
+//! 计时用 `time` CSR（[`get_time`]），单位是 timer tick，频率为 [`CLOCK_FREQ`]。
+//! 每个系统调用号和每类 trap 记录次数、总耗时、最大耗时和按 log2(tick) 分桶的直方图，
+//! 和任务自己的 `syscall_times` 一样按系统调用号索引。
+//! 结果通过 `/proc/profile` 读出，向其写入任意内容清零。
+
+use alloc::{format, string::String};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    config::{CLOCK_FREQ, MAX_SYSCALL_NUM},
+    syscall::syscall_name,
+    timer::get_time,
+};
+
+/// bucket `i` counts latencies in `[2^i, 2^(i+1))` ticks, the last one is open-ended
+pub const HIST_BUCKETS: usize = 16;
+
+/// Counters of one syscall or trap kind
+struct Counter {
+    count: AtomicUsize,
+    total: AtomicUsize,
+    max:   AtomicUsize,
+    hist:  [AtomicUsize; HIST_BUCKETS],
+}
+
+impl Counter {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self = Self {
+        count: Self::ZERO,
+        total: Self::ZERO,
+        max:   Self::ZERO,
+        hist:  [Self::ZERO; HIST_BUCKETS],
+    };
+
+    fn record(&self, ticks: usize) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(ticks, Ordering::Relaxed);
+        self.max.fetch_max(ticks, Ordering::Relaxed);
+        let bucket = (usize::BITS - ticks.leading_zeros()).saturating_sub(1) as usize;
+        self.hist[bucket.min(HIST_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+        for bucket in self.hist.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn show(&self, name: &str, out: &mut String) {
+        let _ = write!(
+            out,
+            "{:<20} {:>10} {:>14} {:>10}",
+            name,
+            self.count(),
+            self.total.load(Ordering::Relaxed),
+            self.max.load(Ordering::Relaxed)
+        );
+        for bucket in self.hist.iter() {
+            let _ = write!(out, " {}", bucket.load(Ordering::Relaxed));
+        }
+        out.push('\n');
+    }
+}
+
+/// Kinds of traps timed by `trap_handler`
+#[derive(Debug, Clone, Copy)]
+pub enum TrapKind {
+    Syscall,
+    PageFault,
+    IllegalInstruction,
+    Timer,
+    External,
+}
+
+const TRAP_KINDS: [TrapKind; 5] = [
+    TrapKind::Syscall,
+    TrapKind::PageFault,
+    TrapKind::IllegalInstruction,
+    TrapKind::Timer,
+    TrapKind::External,
+];
+
+static SYSCALL_COUNTERS: [Counter; MAX_SYSCALL_NUM] = [Counter::NEW; MAX_SYSCALL_NUM];
+static TRAP_COUNTERS: [Counter; TRAP_KINDS.len()] = [Counter::NEW; TRAP_KINDS.len()];
+
+/// Current time for a later [`record_syscall`] or [`record_trap`]
+pub fn start() -> usize {
+    get_time()
+}
+
+/// Record a syscall that began at `start`
+pub fn record_syscall(syscall_id: usize, start: usize) {
+    if let Some(counter) = SYSCALL_COUNTERS.get(syscall_id) {
+        counter.record(get_time().wrapping_sub(start));
+    }
+}
+
+/// Record the handling of a trap that began at `start`
+pub fn record_trap(kind: TrapKind, start: usize) {
+    TRAP_COUNTERS[kind as usize].record(get_time().wrapping_sub(start));
+}
+
+/// Clear all counters
+pub fn reset() {
+    SYSCALL_COUNTERS
+        .iter()
+        .chain(TRAP_COUNTERS.iter())
+        .for_each(Counter::reset);
+}
+
+/// Contents of `/proc/profile`, only syscalls and traps seen so far are listed
+pub fn render() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "ticks per second: {}", CLOCK_FREQ);
+    let _ = writeln!(
+        out,
+        "{:<20} {:>10} {:>14} {:>10} histogram (log2 ticks, {} buckets)",
+        "name", "count", "total_ticks", "max_ticks", HIST_BUCKETS
+    );
+    for kind in TRAP_KINDS {
+        let counter = &TRAP_COUNTERS[kind as usize];
+        if counter.count() > 0 {
+            counter.show(&format!("trap:{:?}", kind), &mut out);
+        }
+    }
+    for (id, counter) in SYSCALL_COUNTERS.iter().enumerate() {
+        if counter.count() == 0 {
+            continue;
+        }
+        let name = match syscall_name(id) {
+            Some(name) => format!("{}({})", name, id),
+            None => format!("syscall({})", id),
+        };
+        counter.show(&name, &mut out);
+    }
+    out
+}
@@ -11,6 +11,19 @@ const SBI_CONSOLE_PUTCHAR: usize = 1;
 const SBI_CONSOLE_GETCHAR: usize = 2;
 /// shutdown sbi call id
 const SBI_SHUTDOWN: usize = 8;
+/// hart state management extension id ("HSM")
+const SBI_EXT_HSM: usize = 0x48534d;
+/// start a stopped hart
+const SBI_HSM_HART_START: usize = 0;
+/// query the state of a hart
+const SBI_HSM_HART_GET_STATUS: usize = 2;
+/// ipi extension id ("sPI")
+const SBI_EXT_IPI: usize = 0x735049;
+/// send a supervisor software interrupt to a set of harts
+const SBI_IPI_SEND_IPI: usize = 0;
+
+/// `hart_status` of a hart that can be started with `hart_start`
+pub const HART_STOPPED: usize = 1;
 
 /// general sbi call
 #[inline(always)]
@@ -29,6 +42,23 @@ fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     ret
 }
 
+/// sbi call of an extension in SBI v0.2 and later, returning `(error, value)`
+#[inline(always)]
+fn sbi_call_ext(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> (isize, usize) {
+    let (error, value): (usize, usize);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x12") arg2,
+            in("x16") fid,
+            in("x17") eid,
+        );
+    }
+    (error as isize, value)
+}
+
 /// use sbi call to set timer
 pub fn set_timer(timer: usize) {
     sbi_call(SBI_SET_TIMER, timer, 0, 0);
@@ -49,3 +79,22 @@ pub fn shutdown() -> ! {
     sbi_call(SBI_SHUTDOWN, 0, 0, 0);
     panic!("It should shutdown!");
 }
+
+/// Start `hartid` at the physical address `start_addr` with paging off,
+/// `a0` = hartid and `a1` = `opaque`
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> isize {
+    sbi_call_ext(SBI_EXT_HSM, SBI_HSM_HART_START, hartid, start_addr, opaque).0
+}
+
+/// State of `hartid`, `None` if the hart does not exist
+pub fn hart_status(hartid: usize) -> Option<usize> {
+    match sbi_call_ext(SBI_EXT_HSM, SBI_HSM_HART_GET_STATUS, hartid, 0, 0) {
+        (0, status) => Some(status),
+        _ => None,
+    }
+}
+
+/// Raise a supervisor software interrupt on every hart in `hart_mask`
+pub fn send_ipi(hart_mask: usize) {
+    sbi_call_ext(SBI_EXT_IPI, SBI_IPI_SEND_IPI, hart_mask, 0, 0);
+}
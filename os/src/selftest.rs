@@ -0,0 +1,53 @@
+//! Boot-time self tests, enabled by the `selftest` feature
+//!
+//! 每个测试失败时直接 panic，panic handler 会通过 QEMU exit 设备以失败码退出；
+//! 全部通过后以成功码退出，便于 CI 自动判断是否出现回归。
+//!
+//! 同一套退出约定也用于 `cargo test`（`make test`）：各模块用 `#[test_case]` 标记的单元测试
+//! 由 [`test_runner`] 在内核初始化完成后依次运行，runner 脚本把 QEMU 的退出码交给 cargo。
+
+use crate::{block::block_cache::block_cache_test, boards, drivers::block::block_device_test};
+
+/// (name, test function)
+const SELF_TESTS: &[(&str, fn())] = &[
+    ("remap", crate::mm::remap_test),
+    ("block_device", block_device_test),
+    ("block_cache", block_cache_test),
+    ("fs", crate::fs::fs_test),
+    ("scheduler", crate::task::scheduler_test),
+];
+
+/// A `#[test_case]` function, printed with its path
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print!("[test] {} ... ", core::any::type_name::<T>());
+        self();
+        println!("ok");
+    }
+}
+
+/// Runner of the `custom_test_frameworks` harness, called through `test_main`
+pub fn test_runner(tests: &[&dyn Testable]) -> ! {
+    println!("[test] running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    println!("[test] all tests passed");
+    boards::shutdown()
+}
+
+/// Run all self tests and exit through the QEMU exit device
+pub fn run() -> ! {
+    println!("[selftest] running {} tests", SELF_TESTS.len());
+    for (i, (name, test)) in SELF_TESTS.iter().enumerate() {
+        println!("[selftest] ({}/{}) {} ...", i + 1, SELF_TESTS.len(), name);
+        test();
+        println!("[selftest] {} ok", name);
+    }
+    println!("[selftest] all tests passed");
+    boards::shutdown()
+}
@@ -0,0 +1,147 @@
+//! Bringing up the other harts
+//!
+//! 启动核初始化完成后用 SBI HSM 逐个启动其余的 hart。每个 hart 有自己的启动栈、应急栈
+//! 和 `Processor`，按 `hart_id % MAX_HARTS` 编号。hart id 在内核里一直放在 tp，
+//! 返回用户态时存进中断上下文，陷入时由 `__alltraps` 取回。
+//!
+//! 所有 hart 上线后都进入调度循环，加锁顺序见 [`crate::task`]。在 wfi 中空闲等待的 hart
+//! 记在 [`IDLE`] 里，放入就绪任务时用核间中断叫醒其中一个。
+//! 块设备、网络和块缓存回写的轮询只在启动核的时钟中断里做。
+
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use riscv::{asm::wfi, register::satp};
+
+use crate::{
+    config::{KERNEL_SPACE_OFFSET, MAX_HARTS},
+    mm::KERNEL_SPACE,
+    sbi::{hart_start, hart_status, send_ipi, HART_STOPPED},
+    task::{hart_id, has_ready_task},
+    timer::get_time_ms,
+    trap,
+};
+
+/// how long the boot hart waits for another hart to come up
+const HART_START_TIMEOUT_MS: usize = 1000;
+
+/// slots (`hart_id % MAX_HARTS`) of the harts that are up
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+/// ids of the harts waiting in wfi for work
+static IDLE: AtomicUsize = AtomicUsize::new(0);
+/// ids of the harts running a task
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+
+/// Start every other hart SBI knows about, one at a time
+pub fn start_secondary_harts() {
+    extern "C" {
+        fn _start_secondary();
+    }
+    // 新启动的 hart 还没开分页，入口用物理地址
+    let entry = _start_secondary as usize - (KERNEL_SPACE_OFFSET << 12);
+    let kernel_satp = KERNEL_SPACE.lock().token();
+    ONLINE.fetch_or(1 << (hart_id() % MAX_HARTS), Ordering::Relaxed);
+    // VisionFive2 的四个 U74 是 1..=4 号 hart，0 号是没有 S 态的 S7，HSM 不会报告它处于停止状态
+    for hart in 0..=MAX_HARTS {
+        let slot = 1 << (hart % MAX_HARTS);
+        if ONLINE.load(Ordering::Acquire) & slot != 0 || hart_status(hart) != Some(HART_STOPPED) {
+            continue;
+        }
+        let error = hart_start(hart, entry, kernel_satp);
+        if error != 0 {
+            warn!("failed to start hart {}: sbi error {}", hart, error);
+            continue;
+        }
+        let start = get_time_ms();
+        while ONLINE.load(Ordering::Acquire) & slot == 0
+            && get_time_ms() - start <= HART_START_TIMEOUT_MS
+        {
+            spin_loop();
+        }
+        if ONLINE.load(Ordering::Acquire) & slot != 0 {
+            info!("hart {} online", hart);
+        } else {
+            warn!("hart {} did not come up", hart);
+        }
+    }
+}
+
+/// Number of harts that are up
+pub fn online_harts() -> usize {
+    ONLINE.load(Ordering::Relaxed).count_ones() as usize
+}
+
+/// Rust entry of the harts started by `start_secondary_harts`, on the boot stack of the hart
+#[no_mangle]
+pub fn rust_main_secondary(hart: usize, kernel_satp: usize) -> ! {
+    unsafe {
+        satp::write(kernel_satp);
+        asm!("sfence.vma");
+    }
+    trap::init();
+    trap::enable_soft_interrupt();
+    // 由启动核报告上线，这里打日志会和启动核的输出交错
+    ONLINE.fetch_or(1 << (hart % MAX_HARTS), Ordering::Release);
+    trap::enable_timer_interrupt();
+    crate::timer::set_next_trigger();
+    loop {
+        crate::task::run_tasks();
+        idle(|| unsafe { wfi() });
+    }
+}
+
+/// Whether this is the hart that booted the kernel
+pub fn is_boot_hart() -> bool {
+    extern "C" {
+        // saved by entry.S
+        static boot_hart_id: usize;
+    }
+    hart_id() == unsafe { boot_hart_id }
+}
+
+/// Wait in `wait` with this hart marked idle, so that `kick_idle_hart` can wake it
+///
+/// 先标记空闲再检查就绪队列，放入任务的 hart 要么能看到空闲标记，要么任务已经在队列里。
+pub fn idle(wait: impl FnOnce()) {
+    let bit = 1 << hart_id();
+    IDLE.fetch_or(bit, Ordering::SeqCst);
+    if !has_ready_task() {
+        wait();
+    }
+    IDLE.fetch_and(!bit, Ordering::SeqCst);
+    clear_ipi();
+}
+
+/// Mark this hart as running a task or back in the scheduler
+pub fn set_busy(busy: bool) {
+    let bit = 1 << hart_id();
+    if busy {
+        BUSY.fetch_or(bit, Ordering::SeqCst);
+    } else {
+        BUSY.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Whether another hart is running a task, which may make more tasks ready
+pub fn other_harts_busy() -> bool {
+    BUSY.load(Ordering::SeqCst) & !(1 << hart_id()) != 0
+}
+
+/// Wake one idle hart, if any, to pick up a task just made ready
+pub fn kick_idle_hart() {
+    let idle = IDLE.load(Ordering::SeqCst) & !(1 << hart_id());
+    if idle != 0 {
+        send_ipi(1 << idle.trailing_zeros());
+    }
+}
+
+/// Acknowledge an inter-processor interrupt
+pub fn clear_ipi() {
+    unsafe {
+        // sip.SSIP
+        asm!("csrc sip, {}", in(reg) 1 << 1);
+    }
+}
@@ -1,50 +1,58 @@
-// //! Conditian variable
-
-// use crate::sync::UPSafeCell;
-// use crate::task::{block_current_and_run_next, current_task, wakeup_task, TaskControlBlock};
-// use alloc::{collections::VecDeque, sync::Arc};
-
-// use super::mutex::MutexSupport;
-
-// /// Condition variable structure
-// pub struct Condvar {
-//     /// Condition variable inner
-//     pub inner: UPSafeCell<CondvarInner>,
-// }
-
-// pub struct CondvarInner {
-//     pub wait_queue: VecDeque<Arc<TaskControlBlock>>,
-// }
-
-// impl Condvar {
-//     /// Create a new condition variable
-//     pub fn new() -> Self {
-//         trace!("kernel: Condvar::new");
-//         Self {
-//             inner: unsafe {
-//                 UPSafeCell::new(CondvarInner {
-//                     wait_queue: VecDeque::new(),
-//                 })
-//             },
-//         }
-//     }
-
-//     /// Signal a task waiting on the condition variable
-//     pub fn signal(&self) {
-//         let mut inner = self.inner.exclusive_access(file!(), line!());
-//         if let Some(task) = inner.wait_queue.pop_front() {
-//             wakeup_task(task);
-//         }
-//     }
-
-//     /// blocking current task, let it wait on the condition variable
-//     pub fn wait(&self, mutex: Arc<dyn MutexSupport>) {
-//         trace!("kernel: Condvar::wait_with_mutex");
-//         mutex.unlock();
-//         let mut inner = self.inner.exclusive_access(file!(), line!());
-//         inner.wait_queue.push_back(current_task().unwrap());
-//         drop(inner);
-//         block_current_and_run_next();
-//         mutex.lock();
-//     }
-// }
+//! Conditian variable
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::mutex::Mutex;
+use crate::{
+    sync::SpinNoIrqLock,
+    task::{block_current_and_run_next, current_task, wakeup_task, TaskControlBlock},
+};
+
+/// Condition variable structure
+pub struct Condvar {
+    /// Condition variable inner
+    pub inner: SpinNoIrqLock<CondvarInner>,
+}
+
+pub struct CondvarInner {
+    pub wait_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl Condvar {
+    /// Create a new condition variable
+    pub fn new() -> Self {
+        trace!("kernel: Condvar::new");
+        Self {
+            inner: SpinNoIrqLock::new(CondvarInner {
+                wait_queue: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Signal a task waiting on the condition variable
+    pub fn signal(&self) {
+        let mut inner = self.inner.lock();
+        if let Some(task) = inner.wait_queue.pop_front() {
+            drop(inner);
+            wakeup_task(task);
+        }
+    }
+
+    /// blocking current task, let it wait on the condition variable
+    pub fn wait(&self, mutex: Arc<dyn Mutex>) {
+        trace!("kernel: Condvar::wait_with_mutex");
+        // 先入队再解锁，解锁后到睡眠前的 signal 不会丢失
+        let mut inner = self.inner.lock();
+        inner.wait_queue.push_back(current_task().unwrap());
+        drop(inner);
+        mutex.unlock();
+        block_current_and_run_next();
+        mutex.lock();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,116 @@
+//! Deadlock detection for user mutexes and semaphores
+//!
+//! 线程组 leader 记录每种资源的可用数量，以及每个线程已经占有（allocation）和正在申请（need）
+//! 的数量。开启检测后，每次加锁或 P 操作前先把这次申请记入 need，再用银行家算法的安全性检查
+//! 判断是否还存在一个让所有线程都能完成的顺序；不存在就撤销申请，由系统调用返回 EDEADLK。
+//! 检查只看当前占有和申请的资源，用来在线程间传递事件的信号量（初值为 0，由别的线程 V）
+//! 会被当成死锁，这类程序不应开启检测。
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// A resource threads can hold and wait for
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Resource {
+    /// mutex id
+    Mutex(usize),
+    /// semaphore id
+    Semaphore(usize),
+}
+
+/// Units of each resource
+type Units = BTreeMap<Resource, usize>;
+
+/// Resource allocation state of a thread group, indexed by the threads' global pids
+#[derive(Default)]
+pub struct DeadlockDetector {
+    /// whether requests are checked, set by sys_enable_deadlock_detect
+    pub enabled: bool,
+    available:   Units,
+    allocation:  BTreeMap<usize, Units>,
+    need:        BTreeMap<usize, Units>,
+}
+
+impl DeadlockDetector {
+    /// Create a detector with no resources and detection off
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a resource with `count` free units, replacing what is known about a
+    /// resource that had the same id before
+    pub fn add_resource(&mut self, res: Resource, count: usize) {
+        self.available.insert(res, count);
+        for units in self.allocation.values_mut().chain(self.need.values_mut()) {
+            units.remove(&res);
+        }
+    }
+
+    /// Record that `task` is about to wait for one unit of `res`
+    ///
+    /// Return false and forget the request if detection is on and granting it could
+    /// leave the group deadlocked.
+    pub fn request(&mut self, task: usize, res: Resource) -> bool {
+        *self.need.entry(task).or_default().entry(res).or_default() += 1;
+        if self.enabled && !self.is_safe() {
+            dec(self.need.get_mut(&task).unwrap(), res);
+            return false;
+        }
+        true
+    }
+
+    /// `task` got the unit of `res` it requested
+    pub fn acquire(&mut self, task: usize, res: Resource) {
+        if let Some(need) = self.need.get_mut(&task) {
+            dec(need, res);
+        }
+        *self
+            .allocation
+            .entry(task)
+            .or_default()
+            .entry(res)
+            .or_default() += 1;
+        dec(&mut self.available, res);
+    }
+
+    /// `task` gave back one unit of `res`
+    ///
+    /// 信号量可以由没有做过 P 操作的线程 V，这时只增加可用数量。
+    pub fn release(&mut self, task: usize, res: Resource) {
+        if let Some(allocation) = self.allocation.get_mut(&task) {
+            dec(allocation, res);
+        }
+        *self.available.entry(res).or_default() += 1;
+    }
+
+    /// Whether every thread can still finish in some order
+    fn is_safe(&self) -> bool {
+        let mut work = self.available.clone();
+        let mut unfinished: Vec<usize> = self.allocation.keys().copied().collect();
+        for task in self.need.keys() {
+            if !unfinished.contains(task) {
+                unfinished.push(*task);
+            }
+        }
+        while let Some(idx) = unfinished.iter().position(|task| {
+            self.need.get(task).map_or(true, |need| {
+                need.iter()
+                    .all(|(res, &count)| work.get(res).copied().unwrap_or(0) >= count)
+            })
+        }) {
+            let task = unfinished.swap_remove(idx);
+            if let Some(allocation) = self.allocation.get(&task) {
+                for (&res, &count) in allocation {
+                    *work.entry(res).or_default() += count;
+                }
+            }
+        }
+        unfinished.is_empty()
+    }
+}
+
+/// Take one unit of `res` from `units`, stopping at zero
+fn dec(units: &mut Units, res: Resource) {
+    if let Some(count) = units.get_mut(&res) {
+        *count = count.saturating_sub(1);
+    }
+}
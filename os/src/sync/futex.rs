@@ -0,0 +1,108 @@
+//! Futexes
+//!
+//! 等待者按 futex 字的物理地址排队：同一地址空间的线程，以及映射了同一页帧（MAP_SHARED）的
+//! 进程，都能通过同一个字同步。取地址时先把页面换成可写的私有页帧，之后写时复制不会再换掉它。
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::ptr;
+
+use lazy_static::*;
+
+use super::{SpinNoIrqLock, WaitQueue};
+use crate::{
+    mm::{translated_user_pa, PhysAddr},
+    syscall::errno::{EAGAIN, ETIMEDOUT, SUCCESS},
+    task::current_task,
+    timer::get_time,
+};
+
+lazy_static! {
+    /// waiters of each futex word, keyed by its physical address
+    static ref FUTEX_QUEUES: SpinNoIrqLock<BTreeMap<usize, Arc<WaitQueue>>> =
+        SpinNoIrqLock::new(BTreeMap::new());
+}
+
+/// FUTEX_WAIT: block the current task on the word at `pa` if it still holds `val`, for
+/// at most `timeout` clock ticks
+pub fn futex_wait(pa: PhysAddr, val: u32, timeout: Option<usize>) -> isize {
+    let task = current_task().unwrap();
+    let mut queues = FUTEX_QUEUES.lock();
+    if unsafe { ptr::read_volatile(pa.get_ref::<u32>()) } != val {
+        return EAGAIN;
+    }
+    let queue = queues.entry(pa.0).or_default().clone();
+    let mut timed_out = match timeout {
+        Some(timeout) => queue.wait_until(queues, get_time() + timeout),
+        None => {
+            queue.wait(queues);
+            false
+        }
+    };
+    // 可能已被 requeue 到别的字上，还在那边的队列里同样是超时醒来的
+    let mut queues = FUTEX_QUEUES.lock();
+    if timeout.is_some() && !timed_out {
+        timed_out = queues.values().any(|queue| queue.remove(&task));
+    }
+    queues.retain(|_, queue| !queue.is_empty());
+    if timed_out {
+        ETIMEDOUT
+    } else {
+        SUCCESS
+    }
+}
+
+/// FUTEX_WAKE: wake at most `max` waiters of the word at `pa`, returning how many were woken
+pub fn futex_wake(pa: PhysAddr, max: usize) -> usize {
+    let mut queues = FUTEX_QUEUES.lock();
+    let Some(queue) = queues.get(&pa.0) else {
+        return 0;
+    };
+    let woken = queue.wake(max);
+    if queue.is_empty() {
+        queues.remove(&pa.0);
+    }
+    woken
+}
+
+/// FUTEX_REQUEUE: wake at most `max_wake` waiters of the word at `pa` and move at most
+/// `max_requeue` of the others to the word at `pa2`, returning how many were woken or moved
+pub fn futex_requeue(pa: PhysAddr, max_wake: usize, pa2: PhysAddr, max_requeue: usize) -> usize {
+    let mut queues = FUTEX_QUEUES.lock();
+    let Some(queue) = queues.get(&pa.0).cloned() else {
+        return 0;
+    };
+    let woken = queue.wake(max_wake);
+    let moved = if pa2.0 != pa.0 {
+        let target = queues.entry(pa2.0).or_default();
+        queue.requeue(target, max_requeue)
+    } else {
+        queue.len().min(max_requeue)
+    };
+    queues.retain(|_, queue| !queue.is_empty());
+    woken + moved
+}
+
+/// CLONE_CHILD_CLEARTID on thread exit: zero the tid at `ptr` and wake one waiter on it,
+/// which is how pthread_join waits
+pub fn clear_child_tid(token: usize, ptr: usize) {
+    if let Some(pa) = translated_user_pa(token, ptr, true) {
+        *pa.get_mut::<u32>() = 0;
+        futex_wake(pa, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::frame_alloc;
+
+    #[test_case]
+    fn wake_without_waiters() {
+        let frame = frame_alloc().unwrap();
+        let pa = PhysAddr::from(frame.ppn);
+        assert_eq!(futex_wake(pa, 1), 0);
+        assert_eq!(futex_requeue(pa, 1, PhysAddr(pa.0 + 4), 1), 0);
+        // 没有等待者时不留下空队列
+        assert!(!FUTEX_QUEUES.lock().contains_key(&pa.0));
+    }
+}
@@ -0,0 +1,71 @@
+//! IRQ-save critical sections
+//!
+//! [`IrqGuard`] 构造时关闭 S 态中断，析构时恢复。守卫可以嵌套：每个 hart 记录嵌套深度，
+//! 只有最外层守卫保存进入前的 `sstatus.SIE`，并在最外层守卫析构时恢复，
+//! 内层守卫析构不会提前打开中断（和 xv6 的 push_off/pop_off 相同）。
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use riscv::register::sstatus;
+
+use crate::{config::MAX_HARTS, task::hart_id};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// live guards on each hart
+static DEPTH: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+/// `sstatus.SIE` before the outermost guard of each hart
+static SAVED_SIE: [AtomicBool; MAX_HARTS] = [DISABLED; MAX_HARTS];
+
+/// Interrupts stay off on this hart while any guard is alive
+pub struct IrqGuard {
+    hart: usize,
+}
+
+/// 深度按 hart 记录，守卫不能交给别的 hart 释放
+impl !Send for IrqGuard {}
+
+impl IrqGuard {
+    /// Disable interrupts, remembering whether they were on if this is the outermost guard
+    pub fn new() -> Self {
+        let enabled = sstatus::read().sie();
+        unsafe {
+            sstatus::clear_sie();
+        }
+        let hart = hart_id() % MAX_HARTS;
+        if DEPTH[hart].fetch_add(1, Ordering::Relaxed) == 0 {
+            SAVED_SIE[hart].store(enabled, Ordering::Relaxed);
+        }
+        Self { hart }
+    }
+
+    /// Number of guards alive on the current hart
+    pub fn depth() -> usize {
+        DEPTH[hart_id() % MAX_HARTS].load(Ordering::Relaxed)
+    }
+}
+
+impl Default for IrqGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        assert!(
+            !sstatus::read().sie(),
+            "interrupts enabled inside an IrqGuard"
+        );
+        if DEPTH[self.hart].fetch_sub(1, Ordering::Relaxed) == 1
+            && SAVED_SIE[self.hart].load(Ordering::Relaxed)
+        {
+            unsafe {
+                sstatus::set_sie();
+            }
+        }
+    }
+}
@@ -1,10 +1,17 @@
 //! Synchronization and interior mutability primitives
 
 mod condvar;
+pub mod deadlock;
+pub mod futex;
+mod irq;
 pub mod mutex;
 mod semaphore;
-mod up;
+mod sleep_lock;
+mod wait_queue;
 
-// pub use condvar::Condvar;
+pub use condvar::Condvar;
+pub use irq::IrqGuard;
+pub use mutex::{SpinNoIrqLock, SpinNoIrqLockGuard};
 pub use semaphore::Semaphore;
-pub use up::UPSafeCell;
+pub use sleep_lock::{SleepLock, SleepLockGuard};
+pub use wait_queue::WaitQueue;
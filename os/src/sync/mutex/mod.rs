@@ -1,15 +1,22 @@
 //! Mutex (spin-like and blocking(sleep))
 
-use riscv::register::sstatus;
+pub use spin_mutex::MutexGuard;
 use spin_mutex::SpinMutex;
 
+use super::IrqGuard;
+
 /// SpinMutex
 pub mod spin_mutex;
+mod task_mutex;
+
+pub use task_mutex::{MutexBlocking, MutexSpin};
 
 /// SpinLock
 pub type SpinLock<T> = SpinMutex<T, Spin>;
 /// SpinNoIrqLock(Cannot be interrupted)
 pub type SpinNoIrqLock<T> = SpinMutex<T, SpinNoIrq>;
+/// Guard of a [`SpinNoIrqLock`]
+pub type SpinNoIrqLockGuard<'a, T> = MutexGuard<'a, T, SpinNoIrq>;
 
 /// Mutex trait
 pub trait Mutex: Sync + Send {
@@ -42,38 +49,14 @@ impl MutexSupport for Spin {
     fn after_unlock(_: &mut Self::GuardData) {}
 }
 
-/// Sie Guard
-pub struct SieGuard(bool);
-
-impl SieGuard {
-    /// Construct a SieGuard 关中断
-    pub fn new() -> Self {
-        Self(unsafe {
-            let sie_before = sstatus::read().sie();
-            sstatus::clear_sie();
-            sie_before
-        })
-    }
-}
-impl Drop for SieGuard {
-    /// 开中断
-    fn drop(&mut self) {
-        if self.0 {
-            unsafe {
-                sstatus::set_sie();
-            }
-        }
-    }
-}
-
 /// SpinNoIrq MutexSupport
 pub struct SpinNoIrq;
 
 impl MutexSupport for SpinNoIrq {
-    type GuardData = SieGuard;
+    type GuardData = IrqGuard;
     #[inline(always)]
     fn before_lock() -> Self::GuardData {
-        SieGuard::new()
+        IrqGuard::new()
     }
     #[inline(always)]
     fn after_unlock(_: &mut Self::GuardData) {}
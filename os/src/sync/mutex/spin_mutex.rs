@@ -1,21 +1,35 @@
+//! Spin lock shared by the harts
+//!
+//! 同一个 hart 重复加锁必然死锁（持有者要等这个 hart 回来才能解锁），这里直接 panic，
+//! 并报告上一次加锁的位置。
+
 use core::{
     cell::UnsafeCell,
     marker::PhantomData,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
-use crate::{sync::mutex::MutexSupport, utils::async_utils::SendWrapper};
+use crate::{sync::mutex::MutexSupport, task::hart_id, utils::async_utils::SendWrapper};
+
+/// `owner` of a free lock
+const NO_OWNER: usize = usize::MAX;
 
 /// `SpinMutex` can include different `MutexSupport` type
 pub struct SpinMutex<T: ?Sized, S: MutexSupport> {
     // debug_cnt: UnsafeCell<usize>,
-    lock:    AtomicBool,
-    _marker: PhantomData<S>,
-    data:    UnsafeCell<T>,
+    lock:     AtomicBool,
+    /// hart holding the lock
+    owner:    AtomicUsize,
+    /// where the holder took the lock, only accessed by the holder
+    location: UnsafeCell<Option<&'static Location<'static>>>,
+    _marker:  PhantomData<S>,
+    data:     UnsafeCell<T>,
 }
 
-struct MutexGuard<'a, T: ?Sized, S: MutexSupport> {
+/// Holds a [`SpinMutex`] until dropped
+pub struct MutexGuard<'a, T: ?Sized, S: MutexSupport> {
     mutex:         &'a SpinMutex<T, S>,
     support_guard: S::GuardData,
 }
@@ -24,16 +38,19 @@ struct MutexGuard<'a, T: ?Sized, S: MutexSupport> {
 impl<'a, T: ?Sized, S: MutexSupport> !Sync for MutexGuard<'a, T, S> {}
 impl<'a, T: ?Sized, S: MutexSupport> !Send for MutexGuard<'a, T, S> {}
 
-unsafe impl<T: ?Sized + Send, S: MutexSupport> Sync for SpinMutex<T, S> {}
+// 内核里不少被保护的数据含有裸指针，和 SpinNoIrqLock 一样不要求 T: Send
+unsafe impl<T: ?Sized, S: MutexSupport> Sync for SpinMutex<T, S> {}
 unsafe impl<T: ?Sized + Send, S: MutexSupport> Send for SpinMutex<T, S> {}
 
 impl<'a, T, S: MutexSupport> SpinMutex<T, S> {
     /// Construct a SpinMutex
     pub const fn new(user_data: T) -> Self {
         SpinMutex {
-            lock:    AtomicBool::new(false),
-            _marker: PhantomData,
-            data:    UnsafeCell::new(user_data),
+            lock:     AtomicBool::new(false),
+            owner:    AtomicUsize::new(NO_OWNER),
+            location: UnsafeCell::new(None),
+            _marker:  PhantomData,
+            data:     UnsafeCell::new(user_data),
             // debug_cnt: UnsafeCell::new(0),
         }
     }
@@ -54,9 +71,21 @@ impl<'a, T, S: MutexSupport> SpinMutex<T, S> {
 
     /// Note that the locked data cannot step over `await`,
     /// i.e. cannot be sent between thread.
+    ///
+    /// Panic if the current hart already holds the lock.
     #[inline(always)]
-    pub fn lock(&self) -> impl DerefMut<Target = T> + '_ {
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T, S> {
         let support_guard = S::before_lock();
+        let hart = hart_id();
+        if self.owner.load(Ordering::Relaxed) == hart {
+            panic!(
+                "lock at {} already held by hart {} since {:?}",
+                Location::caller(),
+                hart,
+                unsafe { *self.location.get() }
+            );
+        }
         loop {
             self.wait_unlock();
             if self
@@ -67,12 +96,57 @@ impl<'a, T, S: MutexSupport> SpinMutex<T, S> {
                 break;
             }
         }
+        self.hold(hart);
         MutexGuard {
             mutex: self,
             support_guard,
         }
     }
 
+    /// Try to lock the mutex once, return `None` if it is held
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T, S>> {
+        let support_guard = S::before_lock();
+        self.lock
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.hold(hart_id());
+        Some(MutexGuard {
+            mutex: self,
+            support_guard,
+        })
+    }
+
+    /// Lock the mutex, or return `None` if the current hart already holds it
+    ///
+    /// 给打断了持有者的代码用，比如内核访问用户内存时的缺页，等下去只会死锁。
+    #[inline(always)]
+    #[track_caller]
+    pub fn lock_unless_held(&self) -> Option<MutexGuard<'_, T, S>> {
+        self.try_lock()
+            .or_else(|| (self.owner.load(Ordering::Relaxed) != hart_id()).then(|| self.lock()))
+    }
+
+    #[inline(always)]
+    #[track_caller]
+    fn hold(&self, hart: usize) {
+        self.owner.store(hart, Ordering::Relaxed);
+        unsafe {
+            *self.location.get() = Some(Location::caller());
+        }
+    }
+
+    /// Get the data even if the lock is held.
+    ///
+    /// # Safety
+    ///
+    /// For handlers interrupting the holder, which must not be using the parts of
+    /// the data the caller changes.
+    pub unsafe fn get_unchecked(&self) -> &mut T {
+        &mut *self.data.get()
+    }
+
     /// # SAFETY
     /// This is highly unsafe.
     /// You should ensure that context switch won't happen during
@@ -103,6 +177,7 @@ impl<'a, T: ?Sized, S: MutexSupport> Drop for MutexGuard<'a, T, S> {
     #[inline(always)]
     fn drop(&mut self) {
         // debug_assert!(self.mutex.lock.load(Ordering::Relaxed));
+        self.mutex.owner.store(NO_OWNER, Ordering::Relaxed);
         self.mutex.lock.store(false, Ordering::Release);
         S::after_unlock(&mut self.support_guard);
     }
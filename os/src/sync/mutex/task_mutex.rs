@@ -0,0 +1,114 @@
+//! Mutexes handed out to user programs by `sys_mutex_create`
+//!
+//! 两种锁都不会在内核里忙等：[`MutexSpin`] 拿不到锁就让出 CPU 稍后重试，
+//! [`MutexBlocking`] 把当前任务挂到等待队列上睡眠，解锁时把锁直接交给队首的任务。
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::Mutex;
+use crate::{
+    sync::SpinNoIrqLock,
+    task::{
+        block_current_and_run_next,
+        current_task,
+        suspend_current_and_run_next,
+        wakeup_task,
+        TaskControlBlock,
+    },
+};
+
+/// Mutex that yields the CPU until the lock is free
+pub struct MutexSpin {
+    locked: SpinNoIrqLock<bool>,
+}
+
+impl MutexSpin {
+    /// Create a new unlocked mutex
+    pub fn new() -> Self {
+        Self {
+            locked: SpinNoIrqLock::new(false),
+        }
+    }
+}
+
+impl Default for MutexSpin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mutex for MutexSpin {
+    fn lock(&self) {
+        trace!("kernel: MutexSpin::lock");
+        loop {
+            let mut locked = self.locked.lock();
+            if *locked {
+                drop(locked);
+                suspend_current_and_run_next();
+            } else {
+                *locked = true;
+                return;
+            }
+        }
+    }
+
+    fn unlock(&self) {
+        trace!("kernel: MutexSpin::unlock");
+        *self.locked.lock() = false;
+    }
+}
+
+/// Mutex that puts waiters to sleep
+pub struct MutexBlocking {
+    inner: SpinNoIrqLock<MutexBlockingInner>,
+}
+
+struct MutexBlockingInner {
+    locked:     bool,
+    wait_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl MutexBlocking {
+    /// Create a new unlocked mutex
+    pub fn new() -> Self {
+        Self {
+            inner: SpinNoIrqLock::new(MutexBlockingInner {
+                locked:     false,
+                wait_queue: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl Default for MutexBlocking {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mutex for MutexBlocking {
+    fn lock(&self) {
+        trace!("kernel: MutexBlocking::lock");
+        let mut inner = self.inner.lock();
+        if inner.locked {
+            inner.wait_queue.push_back(current_task().unwrap());
+            drop(inner);
+            // 醒来时锁已经由 unlock 交给了自己
+            block_current_and_run_next();
+        } else {
+            inner.locked = true;
+        }
+    }
+
+    fn unlock(&self) {
+        trace!("kernel: MutexBlocking::unlock");
+        let mut inner = self.inner.lock();
+        assert!(inner.locked, "unlocking a mutex that is not locked");
+        if let Some(waiter) = inner.wait_queue.pop_front() {
+            drop(inner);
+            wakeup_task(waiter);
+        } else {
+            inner.locked = false;
+        }
+    }
+}
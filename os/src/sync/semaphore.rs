@@ -3,14 +3,14 @@
 use alloc::{collections::VecDeque, sync::Arc};
 
 use crate::{
-    sync::UPSafeCell,
+    sync::SpinNoIrqLock,
     task::{block_current_and_run_next, current_task, wakeup_task, TaskControlBlock},
 };
 
 /// semaphore structure
 pub struct Semaphore {
     /// semaphore inner
-    pub inner: UPSafeCell<SemaphoreInner>,
+    pub inner: SpinNoIrqLock<SemaphoreInner>,
 }
 
 pub struct SemaphoreInner {
@@ -23,19 +23,17 @@ impl Semaphore {
     pub fn new(res_count: usize) -> Self {
         trace!("kernel: Semaphore::new");
         Self {
-            inner: unsafe {
-                UPSafeCell::new(SemaphoreInner {
-                    count:      res_count as isize,
-                    wait_queue: VecDeque::new(),
-                })
-            },
+            inner: SpinNoIrqLock::new(SemaphoreInner {
+                count:      res_count as isize,
+                wait_queue: VecDeque::new(),
+            }),
         }
     }
 
     /// up operation of semaphore
     pub fn up(&self) {
         trace!("kernel: Semaphore::up");
-        let mut inner = self.inner.exclusive_access(file!(), line!());
+        let mut inner = self.inner.lock();
         inner.count += 1;
         if inner.count <= 0 {
             if let Some(task) = inner.wait_queue.pop_front() {
@@ -47,7 +45,7 @@ impl Semaphore {
     /// down operation of semaphore
     pub fn down(&self) {
         trace!("kernel: Semaphore::down");
-        let mut inner = self.inner.exclusive_access(file!(), line!());
+        let mut inner = self.inner.lock();
         inner.count -= 1;
         if inner.count < 0 {
             inner.wait_queue.push_back(current_task().unwrap());
@@ -0,0 +1,95 @@
+//! Lock that may be held while sleeping
+//!
+//! 持锁期间可以阻塞（比如等块设备传输），等锁的任务挂在等待队列上睡眠而不是忙等。
+//! 解锁时锁直接交给队首的任务，和 `MutexBlocking` 相同。只能在任务上下文里使用，
+//! 空闲循环和时钟中断里的轮询没有可以阻塞的任务。
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+};
+
+use super::SpinNoIrqLock;
+use crate::task::{block_current_in_syscall, current_task, wakeup_task, TaskControlBlock};
+
+struct SleepLockState {
+    locked:  bool,
+    waiters: VecDeque<Arc<TaskControlBlock>>,
+}
+
+/// Mutual exclusion between tasks, contending tasks sleep
+pub struct SleepLock<T> {
+    state: SpinNoIrqLock<SleepLockState>,
+    data:  UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SleepLock<T> {}
+
+impl<T> SleepLock<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            state: SpinNoIrqLock::new(SleepLockState {
+                locked:  false,
+                waiters: VecDeque::new(),
+            }),
+            data:  UnsafeCell::new(data),
+        }
+    }
+
+    /// Sleep until the lock is free
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        let mut state = self.state.lock();
+        if state.locked {
+            let task = current_task().expect("sleep lock contended outside a task");
+            state.waiters.push_back(task);
+            drop(state);
+            // 醒来时锁已经由解锁的任务交给了自己
+            block_current_in_syscall();
+        } else {
+            state.locked = true;
+        }
+        SleepLockGuard { lock: self }
+    }
+
+    /// Like `lock`, but return `None` instead of sleeping if the lock is held
+    pub fn try_lock(&self) -> Option<SleepLockGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(SleepLockGuard { lock: self })
+    }
+}
+
+/// Holds a [`SleepLock`] until dropped, and may be held across blocking
+pub struct SleepLockGuard<'a, T> {
+    lock: &'a SleepLock<T>,
+}
+
+impl<T> Deref for SleepLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SleepLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SleepLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        if let Some(waiter) = state.waiters.pop_front() {
+            drop(state);
+            wakeup_task(waiter);
+        } else {
+            state.locked = false;
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Queues of tasks sleeping until a condition may hold
+//!
+//! 等待者在持有保护条件的锁时检查条件并入队，入队后才释放锁并阻塞；唤醒方修改条件后再唤醒，
+//! 这样检查和阻塞之间的唤醒不会丢失。醒来时条件不一定成立（超时、被别的等待者抢先），
+//! 调用者要重新检查。
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+
+use super::SpinNoIrqLock;
+use crate::{
+    task::{block_current_in_syscall, current_task, wakeup_task, TaskControlBlock},
+    timer::{add_timer, remove_timer},
+};
+
+/// Tasks sleeping until woken, in the order they came
+pub struct WaitQueue {
+    waiters: SpinNoIrqLock<VecDeque<Arc<TaskControlBlock>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinNoIrqLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Sleep until woken, releasing `guard` once the current task is on the queue
+    pub fn wait<G>(&self, guard: G) {
+        self.waiters.lock().push_back(current_task().unwrap());
+        drop(guard);
+        block_current_in_syscall();
+    }
+
+    /// Like `wait`, but wake at `deadline` in clock ticks at the latest, true if not woken
+    /// before the deadline
+    pub fn wait_until<G>(&self, guard: G, deadline: usize) -> bool {
+        let task = current_task().unwrap();
+        self.waiters.lock().push_back(task.clone());
+        drop(guard);
+        add_timer(deadline, task.clone());
+        block_current_in_syscall();
+        remove_timer(task.clone());
+        // 被唤醒时已经出队，还在队列里说明是定时器叫醒的
+        self.remove(&task)
+    }
+
+    /// Wake at most `max` waiters, returning how many were woken
+    pub fn wake(&self, max: usize) -> usize {
+        let woken: Vec<_> = {
+            let mut waiters = self.waiters.lock();
+            let count = waiters.len().min(max);
+            waiters.drain(..count).collect()
+        };
+        let count = woken.len();
+        woken.into_iter().for_each(wakeup_task);
+        count
+    }
+
+    pub fn wake_one(&self) -> bool {
+        self.wake(1) == 1
+    }
+
+    pub fn wake_all(&self) -> usize {
+        self.wake(usize::MAX)
+    }
+
+    /// Move at most `max` waiters to the end of `other` without waking them
+    pub fn requeue(&self, other: &WaitQueue, max: usize) -> usize {
+        let moved: Vec<_> = {
+            let mut waiters = self.waiters.lock();
+            let count = waiters.len().min(max);
+            waiters.drain(..count).collect()
+        };
+        let count = moved.len();
+        other.waiters.lock().extend(moved);
+        count
+    }
+
+    /// Take `task` off the queue, false if it is not on it
+    pub fn remove(&self, task: &Arc<TaskControlBlock>) -> bool {
+        let mut waiters = self.waiters.lock();
+        match waiters.iter().position(|waiter| Arc::ptr_eq(waiter, task)) {
+            Some(idx) => {
+                waiters.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,154 @@
+//! Security audit log
+//!
+//! 对敏感的系统调用（执行程序、挂载、修改身份、发送信号、建立可执行映射）不论成败都记录一条，
+//! 包括调用前的 pid、euid、参数和返回值，放在独立的环形缓冲区里，不会被普通内核日志冲掉。
+//! 缓冲区满时丢弃最早的记录并计数。通过只有 root 可读的 `/proc/audit` 读出，写入任意内容清空，
+//! 可用于检查沙箱中运行的程序做了什么。
+
+use alloc::{collections::VecDeque, string::String};
+use core::fmt::Write;
+
+use spin::Mutex;
+
+use super::{errno::Errno, trace, *};
+use crate::{
+    mm::{PageTable, PhysAddr, VirtAddr},
+    task::current_user_token,
+    timer::get_time_ms,
+};
+
+/// records kept at most
+pub const AUDIT_RING_LEN: usize = 256;
+/// `prot` bit of mmap and mprotect asking for an executable mapping
+const PROT_EXEC: usize = 0x4;
+/// argv entries of execve printed at most
+const MAX_ARGV: usize = 16;
+
+struct AuditRecord {
+    seq:  usize,
+    /// milliseconds since boot
+    time: usize,
+    pid:  usize,
+    /// effective uid when the call was made
+    uid:  u32,
+    /// `name(args)` as printed by `syscall::trace`
+    call: String,
+    ret:  isize,
+}
+
+struct AuditLog {
+    records:  VecDeque<AuditRecord>,
+    next_seq: usize,
+    /// records dropped because the ring was full
+    lost:     usize,
+}
+
+static AUDIT_LOG: Mutex<AuditLog> = Mutex::new(AuditLog {
+    records:  VecDeque::new(),
+    next_seq: 0,
+    lost:     0,
+});
+
+/// Whether this call is recorded
+pub fn audited(syscall_id: usize, args: &[usize; 6]) -> bool {
+    match syscall_id {
+        SYSCALL_EXECVE | SYSCALL_SPAWN | SYSCALL_RESTORE => true,
+        SYSCALL_MOUNT | SYSCALL_UMOUNT2 => true,
+        SYSCALL_SETUID | SYSCALL_SETRESUID | SYSCALL_SETGID | SYSCALL_SETRESGID => true,
+        SYSCALL_KILL => true,
+        SYSCALL_MMAP | SYSCALL_MPROTECT => args[2] & PROT_EXEC != 0,
+        _ => false,
+    }
+}
+
+/// Read a usize from user memory, `None` if it is not mapped
+fn read_user_usize(token: usize, ptr: usize) -> Option<usize> {
+    let page_table = PageTable::from_token(token);
+    let va = VirtAddr::from(ptr);
+    if ptr % core::mem::size_of::<usize>() != 0
+        || !page_table
+            .translate(va.floor())
+            .map_or(false, |pte| pte.is_valid() && pte.readable())
+    {
+        return None;
+    }
+    let pa: PhysAddr = page_table.translate_va(va)?;
+    Some(*pa.get_ref::<usize>())
+}
+
+/// The call as it will be recorded, formatted before it runs
+///
+/// execve 的参数在 exec 之后就读不到了，所以要在调用前格式化。
+pub fn format_call(syscall_id: usize, args: &[usize; 6]) -> String {
+    let mut call = trace::format_call(syscall_id, args);
+    if syscall_id != SYSCALL_EXECVE || args[1] == 0 {
+        return call;
+    }
+    let token = current_user_token();
+    call.push_str(" argv=[");
+    for i in 0..=MAX_ARGV {
+        let arg = match read_user_usize(token, args[1] + i * core::mem::size_of::<usize>()) {
+            Some(0) => break,
+            Some(arg) => arg,
+            None => {
+                call.push_str("<fault>");
+                break;
+            }
+        };
+        if i > 0 {
+            call.push_str(", ");
+        }
+        if i == MAX_ARGV {
+            call.push_str("...");
+            break;
+        }
+        trace::read_user_str(token, arg, &mut call);
+    }
+    call.push(']');
+    call
+}
+
+/// Append a finished call
+pub fn record(pid: usize, uid: u32, call: String, ret: isize) {
+    let mut log = AUDIT_LOG.lock();
+    if log.records.len() == AUDIT_RING_LEN {
+        log.records.pop_front();
+        log.lost += 1;
+    }
+    let seq = log.next_seq;
+    log.next_seq += 1;
+    log.records.push_back(AuditRecord {
+        seq,
+        time: get_time_ms(),
+        pid,
+        uid,
+        call,
+        ret,
+    });
+}
+
+/// Contents of `/proc/audit`
+pub fn render() -> String {
+    let log = AUDIT_LOG.lock();
+    let mut out = String::new();
+    let _ = writeln!(out, "records: {}, lost: {}", log.records.len(), log.lost);
+    for record in log.records.iter() {
+        let _ = write!(
+            out,
+            "[{}] time={} pid={} uid={} {} = ",
+            record.seq, record.time, record.pid, record.uid, record.call
+        );
+        let _ = match Errno::try_from(record.ret) {
+            Ok(errno) if record.ret < 0 => writeln!(out, "-1 {:?}", errno),
+            _ => writeln!(out, "{}", record.ret),
+        };
+    }
+    out
+}
+
+/// Drop all records, keeping the sequence numbers going
+pub fn clear() {
+    let mut log = AUDIT_LOG.lock();
+    log.records.clear();
+    log.lost = 0;
+}
@@ -0,0 +1,57 @@
+//! checkpoint and restore syscalls, see [`crate::task::checkpoint`]
+
+use alloc::sync::Arc;
+
+use crate::{
+    fs::{defs::OpenFlags, inode::Inode, may_open, open_file},
+    mm::translated_str,
+    syscall::errno::EACCES,
+    task::{current_task, current_user_token},
+};
+
+/// Open `path` relative to the working directory of the current task
+fn open_image(path: *const u8, flags: OpenFlags) -> Result<Arc<dyn Inode>, isize> {
+    let task = current_task().unwrap();
+    let path = translated_str(current_user_token(), path);
+    let (curdir, cred) = {
+        let inner = task.inner_exclusive_access();
+        (inner.work_dir.clone(), inner.cred.clone())
+    };
+    if !may_open(&cred, &curdir, path.as_str(), flags) {
+        return Err(EACCES);
+    }
+    open_file(&curdir, path.as_str(), flags).map(|dentry| dentry.inode())
+}
+
+/// Save the current process to `path`
+///
+/// 返回 0；从镜像恢复的进程在这里返回 1。
+pub fn sys_checkpoint(path: *const u8) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_checkpoint",
+        current_task().unwrap().pid.0
+    );
+    let flags = OpenFlags::O_CREAT | OpenFlags::O_TRUNC | OpenFlags::O_WRONLY;
+    let image = match open_image(path, flags) {
+        Ok(image) => image,
+        Err(errno) => return errno,
+    };
+    match current_task().unwrap().checkpoint(image) {
+        Ok(()) => 0,
+        Err(errno) => errno,
+    }
+}
+
+/// Replace the current process with the one saved in `path`, only returns on failure
+pub fn sys_restore(path: *const u8) -> isize {
+    trace!("kernel:pid[{}] sys_restore", current_task().unwrap().pid.0);
+    let image = match open_image(path, OpenFlags::O_RDONLY) {
+        Ok(image) => image,
+        Err(errno) => return errno,
+    };
+    match current_task().unwrap().restore(image) {
+        // trap_handler 会把返回值写进新的 a0，即恢复出的 checkpoint 的返回值
+        Ok(()) => 1,
+        Err(errno) => errno,
+    }
+}
@@ -0,0 +1,170 @@
+//! User and group id syscalls, see [`crate::task::cred`]
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::errno::{EFAULT, EINVAL, EPERM, SUCCESS};
+use crate::{
+    mm::{translated_ref, translated_refmut, user_range_ok},
+    task::{
+        cred::{Credentials, NGROUPS_MAX},
+        current_task,
+        current_user_token,
+    },
+};
+
+/// `-1` passed to setres[ug]id leaves that id unchanged
+const ID_UNCHANGED: u32 = u32::MAX;
+
+fn optional_id(id: usize) -> Option<u32> {
+    match id as u32 {
+        ID_UNCHANGED => None,
+        id => Some(id),
+    }
+}
+
+fn with_cred<T>(f: impl FnOnce(&mut Credentials) -> T) -> T {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    f(&mut inner.cred)
+}
+
+fn ok_or_eperm(ok: bool) -> isize {
+    if ok {
+        SUCCESS
+    } else {
+        EPERM
+    }
+}
+
+/// 获取实际用户 id
+pub fn sys_getuid() -> isize {
+    trace!("kernel:pid[{}] sys_getuid", current_task().unwrap().pid.0);
+    with_cred(|cred| cred.uid as isize)
+}
+
+/// 获取有效用户 id，即相当于哪个用户的权限
+pub fn sys_geteuid() -> isize {
+    trace!("kernel:pid[{}] sys_geteuid", current_task().unwrap().pid.0);
+    with_cred(|cred| cred.euid as isize)
+}
+
+/// 获取实际用户组 id
+pub fn sys_getgid() -> isize {
+    trace!("kernel:pid[{}] sys_getgid", current_task().unwrap().pid.0);
+    with_cred(|cred| cred.gid as isize)
+}
+
+/// 获取有效用户组 id，即相当于哪个用户组的权限
+pub fn sys_getegid() -> isize {
+    trace!("kernel:pid[{}] sys_getegid", current_task().unwrap().pid.0);
+    with_cred(|cred| cred.egid as isize)
+}
+
+pub fn sys_setuid(uid: usize) -> isize {
+    trace!("kernel:pid[{}] sys_setuid", current_task().unwrap().pid.0);
+    ok_or_eperm(with_cred(|cred| cred.setuid(uid as u32)))
+}
+
+pub fn sys_setgid(gid: usize) -> isize {
+    trace!("kernel:pid[{}] sys_setgid", current_task().unwrap().pid.0);
+    ok_or_eperm(with_cred(|cred| cred.setgid(gid as u32)))
+}
+
+pub fn sys_setresuid(uid: usize, euid: usize, suid: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_setresuid",
+        current_task().unwrap().pid.0
+    );
+    ok_or_eperm(with_cred(|cred| {
+        cred.setresuid(optional_id(uid), optional_id(euid), optional_id(suid))
+    }))
+}
+
+pub fn sys_setresgid(gid: usize, egid: usize, sgid: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_setresgid",
+        current_task().unwrap().pid.0
+    );
+    ok_or_eperm(with_cred(|cred| {
+        cred.setresgid(optional_id(gid), optional_id(egid), optional_id(sgid))
+    }))
+}
+
+/// Write `ids` to the user pointers `ptrs`, EFAULT without writing any if one of them
+/// is not mapped writable
+fn write_ids(ids: [u32; 3], ptrs: [*mut u32; 3]) -> isize {
+    let token = current_user_token();
+    if !ptrs
+        .iter()
+        .all(|&ptr| user_range_ok(token, ptr as usize, size_of::<u32>(), true))
+    {
+        return EFAULT;
+    }
+    for (id, ptr) in ids.into_iter().zip(ptrs) {
+        *translated_refmut(token, ptr) = id;
+    }
+    SUCCESS
+}
+
+pub fn sys_getresuid(uid: *mut u32, euid: *mut u32, suid: *mut u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getresuid",
+        current_task().unwrap().pid.0
+    );
+    let ids = with_cred(|cred| [cred.uid, cred.euid, cred.suid]);
+    write_ids(ids, [uid, euid, suid])
+}
+
+pub fn sys_getresgid(gid: *mut u32, egid: *mut u32, sgid: *mut u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getresgid",
+        current_task().unwrap().pid.0
+    );
+    let ids = with_cred(|cred| [cred.gid, cred.egid, cred.sgid]);
+    write_ids(ids, [gid, egid, sgid])
+}
+
+/// getgroups syscall
+///
+/// With `size` 0 only the number of supplementary groups is returned.
+pub fn sys_getgroups(size: usize, list: *mut u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getgroups",
+        current_task().unwrap().pid.0
+    );
+    let groups = with_cred(|cred| cred.groups.clone());
+    if size == 0 {
+        return groups.len() as isize;
+    }
+    if size < groups.len() {
+        return EINVAL;
+    }
+    let token = current_user_token();
+    if !user_range_ok(token, list as usize, groups.len() * size_of::<u32>(), true) {
+        return EFAULT;
+    }
+    for (i, &gid) in groups.iter().enumerate() {
+        *translated_refmut(token, unsafe { list.add(i) }) = gid;
+    }
+    groups.len() as isize
+}
+
+/// setgroups syscall, root only
+pub fn sys_setgroups(size: usize, list: *const u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_setgroups",
+        current_task().unwrap().pid.0
+    );
+    if size > NGROUPS_MAX {
+        return EINVAL;
+    }
+    let token = current_user_token();
+    if !user_range_ok(token, list as usize, size * size_of::<u32>(), false) {
+        return EFAULT;
+    }
+    let groups: Vec<u32> = (0..size)
+        .map(|i| *translated_ref(token, unsafe { list.add(i) }))
+        .collect();
+    ok_or_eperm(with_cred(|cred| cred.setgroups(groups)))
+}
@@ -0,0 +1,133 @@
+//! epoll syscalls
+//!
+//! epoll 实例本身是一个文件（[`EpollInstance`]），可以被 dup、fork 继承，也可以嵌套在
+//! 另一个 epoll 或 ppoll 中。epoll_pwait 没有就绪事件时和 ppoll 一样在 [`wait_for_poll`]
+//! 中睡眠。
+
+use alloc::sync::Arc;
+
+use super::signal::sys_sigprocmask;
+use crate::{
+    config::CLOCK_FREQ,
+    fs::{
+        defs::OpenFlags,
+        epoll::{EpollEvent, EpollInstance},
+        fd::FdEntry,
+        file::{cast_file_to_epoll, File},
+        poll::wait_for_poll,
+    },
+    mm::{translated_ref, translated_refmut},
+    syscall::errno::{EBADF, EINVAL, EMFILE},
+    task::{
+        current_task,
+        current_user_token,
+        resource::RLIMIT_NOFILE,
+        signal::SIG_SETMASK,
+        SignalFlags,
+    },
+    timer::get_time,
+};
+
+const EPOLL_CTL_ADD: i32 = 1;
+const EPOLL_CTL_DEL: i32 = 2;
+const EPOLL_CTL_MOD: i32 = 3;
+
+/// The file open at `fd` in the current task
+fn fd_file(fd: usize) -> Option<Arc<dyn File>> {
+    let task = current_task().unwrap();
+    let fd_table = task.fd_table();
+    let fd_table = fd_table.lock();
+    let entry = fd_table.get(fd)?.as_ref()?;
+    Some(entry.file.clone())
+}
+
+/// Create an epoll instance, EPOLL_CLOEXEC (the value of O_CLOEXEC) is the only flag
+pub fn sys_epoll_create1(flags: i32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_epoll_create1",
+        current_task().unwrap().pid.0
+    );
+    let Some(flags) = OpenFlags::from_bits(flags) else {
+        return EINVAL;
+    };
+    if !(flags - OpenFlags::O_CLOEXEC).is_empty() {
+        return EINVAL;
+    }
+    let task = current_task().unwrap();
+    let nofile = task.rlimit(RLIMIT_NOFILE);
+    let fd_table = task.fd_table();
+    let mut fd_table = fd_table.lock();
+    let Some(fd) = fd_table.alloc_fd(nofile) else {
+        return EMFILE;
+    };
+    let epoll = Arc::new(EpollInstance::new());
+    fd_table[fd] = Some(FdEntry::new(epoll, flags | OpenFlags::O_RDWR));
+    fd as isize
+}
+
+/// Add, change or remove `fd` on the interest list of the epoll instance `epfd`
+pub fn sys_epoll_ctl(epfd: usize, op: i32, fd: usize, event: *const EpollEvent) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_epoll_ctl",
+        current_task().unwrap().pid.0
+    );
+    let (Some(epoll), Some(file)) = (fd_file(epfd), fd_file(fd)) else {
+        return EBADF;
+    };
+    let Some(epoll) = cast_file_to_epoll(epoll) else {
+        return EINVAL;
+    };
+    // 把实例加入它自己的兴趣列表，检查就绪时会重复借用
+    if fd == epfd {
+        return EINVAL;
+    }
+    let token = current_user_token();
+    let ret = match op {
+        EPOLL_CTL_ADD => epoll.add(fd, &file, translated_ref(token, event)),
+        EPOLL_CTL_MOD => epoll.modify(fd, translated_ref(token, event)),
+        EPOLL_CTL_DEL => epoll.remove(fd),
+        _ => Err(EINVAL),
+    };
+    ret.map_or_else(|errno| errno, |_| 0)
+}
+
+/// Wait up to `timeout` milliseconds (forever if negative) for events on `epfd`, with
+/// `sigmask` as the signal mask meanwhile
+pub fn sys_epoll_pwait(
+    epfd: usize, events: *mut EpollEvent, maxevents: i32, timeout: i32, sigmask: *const SignalFlags,
+) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_epoll_pwait",
+        current_task().unwrap().pid.0
+    );
+    if maxevents <= 0 {
+        return EINVAL;
+    }
+    let Some(epoll) = fd_file(epfd) else {
+        return EBADF;
+    };
+    let Some(epoll) = cast_file_to_epoll(epoll) else {
+        return EINVAL;
+    };
+    let mut oldsig = SignalFlags::empty();
+    let oldsig_ptr = &mut oldsig as *mut SignalFlags as *mut usize;
+    if !sigmask.is_null() {
+        sys_sigprocmask(SIG_SETMASK, sigmask as *mut usize, oldsig_ptr, true);
+    }
+    let deadline = (timeout >= 0).then(|| get_time() + timeout as usize * CLOCK_FREQ / 1000);
+    let ready = loop {
+        let ready = epoll.take_events(maxevents as usize);
+        if !ready.is_empty() || deadline.map_or(false, |deadline| get_time() >= deadline) {
+            break ready;
+        }
+        wait_for_poll(deadline);
+    };
+    if !sigmask.is_null() {
+        sys_sigprocmask(SIG_SETMASK, oldsig_ptr, 0 as *mut usize, true);
+    }
+    let token = current_user_token();
+    for (i, event) in ready.iter().enumerate() {
+        *translated_refmut(token, unsafe { events.add(i) }) = *event;
+    }
+    ready.len() as isize
+}
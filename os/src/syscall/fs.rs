@@ -11,20 +11,52 @@ use riscv::register::sstatus;
 use crate::{
     fs::{
         defs::OpenFlags,
-        file::{cast_file_to_inode, cast_inode_to_file},
-        inode::Stat,
+        dentry::Dentry,
+        fd::{FdEntry, FdFlags},
+        file::{cast_file_to_inode, cast_file_to_open_file, cast_inode_to_file, File, OpenFile},
+        fs_in_use,
+        inode::{Inode, Stat, Statx},
+        lookup_link,
+        lookup_parent,
+        lookup_path,
+        may_open,
+        new_filesystem,
         open_file,
         pipe::make_pipe,
+        sync_all,
+        sync_file,
+        FileSystemType,
         Iovec,
         ROOT_INODE,
     },
     mm::{translated_byte_buffer, translated_refmut, translated_str},
     syscall::{
-        errno::{EACCES, EBADF, EBUSY, ENOENT, ENOTDIR, ENOTTY},
+        errno::{
+            EACCES,
+            EBADF,
+            EBUSY,
+            EEXIST,
+            EINVAL,
+            EIO,
+            EISDIR,
+            EMFILE,
+            ENODEV,
+            ENOENT,
+            ENOTDIR,
+            ENOTEMPTY,
+            EPERM,
+            ESPIPE,
+            EXDEV,
+        },
         Dirent,
     },
-    task::{current_task, current_user_token},
-    utils::string::c_ptr_to_string,
+    task::{
+        cred::{MAY_EXEC, MAY_WRITE},
+        current_task,
+        current_user_token,
+        resource::RLIMIT_NOFILE,
+        TaskControlBlockInner,
+    },
 };
 
 pub const AT_FDCWD: i32 = -100;
@@ -37,17 +69,18 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
         fd,
     );
     let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access(file!(), line!());
-    if fd >= inner.fd_table.len() {
+    let fd_table = task.fd_table();
+    let fd_table = fd_table.lock();
+    if fd >= fd_table.len() {
         return EBADF;
     }
-    if let Some(file) = &inner.fd_table[fd] {
-        if !file.writable() {
+    if let Some(entry) = &fd_table[fd] {
+        if !entry.file.writable() {
             return EACCES;
         }
-        let file = file.clone();
+        let file = entry.file.clone();
         // release current task TCB manually to avoid multi-borrow
-        drop(inner);
+        drop(fd_table);
 
         let buf = unsafe {
             sstatus::set_sum();
@@ -55,7 +88,10 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
             sstatus::clear_sum();
             buf
         };
-        file.write(buf) as isize
+        match file.try_write(buf) {
+            Ok(len) => len as isize,
+            Err(errno) => errno,
+        }
     } else {
         EBADF
     }
@@ -68,21 +104,25 @@ pub fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
         fd,
     );
     let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access(file!(), line!());
-    if fd >= inner.fd_table.len() {
+    let fd_table = task.fd_table();
+    let fd_table = fd_table.lock();
+    if fd >= fd_table.len() {
         return EBADF;
     }
-    if let Some(file) = &inner.fd_table[fd] {
-        let file = file.clone();
+    if let Some(entry) = &fd_table[fd] {
+        let file = entry.file.clone();
         if !file.readable() {
             return EACCES;
         }
         // release current task TCB manually to avoid multi-borrow
-        drop(inner);
+        drop(fd_table);
         unsafe {
             sstatus::set_sum();
             let buf = core::slice::from_raw_parts_mut(buf, len);
-            let ret = file.read(buf) as isize;
+            let ret = match file.try_read(buf) {
+                Ok(len) => len as isize,
+                Err(errno) => errno,
+            };
             trace!(
                 "kernel:pid[{}] sys_read fd:{} buf:{}",
                 task.pid.0,
@@ -96,63 +136,159 @@ pub fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
         EBADF
     }
 }
-/// openat sys
-pub fn sys_open(path: *const u8, flags: i32) -> isize {
-    trace!("kernel:pid[{}] sys_open", current_task().unwrap().pid.0);
+/// lseek whence
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+/// The [`OpenFile`] at `fd`, EBADF if `fd` is not open and ESPIPE for files without
+/// an offset (pipes, the console)
+fn open_file_at(fd: usize) -> Result<Arc<OpenFile>, isize> {
     let task = current_task().unwrap();
-    let token = current_user_token();
-    let path = translated_str(token, path);
-    debug!("kernel: sys_open path: {}", path);
-    let curdir = task
-        .inner_exclusive_access(file!(), line!())
-        .work_dir
-        .clone();
-    if let Some(dentry) = open_file(
-        curdir.inode(),
-        path.as_str(),
-        OpenFlags::from_bits(flags).unwrap(),
-    ) {
-        let inode = dentry.inode();
-        let mut inner = task.inner_exclusive_access(file!(), line!());
-        let fd = inner.alloc_fd();
-        let file = cast_inode_to_file(inode).unwrap();
-        inner.fd_table[fd] = Some(file);
-        trace!("kernel:pid[{}] sys_open success fd:{}", task.pid.0, fd);
-        fd as isize
-    } else {
-        ENOENT
-    }
+    let file = task
+        .fd_table()
+        .lock()
+        .get(fd)
+        .cloned()
+        .flatten()
+        .ok_or(EBADF)?
+        .file;
+    cast_file_to_open_file(file).ok_or(ESPIPE)
 }
-pub fn sys_openat(dirfd: i32, path: *const u8, flags: i32) -> isize {
-    trace!("kernel:pid[{}] sys_openat", current_task().unwrap().pid.0);
-    if dirfd == AT_FDCWD {
-        return sys_open(path, flags);
+
+/// lseek syscall
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    trace!("kernel:pid[{}] sys_lseek", current_task().unwrap().pid.0);
+    let file = match open_file_at(fd) {
+        Ok(file) => file,
+        Err(err) => return err,
+    };
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => file.offset(),
+        SEEK_END => file.inode().size(),
+        _ => return EINVAL,
+    };
+    // 可以移到文件末尾之后，不能移到文件开头之前
+    match (base as isize).checked_add(offset) {
+        Some(pos) if pos >= 0 => {
+            file.set_offset(pos as usize);
+            pos
+        }
+        _ => EINVAL,
     }
-    let dirfd = dirfd as usize;
-    let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
-    if dirfd >= inner.fd_table.len() {
+}
+
+/// pread64 syscall: read at `offset` without moving the file offset
+pub fn sys_pread64(fd: usize, buf: *mut u8, len: usize, offset: isize) -> isize {
+    trace!("kernel:pid[{}] sys_pread64", current_task().unwrap().pid.0);
+    let file = match open_file_at(fd) {
+        Ok(file) => file,
+        Err(err) => return err,
+    };
+    if !file.readable() {
         return EBADF;
     }
-    if inner.fd_table[dirfd].is_none() {
+    if offset < 0 {
+        return EINVAL;
+    }
+    unsafe {
+        sstatus::set_sum();
+        let buf = core::slice::from_raw_parts_mut(buf, len);
+        let ret = file.inode().try_read_at(offset as usize, buf);
+        sstatus::clear_sum();
+        match ret {
+            Ok(len) => len as isize,
+            Err(errno) => errno,
+        }
+    }
+}
+
+/// pwrite64 syscall: write at `offset` without moving the file offset
+///
+/// 和 Linux 一样，O_APPEND 打开的文件仍然写在 `offset` 处。
+pub fn sys_pwrite64(fd: usize, buf: *const u8, len: usize, offset: isize) -> isize {
+    trace!("kernel:pid[{}] sys_pwrite64", current_task().unwrap().pid.0);
+    let file = match open_file_at(fd) {
+        Ok(file) => file,
+        Err(err) => return err,
+    };
+    if !file.writable() {
         return EBADF;
     }
-    let dir = inner.fd_table[dirfd].as_ref().unwrap().clone();
+    if offset < 0 {
+        return EINVAL;
+    }
+    let ret = unsafe {
+        sstatus::set_sum();
+        let buf = core::slice::from_raw_parts(buf, len);
+        let ret = file.inode().try_write_at(offset as usize, buf);
+        sstatus::clear_sum();
+        ret
+    };
+    match ret {
+        Ok(len) => len as isize,
+        Err(errno) => errno,
+    }
+}
+
+/// Where a path given with `dirfd` is looked up from: the working directory for
+/// AT_FDCWD, else the directory open at `dirfd`
+fn start_dir(inner: &TaskControlBlockInner, dirfd: i32) -> Result<Arc<Dentry>, isize> {
+    if dirfd == AT_FDCWD {
+        return Ok(inner.work_dir.clone());
+    }
+    let file = inner
+        .fd_table
+        .lock()
+        .get(dirfd as usize)
+        .cloned()
+        .flatten()
+        .ok_or(EBADF)?
+        .file;
     // TODO: 好像无法判断是否是目录
-    // if !dir.is_dir() {
-    //     return -1;
-    // }
-    let inode = cast_file_to_inode(dir).unwrap();
-    let token = inner.memory_set.token();
-    let path = translated_str(token, path);
-    if let Some(dentry) = open_file(inode, path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
-        let fd = inner.alloc_fd();
-        let inode = dentry.inode();
-        let file = cast_inode_to_file(inode).unwrap();
-        inner.fd_table[fd] = Some(file);
-        fd as isize
-    } else {
-        ENOENT
+    let inode = cast_file_to_inode(file).ok_or(ENOTDIR)?;
+    // 打开的目录不知道自己的路径，从它出发的查找不会经过挂载点
+    Ok(Arc::new(Dentry::new("", inode)))
+}
+
+/// openat sys
+pub fn sys_open(path: *const u8, flags: i32) -> isize {
+    trace!("kernel:pid[{}] sys_open", current_task().unwrap().pid.0);
+    sys_openat(AT_FDCWD, path, flags)
+}
+pub fn sys_openat(dirfd: i32, path: *const u8, flags: i32) -> isize {
+    trace!("kernel:pid[{}] sys_openat", current_task().unwrap().pid.0);
+    let task = current_task().unwrap();
+    let path = translated_str(current_user_token(), path);
+    debug!("kernel: sys_openat path: {}", path);
+    let (start, cred) = {
+        let inner = task.inner_exclusive_access();
+        match start_dir(&inner, dirfd) {
+            Ok(start) => (start, inner.cred.clone()),
+            Err(err) => return err,
+        }
+    };
+    let flags = OpenFlags::from_bits(flags).unwrap();
+    if !may_open(&cred, &start, &path, flags) {
+        return EACCES;
+    }
+    match open_file(&start, &path, flags) {
+        Ok(dentry) => {
+            let Some(file) = OpenFile::new(dentry.inode(), flags) else {
+                return EACCES;
+            };
+            let nofile = task.rlimit(RLIMIT_NOFILE);
+            let fd_table = task.fd_table();
+            let mut fd_table = fd_table.lock();
+            let Some(fd) = fd_table.alloc_fd(nofile) else {
+                return EMFILE;
+            };
+            fd_table[fd] = Some(FdEntry::new(file, flags));
+            trace!("kernel:pid[{}] sys_openat success fd:{}", task.pid.0, fd);
+            fd as isize
+        }
+        Err(err) => err,
     }
 }
 /// close syscall
@@ -163,26 +299,39 @@ pub fn sys_close(fd: usize) -> isize {
         fd,
     );
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
-    if fd >= inner.fd_table.len() {
+    let fd_table = task.fd_table();
+    let mut fd_table = fd_table.lock();
+    if fd >= fd_table.len() {
         return EBADF;
     }
-    if inner.fd_table[fd].is_none() {
+    if fd_table[fd].is_none() {
         return EBADF;
     }
-    inner.fd_table[fd].take();
+    fd_table[fd].take();
     0
 }
-/// pipe syscall
-pub fn sys_pipe(pipe: *mut u32) -> isize {
+/// pipe2 syscall, `flags` can have O_CLOEXEC and O_NONBLOCK
+pub fn sys_pipe(pipe: *mut u32, flags: i32) -> isize {
     trace!("kernel:pid[{}] sys_pipe", current_task().unwrap().pid.0);
+    let Some(flags) = OpenFlags::from_bits(flags)
+        .filter(|flags| (OpenFlags::O_CLOEXEC | OpenFlags::O_NONBLOCK).contains(*flags))
+    else {
+        return EINVAL;
+    };
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
+    let nofile = task.rlimit(RLIMIT_NOFILE);
+    let fd_table = task.fd_table();
+    let mut fd_table = fd_table.lock();
     let (pipe_read, pipe_write) = make_pipe();
-    let read_fd = inner.alloc_fd();
-    inner.fd_table[read_fd] = Some(pipe_read);
-    let write_fd = inner.alloc_fd();
-    inner.fd_table[write_fd] = Some(pipe_write);
+    let Some(read_fd) = fd_table.alloc_fd(nofile) else {
+        return EMFILE;
+    };
+    fd_table[read_fd] = Some(FdEntry::new(pipe_read, flags | OpenFlags::O_RDONLY));
+    let Some(write_fd) = fd_table.alloc_fd(nofile) else {
+        fd_table[read_fd] = None;
+        return EMFILE;
+    };
+    fd_table[write_fd] = Some(FdEntry::new(pipe_write, flags | OpenFlags::O_WRONLY));
     unsafe {
         sstatus::set_sum();
         *pipe = read_fd as u32;
@@ -199,36 +348,49 @@ pub fn sys_pipe(pipe: *mut u32) -> isize {
 pub fn sys_dup(fd: usize) -> isize {
     trace!("kernel:pid[{}] sys_dup", current_task().unwrap().pid.0);
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
-    if fd >= inner.fd_table.len() {
+    let nofile = task.rlimit(RLIMIT_NOFILE);
+    let fd_table = task.fd_table();
+    let mut fd_table = fd_table.lock();
+    if fd >= fd_table.len() {
         return EBADF;
     }
-    if inner.fd_table[fd].is_none() {
+    if fd_table[fd].is_none() {
         return EBADF;
     }
-    let new_fd = inner.alloc_fd();
-    inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
+    let entry = fd_table[fd].as_ref().unwrap().dup(false);
+    let Some(new_fd) = fd_table.alloc_fd(nofile) else {
+        return EMFILE;
+    };
+    fd_table[new_fd] = Some(entry);
     new_fd as isize
 }
 
-/// dup3 syscall
-pub fn sys_dup3(fd: usize, new_fd: usize) -> isize {
+/// dup3 syscall, `flags` can only be O_CLOEXEC
+pub fn sys_dup3(fd: usize, new_fd: usize, flags: i32) -> isize {
     trace!("kernel:pid[{}] sys_dup3", current_task().unwrap().pid.0);
+    let cloexec = match OpenFlags::from_bits(flags) {
+        Some(flags) if flags.is_empty() => false,
+        Some(OpenFlags::O_CLOEXEC) => true,
+        _ => return EINVAL,
+    };
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
-    if fd >= inner.fd_table.len() {
+    let nofile = task.rlimit(RLIMIT_NOFILE);
+    let fd_table = task.fd_table();
+    let mut fd_table = fd_table.lock();
+    if fd >= fd_table.len() || new_fd >= nofile {
         return EBADF;
     }
-    if inner.fd_table[fd].is_none() {
+    if fd_table[fd].is_none() {
         return EBADF;
     }
-    while inner.fd_table.len() <= new_fd {
-        inner.fd_table.push(None);
+    if fd == new_fd {
+        return EINVAL;
     }
-    if inner.fd_table[new_fd].is_some() {
-        inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
+    while fd_table.len() <= new_fd {
+        fd_table.push(None);
     }
-    inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
+    let entry = fd_table[fd].as_ref().unwrap().dup(cloexec);
+    fd_table[new_fd] = Some(entry);
 
     debug!(
         "kernel:pid[{}] sys_dup3 fd:{} => new_fd:{}",
@@ -238,29 +400,133 @@ pub fn sys_dup3(fd: usize, new_fd: usize) -> isize {
     new_fd as isize
 }
 
+/// sync syscall: write back the mapped pages of every file and all dirty blocks
+pub fn sys_sync() -> isize {
+    trace!("kernel:pid[{}] sys_sync", current_task().unwrap().pid.0);
+    sync_all();
+    0
+}
+
+/// fsync and fdatasync syscall
+pub fn sys_fsync(fd: usize) -> isize {
+    trace!("kernel:pid[{}] sys_fsync", current_task().unwrap().pid.0);
+    let task = current_task().unwrap();
+    let Some(entry) = task.fd_table().lock().get(fd).cloned().flatten() else {
+        return EBADF;
+    };
+    // 管道和控制台没有可写回的内容
+    let Some(inode) = cast_file_to_inode(entry.file) else {
+        return EINVAL;
+    };
+    sync_file(&inode);
+    0
+}
+
+/// Status of `file`, with the owner and permission bits of its inode
+fn file_stat(file: Arc<dyn File>) -> Option<Stat> {
+    let mut stat = file.fstat()?;
+    if let Some(perm) = cast_file_to_inode(file).and_then(|inode| inode.perm()) {
+        stat.set_perm(perm);
+    }
+    Some(stat)
+}
+
+/// fstatat/statx flag: do not follow a symbolic link as the last component
+const AT_SYMLINK_NOFOLLOW: u32 = 0x100;
+/// fstatat/statx flag: an empty path means the file open at `dirfd` itself
+const AT_EMPTY_PATH: u32 = 0x1000;
+
+/// Status of `path` looked up from `dirfd`, as fstatat and statx see it
+fn stat_at(dirfd: i32, path: *const u8, flags: u32) -> Result<Stat, isize> {
+    let path = translated_str(current_user_token(), path);
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    if path.is_empty() {
+        if flags & AT_EMPTY_PATH == 0 {
+            return Err(ENOENT);
+        }
+        if dirfd != AT_FDCWD {
+            // dirfd 可以是任何打开的文件，不一定是目录
+            let file = inner
+                .fd_table
+                .lock()
+                .get(dirfd as usize)
+                .cloned()
+                .flatten()
+                .ok_or(EBADF)?
+                .file;
+            drop(inner);
+            return file_stat(file).ok_or(EBADF);
+        }
+    }
+    let start = start_dir(&inner, dirfd)?;
+    // 查找时可能读盘并切换到其他任务，不能一直借用 inner
+    drop(inner);
+    let dentry = if path.is_empty() {
+        start
+    } else if flags & AT_SYMLINK_NOFOLLOW != 0 {
+        lookup_link(&start, &path)?
+    } else {
+        lookup_path(&start, &path)?
+    };
+    cast_inode_to_file(dentry.inode())
+        .and_then(file_stat)
+        .ok_or(EIO)
+}
+
 /// YOUR JOB: Implement fstat.
 pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
     trace!("kernel:pid[{}] sys_fstat", current_task().unwrap().pid.0);
     let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access(file!(), line!());
-    if fd >= inner.fd_table.len() {
+    let fd_table = task.fd_table();
+    let fd_table = fd_table.lock();
+    let Some(Some(entry)) = fd_table.get(fd) else {
         return EBADF;
-    }
-    if inner.fd_table[fd].is_none() {
+    };
+    let file = entry.file.clone();
+    drop(fd_table);
+    let Some(stat) = file_stat(file) else {
         return EBADF;
+    };
+    unsafe {
+        sstatus::set_sum();
+        *st = stat;
+        sstatus::clear_sum();
     }
-    if let Some(file) = &inner.fd_table[fd] {
-        let file = file.clone();
-        let stat = file.fstat();
-        if stat.is_none() {
-            return EBADF;
-        }
-        let stat = stat.unwrap();
-        unsafe {
-            sstatus::set_sum();
-            *st = stat;
-            sstatus::clear_sum();
-        }
+    0
+}
+
+pub fn sys_fstatat(dirfd: i32, path: *const u8, st: *mut Stat, flags: u32) -> isize {
+    trace!("kernel:pid[{}] sys_fstatat", current_task().unwrap().pid.0);
+    let stat = match stat_at(dirfd, path, flags) {
+        Ok(stat) => stat,
+        Err(err) => return err,
+    };
+    unsafe {
+        sstatus::set_sum();
+        *st = stat;
+        sstatus::clear_sum();
+    }
+    0
+}
+
+/// statx mask bit reserved for a future extension of `struct statx`
+const STATX_RESERVED: u32 = 0x8000_0000;
+
+/// statx syscall, every field of the basic stats is filled in whatever `mask` asks for
+pub fn sys_statx(dirfd: i32, path: *const u8, flags: u32, mask: u32, buf: *mut Statx) -> isize {
+    trace!("kernel:pid[{}] sys_statx", current_task().unwrap().pid.0);
+    if mask & STATX_RESERVED != 0 {
+        return EINVAL;
+    }
+    let stat = match stat_at(dirfd, path, flags) {
+        Ok(stat) => stat,
+        Err(err) => return err,
+    };
+    unsafe {
+        sstatus::set_sum();
+        *buf = Statx::from(&stat);
+        sstatus::clear_sum();
     }
     0
 }
@@ -273,40 +539,256 @@ pub fn sys_linkat(old_name: *const u8, new_name: *const u8) -> isize {
     let new_name = translated_str(token, new_name);
     let curdir = current_task()
         .unwrap()
-        .inner_exclusive_access(file!(), line!())
+        .inner_exclusive_access()
         .work_dir
         .clone();
-    let target = curdir.inode().lookup(old_name.as_str()).unwrap();
-    if curdir.inode().link(&new_name, target) {
+    let target = match lookup_path(&curdir, &old_name) {
+        Ok(target) => target,
+        Err(err) => return err,
+    };
+    let (dir, name) = match lookup_parent(&curdir, &new_name) {
+        Ok(parent) => parent,
+        Err(err) => return err,
+    };
+    if dir.inode().link(&name, target) {
         0
     } else {
         ENOENT
     }
 }
 
+/// unlinkat flag: remove a directory instead of a file
+const AT_REMOVEDIR: u32 = 0x200;
+
 /// YOUR JOB: Implement unlinkat.
-pub fn sys_unlinkat(name: *const u8) -> isize {
+pub fn sys_unlinkat(dirfd: i32, name: *const u8, flags: u32) -> isize {
     trace!("kernel:pid[{}] sys_unlinkat", current_task().unwrap().pid.0);
     let token = current_user_token();
     let name = translated_str(token, name);
-    let curdir = current_task()
-        .unwrap()
-        .inner_exclusive_access(file!(), line!())
-        .work_dir
-        .clone();
-    if curdir.inode().unlink(&name) {
+    let (start, cred) = {
+        let task = current_task().unwrap();
+        let inner = task.inner_exclusive_access();
+        match start_dir(&inner, dirfd) {
+            Ok(start) => (start, inner.cred.clone()),
+            Err(err) => return err,
+        }
+    };
+    let (dir, name) = match lookup_parent(&start, &name) {
+        Ok(parent) => parent,
+        Err(err) => return err,
+    };
+    // 删除的是目录项本身，最后一个分量是符号链接时不跟随
+    if dir.inode().lookup(&name).is_none() {
+        return ENOENT;
+    }
+    if !cred.permits(dir.inode().perm(), MAY_WRITE | MAY_EXEC) {
+        return EACCES;
+    }
+    if flags & AT_REMOVEDIR != 0 {
+        // 目录存在却删除失败，多半是目录不空
+        if dir.inode().rmdir(&name) {
+            0
+        } else {
+            ENOTEMPTY
+        }
+    } else if dir.inode().unlink(&name) {
         0
     } else {
         ENOENT
     }
 }
 
+/// renameat2 flag: fail with EEXIST instead of replacing the target
+const RENAME_NOREPLACE: u32 = 1;
+
+/// Whether the directory `dir` is `ancestor` or lies below it, walking up through `..`
+fn is_within(dir: Arc<dyn Inode>, ancestor: &Arc<dyn Inode>) -> bool {
+    let Some(target) = ancestor.cache_key() else {
+        return false;
+    };
+    let mut dir = dir;
+    while let Some(key) = dir.cache_key() {
+        if key == target {
+            return true;
+        }
+        let Some(parent) = dir.clone().lookup("..") else {
+            return false;
+        };
+        dir = parent.inode();
+        // 根目录的 .. 是它自己
+        if dir.cache_key() == Some(key) {
+            return false;
+        }
+    }
+    false
+}
+
+fn inode_is_dir(inode: &Arc<dyn Inode>) -> bool {
+    cast_inode_to_file(inode.clone()).map_or(false, |file| file.is_dir())
+}
+
+/// Whether the directory has entries other than `.` and `..`
+fn has_entries(dir: &Arc<dyn Inode>) -> bool {
+    dir.ls().iter().any(|name| name != "." && name != "..")
+}
+
+/// renameat2 syscall, RENAME_EXCHANGE and RENAME_WHITEOUT are not supported
+pub fn sys_renameat2(
+    olddirfd: i32, oldpath: *const u8, newdirfd: i32, newpath: *const u8, flags: u32,
+) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_renameat2",
+        current_task().unwrap().pid.0
+    );
+    if flags & !RENAME_NOREPLACE != 0 {
+        return EINVAL;
+    }
+    let token = current_user_token();
+    let oldpath = translated_str(token, oldpath);
+    let newpath = translated_str(token, newpath);
+    let (old_start, new_start, cred) = {
+        let task = current_task().unwrap();
+        let inner = task.inner_exclusive_access();
+        match (start_dir(&inner, olddirfd), start_dir(&inner, newdirfd)) {
+            (Ok(old_start), Ok(new_start)) => (old_start, new_start, inner.cred.clone()),
+            (Err(err), _) | (_, Err(err)) => return err,
+        }
+    };
+    // `.` 和 `..` 不能改名，也不能作为新名字
+    let (old_dir, old_name) = match lookup_parent(&old_start, &oldpath) {
+        Ok(parent) => parent,
+        Err(EEXIST) => return EBUSY,
+        Err(err) => return err,
+    };
+    let (new_dir, new_name) = match lookup_parent(&new_start, &newpath) {
+        Ok(parent) => parent,
+        Err(EEXIST) => return EBUSY,
+        Err(err) => return err,
+    };
+    let (old_dir, new_dir) = (old_dir.inode(), new_dir.inode());
+    // 改名的是目录项本身，符号链接不跟随
+    let old = match lookup_link(&old_start, &oldpath) {
+        Ok(old) => old.inode(),
+        Err(err) => return err,
+    };
+    // 挂载在这个名字上的是另一个文件系统的根
+    if old.fs_id() != old_dir.fs_id() {
+        return EBUSY;
+    }
+    if old_dir.fs_id() != new_dir.fs_id() {
+        return EXDEV;
+    }
+    if !cred.permits(old_dir.perm(), MAY_WRITE | MAY_EXEC)
+        || !cred.permits(new_dir.perm(), MAY_WRITE | MAY_EXEC)
+    {
+        return EACCES;
+    }
+    if inode_is_dir(&old) && is_within(new_dir.clone(), &old) {
+        return EINVAL;
+    }
+    if let Ok(new) = lookup_link(&new_start, &newpath) {
+        let new = new.inode();
+        if flags & RENAME_NOREPLACE != 0 {
+            return EEXIST;
+        }
+        if new.fs_id() != new_dir.fs_id() {
+            return EBUSY;
+        }
+        match (inode_is_dir(&old), inode_is_dir(&new)) {
+            (true, false) => return ENOTDIR,
+            (false, true) => return EISDIR,
+            (true, true) if has_entries(&new) => return ENOTEMPTY,
+            _ => {}
+        }
+    }
+    if old_dir.rename_to(&old_name, new_dir, &new_name) {
+        0
+    } else {
+        EPERM
+    }
+}
+
+/// symlinkat syscall
+pub fn sys_symlinkat(target: *const u8, dirfd: i32, path: *const u8) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_symlinkat",
+        current_task().unwrap().pid.0
+    );
+    let token = current_user_token();
+    let target = translated_str(token, target);
+    let path = translated_str(token, path);
+    if target.is_empty() {
+        return ENOENT;
+    }
+    let (start, cred) = {
+        let task = current_task().unwrap();
+        let inner = task.inner_exclusive_access();
+        match start_dir(&inner, dirfd) {
+            Ok(start) => (start, inner.cred.clone()),
+            Err(err) => return err,
+        }
+    };
+    if lookup_link(&start, &path).is_ok() {
+        return EEXIST;
+    }
+    let (dir, name) = match lookup_parent(&start, &path) {
+        Ok(parent) => parent,
+        Err(err) => return err,
+    };
+    if !cred.permits(dir.inode().perm(), MAY_WRITE | MAY_EXEC) {
+        return EACCES;
+    }
+    // 文件系统不支持符号链接
+    if dir.inode().symlink(&name, &target) {
+        0
+    } else {
+        EPERM
+    }
+}
+
+/// readlinkat syscall
+///
+/// 目标不以 '\0' 结尾，`buf` 放不下时截断，返回写入的字节数。
+pub fn sys_readlinkat(dirfd: i32, path: *const u8, buf: *mut u8, len: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_readlinkat",
+        current_task().unwrap().pid.0
+    );
+    if len as isize <= 0 {
+        return EINVAL;
+    }
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let start = {
+        let task = current_task().unwrap();
+        let inner = task.inner_exclusive_access();
+        match start_dir(&inner, dirfd) {
+            Ok(start) => start,
+            Err(err) => return err,
+        }
+    };
+    let dentry = match lookup_link(&start, &path) {
+        Ok(dentry) => dentry,
+        Err(err) => return err,
+    };
+    let Some(target) = dentry.inode().read_link() else {
+        return EINVAL;
+    };
+    let target = &target.as_bytes()[..min(len, target.len())];
+    let mut copied = 0;
+    for slice in translated_byte_buffer(token, buf, target.len()) {
+        slice.copy_from_slice(&target[copied..copied + slice.len()]);
+        copied += slice.len();
+    }
+    target.len() as isize
+}
+
 pub fn sys_getcwd(buf: *mut u8, len: usize) -> isize {
     trace!("kernel:pid[{}] sys_getcwd", current_task().unwrap().pid.0);
     let token = current_user_token();
     if let path = current_task()
         .unwrap()
-        .inner_exclusive_access(file!(), line!())
+        .inner_exclusive_access()
         .work_dir
         .clone()
         .name()
@@ -332,49 +814,43 @@ pub fn sys_chdir(path: *const u8) -> isize {
     let token = current_user_token();
     let path = translated_str(token, path);
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
-    let dir = inner.work_dir.clone();
-    let inode = dir.inode();
-    let dir = open_file(inode, &path, OpenFlags::O_RDWR | OpenFlags::O_DIRECTORY);
-    inner.work_dir = dir.unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let dir = match lookup_path(&inner.work_dir, &path) {
+        Ok(dir) => dir,
+        Err(err) => return err,
+    };
+    // 进入目录需要搜索（执行）权限
+    if !inner.cred.permits(dir.inode().perm(), MAY_EXEC) {
+        return EACCES;
+    }
+    inner.work_dir = dir;
     0
 }
 
 pub fn sys_mkdirat64(dirfd: i32, path: *const u8, _mode: u32) -> isize {
     trace!("kernel:pid[{}] sys_mkdirat", current_task().unwrap().pid.0);
-    let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
-    let inode;
-    if dirfd == AT_FDCWD {
-        inode = ROOT_INODE.clone();
-    } else {
-        let dirfd = dirfd as usize;
-        if dirfd >= inner.fd_table.len() {
-            return EBADF;
-        }
-        if inner.fd_table[dirfd].is_none() {
-            return EBADF;
-        }
-        let dir = inner.fd_table[dirfd].as_ref().unwrap().clone();
-        if !dir.is_dir() {
-            return ENOTDIR;
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let (start, cred) = {
+        let task = current_task().unwrap();
+        let inner = task.inner_exclusive_access();
+        match start_dir(&inner, dirfd) {
+            Ok(start) => (start, inner.cred.clone()),
+            Err(err) => return err,
         }
-        inode = cast_file_to_inode(dir).unwrap();
+    };
+    if lookup_path(&start, &path).is_ok() {
+        return EEXIST;
+    }
+    let (dir, name) = match lookup_parent(&start, &path) {
+        Ok(parent) => parent,
+        Err(err) => return err,
+    };
+    if !cred.permits(dir.inode().perm(), MAY_WRITE | MAY_EXEC) {
+        return EACCES;
     }
-    let path = c_ptr_to_string(path);
-    if let Some(_) = open_file(inode.clone(), &path, OpenFlags::O_RDONLY) {
-        return -1;
-    }
-    if let Some(dentry) = open_file(
-        inode.clone(),
-        &path,
-        OpenFlags::O_DIRECTORY | OpenFlags::O_CREAT,
-    ) {
-        let fd = inner.alloc_fd();
-        let inode = dentry.inode();
-        let file = cast_inode_to_file(inode).unwrap();
-        inner.fd_table[fd] = Some(file);
-        fd as isize
+    if dir.inode().mkdir(&name) {
+        0
     } else {
         EACCES //TODO: to be confirmed
     }
@@ -386,25 +862,23 @@ pub fn sys_getdents64(dirfd: i32, buf: *mut u8, len: usize) -> isize {
         current_task().unwrap().pid.0
     );
     let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access(file!(), line!());
+    let inner = task.inner_exclusive_access();
     let inode;
     if dirfd == AT_FDCWD {
         inode = ROOT_INODE.clone();
     } else {
-        let dirfd = dirfd as usize;
-        if dirfd >= inner.fd_table.len() {
+        let Some(Some(entry)) = inner.fd_table.lock().get(dirfd as usize).cloned() else {
             return EBADF;
-        }
-        if inner.fd_table[dirfd].is_none() {
-            return EBADF;
-        }
-        let dir = inner.fd_table[dirfd].as_ref().unwrap().clone();
+        };
+        let dir = entry.file;
         if !dir.is_dir() {
             return ENOTDIR;
         }
         inode = cast_file_to_inode(dir).unwrap();
     }
-    let token = inner.memory_set.token();
+    let token = inner.memory_set.lock().token();
+    // 访问用户缓冲区时可能缺页，不能持有 inner
+    drop(inner);
     let mut v = translated_byte_buffer(token, buf, len);
     let mut read_size = 0usize;
     let mut offset_in_slice = 0usize;
@@ -456,53 +930,126 @@ pub fn sys_getdents64(dirfd: i32, buf: *mut u8, len: usize) -> isize {
     }
 }
 
-pub fn sys_umount2(_target: *const u8, _flags: i32) -> isize {
+/// umount2 flag: detach the filesystem even if it is busy
+const MNT_DETACH: i32 = 2;
+
+/// umount2 syscall
+///
+/// 文件系统上还有打开的文件、工作目录或下层挂载点时返回 EBUSY，除非指定了 MNT_DETACH。
+pub fn sys_umount2(target: *const u8, flags: i32) -> isize {
     trace!("kernel:pid[{}] sys_umount2", current_task().unwrap().pid.0);
+    let task = current_task().unwrap();
+    let (work_dir, mnt_ns, is_root) = {
+        let inner = task.inner_exclusive_access();
+        (
+            inner.work_dir.clone(),
+            inner.mnt_ns.clone(),
+            inner.cred.is_root(),
+        )
+    };
+    if !is_root {
+        return EPERM;
+    }
+    let target = match lookup_path(&work_dir, &translated_str(current_user_token(), target)) {
+        Ok(target) => target.name().to_string(),
+        Err(err) => return err,
+    };
+    let Some(fs) = mnt_ns.fs_at(&target) else {
+        return EINVAL;
+    };
+    if Arc::ptr_eq(&fs, &mnt_ns.rootfs()) {
+        return EBUSY;
+    }
+    if flags & MNT_DETACH == 0 && (!mnt_ns.mounts_below(&target).is_empty() || fs_in_use(&fs)) {
+        return EBUSY;
+    }
+    // 卸载前把文件系统的脏块写回磁盘
+    sync_all();
+    mnt_ns.unmount(&target);
     0
 }
 
+/// mount syscall
+///
+/// `source` is ignored, ext4 and vfat come from the only block device. `flags` and
+/// `data` are not supported yet.
 pub fn sys_mount(
-    _source: *const u8, _target: *const u8, _fs: *const u8, _flags: u32, _data: *const u8,
+    _source: *const u8, target: *const u8, fs: *const u8, _flags: u32, _data: *const u8,
 ) -> isize {
     trace!("kernel:pid[{}] sys_mount", current_task().unwrap().pid.0);
+    let task = current_task().unwrap();
+    let token = current_user_token();
+    let (work_dir, mnt_ns, is_root) = {
+        let inner = task.inner_exclusive_access();
+        (
+            inner.work_dir.clone(),
+            inner.mnt_ns.clone(),
+            inner.cred.is_root(),
+        )
+    };
+    if !is_root {
+        return EPERM;
+    }
+    let Some(fs_type) = FileSystemType::from_str(&translated_str(token, fs)) else {
+        return ENODEV;
+    };
+    let target = match lookup_path(&work_dir, &translated_str(token, target)) {
+        Ok(target) => target.name().to_string(),
+        Err(err) => return err,
+    };
+    if mnt_ns.fs_at(&target).is_some() {
+        return EBUSY;
+    }
+    let Some(fs) = new_filesystem(fs_type) else {
+        return EINVAL;
+    };
+    mnt_ns.mount(fs, &target);
     0
 }
 
+const FIONCLEX: usize = 0x5450;
+const FIOCLEX: usize = 0x5451;
+
+/// ioctl syscall
+///
+/// FIOCLEX 和 FIONCLEX 修改的是描述符自己的 FD_CLOEXEC，在描述符表里处理；其余请求交给文件。
 pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> isize {
     trace!("kernel:pid[{}] sys_ioctl", current_task().unwrap().pid.0);
-    // TODO:
-    ENOTTY
-    // let task = current_task().unwrap();
-    // let mut inner = task.inner_exclusive_access(file!(), line!());
-    // if fd >= inner.fd_table.len() {
-    //     return EBADF;
-    // }
-    // if inner.fd_table[fd].is_none() {
-    //     return EBADF;
-    // }
-    // if let Some(file) = &inner.fd_table[fd] {
-    //     let file = file.clone();
-    //     file.ioctl(request, arg1, arg2, arg3, arg4)
-    // } else {
-    //     EBADF
-    // }
+    let task = current_task().unwrap();
+    let fd_table = task.fd_table();
+    let mut fd_table = fd_table.lock();
+    let Some(Some(entry)) = fd_table.get_mut(fd) else {
+        return EBADF;
+    };
+    match request {
+        FIONCLEX | FIOCLEX => {
+            entry.fd_flags.set(FdFlags::FD_CLOEXEC, request == FIOCLEX);
+            0
+        }
+        _ => {
+            let file = entry.file.clone();
+            drop(fd_table);
+            file.ioctl(request, arg)
+        }
+    }
 }
 
 pub fn sys_writev(fd: usize, iov: usize, iovcnt: usize) -> isize {
     trace!("kernel:pid[{}] sys_writev", current_task().unwrap().pid.0);
     let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access(file!(), line!());
-    if fd >= inner.fd_table.len() {
+    let fd_table = task.fd_table();
+    let fd_table = fd_table.lock();
+    if fd >= fd_table.len() {
         return EBADF;
     }
-    if inner.fd_table[fd].is_none() {
+    if fd_table[fd].is_none() {
         return EBADF;
     }
-    if let Some(file) = &inner.fd_table[fd] {
-        if !file.writable() {
+    if let Some(entry) = &fd_table[fd] {
+        if !entry.file.writable() {
             return EACCES;
         }
-        let file = file.clone();
+        let file = entry.file.clone();
         let mut total_len = 0;
         let iovec_size: usize = core::mem::size_of::<Iovec>();
         for i in 0..iovcnt {
@@ -513,10 +1060,16 @@ pub fn sys_writev(fd: usize, iov: usize, iovcnt: usize) -> isize {
             let iov_base = unsafe { (*(current as *const Iovec)).iov_base };
             let iov_len = unsafe { (*(current as *const Iovec)).iov_len };
             let buf = unsafe { core::slice::from_raw_parts(iov_base as *const u8, iov_len) };
-            total_len += file.write(buf);
+            let ret = file.try_write(buf);
             unsafe {
                 sstatus::clear_sum();
             }
+            // 已经写入了一部分时返回写入的长度，错误留给下一次写
+            match ret {
+                Ok(len) => total_len += len,
+                Err(errno) if total_len == 0 => return errno,
+                Err(_) => break,
+            }
         }
 
         total_len as isize
@@ -535,52 +1088,56 @@ const F_SETFL: i32 = 4;
 pub fn sys_fcntl(fd: usize, cmd: i32, arg: usize) -> isize {
     trace!("kernel:pid[{}] sys_fcntl", current_task().unwrap().pid.0);
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
-    if fd >= inner.fd_table.len() {
+    let nofile = task.rlimit(RLIMIT_NOFILE);
+    let fd_table = task.fd_table();
+    let mut fd_table = fd_table.lock();
+    if fd >= fd_table.len() {
         return EBADF;
     }
-    if inner.fd_table[fd].is_none() {
+    if fd_table[fd].is_none() {
         return EBADF;
     }
     match cmd {
-        F_DUPFD => {
-            let new_fd = inner.alloc_fd();
-            inner.fd_table[new_fd] = inner.fd_table[fd].clone();
-            debug!(
-                "kernel:pid[{}] sys_fcntl F_DUPFD fd:{} => new_fd:{}",
-                task.pid.0, fd, new_fd
-            );
-            new_fd as isize
-        }
-        F_DUPFD_CLOEXEC => {
-            let new_fd = inner.alloc_fd();
-            inner.fd_table[new_fd] = inner.fd_table[fd].clone();
-            // TODO: fix this
-            // inner.fd_table[new_fd].as_mut().unwrap().flags |= OpenFlags::CLOEXEC;
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            if arg >= nofile {
+                return EINVAL;
+            }
+            let Some(new_fd) = fd_table.alloc_fd_from(arg, nofile) else {
+                return EMFILE;
+            };
+            let cloexec = cmd == F_DUPFD_CLOEXEC;
+            let entry = fd_table[fd].as_ref().unwrap().dup(cloexec);
+            fd_table[new_fd] = Some(entry);
             debug!(
                 "kernel:pid[{}] sys_fcntl F_DUPFD fd:{} => new_fd:{}",
                 task.pid.0, fd, new_fd
             );
             new_fd as isize
         }
+        F_GETFD => fd_table[fd].as_ref().unwrap().fd_flags.bits() as isize,
         F_SETFD => {
-            // TODO: fix this
-            // let flags = OpenFlags::from_bits(arg as u32).ok_or(SyscallErr::EINVAL)?;
-            // inner.fd_table[fd].as_mut().unwrap().flags = flags;
+            let entry = fd_table[fd].as_mut().unwrap();
+            entry.fd_flags = FdFlags::from_bits_truncate(arg as u32);
             0
         }
+        F_GETFL => fd_table[fd].as_ref().unwrap().status_flags.bits() as isize,
         F_SETFL => {
-            // TODO: fix this
-            // let flags = OpenFlags::from_bits(arg as u32).ok_or(SyscallErr::EINVAL)?;
-            // inner.fd_table[fd].as_mut().unwrap().flags = flags;
+            let settable = FdEntry::settable_flags();
+            let flags = OpenFlags::from_bits_truncate(arg as i32) & settable;
+            // 状态标志属于打开的文件，dup 得到的描述符也要看到修改
+            let file = fd_table[fd].as_ref().unwrap().file.clone();
+            for entry in fd_table.iter_mut().flatten() {
+                if Arc::ptr_eq(&entry.file, &file) {
+                    entry.status_flags = (entry.status_flags - settable) | flags;
+                }
+            }
+            file.set_nonblock(flags.contains(OpenFlags::O_NONBLOCK));
+            if let Some(file) = cast_file_to_open_file(file) {
+                file.set_append(flags.contains(OpenFlags::O_APPEND));
+            }
             0
         }
-        F_GETFD | F_GETFL => {
-            todo!()
-        }
-        _ => {
-            todo!()
-        }
+        _ => EINVAL,
     }
 }
 
@@ -592,20 +1149,27 @@ pub fn sys_sendfile(out_fd: usize, in_fd: usize, offset: usize, count: usize) ->
         out_fd
     );
     let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access(file!(), line!());
-    if out_fd >= inner.fd_table.len() || in_fd >= inner.fd_table.len() {
+    let fd_table = task.fd_table();
+    let fd_table = fd_table.lock();
+    if out_fd >= fd_table.len() || in_fd >= fd_table.len() {
         return EBADF;
     }
-    if inner.fd_table[out_fd].is_none() || inner.fd_table[in_fd].is_none() {
+    if fd_table[out_fd].is_none() || fd_table[in_fd].is_none() {
         return EBADF;
     }
-    let out_file = inner.fd_table[out_fd].as_ref().unwrap().clone();
-    let in_file = inner.fd_table[in_fd].as_ref().unwrap().clone();
+    let out_file = fd_table[out_fd].as_ref().unwrap().file.clone();
+    let in_file = fd_table[in_fd].as_ref().unwrap().file.clone();
     let mut buf = vec![0u8; 10000];
-    drop(inner);
-    let read_size = in_file.read(&mut buf);
+    drop(fd_table);
+    let read_size = match in_file.try_read(&mut buf) {
+        Ok(len) => len,
+        Err(errno) => return errno,
+    };
     // warn!("buf: {:?}", buf,);
-    let ret = out_file.write(&buf[..read_size]) as isize;
+    let ret = match out_file.try_write(&buf[..read_size]) {
+        Ok(len) => len as isize,
+        Err(errno) => errno,
+    };
     error!("count: {}, write size: {}", count, ret);
     ret
 }
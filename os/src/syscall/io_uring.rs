@@ -0,0 +1,124 @@
+//! io_uring syscalls
+//!
+//! 环和执行请求的内核线程在 [`crate::fs::io_uring`] 中，这里只处理参数的拷贝和 fd。
+//! 不支持任何 setup 标志（SQPOLL 等），提交总是经过 io_uring_enter。
+
+use alloc::sync::Arc;
+use core::mem::size_of;
+
+use crate::{
+    fs::{
+        defs::OpenFlags,
+        fd::FdEntry,
+        file::cast_file_to_io_ring,
+        io_uring::{IoRing, IoUringParams, IORING_ENTER_GETEVENTS},
+    },
+    mm::{translated_byte_buffer, user_range_ok},
+    syscall::errno::{EBADF, EFAULT, EINVAL, EMFILE, EOPNOTSUPP},
+    task::{current_task, current_user_token, resource::RLIMIT_NOFILE},
+};
+
+/// Copy `params` from user space, false if it is not mapped
+fn read_params(ptr: *const IoUringParams, params: &mut IoUringParams) -> bool {
+    let token = current_user_token();
+    if !user_range_ok(token, ptr as usize, size_of::<IoUringParams>(), false) {
+        return false;
+    }
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(
+            params as *mut IoUringParams as *mut u8,
+            size_of::<IoUringParams>(),
+        )
+    };
+    let mut copied = 0;
+    for buf in translated_byte_buffer(token, ptr as *const u8, size_of::<IoUringParams>()) {
+        bytes[copied..copied + buf.len()].copy_from_slice(buf);
+        copied += buf.len();
+    }
+    true
+}
+
+/// Copy `params` to user space, false if it is not mapped writable
+fn write_params(ptr: *mut IoUringParams, params: &IoUringParams) -> bool {
+    let token = current_user_token();
+    if !user_range_ok(token, ptr as usize, size_of::<IoUringParams>(), true) {
+        return false;
+    }
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            params as *const IoUringParams as *const u8,
+            size_of::<IoUringParams>(),
+        )
+    };
+    let mut copied = 0;
+    for buf in translated_byte_buffer(token, ptr as *const u8, size_of::<IoUringParams>()) {
+        buf.copy_from_slice(&bytes[copied..copied + buf.len()]);
+        copied += buf.len();
+    }
+    true
+}
+
+/// Create a ring with `entries` SQ entries and a kernel thread serving it, filling in
+/// `params` with where the fields of the rings are
+pub fn sys_io_uring_setup(entries: u32, params: *mut IoUringParams) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_io_uring_setup",
+        current_task().unwrap().pid.0
+    );
+    let mut user_params = IoUringParams::default();
+    if !read_params(params, &mut user_params) {
+        return EFAULT;
+    }
+    if user_params.flags != 0 {
+        return EINVAL;
+    }
+    let task = current_task().unwrap();
+    let cgroup = task.inner_exclusive_access().cgroup.clone();
+    let ring = match IoRing::new(entries, &cgroup) {
+        Ok(ring) => Arc::new(ring),
+        Err(errno) => return errno,
+    };
+    if !write_params(params, &ring.params()) {
+        return EFAULT;
+    }
+    // 分配 fd 失败时环随之关闭，内核线程也就退出了
+    ring.spawn_worker(&task);
+    let nofile = task.rlimit(RLIMIT_NOFILE);
+    let fd_table = task.fd_table();
+    let mut fd_table = fd_table.lock();
+    let Some(fd) = fd_table.alloc_fd(nofile) else {
+        return EMFILE;
+    };
+    fd_table[fd] = Some(FdEntry::new(ring, OpenFlags::O_RDWR | OpenFlags::O_CLOEXEC));
+    fd as isize
+}
+
+/// Submit at most `to_submit` SQEs of the ring at `fd`, then with IORING_ENTER_GETEVENTS
+/// wait until `min_complete` CQEs are ready; returns how many SQEs were submitted
+pub fn sys_io_uring_enter(fd: usize, to_submit: u32, min_complete: u32, flags: u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_io_uring_enter",
+        current_task().unwrap().pid.0
+    );
+    let task = current_task().unwrap();
+    let Some(entry) = task.fd_table().lock().get(fd).cloned().flatten() else {
+        return EBADF;
+    };
+    let Some(ring) = cast_file_to_io_ring(entry.file) else {
+        return EOPNOTSUPP;
+    };
+    if flags & !IORING_ENTER_GETEVENTS != 0 {
+        return EINVAL;
+    }
+    drop(task);
+    let submitted = ring.submit(to_submit);
+    if flags & IORING_ENTER_GETEVENTS != 0 {
+        // 被信号打断时，已经提交的请求照常报告
+        if let Err(errno) = ring.wait_completions(min_complete) {
+            if submitted == 0 {
+                return errno;
+            }
+        }
+    }
+    submitted as isize
+}
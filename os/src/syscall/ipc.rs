@@ -0,0 +1,115 @@
+//! System V IPC syscalls, see [`crate::ipc`]
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use crate::{
+    ipc::{
+        msg::{msg_queue, msg_remove, msgget, Message, MsqidDs, MSGMAX},
+        IPC_64,
+        IPC_RMID,
+        IPC_SET,
+        IPC_STAT,
+    },
+    mm::{translated_byte_buffer, translated_ref, translated_refmut, user_range_ok},
+    syscall::errno::{EFAULT, EINVAL, SUCCESS},
+    task::{cred::Credentials, current_task, current_user_token},
+};
+
+fn current_cred() -> Credentials {
+    current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .cred
+        .clone()
+}
+
+pub fn sys_msgget(key: i32, msgflg: usize) -> isize {
+    trace!("kernel:pid[{}] sys_msgget", current_task().unwrap().pid.0);
+    match msgget(key, msgflg, &current_cred()) {
+        Ok(id) => id as isize,
+        Err(errno) => errno,
+    }
+}
+
+/// `msgp` points to `struct msgbuf { long mtype; char mtext[msgsz]; }`
+pub fn sys_msgsnd(msqid: usize, msgp: *const u8, msgsz: usize, msgflg: usize) -> isize {
+    trace!("kernel:pid[{}] sys_msgsnd", current_task().unwrap().pid.0);
+    if msgp.is_null() {
+        return EFAULT;
+    }
+    // 先检查长度和地址，再按 msgsz 分配和复制
+    if msgsz > MSGMAX {
+        return EINVAL;
+    }
+    let token = current_user_token();
+    if !user_range_ok(token, msgp as usize, size_of::<isize>() + msgsz, false) {
+        return EFAULT;
+    }
+    let queue = match msg_queue(msqid) {
+        Ok(queue) => queue,
+        Err(errno) => return errno,
+    };
+    let mtype = *translated_ref(token, msgp as *const isize);
+    let mut text = Vec::with_capacity(msgsz);
+    for chunk in translated_byte_buffer(token, msgp.wrapping_add(size_of::<isize>()), msgsz) {
+        text.extend_from_slice(chunk);
+    }
+    match queue.send(&current_cred(), Message { mtype, text }, msgflg) {
+        Ok(()) => SUCCESS,
+        Err(errno) => errno,
+    }
+}
+
+/// Returns the number of bytes copied into `mtext`
+pub fn sys_msgrcv(
+    msqid: usize, msgp: *mut u8, msgsz: usize, msgtyp: isize, msgflg: usize,
+) -> isize {
+    trace!("kernel:pid[{}] sys_msgrcv", current_task().unwrap().pid.0);
+    if msgp.is_null() {
+        return EFAULT;
+    }
+    let queue = match msg_queue(msqid) {
+        Ok(queue) => queue,
+        Err(errno) => return errno,
+    };
+    let msg = match queue.receive(&current_cred(), msgsz, msgtyp, msgflg) {
+        Ok(msg) => msg,
+        Err(errno) => return errno,
+    };
+    // 阻塞期间可能切换过地址空间，重新取 token
+    let token = current_user_token();
+    let len = size_of::<isize>() + msg.text.len();
+    if !user_range_ok(token, msgp as usize, len, true) {
+        return EFAULT;
+    }
+    *translated_refmut(token, msgp as *mut isize) = msg.mtype;
+    let mut copied = 0;
+    let mtext = msgp.wrapping_add(size_of::<isize>());
+    for chunk in translated_byte_buffer(token, mtext, msg.text.len()) {
+        chunk.copy_from_slice(&msg.text[copied..copied + chunk.len()]);
+        copied += chunk.len();
+    }
+    copied as isize
+}
+
+pub fn sys_msgctl(msqid: usize, cmd: usize, buf: *mut MsqidDs) -> isize {
+    trace!("kernel:pid[{}] sys_msgctl", current_task().unwrap().pid.0);
+    let cred = current_cred();
+    let result = match cmd & !IPC_64 {
+        IPC_RMID => msg_remove(msqid, &cred),
+        IPC_STAT | IPC_SET if buf.is_null() => Err(EFAULT),
+        IPC_STAT => msg_queue(msqid)
+            .and_then(|queue| queue.stat(&cred))
+            .map(|ds| *translated_refmut(current_user_token(), buf) = ds),
+        IPC_SET => {
+            let ds = *translated_ref(current_user_token(), buf as *const MsqidDs);
+            msg_queue(msqid).and_then(|queue| queue.set(&cred, &ds))
+        }
+        _ => Err(EINVAL),
+    };
+    match result {
+        Ok(()) => SUCCESS,
+        Err(errno) => errno,
+    }
+}
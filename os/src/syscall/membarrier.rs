@@ -0,0 +1,67 @@
+//! membarrier(2)
+//!
+//! 内核目前只在一个 hart 上运行：系统调用返回前执行一次完整的 fence，
+//! 其他线程此时都没有在运行，下次被调度时经过上下文切换，本身就满足屏障语义。
+//! 多核之后，expedited 命令需要向正在运行该进程的 hart 发 IPI，让它们各自执行 fence。
+
+use core::sync::atomic::{fence, Ordering};
+
+use super::errno::{EINVAL, EPERM, SUCCESS};
+use crate::task::current_task;
+
+bitflags! {
+    /// membarrier commands, also used for the set of commands a process registered for
+    pub struct MembarrierCmd: usize {
+        /// barrier on all running threads of all processes
+        const GLOBAL                     = 1 << 0;
+        /// barrier on threads of processes registered for it
+        const GLOBAL_EXPEDITED           = 1 << 1;
+        const REGISTER_GLOBAL_EXPEDITED  = 1 << 2;
+        /// barrier on the running threads of the calling process
+        const PRIVATE_EXPEDITED          = 1 << 3;
+        const REGISTER_PRIVATE_EXPEDITED = 1 << 4;
+    }
+}
+
+/// return the supported commands
+pub const MEMBARRIER_CMD_QUERY: usize = 0;
+
+/// membarrier syscall
+///
+/// `PRIVATE_EXPEDITED` fails with EPERM unless the process registered for it; registrations are
+/// dropped by exec and not inherited by fork, as in Linux.
+pub fn sys_membarrier(cmd: usize, flags: usize, _cpu_id: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_membarrier",
+        current_task().unwrap().pid.0
+    );
+    if flags != 0 {
+        return EINVAL;
+    }
+    if cmd == MEMBARRIER_CMD_QUERY {
+        return MembarrierCmd::all().bits() as isize;
+    }
+    // 一次只能有一个命令
+    let cmd = match MembarrierCmd::from_bits(cmd) {
+        Some(cmd) if cmd.bits().is_power_of_two() => cmd,
+        _ => return EINVAL,
+    };
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if cmd == MembarrierCmd::REGISTER_GLOBAL_EXPEDITED
+        || cmd == MembarrierCmd::REGISTER_PRIVATE_EXPEDITED
+    {
+        inner.membarrier |= cmd;
+        return SUCCESS;
+    }
+    if cmd == MembarrierCmd::PRIVATE_EXPEDITED
+        && !inner
+            .membarrier
+            .contains(MembarrierCmd::REGISTER_PRIVATE_EXPEDITED)
+    {
+        return EPERM;
+    }
+    drop(inner);
+    fence(Ordering::SeqCst);
+    SUCCESS
+}
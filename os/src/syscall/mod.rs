@@ -13,12 +13,16 @@
 pub mod errno;
 
 pub const SYSCALL_GETCWD: usize = 17;
+pub const SYSCALL_EPOLL_CREATE1: usize = 20;
+pub const SYSCALL_EPOLL_CTL: usize = 21;
+pub const SYSCALL_EPOLL_PWAIT: usize = 22;
 pub const SYSCALL_DUP: usize = 23;
 pub const SYSCALL_DUP3: usize = 24;
 pub const SYSCALL_FCNTL: usize = 25;
 pub const SYSCALL_IOCTL: usize = 29;
 pub const SYSCALL_MKDIRAT: usize = 34;
 pub const SYSCALL_UNLINKAT: usize = 35;
+pub const SYSCALL_SYMLINKAT: usize = 36;
 pub const SYSCALL_LINKAT: usize = 37;
 pub const SYSCALL_UMOUNT2: usize = 39;
 pub const SYSCALL_MOUNT: usize = 40;
@@ -26,25 +30,54 @@ pub const SYSCALL_CHDIR: usize = 49;
 pub const SYSCALL_OPENAT: usize = 56;
 pub const SYSCALL_CLOSE: usize = 57;
 pub const SYSCALL_GETDENTS64: usize = 61;
+pub const SYSCALL_LSEEK: usize = 62;
 pub const SYSCALL_READ: usize = 63;
 pub const SYSCALL_WRITE: usize = 64;
 pub const SYSCALL_WRITEV: usize = 66;
+pub const SYSCALL_PREAD64: usize = 67;
+pub const SYSCALL_PWRITE64: usize = 68;
 pub const SYSCALL_SENDFILE: usize = 71;
 pub const SYSCALL_PPOLL: usize = 73;
+pub const SYSCALL_READLINKAT: usize = 78;
+pub const SYSCALL_FSTATAT: usize = 79;
 pub const SYSCALL_FSTAT: usize = 80;
+pub const SYSCALL_SYNC: usize = 81;
+pub const SYSCALL_FSYNC: usize = 82;
+pub const SYSCALL_FDATASYNC: usize = 83;
 pub const SYSCALL_EXIT: usize = 93;
 pub const SYSCALL_EXIT_GROUP: usize = 94;
 pub const SYSCALL_SETTID: usize = 96;
-pub const SYSCALL_SLEEP: usize = 101;
+pub const SYSCALL_FUTEX: usize = 98;
+pub const SYSCALL_NANOSLEEP: usize = 101;
+pub const SYSCALL_GETITIMER: usize = 102;
+pub const SYSCALL_SETITIMER: usize = 103;
+pub const SYSCALL_CLOCK_SETTIME: usize = 112;
 pub const SYSCALL_CLOCK_GETTIME: usize = 113;
+pub const SYSCALL_CLOCK_NANOSLEEP: usize = 115;
+pub const SYSCALL_SYSLOG: usize = 116;
 pub const SYSCALL_YIELD: usize = 124;
 pub const SYSCALL_KILL: usize = 129;
 pub const SYSCALL_SIGACTION: usize = 134;
 pub const SYSCALL_SIGPROCMASK: usize = 135;
 pub const SYSCALL_SIGTIMEDWAIT: usize = 137;
 pub const SYSCALL_SIGRETURN: usize = 139;
+pub const SYSCALL_SETGID: usize = 144;
+pub const SYSCALL_SETUID: usize = 146;
+pub const SYSCALL_SETRESUID: usize = 147;
+pub const SYSCALL_GETRESUID: usize = 148;
+pub const SYSCALL_SETRESGID: usize = 149;
+pub const SYSCALL_GETRESGID: usize = 150;
 pub const SYSCALL_TIMES: usize = 153;
+pub const SYSCALL_SETPGID: usize = 154;
+pub const SYSCALL_GETPGID: usize = 155;
+pub const SYSCALL_GETSID: usize = 156;
+pub const SYSCALL_SETSID: usize = 157;
+pub const SYSCALL_GETGROUPS: usize = 158;
+pub const SYSCALL_SETGROUPS: usize = 159;
 pub const SYSCALL_UNAME: usize = 160;
+pub const SYSCALL_GETRLIMIT: usize = 163;
+pub const SYSCALL_SETRLIMIT: usize = 164;
+pub const SYSCALL_PRCTL: usize = 167;
 pub const SYSCALL_GETTIMEOFDAY: usize = 169;
 pub const SYSCALL_GETPID: usize = 172;
 pub const SYSCALL_GETPPID: usize = 173;
@@ -53,21 +86,53 @@ pub const SYSCALL_GETEUID: usize = 175;
 pub const SYSCALL_GETGID: usize = 176;
 pub const SYSCALL_GETEGID: usize = 177;
 pub const SYSCALL_GETTID: usize = 178;
+pub const SYSCALL_MSGGET: usize = 186;
+pub const SYSCALL_MSGCTL: usize = 187;
+pub const SYSCALL_MSGRCV: usize = 188;
+pub const SYSCALL_MSGSND: usize = 189;
+pub const SYSCALL_SOCKET: usize = 198;
+pub const SYSCALL_SOCKETPAIR: usize = 199;
+pub const SYSCALL_BIND: usize = 200;
+pub const SYSCALL_LISTEN: usize = 201;
+pub const SYSCALL_ACCEPT: usize = 202;
+pub const SYSCALL_CONNECT: usize = 203;
+pub const SYSCALL_GETSOCKNAME: usize = 204;
+pub const SYSCALL_GETPEERNAME: usize = 205;
+pub const SYSCALL_SENDTO: usize = 206;
+pub const SYSCALL_RECVFROM: usize = 207;
+pub const SYSCALL_SETSOCKOPT: usize = 208;
+pub const SYSCALL_GETSOCKOPT: usize = 209;
+pub const SYSCALL_SHUTDOWN: usize = 210;
+pub const SYSCALL_SENDMSG: usize = 211;
+pub const SYSCALL_RECVMSG: usize = 212;
 pub const SYSCALL_CLONE: usize = 220;
 pub const SYSCALL_EXECVE: usize = 221;
 pub const SYSCALL_WAIT4: usize = 260;
 pub const SYSCALL_PRLIMIT64: usize = 261;
 pub const SYSCALL_SET_PRIORITY: usize = 140;
+pub const SYSCALL_GET_PRIORITY: usize = 141;
 pub const SYSCALL_BRK: usize = 214;
 pub const SYSCALL_MUNMAP: usize = 215;
 pub const SYSCALL_MMAP: usize = 222;
+pub const SYSCALL_MPROTECT: usize = 226;
+pub const SYSCALL_MSYNC: usize = 227;
+pub const SYSCALL_ACCEPT4: usize = 242;
+pub const SYSCALL_RENAMEAT2: usize = 276;
+pub const SYSCALL_SECCOMP: usize = 277;
+pub const SYSCALL_GETRANDOM: usize = 278;
+pub const SYSCALL_MEMBARRIER: usize = 283;
+pub const SYSCALL_STATX: usize = 291;
 pub const SYSCALL_SPAWN: usize = 400;
+pub const SYSCALL_IO_URING_SETUP: usize = 425;
+pub const SYSCALL_IO_URING_ENTER: usize = 426;
 /*
 pub const SYSCALL_MAIL_READ: usize = 401;
 pub const SYSCALL_MAIL_WRITE: usize = 402;
 */
 pub const SYSCALL_PIPE: usize = 59;
 pub const SYSCALL_TASK_INFO: usize = 410;
+pub const SYSCALL_CHECKPOINT: usize = 420;
+pub const SYSCALL_RESTORE: usize = 421;
 pub const SYSCALL_THREAD_CREATE: usize = 460;
 pub const SYSCALL_WAITTID: usize = 462;
 pub const SYSCALL_MUTEX_CREATE: usize = 463;
@@ -81,62 +146,295 @@ pub const SYSCALL_CONDVAR_CREATE: usize = 471;
 pub const SYSCALL_CONDVAR_SIGNAL: usize = 472;
 pub const SYSCALL_CONDVAR_WAIT: usize = 473;
 
+pub mod audit;
+mod checkpoint;
+mod cred;
+mod epoll;
 mod fs;
+mod io_uring;
+mod ipc;
+pub mod membarrier;
+mod net;
 mod ppoll;
 mod process;
+mod random;
+mod resource;
+mod seccomp;
 mod signal;
 mod sync;
+pub mod syslog;
 mod thread;
 mod time;
+mod trace;
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use checkpoint::{sys_checkpoint, sys_restore};
+use cred::*;
+use epoll::{sys_epoll_create1, sys_epoll_ctl, sys_epoll_pwait};
+use errno::ENOSYS;
 use fs::*;
+use io_uring::{sys_io_uring_enter, sys_io_uring_setup};
+use ipc::{sys_msgctl, sys_msgget, sys_msgrcv, sys_msgsnd};
+use membarrier::sys_membarrier;
+use net::*;
 use ppoll::{sys_ppoll, PollFd};
 use process::*;
-use signal::{sys_sigaction, sys_sigprocmask, sys_sigtimedwait};
+use random::sys_getrandom;
+use resource::{sys_getrlimit, sys_prlimit64, sys_setrlimit};
+use seccomp::sys_seccomp;
+use signal::{sys_sigaction, sys_sigprocmask, sys_sigreturn, sys_sigtimedwait};
+use sync::*;
+use syslog::sys_syslog;
 use thread::*;
-use time::sys_clock_gettime;
+use time::{
+    sys_clock_gettime,
+    sys_clock_nanosleep,
+    sys_clock_settime,
+    sys_getitimer,
+    sys_nanosleep,
+    sys_setitimer,
+};
+pub use trace::name as syscall_name;
 
 use crate::{
-    fs::inode::Stat,
-    task::{current_task, sigaction::SignalAction, signal::SigInfo, SignalFlags},
-    timer::TimeSpec,
+    fs::{
+        epoll::EpollEvent,
+        inode::{Stat, Statx},
+        io_uring::IoUringParams,
+    },
+    ipc::msg::MsqidDs,
+    profile,
+    task::{
+        current_task,
+        resource::RLimit,
+        seccomp::{FilterAction, SeccompList},
+        sigaction::SignalAction,
+        signal::SigInfo,
+        SignalFlags,
+    },
+    timer::{get_time_ms, ITimerVal, TimeSpec},
 };
 
 /// handle syscall exception with `syscall_id` and other arguments
 pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
+    let start = profile::start();
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
-    inner.syscall_times[syscall_id] += 1;
+    let mut inner = task.inner_exclusive_access();
+    // 用户可以传任意的调用号，超出范围的不计数
+    if let Some(times) = inner.syscall_times.get_mut(syscall_id) {
+        *times += 1;
+    }
+    let traced = inner.syscall_trace;
+    // 审计记录调用前的身份，setuid 之类会改变它
+    let audit_uid = audit::audited(syscall_id, &args).then_some(inner.cred.euid);
+    let verdict = inner
+        .syscall_filter
+        .as_ref()
+        .and_then(|filter| filter.check(syscall_id));
+    if verdict == Some(FilterAction::Kill) {
+        // 返回用户态前的信号检查会以 SIGSYS 结束该任务
+        inner.signals |= SignalFlags::SIGSYS;
+    }
     drop(inner);
+    let (pid, tid) = (task.pid.0, task.tid);
     drop(task);
+    if !traced && audit_uid.is_none() {
+        let ret = filtered_dispatch(verdict, syscall_id, args);
+        profile::record_syscall(syscall_id, start);
+        return ret;
+    }
+    // 参数在调用前格式化，execve 之后原来的用户内存就不在了
+    let audit_call = audit_uid.map(|uid| (uid, audit::format_call(syscall_id, &args)));
+    let call = traced.then(|| trace::format_call(syscall_id, &args));
+    if let Some(call) = call.as_ref() {
+        if trace::no_return(syscall_id) && verdict.is_none() {
+            trace::record(syscall_id, pid, tid, call, None);
+        }
+    }
+    let ret = filtered_dispatch(verdict, syscall_id, args);
+    profile::record_syscall(syscall_id, start);
+    if let Some((uid, audit_call)) = audit_call {
+        audit::record(pid, uid, audit_call, ret);
+    }
+    if let Some(call) = call {
+        trace::record(syscall_id, pid, tid, &call, Some(ret));
+    }
+    ret
+}
+
+/// Run the syscall unless the seccomp filter rejected it
+fn filtered_dispatch(verdict: Option<FilterAction>, syscall_id: usize, args: [usize; 6]) -> isize {
+    match verdict {
+        None => dispatch(syscall_id, args),
+        Some(FilterAction::Errno(errno)) => errno,
+        Some(FilterAction::Kill) => ENOSYS,
+    }
+}
+
+fn dispatch(syscall_id: usize, args: [usize; 6]) -> isize {
     match syscall_id {
         SYSCALL_GETCWD => sys_getcwd(args[0] as *mut u8, args[1]),
         SYSCALL_DUP => sys_dup(args[0]),
-        SYSCALL_DUP3 => sys_dup3(args[0], args[1]),
+        SYSCALL_DUP3 => sys_dup3(args[0], args[1], args[2] as i32),
         SYSCALL_LINKAT => sys_linkat(args[1] as *const u8, args[3] as *const u8),
-        SYSCALL_UNLINKAT => sys_unlinkat(args[1] as *const u8),
+        SYSCALL_SYMLINKAT => {
+            sys_symlinkat(args[0] as *const u8, args[1] as i32, args[2] as *const u8)
+        }
+        SYSCALL_READLINKAT => sys_readlinkat(
+            args[0] as i32,
+            args[1] as *const u8,
+            args[2] as *mut u8,
+            args[3],
+        ),
+        SYSCALL_UNLINKAT => sys_unlinkat(args[0] as i32, args[1] as *const u8, args[2] as u32),
+        SYSCALL_RENAMEAT2 => sys_renameat2(
+            args[0] as i32,
+            args[1] as *const u8,
+            args[2] as i32,
+            args[3] as *const u8,
+            args[4] as u32,
+        ),
         SYSCALL_OPENAT => sys_openat(args[0] as i32, args[1] as *const u8, args[2] as i32),
         SYSCALL_CLOSE => sys_close(args[0]),
-        SYSCALL_PIPE => sys_pipe(args[0] as *mut u32),
+        SYSCALL_PIPE => sys_pipe(args[0] as *mut u32, args[1] as i32),
         SYSCALL_READ => sys_read(args[0], args[1] as *mut u8, args[2]),
         SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
+        SYSCALL_LSEEK => sys_lseek(args[0], args[1] as isize, args[2]),
+        SYSCALL_PREAD64 => sys_pread64(args[0], args[1] as *mut u8, args[2], args[3] as isize),
+        SYSCALL_PWRITE64 => sys_pwrite64(args[0], args[1] as *const u8, args[2], args[3] as isize),
         SYSCALL_WRITEV => sys_writev(args[0], args[1], args[2]),
+        SYSCALL_FSTATAT => sys_fstatat(
+            args[0] as i32,
+            args[1] as *const u8,
+            args[2] as *mut Stat,
+            args[3] as u32,
+        ),
         SYSCALL_FSTAT => sys_fstat(args[0], args[1] as *mut Stat),
+        SYSCALL_STATX => sys_statx(
+            args[0] as i32,
+            args[1] as *const u8,
+            args[2] as u32,
+            args[3] as u32,
+            args[4] as *mut Statx,
+        ),
+        SYSCALL_SYNC => sys_sync(),
+        SYSCALL_FSYNC | SYSCALL_FDATASYNC => sys_fsync(args[0]),
         SYSCALL_EXIT => sys_exit(args[0] as i32),
         SYSCALL_EXIT_GROUP => sys_exit_group(args[0] as i32),
         SYSCALL_SETTID => sys_set_tid_address(args[0]),
-        // SYSCALL_SLEEP => sys_sleep(args[0] as *const u64, args[1] as *mut u64),
+        SYSCALL_FUTEX => sys_futex(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYSCALL_NANOSLEEP => sys_nanosleep(args[0] as *const TimeSpec, args[1] as *mut TimeSpec),
+        SYSCALL_GETITIMER => sys_getitimer(args[0], args[1] as *mut ITimerVal),
+        SYSCALL_SETITIMER => sys_setitimer(
+            args[0],
+            args[1] as *const ITimerVal,
+            args[2] as *mut ITimerVal,
+        ),
+        SYSCALL_CLOCK_SETTIME => sys_clock_settime(args[0], args[1] as *const TimeSpec),
         SYSCALL_CLOCK_GETTIME => sys_clock_gettime(args[0], args[1] as *mut TimeSpec),
+        SYSCALL_CLOCK_NANOSLEEP => sys_clock_nanosleep(
+            args[0],
+            args[1],
+            args[2] as *const TimeSpec,
+            args[3] as *mut TimeSpec,
+        ),
+        SYSCALL_SYSLOG => sys_syslog(args[0], args[1] as *mut u8, args[2]),
         SYSCALL_YIELD => sys_yield(),
         SYSCALL_TIMES => sys_times(args[0] as *mut Tms),
+        SYSCALL_SETPGID => sys_setpgid(args[0], args[1]),
+        SYSCALL_GETPGID => sys_getpgid(args[0]),
+        SYSCALL_GETSID => sys_getsid(args[0]),
+        SYSCALL_SETSID => sys_setsid(),
         SYSCALL_UNAME => sys_uname(args[0] as *mut Utsname),
+        SYSCALL_GETRLIMIT => sys_getrlimit(args[0], args[1] as *mut RLimit),
+        SYSCALL_SETRLIMIT => sys_setrlimit(args[0], args[1] as *const RLimit),
+        SYSCALL_PRCTL => sys_prctl(args[0], args[1]),
         SYSCALL_GETPID => sys_getpid(),
         SYSCALL_GETPPID => sys_getppid(),
         SYSCALL_GETUID => sys_getuid(),
         SYSCALL_GETEUID => sys_geteuid(),
         SYSCALL_GETGID => sys_getgid(),
         SYSCALL_GETEGID => sys_getegid(),
+        SYSCALL_SETUID => sys_setuid(args[0]),
+        SYSCALL_SETGID => sys_setgid(args[0]),
+        SYSCALL_SETRESUID => sys_setresuid(args[0], args[1], args[2]),
+        SYSCALL_SETRESGID => sys_setresgid(args[0], args[1], args[2]),
+        SYSCALL_GETRESUID => sys_getresuid(
+            args[0] as *mut u32,
+            args[1] as *mut u32,
+            args[2] as *mut u32,
+        ),
+        SYSCALL_GETRESGID => sys_getresgid(
+            args[0] as *mut u32,
+            args[1] as *mut u32,
+            args[2] as *mut u32,
+        ),
+        SYSCALL_GETGROUPS => sys_getgroups(args[0], args[1] as *mut u32),
+        SYSCALL_SETGROUPS => sys_setgroups(args[0], args[1] as *const u32),
         SYSCALL_GETTID => sys_gettid(),
+        SYSCALL_MSGGET => sys_msgget(args[0] as i32, args[1]),
+        SYSCALL_MSGCTL => sys_msgctl(args[0], args[1], args[2] as *mut MsqidDs),
+        SYSCALL_MSGRCV => sys_msgrcv(
+            args[0],
+            args[1] as *mut u8,
+            args[2],
+            args[3] as isize,
+            args[4],
+        ),
+        SYSCALL_MSGSND => sys_msgsnd(args[0], args[1] as *const u8, args[2], args[3]),
+        SYSCALL_SOCKET => sys_socket(args[0] as i32, args[1] as i32, args[2] as i32),
+        SYSCALL_SOCKETPAIR => sys_socketpair(
+            args[0] as i32,
+            args[1] as i32,
+            args[2] as i32,
+            args[3] as *mut i32,
+        ),
+        SYSCALL_BIND => sys_bind(args[0], args[1] as *const u8, args[2] as u32),
+        SYSCALL_LISTEN => sys_listen(args[0], args[1] as i32),
+        SYSCALL_ACCEPT => sys_accept4(args[0], args[1] as *mut u8, args[2] as *mut u32, 0),
+        SYSCALL_ACCEPT4 => sys_accept4(
+            args[0],
+            args[1] as *mut u8,
+            args[2] as *mut u32,
+            args[3] as i32,
+        ),
+        SYSCALL_CONNECT => sys_connect(args[0], args[1] as *const u8, args[2] as u32),
+        SYSCALL_GETSOCKNAME => sys_getsockname(args[0], args[1] as *mut u8, args[2] as *mut u32),
+        SYSCALL_GETPEERNAME => sys_getpeername(args[0], args[1] as *mut u8, args[2] as *mut u32),
+        SYSCALL_SENDTO => sys_sendto(
+            args[0],
+            args[1] as *const u8,
+            args[2],
+            args[3] as u32,
+            args[4] as *const u8,
+            args[5] as u32,
+        ),
+        SYSCALL_RECVFROM => sys_recvfrom(
+            args[0],
+            args[1] as *mut u8,
+            args[2],
+            args[3] as u32,
+            args[4] as *mut u8,
+            args[5] as *mut u32,
+        ),
+        SYSCALL_SETSOCKOPT => sys_setsockopt(
+            args[0],
+            args[1] as i32,
+            args[2] as i32,
+            args[3] as *const u8,
+            args[4] as u32,
+        ),
+        SYSCALL_GETSOCKOPT => sys_getsockopt(
+            args[0],
+            args[1] as i32,
+            args[2] as i32,
+            args[3] as *mut u8,
+            args[4] as *mut u32,
+        ),
+        SYSCALL_SHUTDOWN => sys_shutdown(args[0], args[1] as i32),
+        SYSCALL_SENDMSG => sys_sendmsg(args[0], args[1] as *const MsgHdr, args[2] as u32),
+        SYSCALL_RECVMSG => sys_recvmsg(args[0], args[1] as *mut MsgHdr, args[2] as u32),
         SYSCALL_SIGACTION => sys_sigaction(
             args[0],
             args[1] as *const SignalAction,
@@ -151,13 +449,8 @@ pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
             args[2] as *const TimeSpec,
             args[3],
         ),
-        SYSCALL_CLONE => sys_clone(
-            args[0],
-            args[1],
-            args[2] as *mut usize,
-            args[3],
-            args[4] as *mut usize,
-        ),
+        SYSCALL_SIGRETURN => sys_sigreturn(),
+        SYSCALL_CLONE => sys_clone(args[0], args[1], args[2], args[3], args[4]),
         SYSCALL_BRK => sys_brk(args[0]),
         SYSCALL_EXECVE => sys_execve(
             args[0] as *const u8,
@@ -173,20 +466,26 @@ pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
         SYSCALL_GETTIMEOFDAY => sys_gettimeofday(args[0] as *mut TimeVal, args[1]),
         SYSCALL_MMAP => sys_mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
         SYSCALL_MUNMAP => sys_munmap(args[0], args[1]),
-        SYSCALL_SET_PRIORITY => sys_set_priority(args[0] as isize),
+        SYSCALL_MPROTECT => sys_mprotect(args[0], args[1], args[2]),
+        SYSCALL_MSYNC => sys_msync(args[0], args[1], args[2]),
+        SYSCALL_SET_PRIORITY => sys_set_priority(args[0], args[1], args[2] as i32 as isize),
+        SYSCALL_GET_PRIORITY => sys_get_priority(args[0], args[1]),
         SYSCALL_TASK_INFO => sys_task_info(args[0] as *mut TaskInfo),
         SYSCALL_SPAWN => sys_spawn(args[0] as *const u8),
+        SYSCALL_CHECKPOINT => sys_checkpoint(args[0] as *const u8),
+        SYSCALL_RESTORE => sys_restore(args[0] as *const u8),
         SYSCALL_THREAD_CREATE => sys_thread_create(args[0], args[1]),
         SYSCALL_WAITTID => sys_waittid(args[0]) as isize,
-        // SYSCALL_MUTEX_CREATE => sys_mutex_create(args[0] == 1),
-        // SYSCALL_MUTEX_LOCK => sys_mutex_lock(args[0]),
-        // SYSCALL_MUTEX_UNLOCK => sys_mutex_unlock(args[0]),
-        // SYSCALL_SEMAPHORE_CREATE => sys_semaphore_create(args[0]),
-        // SYSCALL_SEMAPHORE_UP => sys_semaphore_up(args[0]),
-        // SYSCALL_SEMAPHORE_DOWN => sys_semaphore_down(args[0]),
-        // SYSCALL_CONDVAR_CREATE => sys_condvar_create(),
-        // SYSCALL_CONDVAR_SIGNAL => sys_condvar_signal(args[0]),
-        // SYSCALL_CONDVAR_WAIT => sys_condvar_wait(args[0], args[1]),
+        SYSCALL_MUTEX_CREATE => sys_mutex_create(args[0] == 1),
+        SYSCALL_MUTEX_LOCK => sys_mutex_lock(args[0]),
+        SYSCALL_MUTEX_UNLOCK => sys_mutex_unlock(args[0]),
+        SYSCALL_SEMAPHORE_CREATE => sys_semaphore_create(args[0]),
+        SYSCALL_SEMAPHORE_UP => sys_semaphore_up(args[0]),
+        SYSCALL_ENABLE_DEADLOCK_DETECT => sys_enable_deadlock_detect(args[0]),
+        SYSCALL_SEMAPHORE_DOWN => sys_semaphore_down(args[0]),
+        SYSCALL_CONDVAR_CREATE => sys_condvar_create(),
+        SYSCALL_CONDVAR_SIGNAL => sys_condvar_signal(args[0]),
+        SYSCALL_CONDVAR_WAIT => sys_condvar_wait(args[0], args[1]),
         SYSCALL_KILL => sys_kill(args[0], args[1] as u32),
         SYSCALL_CHDIR => sys_chdir(args[0] as *const u8),
         SYSCALL_MKDIRAT => sys_mkdirat64(args[0] as i32, args[1] as *const u8, args[2] as u32),
@@ -207,8 +506,66 @@ pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
             args[2] as *const TimeSpec,
             args[3] as *const SignalFlags,
         ),
+        SYSCALL_EPOLL_CREATE1 => sys_epoll_create1(args[0] as i32),
+        SYSCALL_EPOLL_CTL => sys_epoll_ctl(
+            args[0],
+            args[1] as i32,
+            args[2],
+            args[3] as *const EpollEvent,
+        ),
+        SYSCALL_EPOLL_PWAIT => sys_epoll_pwait(
+            args[0],
+            args[1] as *mut EpollEvent,
+            args[2] as i32,
+            args[3] as i32,
+            args[4] as *const SignalFlags,
+        ),
         SYSCALL_SENDFILE => sys_sendfile(args[0], args[1], args[2], args[3]),
-        SYSCALL_PRLIMIT64 => 0,
-        _ => panic!("Unsupported syscall_id: {}", syscall_id),
+        SYSCALL_PRLIMIT64 => sys_prlimit64(
+            args[0],
+            args[1],
+            args[2] as *const RLimit,
+            args[3] as *mut RLimit,
+        ),
+        SYSCALL_SECCOMP => sys_seccomp(args[0], args[1], args[2] as *const SeccompList),
+        SYSCALL_GETRANDOM => sys_getrandom(args[0] as *mut u8, args[1], args[2] as u32),
+        SYSCALL_MEMBARRIER => sys_membarrier(args[0], args[1], args[2]),
+        SYSCALL_IO_URING_SETUP => sys_io_uring_setup(args[0] as u32, args[1] as *mut IoUringParams),
+        SYSCALL_IO_URING_ENTER => {
+            sys_io_uring_enter(args[0], args[1] as u32, args[2] as u32, args[3] as u32)
+        }
+        _ => unknown_syscall(syscall_id),
+    }
+}
+
+/// Warnings about unknown syscalls logged per second, the rest are only counted
+const UNKNOWN_SYSCALL_WARNINGS: usize = 8;
+
+/// The second of the current warning window and the unknown syscalls seen in it
+static UNKNOWN_SYSCALL_WINDOW: AtomicUsize = AtomicUsize::new(0);
+static UNKNOWN_SYSCALL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A syscall the kernel does not implement: ENOSYS, or a panic with the `strict_syscall`
+/// feature
+fn unknown_syscall(syscall_id: usize) -> isize {
+    if cfg!(feature = "strict_syscall") {
+        panic!("Unsupported syscall_id: {}", syscall_id);
+    }
+    let second = get_time_ms() / 1000;
+    if UNKNOWN_SYSCALL_WINDOW.swap(second, Ordering::Relaxed) != second {
+        let suppressed = UNKNOWN_SYSCALL_COUNT
+            .swap(0, Ordering::Relaxed)
+            .saturating_sub(UNKNOWN_SYSCALL_WARNINGS);
+        if suppressed > 0 {
+            warn!("{} more unsupported syscalls not logged", suppressed);
+        }
+    }
+    if UNKNOWN_SYSCALL_COUNT.fetch_add(1, Ordering::Relaxed) < UNKNOWN_SYSCALL_WARNINGS {
+        warn!(
+            "pid[{}] unsupported syscall_id {}, returning ENOSYS",
+            current_task().unwrap().pid.0,
+            syscall_id
+        );
     }
+    ENOSYS
 }
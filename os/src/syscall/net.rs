@@ -0,0 +1,512 @@
+//! Socket syscalls
+//!
+//! 地址在用户的 `sockaddr` 和 [`SockAddr`] 之间转换，用户缓冲区的数据先复制到内核中，
+//! 收发可能阻塞，期间不能一直打开 SUM；其余的事交给描述符背后的 [`Socket`]。
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::{mem::size_of, ptr::addr_of_mut};
+
+use riscv::register::sstatus;
+
+use crate::{
+    fs::{defs::OpenFlags, fd::FdEntry, file::File, Iovec},
+    mm::user_range_ok,
+    net::{
+        cast_file_to_socket,
+        ip::{IPPROTO_TCP, IPPROTO_UDP},
+        tcp::TcpSocket,
+        udp::UdpSocket,
+        unix::UnixSocket,
+        SockAddr,
+        Socket,
+        AF_INET,
+        AF_UNIX,
+        SOCK_DGRAM,
+        SOCK_STREAM,
+    },
+    syscall::errno::{
+        EAFNOSUPPORT,
+        EBADF,
+        EFAULT,
+        EINVAL,
+        EMFILE,
+        ENOPROTOOPT,
+        ENOTSOCK,
+        EOPNOTSUPP,
+        EPROTONOSUPPORT,
+        ESOCKTNOSUPPORT,
+    },
+    task::{current_task, current_user_token, resource::RLIMIT_NOFILE},
+};
+
+const SOCK_TYPE_MASK: i32 = 0xf;
+const SOCK_NONBLOCK: i32 = 0o4000;
+const SOCK_CLOEXEC: i32 = 0o2000000;
+/// most connections a listening socket queues
+const SOMAXCONN: usize = 4096;
+/// size of `struct sockaddr_storage`, the longest address accepted
+const SOCKADDR_MAX: usize = 128;
+const MSG_DONTWAIT: u32 = 0x40;
+/// most iovecs sendmsg and recvmsg take, as in Linux
+const UIO_MAXIOV: usize = 1024;
+/// most bytes one recv copies out, no socket buffers more
+const RECV_MAX: usize = 64 * 1024;
+
+/// `level` of the socket options
+const SOL_SOCKET: i32 = 1;
+const SOL_TCP: i32 = IPPROTO_TCP as i32;
+const SO_REUSEADDR: i32 = 2;
+const SO_TYPE: i32 = 3;
+const SO_ERROR: i32 = 4;
+const SO_SNDBUF: i32 = 7;
+const SO_RCVBUF: i32 = 8;
+const SO_KEEPALIVE: i32 = 9;
+const TCP_NODELAY: i32 = 1;
+/// SO_SNDBUF and SO_RCVBUF reported, the buffers have a fixed size
+const SOCK_BUF_REPORTED: i32 = 64 * 1024;
+
+/// `struct msghdr`
+#[repr(C)]
+pub struct MsgHdr {
+    name:       usize,
+    namelen:    u32,
+    iov:        usize,
+    iovlen:     usize,
+    control:    usize,
+    controllen: usize,
+    flags:      i32,
+}
+
+/// The socket open at `fd`
+fn fd_socket(fd: usize) -> Result<Arc<dyn Socket>, isize> {
+    let task = current_task().unwrap();
+    let fd_table = task.fd_table();
+    let fd_table = fd_table.lock();
+    let entry = fd_table.get(fd).and_then(|entry| entry.as_ref());
+    let file = entry.ok_or(EBADF)?.file.clone();
+    drop(fd_table);
+    cast_file_to_socket(file).ok_or(ENOTSOCK)
+}
+
+/// Open `file` with the SOCK_NONBLOCK and SOCK_CLOEXEC of `flags`, return the new fd
+fn install(file: Arc<dyn File>, flags: i32) -> Result<usize, isize> {
+    let mut open_flags = OpenFlags::O_RDWR;
+    open_flags.set(OpenFlags::O_NONBLOCK, flags & SOCK_NONBLOCK != 0);
+    open_flags.set(OpenFlags::O_CLOEXEC, flags & SOCK_CLOEXEC != 0);
+    let task = current_task().unwrap();
+    let nofile = task.rlimit(RLIMIT_NOFILE);
+    let fd_table = task.fd_table();
+    let mut fd_table = fd_table.lock();
+    let fd = fd_table.alloc_fd(nofile).ok_or(EMFILE)?;
+    fd_table[fd] = Some(FdEntry::new(file, open_flags));
+    Ok(fd)
+}
+
+/// A new socket of `domain` and `sock_type`, the type without its flags
+fn new_socket(domain: i32, sock_type: i32, protocol: i32) -> Result<Arc<dyn File>, isize> {
+    let domain = u16::try_from(domain).map_err(|_| EAFNOSUPPORT)?;
+    match (domain, sock_type) {
+        (AF_UNIX, _) => {
+            if protocol != 0 {
+                return Err(EPROTONOSUPPORT);
+            }
+            let socket = UnixSocket::new(sock_type).ok_or(ESOCKTNOSUPPORT)?;
+            Ok(socket)
+        }
+        (AF_INET, SOCK_STREAM) => {
+            if protocol != 0 && protocol != IPPROTO_TCP as i32 {
+                return Err(EPROTONOSUPPORT);
+            }
+            Ok(TcpSocket::new())
+        }
+        (AF_INET, SOCK_DGRAM) => {
+            if protocol != 0 && protocol != IPPROTO_UDP as i32 {
+                return Err(EPROTONOSUPPORT);
+            }
+            Ok(UdpSocket::new())
+        }
+        (AF_INET, _) => Err(ESOCKTNOSUPPORT),
+        _ => Err(EAFNOSUPPORT),
+    }
+}
+
+/// Split the type argument of socket and socketpair into the type and the flags
+fn split_type(type_: i32) -> Result<(i32, i32), isize> {
+    let flags = type_ & !SOCK_TYPE_MASK;
+    if flags & !(SOCK_NONBLOCK | SOCK_CLOEXEC) != 0 {
+        return Err(EINVAL);
+    }
+    Ok((type_ & SOCK_TYPE_MASK, flags))
+}
+
+fn copy_from_user(buf: *const u8, len: usize) -> Vec<u8> {
+    unsafe {
+        sstatus::set_sum();
+        let data = core::slice::from_raw_parts(buf, len).to_vec();
+        sstatus::clear_sum();
+        data
+    }
+}
+
+fn copy_to_user(buf: *mut u8, data: &[u8]) {
+    unsafe {
+        sstatus::set_sum();
+        core::slice::from_raw_parts_mut(buf, data.len()).copy_from_slice(data);
+        sstatus::clear_sum();
+    }
+}
+
+/// Parse the `sockaddr` of `addrlen` bytes at `addr`
+fn read_sockaddr(addr: *const u8, addrlen: u32) -> Result<SockAddr, isize> {
+    if addr.is_null() {
+        return Err(EFAULT);
+    }
+    if addrlen as usize > SOCKADDR_MAX {
+        return Err(EINVAL);
+    }
+    SockAddr::from_bytes(&copy_from_user(addr, addrlen as usize))
+}
+
+/// Store `sockaddr` at `addr`, truncated to `*addrlen` bytes, and its full length in
+/// `*addrlen`; nothing if `addr` is null
+fn write_sockaddr(addr: *mut u8, addrlen: *mut u32, sockaddr: &SockAddr) {
+    if addr.is_null() || addrlen.is_null() {
+        return;
+    }
+    let bytes = sockaddr.to_bytes();
+    unsafe {
+        sstatus::set_sum();
+        let len = bytes.len().min(*addrlen as usize);
+        core::slice::from_raw_parts_mut(addr, len).copy_from_slice(&bytes[..len]);
+        *addrlen = bytes.len() as u32;
+        sstatus::clear_sum();
+    }
+}
+
+pub fn sys_socket(domain: i32, type_: i32, protocol: i32) -> isize {
+    trace!("kernel:pid[{}] sys_socket", current_task().unwrap().pid.0);
+    let result = split_type(type_).and_then(|(sock_type, flags)| {
+        let socket = new_socket(domain, sock_type, protocol)?;
+        install(socket, flags)
+    });
+    result.map_or_else(|errno| errno, |fd| fd as isize)
+}
+
+pub fn sys_socketpair(domain: i32, type_: i32, protocol: i32, sv: *mut i32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_socketpair",
+        current_task().unwrap().pid.0
+    );
+    let (sock_type, flags) = match split_type(type_) {
+        Ok(split) => split,
+        Err(errno) => return errno,
+    };
+    // 先按 socket 的规则检查参数，只有 UNIX 域的套接字能成对创建
+    if let Err(errno) = new_socket(domain, sock_type, protocol) {
+        return errno;
+    }
+    if domain != AF_UNIX as i32 {
+        return EOPNOTSUPP;
+    }
+    let (a, b) = UnixSocket::pair(sock_type).unwrap();
+    let fd_a = match install(a, flags) {
+        Ok(fd) => fd,
+        Err(errno) => return errno,
+    };
+    let fd_b = match install(b, flags) {
+        Ok(fd) => fd,
+        Err(errno) => {
+            current_task().unwrap().fd_table().lock()[fd_a] = None;
+            return errno;
+        }
+    };
+    let fds = [fd_a as i32, fd_b as i32];
+    unsafe {
+        sstatus::set_sum();
+        *sv = fds[0];
+        *sv.add(1) = fds[1];
+        sstatus::clear_sum();
+    }
+    0
+}
+
+pub fn sys_bind(fd: usize, addr: *const u8, addrlen: u32) -> isize {
+    trace!("kernel:pid[{}] sys_bind", current_task().unwrap().pid.0);
+    let result = fd_socket(fd).and_then(|socket| socket.bind(read_sockaddr(addr, addrlen)?));
+    result.map_or_else(|errno| errno, |_| 0)
+}
+
+pub fn sys_listen(fd: usize, backlog: i32) -> isize {
+    trace!("kernel:pid[{}] sys_listen", current_task().unwrap().pid.0);
+    let backlog = (backlog.max(0) as usize).min(SOMAXCONN);
+    let result = fd_socket(fd).and_then(|socket| socket.listen(backlog));
+    result.map_or_else(|errno| errno, |_| 0)
+}
+
+/// accept is accept4 with no flags
+pub fn sys_accept4(fd: usize, addr: *mut u8, addrlen: *mut u32, flags: i32) -> isize {
+    trace!("kernel:pid[{}] sys_accept4", current_task().unwrap().pid.0);
+    if flags & !(SOCK_NONBLOCK | SOCK_CLOEXEC) != 0 {
+        return EINVAL;
+    }
+    let socket = match fd_socket(fd) {
+        Ok(socket) => socket,
+        Err(errno) => return errno,
+    };
+    match socket.accept(socket.nonblock()) {
+        Ok((conn, peer)) => {
+            write_sockaddr(addr, addrlen, &peer);
+            install(conn, flags).map_or_else(|errno| errno, |fd| fd as isize)
+        }
+        Err(errno) => errno,
+    }
+}
+
+pub fn sys_connect(fd: usize, addr: *const u8, addrlen: u32) -> isize {
+    trace!("kernel:pid[{}] sys_connect", current_task().unwrap().pid.0);
+    let result = fd_socket(fd).and_then(|socket| {
+        let addr = read_sockaddr(addr, addrlen)?;
+        socket.connect(addr, socket.nonblock())
+    });
+    result.map_or_else(|errno| errno, |_| 0)
+}
+
+pub fn sys_getsockname(fd: usize, addr: *mut u8, addrlen: *mut u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getsockname",
+        current_task().unwrap().pid.0
+    );
+    match fd_socket(fd) {
+        Ok(socket) => {
+            write_sockaddr(addr, addrlen, &socket.local_addr());
+            0
+        }
+        Err(errno) => errno,
+    }
+}
+
+pub fn sys_getpeername(fd: usize, addr: *mut u8, addrlen: *mut u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getpeername",
+        current_task().unwrap().pid.0
+    );
+    match fd_socket(fd).and_then(|socket| socket.peer_addr()) {
+        Ok(peer) => {
+            write_sockaddr(addr, addrlen, &peer);
+            0
+        }
+        Err(errno) => errno,
+    }
+}
+
+/// Send `data` to the user address `addr`, or to the peer if it is null
+fn send(fd: usize, data: &[u8], flags: u32, addr: *const u8, addrlen: u32) -> isize {
+    let result = fd_socket(fd).and_then(|socket| {
+        let addr = (!addr.is_null())
+            .then(|| read_sockaddr(addr, addrlen))
+            .transpose()?;
+        let nonblock = socket.nonblock() || flags & MSG_DONTWAIT != 0;
+        socket.send_to(data, addr, nonblock)
+    });
+    result.map_or_else(|errno| errno, |len| len as isize)
+}
+
+pub fn sys_sendto(
+    fd: usize, buf: *const u8, len: usize, flags: u32, addr: *const u8, addrlen: u32,
+) -> isize {
+    trace!("kernel:pid[{}] sys_sendto", current_task().unwrap().pid.0);
+    if !user_range_ok(current_user_token(), buf as usize, len, false) {
+        return EFAULT;
+    }
+    send(fd, &copy_from_user(buf, len), flags, addr, addrlen)
+}
+
+pub fn sys_recvfrom(
+    fd: usize, buf: *mut u8, len: usize, flags: u32, addr: *mut u8, addrlen: *mut u32,
+) -> isize {
+    trace!("kernel:pid[{}] sys_recvfrom", current_task().unwrap().pid.0);
+    let socket = match fd_socket(fd) {
+        Ok(socket) => socket,
+        Err(errno) => return errno,
+    };
+    let nonblock = socket.nonblock() || flags & MSG_DONTWAIT != 0;
+    // 数据报比缓冲区长时和 Linux 一样截断
+    let len = len.min(RECV_MAX);
+    if !user_range_ok(current_user_token(), buf as usize, len, true) {
+        return EFAULT;
+    }
+    let mut data = vec![0u8; len];
+    match socket.recv_from(&mut data, nonblock) {
+        Ok((len, sender)) => {
+            copy_to_user(buf, &data[..len]);
+            if let Some(sender) = sender {
+                write_sockaddr(addr, addrlen, &sender);
+            }
+            len as isize
+        }
+        Err(errno) => errno,
+    }
+}
+
+/// The buffers of the `iovlen` iovecs at `iov`, EFAULT unless they are all mapped, and
+/// writable if `write`
+fn read_iovecs(iov: usize, iovlen: usize, write: bool) -> Result<Vec<Iovec>, isize> {
+    if iovlen > UIO_MAXIOV {
+        return Err(EINVAL);
+    }
+    let token = current_user_token();
+    if !user_range_ok(token, iov, iovlen * size_of::<Iovec>(), false) {
+        return Err(EFAULT);
+    }
+    let mut iovecs = Vec::with_capacity(iovlen);
+    unsafe {
+        sstatus::set_sum();
+        for i in 0..iovlen {
+            let iovec = &*(iov as *const Iovec).add(i);
+            iovecs.push(Iovec {
+                iov_base: iovec.iov_base,
+                iov_len:  iovec.iov_len,
+            });
+        }
+        sstatus::clear_sum();
+    }
+    let mapped = |iovec: &Iovec| user_range_ok(token, iovec.iov_base, iovec.iov_len, write);
+    if !iovecs.iter().all(mapped) {
+        return Err(EFAULT);
+    }
+    Ok(iovecs)
+}
+
+/// sendto with the address and the gathered buffers of `msg`, control messages are ignored
+pub fn sys_sendmsg(fd: usize, msg: *const MsgHdr, flags: u32) -> isize {
+    trace!("kernel:pid[{}] sys_sendmsg", current_task().unwrap().pid.0);
+    let token = current_user_token();
+    if !user_range_ok(token, msg as usize, size_of::<MsgHdr>(), false) {
+        return EFAULT;
+    }
+    let (name, namelen, iov, iovlen) = unsafe {
+        sstatus::set_sum();
+        let msg = &*msg;
+        let fields = (msg.name, msg.namelen, msg.iov, msg.iovlen);
+        sstatus::clear_sum();
+        fields
+    };
+    let iovecs = match read_iovecs(iov, iovlen, false) {
+        Ok(iovecs) => iovecs,
+        Err(errno) => return errno,
+    };
+    let mut data = Vec::new();
+    for iovec in iovecs {
+        data.extend(copy_from_user(iovec.iov_base as *const u8, iovec.iov_len));
+    }
+    send(fd, &data, flags, name as *const u8, namelen)
+}
+
+/// recvfrom into the scattered buffers of `msg`, no control messages are returned
+pub fn sys_recvmsg(fd: usize, msg: *mut MsgHdr, flags: u32) -> isize {
+    trace!("kernel:pid[{}] sys_recvmsg", current_task().unwrap().pid.0);
+    let socket = match fd_socket(fd) {
+        Ok(socket) => socket,
+        Err(errno) => return errno,
+    };
+    let token = current_user_token();
+    if !user_range_ok(token, msg as usize, size_of::<MsgHdr>(), true) {
+        return EFAULT;
+    }
+    let (name, iov, iovlen) = unsafe {
+        sstatus::set_sum();
+        let fields = ((*msg).name, (*msg).iov, (*msg).iovlen);
+        sstatus::clear_sum();
+        fields
+    };
+    let iovecs = match read_iovecs(iov, iovlen, true) {
+        Ok(iovecs) => iovecs,
+        Err(errno) => return errno,
+    };
+    let total = iovecs
+        .iter()
+        .try_fold(0usize, |total, iovec| total.checked_add(iovec.iov_len));
+    let Some(total) = total.filter(|&total| total <= isize::MAX as usize) else {
+        return EINVAL;
+    };
+    let nonblock = socket.nonblock() || flags & MSG_DONTWAIT != 0;
+    let mut data = vec![0u8; total.min(RECV_MAX)];
+    let (len, sender) = match socket.recv_from(&mut data, nonblock) {
+        Ok(received) => received,
+        Err(errno) => return errno,
+    };
+    let mut rest = &data[..len];
+    for iovec in iovecs {
+        let n = rest.len().min(iovec.iov_len);
+        copy_to_user(iovec.iov_base as *mut u8, &rest[..n]);
+        rest = &rest[n..];
+    }
+    unsafe {
+        if let Some(sender) = sender {
+            write_sockaddr(name as *mut u8, addr_of_mut!((*msg).namelen), &sender);
+        }
+        sstatus::set_sum();
+        (*msg).controllen = 0;
+        (*msg).flags = 0;
+        sstatus::clear_sum();
+    }
+    len as isize
+}
+
+pub fn sys_shutdown(fd: usize, how: i32) -> isize {
+    trace!("kernel:pid[{}] sys_shutdown", current_task().unwrap().pid.0);
+    let result = fd_socket(fd).and_then(|socket| socket.shutdown(how));
+    result.map_or_else(|errno| errno, |_| 0)
+}
+
+/// Options are accepted and ignored, the stack has nothing to tune
+pub fn sys_setsockopt(
+    fd: usize, level: i32, optname: i32, _optval: *const u8, _optlen: u32,
+) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_setsockopt",
+        current_task().unwrap().pid.0
+    );
+    if let Err(errno) = fd_socket(fd) {
+        return errno;
+    }
+    match (level, optname) {
+        (SOL_SOCKET, SO_REUSEADDR | SO_SNDBUF | SO_RCVBUF | SO_KEEPALIVE) => 0,
+        (SOL_TCP, TCP_NODELAY) => 0,
+        _ => ENOPROTOOPT,
+    }
+}
+
+pub fn sys_getsockopt(
+    fd: usize, level: i32, optname: i32, optval: *mut u8, optlen: *mut u32,
+) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getsockopt",
+        current_task().unwrap().pid.0
+    );
+    let socket = match fd_socket(fd) {
+        Ok(socket) => socket,
+        Err(errno) => return errno,
+    };
+    let value: i32 = match (level, optname) {
+        (SOL_SOCKET, SO_TYPE) => socket.sock_type(),
+        // SO_ERROR 取走待报告的错误，值是正的 errno
+        (SOL_SOCKET, SO_ERROR) => socket.sock_error().map_or(0, |errno| -errno as i32),
+        (SOL_SOCKET, SO_SNDBUF | SO_RCVBUF) => SOCK_BUF_REPORTED,
+        (SOL_SOCKET, SO_REUSEADDR | SO_KEEPALIVE) => 0,
+        (SOL_TCP, TCP_NODELAY) if socket.sock_type() == SOCK_STREAM => 1,
+        _ => return ENOPROTOOPT,
+    };
+    if optval.is_null() || optlen.is_null() {
+        return EFAULT;
+    }
+    let bytes = value.to_ne_bytes();
+    unsafe {
+        sstatus::set_sum();
+        let len = bytes.len().min(*optlen as usize);
+        core::slice::from_raw_parts_mut(optval, len).copy_from_slice(&bytes[..len]);
+        *optlen = len as u32;
+        sstatus::clear_sum();
+    }
+    0
+}
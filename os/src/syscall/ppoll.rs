@@ -49,7 +49,8 @@ bitflags! {
 ///     pthread_sigmask(SIG_SETMASK, &origmask, NULL);
 /// }`
 ///
-/// Timeout is not yet supported.
+/// 没有就绪的文件时在 [`wait_for_poll`] 中睡眠，被文件的通知或定期检查唤醒后重新检查，
+/// 直到超时。
 pub fn sys_ppoll(
     fds: *mut PollFd, nfds: usize, tmo_p: *const TimeSpec, sigmask: *const SignalFlags,
 ) -> isize {
@@ -72,17 +73,17 @@ pub fn sys_ppoll(
             true,
         );
     }
-    if tmo_p as usize != 0 {
-        println!("[sys_ppoll] Time limited maybe is needed!")
-    }
+    let deadline = (!tmo_p.is_null()).then(|| get_time() + translated_ref(token, tmo_p).to_tick());
     let mut done = 0;
     loop {
         let task = current_task().unwrap();
-        let inner = task.inner_exclusive_access(file!(), line!());
+        let fd_table = task.fd_table();
+        let fd_table = fd_table.lock();
         for i in 0..nfds {
             let poll_fd = unsafe { fds.add(i).as_mut() }.unwrap();
+            poll_fd.revents = PollEvent::empty();
             let fd = poll_fd.fd as usize;
-            match inner.fd_table[fd].as_ref() {
+            match fd_table[fd].as_ref().map(|entry| &entry.file) {
                 Some(file_descriptor) => {
                     let mut trigger = 0;
                     if file_descriptor.hang_up() {
@@ -102,13 +103,12 @@ pub fn sys_ppoll(
                 None => continue,
             }
         }
-        done += 1;
-        if done > 0 {
+        if done > 0 || deadline.map_or(false, |deadline| get_time() >= deadline) {
             break;
         }
-        drop(inner);
+        drop(fd_table);
         drop(task);
-        suspend_current_and_run_next();
+        wait_for_poll(deadline);
     }
 
     if !sigmask.is_null() {
@@ -132,6 +132,8 @@ use core::str::Bytes;
 
 use super::signal::sys_sigprocmask;
 use crate::{
+    fs::poll::wait_for_poll,
+    mm::translated_ref,
     syscall::errno::SUCCESS,
     task::{
         current_task,
@@ -140,8 +142,9 @@ use crate::{
         suspend_current_and_run_next,
         SignalFlags,
     },
-    timer::TimeSpec,
+    timer::{get_time, TimeSpec},
 };
+
 #[allow(unused)]
 impl FdSet {
     /// Return an empty bitmap for further manipulation
@@ -253,15 +256,16 @@ pub fn pselect(
     loop {
         // let token = current_user_token();
         let task = current_task().unwrap();
-        let inner = task.inner_exclusive_access(file!(), line!());
+        let fd_table = task.fd_table();
+        let fd_table = fd_table.lock();
         // check read
         if let Some(ref read_fds) = read_fds {
             for i in 0..nfds {
                 if !read_fds.is_set(i) {
                     continue;
                 }
-                if let Some(file) = &inner.fd_table[i] {
-                    if file.r_ready() {
+                if let Some(entry) = &fd_table[i] {
+                    if entry.file.r_ready() {
                         done += 1;
                     }
                 }
@@ -273,8 +277,8 @@ pub fn pselect(
                 if !write_fds.is_set(i) {
                     continue;
                 }
-                if let Some(fd) = &inner.fd_table[i] {
-                    if fd.w_ready() {
+                if let Some(entry) = &fd_table[i] {
+                    if entry.file.w_ready() {
                         done += 1;
                     }
                 }
@@ -292,21 +296,22 @@ pub fn pselect(
             }
         }
 
-        drop(inner);
+        drop(fd_table);
         drop(task);
         debug!("kernel: pselect suspend_current_and_run_next");
         suspend_current_and_run_next();
     }
     let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access(file!(), line!());
+    let fd_table = task.fd_table();
+    let fd_table = fd_table.lock();
     // count read
     if let Some(read_fds) = read_fds.as_mut() {
         for i in 0..nfds {
             if !read_fds.is_set(i) {
                 continue;
             }
-            if let Some(fd) = &inner.fd_table[i] {
-                if !fd.r_ready() {
+            if let Some(entry) = &fd_table[i] {
+                if !entry.file.r_ready() {
                     read_fds.clr(i);
                 }
             }
@@ -318,8 +323,8 @@ pub fn pselect(
             if !write_fds.is_set(i) {
                 continue;
             }
-            if let Some(fd) = &inner.fd_table[i] {
-                if !fd.w_ready() {
+            if let Some(entry) = &fd_table[i] {
+                if !entry.file.w_ready() {
                     write_fds.clr(i);
                 }
             }
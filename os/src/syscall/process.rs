@@ -1,28 +1,39 @@
-use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
 use core::{borrow::BorrowMut, mem::size_of, ptr};
 
 use riscv::register::{satp, sstatus};
 
 #[allow(unused)]
-use super::errno::{EINVAL, EPERM, SUCCESS};
+use super::errno::{EACCES, EINVAL, EPERM, SUCCESS};
 use crate::{
     config::*,
     fs::{defs::OpenFlags, dentry, open_file, ROOT_INODE},
-    mm::{translated_byte_buffer, translated_refmut, VirtAddr},
-    syscall::errno::{ECHILD, ENOENT, ESRCH},
+    mm::{lend_user_space, translated_byte_buffer, translated_refmut, translated_str, VirtAddr},
+    syscall::{
+        errno::{ECHILD, ENOEXEC, ENOMEM, ESRCH},
+        membarrier::MembarrierCmd,
+    },
     task::{
+        all_processes,
+        cred::MAY_EXEC,
         current_task,
         current_user_token,
         exit_current_and_run_next,
-        pid2process,
+        process::{MmapProt, MsyncFlags},
+        processes_in_group,
+        resource::RLIMIT_AS,
+        send_signal,
         suspend_current_and_run_next,
         CloneFlags,
         SignalFlags,
+        TaskControlBlock,
         TaskStatus,
         CSIGNAL,
+        INITPROC,
+        NICE_MAX,
+        NICE_MIN,
     },
-    timer::{get_time_ms, get_time_us},
-    trap,
+    timer::{get_time_ms, realtime},
     utils::string::c_ptr_to_string,
 };
 
@@ -126,27 +137,26 @@ pub fn sys_yield() -> isize {
 /// getpid syscall
 pub fn sys_getpid() -> isize {
     trace!("kernel: sys_getpid pid:{}", current_task().unwrap().pid.0);
-
-    (current_task().unwrap().pid.0) as isize
+    let task = current_task().unwrap();
+    // 线程组的 pid 是 leader 的 pid
+    task.pid_ns().pid_of(task.tid).unwrap() as isize
 }
 /// getppid syscall
 pub fn sys_getppid() -> isize {
     trace!("kernel: sys_getppid pid:{}", current_task().unwrap().pid.0);
-    if let Some(parent) = &current_task()
-        .unwrap()
-        .inner_exclusive_access(file!(), line!())
-        .parent
-    {
-        parent.upgrade().unwrap().pid.0 as isize
+    let task = current_task().unwrap();
+    let parent = task.inner_exclusive_access().parent.clone();
+    if let Some(parent) = parent {
+        // 父进程在调用者的 pid 命名空间之外（如命名空间的 init）时返回 0
+        let parent = parent.upgrade().unwrap();
+        task.pid_ns().pid_of(parent.pid.0).unwrap_or(0) as isize
     } else {
         warn!("kwenel: getppid NOT IMPLEMENTED YET!!");
         ESRCH
     }
 }
 /// fork child process syscall
-pub fn sys_clone(
-    flags: usize, stack_ptr: usize, ptid: *mut usize, tls: usize, ctid: *mut usize,
-) -> isize {
+pub fn sys_clone(flags: usize, stack_ptr: usize, ptid: usize, tls: usize, ctid: usize) -> isize {
     trace!(
         "[sys_clone] flags {:?} stack_ptr {:x?} ptid {:x?} tls {:x?} ctid {:x?}",
         flags,
@@ -157,7 +167,11 @@ pub fn sys_clone(
     );
     let current_task = current_task().unwrap();
 
-    let exit_signal = SignalFlags::from_bits(1 << ((flags & CSIGNAL) - 1)).unwrap();
+    // 线程通常不指定退出信号，此时低 8 位为 0
+    let exit_signal = match flags & CSIGNAL {
+        0 => SignalFlags::empty(),
+        signum => SignalFlags::from_bits(1 << (signum - 1)).unwrap(),
+    };
     let clone_signals = CloneFlags::from_bits((flags & !CSIGNAL) as u32).unwrap();
 
     trace!(
@@ -166,52 +180,30 @@ pub fn sys_clone(
         exit_signal,
         clone_signals,
         stack_ptr,
-        ptid as usize,
+        ptid,
         tls,
-        ctid as usize
+        ctid
     );
-    if !clone_signals.contains(CloneFlags::CLONE_THREAD) {
-        // assert!(stack_ptr == 0);
-        if stack_ptr == 0 {
-            return current_task.fork() as isize;
-        } else {
-            // return current_task.fork2(stack_ptr) as isize; //todo仅用于初赛
-            return current_task.fork() as isize; //todo
-        }
-    } else {
-        println!("[sys_clone] create thread");
-        let new_thread = current_task.clone2(exit_signal, clone_signals, stack_ptr, tls);
-
-        // The thread ID of the main thread needs to be the same as the Process ID,
-        // so we will exchange the thread whose thread ID is equal to Process ID with the thread whose thread ID is equal to 0,
-        // but the system will not exchange it internally
-        let process_pid = current_task.pid.0;
-        let mut new_thread_ttid = new_thread.gettid();
-        if new_thread_ttid == process_pid {
-            new_thread_ttid = 0;
-        }
-
-        if clone_signals.contains(CloneFlags::CLONE_PARENT_SETTID) && !ptid.is_null() {
-            unsafe {
-                sstatus::set_sum();
-                *ptid = new_thread_ttid;
-                sstatus::clear_sum();
-            };
-        }
-        if clone_signals.contains(CloneFlags::CLONE_CHILD_SETTID) && !ctid.is_null() {
-            unsafe {
-                sstatus::set_sum();
-                *ctid = new_thread_ttid;
-                sstatus::clear_sum();
-            };
+    // 共享信号处理函数要求共享地址空间，线程要求共享信号处理函数
+    if clone_signals.contains(CloneFlags::CLONE_SIGHAND)
+        && !clone_signals.contains(CloneFlags::CLONE_VM)
+        || clone_signals.contains(CloneFlags::CLONE_THREAD)
+            && !clone_signals.contains(CloneFlags::CLONE_SIGHAND)
+    {
+        return EINVAL;
+    }
+    let new_ns = CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWNS;
+    if clone_signals.intersects(new_ns) {
+        if clone_signals.contains(CloneFlags::CLONE_THREAD) {
+            return EINVAL;
         }
-        if clone_signals.contains(CloneFlags::CLONE_CHILD_CLEARTID) {
-            let mut thread_inner = new_thread.inner_exclusive_access(file!(), line!());
-            thread_inner.clear_child_tid = ctid as usize;
+        if !current_task.inner_exclusive_access().cred.is_root() {
+            return EPERM;
         }
-
-        new_thread_ttid as isize
     }
+    let child = current_task.clone_t(clone_signals, stack_ptr, exit_signal, ptid, tls, ctid);
+    // 返回子任务在调用者所在 pid 命名空间中的 tid
+    current_task.pid_ns().pid_of(child.pid.0).unwrap() as isize
 }
 /// exec syscall
 pub fn sys_execve(path: *const u8, mut args: *const usize, mut envp: *const usize) -> isize {
@@ -240,7 +232,7 @@ pub fn sys_execve(path: *const u8, mut args: *const usize, mut envp: *const usiz
             if unsafe { *env_str_ptr == 0 } {
                 break;
             }
-            envp_vec.push(c_ptr_to_string(env_str_ptr as *const u8));
+            envp_vec.push(c_ptr_to_string(unsafe { (*env_str_ptr) as *const u8 }));
             unsafe {
                 envp = envp.add(1);
             }
@@ -256,22 +248,36 @@ pub fn sys_execve(path: *const u8, mut args: *const usize, mut envp: *const usiz
         sstatus::clear_sum();
     }
     let task = current_task().unwrap();
-    let work_dir = task
-        .inner_exclusive_access(file!(), line!())
-        .work_dir
-        .clone();
-    if let Some(dentry) = open_file(work_dir.inode(), path.as_str(), OpenFlags::O_RDONLY) {
-        debug!("kernel: execve open app success : {}", path.as_str());
-        let inode = dentry.inode();
-        let all_data = inode.read_all();
-        debug!("kernel: execve read app success : {}", path.as_str());
-        let argc = args_vec.len();
-        task.exec(all_data.as_slice(), args_vec, envp_vec);
-        // return argc because cx.x[10] will be covered with it later
-        argc as isize
-    } else {
-        error!("kernel: execve open app error : {}", path.as_str());
-        ENOENT
+    let work_dir = task.inner_exclusive_access().work_dir.clone();
+    match open_file(&work_dir, path.as_str(), OpenFlags::O_RDONLY) {
+        Ok(dentry) => {
+            debug!("kernel: execve open app success : {}", path.as_str());
+            let inode = dentry.inode();
+            let perm = inode.perm();
+            if !task.inner_exclusive_access().cred.permits(perm, MAY_EXEC) {
+                return EACCES;
+            }
+            let all_data = inode.read_all();
+            debug!("kernel: execve read app success : {}", path.as_str());
+            let argc = args_vec.len();
+            if let Err(err) = task.exec(all_data.as_slice(), args_vec, envp_vec) {
+                return err;
+            }
+            let mut inner = task.inner_exclusive_access();
+            // 新程序不能替换或放宽 exec 前安装的过滤器
+            if let Some(filter) = inner.syscall_filter.as_mut() {
+                filter.lock();
+            }
+            inner.cred.apply_exec(perm);
+            inner.membarrier = MembarrierCmd::empty();
+            drop(inner);
+            // return argc because cx.x[10] will be covered with it later
+            argc as isize
+        }
+        Err(err) => {
+            error!("kernel: execve open app error : {}", path.as_str());
+            err
+        }
     }
 }
 
@@ -282,9 +288,19 @@ pub fn sys_execve(path: *const u8, mut args: *const usize, mut envp: *const usiz
 pub fn sys_wait4(pid: isize, exit_code_ptr: *mut i32, option: u32, _ru: usize) -> isize {
     trace!("kernel: sys_waitpid");
     let option = WaitOption::from_bits(option).unwrap();
+    // pid 是调用者所在 pid 命名空间中的编号，换成全局 pid 比较
+    let pid_ns = current_task().unwrap().pid_ns();
+    let pid = if pid > 0 {
+        match pid_ns.global_pid(pid as usize) {
+            Some(global) => global as isize,
+            None => return ECHILD,
+        }
+    } else {
+        pid
+    };
+    let task = current_task().unwrap();
     loop {
-        let task = current_task().unwrap();
-        let mut inner = task.inner_exclusive_access(file!(), line!());
+        let mut inner = task.inner_exclusive_access();
         if !inner
             .children
             .iter()
@@ -294,61 +310,220 @@ pub fn sys_wait4(pid: isize, exit_code_ptr: *mut i32, option: u32, _ru: usize) -
             return ECHILD;
         }
         let pair = inner.children.iter().enumerate().find(|(_, p)| {
-            p.inner_exclusive_access(file!(), line!()).is_zombie
-                && (pid == -1 || pid as usize == p.pid.0)
+            p.inner_exclusive_access().is_zombie && (pid == -1 || pid as usize == p.pid.0)
         });
-        if let Some((idx, _)) = pair {
+        let found = if let Some((idx, _)) = pair {
             let child = inner.children.remove(idx);
             // confirm that child will be deallocated after being removed from children list
             // assert_eq!(Arc::strong_count(&child), 2);
-            let found_pid = child.pid.0;
             // ++++ temporarily access child PCB exclusively
-            let exit_code = child
-                .inner_exclusive_access(file!(), line!())
-                .exit_code
-                .unwrap();
+            let exit_code = child.inner_exclusive_access().exit_code.unwrap();
             // ++++ release child PCB
+            Some((child, exit_code))
+        } else {
+            // 停止和继续的子进程不回收，按 Linux 的编码报告：(signo << 8) | 0x7f 和 0xffff
+            inner.children.iter().find_map(|p| {
+                if pid != -1 && pid as usize != p.pid.0 {
+                    return None;
+                }
+                let mut child_inner = p.inner_exclusive_access();
+                let keep = option.contains(WaitOption::WNOWAIT);
+                let status = match child_inner.stop_report {
+                    Some(signo) if option.contains(WaitOption::WUNTRACED) => {
+                        if !keep {
+                            child_inner.stop_report = None;
+                        }
+                        ((signo as i32) << 8) | 0x7f
+                    }
+                    _ if option.contains(WaitOption::WCONTINUED) && child_inner.continue_report => {
+                        if !keep {
+                            child_inner.continue_report = false;
+                        }
+                        0xffff
+                    }
+                    _ => return None,
+                };
+                Some((p.clone(), status))
+            })
+        };
+        if let Some((child, exit_code)) = found {
+            let found_pid = pid_ns.pid_of(child.pid.0).unwrap_or(0);
             if !exit_code_ptr.is_null() {
-                unsafe { sstatus::set_sum() };
                 debug!("kernel:sys_waitpid: exit_code_ptr is not null");
-                unsafe {
+                // 还持有自己的 inner，写用户内存时的缺页借用这里锁住的地址空间
+                let inner = &*inner;
+                lend_user_space(&mut inner.memory_set.lock(), &inner.cgroup, || unsafe {
+                    sstatus::set_sum();
                     *exit_code_ptr = exit_code;
-                }
-
-                unsafe { sstatus::clear_sum() };
+                    sstatus::clear_sum();
+                });
             }
             return found_pid as isize;
+        } else if option.contains(WaitOption::WNOHANG) {
+            return 0;
         } else {
-            // drop ProcessControlBlock and ProcessControlBlock to avoid mulit-use
-            drop(inner);
-            drop(task);
-            if option.contains(WaitOption::WNOHANG) {
-                return 0;
-            } else {
-                debug!("kernel:sys_waitpid: suspend_current_and_run_next");
-                suspend_current_and_run_next();
-                trap::wait_return();
-                //block_current_and_run_next();
-            }
+            // 持有自己的锁入队，子进程退出时先拿这把锁再唤醒，不会错过
+            debug!("kernel:sys_waitpid: sleep until a child exits");
+            task.child_exit.wait(inner);
         }
     }
-
-    // ---- release current PCB automatically
 }
 
 /// kill syscall
+///
+/// `pid` 为正数时发给该进程，为 0 时发给调用者所在的进程组，为 -1 时发给调用者能看到的
+/// 除 init 和自己以外的所有进程，小于 -1 时发给进程组 `-pid`。
 pub fn sys_kill(pid: usize, signal: u32) -> isize {
     trace!("kernel:pid[{}] sys_kill", current_task().unwrap().pid.0);
-    if let Some(process) = pid2process(pid) {
-        if let Some(flag) = SignalFlags::from_bits(signal as usize) {
-            process.inner_exclusive_access(file!(), line!()).signals |= flag;
-            0
-        } else {
-            EINVAL
+    let Some(flag) = SignalFlags::from_bits(signal as usize) else {
+        return EINVAL;
+    };
+    let task = current_task().unwrap();
+    let pid_ns = task.pid_ns();
+    let process = task.group_leader();
+    let ns_init = pid_ns.init();
+    let targets = match pid as isize {
+        pid if pid > 0 => pid_ns.find(pid as usize).into_iter().collect(),
+        0 => processes_in_group(process.inner_exclusive_access().pgid),
+        -1 => all_processes()
+            .into_iter()
+            .filter(|target| {
+                pid_ns.pid_of(target.pid.0).is_some()
+                    && !Arc::ptr_eq(target, &process)
+                    && !Arc::ptr_eq(target, &INITPROC)
+                    && !ns_init
+                        .as_ref()
+                        .map_or(false, |init| Arc::ptr_eq(target, init))
+            })
+            .collect(),
+        pgid => match pid_ns.global_pid(pgid.unsigned_abs()) {
+            Some(pgid) => processes_in_group(pgid),
+            None => Vec::new(),
+        },
+    };
+    if targets.is_empty() {
+        return ESRCH;
+    }
+    for target in targets {
+        send_signal(&target, flag);
+    }
+    0
+}
+
+/// Find the process `pid` of the caller's pid namespace, 0 for the caller itself
+fn find_process(pid: usize) -> Option<Arc<TaskControlBlock>> {
+    let task = current_task().unwrap();
+    if pid == 0 {
+        Some(task.group_leader())
+    } else {
+        task.pid_ns().find(pid)
+    }
+}
+
+/// setpgid syscall
+///
+/// 只能修改自己或自己的子进程，目标必须和调用者在同一个会话中且不是会话首进程；
+/// 加入已有的进程组时，该组必须在同一个会话中。
+pub fn sys_setpgid(pid: usize, pgid: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_setpgid",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    if (pgid as isize) < 0 {
+        return EINVAL;
+    }
+    let task = current_task().unwrap();
+    let pid_ns = task.pid_ns();
+    let process = task.group_leader();
+    let Some(target) = find_process(pid) else {
+        return ESRCH;
+    };
+    let sid = process.inner_exclusive_access().sid;
+    if !Arc::ptr_eq(&target, &process) {
+        let is_child = process
+            .inner_exclusive_access()
+            .children
+            .iter()
+            .any(|child| Arc::ptr_eq(child, &target));
+        if !is_child {
+            return ESRCH;
         }
+    }
+    let mut target_inner = target.inner_exclusive_access();
+    if target_inner.sid != sid || target_inner.sid == target.pid.0 {
+        return EPERM;
+    }
+    drop(target_inner);
+    let pgid = if pgid == 0 {
+        target.pid.0
     } else {
-        ESRCH
+        match pid_ns.global_pid(pgid) {
+            Some(pgid) => pgid,
+            None => return EPERM,
+        }
+    };
+    if pgid != target.pid.0
+        && !processes_in_group(pgid)
+            .iter()
+            .any(|member| member.inner_exclusive_access().sid == sid)
+    {
+        return EPERM;
     }
+    target_inner = target.inner_exclusive_access();
+    target_inner.pgid = pgid;
+    0
+}
+
+/// getpgid syscall
+pub fn sys_getpgid(pid: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_getpgid",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let Some(target) = find_process(pid) else {
+        return ESRCH;
+    };
+    let pgid = target.inner_exclusive_access().pgid;
+    // 进程组首进程在调用者的 pid 命名空间之外时返回 0
+    current_task().unwrap().pid_ns().pid_of(pgid).unwrap_or(0) as isize
+}
+
+/// setsid syscall
+///
+/// 调用者成为新会话和新进程组的首进程，已经是进程组首进程时返回 EPERM。
+pub fn sys_setsid() -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_setsid",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let task = current_task().unwrap();
+    let process = task.group_leader();
+    let pid = process.pid.0;
+    if !processes_in_group(pid).is_empty() {
+        return EPERM;
+    }
+    let mut inner = process.inner_exclusive_access();
+    inner.pgid = pid;
+    inner.sid = pid;
+    drop(inner);
+    task.pid_ns().pid_of(pid).unwrap() as isize
+}
+
+/// getsid syscall
+pub fn sys_getsid(pid: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_getsid",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let Some(target) = find_process(pid) else {
+        return ESRCH;
+    };
+    let sid = target.inner_exclusive_access().sid;
+    current_task().unwrap().pid_ns().pid_of(sid).unwrap_or(0) as isize
 }
 
 /// get_time syscall
@@ -358,10 +533,10 @@ pub fn sys_kill(pid: usize, signal: u32) -> isize {
 /// HINT: What if [`TimeVal`] is splitted by two pages ?
 pub fn sys_gettimeofday(ts: *mut TimeVal, _tz: usize) -> isize {
     trace!("kernel:pid[{}] sys_get_time", current_task().unwrap().pid.0);
-    let us = get_time_us();
+    let now = realtime();
     let new_ts = TimeVal {
-        sec:  us / 1_000_000,
-        usec: us % 1_000_000,
+        sec:  now.tv_sec,
+        usec: now.tv_nsec / 1_000,
     };
     unsafe {
         sstatus::set_sum();
@@ -378,12 +553,13 @@ pub fn sys_task_info(ti: *mut TaskInfo) -> isize {
         current_task().unwrap().pid.0
     );
     let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access(file!(), line!());
+    let inner = task.inner_exclusive_access();
     let ti_new = TaskInfo {
         status:        TaskStatus::Running,
         syscall_times: inner.syscall_times,
         time:          get_time_ms() - inner.first_time.unwrap(),
     };
+    drop(inner);
     unsafe {
         sstatus::set_sum();
         *ti = ti_new;
@@ -413,7 +589,12 @@ pub fn sys_mmap(
         return EINVAL;
     }
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
+    let as_limit = task.rlimit(RLIMIT_AS);
+    let mut inner = task.inner_exclusive_access();
+    let pages = (len - 1) / PAGE_SIZE + 1;
+    if !inner.memory_set.lock().fits_vm(pages, as_limit) {
+        return ENOMEM;
+    }
     inner.mmap(start, len, prot, flags, fd, off)
 }
 
@@ -422,67 +603,221 @@ pub fn sys_munmap(start: usize, len: usize) -> isize {
     trace!("kernel:pid[{}] sys_munmap", current_task().unwrap().pid.0);
     current_task()
         .unwrap()
-        .inner_exclusive_access(file!(), line!())
+        .inner_exclusive_access()
         .munmap(start, len)
 }
 
+/// mprotect syscall
+pub fn sys_mprotect(start: usize, len: usize, prot: usize) -> isize {
+    trace!("kernel:pid[{}] sys_mprotect", current_task().unwrap().pid.0);
+    let Some(prot) = MmapProt::from_bits(prot as u32) else {
+        return EINVAL;
+    };
+    current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .memory_set
+        .lock()
+        .mprotect(start, len, prot)
+}
+
+/// msync syscall, shared file mappings are written back synchronously whatever `flags` asks
+pub fn sys_msync(start: usize, len: usize, flags: usize) -> isize {
+    trace!("kernel:pid[{}] sys_msync", current_task().unwrap().pid.0);
+    let Some(flags) = MsyncFlags::from_bits(flags as u32) else {
+        return EINVAL;
+    };
+    if flags.contains(MsyncFlags::MS_ASYNC | MsyncFlags::MS_SYNC) {
+        return EINVAL;
+    }
+    current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .msync(start, len)
+}
+
 /// change data segment size
 pub fn sys_brk(addr: usize) -> isize {
     trace!("kernel:pid[{}] sys_brk", current_task().unwrap().pid.0);
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
+    let as_limit = task.rlimit(RLIMIT_AS);
+    let inner = task.inner_exclusive_access();
+    // 堆属于地址空间，同一进程的线程共用
+    let mut memory_set = inner.memory_set.lock();
     if addr == 0 {
-        inner.heap_end.0 as isize
-    } else if addr < inner.heap_base.0 {
+        memory_set.heap_end.0 as isize
+    } else if addr < memory_set.heap_base.0 {
         EINVAL
     } else {
         // We need to calculate to determine if we need a new page table
         // current end page address
         let align_addr = ((addr) + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
         // the end of 'addr' value
-        let align_end = ((inner.heap_end.0) + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
+        let align_end = ((memory_set.heap_end.0) + PAGE_SIZE - 1) & (!(PAGE_SIZE - 1));
         if align_end >= addr {
-            inner.heap_end = addr.into();
+            memory_set.heap_end = addr.into();
             align_addr as isize
         } else {
-            let heap_end = inner.heap_end;
+            if !memory_set.fits_vm((align_addr - align_end) / PAGE_SIZE, as_limit) {
+                return ENOMEM;
+            }
+            let heap_end = memory_set.heap_end;
             // map heap
-            inner.memory_set.map_heap(heap_end, align_addr.into());
-            inner.heap_end = align_addr.into();
+            let ret = memory_set.map_heap(heap_end, align_addr.into(), &inner.cgroup);
+            if ret < 0 {
+                // 超出 cgroup 的内存限额，堆顶不变
+                return ret;
+            }
+            memory_set.heap_end = align_addr.into();
             addr as isize
         }
     }
 }
 
 /// spawn syscall
-/// YOUR JOB: Implement spawn.
-/// HINT: fork + exec =/= spawn
-pub fn sys_spawn(_path: *const u8) -> isize {
+///
+/// 直接从 ELF 创建子进程，不像 fork + exec 那样先复制一遍父进程的地址空间。
+/// 子进程继承文件描述符表、工作目录和凭据，argv 只有 `path`，没有环境变量。
+/// 返回子进程在调用者 pid 命名空间中的 pid。
+pub fn sys_spawn(path: *const u8) -> isize {
     trace!("kernel:pid[{}] sys_spawn", current_task().unwrap().pid.0);
-    -1
-    // let token = current_user_token();
-    // let path = translated_str(token, path);
-    // if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
-    //     let task = current_task().unwrap();
-    //     let all_data = app_inode.read_all();
-    //     let new_task = task.spawn(all_data.as_slice());
-    //     let new_pid = new_task.pid.0;
-    //     add_task(new_task);
-    //     new_pid as isize
-    // } else {
-    //     -1
-    // }
-}
-
-/// set priority syscall
+    let task = current_task().unwrap();
+    let path = translated_str(current_user_token(), path);
+    let (work_dir, cred) = {
+        let inner = task.inner_exclusive_access();
+        (inner.work_dir.clone(), inner.cred.clone())
+    };
+    let dentry = match open_file(&work_dir, path.as_str(), OpenFlags::O_RDONLY) {
+        Ok(dentry) => dentry,
+        Err(err) => return err,
+    };
+    let inode = dentry.inode();
+    let perm = inode.perm();
+    if !cred.permits(perm, MAY_EXEC) {
+        return EACCES;
+    }
+    let all_data = inode.read_all();
+    if !all_data.starts_with(b"\x7fELF") {
+        return ENOEXEC;
+    }
+    let child = match task.spawn(all_data.as_slice(), vec![path], Vec::new(), perm) {
+        Ok(child) => child,
+        Err(err) => return err,
+    };
+    task.pid_ns().pid_of(child.pid.0).unwrap() as isize
+}
+
+/// setpriority/getpriority `which`: `who` is a pid
+pub const PRIO_PROCESS: usize = 0;
+/// setpriority/getpriority `which`: `who` is a process group id
+pub const PRIO_PGRP: usize = 1;
+/// setpriority/getpriority `which`: `who` is a real user id
+pub const PRIO_USER: usize = 2;
+
+/// Processes selected by `which` and `who`, `who` 为 0 时指调用者，`which` 不合法时返回 None
+fn priority_targets(which: usize, who: usize) -> Option<Vec<Arc<TaskControlBlock>>> {
+    let task = current_task().unwrap();
+    let pid_ns = task.pid_ns();
+    let targets = match which {
+        PRIO_PROCESS => find_process(who).into_iter().collect(),
+        PRIO_PGRP => {
+            let pgid = if who == 0 {
+                Some(task.group_leader().inner_exclusive_access().pgid)
+            } else {
+                pid_ns.global_pid(who)
+            };
+            pgid.map_or(Vec::new(), processes_in_group)
+        }
+        PRIO_USER => {
+            let uid = if who == 0 {
+                task.inner_exclusive_access().cred.uid
+            } else {
+                who as u32
+            };
+            all_processes()
+                .into_iter()
+                .filter(|process| {
+                    pid_ns.pid_of(process.pid.0).is_some()
+                        && process.inner_exclusive_access().cred.uid == uid
+                })
+                .collect()
+        }
+        _ => return None,
+    };
+    Some(targets)
+}
+
+/// setpriority syscall
 ///
-/// YOUR JOB: Set task priority
-pub fn sys_set_priority(prio: isize) -> isize {
+/// 超出 -20..=19 的 nice 值截断到范围内。只能修改属主是自己的进程，只有 root 能调低 nice。
+pub fn sys_set_priority(which: usize, who: usize, nice: isize) -> isize {
     trace!(
         "kernel:pid[{}] sys_set_priority",
         current_task().unwrap().pid.0
     );
-    0
+    let Some(targets) = priority_targets(which, who) else {
+        return EINVAL;
+    };
+    if targets.is_empty() {
+        return ESRCH;
+    }
+    let nice = nice.clamp(NICE_MIN, NICE_MAX);
+    let cred = current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .cred
+        .clone();
+    let mut ret = SUCCESS;
+    for target in targets {
+        let mut inner = target.inner_exclusive_access();
+        if !cred.is_root() && cred.euid != inner.cred.uid && cred.euid != inner.cred.euid {
+            ret = EPERM;
+        } else if !cred.is_root() && nice < inner.sched.nice {
+            ret = EACCES;
+        } else {
+            inner.sched.nice = nice;
+        }
+    }
+    ret
+}
+
+/// getpriority syscall
+///
+/// 与 Linux 的系统调用相同，返回 `20 - nice`（1..=40）以免和错误码混淆，由 libc 换算回 nice；
+/// 选中多个进程时返回其中最高的优先级。
+pub fn sys_get_priority(which: usize, who: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_get_priority",
+        current_task().unwrap().pid.0
+    );
+    let Some(targets) = priority_targets(which, who) else {
+        return EINVAL;
+    };
+    targets
+        .iter()
+        .map(|target| 20 - target.inner_exclusive_access().sched.nice)
+        .max()
+        .unwrap_or(ESRCH)
+}
+
+/// prctl option: turn syscall tracing of the calling task on (arg2 != 0) or off
+pub const PR_SET_SYSCALL_TRACE: usize = 0x5343_0001;
+/// prctl option: whether the calling task is being traced
+pub const PR_GET_SYSCALL_TRACE: usize = 0x5343_0002;
+
+/// prctl syscall, only the chaos specific options are supported
+pub fn sys_prctl(option: usize, arg2: usize) -> isize {
+    trace!("kernel:pid[{}] sys_prctl", current_task().unwrap().pid.0);
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    match option {
+        PR_SET_SYSCALL_TRACE => {
+            inner.syscall_trace = arg2 != 0;
+            SUCCESS
+        }
+        PR_GET_SYSCALL_TRACE => inner.syscall_trace as isize,
+        _ => EINVAL,
+    }
 }
 
 /// get current process times
@@ -491,11 +826,11 @@ pub fn sys_times(tms: *mut Tms) -> isize {
     trace!("kernel:pid[{}] sys_get_time", current_task().unwrap().pid.0);
     let (tms_stime, tms_utime) = current_task()
         .unwrap()
-        .inner_exclusive_access(file!(), line!())
+        .inner_exclusive_access()
         .get_process_clock_time();
     let (tms_cstime, tms_cutime) = current_task()
         .unwrap()
-        .inner_exclusive_access(file!(), line!())
+        .inner_exclusive_access()
         .get_children_process_clock_time();
     let mut sys_tms = Tms {
         tms_utime,
@@ -543,27 +878,3 @@ pub fn sys_uname(uts: *mut Utsname) -> isize {
     unsafe { sstatus::clear_sum() };
     0
 }
-
-/// 获取用户 id。在实现多用户权限前默认为最高权限。目前直接返回0。
-pub fn sys_getuid() -> isize {
-    trace!("kernel:pid[{}] sys_getuid", current_task().unwrap().pid.0);
-    0
-}
-
-/// 获取有效用户 id，即相当于哪个用户的权限。在实现多用户权限前默认为最高权限。目前直接返回0。
-pub fn sys_geteuid() -> isize {
-    trace!("kernel:pid[{}] sys_geteuid", current_task().unwrap().pid.0);
-    0
-}
-
-/// 获取用户组 id。在实现多用户权限前默认为最高权限。目前直接返回0。
-pub fn sys_getgid() -> isize {
-    trace!("kernel:pid[{}] sys_getgid", current_task().unwrap().pid.0);
-    0
-}
-
-/// 获取有效用户组 id，即相当于哪个用户组的权限。在实现多用户组权限前默认为最高权限。目前直接返回0。
-pub fn sys_getegid() -> isize {
-    trace!("kernel:pid[{}] sys_getegid", current_task().unwrap().pid.0);
-    0
-}
@@ -0,0 +1,48 @@
+//! getrandom(2)
+//!
+//! 熵池从启动起就可以使用，GRND_RANDOM 和 GRND_NONBLOCK 都不会改变结果，也从不阻塞。
+
+use riscv::register::sstatus;
+
+use super::errno::EINVAL;
+use crate::{drivers::random, task::current_task};
+
+bitflags! {
+    /// flags of getrandom
+    pub struct GetRandomFlags: u32 {
+        const GRND_NONBLOCK = 1 << 0;
+        const GRND_RANDOM   = 1 << 1;
+        const GRND_INSECURE = 1 << 2;
+    }
+}
+
+/// Most bytes returned by one call, as in Linux
+const GETRANDOM_MAX: usize = (1 << 25) - 1;
+/// Bytes generated before each copy to the user buffer
+const CHUNK_SIZE: usize = 256;
+
+/// getrandom syscall, return the number of bytes written to `buf`
+pub fn sys_getrandom(buf: *mut u8, buflen: usize, flags: u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getrandom",
+        current_task().unwrap().pid.0
+    );
+    let Some(flags) = GetRandomFlags::from_bits(flags) else {
+        return EINVAL;
+    };
+    if flags.contains(GetRandomFlags::GRND_RANDOM | GetRandomFlags::GRND_INSECURE) {
+        return EINVAL;
+    }
+    let len = buflen.min(GETRANDOM_MAX);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    for start in (0..len).step_by(CHUNK_SIZE) {
+        let n = CHUNK_SIZE.min(len - start);
+        random::fill(&mut chunk[..n]);
+        unsafe {
+            sstatus::set_sum();
+            core::slice::from_raw_parts_mut(buf.add(start), n).copy_from_slice(&chunk[..n]);
+            sstatus::clear_sum();
+        }
+    }
+    len as isize
+}
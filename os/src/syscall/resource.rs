@@ -0,0 +1,68 @@
+//! Resource limit syscalls, see [`crate::task::resource`]
+
+use super::errno::{EINVAL, EPERM, ESRCH, SUCCESS};
+use crate::{
+    mm::{translated_ref, translated_refmut},
+    task::{current_task, current_user_token, resource::RLimit},
+};
+
+/// prlimit64 syscall
+///
+/// `pid` 为 0 时是调用者所在的进程。修改别的进程的限制要求调用者是 root 或者 euid
+/// 与目标的 uid 或 euid 相同，和 setpriority 一样。`old_limit` 不为空时返回修改前的值。
+pub fn sys_prlimit64(
+    pid: usize, resource: usize, new_limit: *const RLimit, old_limit: *mut RLimit,
+) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_prlimit64 resource:{}",
+        current_task().unwrap().pid.0,
+        resource
+    );
+    let task = current_task().unwrap();
+    let target = if pid == 0 {
+        task.group_leader()
+    } else {
+        match task.pid_ns().find(pid) {
+            Some(target) => target,
+            None => return ESRCH,
+        }
+    };
+    let cred = task.inner_exclusive_access().cred.clone();
+    let token = current_user_token();
+    let new_limit = (!new_limit.is_null()).then(|| *translated_ref(token, new_limit));
+    let old = {
+        let mut inner = target.inner_exclusive_access();
+        let Some(old) = inner.rlimits.get(resource) else {
+            return EINVAL;
+        };
+        if let Some(limit) = new_limit {
+            if !cred.is_root() && cred.euid != inner.cred.uid && cred.euid != inner.cred.euid {
+                return EPERM;
+            }
+            if let Err(errno) = inner.rlimits.set(resource, limit, cred.is_root()) {
+                return errno;
+            }
+        }
+        old
+    };
+    if !old_limit.is_null() {
+        *translated_refmut(token, old_limit) = old;
+    }
+    SUCCESS
+}
+
+pub fn sys_getrlimit(resource: usize, rlim: *mut RLimit) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getrlimit",
+        current_task().unwrap().pid.0
+    );
+    sys_prlimit64(0, resource, core::ptr::null(), rlim)
+}
+
+pub fn sys_setrlimit(resource: usize, rlim: *const RLimit) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_setrlimit",
+        current_task().unwrap().pid.0
+    );
+    sys_prlimit64(0, resource, rlim, core::ptr::null_mut())
+}
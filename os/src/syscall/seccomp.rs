@@ -0,0 +1,84 @@
+use core::mem::size_of;
+
+use super::{
+    errno::{EFAULT, EINVAL, EPERM, SUCCESS},
+    SYSCALL_EXIT,
+    SYSCALL_EXIT_GROUP,
+    SYSCALL_READ,
+    SYSCALL_SIGRETURN,
+    SYSCALL_WRITE,
+};
+use crate::{
+    mm::{translated_byte_buffer, user_range_ok},
+    task::{
+        current_task,
+        current_user_token,
+        seccomp::{SeccompList, SyscallFilter},
+    },
+};
+
+/// only read, write, exit and sigreturn, like Linux strict mode
+pub const SECCOMP_SET_MODE_STRICT: usize = 0;
+/// BPF filters, not supported
+pub const SECCOMP_SET_MODE_FILTER: usize = 1;
+/// install the allow/deny list pointed to by `args`, see [`SeccompList`]
+pub const SECCOMP_SET_MODE_LIST: usize = 0x5343_0001;
+
+/// Copy the list at `ptr` from user space, `None` if it is not mapped
+///
+/// 按字节拷贝，结构体跨页时也能读全。
+fn read_list(ptr: *const SeccompList) -> Option<SeccompList> {
+    let token = current_user_token();
+    if !user_range_ok(token, ptr as usize, size_of::<SeccompList>(), false) {
+        return None;
+    }
+    let mut bytes = [0u8; size_of::<SeccompList>()];
+    let mut copied = 0;
+    for buf in translated_byte_buffer(token, ptr as *const u8, size_of::<SeccompList>()) {
+        bytes[copied..copied + buf.len()].copy_from_slice(buf);
+        copied += buf.len();
+    }
+    Some(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const SeccompList) })
+}
+
+/// seccomp syscall
+///
+/// A filter can be replaced until the task execs, after that it is locked.
+/// exit and exit_group always stay allowed.
+pub fn sys_seccomp(operation: usize, flags: usize, args: *const SeccompList) -> isize {
+    trace!("kernel:pid[{}] sys_seccomp", current_task().unwrap().pid.0);
+    if flags != 0 {
+        return EINVAL;
+    }
+    let mut filter = match operation {
+        SECCOMP_SET_MODE_STRICT => SyscallFilter::strict(&[
+            SYSCALL_READ,
+            SYSCALL_WRITE,
+            SYSCALL_EXIT,
+            SYSCALL_SIGRETURN,
+        ]),
+        SECCOMP_SET_MODE_LIST => {
+            let Some(list) = read_list(args) else {
+                return EFAULT;
+            };
+            match SyscallFilter::from_list(&list) {
+                Some(filter) => filter,
+                None => return EINVAL,
+            }
+        }
+        _ => return EINVAL,
+    };
+    filter.allow(SYSCALL_EXIT);
+    filter.allow(SYSCALL_EXIT_GROUP);
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if inner
+        .syscall_filter
+        .as_ref()
+        .map_or(false, |filter| filter.is_locked())
+    {
+        return EPERM;
+    }
+    inner.syscall_filter = Some(filter);
+    SUCCESS
+}
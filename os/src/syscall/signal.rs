@@ -1,18 +1,24 @@
 use riscv::register::{sscratch, sstatus};
 
 use crate::{
+    config::CLOCK_FREQ,
     mm::{translated_ref, translated_refmut},
     syscall::errno::{EAGAIN, EPERM, SUCCESS},
     task::{
         current_task,
+        current_user_token,
+        exit_current_and_run_next,
+        restore_signal_frame,
         sigaction::SignalAction,
         signal::{SigInfo, MAX_SIG, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK},
-        suspend_current_and_run_next,
         SignalFlags,
     },
-    timer::TimeSpec,
+    timer::{get_time, sleep_until, TimeSpec},
 };
 
+/// How long sigtimedwait sleeps between checks for the signals
+const SIGNAL_POLL_MS: usize = 10;
+
 /// 一个系统调用，用于获取和设置信号的屏蔽位。通过 `sigprocmask`，进程可以方便的屏蔽某些信号。
 ///
 /// 参数：
@@ -33,9 +39,8 @@ pub fn sys_sigprocmask(
         current_task().unwrap().tid
     );
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
-
-    let mut mask = inner.signal_mask;
+    // 访问用户内存时可能缺页，不能持有 inner
+    let mut mask = task.inner_exclusive_access().signal_mask;
 
     if kernel_space {
         if old_set as usize != 0 {
@@ -76,7 +81,7 @@ pub fn sys_sigprocmask(
             SIG_SETMASK => mask = set_flags,
             _ => return EPERM,
         }
-        inner.signal_mask = mask;
+        task.inner_exclusive_access().signal_mask = mask;
     }
     SUCCESS
 }
@@ -101,14 +106,16 @@ pub fn sys_sigaction(
         current_task().unwrap().tid
     );
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access(file!(), line!());
+    // 访问用户内存时可能缺页，不能持有 inner
+    let signal_actions = task.inner_exclusive_access().signal_actions.clone();
+    let mut signal_actions = signal_actions.lock();
     if signum > MAX_SIG {
         error!("[sys_sigaction] error signum");
         return EPERM;
     }
     if old_action as usize != 0 {
         unsafe { sstatus::set_sum() };
-        unsafe { *old_action = inner.signal_actions.table[signum].clone() };
+        unsafe { *old_action = signal_actions.table[signum].clone() };
         unsafe { sstatus::clear_sum() };
     }
     if let Some(flag) = SignalFlags::from_bits(1 << (signum - 1)) {
@@ -116,7 +123,7 @@ pub fn sys_sigaction(
             error!("[sys_sigaction] check_sigaction_error");
             return EPERM;
         }
-        let old_kernel_action = inner.signal_actions.table[signum];
+        let old_kernel_action = signal_actions.table[signum];
         if old_action as usize != 0 {
             if old_kernel_action.mask != SignalFlags::from_bits(40).unwrap() {
                 unsafe { sstatus::set_sum() };
@@ -132,7 +139,7 @@ pub fn sys_sigaction(
         if action as usize != 0 {
             unsafe { sstatus::set_sum() };
             let ref_action = unsafe { &*action };
-            inner.signal_actions.table[signum as usize] = *ref_action;
+            signal_actions.table[signum as usize] = *ref_action;
             unsafe { sstatus::clear_sum() };
         }
         return SUCCESS;
@@ -150,69 +157,67 @@ fn check_sigaction_error(signal: SignalFlags) -> bool {
     }
 }
 
-// The timedwait used in the libtest is different from the linux manual page
+/// rt_sigtimedwait syscall
+///
+/// 等待 `uthese` 中的某个信号到来，取走它并返回信号编号；`uts` 指定的时间内没有等到返回
+/// EAGAIN，`uts` 为空时一直等。信号到来时不会唤醒等待者，每隔 [`SIGNAL_POLL_MS`] 醒来检查一次。
 pub fn sys_sigtimedwait(
     uthese: *mut usize,
     info: *mut SigInfo,
     uts: *const TimeSpec,
     // I find sigsetsize in Linux 5.2 source code, but I dont know how to use it.
-    sigsetsize: usize,
+    _sigsetsize: usize,
 ) -> isize {
     trace!(
         "kernel:pid[{}] tid[{}] sys_sigtimedwait",
         current_task().unwrap().pid.0,
         current_task().unwrap().tid
     );
+    let token = current_user_token();
+    let set = SignalFlags::from_bits_truncate(*translated_ref(token, uthese));
+    let deadline = (!uts.is_null()).then(|| get_time() + translated_ref(token, uts).to_tick());
+    loop {
+        let task = current_task().unwrap();
+        let mut inner = task.inner_exclusive_access();
+        let matched = inner.signals & set;
+        if !matched.is_empty() {
+            let signo = matched.bits().trailing_zeros() as usize + 1;
+            inner
+                .signals
+                .remove(SignalFlags::from_bits_truncate(1 << (signo - 1)));
+            drop(inner);
+            if !info.is_null() {
+                *translated_refmut(token, info) = SigInfo::new(signo, 0, 0);
+            }
+            return signo as isize;
+        }
+        drop(inner);
+        drop(task);
+        let now = get_time();
+        if deadline.map_or(false, |deadline| now >= deadline) {
+            return EAGAIN;
+        }
+        let next_check = now + SIGNAL_POLL_MS * CLOCK_FREQ / 1000;
+        sleep_until(deadline.map_or(next_check, |deadline| deadline.min(next_check)));
+    }
+}
 
-    // if uthese as usize == 0 || uts as usize == 0 {
-    //     error!("[sys_sigtimedwait] Null pointer.");
-    //     return EPERM;
-    // }
-    // let mut timeout: TimeSpec = TimeSpec::now();
-    // unsafe {
-    //     sstatus::set_sum();
-    //     timeout = *uts;
-    //     sstatus::clear_sum();
-    // }
-
-    // let limit_time = TimeSpec::now() + timeout;
-
-    // let mut set = 0;
-    // unsafe {
-    //     sstatus::set_sum();
-    //     set = *uthese;
-    //     sstatus::clear_sum();
-    // }
-
-    // let set_flags = SignalFlags::from_bits(set).unwrap();
-
-    // loop {
-    //     let task = current_task().unwrap();
-    //     let signals_pending = task
-    //         .inner_exclusive_access(file!(), line!())
-    //         .signals_pending;
-    //     // Every matched signals will return. This method is wrong.
-    //     let match_signals = set_flags & signals_pending;
-    //     if !match_signals.is_empty() {
-    //         let first_signals = match_signals.bits().trailing_zeros();
-    //         if info as usize != 0 {
-    //             let siginfo = SigInfo::new(first_signals as usize, 0, 0);
-    //             unsafe {
-    //                 sstatus::set_sum();
-    //                 *info = siginfo;
-    //                 sstatus::clear_sum();
-    //             }
-    //         }
-    //         return SUCCESS;
-    //     }
-    //     if limit_time < TimeSpec::now() {
-    //         println!("[sys_sigtimedwait] Timeout.");
-    //         return EAGAIN;
-    //     }
-    //     drop(task);
-    //     drop(signals_pending);
-    //     debug!("sigtimedwait: suspend_current_and_run_next");
-    //     suspend_current_and_run_next();
-    // }
-    SUCCESS
+/// rt_sigreturn syscall
+///
+/// 信号处理函数返回后经由 `sa_restorer` 或内核的 sigreturn 跳板调用，此时用户栈指针仍指向
+/// 信号帧。恢复信号帧里保存的中断上下文和信号屏蔽字，返回值就是被打断时的 a0。
+pub fn sys_sigreturn() -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_sigreturn",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    match restore_signal_frame() {
+        Some(a0) => a0 as isize,
+        None => {
+            // 信号帧被破坏，按 SIGSEGV 终止
+            exit_current_and_run_next(-11);
+            unreachable!()
+        }
+    }
 }
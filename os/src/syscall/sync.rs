@@ -1,352 +1,272 @@
-use riscv::register::sstatus;
+use alloc::{sync::Arc, vec::Vec};
 
+use super::errno::{EDEADLK, EFAULT, EINVAL, ENOSYS};
 use crate::{
-    boards::CLOCK_FREQ,
-    task::{current_task, suspend_current_and_run_next},
-    timer::{get_time, NSEC_PER_SEC},
+    mm::{translated_ref, translated_user_pa},
+    sync::{
+        deadlock::{DeadlockDetector, Resource},
+        futex::{futex_requeue, futex_wait, futex_wake},
+        mutex::{Mutex, MutexBlocking, MutexSpin},
+        Condvar,
+        Semaphore,
+    },
+    task::{current_pid, current_task, current_user_token},
+    timer::TimeSpec,
 };
-/// sleep syscall
-pub fn sys_sleep(time_req: *const u64, time_remain: *mut u64) -> isize {
+
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+const FUTEX_REQUEUE: usize = 3;
+/// futexes are keyed by physical address, private ones are no different
+const FUTEX_PRIVATE_FLAG: usize = 128;
+const FUTEX_CLOCK_REALTIME: usize = 256;
+
+/// futex syscall, FUTEX_WAIT, FUTEX_WAKE and FUTEX_REQUEUE
+///
+/// `timeout` is a relative `timespec` for FUTEX_WAIT and the number of waiters to move for
+/// FUTEX_REQUEUE.
+pub fn sys_futex(
+    uaddr: usize, op: usize, val: usize, timeout: usize, uaddr2: usize, _val3: usize,
+) -> isize {
     trace!(
-        "kernel:pid[{}] tid[{}] sys_sleep",
+        "kernel:pid[{}] tid[{}] sys_futex",
         current_task().unwrap().pid.0,
         current_task().unwrap().tid
     );
-    #[inline]
-    fn is_end(end_time: usize) -> bool {
-        let current_time = get_time();
-        current_time >= end_time
+    if uaddr % 4 != 0 {
+        return EINVAL;
     }
-    unsafe {
-        sstatus::set_sum();
-        let sec = *time_req;
-        let nano_sec = *time_req.add(1);
-        sstatus::clear_sum();
-        let end_time =
-            get_time() + sec as usize * CLOCK_FREQ + nano_sec as usize * CLOCK_FREQ / NSEC_PER_SEC;
-
-        loop {
-            if is_end(end_time) {
-                break;
-            } else {
-                debug!("kernel: sleep suspend_current_and_run_next");
-                suspend_current_and_run_next()
+    let token = current_user_token();
+    let Some(pa) = translated_user_pa(token, uaddr, true) else {
+        return EFAULT;
+    };
+    match op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) {
+        FUTEX_WAIT => {
+            let timeout =
+                (timeout != 0).then(|| translated_ref(token, timeout as *const TimeSpec).to_tick());
+            futex_wait(pa, val as u32, timeout)
+        }
+        FUTEX_WAKE => futex_wake(pa, val) as isize,
+        FUTEX_REQUEUE => {
+            if uaddr2 % 4 != 0 {
+                return EINVAL;
             }
+            let Some(pa2) = translated_user_pa(token, uaddr2, true) else {
+                return EFAULT;
+            };
+            futex_requeue(pa, val, pa2, timeout) as isize
         }
+        _ => ENOSYS,
+    }
+}
 
-        sstatus::set_sum();
-        if time_remain as usize != 0 {
-            *time_remain = 0;
-            *time_remain.add(1) = 0;
-        }
-        sstatus::clear_sum();
+/// Put `item` in the first free slot of `list`, returning its id
+fn alloc_id<T>(list: &mut Vec<Option<T>>, item: T) -> usize {
+    if let Some(id) = list.iter().position(Option::is_none) {
+        list[id] = Some(item);
+        id
+    } else {
+        list.push(Some(item));
+        list.len() - 1
+    }
+}
+
+/// Run `f` on the deadlock detector of the current thread group
+fn with_deadlock<R>(f: impl FnOnce(&mut DeadlockDetector) -> R) -> R {
+    let leader = current_task().unwrap().group_leader();
+    let mut inner = leader.inner_exclusive_access();
+    f(&mut inner.deadlock)
+}
+
+/// mutex create syscall
+///
+/// 同步对象放在线程组 leader 的 TCB 里，同组线程用同一套 id。
+pub fn sys_mutex_create(blocking: bool) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_mutex_create",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let mutex: Arc<dyn Mutex> = if blocking {
+        Arc::new(MutexBlocking::new())
+    } else {
+        Arc::new(MutexSpin::new())
+    };
+    let leader = current_task().unwrap().group_leader();
+    let mut inner = leader.inner_exclusive_access();
+    let id = alloc_id(&mut inner.mutex_list, mutex);
+    inner.deadlock.add_resource(Resource::Mutex(id), 1);
+    id as isize
+}
+
+/// The mutex `mutex_id` of the current thread group
+fn get_mutex(mutex_id: usize) -> Option<Arc<dyn Mutex>> {
+    let leader = current_task().unwrap().group_leader();
+    let inner = leader.inner_exclusive_access();
+    inner.mutex_list.get(mutex_id).cloned().flatten()
+}
+
+/// mutex lock syscall
+pub fn sys_mutex_lock(mutex_id: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_mutex_lock",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let Some(mutex) = get_mutex(mutex_id) else {
+        return EINVAL;
+    };
+    let tid = current_pid().unwrap();
+    let res = Resource::Mutex(mutex_id);
+    if !with_deadlock(|deadlock| deadlock.request(tid, res)) {
+        return EDEADLK;
     }
+    mutex.lock();
+    with_deadlock(|deadlock| deadlock.acquire(tid, res));
     0
 }
 
-// /// mutex create syscall
-// pub fn sys_mutex_create(blocking: bool) -> isize {
-//     trace!(
-//         "kernel:pid[{}] tid[{}] sys_mutex_create",
-//         current_task().unwrap().process.upgrade().unwrap().getpid(),
-//         current_task()
-//             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
-//             .res
-//             .as_ref()
-//             .unwrap()
-//             .tid
-//     );
-//     let process = current_process();
-//     let mutex: Option<Arc<dyn MutexSupport>> = if !blocking {
-//         Some(Arc::new(SpinNoIrqLock::new()))
-//     } else {
-//         Some(Arc::new(SpinNoIrqLock::new()))
-//     };
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     if let Some(id) = process_inner
-//         .mutex_list
-//         .iter()
-//         .enumerate()
-//         .find(|(_, item)| item.is_none())
-//         .map(|(id, _)| id)
-//     {
-//         process_inner.mutex_list[id] = mutex;
-//         process_inner.available[id] = 1;
-//         for task in &mut process_inner.allocation {
-//             task[id] = 0;
-//         }
-//         for task in &mut process_inner.need {
-//             task[id] = 0;
-//         }
-//         id as isize
-//     } else {
-//         process_inner.mutex_list.push(mutex);
-//         process_inner.available.push(1);
-//         for task in &mut process_inner.allocation {
-//             task.push(0);
-//         }
-//         for task in &mut process_inner.need {
-//             task.push(0);
-//         }
-//         process_inner.mutex_list.len() as isize - 1
-//     }
-// }
+/// mutex unlock syscall
+pub fn sys_mutex_unlock(mutex_id: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_mutex_unlock",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let Some(mutex) = get_mutex(mutex_id) else {
+        return EINVAL;
+    };
+    mutex.unlock();
+    let tid = current_pid().unwrap();
+    with_deadlock(|deadlock| deadlock.release(tid, Resource::Mutex(mutex_id)));
+    0
+}
 
-// /// mutex lock syscall
-// pub fn sys_mutex_lock(mutex_id: usize) -> isize {
-//     trace!(
-//         "kernel:pid[{}] tid[{}] sys_mutex_lock",
-//         current_task().unwrap().process.upgrade().unwrap().getpid(),
-//         current_task()
-//             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
-//             .res
-//             .as_ref()
-//             .unwrap()
-//             .tid
-//     );
-//     let process = current_process();
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     let mutex = Arc::clone(process_inner.mutex_list[mutex_id].as_ref().unwrap());
-//     let tid = current_task().unwrap().inner_exclusive_access(file!(), line!()).res.as_ref().unwrap().tid;
-//     process_inner.need[tid][mutex_id] += 1;
-//     let deadlock_detect = process_inner.deadlock_detect;
-//     drop(process_inner);
-//     drop(process);
-//     if deadlock_detect && detect_deadlock() {
-//         return -0xdead;
-//     }
-//     mutex.lock();
-//     let process = current_process();
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     process_inner.available[mutex_id] -= 1;
-//     let tid = current_task().unwrap().inner_exclusive_access(file!(), line!()).res.as_ref().unwrap().tid;
-//     process_inner.allocation[tid][mutex_id] += 1;
-//     process_inner.need[tid][mutex_id] -= 1;
-//     0
-// }
+/// semaphore create syscall
+pub fn sys_semaphore_create(res_count: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_semaphore_create",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let leader = current_task().unwrap().group_leader();
+    let mut inner = leader.inner_exclusive_access();
+    let id = alloc_id(
+        &mut inner.semaphore_list,
+        Arc::new(Semaphore::new(res_count)),
+    );
+    inner
+        .deadlock
+        .add_resource(Resource::Semaphore(id), res_count);
+    id as isize
+}
 
-// /// mutex unlock syscall
-// pub fn sys_mutex_unlock(mutex_id: usize) -> isize {
-//     trace!(
-//         "kernel:pid[{}] tid[{}] sys_mutex_unlock",
-//         current_task().unwrap().process.upgrade().unwrap().getpid(),
-//         current_task()
-//             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
-//             .res
-//             .as_ref()
-//             .unwrap()
-//             .tid
-//     );
-//     let process = current_process();
-//     let process_inner = process.inner_exclusive_access(file!(), line!());
-//     let mutex = Arc::clone(process_inner.mutex_list[mutex_id].as_ref().unwrap());
-//     drop(process_inner);
-//     drop(process);
-//     mutex.unlock();
-//     let process = current_process();
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     process_inner.available[mutex_id] += 1;
-//     let tid = current_task().unwrap().inner_exclusive_access(file!(), line!()).res.as_ref().unwrap().tid;
-//     process_inner.allocation[tid][mutex_id] -= 1;
-//     0
-// }
+/// The semaphore `sem_id` of the current thread group
+fn get_semaphore(sem_id: usize) -> Option<Arc<Semaphore>> {
+    let leader = current_task().unwrap().group_leader();
+    let inner = leader.inner_exclusive_access();
+    inner.semaphore_list.get(sem_id).cloned().flatten()
+}
 
-// /// semaphore create syscall
-// pub fn sys_semaphore_create(res_count: usize) -> isize {
-//     trace!(
-//         "kernel:pid[{}] tid[{}] sys_semaphore_create",
-//         current_task().unwrap().process.upgrade().unwrap().getpid(),
-//         current_task()
-//             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
-//             .res
-//             .as_ref()
-//             .unwrap()
-//             .tid
-//     );
-//     let process = current_process();
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     let id = if let Some(id) = process_inner
-//         .semaphore_list
-//         .iter()
-//         .enumerate()
-//         .find(|(_, item)| item.is_none())
-//         .map(|(id, _)| id)
-//     {
-//         process_inner.semaphore_list[id] = Some(Arc::new(Semaphore::new(res_count)));
-//         process_inner.available[id] = res_count as u32;
-//         for task in &mut process_inner.allocation {
-//             task[id] = 0;
-//         }
-//         for task in &mut process_inner.need {
-//             task[id] = 0;
-//         }
-//         id
-//     } else {
-//         process_inner
-//             .semaphore_list
-//             .push(Some(Arc::new(Semaphore::new(res_count))));
-//         process_inner.available.push(res_count as u32);
-//         for task in &mut process_inner.allocation {
-//             task.push(0);
-//         }
-//         for task in &mut process_inner.need {
-//             task.push(0);
-//         }
-//         process_inner.semaphore_list.len() - 1
-//     };
-//     id as isize
-// }
+/// semaphore up syscall
+pub fn sys_semaphore_up(sem_id: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_semaphore_up",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let Some(sem) = get_semaphore(sem_id) else {
+        return EINVAL;
+    };
+    sem.up();
+    let tid = current_pid().unwrap();
+    with_deadlock(|deadlock| deadlock.release(tid, Resource::Semaphore(sem_id)));
+    0
+}
 
-// /// semaphore up syscall
-// pub fn sys_semaphore_up(sem_id: usize) -> isize {
-//     trace!(
-//         "kernel:pid[{}] tid[{}] sys_semaphore_up",
-//         current_task().unwrap().process.upgrade().unwrap().getpid(),
-//         current_task()
-//             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
-//             .res
-//             .as_ref()
-//             .unwrap()
-//             .tid
-//     );
-//     let process = current_process();
-//     let process_inner = process.inner_exclusive_access(file!(), line!());
-//     let sem = Arc::clone(process_inner.semaphore_list[sem_id].as_ref().unwrap());
-//     drop(process_inner);
-//     drop(process);
-//     sem.up();
-//     let process = current_process();
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     process_inner.available[sem_id] += 1;
-//     let tid = current_task().unwrap().inner_exclusive_access(file!(), line!()).res.as_ref().unwrap().tid;
-//     process_inner.allocation[tid][sem_id] -= 1;
-//     0
-// }
+/// semaphore down syscall
+pub fn sys_semaphore_down(sem_id: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_semaphore_down",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let Some(sem) = get_semaphore(sem_id) else {
+        return EINVAL;
+    };
+    let tid = current_pid().unwrap();
+    let res = Resource::Semaphore(sem_id);
+    if !with_deadlock(|deadlock| deadlock.request(tid, res)) {
+        return EDEADLK;
+    }
+    sem.down();
+    with_deadlock(|deadlock| deadlock.acquire(tid, res));
+    0
+}
 
-// /// semaphore down syscall
-// pub fn sys_semaphore_down(sem_id: usize) -> isize {
-//     trace!(
-//         "kernel:pid[{}] tid[{}] sys_semaphore_down",
-//         current_task().unwrap().process.upgrade().unwrap().getpid(),
-//         current_task()
-//             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
-//             .res
-//             .as_ref()
-//             .unwrap()
-//             .tid
-//     );
-//     let process = current_process();
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     let sem = Arc::clone(process_inner.semaphore_list[sem_id].as_ref().unwrap());
-//     let tid = current_task().unwrap().inner_exclusive_access(file!(), line!()).res.as_ref().unwrap().tid;
-//     process_inner.need[tid][sem_id] += 1;
-//     let deadlock_detect = process_inner.deadlock_detect;
-//     drop(process_inner);
-//     drop(process);
-//     if deadlock_detect && detect_deadlock() {
-//         return -0xdead;
-//     }
-//     sem.down();
-//     let process = current_process();
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     process_inner.available[sem_id] -= 1;
-//     let tid = current_task().unwrap().inner_exclusive_access(file!(), line!()).res.as_ref().unwrap().tid;
-//     process_inner.allocation[tid][sem_id] += 1;
-//     process_inner.need[tid][sem_id] -= 1;
-//     0
-// }
+/// condvar create syscall
+pub fn sys_condvar_create() -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_condvar_create",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let leader = current_task().unwrap().group_leader();
+    let mut inner = leader.inner_exclusive_access();
+    alloc_id(&mut inner.condvar_list, Arc::new(Condvar::new())) as isize
+}
 
-// /// condvar create syscall
-// pub fn sys_condvar_create() -> isize {
-//     trace!(
-//         "kernel:pid[{}] tid[{}] sys_condvar_create",
-//         current_task().unwrap().process.upgrade().unwrap().getpid(),
-//         current_task()
-//             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
-//             .res
-//             .as_ref()
-//             .unwrap()
-//             .tid
-//     );
-//     let process = current_process();
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     let id = if let Some(id) = process_inner
-//         .condvar_list
-//         .iter()
-//         .enumerate()
-//         .find(|(_, item)| item.is_none())
-//         .map(|(id, _)| id)
-//     {
-//         process_inner.condvar_list[id] = Some(Arc::new(Condvar::new()));
-//         id
-//     } else {
-//         process_inner
-//             .condvar_list
-//             .push(Some(Arc::new(Condvar::new())));
-//         process_inner.condvar_list.len() - 1
-//     };
-//     id as isize
-// }
+/// The condition variable `condvar_id` of the current thread group
+fn get_condvar(condvar_id: usize) -> Option<Arc<Condvar>> {
+    let leader = current_task().unwrap().group_leader();
+    let inner = leader.inner_exclusive_access();
+    inner.condvar_list.get(condvar_id).cloned().flatten()
+}
 
-// /// condvar signal syscall
-// pub fn sys_condvar_signal(condvar_id: usize) -> isize {
-//     trace!(
-//         "kernel:pid[{}] tid[{}] sys_condvar_signal",
-//         current_task().unwrap().process.upgrade().unwrap().getpid(),
-//         current_task()
-//             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
-//             .res
-//             .as_ref()
-//             .unwrap()
-//             .tid
-//     );
-//     let process = current_process();
-//     let process_inner = process.inner_exclusive_access(file!(), line!());
-//     let condvar = Arc::clone(process_inner.condvar_list[condvar_id].as_ref().unwrap());
-//     drop(process_inner);
-//     condvar.signal();
-//     0
-// }
+/// condvar signal syscall
+pub fn sys_condvar_signal(condvar_id: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_condvar_signal",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let Some(condvar) = get_condvar(condvar_id) else {
+        return EINVAL;
+    };
+    condvar.signal();
+    0
+}
 
-// /// condvar wait syscall
-// pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> isize {
-//     trace!(
-//         "kernel:pid[{}] tid[{}] sys_condvar_wait",
-//         current_task().unwrap().process.upgrade().unwrap().getpid(),
-//         current_task()
-//             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
-//             .res
-//             .as_ref()
-//             .unwrap()
-//             .tid
-//     );
-//     let process = current_process();
-//     let process_inner = process.inner_exclusive_access(file!(), line!());
-//     let condvar = Arc::clone(process_inner.condvar_list[condvar_id].as_ref().unwrap());
-//     let mutex = Arc::clone(process_inner.mutex_list[mutex_id].as_ref().unwrap());
-//     drop(process_inner);
-//     condvar.wait(mutex);
-//     0
-// }
+/// condvar wait syscall
+pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_condvar_wait",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let (Some(condvar), Some(mutex)) = (get_condvar(condvar_id), get_mutex(mutex_id)) else {
+        return EINVAL;
+    };
+    let tid = current_pid().unwrap();
+    let res = Resource::Mutex(mutex_id);
+    with_deadlock(|deadlock| deadlock.release(tid, res));
+    condvar.wait(mutex);
+    with_deadlock(|deadlock| deadlock.acquire(tid, res));
+    0
+}
 
-///// enable deadlock detection syscall
-// //
-// pub fn sys_enable_deadlock_detect(enabled: usize) -> isize {
-//     trace!("kernel: sys_enable_deadlock_detect");
-//     if enabled != 0 && enabled != 1 {
-//         return -1;
-//     }
-//     let process = current_process();
-//     let mut process_inner = process.inner_exclusive_access(file!(), line!());
-//     process_inner.deadlock_detect = enabled == 1;
-//     0
-// }
+/// enable deadlock detection syscall
+///
+/// 开启后 sys_mutex_lock 和 sys_semaphore_down 在可能造成死锁时返回 EDEADLK，见 [`DeadlockDetector`]。
+pub fn sys_enable_deadlock_detect(enabled: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_enable_deadlock_detect",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    if enabled > 1 {
+        return EINVAL;
+    }
+    with_deadlock(|deadlock| deadlock.enabled = enabled == 1);
+    0
+}
@@ -0,0 +1,148 @@
+//! syslog(2): read and control the kernel log buffer
+
+use alloc::vec;
+
+use log::LevelFilter;
+use riscv::register::sstatus;
+use spin::Mutex;
+
+use crate::{
+    logging::{
+        console_level,
+        console_level_to_filter,
+        kmsg::{KMSG, KMSG_READERS, LOG_BUF_LEN},
+        set_console_level,
+    },
+    mm::user_range_ok,
+    syscall::errno::{EFAULT, EINVAL, EPERM, SUCCESS},
+    task::{current_task, current_user_token},
+};
+
+/// Close the log. Currently a NOP
+pub const SYSLOG_ACTION_CLOSE: usize = 0;
+/// Open the log. Currently a NOP
+pub const SYSLOG_ACTION_OPEN: usize = 1;
+/// Read from the log, blocks until the log is nonempty
+pub const SYSLOG_ACTION_READ: usize = 2;
+/// Read all messages remaining in the ring buffer
+pub const SYSLOG_ACTION_READ_ALL: usize = 3;
+/// Read and clear all messages remaining in the ring buffer
+pub const SYSLOG_ACTION_READ_CLEAR: usize = 4;
+/// Clear ring buffer
+pub const SYSLOG_ACTION_CLEAR: usize = 5;
+/// Disable printk to console
+pub const SYSLOG_ACTION_CONSOLE_OFF: usize = 6;
+/// Enable printk to console
+pub const SYSLOG_ACTION_CONSOLE_ON: usize = 7;
+/// Set level of messages printed to console
+pub const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+/// Return number of unread characters in the log buffer
+pub const SYSLOG_ACTION_SIZE_UNREAD: usize = 9;
+/// Return size of the log buffer
+pub const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+
+/// console log level saved by `SYSLOG_ACTION_CONSOLE_OFF`
+static SAVED_LEVEL: Mutex<Option<LevelFilter>> = Mutex::new(None);
+
+/// Copy `len` bytes read by `f` from the log buffer to user `buf`, EFAULT if `buf` is
+/// not mapped writable
+fn copy_to_user(buf: *mut u8, len: usize, f: impl FnOnce(&mut [u8]) -> usize) -> isize {
+    let len = len.min(LOG_BUF_LEN);
+    if !user_range_ok(current_user_token(), buf as usize, len, true) {
+        return EFAULT;
+    }
+    let mut kbuf = vec![0u8; len];
+    let read = f(&mut kbuf);
+    unsafe {
+        sstatus::set_sum();
+        core::slice::from_raw_parts_mut(buf, read).copy_from_slice(&kbuf[..read]);
+        sstatus::clear_sum();
+    }
+    read as isize
+}
+
+/// Destructive read from the log into `buf`, blocking until the log is nonempty
+///
+/// `/proc/kmsg` 的读也走这里，和 `SYSLOG_ACTION_READ` 共用一个读位置。
+pub fn read_kmsg(buf: &mut [u8]) -> usize {
+    loop {
+        let mut kmsg = KMSG.lock();
+        let read = kmsg.read(buf);
+        if read > 0 || buf.is_empty() {
+            return read;
+        }
+        KMSG_READERS.wait(kmsg);
+    }
+}
+
+pub fn sys_syslog(log_type: usize, buf: *mut u8, len: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_syslog type:{}",
+        current_task().unwrap().pid.0,
+        log_type
+    );
+    let privileged = matches!(
+        log_type,
+        SYSLOG_ACTION_READ_CLEAR
+            | SYSLOG_ACTION_CLEAR
+            | SYSLOG_ACTION_CONSOLE_OFF
+            | SYSLOG_ACTION_CONSOLE_ON
+            | SYSLOG_ACTION_CONSOLE_LEVEL
+    );
+    // 清空日志和修改 console 输出只有 root 可以做
+    let task = current_task().unwrap();
+    if privileged && !task.inner_exclusive_access().cred.is_root() {
+        return EPERM;
+    }
+    match log_type {
+        SYSLOG_ACTION_CLOSE | SYSLOG_ACTION_OPEN => SUCCESS,
+        SYSLOG_ACTION_READ | SYSLOG_ACTION_READ_ALL | SYSLOG_ACTION_READ_CLEAR
+            if buf.is_null() =>
+        {
+            EINVAL
+        }
+        SYSLOG_ACTION_READ => {
+            if len == 0 {
+                return SUCCESS;
+            }
+            copy_to_user(buf, len, read_kmsg)
+        }
+        SYSLOG_ACTION_READ_ALL => copy_to_user(buf, len, |kbuf| KMSG.lock().read_all(kbuf)),
+        SYSLOG_ACTION_READ_CLEAR => copy_to_user(buf, len, |kbuf| {
+            let mut kmsg = KMSG.lock();
+            let read = kmsg.read_all(kbuf);
+            kmsg.clear();
+            read
+        }),
+        SYSLOG_ACTION_CLEAR => {
+            KMSG.lock().clear();
+            SUCCESS
+        }
+        SYSLOG_ACTION_CONSOLE_OFF => {
+            let mut saved = SAVED_LEVEL.lock();
+            if saved.is_none() {
+                *saved = Some(console_level());
+            }
+            set_console_level(LevelFilter::Off);
+            SUCCESS
+        }
+        SYSLOG_ACTION_CONSOLE_ON => {
+            if let Some(level) = SAVED_LEVEL.lock().take() {
+                set_console_level(level);
+            }
+            SUCCESS
+        }
+        SYSLOG_ACTION_CONSOLE_LEVEL => {
+            if !(1..=8).contains(&len) {
+                return EINVAL;
+            }
+            // syslog(2) 中 len 参数即为新的 console loglevel
+            SAVED_LEVEL.lock().take();
+            set_console_level(console_level_to_filter(len));
+            SUCCESS
+        }
+        SYSLOG_ACTION_SIZE_UNREAD => KMSG.lock().unread() as isize,
+        SYSLOG_ACTION_SIZE_BUFFER => LOG_BUF_LEN as isize,
+        _ => EINVAL,
+    }
+}
@@ -18,14 +18,14 @@ pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
     // // create a new thread
     // let new_task = Arc::new(task.clone2(
     //     Arc::clone(&process),
-    //     task.inner_exclusive_access(file!(), line!()).user_stack_top,
+    //     task.inner_exclusive_access().user_stack_top,
     //     0,
     //     true,
     // ));
     // // add new task to scheduler
     // add_task(Arc::clone(&new_task));
     // let new_task_tid = new_task.tid;
-    // let mut process_inner = process.inner_exclusive_access(file!(), line!());
+    // let mut process_inner = process.inner_exclusive_access();
     // // add new thread to current process
     // let tasks = &mut process_inner.tasks;
     // while tasks.len() < new_task_tid + 1 {
@@ -40,7 +40,7 @@ pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
     // let new_task_trap_cx = new_task.get_trap_cx();
     // *new_task_trap_cx = TrapContext::app_init_context(
     //     entry,
-    //     new_task.inner_exclusive_access(file!(), line!()).user_stack_top,
+    //     new_task.inner_exclusive_access().user_stack_top,
     //     kernel_token(),
     //     new_task.kstack.get_top(),
     //     trap_handler as usize,
@@ -56,7 +56,8 @@ pub fn sys_gettid() -> isize {
         current_task().unwrap().pid.0,
         current_task().unwrap().tid
     );
-    current_task().unwrap().tid as isize
+    let task = current_task().unwrap();
+    task.pid_ns().pid_of(task.pid.0).unwrap_or(task.pid.0) as isize
 }
 
 /// wait for a thread to exit syscall
@@ -71,7 +72,7 @@ pub fn sys_waittid(tid: usize) -> i32 {
         current_task().unwrap().tid
     );
     // let task = current_task().unwrap();
-    // let task_inner = task.inner_exclusive_access(file!(), line!());
+    // let task_inner = task.inner_exclusive_access();
     // // a thread cannot wait for itself
     // if task.tid == tid {
     //     return -1;
@@ -79,8 +80,8 @@ pub fn sys_waittid(tid: usize) -> i32 {
     // let mut exit_code: Option<i32> = None;
     // let waited_task = task_inner.tasks[tid].as_ref();
     // if let Some(waited_task) = waited_task {
-    //     if let Some(waited_exit_code) = waited_task.inner_exclusive_access(file!(), line!()).exit_code {
-    //         exit_code = waited_task.inner_exclusive_access(file!(), line!()).exit_code;
+    //     if let Some(waited_exit_code) = waited_task.inner_exclusive_access().exit_code {
+    //         exit_code = waited_task.inner_exclusive_access().exit_code;
     //     }
     // } else {
     //     // waited thread does not exist
@@ -104,7 +105,7 @@ pub fn sys_set_tid_address(tidptr: usize) -> isize {
         current_task().unwrap().tid
     );
     let task = current_task().unwrap();
-    let mut task_inner = task.inner_exclusive_access(file!(), line!());
+    let mut task_inner = task.inner_exclusive_access();
     task_inner.clear_child_tid = tidptr;
-    task.get_tid() as isize
+    task.pid_ns().pid_of(task.pid.0).unwrap_or(task.pid.0) as isize
 }
@@ -1,38 +1,207 @@
-use riscv::register::sstatus;
+use alloc::{sync::Arc, vec::Vec};
 
+use super::errno::{EINVAL, EPERM};
 use crate::{
-    task::current_task,
-    timer::{ClockId, TimeSpec},
+    mm::{translated_ref, translated_refmut},
+    task::{current_task, current_user_token},
+    timer::{
+        get_time,
+        realtime,
+        realtime_to_tick,
+        set_real_timer,
+        set_realtime,
+        sleep_until,
+        ClockId,
+        ITimerVal,
+        TimeSpec,
+        TimerType,
+        NSEC_PER_SEC,
+        USEC_PER_SEC,
+    },
 };
 
-pub fn sys_clock_gettime(clock_id: usize, timespec: *mut TimeSpec) -> isize {
+/// flag of clock_nanosleep: `req` is an absolute time of the clock
+const TIMER_ABSTIME: usize = 1;
+
+/// nanosleep syscall, a relative sleep on CLOCK_MONOTONIC
+pub fn sys_nanosleep(req: *const TimeSpec, rem: *mut TimeSpec) -> isize {
     trace!(
-        "kernel:pid[{}] tid[{}] sys_clock_gettime",
+        "kernel:pid[{}] tid[{}] sys_nanosleep",
         current_task().unwrap().pid.0,
         current_task().unwrap().tid
     );
+    clock_sleep(ClockId::Monotonic, 0, req, rem)
+}
 
+/// clock_nanosleep syscall
+pub fn sys_clock_nanosleep(
+    clock_id: usize, flags: usize, req: *const TimeSpec, rem: *mut TimeSpec,
+) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_clock_nanosleep",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
     match ClockId::from(clock_id) {
-        ClockId::Monotonic | ClockId::Realtime | ClockId::ProcessCputimeId => {
-            let time = TimeSpec::now();
-            unsafe { *timespec = time };
-        }
-        _ => {
-            panic!("clock_get_time: clock_id {:?} not supported", clock_id);
-        }
+        Some(clock) => clock_sleep(clock, flags, req, rem),
+        None => EINVAL,
     }
-    let time = TimeSpec::now();
-    if timespec as usize != 0 {
-        unsafe {
-            sstatus::set_sum();
-        }
-        debug!("timespec: {:#x?}", timespec);
-        unsafe {
-            *timespec = time;
-        }
-        unsafe {
-            sstatus::clear_sum();
+}
+
+/// Sleep on `clock` until `req`, or for `req` without TIMER_ABSTIME
+///
+/// 注册一个定时器后阻塞，由时钟中断里的 `check_timer` 唤醒。只能按单调时钟或墙上时钟睡眠，
+/// 绝对时间已经过去时立即返回。目前没有信号能打断睡眠，剩余时间总是 0。
+fn clock_sleep(clock: ClockId, flags: usize, req: *const TimeSpec, rem: *mut TimeSpec) -> isize {
+    let token = current_user_token();
+    let req = *translated_ref(token, req);
+    if req.tv_nsec >= NSEC_PER_SEC {
+        return EINVAL;
+    }
+    let absolute = flags & TIMER_ABSTIME != 0;
+    let expire = if !clock.is_realtime() && !clock.is_monotonic() {
+        return EINVAL;
+    } else if !absolute {
+        get_time() + req.to_tick()
+    } else if clock.is_realtime() {
+        realtime_to_tick(req)
+    } else {
+        req.to_tick()
+    };
+    while get_time() < expire {
+        sleep_until(expire);
+    }
+    if !absolute && !rem.is_null() {
+        *translated_refmut(token, rem) = TimeSpec::new();
+    }
+    0
+}
+
+/// getitimer syscall
+///
+/// 只支持 ITIMER_REAL，计时器属于整个进程，保存在线程组 leader 上。
+pub fn sys_getitimer(which: usize, curr_value: *mut ITimerVal) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_getitimer",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    if which != TimerType::REAL as usize {
+        return EINVAL;
+    }
+    let process = current_task().unwrap().group_leader();
+    let value = process
+        .inner_exclusive_access()
+        .real_timer
+        .value(get_time());
+    *translated_refmut(current_user_token(), curr_value) = value;
+    0
+}
+
+/// setitimer syscall
+///
+/// 到期时由时钟中断里的 `check_timer` 给进程发 SIGALRM，`it_interval` 非零时按周期重新设置。
+pub fn sys_setitimer(
+    which: usize, new_value: *const ITimerVal, old_value: *mut ITimerVal,
+) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_setitimer",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    if which != TimerType::REAL as usize {
+        return EINVAL;
+    }
+    let token = current_user_token();
+    let new = *translated_ref(token, new_value);
+    if new.it_value.tv_usec >= USEC_PER_SEC || new.it_interval.tv_usec >= USEC_PER_SEC {
+        return EINVAL;
+    }
+    let old = set_real_timer(&current_task().unwrap().group_leader(), new);
+    if !old_value.is_null() {
+        *translated_refmut(token, old_value) = old;
+    }
+    0
+}
+
+/// Current time of `clock`, `None` for the alarm clocks
+///
+/// 单调时钟从启动开始计时，墙上时钟在它上面加上 clock_settime 设置的偏移，不区分闰秒。
+/// CPU 时间来自陷入和切换任务时记下的用户态、内核态时间。
+fn clock_now(clock: ClockId) -> Option<TimeSpec> {
+    match clock {
+        ClockId::ProcessCputimeId => Some(TimeSpec::from_tick(cpu_time(true))),
+        ClockId::ThreadCputimeId => Some(TimeSpec::from_tick(cpu_time(false))),
+        _ if clock.is_realtime() => Some(realtime()),
+        _ if clock.is_monotonic() => Some(TimeSpec::now()),
+        _ => None,
+    }
+}
+
+/// Clock ticks the current thread, or all threads of its process, ran in user and kernel mode
+///
+/// 当前线程的时间先结算到现在，其他线程只算到它们上次陷入或被切换出去时。
+fn cpu_time(process: bool) -> usize {
+    let task = current_task().unwrap();
+    let (kernel, user) = task.inner_exclusive_access().get_process_clock_time();
+    let mut ticks = (kernel + user) as usize;
+    if process {
+        let leader = task.group_leader();
+        let mut others: Vec<_> = leader
+            .inner_exclusive_access()
+            .threads
+            .iter()
+            .flatten()
+            .cloned()
+            .collect();
+        others.push(leader);
+        for other in others.iter().filter(|other| !Arc::ptr_eq(other, &task)) {
+            let inner = other.inner_exclusive_access();
+            ticks += inner.kernel_clock + inner.user_clock;
         }
     }
+    ticks
+}
+
+pub fn sys_clock_gettime(clock_id: usize, timespec: *mut TimeSpec) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_clock_gettime",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    let Some(time) = ClockId::from(clock_id).and_then(clock_now) else {
+        return EINVAL;
+    };
+    if !timespec.is_null() {
+        *translated_refmut(current_user_token(), timespec) = time;
+    }
+    0
+}
+
+/// clock_settime syscall, only root can set CLOCK_REALTIME and the other clocks can not be set
+pub fn sys_clock_settime(clock_id: usize, timespec: *const TimeSpec) -> isize {
+    trace!(
+        "kernel:pid[{}] tid[{}] sys_clock_settime",
+        current_task().unwrap().pid.0,
+        current_task().unwrap().tid
+    );
+    if ClockId::from(clock_id) != Some(ClockId::Realtime) {
+        return EINVAL;
+    }
+    let time = *translated_ref(current_user_token(), timespec);
+    if time.tv_nsec >= NSEC_PER_SEC {
+        return EINVAL;
+    }
+    if !current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .cred
+        .is_root()
+    {
+        return EPERM;
+    }
+    if !set_realtime(time) {
+        return EINVAL;
+    }
     0
 }
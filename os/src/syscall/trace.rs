@@ -0,0 +1,279 @@
+//! Per-task syscall tracing
+//!
+//! 对设置了 `syscall_trace` 的任务，每个系统调用以类似 strace 的格式
+//! `[pid tid] name(args) = ret` 记录到内核日志环形缓冲区，用 dmesg 查看。
+//! 通过 `prctl(PR_SET_SYSCALL_TRACE, 1)` 开启，exec 后保留，fork 不继承。
+
+use alloc::string::String;
+use core::fmt::Write;
+
+use super::{errno::Errno, *};
+use crate::{
+    logging::kmsg,
+    mm::{PageTable, PhysAddr, VirtAddr},
+    task::current_user_token,
+};
+
+/// syslog level of trace lines (KERN_DEBUG)
+const TRACE_LEVEL: u8 = 7;
+/// longest string argument printed, the rest is elided
+const MAX_STR_LEN: usize = 48;
+
+/// How an argument is printed
+#[derive(Clone, Copy)]
+enum Arg {
+    /// signed decimal (fds, dirfds, pids)
+    Int,
+    /// unsigned decimal (sizes, counts)
+    Uint,
+    /// hex (pointers, flags)
+    Hex,
+    /// NUL-terminated user string
+    Str,
+}
+
+use Arg::*;
+
+/// Name and argument kinds of the syscalls the kernel knows about
+fn signature(syscall_id: usize) -> Option<(&'static str, &'static [Arg])> {
+    let sig: (&'static str, &'static [Arg]) = match syscall_id {
+        SYSCALL_GETCWD => ("getcwd", &[Hex, Uint]),
+        SYSCALL_EPOLL_CREATE1 => ("epoll_create1", &[Hex]),
+        SYSCALL_EPOLL_CTL => ("epoll_ctl", &[Int, Int, Int, Hex]),
+        SYSCALL_EPOLL_PWAIT => ("epoll_pwait", &[Int, Hex, Int, Int, Hex]),
+        SYSCALL_DUP => ("dup", &[Int]),
+        SYSCALL_DUP3 => ("dup3", &[Int, Int, Hex]),
+        SYSCALL_FCNTL => ("fcntl", &[Int, Int, Hex]),
+        SYSCALL_IOCTL => ("ioctl", &[Int, Hex, Hex]),
+        SYSCALL_MKDIRAT => ("mkdirat", &[Int, Str, Hex]),
+        SYSCALL_UNLINKAT => ("unlinkat", &[Int, Str, Hex]),
+        SYSCALL_RENAMEAT2 => ("renameat2", &[Int, Str, Int, Str, Hex]),
+        SYSCALL_LINKAT => ("linkat", &[Int, Str, Int, Str, Hex]),
+        SYSCALL_UMOUNT2 => ("umount2", &[Str, Hex]),
+        SYSCALL_MOUNT => ("mount", &[Str, Str, Str, Hex, Hex]),
+        SYSCALL_CHDIR => ("chdir", &[Str]),
+        SYSCALL_OPENAT => ("openat", &[Int, Str, Hex]),
+        SYSCALL_CLOSE => ("close", &[Int]),
+        SYSCALL_PIPE => ("pipe2", &[Hex]),
+        SYSCALL_GETDENTS64 => ("getdents64", &[Int, Hex, Uint]),
+        SYSCALL_READ => ("read", &[Int, Hex, Uint]),
+        SYSCALL_WRITE => ("write", &[Int, Hex, Uint]),
+        SYSCALL_WRITEV => ("writev", &[Int, Hex, Uint]),
+        SYSCALL_SENDFILE => ("sendfile", &[Int, Int, Hex, Uint]),
+        SYSCALL_PPOLL => ("ppoll", &[Hex, Uint, Hex, Hex]),
+        SYSCALL_FSTATAT => ("newfstatat", &[Int, Str, Hex, Hex]),
+        SYSCALL_FSTAT => ("fstat", &[Int, Hex]),
+        SYSCALL_STATX => ("statx", &[Int, Str, Hex, Hex, Hex]),
+        SYSCALL_EXIT => ("exit", &[Int]),
+        SYSCALL_EXIT_GROUP => ("exit_group", &[Int]),
+        SYSCALL_SETTID => ("set_tid_address", &[Hex]),
+        SYSCALL_FUTEX => ("futex", &[Hex, Int, Uint, Hex, Hex, Hex]),
+        SYSCALL_NANOSLEEP => ("nanosleep", &[Hex, Hex]),
+        SYSCALL_GETITIMER => ("getitimer", &[Int, Hex]),
+        SYSCALL_SETITIMER => ("setitimer", &[Int, Hex, Hex]),
+        SYSCALL_CLOCK_SETTIME => ("clock_settime", &[Int, Hex]),
+        SYSCALL_CLOCK_GETTIME => ("clock_gettime", &[Int, Hex]),
+        SYSCALL_CLOCK_NANOSLEEP => ("clock_nanosleep", &[Int, Hex, Hex, Hex]),
+        SYSCALL_SYSLOG => ("syslog", &[Int, Hex, Uint]),
+        SYSCALL_YIELD => ("sched_yield", &[]),
+        SYSCALL_KILL => ("kill", &[Int, Hex]),
+        SYSCALL_SIGACTION => ("rt_sigaction", &[Int, Hex, Hex]),
+        SYSCALL_SIGPROCMASK => ("rt_sigprocmask", &[Int, Hex, Hex]),
+        SYSCALL_SIGTIMEDWAIT => ("rt_sigtimedwait", &[Hex, Hex, Hex, Uint]),
+        SYSCALL_SIGRETURN => ("rt_sigreturn", &[]),
+        SYSCALL_SET_PRIORITY => ("setpriority", &[Int, Int, Int]),
+        SYSCALL_GET_PRIORITY => ("getpriority", &[Int, Int]),
+        SYSCALL_SETGID => ("setgid", &[Uint]),
+        SYSCALL_SETUID => ("setuid", &[Uint]),
+        SYSCALL_SETRESUID => ("setresuid", &[Int, Int, Int]),
+        SYSCALL_GETRESUID => ("getresuid", &[Hex, Hex, Hex]),
+        SYSCALL_SETRESGID => ("setresgid", &[Int, Int, Int]),
+        SYSCALL_GETRESGID => ("getresgid", &[Hex, Hex, Hex]),
+        SYSCALL_TIMES => ("times", &[Hex]),
+        SYSCALL_SETPGID => ("setpgid", &[Int, Int]),
+        SYSCALL_GETPGID => ("getpgid", &[Int]),
+        SYSCALL_GETSID => ("getsid", &[Int]),
+        SYSCALL_SETSID => ("setsid", &[]),
+        SYSCALL_GETGROUPS => ("getgroups", &[Uint, Hex]),
+        SYSCALL_SETGROUPS => ("setgroups", &[Uint, Hex]),
+        SYSCALL_UNAME => ("uname", &[Hex]),
+        SYSCALL_GETRLIMIT => ("getrlimit", &[Int, Hex]),
+        SYSCALL_SETRLIMIT => ("setrlimit", &[Int, Hex]),
+        SYSCALL_PRCTL => ("prctl", &[Int, Hex, Hex, Hex, Hex]),
+        SYSCALL_GETTIMEOFDAY => ("gettimeofday", &[Hex, Hex]),
+        SYSCALL_GETPID => ("getpid", &[]),
+        SYSCALL_GETPPID => ("getppid", &[]),
+        SYSCALL_GETUID => ("getuid", &[]),
+        SYSCALL_GETEUID => ("geteuid", &[]),
+        SYSCALL_GETGID => ("getgid", &[]),
+        SYSCALL_GETEGID => ("getegid", &[]),
+        SYSCALL_GETTID => ("gettid", &[]),
+        SYSCALL_MSGGET => ("msgget", &[Int, Hex]),
+        SYSCALL_MSGCTL => ("msgctl", &[Int, Int, Hex]),
+        SYSCALL_MSGRCV => ("msgrcv", &[Int, Hex, Uint, Int, Hex]),
+        SYSCALL_MSGSND => ("msgsnd", &[Int, Hex, Uint, Hex]),
+        SYSCALL_SOCKET => ("socket", &[Int, Hex, Int]),
+        SYSCALL_SOCKETPAIR => ("socketpair", &[Int, Hex, Int, Hex]),
+        SYSCALL_BIND => ("bind", &[Int, Hex, Uint]),
+        SYSCALL_LISTEN => ("listen", &[Int, Int]),
+        SYSCALL_ACCEPT => ("accept", &[Int, Hex, Hex]),
+        SYSCALL_ACCEPT4 => ("accept4", &[Int, Hex, Hex, Hex]),
+        SYSCALL_CONNECT => ("connect", &[Int, Hex, Uint]),
+        SYSCALL_GETSOCKNAME => ("getsockname", &[Int, Hex, Hex]),
+        SYSCALL_GETPEERNAME => ("getpeername", &[Int, Hex, Hex]),
+        SYSCALL_SENDTO => ("sendto", &[Int, Hex, Uint, Hex, Hex, Uint]),
+        SYSCALL_RECVFROM => ("recvfrom", &[Int, Hex, Uint, Hex, Hex, Hex]),
+        SYSCALL_SETSOCKOPT => ("setsockopt", &[Int, Int, Int, Hex, Uint]),
+        SYSCALL_GETSOCKOPT => ("getsockopt", &[Int, Int, Int, Hex, Hex]),
+        SYSCALL_SHUTDOWN => ("shutdown", &[Int, Int]),
+        SYSCALL_SENDMSG => ("sendmsg", &[Int, Hex, Hex]),
+        SYSCALL_RECVMSG => ("recvmsg", &[Int, Hex, Hex]),
+        SYSCALL_BRK => ("brk", &[Hex]),
+        SYSCALL_MUNMAP => ("munmap", &[Hex, Uint]),
+        SYSCALL_CLONE => ("clone", &[Hex, Hex, Hex, Hex, Hex]),
+        SYSCALL_EXECVE => ("execve", &[Str, Hex, Hex]),
+        SYSCALL_MMAP => ("mmap", &[Hex, Uint, Hex, Hex, Int, Hex]),
+        SYSCALL_MPROTECT => ("mprotect", &[Hex, Uint, Hex]),
+        SYSCALL_MSYNC => ("msync", &[Hex, Uint, Hex]),
+        SYSCALL_WAIT4 => ("wait4", &[Int, Hex, Hex, Hex]),
+        SYSCALL_PRLIMIT64 => ("prlimit64", &[Int, Int, Hex, Hex]),
+        SYSCALL_SECCOMP => ("seccomp", &[Hex, Hex, Hex]),
+        SYSCALL_GETRANDOM => ("getrandom", &[Hex, Uint, Hex]),
+        SYSCALL_MEMBARRIER => ("membarrier", &[Hex, Hex, Int]),
+        SYSCALL_SPAWN => ("spawn", &[Str]),
+        SYSCALL_IO_URING_SETUP => ("io_uring_setup", &[Uint, Hex]),
+        SYSCALL_IO_URING_ENTER => ("io_uring_enter", &[Int, Uint, Uint, Hex]),
+        SYSCALL_TASK_INFO => ("task_info", &[Hex]),
+        SYSCALL_CHECKPOINT => ("checkpoint", &[Str]),
+        SYSCALL_RESTORE => ("restore", &[Str]),
+        SYSCALL_THREAD_CREATE => ("thread_create", &[Hex, Hex]),
+        SYSCALL_WAITTID => ("waittid", &[Int]),
+        SYSCALL_MUTEX_CREATE => ("mutex_create", &[Uint]),
+        SYSCALL_MUTEX_LOCK => ("mutex_lock", &[Uint]),
+        SYSCALL_MUTEX_UNLOCK => ("mutex_unlock", &[Uint]),
+        SYSCALL_SEMAPHORE_CREATE => ("semaphore_create", &[Uint]),
+        SYSCALL_SEMAPHORE_UP => ("semaphore_up", &[Uint]),
+        SYSCALL_ENABLE_DEADLOCK_DETECT => ("enable_deadlock_detect", &[Uint]),
+        SYSCALL_SEMAPHORE_DOWN => ("semaphore_down", &[Uint]),
+        SYSCALL_CONDVAR_CREATE => ("condvar_create", &[]),
+        SYSCALL_CONDVAR_SIGNAL => ("condvar_signal", &[Uint]),
+        SYSCALL_CONDVAR_WAIT => ("condvar_wait", &[Uint, Uint]),
+        _ => return None,
+    };
+    Some(sig)
+}
+
+/// Name of a syscall, `None` for ones the kernel does not know
+pub fn name(syscall_id: usize) -> Option<&'static str> {
+    signature(syscall_id).map(|(name, _)| name)
+}
+
+/// Syscalls that do not return to the caller on success, their line is
+/// written before the call
+pub fn no_return(syscall_id: usize) -> bool {
+    matches!(
+        syscall_id,
+        SYSCALL_EXIT | SYSCALL_EXIT_GROUP | SYSCALL_EXECVE | SYSCALL_SIGRETURN | SYSCALL_RESTORE
+    )
+}
+
+/// Read a user string without faulting on unmapped pages
+pub(super) fn read_user_str(token: usize, ptr: usize, out: &mut String) {
+    if ptr == 0 {
+        out.push_str("NULL");
+        return;
+    }
+    let page_table = PageTable::from_token(token);
+    out.push('"');
+    for va in ptr..ptr + MAX_STR_LEN {
+        let va = VirtAddr::from(va);
+        let mapped = page_table
+            .translate(va.floor())
+            .map_or(false, |pte| pte.is_valid() && pte.readable());
+        if !mapped {
+            out.push_str("\"<fault>");
+            return;
+        }
+        let pa: PhysAddr = page_table.translate_va(va).unwrap();
+        let ch = *pa.get_ref::<u8>();
+        if ch == 0 {
+            out.push('"');
+            return;
+        }
+        if ch.is_ascii_graphic() || ch == b' ' {
+            out.push(ch as char);
+        } else {
+            let _ = write!(out, "\\x{:02x}", ch);
+        }
+    }
+    out.push_str("\"...");
+}
+
+/// `name(arg, ...)` of a syscall about to run in the current task
+pub fn format_call(syscall_id: usize, args: &[usize; 6]) -> String {
+    let mut line = String::new();
+    let token = current_user_token();
+    match signature(syscall_id) {
+        Some((name, kinds)) => {
+            line.push_str(name);
+            line.push('(');
+            for (i, (kind, &arg)) in kinds.iter().zip(args.iter()).enumerate() {
+                if i > 0 {
+                    line.push_str(", ");
+                }
+                let _ = match kind {
+                    Int => write!(line, "{}", arg as isize as i32),
+                    Uint => write!(line, "{}", arg),
+                    Hex => write!(line, "{:#x}", arg),
+                    Str => {
+                        read_user_str(token, arg, &mut line);
+                        Ok(())
+                    }
+                };
+            }
+        }
+        None => {
+            let _ = write!(
+                line,
+                "syscall_{}({:#x}, {:#x}, {:#x}, {:#x}, {:#x}, {:#x}",
+                syscall_id, args[0], args[1], args[2], args[3], args[4], args[5]
+            );
+        }
+    }
+    line.push(')');
+    line
+}
+
+/// Syscalls returning an address rather than a count
+fn returns_addr(syscall_id: usize) -> bool {
+    matches!(syscall_id, SYSCALL_BRK | SYSCALL_MMAP)
+}
+
+/// Write a finished call to the kernel log, `ret` is `None` for calls that
+/// have not returned (yet)
+pub fn record(syscall_id: usize, pid: usize, tid: usize, call: &str, ret: Option<isize>) {
+    match ret {
+        None => kmsg::record(
+            TRACE_LEVEL,
+            format_args!("[{} {}] {} = ?\n", pid, tid, call),
+        ),
+        Some(ret) if ret < 0 => match Errno::try_from(ret) {
+            Ok(errno) => kmsg::record(
+                TRACE_LEVEL,
+                format_args!("[{} {}] {} = -1 {:?}\n", pid, tid, call, errno),
+            ),
+            Err(_) => kmsg::record(
+                TRACE_LEVEL,
+                format_args!("[{} {}] {} = {}\n", pid, tid, call, ret),
+            ),
+        },
+        Some(ret) if returns_addr(syscall_id) => kmsg::record(
+            TRACE_LEVEL,
+            format_args!("[{} {}] {} = {:#x}\n", pid, tid, call, ret),
+        ),
+        Some(ret) => kmsg::record(
+            TRACE_LEVEL,
+            format_args!("[{} {}] {} = {}\n", pid, tid, call, ret),
+        ),
+    }
+}
@@ -0,0 +1,184 @@
+//! Control groups limiting CPU time and memory of the processes in them
+//!
+//! 组是扁平的：根组下面可以创建若干子组，进程 fork 时继承父进程所在的组。
+//! - CPU：调度器按组的 `cpu.weight` 计算虚拟运行时间，总是先挑虚拟运行时间最小的组，
+//!   再按任务的 nice 在组内挑选，权重越大的组分到的时间片越多；
+//! - 内存：堆（brk）和 mmap 的物理页帧计入所在组，超过 `memory.max` 时分配失败，返回 ENOMEM。
+//!
+//! 配置接口见 [`crate::fs::cgroup`]，和 cgroup v2 的文件名一致。
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use lazy_static::*;
+
+use super::{manager::PID2PCB, TaskControlBlock};
+use crate::sync::SpinNoIrqLock;
+
+/// default `cpu.weight`, as in cgroup v2
+pub const CPU_WEIGHT_DEFAULT: usize = 100;
+pub const CPU_WEIGHT_MAX: usize = 10000;
+/// virtual runtime a group of default weight accrues per time slice
+const SLICE_VRUNTIME: usize = 1024;
+/// `memory.max` of a group without a limit
+pub const MEMORY_UNLIMITED: usize = usize::MAX;
+
+pub struct Cgroup {
+    /// empty for the root group
+    name:       String,
+    cpu_weight: AtomicUsize,
+    vruntime:   AtomicUsize,
+    /// limit and usage in pages
+    mem_max:    AtomicUsize,
+    mem_pages:  AtomicUsize,
+}
+
+lazy_static! {
+    /// The group every process starts in
+    pub static ref ROOT_CGROUP: Arc<Cgroup> = Cgroup::new("");
+    /// All groups but the root, by name
+    static ref CGROUPS: SpinNoIrqLock<Vec<Arc<Cgroup>>> = SpinNoIrqLock::new(Vec::new());
+}
+
+/// smallest vruntime handed out so far, new and waking groups start here
+static MIN_VRUNTIME: AtomicUsize = AtomicUsize::new(0);
+
+impl Cgroup {
+    fn new(name: &str) -> Arc<Self> {
+        Arc::new(Self {
+            name:       name.to_string(),
+            cpu_weight: AtomicUsize::new(CPU_WEIGHT_DEFAULT),
+            vruntime:   AtomicUsize::new(MIN_VRUNTIME.load(Ordering::Relaxed)),
+            mem_max:    AtomicUsize::new(MEMORY_UNLIMITED),
+            mem_pages:  AtomicUsize::new(0),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    pub fn cpu_weight(&self) -> usize {
+        self.cpu_weight.load(Ordering::Relaxed)
+    }
+
+    /// false if `weight` is outside 1..=10000
+    pub fn set_cpu_weight(&self, weight: usize) -> bool {
+        if !(1..=CPU_WEIGHT_MAX).contains(&weight) {
+            return false;
+        }
+        self.cpu_weight.store(weight, Ordering::Relaxed);
+        true
+    }
+
+    pub fn vruntime(&self) -> usize {
+        self.vruntime.load(Ordering::Relaxed)
+    }
+
+    /// Charge one time slice to the group, called when the scheduler picks one of its tasks
+    pub fn account_slice(&self) {
+        let start = self.vruntime().max(MIN_VRUNTIME.load(Ordering::Relaxed));
+        MIN_VRUNTIME.fetch_max(start, Ordering::Relaxed);
+        let delta = (SLICE_VRUNTIME * CPU_WEIGHT_DEFAULT / self.cpu_weight()).max(1);
+        self.vruntime.store(start + delta, Ordering::Relaxed);
+    }
+
+    /// memory limit in pages
+    pub fn mem_max(&self) -> usize {
+        self.mem_max.load(Ordering::Relaxed)
+    }
+
+    /// Lower or raise the limit, pages already charged are not reclaimed
+    pub fn set_mem_max(&self, pages: usize) {
+        self.mem_max.store(pages, Ordering::Relaxed);
+    }
+
+    /// pages charged to the group
+    pub fn mem_pages(&self) -> usize {
+        self.mem_pages.load(Ordering::Relaxed)
+    }
+
+    /// Reserve `pages` frames, false if that would go over `memory.max`
+    pub fn try_charge(&self, pages: usize) -> bool {
+        let max = self.mem_max();
+        self.mem_pages
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(pages).filter(|&total| total <= max)
+            })
+            .is_ok()
+    }
+
+    /// Whether `pages` more frames would stay within `memory.max`, without charging them
+    pub fn fits(&self, pages: usize) -> bool {
+        self.mem_pages()
+            .checked_add(pages)
+            .map_or(false, |total| total <= self.mem_max())
+    }
+
+    /// Charge `pages` regardless of the limit, for copy-on-write copies made after fork
+    pub fn charge(&self, pages: usize) {
+        self.mem_pages.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    pub fn uncharge(&self, pages: usize) {
+        self.mem_pages.fetch_sub(pages, Ordering::Relaxed);
+    }
+
+    /// pids of the processes in this group
+    pub fn procs(self: &Arc<Self>) -> Vec<usize> {
+        let map = PID2PCB.lock();
+        map.iter()
+            .filter(|(_, task)| Arc::ptr_eq(&task.inner_exclusive_access().cgroup, self))
+            .map(|(&pid, _)| pid)
+            .collect()
+    }
+
+    /// Move `task` into this group, its charged memory stays with the old one
+    pub fn attach(self: &Arc<Self>, task: &Arc<TaskControlBlock>) {
+        task.inner_exclusive_access().cgroup = self.clone();
+    }
+}
+
+/// Look up a group by name, the empty name is the root group
+pub fn find(name: &str) -> Option<Arc<Cgroup>> {
+    if name.is_empty() {
+        return Some(ROOT_CGROUP.clone());
+    }
+    let groups = CGROUPS.lock();
+    groups.iter().find(|group| group.name == name).cloned()
+}
+
+/// Names of all groups but the root
+pub fn names() -> Vec<String> {
+    let groups = CGROUPS.lock();
+    groups.iter().map(|group| group.name.clone()).collect()
+}
+
+/// Create a group under the root, `None` if the name is taken
+pub fn create(name: &str) -> Option<Arc<Cgroup>> {
+    if name.is_empty() || find(name).is_some() {
+        return None;
+    }
+    let group = Cgroup::new(name);
+    CGROUPS.lock().push(group.clone());
+    Some(group)
+}
+
+/// Remove a group, fails while it still has processes
+pub fn remove(name: &str) -> bool {
+    match find(name) {
+        Some(group) if !group.is_root() && group.procs().is_empty() => {
+            CGROUPS.lock().retain(|other| !Arc::ptr_eq(other, &group));
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,377 @@
+//! Process checkpoint and restore
+//!
+//! `checkpoint(path)` 把调用进程的用户态现场写入文件，`restore(path)` 用文件中的镜像替换
+//! 调用进程的地址空间和寄存器，类似 exec。恢复后进程从 checkpoint 调用处继续执行，
+//! 此时 checkpoint 返回 1（第一次调用返回 0），和 setjmp 的用法一样。
+//!
+//! 镜像格式（小端，按原生布局）：
+//! [`ImageHeader`]，`nr_areas` 个 [`AreaRecord`]，`nr_fds` 个 [`FdRecord`]，
+//! `nr_reserved` 个 [`ReservedRecord`]，
+//! 然后依次是各区域每一页的内容、堆的页、mmap 的页，后两者每页前有一个 usize 的 vpn。
+//!
+//! 文件描述符只记录元数据：恢复时关闭镜像中没有打开的描述符，其余保持调用者的状态。
+//! 只支持由主线程调用。
+
+use alloc::{sync::Arc, vec::Vec};
+use core::mem::size_of;
+
+use super::{SignalFlags, TaskContext, TaskControlBlock};
+use crate::{
+    config::{PAGE_SIZE, USER_SPACE_END},
+    fs::inode::Inode,
+    mm::{
+        MapPermission,
+        MemorySet,
+        PhysPageNum,
+        PrivateRegion,
+        VirtAddr,
+        VirtPageNum,
+        KERNEL_SPACE,
+    },
+    sync::SpinNoIrqLock,
+    syscall::errno::{EINVAL, EIO, ENOMEM},
+    task::res::trap_cx_bottom_from_tid,
+    trap::{trap_handler, TrapContext},
+};
+
+/// "CHAOSCKP"
+const IMAGE_MAGIC: usize = 0x504b_4353_4f41_4843;
+const IMAGE_VERSION: usize = 2;
+
+/// what checkpoint returns in the restored process
+const RESTORED: usize = 1;
+
+/// Most pages the areas, heap and mmap region of an image may map, half of the RAM
+const MAX_IMAGE_PAGES: usize = 0x4000;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ImageHeader {
+    magic:          usize,
+    version:        usize,
+    /// user registers, `x[10]` already holds the value checkpoint returns after restore
+    regs:           [usize; 32],
+    /// the instruction after the checkpoint ecall
+    sepc:           usize,
+    heap_base:      usize,
+    heap_end:       usize,
+    mmap_end:       usize,
+    user_stack_top: usize,
+    signal_mask:    usize,
+    nr_areas:       usize,
+    nr_fds:         usize,
+    nr_heap_pages:  usize,
+    nr_mmap_pages:  usize,
+    nr_reserved:    usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct AreaRecord {
+    start_vpn: usize,
+    end_vpn:   usize,
+    perm:      usize,
+}
+
+/// A range of the heap or the mmap region whose pages are mapped on first touch
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ReservedRecord {
+    start_vpn: usize,
+    end_vpn:   usize,
+    /// [`RESERVED_HEAP`] or [`RESERVED_MMAP`]
+    region:    usize,
+}
+
+const RESERVED_HEAP: usize = 0;
+const RESERVED_MMAP: usize = 1;
+
+const FD_READABLE: usize = 1 << 0;
+const FD_WRITABLE: usize = 1 << 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FdRecord {
+    fd:    usize,
+    flags: usize,
+}
+
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn as_bytes_mut<T: Copy>(value: &mut T) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) }
+}
+
+/// Sequential access to the image file
+struct ImageFile {
+    inode:  Arc<dyn Inode>,
+    offset: usize,
+}
+
+impl ImageFile {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), isize> {
+        if self.inode.write_at(self.offset, bytes) != bytes.len() {
+            return Err(EIO);
+        }
+        self.offset += bytes.len();
+        Ok(())
+    }
+
+    /// EINVAL on a short read, the image is truncated
+    fn read(&mut self, bytes: &mut [u8]) -> Result<(), isize> {
+        let mut done = 0;
+        while done < bytes.len() {
+            match self.inode.read_at(self.offset + done, &mut bytes[done..]) {
+                0 => return Err(EINVAL),
+                len => done += len,
+            }
+        }
+        self.offset += done;
+        Ok(())
+    }
+
+    fn read_value<T: Copy + Default>(&mut self) -> Result<T, isize> {
+        let mut value = T::default();
+        self.read(as_bytes_mut(&mut value))?;
+        Ok(value)
+    }
+}
+
+impl TaskControlBlock {
+    /// Write the calling process into `image`, it must be the current task
+    pub fn checkpoint(&self, image: Arc<dyn Inode>) -> Result<(), isize> {
+        // 和 exec 一样，只支持由主线程调用
+        if self.pid.0 != self.tid {
+            return Err(EINVAL);
+        }
+        let trap_cx = self.get_trap_cx();
+        let inner = self.inner_exclusive_access();
+        let memory_set = inner.memory_set.lock();
+        // 共享文件映射的内容属于文件，镜像中无法记录
+        if memory_set.has_shared_pages() {
+            return Err(EINVAL);
+        }
+        let mut regs = trap_cx.x;
+        regs[10] = RESTORED;
+        let areas: Vec<AreaRecord> = memory_set
+            .user_areas()
+            .map(|area| AreaRecord {
+                start_vpn: area.vpn_range.get_start().0,
+                end_vpn:   area.vpn_range.get_end().0,
+                perm:      area.map_perm.bits() as usize,
+            })
+            .collect();
+        let fds: Vec<FdRecord> = inner
+            .fd_table
+            .lock()
+            .iter()
+            .enumerate()
+            .filter_map(|(fd, entry)| {
+                let file = &entry.as_ref()?.file;
+                let mut flags = 0;
+                if file.readable() {
+                    flags |= FD_READABLE;
+                }
+                if file.writable() {
+                    flags |= FD_WRITABLE;
+                }
+                Some(FdRecord { fd, flags })
+            })
+            .collect();
+        // 页帧属于调用者自己的地址空间，系统调用期间不会变化，写文件时不必持有 inner
+        let area_pages: Vec<PhysPageNum> = areas
+            .iter()
+            .flat_map(|area| area.start_vpn..area.end_vpn)
+            .map(|vpn| memory_set.translate(VirtPageNum(vpn)).unwrap().ppn())
+            .collect();
+        let heap_pages: Vec<_> = memory_set.private_pages(PrivateRegion::Heap).collect();
+        let mmap_pages: Vec<_> = memory_set.private_pages(PrivateRegion::Mmap).collect();
+        let reserved: Vec<ReservedRecord> = [
+            (PrivateRegion::Heap, RESERVED_HEAP),
+            (PrivateRegion::Mmap, RESERVED_MMAP),
+        ]
+        .into_iter()
+        .flat_map(|(region, tag)| {
+            memory_set
+                .reserved_ranges(region)
+                .map(move |(start, end)| ReservedRecord {
+                    start_vpn: start.0,
+                    end_vpn:   end.0,
+                    region:    tag,
+                })
+        })
+        .collect();
+        let header = ImageHeader {
+            magic: IMAGE_MAGIC,
+            version: IMAGE_VERSION,
+            regs,
+            sepc: trap_cx.sepc,
+            heap_base: memory_set.heap_base.0,
+            heap_end: memory_set.heap_end.0,
+            mmap_end: memory_set.mmap_end.0,
+            user_stack_top: inner.user_stack_top,
+            signal_mask: inner.signal_mask.bits() as usize,
+            nr_areas: areas.len(),
+            nr_fds: fds.len(),
+            nr_heap_pages: heap_pages.len(),
+            nr_mmap_pages: mmap_pages.len(),
+            nr_reserved: reserved.len(),
+        };
+        drop(memory_set);
+        drop(inner);
+
+        let mut file = ImageFile {
+            inode:  image,
+            offset: 0,
+        };
+        file.write(as_bytes(&header))?;
+        for area in areas.iter() {
+            file.write(as_bytes(area))?;
+        }
+        for fd in fds.iter() {
+            file.write(as_bytes(fd))?;
+        }
+        for range in reserved.iter() {
+            file.write(as_bytes(range))?;
+        }
+        for ppn in area_pages {
+            file.write(ppn.get_bytes_array())?;
+        }
+        for (vpn, ppn) in heap_pages.into_iter().chain(mmap_pages) {
+            file.write(as_bytes(&vpn.0))?;
+            file.write(ppn.get_bytes_array())?;
+        }
+        Ok(())
+    }
+
+    /// Replace the calling process with the one saved in `image`
+    ///
+    /// 镜像读完并校验通过后才替换地址空间，失败时调用者不受影响。
+    pub fn restore(self: &Arc<Self>, image: Arc<dyn Inode>) -> Result<(), isize> {
+        if self.pid.0 != self.tid {
+            return Err(EINVAL);
+        }
+        let cgroup = self.inner_exclusive_access().cgroup.clone();
+        let mut file = ImageFile {
+            inode:  image,
+            offset: 0,
+        };
+        let header: ImageHeader = file.read_value()?;
+        if header.magic != IMAGE_MAGIC || header.version != IMAGE_VERSION {
+            return Err(EINVAL);
+        }
+        let mut areas = Vec::new();
+        for _ in 0..header.nr_areas {
+            areas.push(file.read_value::<AreaRecord>()?);
+        }
+        let mut fds = Vec::new();
+        for _ in 0..header.nr_fds {
+            fds.push(file.read_value::<FdRecord>()?);
+        }
+        let mut reserved = Vec::new();
+        for _ in 0..header.nr_reserved {
+            reserved.push(file.read_value::<ReservedRecord>()?);
+        }
+        // 分配任何页之前先看总页数，区域的页和堆、mmap 的页一样计入 cgroup
+        let total_pages = areas
+            .iter()
+            .try_fold(0usize, |total, area| {
+                total.checked_add(area.end_vpn.saturating_sub(area.start_vpn))
+            })
+            .and_then(|total| total.checked_add(header.nr_heap_pages))
+            .and_then(|total| total.checked_add(header.nr_mmap_pages));
+        if total_pages.map_or(true, |total| total > MAX_IMAGE_PAGES) {
+            return Err(ENOMEM);
+        }
+
+        let mut memory_set = MemorySet::new_process();
+        // trap 上下文按 pid 放置，先占住它的位置，镜像中的区域不能与之重叠
+        let mut trap_cx = TrapContext::app_init_context(
+            header.sepc,
+            header.regs[2],
+            KERNEL_SPACE.lock().token(),
+            self.kstack.get_top(),
+            trap_handler as usize,
+        );
+        trap_cx.x = header.regs;
+        let trap_cx_bottom = trap_cx_bottom_from_tid(self.pid.0);
+        memory_set.insert_framed_area_with_data(
+            trap_cx_bottom.into(),
+            (trap_cx_bottom + PAGE_SIZE).into(),
+            MapPermission::R | MapPermission::W,
+            as_bytes(&trap_cx),
+        );
+        for area in areas.iter() {
+            let perm = MapPermission::from_bits(area.perm as u8)
+                .filter(|perm| area.perm <= u8::MAX as usize && perm.contains(MapPermission::U))
+                .ok_or(EINVAL)?;
+            if area.start_vpn >= area.end_vpn || area.end_vpn > (USER_SPACE_END + 1) / PAGE_SIZE {
+                return Err(EINVAL);
+            }
+            let start_va: VirtAddr = (area.start_vpn * PAGE_SIZE).into();
+            let end_va: VirtAddr = (area.end_vpn * PAGE_SIZE).into();
+            if memory_set.is_conflict_with_va(start_va, end_va) {
+                return Err(EINVAL);
+            }
+            memory_set.insert_charged_area(start_va, end_va, perm, &cgroup)?;
+        }
+        for area in areas.iter() {
+            for vpn in area.start_vpn..area.end_vpn {
+                let ppn = memory_set.translate(VirtPageNum(vpn)).unwrap().ppn();
+                file.read(ppn.get_bytes_array())?;
+            }
+        }
+        let regions = [
+            (PrivateRegion::Heap, header.nr_heap_pages),
+            (PrivateRegion::Mmap, header.nr_mmap_pages),
+        ];
+        for (region, nr_pages) in regions {
+            for _ in 0..nr_pages {
+                let vpn: usize = file.read_value()?;
+                if vpn >= (USER_SPACE_END + 1) / PAGE_SIZE
+                    || memory_set
+                        .translate(VirtPageNum(vpn))
+                        .map_or(false, |pte| pte.is_valid())
+                {
+                    return Err(EINVAL);
+                }
+                let ppn = memory_set.map_private_page(region, VirtPageNum(vpn), &cgroup)?;
+                file.read(ppn.get_bytes_array())?;
+            }
+        }
+        for range in reserved.iter() {
+            let region = match range.region {
+                RESERVED_HEAP => PrivateRegion::Heap,
+                RESERVED_MMAP => PrivateRegion::Mmap,
+                _ => return Err(EINVAL),
+            };
+            if range.start_vpn >= range.end_vpn || range.end_vpn > (USER_SPACE_END + 1) / PAGE_SIZE
+            {
+                return Err(EINVAL);
+            }
+            memory_set.reserve_private(
+                region,
+                VirtPageNum(range.start_vpn),
+                VirtPageNum(range.end_vpn),
+            );
+        }
+        memory_set.mmap_end = header.mmap_end.into();
+        memory_set.heap_base = header.heap_base.into();
+        memory_set.heap_end = header.heap_end.into();
+
+        let mut inner = self.inner_exclusive_access();
+        inner.memory_set = Arc::new(SpinNoIrqLock::new(memory_set));
+        inner.user_stack_top = header.user_stack_top;
+        inner.signal_mask = SignalFlags::from_bits_truncate(header.signal_mask as _);
+        for (fd, file) in inner.fd_table.lock().iter_mut().enumerate() {
+            if !fds.iter().any(|record| record.fd == fd) {
+                file.take();
+            }
+        }
+        // 和 exec 一样，下次被调度时从新的地址空间进入用户态
+        inner.task_cx = TaskContext::goto_user_entry(self.kstack.get_top());
+        Ok(())
+    }
+}
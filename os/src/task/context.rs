@@ -38,6 +38,23 @@ impl TaskContext {
         }
     }
 
+    /// Create a task context that starts a kernel thread running `entry(arg)`
+    pub fn goto_kernel_thread(
+        kstack_ptr: usize, entry: extern "C" fn(usize) -> !, arg: usize,
+    ) -> Self {
+        extern "C" {
+            fn __kthread_entry();
+        }
+        let mut s = [0; 12];
+        s[0] = entry as usize;
+        s[1] = arg;
+        Self {
+            ra: __kthread_entry as usize,
+            sp: kstack_ptr,
+            s,
+        }
+    }
+
     pub fn goto_user_entry(kstack_ptr: usize) -> Self {
         Self {
             ra: crate::trap::user_entry as usize,
@@ -0,0 +1,156 @@
+//! Process credentials: real/effective/saved user and group ids
+//!
+//! 权限检查只看有效 id（euid/egid）和附加组。euid 为 0 的 root 可以读写任何文件，
+//! 执行则至少需要有一个执行位被置上，和 Linux 的 CAP_DAC_OVERRIDE 行为一致。
+
+use alloc::vec::Vec;
+
+use crate::fs::inode::InodePerm;
+
+/// set-user-ID on execution
+pub const S_ISUID: u16 = 0o4000;
+/// set-group-ID on execution
+pub const S_ISGID: u16 = 0o2000;
+
+/// access bits asked of [`Credentials::permits`], same values as the rwx bits
+pub const MAY_READ: u16 = 4;
+pub const MAY_WRITE: u16 = 2;
+pub const MAY_EXEC: u16 = 1;
+
+/// most supplementary groups a task may have, NGROUPS_MAX
+pub const NGROUPS_MAX: usize = 65536;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid:    u32,
+    pub euid:   u32,
+    pub suid:   u32,
+    pub gid:    u32,
+    pub egid:   u32,
+    pub sgid:   u32,
+    /// supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    /// credentials of the initial task
+    pub fn root() -> Self {
+        Self {
+            uid:    0,
+            euid:   0,
+            suid:   0,
+            gid:    0,
+            egid:   0,
+            sgid:   0,
+            groups: Vec::new(),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.euid == 0
+    }
+
+    /// whether `gid` is the effective group or one of the supplementary groups
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.egid == gid || self.groups.contains(&gid)
+    }
+
+    /// setuid(2): root sets all three ids, others may only switch euid to uid or suid
+    pub fn setuid(&mut self, uid: u32) -> bool {
+        if self.is_root() {
+            self.uid = uid;
+            self.suid = uid;
+        } else if uid != self.uid && uid != self.suid {
+            return false;
+        }
+        self.euid = uid;
+        true
+    }
+
+    /// setgid(2), same rules as [`Credentials::setuid`]
+    pub fn setgid(&mut self, gid: u32) -> bool {
+        if self.is_root() {
+            self.gid = gid;
+            self.sgid = gid;
+        } else if gid != self.gid && gid != self.sgid {
+            return false;
+        }
+        self.egid = gid;
+        true
+    }
+
+    /// setresuid(2), `None` leaves the id unchanged
+    ///
+    /// Unprivileged tasks may only set each id to one of the current real,
+    /// effective or saved uids.
+    pub fn setresuid(&mut self, uid: Option<u32>, euid: Option<u32>, suid: Option<u32>) -> bool {
+        let allowed = |id: u32| id == self.uid || id == self.euid || id == self.suid;
+        if !self.is_root() && ![uid, euid, suid].iter().flatten().all(|&id| allowed(id)) {
+            return false;
+        }
+        self.uid = uid.unwrap_or(self.uid);
+        self.euid = euid.unwrap_or(self.euid);
+        self.suid = suid.unwrap_or(self.suid);
+        true
+    }
+
+    /// setresgid(2), same rules as [`Credentials::setresuid`]
+    pub fn setresgid(&mut self, gid: Option<u32>, egid: Option<u32>, sgid: Option<u32>) -> bool {
+        let allowed = |id: u32| id == self.gid || id == self.egid || id == self.sgid;
+        if !self.is_root() && ![gid, egid, sgid].iter().flatten().all(|&id| allowed(id)) {
+            return false;
+        }
+        self.gid = gid.unwrap_or(self.gid);
+        self.egid = egid.unwrap_or(self.egid);
+        self.sgid = sgid.unwrap_or(self.sgid);
+        true
+    }
+
+    /// setgroups(2), root only
+    pub fn setgroups(&mut self, groups: Vec<u32>) -> bool {
+        if !self.is_root() {
+            return false;
+        }
+        self.groups = groups;
+        true
+    }
+
+    /// Switch ids for exec'ing a file with permissions `perm`
+    ///
+    /// set-user-ID and set-group-ID bits change the effective id to the
+    /// file's owner, the saved ids always follow the effective ones.
+    pub fn apply_exec(&mut self, perm: Option<InodePerm>) {
+        if let Some(perm) = perm {
+            if perm.mode & S_ISUID != 0 {
+                self.euid = perm.uid;
+            }
+            if perm.mode & S_ISGID != 0 && perm.mode & 0o010 != 0 {
+                self.egid = perm.gid;
+            }
+        }
+        self.suid = self.euid;
+        self.sgid = self.egid;
+    }
+
+    /// Whether these credentials allow `access` (a mask of `MAY_*`) to an inode
+    ///
+    /// `None` means the filesystem keeps no ownership, so everything is allowed.
+    pub fn permits(&self, perm: Option<InodePerm>, access: u16) -> bool {
+        let perm = match perm {
+            Some(perm) => perm,
+            None => return true,
+        };
+        if self.is_root() {
+            // root 只有在没有任何执行位时才不能执行
+            return access & MAY_EXEC == 0 || perm.mode & 0o111 != 0;
+        }
+        let shift = if self.euid == perm.uid {
+            6
+        } else if self.in_group(perm.gid) {
+            3
+        } else {
+            0
+        };
+        (perm.mode >> shift) & access == access
+    }
+}
@@ -2,37 +2,168 @@
 //!
 //! It is only used to manage processes and schedule process based on ready queue.
 //! Other CPU process monitoring functions are in Processor.
+//!
+//! 调度分两层，和 CFS 的组调度类似：先按 cgroup 的 `cpu.weight` 选出虚拟运行时间最小的组，
+//! 再在组内选虚拟运行时间最小的任务。任务的实际运行时间按 nice 对应的权重折算成虚拟运行时间，
+//! 在时钟中断和切换出去时记账；nice 越小权重越大，虚拟运行时间涨得越慢，时间片也越长。
+//!
+//! 持有 `TASK_MANAGER` 时不能再锁任务（见 [`super`] 中的加锁顺序）。就绪队列里记下入队时
+//! 任务所在的 cgroup 和虚拟运行时间，选任务时不用去锁每个任务；任务在就绪队列里不运行，
+//! 虚拟运行时间不会变。
 
 use alloc::{
     collections::{btree_map::BTreeMap, vec_deque::VecDeque},
     sync::Arc,
+    vec::Vec,
 };
 
 use lazy_static::*;
 
-use super::{TaskControlBlock, TaskStatus};
-use crate::sync::UPSafeCell;
+use super::{cgroup::Cgroup, current_task, hart_id, TaskControlBlock, TaskStatus};
+use crate::{
+    config::MAX_HARTS,
+    smp,
+    sync::SpinNoIrqLock,
+    timer::{get_time, TICK_CYCLES},
+};
+
+pub const NICE_MIN: isize = -20;
+pub const NICE_MAX: isize = 19;
+/// weight of nice 0
+const NICE_0_WEIGHT: usize = 1024;
+
+/// Weight of each nice level from -20 to 19, the same as Linux `sched_prio_to_weight`
+///
+/// 相邻两级相差约 1.25 倍，nice 每高一级分到的 CPU 时间约少 10%。
+#[rustfmt::skip]
+const NICE_TO_WEIGHT: [usize; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+     9548,  7620,  6100,  4904,  3906,
+     3121,  2501,  1991,  1586,  1277,
+     1024,   820,   655,   526,   423,
+      335,   272,   215,   172,   137,
+      110,    87,    70,    56,    45,
+       36,    29,    23,    18,    15,
+];
+
+/// Per-task scheduling state
+#[derive(Clone)]
+pub struct SchedEntity {
+    /// -20..=19, inherited by children
+    pub nice:     isize,
+    /// clock cycles run, scaled by `NICE_0_WEIGHT / weight`
+    pub vruntime: usize,
+    /// when the task was last charged
+    exec_start:   usize,
+    /// when the task was switched in
+    slice_start:  usize,
+}
+
+impl SchedEntity {
+    pub fn new() -> Self {
+        Self {
+            nice:        0,
+            vruntime:    0,
+            exec_start:  0,
+            slice_start: 0,
+        }
+    }
+
+    /// State of a child, which starts where the parent is so that forking gains no CPU time
+    pub fn fork(&self) -> Self {
+        Self {
+            nice: self.nice,
+            vruntime: self.vruntime,
+            ..Self::new()
+        }
+    }
+
+    pub fn weight(&self) -> usize {
+        NICE_TO_WEIGHT[(self.nice - NICE_MIN) as usize]
+    }
+
+    /// Clock cycles the task may run before the timer tick preempts it, one tick at nice 0
+    pub fn time_slice(&self) -> usize {
+        (TICK_CYCLES * self.weight() / NICE_0_WEIGHT).max(1)
+    }
+
+    /// Start a new time slice, called when the task is switched in
+    pub fn start(&mut self) {
+        self.exec_start = get_time();
+        self.slice_start = self.exec_start;
+    }
+
+    /// Charge the time run since the last call or `start`
+    pub fn update(&mut self) {
+        let now = get_time();
+        let delta = now.saturating_sub(self.exec_start);
+        self.exec_start = now;
+        self.vruntime += (delta * NICE_0_WEIGHT / self.weight()).max(1);
+    }
+
+    /// Whether the current time slice is used up
+    ///
+    /// 只有时钟中断能抢占，所以按离时间片结束最近的那次中断算：剩下不到半个 tick 就算用完。
+    pub fn slice_expired(&self) -> bool {
+        get_time().saturating_sub(self.slice_start) + TICK_CYCLES / 2 >= self.time_slice()
+    }
+}
+
+/// A task in the ready queue, with what the scheduler picks it by
+pub struct ReadyTask {
+    task:     Arc<TaskControlBlock>,
+    cgroup:   Arc<Cgroup>,
+    vruntime: usize,
+}
+
+impl ReadyTask {
+    /// Record `task` for the ready queue, raising its vruntime to at least `floor`
+    ///
+    /// 睡眠很久的任务醒来时不能攒着大量虚拟运行时间的优势把别人饿死，
+    /// 最多比刚被选中的任务少一个时间片。
+    pub fn new(task: Arc<TaskControlBlock>, floor: usize) -> Self {
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.sched.vruntime = task_inner.sched.vruntime.max(floor);
+        let cgroup = task_inner.cgroup.clone();
+        let vruntime = task_inner.sched.vruntime;
+        drop(task_inner);
+        Self {
+            task,
+            cgroup,
+            vruntime,
+        }
+    }
+}
+
 ///A array of `TaskControlBlock` that is thread-safe
 pub struct TaskManager {
-    ready_queue: VecDeque<Arc<TaskControlBlock>>,
-    block_queue: VecDeque<Arc<TaskControlBlock>>,
+    ready_queue:  VecDeque<ReadyTask>,
+    block_queue:  VecDeque<Arc<TaskControlBlock>>,
+    /// vruntime of the last task picked, never decreases
+    min_vruntime: usize,
 
-    /// The stopping task, leave a reference so that the kernel stack will not be recycled when switching tasks
-    stop_task: Option<Arc<TaskControlBlock>>,
+    /// The stopping task of each hart, leave a reference so that the kernel stack will not be recycled when switching tasks
+    stop_task: [Option<Arc<TaskControlBlock>>; MAX_HARTS],
 }
 
-/// A simple FIFO scheduler.
+/// A weighted fair scheduler.
 impl TaskManager {
     ///Creat an empty TaskManager
     pub fn new() -> Self {
         Self {
-            ready_queue: VecDeque::new(),
-            block_queue: VecDeque::new(),
-            stop_task:   None,
+            ready_queue:  VecDeque::new(),
+            block_queue:  VecDeque::new(),
+            min_vruntime: 0,
+            stop_task:    core::array::from_fn(|_| None),
         }
     }
+    /// Lowest vruntime a task entering the ready queue keeps, see [`ReadyTask::new`]
+    pub fn vruntime_floor(&self) -> usize {
+        self.min_vruntime.saturating_sub(TICK_CYCLES)
+    }
     /// Add process back to ready queue
-    pub fn add(&mut self, task: Arc<TaskControlBlock>) {
+    pub fn add(&mut self, task: ReadyTask) {
         self.ready_queue.push_back(task);
     }
     /// add process back to block queue
@@ -40,105 +171,147 @@ impl TaskManager {
         self.block_queue.push_back(task);
     }
     /// Take a process out of the ready queue
+    ///
+    /// 先选出虚拟运行时间最小的 cgroup，再选组内虚拟运行时间最小的任务，相同时先就绪的优先。
     pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
-        if self.ready_queue.is_empty() {
-            return None;
-        }
-        // let mut min_idx = 0;
-        // for (idx, _) in self.ready_queue.iter().enumerate() {
-        //     let stride_now = self.ready_queue[idx].inner_exclusive_access(file!(), line!()).stride;
-        //     let stride_min = self.ready_queue[min_idx].inner_exclusive_access(file!(), line!()).stride;
-        //     if stride_now < stride_min {
-        //         min_idx = idx;
-        //     }
-        // }
-        // self.ready_queue.swap(0, min_idx);
-        self.ready_queue.pop_front()
+        let cgroup = self
+            .ready_queue
+            .iter()
+            .map(|ready| &ready.cgroup)
+            .min_by_key(|cgroup| cgroup.vruntime())?
+            .clone();
+        let (idx, vruntime) = self
+            .ready_queue
+            .iter()
+            .enumerate()
+            .filter(|(_, ready)| Arc::ptr_eq(&ready.cgroup, &cgroup))
+            .map(|(idx, ready)| (idx, ready.vruntime))
+            .min_by_key(|&(_, vruntime)| vruntime)?;
+        cgroup.account_slice();
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        self.ready_queue.remove(idx).map(|ready| ready.task)
     }
     pub fn remove(&mut self, task: Arc<TaskControlBlock>) {
         if let Some((id, _)) = self
             .ready_queue
             .iter()
             .enumerate()
-            .find(|(_, t)| Arc::as_ptr(t) == Arc::as_ptr(&task))
+            .find(|(_, ready)| Arc::ptr_eq(&ready.task, &task))
         {
             self.ready_queue.remove(id);
         }
     }
     /// Add a task to stopping task
     pub fn add_stop(&mut self, task: Arc<TaskControlBlock>) {
-        // NOTE: as the last stopping task has completely stopped (not
-        // using kernel stack any more, at least in the single-core
-        // case) so that we can simply replace it;
-        self.stop_task = Some(task);
+        // NOTE: as the last stopping task of this hart has completely
+        // stopped (not using kernel stack any more) so that we can
+        // simply replace it;
+        self.stop_task[hart_id() % MAX_HARTS] = Some(task);
     }
 }
 
 lazy_static! {
     /// TASK_MANAGER instance through lazy_static!
-    pub static ref TASK_MANAGER: UPSafeCell<TaskManager> =
-        unsafe { UPSafeCell::new(TaskManager::new()) };
+    pub static ref TASK_MANAGER: SpinNoIrqLock<TaskManager> = SpinNoIrqLock::new(TaskManager::new());
     /// PID2PCB instance (map of pid to pcb)
-    pub static ref PID2PCB: UPSafeCell<BTreeMap<usize, Arc<TaskControlBlock>>> =
-        unsafe { UPSafeCell::new(BTreeMap::new()) };
+    pub static ref PID2PCB: SpinNoIrqLock<BTreeMap<usize, Arc<TaskControlBlock>>> =
+        SpinNoIrqLock::new(BTreeMap::new());
 }
 
 /// Add a task to ready queue
+///
+/// 会锁 `task`，调用者不能持有它。
 pub fn add_task(task: Arc<TaskControlBlock>) {
     //trace!("kernel: TaskManager::add_task");
-    TASK_MANAGER.exclusive_access(file!(), line!()).add(task);
+    let floor = TASK_MANAGER.lock().vruntime_floor();
+    let ready = ReadyTask::new(task, floor);
+    TASK_MANAGER.lock().add(ready);
+    smp::kick_idle_hart();
 }
 
 /// Add a task to block queue
 pub fn add_block_task(task: Arc<TaskControlBlock>) {
     //trace!("kernel: TaskManager::add_block_task");
-    TASK_MANAGER
-        .exclusive_access(file!(), line!())
-        .add_block(task);
+    TASK_MANAGER.lock().add_block(task);
 }
 
 /// Wake up a task
+///
+/// 任务可能已经把自己挂上了等待队列、但还没来得及阻塞（在另一个 hart 上运行），
+/// 这时只把状态改成 Ready，它的 `block_current_and_run_next` 看到后直接返回。
 pub fn wakeup_task(task: Arc<TaskControlBlock>) {
     trace!("kernel: TaskManager::wakeup_task");
-    let mut task_inner = task.inner_exclusive_access(file!(), line!());
-    task_inner.task_status = TaskStatus::Ready;
-    drop(task_inner);
-    add_task(task);
+    let mut task_inner = task.inner_exclusive_access();
+    match task_inner.task_status {
+        TaskStatus::Blocked => {
+            task_inner.task_status = TaskStatus::Ready;
+            drop(task_inner);
+            add_task(task);
+        }
+        TaskStatus::Running => task_inner.task_status = TaskStatus::Ready,
+        _ => {}
+    }
 }
 
 /// Remove a task from the ready queue
 pub fn remove_task(task: Arc<TaskControlBlock>) {
     //trace!("kernel: TaskManager::remove_task");
-    TASK_MANAGER.exclusive_access(file!(), line!()).remove(task);
+    TASK_MANAGER.lock().remove(task);
 }
 
 /// Fetch a task out of the ready queue
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
     //trace!("kernel: TaskManager::fetch_task");
-    TASK_MANAGER.exclusive_access(file!(), line!()).fetch()
+    TASK_MANAGER.lock().fetch()
+}
+
+/// Charge the running task for the time since the last tick, true if it should be preempted
+pub fn scheduler_tick() -> bool {
+    let Some(task) = current_task() else {
+        return false;
+    };
+    let mut task_inner = task.inner_exclusive_access();
+    task_inner.sched.update();
+    task_inner.sched.slice_expired()
+}
+
+/// Whether the ready queue is non-empty
+pub fn has_ready_task() -> bool {
+    !TASK_MANAGER.lock().ready_queue.is_empty()
 }
 
 /// Set a task to stop-wait status, waiting for its kernel stack out of use.
 pub fn add_stopping_task(task: Arc<TaskControlBlock>) {
-    TASK_MANAGER
-        .exclusive_access(file!(), line!())
-        .add_stop(task);
+    TASK_MANAGER.lock().add_stop(task);
 }
 
 /// Get process by pid
 pub fn pid2process(pid: usize) -> Option<Arc<TaskControlBlock>> {
-    let map = PID2PCB.exclusive_access(file!(), line!());
+    let map = PID2PCB.lock();
     map.get(&pid).map(Arc::clone)
 }
 
+/// Every live process whose group leader is global pid `pgid`
+pub fn processes_in_group(pgid: usize) -> Vec<Arc<TaskControlBlock>> {
+    all_processes()
+        .into_iter()
+        .filter(|process| process.inner_exclusive_access().pgid == pgid)
+        .collect()
+}
+
+/// Every live process
+pub fn all_processes() -> Vec<Arc<TaskControlBlock>> {
+    PID2PCB.lock().values().cloned().collect()
+}
+
 /// Insert item(pid, pcb) into PID2PCB map (called by do_fork AND ProcessControlBlock::new)
 pub fn insert_into_pid2process(pid: usize, task: Arc<TaskControlBlock>) {
-    PID2PCB.exclusive_access(file!(), line!()).insert(pid, task);
+    PID2PCB.lock().insert(pid, task);
 }
 
 /// Remove item(pid, _some_pcb) from PDI2PCB map (called by exit_current_and_run_next)
 pub fn remove_from_pid2process(pid: usize) {
-    let mut map = PID2PCB.exclusive_access(file!(), line!());
+    let mut map = PID2PCB.lock();
     if map.remove(&pid).is_none() {
         panic!("cannot find pid {} in pid2task!", pid);
     }
@@ -147,8 +320,10 @@ pub fn remove_from_pid2process(pid: usize) {
 #[allow(unused)]
 pub fn unblock_task(task: Arc<TaskControlBlock>) {
     // println!("[unblock_task] unblock thread");
-    let mut task_manager = TASK_MANAGER.exclusive_access(file!(), line!());
-    task.inner_exclusive_access(file!(), line!()).task_status = TaskStatus::Ready;
+    task.inner_exclusive_access().task_status = TaskStatus::Ready;
+    let floor = TASK_MANAGER.lock().vruntime_floor();
+    let ready = ReadyTask::new(task.clone(), floor);
+    let mut task_manager = TASK_MANAGER.lock();
     if let Some((idx, t)) = task_manager
         .block_queue
         .iter()
@@ -156,6 +331,23 @@ pub fn unblock_task(task: Arc<TaskControlBlock>) {
         .find(|(_, t)| Arc::ptr_eq(t, &task))
     {
         task_manager.block_queue.remove(idx);
-        task_manager.ready_queue.push_front(task);
+        task_manager.ready_queue.push_front(ready);
     }
 }
+
+#[allow(unused)]
+/// Test add/remove/fetch of the ready queue
+pub fn scheduler_test() {
+    let task = super::INITPROC.clone();
+    // 使用独立的 manager，不影响全局就绪队列
+    let mut manager = TaskManager::new();
+    assert!(manager.fetch().is_none());
+    manager.add(ReadyTask::new(task.clone(), 0));
+    manager.add(ReadyTask::new(task.clone(), 0));
+    manager.remove(task.clone());
+    assert!(manager.ready_queue.len() == 1);
+    let fetched = manager.fetch().unwrap();
+    assert!(Arc::ptr_eq(&fetched, &task));
+    assert!(manager.fetch().is_none());
+    info!("scheduler test passed!");
+}
@@ -4,28 +4,62 @@
 //! (such as syscall or clock interrupt).
 //! By suspending or exiting the current task, you can
 //! modify the task state, manage the task queue through TASK_MANAGER (in task/manager.rs) ,
-//! and switch the control flow through PROCESSOR (in task/processor.rs) .
+//! and switch the control flow through the PROCESSORS of each hart (in task/processor.rs) .
 //!
 //! Be careful when you see [`__switch`]. Control flow around this function
 //! might not be what you expect.
+//!
+//! # Lock order
+//!
+//! 所有 hart 都参与调度，需要同时持有多把锁时按下面的顺序获取，前面的先拿：
+//!
+//! 1. 任务的 `inner`。同时锁两个任务时先锁父进程（祖先）或线程组的 leader，再锁子进程或线程；
+//!    退出时过继子进程要先放开自己的锁再去锁收养者。
+//! 2. 任务持有的资源：`memory_set`、`fd_table`、`signal_actions`。
+//! 3. 全局的表和队列：`TASK_MANAGER`、`PID2PCB`、定时器和各个等待队列。持有它们时不再锁任务，
+//!    [`add_task`] 和 [`wakeup_task`] 会锁传入的任务，调用者不能持有这个任务的锁。
+//!
+//! 每个 hart 的 `Processor` 只有这个 hart 自己会锁，不参与排序，但持有它时不能调用
+//! [`current_task`]。
 
+pub mod cgroup;
+mod checkpoint;
 mod context;
+pub mod cred;
 mod manager;
+pub mod pid_ns;
 pub mod process;
 mod processor;
 mod res;
+pub mod resource;
+pub mod seccomp;
 pub mod sigaction;
+mod sigframe;
 pub mod signal;
 mod switch;
 #[allow(clippy::module_inception)]
 mod task;
 
 use alloc::{sync::Arc, vec::Vec};
+use core::arch::asm;
 
 pub use context::TaskContext;
 use lazy_static::*;
 use manager::{add_stopping_task, fetch_task};
-pub use manager::{add_task, pid2process, remove_from_pid2process, remove_task, wakeup_task};
+pub use manager::{
+    add_task,
+    all_processes,
+    has_ready_task,
+    pid2process,
+    processes_in_group,
+    remove_from_pid2process,
+    remove_task,
+    scheduler_test,
+    scheduler_tick,
+    wakeup_task,
+    NICE_MAX,
+    NICE_MIN,
+};
 pub use process::{CloneFlags, CSIGNAL};
 pub use processor::{
     current_kstack_top,
@@ -35,20 +69,28 @@ pub use processor::{
     current_trap_cx,
     current_trap_cx_user_va,
     current_user_token,
+    hart_id,
     run_tasks,
     schedule,
     take_current_task,
+    try_current_task,
 };
-pub use res::{kstack_alloc, pid_alloc, KernelStack, PidHandle, IDLE_PID};
+pub use res::{kstack_alloc, kstack_guard_owner, pid_alloc, KernelStack, PidHandle, IDLE_PID};
+use riscv::register::{satp, sstatus};
+use sigframe::stop_signals;
+pub use sigframe::{handle_signals, restore_signal_frame, signal_pending};
+use signal::SaFlags;
 pub use signal::SignalFlags;
 use switch::__switch;
-pub use task::{TaskControlBlock, TaskStatus};
+pub use task::{TaskControlBlock, TaskControlBlockInner, TaskStatus};
 
 use self::manager::add_block_task;
 use crate::{
-    fs::{defs::OpenFlags, open_file, ROOT_INODE},
+    block::block_cache::block_cache_flusher,
+    fs::{defs::OpenFlags, fd::FdTable, open_file, ROOT_INODE},
     sbi::shutdown,
-    timer::remove_timer,
+    sync::SpinNoIrqLock,
+    timer::{clear_real_timer, remove_timer},
 };
 
 /// Make current task suspended and switch to the next task
@@ -59,9 +101,10 @@ pub fn suspend_current_and_run_next() {
     );
     // There must be an application running.
     let task = take_current_task().unwrap();
+    task.kstack.check_canary(task.pid.0);
 
     // ---- access current TCB exclusively
-    let mut task_inner = task.inner_exclusive_access(file!(), line!());
+    let mut task_inner = task.inner_exclusive_access();
     let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
     // Change status to Ready
     task_inner.task_status = TaskStatus::Ready;
@@ -80,15 +123,117 @@ pub fn block_current_and_run_next() {
         "kernel: pid[{}] block_current_and_run_next",
         current_task().unwrap().pid.0
     );
-    let task = take_current_task().unwrap();
-    let mut task_inner = task.inner_exclusive_access(file!(), line!());
+    let task = current_task().unwrap();
+    task.kstack.check_canary(task.pid.0);
+    let mut task_inner = task.inner_exclusive_access();
+    if task_inner.task_status == TaskStatus::Ready {
+        // 决定阻塞之后、真正阻塞之前已经被 wakeup_task 唤醒
+        task_inner.task_status = TaskStatus::Running;
+        return;
+    }
     let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
     task_inner.task_status = TaskStatus::Blocked;
     drop(task_inner);
+    take_current_task();
     add_block_task(task);
     schedule(task_cx_ptr);
 }
 
+/// [`block_current_and_run_next`] for a syscall that may be accessing the user buffers
+/// of the current task directly, such as sys_read
+///
+/// 其他任务运行时会换掉 satp、清除 sstatus.SUM，回来后恢复。
+pub fn block_current_in_syscall() {
+    let token = satp::read().bits();
+    let sum = sstatus::read().sum();
+    block_current_and_run_next();
+    unsafe {
+        satp::write(token);
+        asm!("sfence.vma");
+        if sum {
+            sstatus::set_sum();
+        } else {
+            sstatus::clear_sum();
+        }
+    }
+}
+
+/// Stop the current task for the stop signal `signo` until SIGCONT or SIGKILL arrives
+///
+/// 只有取出这个信号的任务会停下，同一线程组的其他线程照常运行。
+pub fn stop_current_and_run_next(signo: usize) {
+    trace!(
+        "kernel: pid[{}] stop_current_and_run_next",
+        current_task().unwrap().pid.0
+    );
+    let task = current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    if task_inner
+        .signals
+        .intersects(SignalFlags::SIGCONT | SignalFlags::SIGKILL)
+    {
+        return;
+    }
+    task_inner.stop_report = Some(signo);
+    task_inner.continue_report = false;
+    task_inner.task_status = TaskStatus::Stopped;
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    drop(task_inner);
+    notify_parent(&task, true);
+    // 从这里起 send_signal 可能已经把任务放回就绪队列，run_tasks 会等它换出
+    take_current_task();
+    schedule(task_cx_ptr);
+}
+
+/// Send `flag` to `task`, resuming it if it is stopped and `flag` is SIGCONT or SIGKILL
+pub fn send_signal(task: &Arc<TaskControlBlock>, flag: SignalFlags) {
+    let stop = stop_signals();
+    let mut task_inner = task.inner_exclusive_access();
+    // SIGCONT 丢弃还没处理的停止信号，反过来也一样
+    if flag.contains(SignalFlags::SIGCONT) {
+        task_inner.signals.remove(stop);
+    }
+    if flag.intersects(stop) {
+        task_inner.signals.remove(SignalFlags::SIGCONT);
+    }
+    task_inner.signals |= flag;
+    if task_inner.task_status != TaskStatus::Stopped
+        || !flag.intersects(SignalFlags::SIGCONT | SignalFlags::SIGKILL)
+    {
+        return;
+    }
+    task_inner.task_status = TaskStatus::Ready;
+    task_inner.stop_report = None;
+    task_inner.continue_report = flag.contains(SignalFlags::SIGCONT);
+    drop(task_inner);
+    add_task(Arc::clone(task));
+    notify_parent(task, false);
+}
+
+/// Tell the parent of `task` that it stopped or continued: SIGCHLD, unless the parent
+/// asked not to with SA_NOCLDSTOP, and wake up its wait4
+fn notify_parent(task: &Arc<TaskControlBlock>, stopped: bool) {
+    let parent = task
+        .inner_exclusive_access()
+        .parent
+        .as_ref()
+        .and_then(|parent| parent.upgrade());
+    let Some(parent) = parent else {
+        return;
+    };
+    // 和退出时一样在父进程的锁里修改，wait4 持有这把锁检查子进程，不会错过唤醒
+    let mut parent_inner = parent.inner_exclusive_access();
+    // 17: SIGCHLD
+    let nocldstop = parent_inner.signal_actions.lock().table[17]
+        .sa_flags
+        .contains(SaFlags::SA_NOCLDSTOP);
+    if !(stopped && nocldstop) {
+        parent_inner.signals |= SignalFlags::SIGCHLD;
+    }
+    drop(parent_inner);
+    parent.child_exit.wake_all();
+}
+
 /// Exit the current 'Running' task and run the next task in task list.
 pub fn exit_current_and_run_next(exit_code: i32) {
     trace!(
@@ -100,9 +245,12 @@ pub fn exit_current_and_run_next(exit_code: i32) {
         current_task().unwrap().pid.0,
         exit_code
     );
+    // CLONE_CHILD_CLEARTID：地址空间还在使用，清零 tid 并唤醒等待的 pthread_join
+    current_task().unwrap().release_child_tid();
     // take from Processor
     let task = take_current_task().unwrap();
-    let mut task_inner = task.inner_exclusive_access(file!(), line!());
+    task.kstack.check_canary(task.pid.0);
+    let mut task_inner = task.inner_exclusive_access();
     let tid = task.tid;
     // here we do not remove the thread since we are still using the kstack
     // it will be deallocated when sys_waittid is called
@@ -145,18 +293,27 @@ pub fn exit_current_and_run_next(exit_code: i32) {
         task_inner.is_zombie = true;
         // record exit code of main process
         task_inner.exit_code = Some(exit_code);
+        let parent = task_inner
+            .parent
+            .as_ref()
+            .and_then(|parent| parent.upgrade());
 
+        // pid 1 of a pid namespace takes every process in it down with it
+        let pid_ns = task.pid_ns();
+        if pid_ns
+            .init()
+            .map_or(false, |init| Arc::ptr_eq(&init, &task))
         {
-            // move all child processes under init process
-            let mut initproc_inner = INITPROC.inner_exclusive_access(file!(), line!());
-            for child in task_inner.children.iter() {
-                println!("kernel: move child process {} to initproc", child.pid.0);
-                child.inner_exclusive_access(file!(), line!()).parent =
-                    Some(Arc::downgrade(&INITPROC));
-                initproc_inner.children.push(child.clone());
+            for member in pid_ns.members().into_iter().filter(|&member| member != pid) {
+                if let Some(member) = pid2process(member) {
+                    send_signal(&member, SignalFlags::SIGKILL);
+                }
             }
         }
 
+        // 子进程等放开自己的锁之后再过继，收养者是祖先，不能在持有子孙的锁时去锁它
+        let children = core::mem::take(&mut task_inner.children);
+
         // deallocate user res (including tid/trap_cx/ustack) of all threads
         // it has to be done before we dealloc the whole memory_set
         // otherwise they will be deallocated twice
@@ -186,21 +343,81 @@ pub fn exit_current_and_run_next(exit_code: i32) {
         // for now to avoid deadlock/double borrow problem.
         drop(task_inner);
 
-        let mut task_inner = task.inner_exclusive_access(file!(), line!());
-        task_inner.children.clear();
-        // deallocate other data in user space i.e. program code/data section
-        task_inner.memory_set.recycle_data_pages();
-        // drop file descriptors
-        task_inner.fd_table.clear();
+        {
+            // move all child processes under the reaper of the pid namespace
+            let reaper = child_reaper(&task);
+            let mut reaper_inner = reaper.inner_exclusive_access();
+            for child in children.iter() {
+                println!(
+                    "kernel: move child process {} to reaper {}",
+                    child.pid.0, reaper.pid.0
+                );
+                child.inner_exclusive_access().parent = Some(Arc::downgrade(&reaper));
+                reaper_inner.children.push(child.clone());
+            }
+            drop(reaper_inner);
+            // 过继来的子进程里可能已经有僵尸
+            if !children.is_empty() {
+                reaper.child_exit.wake_all();
+            }
+        }
+
+        let mut task_inner = task.inner_exclusive_access();
         // remove all threads
         task_inner.threads.clear();
+        // deallocate other data in user space i.e. program code/data section,
+        // unless a task cloned with CLONE_VM is still using it
+        if Arc::strong_count(&task_inner.memory_set) == 1 {
+            task_inner.memory_set.lock().recycle_data_pages();
+        }
+        // drop file descriptors, the table itself may be shared through CLONE_FILES
+        task_inner.fd_table = Arc::new(SpinNoIrqLock::new(FdTable::default()));
         drop(task_inner);
+        // a pending SIGALRM would keep the zombie in the timer wheel
+        clear_real_timer(&task);
+        // 在父进程的锁里发 SIGCHLD：父进程的 wait4 持有这把锁检查子进程并入队，
+        // 之后再唤醒就不会错过
+        if let Some(parent) = parent {
+            parent.inner_exclusive_access().signals |= SignalFlags::SIGCHLD;
+            parent.child_exit.wake_all();
+        }
+    } else {
+        // 线程退出：离开线程组，释放它在共享地址空间里的中断上下文和用户栈
+        task_inner.is_zombie = true;
+        task_inner.exit_code = Some(exit_code);
+        task_inner.fd_table = Arc::new(SpinNoIrqLock::new(FdTable::default()));
+        drop(task_inner);
+        let leader = task.group_leader();
+        if !Arc::ptr_eq(&leader, &task) {
+            let mut leader_inner = leader.inner_exclusive_access();
+            for thread in leader_inner.threads.iter_mut() {
+                if thread.as_ref().map_or(false, |t| Arc::ptr_eq(t, &task)) {
+                    *thread = None;
+                }
+            }
+        }
+        task.dealloc_user_res();
     }
     // we do not have to save task context
     let mut _unused = TaskContext::zero_init();
     schedule(&mut _unused as *mut _);
 }
 
+/// Who adopts the orphans of the exiting `task`: the init of the nearest pid
+/// namespace that is still running, the initial process at last
+fn child_reaper(task: &Arc<TaskControlBlock>) -> Arc<TaskControlBlock> {
+    let mut pid_ns = Some(task.pid_ns());
+    while let Some(ns) = pid_ns {
+        if let Some(init) = ns.init() {
+            if !Arc::ptr_eq(&init, task) && !init.inner_exclusive_access().is_zombie {
+                return init;
+            }
+        }
+        pid_ns = ns.parent();
+    }
+    INITPROC.clone()
+}
+
 lazy_static! {
     /// Creation of initial process
     ///
@@ -223,7 +440,9 @@ lazy_static! {
 ///Add init process to the manager
 pub fn add_initproc() {
     debug!("kernel: add_initproc");
-    let _initproc = INITPROC.clone();
+    let initproc = INITPROC.clone();
+    // 后台写回块缓存的内核线程挂在 initproc 的线程组里
+    initproc.spawn_kernel_thread(block_cache_flusher, 0);
 }
 
 /// Run all files in the root directory
@@ -238,17 +457,10 @@ pub fn add_initproc() {
 //     debug!("PCB created: {}", file);
 // }
 
-/// Check if the current task has any signal to handle
-pub fn check_signals_of_current() -> Option<(i32, &'static str)> {
-    let task = current_task().unwrap();
-    let task_inner = task.inner_exclusive_access(file!(), line!());
-    task_inner.signals.check_error()
-}
-
 /// Add signal to the current task
 pub fn current_add_signal(signal: SignalFlags) {
     let task = current_task().unwrap();
-    let mut task_inner = task.inner_exclusive_access(file!(), line!());
+    let mut task_inner = task.inner_exclusive_access();
     task_inner.signals |= signal;
 }
 
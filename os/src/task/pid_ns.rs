@@ -0,0 +1,163 @@
+//! PID namespaces, created by `clone(CLONE_NEWPID)`
+//!
+//! 全局 pid（[`PidHandle`](super::PidHandle)）仍是内核内部的唯一标识，trap 上下文的位置、
+//! `pid2process` 都依赖它。每个非根命名空间另有自己的 pid 分配器，进程在所在命名空间和
+//! 所有祖先命名空间中各有一个 pid，系统调用按调用者所在的命名空间翻译。
+//! 根命名空间中的 pid 就是全局 pid。
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use lazy_static::*;
+
+use super::{manager::pid2process, res::RecycleAllocator, TaskControlBlock};
+use crate::sync::SpinNoIrqLock;
+
+pub struct PidNamespace {
+    parent: Option<Arc<PidNamespace>>,
+    /// 0 for the root namespace
+    level:  usize,
+    inner:  SpinNoIrqLock<PidNamespaceInner>,
+}
+
+struct PidNamespaceInner {
+    /// hands out `nr - 1`, so the first process gets pid 1
+    allocator: RecycleAllocator,
+    /// pid in this namespace -> global pid
+    pids:      BTreeMap<usize, usize>,
+    /// pid 1 of the namespace, reaps its orphans
+    init:      Option<Weak<TaskControlBlock>>,
+}
+
+lazy_static! {
+    /// The namespace of the initial process
+    pub static ref ROOT_PID_NS: Arc<PidNamespace> = PidNamespace::new(None);
+}
+
+impl PidNamespace {
+    fn new(parent: Option<Arc<PidNamespace>>) -> Arc<Self> {
+        Arc::new(Self {
+            level: parent.as_ref().map_or(0, |parent| parent.level + 1),
+            parent,
+            inner: SpinNoIrqLock::new(PidNamespaceInner {
+                allocator: RecycleAllocator::new(),
+                pids:      BTreeMap::new(),
+                init:      None,
+            }),
+        })
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.level == 0
+    }
+
+    /// The global pid of `nr` in this namespace, zombies included
+    pub fn global_pid(&self, nr: usize) -> Option<usize> {
+        if self.is_root() {
+            return Some(nr);
+        }
+        let inner = self.inner.lock();
+        inner.pids.get(&nr).copied()
+    }
+
+    /// The pid of the task with global pid `pid` as seen from this namespace
+    pub fn pid_of(&self, pid: usize) -> Option<usize> {
+        if self.is_root() {
+            return Some(pid);
+        }
+        let inner = self.inner.lock();
+        inner
+            .pids
+            .iter()
+            .find(|(_, &global)| global == pid)
+            .map(|(&nr, _)| nr)
+    }
+
+    /// Look up a live process by its pid in this namespace
+    pub fn find(&self, nr: usize) -> Option<Arc<TaskControlBlock>> {
+        pid2process(self.global_pid(nr)?)
+    }
+
+    /// Global pids of every task in this namespace and its descendants
+    pub fn members(&self) -> Vec<usize> {
+        let inner = self.inner.lock();
+        inner.pids.values().copied().collect()
+    }
+
+    /// The init process of this namespace, `None` once it has exited
+    pub fn init(&self) -> Option<Arc<TaskControlBlock>> {
+        let inner = self.inner.lock();
+        inner.init.as_ref().and_then(Weak::upgrade)
+    }
+
+    pub fn set_init(&self, task: &Arc<TaskControlBlock>) {
+        self.inner.lock().init = Some(Arc::downgrade(task));
+    }
+
+    pub fn parent(&self) -> Option<Arc<PidNamespace>> {
+        self.parent.clone()
+    }
+}
+
+/// A task's pid in one non-root namespace, released on drop
+pub struct NsPid {
+    ns: Arc<PidNamespace>,
+    nr: usize,
+}
+
+impl Drop for NsPid {
+    fn drop(&mut self) {
+        let mut inner = self.ns.inner.lock();
+        inner.pids.remove(&self.nr);
+        inner.allocator.dealloc(self.nr - 1);
+    }
+}
+
+/// Pids of a task in every non-root namespace it belongs to, outermost first
+pub struct NsPids(Vec<NsPid>);
+
+impl NsPids {
+    /// pids of a task living in the root namespace
+    pub fn root() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Allocate pids for global pid `pid` in `ns` and all its non-root ancestors
+    pub fn alloc(ns: &Arc<PidNamespace>, pid: usize) -> Self {
+        let mut chain = Vec::new();
+        let mut ns = Some(ns.clone());
+        while let Some(cur) = ns.filter(|ns| !ns.is_root()) {
+            ns = cur.parent();
+            chain.push(cur);
+        }
+        let pids = chain
+            .into_iter()
+            .rev()
+            .map(|ns| {
+                let nr = {
+                    let mut inner = ns.inner.lock();
+                    let nr = inner.allocator.alloc() + 1;
+                    inner.pids.insert(nr, pid);
+                    nr
+                };
+                NsPid { ns, nr }
+            })
+            .collect();
+        Self(pids)
+    }
+
+    /// The namespace the task lives in
+    pub fn ns(&self) -> Arc<PidNamespace> {
+        self.0
+            .last()
+            .map_or_else(|| ROOT_PID_NS.clone(), |ns_pid| ns_pid.ns.clone())
+    }
+
+    /// A new namespace below the task's own
+    pub fn new_child_ns(&self) -> Arc<PidNamespace> {
+        PidNamespace::new(Some(self.ns()))
+    }
+}
@@ -43,6 +43,8 @@
 
 */
 
+use crate::mm::PTEFlags;
+
 #[allow(unused)]
 #[allow(missing_docs)]
 pub const CSIGNAL: usize = 0x000000ff; /* signal mask to be sent at exit */
@@ -93,6 +95,47 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// `prot` of mmap and mprotect
+    pub struct MmapProt: u32 {
+        const PROT_READ = 1;
+        const PROT_WRITE = 2;
+        const PROT_EXEC = 4;
+    }
+}
+
+impl MmapProt {
+    /// Flags of a user page with this protection
+    ///
+    /// RISC-V 没有只写的页，可写的页同时可读；不可访问的页去掉 U 位，V 和 R 保留，
+    /// 否则会被当成指向下一级页表的表项。
+    pub fn pte_flags(self) -> PTEFlags {
+        if self.is_empty() {
+            return PTEFlags::R;
+        }
+        let mut flags = PTEFlags::U;
+        if self.intersects(MmapProt::PROT_READ | MmapProt::PROT_WRITE) {
+            flags |= PTEFlags::R;
+        }
+        if self.contains(MmapProt::PROT_WRITE) {
+            flags |= PTEFlags::W;
+        }
+        if self.contains(MmapProt::PROT_EXEC) {
+            flags |= PTEFlags::X;
+        }
+        flags
+    }
+}
+
+bitflags! {
+    /// flags of msync
+    pub struct MsyncFlags: u32 {
+        const MS_ASYNC = 1;
+        const MS_INVALIDATE = 2;
+        const MS_SYNC = 4;
+    }
+}
+
 bitflags! {
     pub struct Flags: u32 {
         const MAP_SHARED = 0x01;
@@ -119,7 +162,7 @@ bitflags! {
 //     /// immutable
 //     pub pid: PidHandle,
 //     /// mutable
-//     inner: UPSafeCell<ProcessControlBlockInner>,
+//     inner: SpinNoIrqLock<ProcessControlBlockInner>,
 // }
 
 // /// Inner of Process Control Block
@@ -225,8 +268,8 @@ bitflags! {
 //         let mut children_kernel_clock: usize = 0;
 //         let mut children_user_clock: usize = 0;
 //         for c in &self.children {
-//             children_kernel_clock += c.inner_exclusive_access(file!(), line!()).kernel_clock;
-//             children_user_clock += c.inner_exclusive_access(file!(), line!()).user_clock;
+//             children_kernel_clock += c.inner_exclusive_access().kernel_clock;
+//             children_user_clock += c.inner_exclusive_access().user_clock;
 //         }
 //         (children_kernel_clock as i64, children_user_clock as i64)
 //     }
@@ -275,7 +318,7 @@ bitflags! {
 // impl ProcessControlBlock {
 //     /// inner_exclusive_access
 //     pub fn inner_exclusive_access(&self) -> RefMut<'_, ProcessControlBlockInner> {
-//         self.inner.exclusive_access(file!(), line!())
+//         self.inner.lock()
 //     }
 //     /// new process from elf file
 //     pub fn new(elf_data: &[u8]) -> Arc<Self> {
@@ -288,7 +331,7 @@ bitflags! {
 //         let process = Arc::new(Self {
 //             pid: pid_handle,
 //             inner: unsafe {
-//                 UPSafeCell::new(ProcessControlBlockInner {
+//                 SpinNoIrqLock::new(ProcessControlBlockInner {
 //                     is_zombie: false,
 //                     memory_set,
 //                     parent: None,
@@ -328,7 +371,7 @@ bitflags! {
 //         ));
 //         info!("TaskControlBlock create completed");
 //         // prepare trap_cx of main thread
-//         let task_inner = task.inner_exclusive_access(file!(), line!());
+//         let task_inner = task.inner_exclusive_access();
 //         let trap_cx = task.get_trap_cx();
 //         let ustack_top = task_inner.user_stack_top;
 //         let kstack_top = task.kstack.get_top();
@@ -338,13 +381,13 @@ bitflags! {
 //         *trap_cx = TrapContext::app_init_context(
 //             entry_point,
 //             ustack_top,
-//             KERNEL_SPACE.exclusive_access(file!(), line!()).token(),
+//             KERNEL_SPACE.lock().token(),
 //             kstack_top,
 //             trap_handler as usize,
 //         );
 //         debug!("TrapContext completed");
 //         // add main thread to the process
-//         let mut process_inner = process.inner_exclusive_access(file!(), line!());
+//         let mut process_inner = process.inner_exclusive_access();
 //         process_inner.tasks.push(Some(Arc::clone(&task)));
 //         process_inner.finish.push(false);
 //         drop(process_inner);
@@ -366,7 +409,7 @@ bitflags! {
 //         let process = Arc::new(Self {
 //             pid: pid_handle,
 //             inner: unsafe {
-//                 UPSafeCell::new(ProcessControlBlockInner {
+//                 SpinNoIrqLock::new(ProcessControlBlockInner {
 //                     is_zombie: false,
 //                     memory_set,
 //                     parent: None,
@@ -409,7 +452,7 @@ bitflags! {
 //         ));
 //         info!("init TaskControlBlock create completed");
 //         // prepare trap_cx of main thread
-//         let task_inner = task.inner_exclusive_access(file!(), line!());
+//         let task_inner = task.inner_exclusive_access();
 //         let trap_cx = task.get_trap_cx();
 //         let ustack_top = task_inner.user_stack_top;
 //         let kstack_top = task.kstack.get_top();
@@ -419,12 +462,12 @@ bitflags! {
 //         *trap_cx = TrapContext::app_init_context(
 //             entry_point,
 //             ustack_top,
-//             KERNEL_SPACE.exclusive_access(file!(), line!()).token(),
+//             KERNEL_SPACE.lock().token(),
 //             kstack_top,
 //             trap_handler as usize,
 //         );
 //         // add main thread to the process
-//         let mut process_inner = process.inner_exclusive_access(file!(), line!());
+//         let mut process_inner = process.inner_exclusive_access();
 //         process_inner.tasks.push(Some(Arc::clone(&task)));
 //         process_inner.finish.push(false);
 //         drop(process_inner);
@@ -440,28 +483,28 @@ bitflags! {
 //     /// Only support processes with a single thread.
 //     pub fn exec(self: &Arc<Self>, elf_data: &[u8], args: Vec<String>) {
 //         trace!("kernel: exec");
-//         assert_eq!(self.inner_exclusive_access(file!(), line!()).thread_count(), 1);
+//         assert_eq!(self.inner_exclusive_access().thread_count(), 1);
 //         // memory_set with elf program headers/trampoline/trap context/user stack
 //         trace!("kernel: exec .. MemorySet::from_elf");
 //         let (memory_set, user_heap_base, ustack_top, entry_point) = MemorySet::from_elf(elf_data);
 //         // substitute memory_set
 //         trace!("kernel: exec .. substitute memory_set");
 //         let old_memory_set =
-//             mem::replace(&mut self.inner_exclusive_access(file!(), line!()).memory_set, memory_set);
+//             mem::replace(&mut self.inner_exclusive_access().memory_set, memory_set);
 //         // set heap position
-//         self.inner_exclusive_access(file!(), line!()).heap_base = user_heap_base.into();
-//         self.inner_exclusive_access(file!(), line!()).heap_end = user_heap_base.into();
+//         self.inner_exclusive_access().heap_base = user_heap_base.into();
+//         self.inner_exclusive_access().heap_end = user_heap_base.into();
 //         // then we alloc user resource for main thread again
 //         // since memory_set has been changed
 //         trace!("kernel: exec .. alloc user resource for main thread again");
-//         let task = self.inner_exclusive_access(file!(), line!()).get_task(0);
-//         let mut task_inner = task.inner_exclusive_access(file!(), line!());
+//         let task = self.inner_exclusive_access().get_task(0);
+//         let mut task_inner = task.inner_exclusive_access();
 //         task_inner.user_stack_top = ustack_top;
 
 //         // 想要为另一个向另一个页表的虚拟地址写入trap_cx，需要把另一个页表中断上下文的物理地址映射到
 //         // 当前进程的页表中，这样才能在当前进程的页表中找到trap_cx，这里我们映射到TRAP_CX_TRAMPOLINE
 //         let user_trap_ppn = {
-//             let mut process_inner = self.inner_exclusive_access(file!(), line!());
+//             let mut process_inner = self.inner_exclusive_access();
 //             // alloc user stack
 //             let ustack_top = task_inner.user_stack_top;
 //             let ustack_bottom = ustack_top - USER_STACK_SIZE;
@@ -510,7 +553,7 @@ bitflags! {
 
 //         task_inner.task_cx = TaskContext::goto_user_entry(task.kstack.get_top());
 //         task_inner.trap_cx_ppn = {
-//             let process_inner = self.inner_exclusive_access(file!(), line!());
+//             let process_inner = self.inner_exclusive_access();
 //             let trap_cx_bottom_va: VirtAddr = trap_cx_bottom_from_tid(task.tid).into();
 //             debug!(
 //                 "trap_cx_ppn = {:#x}",
@@ -573,7 +616,7 @@ bitflags! {
 //         let mut trap_cx = TrapContext::app_init_context(
 //             entry_point,
 //             user_sp,
-//             KERNEL_SPACE.exclusive_access(file!(), line!()).token(),
+//             KERNEL_SPACE.lock().token(),
 //             task.kstack.get_top(),
 //             trap_handler as usize,
 //         );
@@ -585,7 +628,7 @@ bitflags! {
 //     /// Only support processes with a single thread.
 //     pub fn fork(self: &Arc<Self>) -> usize {
 //         trace!("kernel: sys_fork");
-//         let mut parent = self.inner_exclusive_access(file!(), line!());
+//         let mut parent = self.inner_exclusive_access();
 //         assert_eq!(parent.thread_count(), 1);
 //         let kstack = kstack_alloc();
 //         // clone parent's memory_set completely including trampoline/ustacks/trap_cxs
@@ -605,7 +648,7 @@ bitflags! {
 //         let child = Arc::new(Self {
 //             pid,
 //             inner: unsafe {
-//                 UPSafeCell::new(ProcessControlBlockInner {
+//                 SpinNoIrqLock::new(ProcessControlBlockInner {
 //                     is_zombie: false,
 //                     memory_set,
 //                     parent: Some(Arc::downgrade(self)),
@@ -633,20 +676,20 @@ bitflags! {
 //         // create main thread of child process
 //         let task = Arc::new(TaskControlBlock::new(
 //             Arc::clone(&child),
-//             parent.get_task(0).inner_exclusive_access(file!(), line!()).user_stack_top,
+//             parent.get_task(0).inner_exclusive_access().user_stack_top,
 //             // here we do not allocate trap_cx or ustack again
 //             // but mention that we allocate a new kstack here
 //             kstack,
 //             false,
 //         ));
 //         // attach task to child process
-//         let mut child_inner = child.inner_exclusive_access(file!(), line!());
+//         let mut child_inner = child.inner_exclusive_access();
 //         child_inner.tasks.push(Some(Arc::clone(&task)));
 //         child_inner.finish.push(false);
 //         drop(child_inner);
 
 //         // modify kstack_top in trap_cx of this thread
-//         let task_inner = task.inner_exclusive_access(file!(), line!());
+//         let task_inner = task.inner_exclusive_access();
 //         let trap_cx = task.get_trap_cx();
 //         // trap_cx.kernel_sp = task.kstack.get_top();
 //         trap_cx.x[10] = 0;
@@ -674,7 +717,7 @@ bitflags! {
 //         let thread_stack_top = if stack_ptr != 0 {
 //             stack_ptr
 //         } else {
-//             task.inner_exclusive_access(file!(), line!()).user_stack_top
+//             task.inner_exclusive_access().user_stack_top
 //         };
 //         let kstack = kstack_alloc(); //todo 这一行正确性未知，为了解决先赋值内核页表再映射内核栈问题
 //         let new_task = Arc::new(TaskControlBlock::new(
@@ -683,9 +726,9 @@ bitflags! {
 //             kstack,
 //             true,
 //         ));
-//         let new_task_inner = new_task.inner_exclusive_access(file!(), line!());
+//         let new_task_inner = new_task.inner_exclusive_access();
 //         let new_task_tid = new_task.tid;
-//         let mut process_inner = process.inner_exclusive_access(file!(), line!());
+//         let mut process_inner = process.inner_exclusive_access();
 //         // add new thread to current process
 //         let tasks = &mut process_inner.tasks;
 //         while tasks.len() < new_task_tid + 1 {
@@ -719,7 +762,7 @@ bitflags! {
 
 //     pub fn fork2(self: &Arc<Self>, stack_ptr: usize) -> usize {
 //         trace!("kernel: sys_fork2");
-//         let mut parent = self.inner_exclusive_access(file!(), line!());
+//         let mut parent = self.inner_exclusive_access();
 //         assert_eq!(parent.thread_count(), 1);
 //         // clone parent's memory_set completely including trampoline/ustacks/trap_cxs
 //         let kstack = kstack_alloc();
@@ -739,7 +782,7 @@ bitflags! {
 //         let child = Arc::new(Self {
 //             pid,
 //             inner: unsafe {
-//                 UPSafeCell::new(ProcessControlBlockInner {
+//                 SpinNoIrqLock::new(ProcessControlBlockInner {
 //                     is_zombie: false,
 //                     memory_set,
 //                     parent: Some(Arc::downgrade(self)),
@@ -767,20 +810,20 @@ bitflags! {
 //         // create main thread of child process
 //         let task = Arc::new(TaskControlBlock::new(
 //             Arc::clone(&child),
-//             parent.get_task(0).inner_exclusive_access(file!(), line!()).user_stack_top,
+//             parent.get_task(0).inner_exclusive_access().user_stack_top,
 //             // here we do not allocate trap_cx or ustack again
 //             // but mention that we allocate a new kstack here
 //             kstack,
 //             false,
 //         ));
 //         // attach task to child process
-//         let mut child_inner = child.inner_exclusive_access(file!(), line!());
+//         let mut child_inner = child.inner_exclusive_access();
 //         child_inner.tasks.push(Some(Arc::clone(&task)));
 //         child_inner.finish.push(false);
 //         drop(child_inner);
 
 //         // modify kstack_top in trap_cx of this thread
-//         let task_inner = task.inner_exclusive_access(file!(), line!());
+//         let task_inner = task.inner_exclusive_access();
 //         let trap_cx = task.get_trap_cx();
 //         trap_cx.kernel_sp = task.kstack.get_top();
 //         trap_cx.x[10] = 0;
@@ -5,15 +5,17 @@
 //! and the replacement and transfer of control flow of different applications are executed.
 
 use alloc::sync::Arc;
+use core::{arch::asm, hint::spin_loop, sync::atomic::Ordering};
 
 use lazy_static::*;
 use riscv::register::{satp, sstatus};
 
 use super::{__switch, fetch_task, switch::__schedule, TaskContext, TaskControlBlock, TaskStatus};
 use crate::{
-    config::__breakpoint,
+    config::{__breakpoint, MAX_HARTS},
     mm::{VirtAddr, KERNEL_SPACE},
-    sync::UPSafeCell,
+    smp,
+    sync::SpinNoIrqLock,
     timer::get_time_ms,
     trap::TrapContext,
 };
@@ -51,19 +53,35 @@ impl Processor {
 }
 
 lazy_static! {
-    pub static ref PROCESSOR: UPSafeCell<Processor> = unsafe { UPSafeCell::new(Processor::new()) };
+    /// 每个 hart 一个，只由所在的 hart 访问
+    pub static ref PROCESSORS: [SpinNoIrqLock<Processor>; MAX_HARTS] =
+        core::array::from_fn(|_| SpinNoIrqLock::new(Processor::new()));
+}
+
+/// The processor of the current hart
+fn processor() -> &'static SpinNoIrqLock<Processor> {
+    &PROCESSORS[hart_id() % MAX_HARTS]
 }
 
 ///The main part of process execution and scheduling
 ///Loop `fetch_task` to get the process that needs to run, and switch the process through `__switch`
 pub fn run_tasks() {
     loop {
+        // 空闲时把各 hart 暂存的 console 输出写出去
+        crate::console::flush();
         debug!("start new turn of scheduling");
-        let mut processor = PROCESSOR.exclusive_access(file!(), line!());
+        let mut processor = processor().lock();
         if let Some(task) = fetch_task() {
+            task.kstack.check_canary(task.pid.0);
+            // 别的 hart 刚把它放回就绪队列时可能还没切换出去，等它的上下文保存完
+            while task.on_cpu.load(Ordering::Acquire) {
+                spin_loop();
+            }
+            task.on_cpu.store(true, Ordering::Relaxed);
+            let running = task.clone();
             let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
             // access coming task TCB exclusively
-            let mut task_inner = task.inner_exclusive_access(file!(), line!());
+            let mut task_inner = task.inner_exclusive_access();
             let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
             task_inner.task_status = TaskStatus::Running;
             if task_inner.first_time.is_none() {
@@ -80,6 +98,7 @@ pub fn run_tasks() {
 
             //被调度，开始计算进程时钟时间
             task_inner.clock_time_refresh();
+            task_inner.sched.start();
             // release coming task_inner manually
             drop(task_inner);
             // release coming task TCB manually
@@ -88,9 +107,29 @@ pub fn run_tasks() {
             drop(processor);
             info!("switch task to pid now");
 
+            smp::set_busy(true);
             unsafe {
                 __switch(idle_task_cx_ptr, next_task_cx_ptr);
             }
+            smp::set_busy(false);
+            // 补上最后一次时钟中断之后运行的时间，以及切换出去之前在内核里的时间
+            let mut running_inner = running.inner_exclusive_access();
+            running_inner.sched.update();
+            running_inner.kernel_clock_time_end();
+            drop(running_inner);
+            running.on_cpu.store(false, Ordering::Release);
+        } else if crate::drivers::block_io_pending() {
+            // 所有任务都在等块设备传输，等中断而不是退出
+            drop(processor);
+            smp::idle(crate::drivers::wait_for_irq);
+        } else if crate::timer::timers_pending() {
+            // 所有任务都在睡眠，等最早的定时器到期
+            drop(processor);
+            smp::idle(crate::timer::wait_for_timer);
+        } else if smp::other_harts_busy() {
+            // 别的 hart 上的任务还可能放入新的就绪任务
+            drop(processor);
+            smp::idle(|| unsafe { riscv::asm::wfi() });
         } else {
             return;
         }
@@ -99,12 +138,27 @@ pub fn run_tasks() {
 
 /// Get current task through take, leaving a None in its place
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access(file!(), line!()).take_current()
+    processor().lock().take_current()
 }
 
 /// Get a copy of the current task
 pub fn current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access(file!(), line!()).current()
+    processor().lock().current()
+}
+
+/// Like `current_task`, but return `None` instead of panicking if the processor
+/// is borrowed, for use in the panic handler
+pub fn try_current_task() -> Option<Arc<TaskControlBlock>> {
+    processor().try_lock()?.current()
+}
+
+/// id of the hart the kernel is running on
+///
+/// entry.S 把 SBI 传来的 hart id 放进 tp，用户态的 tp 在陷入和返回时另行保存。
+pub fn hart_id() -> usize {
+    let hart: usize;
+    unsafe { asm!("mv {}, tp", out(reg) hart) };
+    hart
 }
 
 /// get current pid
@@ -145,7 +199,7 @@ pub fn current_kstack_top() -> usize {
 
 /// Return to idle control flow for new scheduling
 pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
-    let mut processor = PROCESSOR.exclusive_access(file!(), line!());
+    let mut processor = processor().lock();
     let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
     drop(processor);
     unsafe {
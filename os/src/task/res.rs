@@ -18,7 +18,7 @@ use crate::{
         USER_STACK_SIZE,
     },
     mm::{MapPermission, PTEFlags, PageTable, PhysPageNum, VirtAddr, KERNEL_SPACE},
-    sync::UPSafeCell,
+    sync::SpinNoIrqLock,
     trap::TrapContext,
 };
 
@@ -59,11 +59,11 @@ impl RecycleAllocator {
 
 lazy_static! {
     /// Glocal allocator for pid
-    static ref PID_ALLOCATOR: UPSafeCell<RecycleAllocator> =
-        unsafe { UPSafeCell::new(RecycleAllocator::new()) };
+    static ref PID_ALLOCATOR: SpinNoIrqLock<RecycleAllocator> =
+        SpinNoIrqLock::new(RecycleAllocator::new());
     /// Global allocator for kernel stack
-    static ref KSTACK_ALLOCATOR: UPSafeCell<RecycleAllocator> =
-        unsafe { UPSafeCell::new(RecycleAllocator::new()) };
+    static ref KSTACK_ALLOCATOR: SpinNoIrqLock<RecycleAllocator> =
+        SpinNoIrqLock::new(RecycleAllocator::new());
 
 }
 
@@ -75,25 +75,42 @@ pub struct PidHandle(pub usize);
 
 /// Allocate a pid for a process
 pub fn pid_alloc() -> PidHandle {
-    PidHandle(PID_ALLOCATOR.exclusive_access(file!(), line!()).alloc())
+    PidHandle(PID_ALLOCATOR.lock().alloc())
 }
 
 impl Drop for PidHandle {
     fn drop(&mut self) {
         trace!("drop pid {}", self.0);
-        PID_ALLOCATOR
-            .exclusive_access(file!(), line!())
-            .dealloc(self.0);
+        PID_ALLOCATOR.lock().dealloc(self.0);
     }
 }
 
 /// Return (bottom, top) of a kernel stack in kernel space.
+///
+/// 每个内核栈下面都留一页不映射的保护页（包括紧挨着物理内存恒等映射的第一个栈），
+/// 栈溢出时的写入在保护页上触发缺页，而不是悄悄改掉下面的内核栈或物理内存。
 pub fn kernel_stack_position(kstack_id: usize) -> (usize, usize) {
-    let bottom = MEMORY_END + kstack_id * (KERNEL_STACK_SIZE + PAGE_SIZE);
+    let bottom = MEMORY_END + PAGE_SIZE + kstack_id * (KERNEL_STACK_SIZE + PAGE_SIZE);
     let top = bottom + KERNEL_STACK_SIZE;
     (bottom, top)
 }
 
+/// Id of the allocated kernel stack whose guard page holds `addr`
+///
+/// 在 trap_from_kernel 里调用，锁被持有时不等待，直接返回 None。
+pub fn kstack_guard_owner(addr: usize) -> Option<usize> {
+    let slot = KERNEL_STACK_SIZE + PAGE_SIZE;
+    let offset = addr.checked_sub(MEMORY_END)?;
+    let kstack_id = offset / slot;
+    let allocated = KSTACK_ALLOCATOR.try_lock()?.current;
+    (offset % slot < PAGE_SIZE && kstack_id < allocated).then_some(kstack_id)
+}
+
+/// pattern written at the bottom of every kernel stack
+const KSTACK_CANARY: usize = 0x57ac_c0de_57ac_c0de;
+/// words of the canary, an overrun has to clobber at least one of them
+const KSTACK_CANARY_WORDS: usize = 4;
+
 /// Kernel stack for a task
 pub struct KernelStack(pub usize);
 
@@ -101,18 +118,28 @@ pub struct KernelStack(pub usize);
 pub fn kstack_alloc() -> KernelStack {
     trace!("kstack_alloc");
 
-    let kstack_id = KSTACK_ALLOCATOR.exclusive_access(file!(), line!()).alloc();
+    let kstack_id = KSTACK_ALLOCATOR.lock().alloc();
     let (kstack_bottom, kstack_top) = kernel_stack_position(kstack_id);
 
-    KERNEL_SPACE
-        .exclusive_access(file!(), line!())
-        .insert_framed_area(
-            kstack_bottom.into(),
-            kstack_top.into(),
-            MapPermission::R | MapPermission::W,
-        );
+    let mut kernel_space = KERNEL_SPACE.lock();
+    let guard_vpn = VirtAddr::from(kstack_bottom - PAGE_SIZE).floor();
+    debug_assert!(
+        kernel_space
+            .translate(guard_vpn)
+            .map_or(true, |pte| !pte.is_valid()),
+        "guard page of kernel stack {} is mapped",
+        kstack_id
+    );
+    kernel_space.insert_framed_area(
+        kstack_bottom.into(),
+        kstack_top.into(),
+        MapPermission::R | MapPermission::W,
+    );
+    drop(kernel_space);
 
-    KernelStack(kstack_id)
+    let kstack = KernelStack(kstack_id);
+    kstack.canary().fill(KSTACK_CANARY);
+    kstack
 }
 
 impl Drop for KernelStack {
@@ -125,11 +152,9 @@ impl Drop for KernelStack {
             kernel_stack_bottom + KERNEL_STACK_SIZE
         );
         KERNEL_SPACE
-            .exclusive_access(file!(), line!())
+            .lock()
             .remove_area_with_start_vpn(kernel_stack_bottom_va.into());
-        KSTACK_ALLOCATOR
-            .exclusive_access(file!(), line!())
-            .dealloc(self.0);
+        KSTACK_ALLOCATOR.lock().dealloc(self.0);
     }
 }
 
@@ -150,6 +175,28 @@ impl KernelStack {
         let (_, kernel_stack_top) = kernel_stack_position(self.0);
         kernel_stack_top
     }
+    /// the canary words at the bottom of the kernel stack
+    fn canary(&self) -> &'static mut [usize] {
+        let (kernel_stack_bottom, _) = kernel_stack_position(self.0);
+        unsafe {
+            core::slice::from_raw_parts_mut(kernel_stack_bottom as *mut usize, KSTACK_CANARY_WORDS)
+        }
+    }
+    /// Panic if the stack grew into its canary, `pid` is the owner for the message
+    ///
+    /// 栈溢出往往在很久以后才表现为无关的数据损坏，在每次切换任务和任务退出时检查。
+    pub fn check_canary(&self, pid: usize) {
+        if let Some(word) = self.canary().iter().position(|&word| word != KSTACK_CANARY) {
+            let (kernel_stack_bottom, _) = kernel_stack_position(self.0);
+            panic!(
+                "kernel stack overflow: canary word {} at {:#x} of pid {} is {:#x}",
+                word,
+                kernel_stack_bottom + word * core::mem::size_of::<usize>(),
+                pid,
+                self.canary()[word]
+            );
+        }
+    }
 }
 
 // /// User Resource for a task
@@ -179,7 +226,7 @@ fn ustack_top_from_id(ustack_top: usize, id: usize) -> usize {
 // impl TaskUserRes {
 //     /// Create a new TaskUserRes (Task User Resource)
 //     pub fn new(process: Arc<ProcessControlBlock>, ustack_top: usize, alloc_user_res: bool) -> Self {
-//         let tid = process.inner_exclusive_access(file!(), line!()).alloc_tid();
+//         let tid = process.inner_exclusive_access().alloc_tid();
 //         let task_user_res = Self {
 //             tid,
 //             ustack_top,
@@ -195,7 +242,7 @@ fn ustack_top_from_id(ustack_top: usize, id: usize) -> usize {
 //     /// Allocate user resource for a task
 //     pub fn alloc_user_res(&self) -> PhysPageNum {
 //         let process = self.process.upgrade().unwrap();
-//         let mut process_inner = process.inner_exclusive_access(file!(), line!());
+//         let mut process_inner = process.inner_exclusive_access();
 //         // alloc user stack
 //         let ustack_top = self.ustack_top;
 //         let ustack_bottom = ustack_top - USER_STACK_SIZE;
@@ -245,7 +292,7 @@ fn ustack_top_from_id(ustack_top: usize, id: usize) -> usize {
 //     /// Allocate user resource for a task
 //     pub fn alloc_initproc_res(&self) {
 //         let process = self.process.upgrade().unwrap();
-//         let mut process_inner = process.inner_exclusive_access(file!(), line!());
+//         let mut process_inner = process.inner_exclusive_access();
 //         // alloc user stack
 //         let ustack_top = self.ustack_top;
 //         let ustack_bottom = ustack_top - USER_STACK_SIZE;
@@ -279,7 +326,7 @@ fn ustack_top_from_id(ustack_top: usize, id: usize) -> usize {
 //             .translate(trap_cx_bottom_va.into())
 //             .unwrap()
 //             .ppn();
-//         let current_pagetable = &mut KERNEL_SPACE.exclusive_access(file!(), line!()).page_table;
+//         let current_pagetable = &mut KERNEL_SPACE.lock().page_table;
 //         debug!(
 //             "map trap_cx in current pagetable trap_cx_bottom: {:#x}, trap_cx_bottom_ppn: {:#x}, page_table: {:#x}",
 //             trap_cx_bottom_va.0, trap_cx_bottom_ppn.0, current_pagetable.token()
@@ -303,7 +350,7 @@ fn ustack_top_from_id(ustack_top: usize, id: usize) -> usize {
 //     fn dealloc_user_res(&self) {
 //         // dealloc tid
 //         let process = self.process.upgrade().unwrap();
-//         let mut process_inner = process.inner_exclusive_access(file!(), line!());
+//         let mut process_inner = process.inner_exclusive_access();
 //         // dealloc ustack manually
 //         let ustack_top = ustack_top_from_id(self.ustack_top, self.tid);
 //         let ustack_bottom_va: VirtAddr = (ustack_top - USER_STACK_SIZE).into();
@@ -324,13 +371,13 @@ fn ustack_top_from_id(ustack_top: usize, id: usize) -> usize {
 //             .process
 //             .upgrade()
 //             .unwrap()
-//             .inner_exclusive_access(file!(), line!())
+//             .inner_exclusive_access()
 //             .alloc_tid();
 //     }
 //     /// dealloc task id
 //     pub fn dealloc_tid(&self) {
 //         let process = self.process.upgrade().unwrap();
-//         let mut process_inner = process.inner_exclusive_access(file!(), line!());
+//         let mut process_inner = process.inner_exclusive_access();
 //         process_inner.dealloc_tid(self.tid);
 //     }
 //     /// The bottom usr vaddr (low addr) of the trap context for a task with tid
@@ -340,7 +387,7 @@ fn ustack_top_from_id(ustack_top: usize, id: usize) -> usize {
 //     /// The physical page number(ppn) of the trap context for a task with tid
 //     pub fn trap_cx_ppn(&self) -> PhysPageNum {
 //         let process = self.process.upgrade().unwrap();
-//         let process_inner = process.inner_exclusive_access(file!(), line!());
+//         let process_inner = process.inner_exclusive_access();
 //         let trap_cx_bottom_va: VirtAddr = trap_cx_bottom_from_tid(self.tid).into();
 //         debug!(
 //             "trap_cx_ppn = {:#x}",
@@ -373,3 +420,22 @@ fn ustack_top_from_id(ustack_top: usize, id: usize) -> usize {
 //         self.dealloc_user_res();
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::RecycleAllocator;
+
+    #[test_case]
+    fn recycle_allocator_reuses_ids() {
+        let mut allocator = RecycleAllocator::new();
+        assert_eq!(allocator.alloc(), 0);
+        assert_eq!(allocator.alloc(), 1);
+        assert_eq!(allocator.alloc(), 2);
+        allocator.dealloc(0);
+        allocator.dealloc(2);
+        // 最近回收的先被复用
+        assert_eq!(allocator.alloc(), 2);
+        assert_eq!(allocator.alloc(), 0);
+        assert_eq!(allocator.alloc(), 3);
+    }
+}
@@ -1,41 +1,43 @@
+//! Resource limits, read and set by getrlimit/setrlimit/prlimit64
+//!
+//! 限制属于进程：同一线程组的线程都使用组长的限制，fork 和 spawn 的子进程继承，exec 不变。
+//! 目前检查 RLIMIT_NOFILE（分配描述符）、RLIMIT_STACK（exec 时用户栈的大小）和
+//! RLIMIT_AS（brk 与 mmap），其余的只记录下来。
+
+use crate::{
+    config::{MAX_USER_STACK_SIZE, PAGE_SIZE, USER_STACK_SIZE},
+    syscall::errno::{EINVAL, EPERM},
+};
+
 /// Infinity for RLimit
 pub const RLIM_INFINITY: usize = usize::MAX;
 
-#[allow(unused)]
-const RLIMIT_CPU: u32 = 0;
-#[allow(unused)]
-const RLIMIT_FSIZE: u32 = 1;
-#[allow(unused)]
-const RLIMIT_DATA: u32 = 2;
-#[allow(unused)]
-const RLIMIT_STACK: u32 = 3;
-#[allow(unused)]
-const RLIMIT_CORE: u32 = 4;
-#[allow(unused)]
-const RLIMIT_RSS: u32 = 5;
-#[allow(unused)]
-const RLIMIT_NPROC: u32 = 6;
-#[allow(unused)]
-const RLIMIT_NOFILE: u32 = 7;
-#[allow(unused)]
-const RLIMIT_MEMLOCK: u32 = 8;
-#[allow(unused)]
-const RLIMIT_AS: u32 = 9;
-#[allow(unused)]
-const RLIMIT_LOCKS: u32 = 10;
-#[allow(unused)]
-const RLIMIT_SIGPENDING: u32 = 11;
-#[allow(unused)]
-const RLIMIT_MSGQUEUE: u32 = 12;
-#[allow(unused)]
-const RLIMIT_NICE: u32 = 13;
-#[allow(unused)]
-const RLIMIT_RTPRIO: u32 = 14;
-#[allow(unused)]
-const RLIMIT_RTTIME: u32 = 15;
+pub const RLIMIT_CPU: usize = 0;
+pub const RLIMIT_FSIZE: usize = 1;
+pub const RLIMIT_DATA: usize = 2;
+pub const RLIMIT_STACK: usize = 3;
+pub const RLIMIT_CORE: usize = 4;
+pub const RLIMIT_RSS: usize = 5;
+pub const RLIMIT_NPROC: usize = 6;
+pub const RLIMIT_NOFILE: usize = 7;
+pub const RLIMIT_MEMLOCK: usize = 8;
+pub const RLIMIT_AS: usize = 9;
+pub const RLIMIT_LOCKS: usize = 10;
+pub const RLIMIT_SIGPENDING: usize = 11;
+pub const RLIMIT_MSGQUEUE: usize = 12;
+pub const RLIMIT_NICE: usize = 13;
+pub const RLIMIT_RTPRIO: usize = 14;
+pub const RLIMIT_RTTIME: usize = 15;
+/// number of resources
+pub const RLIM_NLIMITS: usize = 16;
 
-/// Resource Limit
-#[derive(Debug, Clone, Copy)]
+/// default soft and hard limits of open descriptors, as in Linux
+const NOFILE_CUR: usize = 1024;
+const NOFILE_MAX: usize = 4096;
+
+/// Resource Limit, `struct rlimit` of the syscalls
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RLimit {
     /// Soft limit
     pub rlim_cur: usize,
@@ -45,32 +47,56 @@ pub struct RLimit {
 
 impl RLimit {
     /// New a RLimit
-    pub fn new(cur: usize, max: usize) -> Self {
+    pub const fn new(cur: usize, max: usize) -> Self {
         Self {
             rlim_cur: cur,
             rlim_max: max,
         }
     }
+}
+
+/// Limits of all resources of a process
+#[derive(Debug, Clone)]
+pub struct RLimits([RLimit; RLIM_NLIMITS]);
+
+impl Default for RLimits {
+    fn default() -> Self {
+        let mut limits = [RLimit::new(RLIM_INFINITY, RLIM_INFINITY); RLIM_NLIMITS];
+        limits[RLIMIT_STACK] = RLimit::new(USER_STACK_SIZE, RLIM_INFINITY);
+        limits[RLIMIT_CORE] = RLimit::new(0, RLIM_INFINITY);
+        limits[RLIMIT_NOFILE] = RLimit::new(NOFILE_CUR, NOFILE_MAX);
+        Self(limits)
+    }
+}
+
+impl RLimits {
+    /// Get RLimit, `None` if `resource` is unknown
+    pub fn get(&self, resource: usize) -> Option<RLimit> {
+        self.0.get(resource).copied()
+    }
+
     /// Set RLimit
-    pub fn set_rlimit(resource: u32, rlimit: &RLimit) -> isize {
-        log::info!("[set_rlimit] try to set limit: {:?}", resource);
-        match resource {
-            RLIMIT_NOFILE => {
-                current_process().inner_handler(|proc| proc.fd_table.set_rlimit(*rlimit))
-            }
-            _ => {}
+    ///
+    /// 软限制不能超过硬限制；只有 root 可以提高硬限制。
+    pub fn set(&mut self, resource: usize, limit: RLimit, privileged: bool) -> Result<(), isize> {
+        let old = self.0.get_mut(resource).ok_or(EINVAL)?;
+        if limit.rlim_cur > limit.rlim_max {
+            return Err(EINVAL);
         }
-        0
-    }
-    /// Get RLimit
-    pub fn get_rlimit(resource: u32) -> Self {
-        match resource {
-            RLIMIT_STACK => Self::new(USER_STACK_SIZE, RLIM_INFINITY),
-            RLIMIT_NOFILE => current_process().inner_handler(|proc| proc.fd_table.rlimit()),
-            _ => Self {
-                rlim_cur: 0,
-                rlim_max: 0,
-            },
+        if limit.rlim_max > old.rlim_max && !privileged {
+            return Err(EPERM);
         }
+        *old = limit;
+        Ok(())
+    }
+
+    /// Size of the user stack exec maps, the RLIMIT_STACK soft limit in whole pages
+    ///
+    /// 用户栈在 exec 时整个分配，所以上限是 `MAX_USER_STACK_SIZE`；下限留出放参数和环境变量的空间。
+    pub fn stack_size(&self) -> usize {
+        let size = self.0[RLIMIT_STACK]
+            .rlim_cur
+            .clamp(USER_STACK_SIZE, MAX_USER_STACK_SIZE);
+        size / PAGE_SIZE * PAGE_SIZE
     }
 }
@@ -0,0 +1,123 @@
+//! Per-task syscall filter, a simplified seccomp
+//!
+//! 过滤器是一张系统调用号的位图加上违规时的动作。fork 和创建线程时复制给子任务，
+//! exec 之后被锁定，新程序无法再替换或放宽它。
+
+use crate::config::MAX_SYSCALL_NUM;
+
+/// `SeccompList::mode`: only the syscalls in the bitmap are allowed
+pub const SECCOMP_MODE_ALLOW_LIST: u32 = 0;
+/// `SeccompList::mode`: the syscalls in the bitmap are denied
+pub const SECCOMP_MODE_DENY_LIST: u32 = 1;
+
+/// `SeccompList::action`: a denied syscall fails with `errno`
+pub const SECCOMP_RET_ERRNO: u32 = 0;
+/// `SeccompList::action`: a denied syscall kills the task with SIGSYS
+pub const SECCOMP_RET_KILL: u32 = 1;
+
+/// largest errno a filter may return
+const MAX_ERRNO: u32 = 4095;
+
+/// u64 words in the syscall bitmap
+pub const FILTER_WORDS: usize = (MAX_SYSCALL_NUM + 63) / 64;
+
+/// Filter description passed by userspace to `seccomp(SECCOMP_SET_MODE_LIST)`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SeccompList {
+    pub mode:   u32,
+    pub action: u32,
+    /// positive errno returned when `action` is `SECCOMP_RET_ERRNO`
+    pub errno:  u32,
+    pub pad:    u32,
+    /// bit `n` stands for syscall `n`
+    pub bitmap: [u64; FILTER_WORDS],
+}
+
+/// What happens to a syscall the filter rejects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    /// fail with this (negative) errno
+    Errno(isize),
+    /// raise SIGSYS
+    Kill,
+}
+
+/// An installed syscall filter
+#[derive(Debug, Clone)]
+pub struct SyscallFilter {
+    deny_list: bool,
+    bitmap:    [u64; FILTER_WORDS],
+    action:    FilterAction,
+    /// set by exec, a locked filter can not be replaced
+    locked:    bool,
+}
+
+impl SyscallFilter {
+    /// Build a filter from a userspace description, `None` if it is malformed
+    pub fn from_list(list: &SeccompList) -> Option<Self> {
+        let deny_list = match list.mode {
+            SECCOMP_MODE_ALLOW_LIST => false,
+            SECCOMP_MODE_DENY_LIST => true,
+            _ => return None,
+        };
+        let action = match list.action {
+            SECCOMP_RET_ERRNO if (1..=MAX_ERRNO).contains(&list.errno) => {
+                FilterAction::Errno(-(list.errno as isize))
+            }
+            SECCOMP_RET_KILL => FilterAction::Kill,
+            _ => return None,
+        };
+        Some(Self {
+            deny_list,
+            bitmap: list.bitmap,
+            action,
+            locked: false,
+        })
+    }
+
+    /// Strict mode: only the listed syscalls are allowed, anything else kills
+    pub fn strict(allowed: &[usize]) -> Self {
+        let mut bitmap = [0u64; FILTER_WORDS];
+        for &id in allowed {
+            bitmap[id / 64] |= 1 << (id % 64);
+        }
+        Self {
+            deny_list: false,
+            bitmap,
+            action: FilterAction::Kill,
+            locked: false,
+        }
+    }
+
+    fn listed(&self, syscall_id: usize) -> bool {
+        syscall_id < MAX_SYSCALL_NUM && self.bitmap[syscall_id / 64] & (1 << (syscall_id % 64)) != 0
+    }
+
+    /// Let `syscall_id` through whatever the list says
+    pub fn allow(&mut self, syscall_id: usize) {
+        let bit = 1 << (syscall_id % 64);
+        if self.deny_list {
+            self.bitmap[syscall_id / 64] &= !bit;
+        } else {
+            self.bitmap[syscall_id / 64] |= bit;
+        }
+    }
+
+    /// `None` if `syscall_id` may run, otherwise what to do instead
+    pub fn check(&self, syscall_id: usize) -> Option<FilterAction> {
+        if self.listed(syscall_id) == self.deny_list {
+            Some(self.action)
+        } else {
+            None
+        }
+    }
+
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
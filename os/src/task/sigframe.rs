@@ -0,0 +1,265 @@
+//! Delivering signals to user handlers
+//!
+//! 返回用户态前检查当前任务未被屏蔽的信号：默认动作为终止的直接退出，注册了处理函数的在用户栈上
+//! 建立信号帧，把 sepc 指向处理函数。信号帧的布局和 Linux riscv64 的 `rt_sigframe` 一致，
+//! 处理函数返回到 `sa_restorer`（没有 SA_RESTORER 时是内核映射的 [`USER_TRAMPOLINE`]），
+//! 由 rt_sigreturn 从信号帧恢复中断上下文和信号屏蔽字。
+
+use core::mem::size_of;
+
+use super::{
+    current_task,
+    current_trap_cx,
+    current_user_token,
+    exit_current_and_run_next,
+    sigaction::SignalAction,
+    signal::{SaFlags, SigInfo, SIG_DFL, SIG_IGN},
+    stop_current_and_run_next,
+    SignalFlags,
+};
+use crate::{
+    config::USER_TRAMPOLINE,
+    mm::{translated_byte_buffer, user_range_ok},
+};
+
+/// `stack_t`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SignalStack {
+    ss_sp:    usize,
+    ss_flags: i32,
+    ss_size:  usize,
+}
+
+/// `mcontext_t`: pc in place of x0, then x1-x31, the floating point state is not saved
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+struct MContext {
+    gregs:   [usize; 32],
+    fpstate: [usize; 66],
+}
+
+/// `ucontext_t`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UContext {
+    uc_flags:    usize,
+    uc_link:     usize,
+    uc_stack:    SignalStack,
+    /// signal mask to restore on sigreturn
+    uc_sigmask:  usize,
+    _unused:     [u8; 120],
+    uc_mcontext: MContext,
+}
+
+/// What is pushed on the user stack for a handler
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SignalFrame {
+    info: SigInfo,
+    uc:   UContext,
+}
+
+/// Signals that can not be blocked
+///
+/// 同步产生的 SIGSEGV/SIGILL/SIGBUS/SIGFPE 被屏蔽时任务会在出错的指令上反复陷入，
+/// 和 Linux 一样不允许屏蔽，没有处理函数时按默认动作终止。
+fn unblockable() -> SignalFlags {
+    SignalFlags::SIGKILL
+        | SignalFlags::SIGSTOP
+        | SignalFlags::SIGSEGV
+        | SignalFlags::SIGILL
+        | SignalFlags::SIGBUS
+        | SignalFlags::SIGFPE
+}
+
+/// Handle the pending signals of the current task before it returns to user mode
+///
+/// `syscall_ret` is the return value of the syscall the task trapped for, it has not
+/// been written to the trap context yet and is saved in the signal frame. Return the
+/// signal number passed in a0 if a handler is entered, the caller writes it back in
+/// place of the syscall return value.
+pub fn handle_signals(syscall_ret: Option<isize>) -> Option<usize> {
+    let task = current_task().unwrap();
+    loop {
+        let mut inner = task.inner_exclusive_access();
+        let deliverable = inner.signals & !(inner.signal_mask - unblockable());
+        if deliverable.is_empty() {
+            return None;
+        }
+        let signo = deliverable.bits().trailing_zeros() as usize + 1;
+        let flag = SignalFlags::from_bits_truncate(1 << (signo - 1));
+        inner.signals.remove(flag);
+        let action = inner.signal_actions.lock().table[signo];
+        match action.sa_handler {
+            SIG_IGN if !unblockable().contains(flag) => continue,
+            SIG_DFL | SIG_IGN => {
+                if ignored_by_default(flag) {
+                    continue;
+                }
+                if stop_signals().contains(flag) {
+                    drop(inner);
+                    stop_current_and_run_next(signo);
+                    continue;
+                }
+                drop(inner);
+                trace!("kernel: pid[{}] killed by signal {}", task.pid.0, signo);
+                exit_current_and_run_next(-(signo as i32));
+                unreachable!();
+            }
+            _ => {}
+        }
+        let old_mask = inner.signal_mask;
+        let mut mask = inner.signal_mask | action.mask;
+        if !action.sa_flags.contains(SaFlags::SA_NODEFER) {
+            mask |= flag;
+        }
+        inner.signal_mask = mask - unblockable();
+        if action.sa_flags.contains(SaFlags::SA_RESETHAND) {
+            inner.signal_actions.lock().table[signo] = SignalAction::default();
+        }
+        drop(inner);
+        if !enter_handler(signo, &action, old_mask, syscall_ret) {
+            // 用户栈放不下信号帧，按 SIGSEGV 终止
+            exit_current_and_run_next(-11);
+        }
+        return Some(signo);
+    }
+}
+
+/// Whether the current task has a signal to handle or be killed by on return to user mode
+///
+/// 阻塞等待时用来提前返回 EINTR，被屏蔽和被忽略的信号不算。
+pub fn signal_pending() -> bool {
+    let task = current_task().unwrap();
+    let inner = task.inner_exclusive_access();
+    let mut deliverable = (inner.signals & !(inner.signal_mask - unblockable())).bits();
+    let actions = inner.signal_actions.lock();
+    while deliverable != 0 {
+        let signo = deliverable.trailing_zeros() as usize + 1;
+        deliverable &= deliverable - 1;
+        let flag = SignalFlags::from_bits_truncate(1 << (signo - 1));
+        let ignored = match actions.table[signo].sa_handler {
+            SIG_IGN if !unblockable().contains(flag) => true,
+            SIG_DFL | SIG_IGN => ignored_by_default(flag),
+            _ => false,
+        };
+        if !ignored {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether the default action of `flag` is to ignore it
+///
+/// SIGCONT 的继续动作在发送时由 [`super::send_signal`] 完成，取出时忽略即可。
+fn ignored_by_default(flag: SignalFlags) -> bool {
+    (SignalFlags::SIGCHLD | SignalFlags::SIGCONT | SignalFlags::SIGURG | SignalFlags::SIGWINCH)
+        .contains(flag)
+}
+
+/// Signals whose default action is to stop the task
+pub(super) fn stop_signals() -> SignalFlags {
+    SignalFlags::SIGSTOP | SignalFlags::SIGTSTP | SignalFlags::SIGTTIN | SignalFlags::SIGTTOU
+}
+
+/// Push the signal frame and redirect the trap context to the handler
+fn enter_handler(
+    signo: usize, action: &SignalAction, old_mask: SignalFlags, syscall_ret: Option<isize>,
+) -> bool {
+    let cx = current_trap_cx();
+    let mut gregs = cx.x;
+    gregs[0] = cx.sepc;
+    if let Some(ret) = syscall_ret {
+        gregs[10] = ret as usize;
+    }
+    let frame = SignalFrame {
+        info: SigInfo::new(signo, 0, 0),
+        uc:   UContext {
+            uc_flags:    0,
+            uc_link:     0,
+            uc_stack:    SignalStack::default(),
+            uc_sigmask:  old_mask.bits(),
+            _unused:     [0; 120],
+            uc_mcontext: MContext {
+                gregs,
+                fpstate: [0; 66],
+            },
+        },
+    };
+    let sp = (cx.x[2] - size_of::<SignalFrame>()) & !0xf;
+    if !copy_to_user(sp, &frame) {
+        return false;
+    }
+    cx.x[1] = if action.sa_flags.contains(SaFlags::SA_RESTORER) {
+        action.sa_restorer
+    } else {
+        USER_TRAMPOLINE
+    };
+    cx.x[2] = sp;
+    cx.x[10] = signo;
+    cx.x[11] = sp;
+    cx.x[12] = sp + size_of::<SigInfo>();
+    cx.sepc = action.sa_handler;
+    true
+}
+
+/// Restore the trap context and signal mask saved in the signal frame at the user
+/// stack pointer, return the restored a0
+pub fn restore_signal_frame() -> Option<usize> {
+    let cx = current_trap_cx();
+    let sp = cx.x[2];
+    let uc_addr = sp + size_of::<SigInfo>();
+    let mut uc = UContext {
+        uc_flags:    0,
+        uc_link:     0,
+        uc_stack:    SignalStack::default(),
+        uc_sigmask:  0,
+        _unused:     [0; 120],
+        uc_mcontext: MContext {
+            gregs:   [0; 32],
+            fpstate: [0; 66],
+        },
+    };
+    if !copy_from_user(uc_addr, &mut uc) {
+        return None;
+    }
+    let gregs = uc.uc_mcontext.gregs;
+    cx.sepc = gregs[0];
+    cx.x[1..].copy_from_slice(&gregs[1..]);
+    let task = current_task().unwrap();
+    task.inner_exclusive_access().signal_mask =
+        SignalFlags::from_bits_truncate(uc.uc_sigmask) - unblockable();
+    Some(cx.x[10])
+}
+
+fn copy_to_user<T: Copy>(addr: usize, value: &T) -> bool {
+    let token = current_user_token();
+    if !user_range_ok(token, addr, size_of::<T>(), true) {
+        return false;
+    }
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    let mut copied = 0;
+    for buf in translated_byte_buffer(token, addr as *const u8, size_of::<T>()) {
+        buf.copy_from_slice(&bytes[copied..copied + buf.len()]);
+        copied += buf.len();
+    }
+    true
+}
+
+fn copy_from_user<T: Copy>(addr: usize, value: &mut T) -> bool {
+    let token = current_user_token();
+    if !user_range_ok(token, addr, size_of::<T>(), false) {
+        return false;
+    }
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) };
+    let mut copied = 0;
+    for buf in translated_byte_buffer(token, addr as *const u8, size_of::<T>()) {
+        bytes[copied..copied + buf.len()].copy_from_slice(buf);
+        copied += buf.len();
+    }
+    true
+}
@@ -118,6 +118,8 @@ impl SignalFlags {
     pub fn check_error(&self) -> Option<(i32, &'static str)> {
         if self.contains(Self::SIGINT) {
             Some((-2, "Killed, SIGINT=2"))
+        } else if self.contains(Self::SIGKILL) {
+            Some((-9, "Killed, SIGKILL=9"))
         } else if self.contains(Self::SIGILL) {
             Some((-4, "Illegal Instruction, SIGILL=4"))
         } else if self.contains(Self::SIGABRT) {
@@ -126,6 +128,8 @@ impl SignalFlags {
             Some((-8, "Erroneous Arithmetic Operation, SIGFPE=8"))
         } else if self.contains(Self::SIGSEGV) {
             Some((-11, "Segmentation Fault, SIGSEGV=11"))
+        } else if self.contains(Self::SIGSYS) {
+            Some((-31, "Bad System Call, SIGSYS=31"))
         } else {
             // warn!("[kernel] signalflags check_error  {:?}", self);
             None
@@ -3,17 +3,23 @@
 use alloc::{
     string::String,
     sync::{Arc, Weak},
-    vec,
     vec::Vec,
 };
-use core::{cell::RefMut, slice};
+use core::{arch::asm, slice, sync::atomic::AtomicBool};
 
-use riscv::register::sstatus;
+use riscv::register::{satp, sstatus};
 
 use super::{
+    cgroup::{Cgroup, ROOT_CGROUP},
+    cred::Credentials,
     kstack_alloc,
-    process::Flags,
-    sigaction::SignalActions,
+    manager::SchedEntity,
+    pid_ns::{NsPids, PidNamespace},
+    process::{Flags, MmapProt},
+    resource::RLimits,
+    seccomp::SyscallFilter,
+    sigaction::{SignalAction, SignalActions},
+    signal::SIG_IGN,
     CloneFlags,
     KernelStack,
     PidHandle,
@@ -21,18 +27,45 @@ use super::{
     TaskContext,
 };
 use crate::{
-    config::{MAX_SYSCALL_NUM, PAGE_SIZE, TRAP_CONTEXT_TRAMPOLINE, USER_STACK_SIZE},
+    config::{
+        MAX_SYSCALL_NUM,
+        PAGE_SIZE,
+        THREAD_STACK_BASE,
+        TRAP_CONTEXT_TRAMPOLINE,
+        USER_STACK_SIZE,
+    },
     fs::{
+        defs::OpenFlags,
         dentry::Dentry,
-        file::{cast_file_to_inode, File},
-        stdio::{Stdin, Stdout},
+        fd::FdTable,
+        file::{cast_file_to_inode, cast_file_to_io_ring},
+        inode::InodePerm,
+        namespace::{MountNamespace, INIT_MNT_NS},
         ROOT_INODE,
     },
-    mm::{MapPermission, MemorySet, PTEFlags, PhysPageNum, VirtAddr, KERNEL_SPACE},
-    sync::UPSafeCell,
-    syscall::errno::EPERM,
-    task::{add_task, manager::insert_into_pid2process, pid_alloc, res::trap_cx_bottom_from_tid},
-    timer::get_time,
+    mm::{MapPermission, MemorySet, PTEFlags, PhysAddr, PhysPageNum, VirtAddr, KERNEL_SPACE},
+    sync::{
+        deadlock::DeadlockDetector,
+        futex,
+        mutex::Mutex,
+        Condvar,
+        Semaphore,
+        SpinNoIrqLock,
+        SpinNoIrqLockGuard,
+        WaitQueue,
+    },
+    syscall::{
+        errno::{EACCES, EBADF, EPERM},
+        membarrier::MembarrierCmd,
+    },
+    task::{
+        add_task,
+        manager::insert_into_pid2process,
+        pid2process,
+        pid_alloc,
+        res::{trap_cx_bottom_from_tid, ustack_bottom_from_tid},
+    },
+    timer::{get_time, RealTimer},
     trap::{trap_handler, TrapContext},
 };
 
@@ -45,15 +78,23 @@ pub struct TaskControlBlock {
     pub tid: usize,
     /// process id, the only identifier of the tasks
     pub pid: PidHandle,
+    /// pids in the pid namespaces the task belongs to
+    pub ns_pids: NsPids,
     /// whether to send SIGCHLD when the task exits
     pub send_sigchld_when_exit: bool,
+    /// 在某个 hart 上运行、上下文还没保存完，其他 hart 取到它时要等它切换出去
+    pub on_cpu: AtomicBool,
+    /// wait4 of this task sleeping until a child exits
+    pub child_exit: WaitQueue,
     /// mutable
-    inner: UPSafeCell<TaskControlBlockInner>,
+    inner: SpinNoIrqLock<TaskControlBlockInner>,
 }
 
+/// 地址空间、描述符表和信号处理函数表可能被 clone 出的多个任务共享，各自有一把锁，
+/// 要在 inner 的锁里面获取，不能反过来
 pub struct TaskControlBlockInner {
-    /// memory set(address space)
-    pub memory_set:       MemorySet,
+    /// memory set(address space), shared with the tasks cloned with CLONE_VM
+    pub memory_set:       Arc<SpinNoIrqLock<MemorySet>>,
     /// The physical page number of the frame where the trap context is placed
     pub trap_cx_ppn:      PhysPageNum,
     /// Save task context
@@ -62,12 +103,28 @@ pub struct TaskControlBlockInner {
     pub task_status:      TaskStatus,
     /// syscall times of tasks
     pub syscall_times:    [u32; MAX_SYSCALL_NUM],
+    /// log every syscall of this task to the kernel log, see `syscall::trace`
+    pub syscall_trace:    bool,
+    /// syscall filter installed by seccomp, copied to children
+    pub syscall_filter:   Option<SyscallFilter>,
+    /// membarrier commands registered for, dropped on exec and fork
+    pub membarrier:       MembarrierCmd,
+    /// user and group ids, checked against file ownership
+    pub cred:             Credentials,
+    /// control group limiting CPU time and memory, inherited by children
+    pub cgroup:           Arc<Cgroup>,
+    /// resource limits, only the group leader's are used
+    pub rlimits:          RLimits,
+    /// nice and virtual runtime, see `manager`
+    pub sched:            SchedEntity,
     /// the time task was first run
     pub first_time:       Option<usize>, // todo: 封装为一个单独的TaskTimer结构体
     ///
     pub clear_child_tid:  usize,
     /// working directory
     pub work_dir:         Arc<Dentry>,
+    /// mount table, shared with the parent unless cloned with CLONE_NEWNS
+    pub mnt_ns:           Arc<MountNamespace>,
     /// father task control block
     pub parent:           Option<Weak<TaskControlBlock>>,
     /// children task control block
@@ -78,53 +135,114 @@ pub struct TaskControlBlockInner {
     pub user_stack_top:   usize,
     /// exit code
     pub exit_code:        Option<i32>,
-    /// file descriptor table
-    pub fd_table:         Vec<Option<Arc<dyn File>>>,
+    /// the signal that stopped the task, until wait4 with WUNTRACED reports it
+    pub stop_report:      Option<usize>,
+    /// continued by SIGCONT, until wait4 with WCONTINUED reports it
+    pub continue_report:  bool,
+    /// file descriptor table, shared with the tasks cloned with CLONE_FILES
+    pub fd_table:         Arc<SpinNoIrqLock<FdTable>>,
     /// clock time stop watch
     pub clock_stop_watch: usize,
     /// user clock time
     pub user_clock:       usize,
     /// kernel clock time
     pub kernel_clock:     usize,
-    /// Record the usage of heap_area in MemorySet
-    pub heap_base:        VirtAddr,
-    ///
-    pub heap_end:         VirtAddr,
     /// is zombie?
     pub is_zombie:        bool,
     /// signal flags
     pub signals:          SignalFlags,
-    // Signal actions
-    pub signal_actions:   SignalActions,
+    /// Signal actions, shared with the tasks cloned with CLONE_SIGHAND
+    pub signal_actions:   Arc<SpinNoIrqLock<SignalActions>>,
     pub signals_pending:  SignalFlags,
     // the signal to mask
     pub signal_mask:      SignalFlags,
+    /// mutexes created by sys_mutex_create, indexed by id, only used by the group leader
+    pub mutex_list:       Vec<Option<Arc<dyn Mutex>>>,
+    /// semaphores created by sys_semaphore_create
+    pub semaphore_list:   Vec<Option<Arc<Semaphore>>>,
+    /// condition variables created by sys_condvar_create
+    pub condvar_list:     Vec<Option<Arc<Condvar>>>,
+    /// who holds and waits for the mutexes and semaphores above
+    pub deadlock:         DeadlockDetector,
+    /// ITIMER_REAL set by sys_setitimer
+    pub real_timer:       RealTimer,
+    /// process group, the global pid of its leader
+    pub pgid:             usize,
+    /// session, the global pid of its leader
+    pub sid:              usize,
+    /// command name shown in /proc, the file name of the program last executed
+    pub comm:             String,
 }
 
 impl TaskControlBlock {
-    /// Get the mutable reference of the inner TCB
-    pub fn inner_exclusive_access(
-        &self, file: &'static str, line: u32,
-    ) -> RefMut<'_, TaskControlBlockInner> {
-        self.inner.exclusive_access(file, line)
+    /// Lock the inner TCB, panicking if the current hart already holds it
+    #[track_caller]
+    pub fn inner_exclusive_access(&self) -> SpinNoIrqLockGuard<'_, TaskControlBlockInner> {
+        self.inner.lock()
+    }
+    /// Like `inner_exclusive_access`, but return `None` if the inner TCB is locked
+    #[track_caller]
+    pub fn try_inner_exclusive_access(
+        &self,
+    ) -> Option<SpinNoIrqLockGuard<'_, TaskControlBlockInner>> {
+        self.inner.try_lock()
+    }
+    /// Like `inner_exclusive_access`, but return `None` if the current hart holds the
+    /// inner TCB, for page faults taken by kernel code
+    #[track_caller]
+    pub fn inner_unless_held(&self) -> Option<SpinNoIrqLockGuard<'_, TaskControlBlockInner>> {
+        self.inner.lock_unless_held()
+    }
+    /// Get the inner TCB even if it is locked, see `SpinMutex::get_unchecked`
+    pub unsafe fn inner_unchecked(&self) -> &mut TaskControlBlockInner {
+        self.inner.get_unchecked()
     }
     /// 使用闭包访问内部数据
     pub fn inner_handler<F, R>(&self, handler: F) -> R
-    where F: FnOnce(&mut TaskControlBlockInner) -> R {
-        handler(&mut self.inner.exclusive_access(file!(), line!()))
+    where
+        F: FnOnce(&mut TaskControlBlockInner) -> R,
+    {
+        handler(&mut self.inner.lock())
     }
     /// Get the address of app's page table
     pub fn get_user_token(&self) -> usize {
-        let inner = self.inner_exclusive_access(file!(), line!());
-        inner.memory_set.token()
+        let inner = self.inner_exclusive_access();
+        let token = inner.memory_set.lock().token();
+        token
+    }
+    /// The fd table, to be locked without holding the inner TCB
+    pub fn fd_table(&self) -> Arc<SpinNoIrqLock<FdTable>> {
+        self.inner_exclusive_access().fd_table.clone()
     }
     /// 根据tid获取task的trap_cx位置
     pub fn get_trap_cx(&self) -> &'static mut TrapContext {
         self.trap_cx_user_va().get_mut()
     }
 
-    pub fn gettid(&self) -> usize {
-        self.tid
+    /// The pid namespace the task lives in
+    pub fn pid_ns(&self) -> Arc<PidNamespace> {
+        self.ns_pids.ns()
+    }
+
+    /// The leader of the task's thread group, which owns the resources its threads share
+    pub fn group_leader(self: &Arc<Self>) -> Arc<Self> {
+        if self.tid == self.pid.0 {
+            return self.clone();
+        }
+        pid2process(self.tid).unwrap_or_else(|| self.clone())
+    }
+
+    /// Soft limit of `resource` for the task's process, see `resource`
+    ///
+    /// 要锁组长的 inner，不能在持有本任务的 inner 或描述符表的锁时调用。
+    pub fn rlimit(self: &Arc<Self>, resource: usize) -> usize {
+        let leader = self.group_leader();
+        let limit = leader
+            .inner_exclusive_access()
+            .rlimits
+            .get(resource)
+            .unwrap();
+        limit.rlim_cur
     }
 }
 
@@ -141,30 +259,24 @@ impl TaskControlBlock {
 
     /// The physical page number(ppn) of the trap context for a task with tid
     pub fn trap_cx_ppn(&self) -> PhysPageNum {
-        let task_inner = self.inner_exclusive_access(file!(), line!());
-        let trap_cx_bottom_va: VirtAddr = trap_cx_bottom_from_tid(self.tid).into();
-        debug!(
-            "trap_cx_ppn = {:#x}",
-            task_inner
-                .memory_set
-                .translate(trap_cx_bottom_va.into())
-                .unwrap()
-                .ppn()
-                .0
-        );
-        task_inner
+        let task_inner = self.inner_exclusive_access();
+        let trap_cx_bottom_va: VirtAddr = trap_cx_bottom_from_tid(self.pid.0).into();
+        let trap_cx_ppn = task_inner
             .memory_set
+            .lock()
             .translate(trap_cx_bottom_va.into())
             .unwrap()
-            .ppn()
+            .ppn();
+        debug!("trap_cx_ppn = {:#x}", trap_cx_ppn.0);
+        trap_cx_ppn
     }
 
     /// 从零开始创建一个新进程，只会在创建初始进程的时候使用一次
     pub fn init_task(elf_data: &[u8]) -> Arc<Self> {
         trace!("TaskControlBlock new");
         let kstack = kstack_alloc();
-        let (mut memory_set, user_heap_base, ustack_top, entry_point, auxv) =
-            MemorySet::from_elf(elf_data);
+        let (mut memory_set, ustack_top, entry_point, auxv) =
+            MemorySet::from_elf(elf_data, USER_STACK_SIZE).expect("cannot load initproc");
         let pid_handle = pid_alloc();
         let tid = pid_handle.0;
 
@@ -203,7 +315,7 @@ impl TaskControlBlock {
 
         {
             // 在一定区域中获取可变引用，保证离开时自动释放
-            let current_pagetable = &mut KERNEL_SPACE.exclusive_access(file!(), line!()).page_table;
+            let current_pagetable = &mut KERNEL_SPACE.lock().page_table;
             debug!(
                 "map trap_cx in current pagetable trap_cx_bottom: {:#x}, trap_cx_bottom_ppn: \
                  {:#x}, page_table: {:#x}",
@@ -214,7 +326,7 @@ impl TaskControlBlock {
             current_pagetable.map(
                 trap_cx_bottom_va.floor(),
                 trap_cx_bottom_ppn,
-                PTEFlags::from_bits((MapPermission::R | MapPermission::W).bits()).unwrap(),
+                PTEFlags::from_bits((MapPermission::R | MapPermission::W).bits() as u16).unwrap(),
             );
         }
 
@@ -240,44 +352,54 @@ impl TaskControlBlock {
             kstack,
             tid: tid,
             pid: pid_handle,
+            ns_pids: NsPids::root(),
             send_sigchld_when_exit: false, //todo
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    is_zombie: false,
-                    memory_set,
-                    trap_cx_ppn,
-                    task_cx: TaskContext::goto_initproc_entry(kstack_top),
-                    task_status: TaskStatus::Ready,
-                    exit_code: None,
-                    syscall_times: [0; MAX_SYSCALL_NUM],
-                    first_time: None,
-                    clear_child_tid: 0,
-                    parent: None,
-                    children: Vec::new(),
-                    threads: Vec::new(),
-                    user_stack_top: ustack_top - 8, // todo
-                    fd_table: vec![
-                        // 0 -> stdin
-                        Some(Arc::new(Stdin)),
-                        // 1 -> stdout
-                        Some(Arc::new(Stdout)),
-                        // 2 -> stderr
-                        Some(Arc::new(Stdout)),
-                    ],
-                    signals: SignalFlags::empty(),
-                    clock_stop_watch: 0,
-                    user_clock: 0,
-                    kernel_clock: 0,
-                    heap_base: user_heap_base.into(),
-                    heap_end: user_heap_base.into(),
-                    work_dir,
-                    signal_actions: SignalActions::default(),
-                    signals_pending: SignalFlags::empty(),
-                    signal_mask: SignalFlags::empty(),
-                })
-            },
+            on_cpu: AtomicBool::new(false),
+            child_exit: WaitQueue::new(),
+            inner: SpinNoIrqLock::new(TaskControlBlockInner {
+                is_zombie: false,
+                memory_set: Arc::new(SpinNoIrqLock::new(memory_set)),
+                trap_cx_ppn,
+                task_cx: TaskContext::goto_initproc_entry(kstack_top),
+                task_status: TaskStatus::Ready,
+                exit_code: None,
+                stop_report: None,
+                continue_report: false,
+                syscall_times: [0; MAX_SYSCALL_NUM],
+                syscall_trace: false,
+                syscall_filter: None,
+                membarrier: MembarrierCmd::empty(),
+                cred: Credentials::root(),
+                cgroup: ROOT_CGROUP.clone(),
+                rlimits: RLimits::default(),
+                sched: SchedEntity::new(),
+                first_time: None,
+                clear_child_tid: 0,
+                parent: None,
+                children: Vec::new(),
+                threads: Vec::new(),
+                user_stack_top: ustack_top - 8, // todo
+                fd_table: Arc::new(SpinNoIrqLock::new(FdTable::with_stdio())),
+                signals: SignalFlags::empty(),
+                clock_stop_watch: 0,
+                user_clock: 0,
+                kernel_clock: 0,
+                work_dir,
+                mnt_ns: INIT_MNT_NS.clone(),
+                signal_actions: Arc::new(SpinNoIrqLock::new(SignalActions::default())),
+                signals_pending: SignalFlags::empty(),
+                signal_mask: SignalFlags::empty(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                deadlock: DeadlockDetector::new(),
+                real_timer: RealTimer::default(),
+                pgid: tid,
+                sid: tid,
+                comm: String::from("initproc"),
+            }),
         });
-        let task_inner = task.inner_exclusive_access(file!(), line!());
+        let task_inner = task.inner_exclusive_access();
         let trap_cx = task.get_trap_cx();
         let ustack_top = task_inner.user_stack_top;
         let kstack_top = task.kstack.get_top();
@@ -287,7 +409,7 @@ impl TaskControlBlock {
         *trap_cx = TrapContext::app_init_context(
             entry_point,
             ustack_top,
-            KERNEL_SPACE.exclusive_access(file!(), line!()).token(),
+            KERNEL_SPACE.lock().token(),
             kstack_top,
             trap_handler as usize,
         );
@@ -297,325 +419,428 @@ impl TaskControlBlock {
         task
     }
 
+    /// Create a task as clone(2) does, sharing the address space, the fd table and the
+    /// signal actions with the current task as `flags` asks, copying them otherwise
     ///
+    /// 中断上下文按 pid 放在子任务的地址空间里。`stack` 为 0 时进程和 vfork 的子进程沿用
+    /// 父任务的栈指针，其他共享地址空间的任务由内核按 pid 分配一个用户栈。CLONE_THREAD 创建的线程挂在线程组
+    /// leader 的 `threads` 上，不是任何任务的子进程，也不进入 pid 到进程的映射。
+    /// `ptid` 和 `ctid` 在子任务开始运行前写好。返回已经加入调度的子任务。
     pub fn clone_t(
-        self: &Arc<Self>, flag: CloneFlags, stack: usize, sig: SignalFlags, ptid: usize,
+        self: &Arc<Self>, flags: CloneFlags, stack: usize, exit_signal: SignalFlags, ptid: usize,
         tls: usize, ctid: usize,
-    ) -> Option<Arc<TaskControlBlock>> {
-        warn!(
-            "clone: flag:{:?}, sig:{:?}, stack:{:#x}, ptid:{:#x}, tls:{:#x}, ctid:{:#x}",
-            flag, sig, stack, ptid, tls, ctid
+    ) -> Arc<Self> {
+        let child_task = self.clone_task(
+            flags,
+            TaskEntry::User { stack, tls },
+            exit_signal,
+            ptid,
+            ctid,
+        );
+        // add this thread to scheduler
+        add_task(Arc::clone(&child_task));
+        info!(
+            "clone: child pid[{}] tgid[{}] add to scheduler",
+            child_task.pid.0, child_task.tid
+        );
+        child_task
+    }
+
+    /// Start a kernel thread in the thread group of this task, running `entry(arg)`
+    ///
+    /// 内核线程共享线程组的地址空间，fd 表是空的，从不回到用户态，所以也不会处理信号；
+    /// `entry` 自己决定何时调用 `exit_current_and_run_next` 退出。
+    pub fn spawn_kernel_thread(
+        self: &Arc<Self>, entry: extern "C" fn(usize) -> !, arg: usize,
+    ) -> Arc<Self> {
+        let thread = self.clone_task(
+            CloneFlags::CLONE_VM | CloneFlags::CLONE_THREAD | CloneFlags::CLONE_SIGHAND,
+            TaskEntry::Kernel { entry, arg },
+            SignalFlags::empty(),
+            0,
+            0,
         );
+        add_task(thread.clone());
+        thread
+    }
+
+    /// [`Self::clone_t`] without putting the child on the scheduler
+    fn clone_task(
+        self: &Arc<Self>, flags: CloneFlags, entry: TaskEntry, exit_signal: SignalFlags,
+        ptid: usize, ctid: usize,
+    ) -> Arc<Self> {
+        let thread = flags.contains(CloneFlags::CLONE_THREAD);
         let pid = pid_alloc();
-        let task_inner = self.inner_exclusive_access(file!(), line!());
-        let memory_set = if flag.contains(CloneFlags::CLONE_VM) {
-            MemorySet::from_existed_user(&task_inner.memory_set)
+        let new_pid_ns = flags.contains(CloneFlags::CLONE_NEWPID);
+        let pid_ns = if new_pid_ns {
+            self.ns_pids.new_child_ns()
         } else {
-            MemorySet::from_existed_user(&task_inner.memory_set) //todo: 改为Flag对应要求
+            self.pid_ns()
         };
+        let ns_pids = NsPids::alloc(&pid_ns, pid.0);
+        let kstack = kstack_alloc();
+        let kstack_top = kstack.get_top();
+        let rlimits = self.group_leader().inner_exclusive_access().rlimits.clone();
 
-        // copy fd table
-        let fd_table = if flag.contains(CloneFlags::CLONE_FILES) {
-            // todo: 实现clone trait，这样就可以直接clone父进程的，解耦合
-            let mut new_fd_table: Vec<Option<Arc<dyn File>>> = Vec::new();
-            for fd in task_inner.fd_table.iter() {
-                if let Some(file) = fd {
-                    new_fd_table.push(Some(file.clone()));
-                } else {
-                    new_fd_table.push(None);
+        // 新任务从父任务陷入时的状态继续执行，clone 返回 0；内核线程用不到，只是占住中断上下文的页
+        let mut trap_cx = *self.get_trap_cx();
+        trap_cx.x[10] = 0;
+        trap_cx.kernel_sp = kstack_top;
+        let kernel_thread = matches!(entry, TaskEntry::Kernel { .. });
+        let (stack, task_cx) = match entry {
+            TaskEntry::User { stack, tls } => {
+                if flags.contains(CloneFlags::CLONE_SETTLS) {
+                    trap_cx.x[4] = tls;
                 }
+                (stack, TaskContext::goto_user_entry(kstack_top))
+            }
+            TaskEntry::Kernel { entry, arg } => {
+                (0, TaskContext::goto_kernel_thread(kstack_top, entry, arg))
             }
-            new_fd_table
-        } else {
-            let new_fd_table: Vec<Option<Arc<dyn File>>> = vec![
-                // 0 -> stdin
-                Some(Arc::new(Stdin)),
-                // 1 -> stdout
-                Some(Arc::new(Stdout)),
-                // 2 -> stderr
-                Some(Arc::new(Stdout)),
-            ];
-            new_fd_table
         };
 
-        let tid = if flag.contains(CloneFlags::CLONE_THREAD) {
-            self.tid
+        let task_inner = self.inner_exclusive_access();
+        let memory_set = if flags.contains(CloneFlags::CLONE_VM) {
+            task_inner.memory_set.clone()
         } else {
-            pid.0
+            let memory_set = MemorySet::from_existed_user(&mut task_inner.memory_set.lock());
+            Arc::new(SpinNoIrqLock::new(memory_set))
         };
-
-        let parent = if flag.contains(CloneFlags::CLONE_PARENT) {
+        let fd_table = if kernel_thread {
+            // 不持有进程的文件，进程关闭它们时不会被内核线程拖住
+            Arc::new(SpinNoIrqLock::new(FdTable::default()))
+        } else if flags.contains(CloneFlags::CLONE_FILES) {
+            task_inner.fd_table.clone()
+        } else {
+            Arc::new(SpinNoIrqLock::new(task_inner.fd_table.lock().clone()))
+        };
+        let signal_actions = if flags.contains(CloneFlags::CLONE_SIGHAND) {
+            task_inner.signal_actions.clone()
+        } else {
+            Arc::new(SpinNoIrqLock::new(task_inner.signal_actions.lock().clone()))
+        };
+        let mnt_ns = if flags.contains(CloneFlags::CLONE_NEWNS) {
+            task_inner.mnt_ns.copy()
+        } else {
+            task_inner.mnt_ns.clone()
+        };
+        let parent = if thread || flags.contains(CloneFlags::CLONE_PARENT) {
             task_inner.parent.clone()
         } else {
             Some(Arc::downgrade(self))
         };
 
-        let kstask = kstack_alloc();
-        let kstack_top = kstask.get_top();
-
-        // map the thread trap_context if clone_vm
-        // let trap_context = if flag.contains(CloneFlags::CLONE_VM) {
-        //     todo!("should alloc a new trap_context for the new thread according to thread id");
-        // } else {
-        //     child_task.get_trap_cx()
-        // };
-
-        // insert_into_pid2process(pid, Arc::clone(child_task));
-
-        todo!("unfinished");
-    }
-
-    pub fn fork(self: &Arc<Self>) -> usize {
-        trace!("[kernel]: sys_fork");
-        let pid = pid_alloc();
-        warn!("fork: pid[{}]", pid.0);
-        let trap_cx_ppn = self.trap_cx_ppn();
-
-        let mut task_inner = self.inner_exclusive_access(file!(), line!());
-        let kstack = kstack_alloc();
-        let kstack_top = kstack.get_top();
-        let mut memory_set = MemorySet::from_existed_user(&task_inner.memory_set);
-
-        let tid = pid.0;
-        let parent = Some(Arc::downgrade(self));
-        // copy fd table
-        let mut new_fd_table: Vec<Option<Arc<dyn File>>> = Vec::new();
-        for fd in task_inner.fd_table.iter() {
-            if let Some(file) = fd {
-                new_fd_table.push(Some(file.clone()));
+        let (user_stack_top, trap_cx_ppn) = {
+            let mut memory_set = memory_set.lock();
+            let new_stack = !kernel_thread
+                && flags.contains(CloneFlags::CLONE_VM)
+                && !flags.contains(CloneFlags::CLONE_VFORK);
+            let user_stack_top = if stack != 0 {
+                stack
+            } else if new_stack {
+                // 和父任务用同一个栈会互相破坏，按 pid 分配一个，线程退出时释放
+                let ustack_bottom = ustack_bottom_from_tid(THREAD_STACK_BASE, pid.0);
+                let ustack_top = ustack_bottom + USER_STACK_SIZE;
+                memory_set.remove_area_with_start_vpn(VirtAddr::from(ustack_bottom).floor());
+                memory_set.insert_framed_area(
+                    ustack_bottom.into(),
+                    ustack_top.into(),
+                    MapPermission::R | MapPermission::W | MapPermission::U,
+                );
+                ustack_top
             } else {
-                new_fd_table.push(None);
+                task_inner.user_stack_top
+            };
+            if stack != 0 || new_stack {
+                trap_cx.set_sp(user_stack_top);
             }
-        }
-
-        // 为新进程分配中断上下文
-        // 现在获取中断上下文靠pid的划分，这其实不太合适，应该在线程组内部按照线程id区分
-        // 但是由于pid是全局唯一的，所以进程之间互相映射或者访问中断上下文的时候就可以不用 TRAP_CONTEXT_TRAMPOLINE了
-        // alloc trap_cx
-        let trap_cx_bottom = trap_cx_bottom_from_tid(pid.0);
-        let trap_cx_top = trap_cx_bottom + PAGE_SIZE;
-        debug!(
-            "alloc_user_res: trap_cx_bottom={:#x} trap_cx_top={:#x}",
-            trap_cx_bottom, trap_cx_top
-        );
-        memory_set.insert_framed_area(
-            trap_cx_bottom.into(),
-            trap_cx_top.into(),
-            MapPermission::R | MapPermission::W,
-        );
-
-        //将初始进程的trap_cx映射到当前页表，确保可以在这个页表里写入
-        //实现无栈协程之后就不用考虑进程之间互相映射了
-        // 注意这里只复制了pte，没有复制物理页帧
-        let trap_cx_bottom_va: VirtAddr = trap_cx_bottom.into();
-        let trap_cx_bottom_ppn = memory_set
-            .translate(trap_cx_bottom_va.into())
-            .unwrap()
-            .ppn();
-
-        {
-            // 在一定区域中获取可变引用，保证离开时自动释放
-            let current_pagetable = &mut task_inner.memory_set.page_table;
-            debug!(
-                "map trap_cx in current pagetable trap_cx_bottom: {:#x}, trap_cx_bottom_ppn: \
-                 {:#x}, page_table: {:#x}",
-                trap_cx_bottom_va.0,
-                trap_cx_bottom_ppn.0,
-                current_pagetable.token()
-            );
-            current_pagetable.map(
-                trap_cx_bottom_va.floor(),
-                trap_cx_bottom_ppn,
-                PTEFlags::from_bits((MapPermission::R | MapPermission::W).bits()).unwrap(),
+            let trap_cx_bytes = unsafe {
+                slice::from_raw_parts(
+                    &trap_cx as *const TrapContext as *const u8,
+                    core::mem::size_of::<TrapContext>(),
+                )
+            };
+            // 从祖先进程复制来的地址空间里可能还留着这个 pid 以前的中断上下文
+            let trap_cx_bottom = trap_cx_bottom_from_tid(pid.0);
+            memory_set.remove_area_with_start_vpn(VirtAddr::from(trap_cx_bottom).floor());
+            memory_set.insert_framed_area_with_data(
+                trap_cx_bottom.into(),
+                (trap_cx_bottom + PAGE_SIZE).into(),
+                MapPermission::R | MapPermission::W,
+                trap_cx_bytes,
             );
-        }
+            let trap_cx_ppn = memory_set
+                .translate(VirtAddr::from(trap_cx_bottom).into())
+                .unwrap()
+                .ppn();
+            (user_stack_top, trap_cx_ppn)
+        };
 
         let child_task = Arc::new(TaskControlBlock {
             kstack,
-            tid,
+            tid: if thread { self.tid } else { pid.0 },
             pid,
-            send_sigchld_when_exit: false,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    is_zombie: false,
-                    memory_set,
-                    trap_cx_ppn,
-                    task_cx: TaskContext::goto_user_entry(kstack_top),
-                    task_status: TaskStatus::Ready,
-                    exit_code: None,
-                    syscall_times: [0; MAX_SYSCALL_NUM],
-                    first_time: None,
-                    clear_child_tid: 0,
-                    parent,
-                    children: Vec::new(),
-                    threads: Vec::new(),
-                    user_stack_top: task_inner.user_stack_top,
-                    fd_table: new_fd_table,
-                    signals: SignalFlags::empty(),
-                    clock_stop_watch: 0,
-                    user_clock: 0,
-                    kernel_clock: 0,
-                    heap_base: task_inner.heap_base.clone(),
-                    heap_end: task_inner.heap_end.clone(),
-                    work_dir: task_inner.work_dir.clone(),
-                    signal_actions: SignalActions::default(),
-                    signals_pending: task_inner.signals_pending,
-                    signal_mask: SignalFlags::empty(),
-                })
-            },
+            ns_pids,
+            send_sigchld_when_exit: exit_signal.contains(SignalFlags::SIGCHLD),
+            on_cpu: AtomicBool::new(false),
+            child_exit: WaitQueue::new(),
+            inner: SpinNoIrqLock::new(TaskControlBlockInner {
+                is_zombie: false,
+                memory_set,
+                trap_cx_ppn,
+                task_cx,
+                task_status: TaskStatus::Ready,
+                exit_code: None,
+                stop_report: None,
+                continue_report: false,
+                syscall_times: [0; MAX_SYSCALL_NUM],
+                syscall_trace: thread && task_inner.syscall_trace,
+                syscall_filter: task_inner.syscall_filter.clone(),
+                membarrier: MembarrierCmd::empty(),
+                cred: task_inner.cred.clone(),
+                cgroup: task_inner.cgroup.clone(),
+                rlimits,
+                sched: task_inner.sched.fork(),
+                first_time: None,
+                clear_child_tid: 0,
+                parent: parent.clone(),
+                children: Vec::new(),
+                threads: Vec::new(),
+                user_stack_top,
+                fd_table,
+                signals: SignalFlags::empty(),
+                clock_stop_watch: 0,
+                user_clock: 0,
+                kernel_clock: 0,
+                work_dir: task_inner.work_dir.clone(),
+                mnt_ns,
+                signal_actions,
+                signals_pending: task_inner.signals_pending,
+                signal_mask: task_inner.signal_mask,
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                deadlock: DeadlockDetector::new(),
+                real_timer: RealTimer::default(),
+                pgid: task_inner.pgid,
+                sid: task_inner.sid,
+                comm: task_inner.comm.clone(),
+            }),
         });
+        drop(task_inner);
 
-        task_inner.children.push(Arc::clone(&child_task));
-        // 这里复制父进程中断上下文，确保接下来能正确切换到子进程
-        let father_trap_cx = self.get_trap_cx();
-        let trap_cx = child_task.get_trap_cx();
-        let src_ptr = father_trap_cx as *const TrapContext;
-        let dst_ptr = trap_cx as *mut TrapContext;
-        debug!(
-            "copy trap_cx from {:#x} to {:#x}",
-            src_ptr as usize, dst_ptr as usize
-        );
-
-        unsafe {
-            core::ptr::copy(
-                src_ptr,
-                dst_ptr,
-                PAGE_SIZE / core::mem::size_of::<TrapContext>(),
-            );
+        if thread {
+            self.group_leader()
+                .inner_exclusive_access()
+                .threads
+                .push(Some(Arc::clone(&child_task)));
+        } else {
+            // CLONE_PARENT 的父进程已经退出时由当前任务收养
+            let parent = parent
+                .and_then(|parent| parent.upgrade())
+                .unwrap_or_else(|| self.clone());
+            child_task.inner_exclusive_access().parent = Some(Arc::downgrade(&parent));
+            parent
+                .inner_exclusive_access()
+                .children
+                .push(Arc::clone(&child_task));
+            insert_into_pid2process(child_task.pid.0, Arc::clone(&child_task));
         }
-
-        // fork出的子进程应该返回0
-        trap_cx.x[10] = 0;
-        trap_cx.kernel_sp = kstack_top;
-        let pid = child_task.pid.0.clone();
-        insert_into_pid2process(pid, Arc::clone(&child_task));
-        // add this thread to scheduler
-        add_task(child_task);
-        info!("fork: child pid[{}] add to scheduler", pid);
-
-        pid
+        if new_pid_ns {
+            pid_ns.set_init(&child_task);
+        }
+        if flags.contains(CloneFlags::CLONE_PARENT_SETTID) && ptid != 0 {
+            let tid = self.pid_ns().pid_of(child_task.pid.0).unwrap_or(0);
+            let inner = self.inner_exclusive_access();
+            put_user_tid(&mut inner.memory_set.lock(), &inner.cgroup, ptid, tid);
+        }
+        {
+            let tid = child_task.pid_ns().pid_of(child_task.pid.0).unwrap_or(0);
+            let mut child_inner = child_task.inner_exclusive_access();
+            if flags.contains(CloneFlags::CLONE_CHILD_SETTID) && ctid != 0 {
+                put_user_tid(
+                    &mut child_inner.memory_set.lock(),
+                    &child_inner.cgroup,
+                    ctid,
+                    tid,
+                );
+            }
+            if flags.contains(CloneFlags::CLONE_CHILD_CLEARTID) {
+                child_inner.clear_child_tid = ctid;
+            }
+        }
+        child_task
     }
 
-    /// clone2
-    pub fn clone2(
-        self: &Arc<Self>, _exit_signals: SignalFlags, _clone_signals: CloneFlags, stack_ptr: usize,
-        tls: usize,
-    ) -> Arc<TaskControlBlock> {
-        trace!("kernel: clone thread");
+    /// Create a child process running `elf_data` directly, without copying the address
+    /// space first as fork + exec does. The child inherits the fd table, working directory,
+    /// credentials, cgroup and mount namespace. Returns the child, already on the scheduler.
+    ///
+    /// `perm` 是程序文件的权限，和 exec 一样在子进程开始运行前锁定过滤器、换用 set-user-ID 的属主。
+    /// 程序或它的动态链接器无法加载时返回 [`MemorySet::from_elf`] 的错误，不创建子进程。
+    pub fn spawn(
+        self: &Arc<Self>, elf_data: &[u8], argv_vec: Vec<String>, envp_vec: Vec<String>,
+        perm: Option<InodePerm>,
+    ) -> Result<Arc<Self>, isize> {
+        trace!("[kernel: spawn]");
+        let comm = comm_of(&argv_vec);
+        let rlimits = self.group_leader().inner_exclusive_access().rlimits.clone();
+        let stack_size = rlimits.stack_size();
+        let (mut memory_set, ustack_top, entry_point, auxv) =
+            MemorySet::from_elf(elf_data, stack_size)?;
         let pid = pid_alloc();
-        let mut father_inner = self.inner_exclusive_access(file!(), line!());
+        let ns_pids = NsPids::alloc(&self.pid_ns(), pid.0);
+
+        // 用户栈布局与 exec 相同
+        let user_stack_top = ustack_top - 8;
+        let ustack_bottom = user_stack_top - stack_size + 8;
+        memory_set.insert_framed_area(
+            ustack_bottom.into(),
+            user_stack_top.into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        );
+
+        // build_stack 把用户栈的页映射进调用它的地址空间再写入，exec 借用的是随后被丢弃的旧地址空间。
+        // 这里父进程还要继续运行，改用一个临时页表，它与所有进程共享内核部分的映射
+        let mut scratch = MemorySet::new_process();
+        let parent_token = satp::read().bits();
+        unsafe {
+            satp::write(scratch.token());
+            asm!("sfence.vma");
+        }
+        let (user_sp, argc, argv_base, envp_base, aux_base) =
+            scratch.build_stack(user_stack_top, argv_vec, envp_vec, auxv, memory_set.token());
+        unsafe {
+            satp::write(parent_token);
+            asm!("sfence.vma");
+        }
+        drop(scratch);
 
-        // create a new thread.
-        // We did not alloc for stack space here
-        // 不需要分配用户栈，只分配内核栈和中断上下文
-        let thread_stack_top = if stack_ptr != 0 {
-            stack_ptr
-        } else {
-            self.inner_exclusive_access(file!(), line!()).user_stack_top
-        };
-        //这里是线程，所以tid = 父进程pid
-        let tid = self.pid.0;
         let kstack = kstack_alloc();
         let kstack_top = kstack.get_top();
+        let mut trap_cx = TrapContext::app_init_context(
+            entry_point,
+            user_sp,
+            KERNEL_SPACE.lock().token(),
+            kstack_top,
+            trap_handler as usize,
+        );
+        trap_cx.x[10] = argc;
+        trap_cx.x[11] = argv_base;
+        trap_cx.x[12] = envp_base;
+        trap_cx.x[13] = aux_base;
+        // 子进程的中断上下文直接写进它自己的地址空间，不必映射到父进程
+        let trap_cx_bytes = unsafe {
+            slice::from_raw_parts(
+                &trap_cx as *const TrapContext as *const u8,
+                core::mem::size_of::<TrapContext>(),
+            )
+        };
         let trap_cx_bottom = trap_cx_bottom_from_tid(pid.0);
-        let trap_cx_top = trap_cx_bottom + PAGE_SIZE;
-        let trap_cx_ppn = father_inner
-            .memory_set
-            .page_table
-            .translate(trap_cx_bottom.into())
-            .unwrap()
-            .ppn();
-
-        // 把子线程的中断上下文映射到当前地址空间
-        father_inner.memory_set.insert_framed_area(
+        memory_set.insert_framed_area_with_data(
             trap_cx_bottom.into(),
-            trap_cx_top.into(),
+            (trap_cx_bottom + PAGE_SIZE).into(),
             MapPermission::R | MapPermission::W,
+            trap_cx_bytes,
         );
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(trap_cx_bottom).into())
+            .unwrap()
+            .ppn();
 
-        let memory_set = MemorySet::from_existed_user(&father_inner.memory_set);
-        let new_task = Arc::new(Self {
+        let mut task_inner = self.inner_exclusive_access();
+        let mut fd_table = task_inner.fd_table.lock().clone();
+        fd_table.close_on_exec();
+        let mut syscall_filter = task_inner.syscall_filter.clone();
+        if let Some(filter) = syscall_filter.as_mut() {
+            filter.lock();
+        }
+        let mut cred = task_inner.cred.clone();
+        cred.apply_exec(perm);
+        let child_task = Arc::new(TaskControlBlock {
             kstack,
-            tid: tid,
-            pid: pid,
-            send_sigchld_when_exit: false, //todo
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    is_zombie: false,
-                    memory_set,
-                    trap_cx_ppn,
-                    task_cx: TaskContext::goto_user_entry(kstack_top),
-                    task_status: TaskStatus::Ready,
-                    exit_code: None,
-                    syscall_times: [0; MAX_SYSCALL_NUM],
-                    first_time: None,
-                    clear_child_tid: 0,
-                    parent: None,
-                    children: Vec::new(),
-                    threads: Vec::new(),
-                    user_stack_top: thread_stack_top, // todo
-                    fd_table: vec![
-                        // 0 -> stdin
-                        Some(Arc::new(Stdin)),
-                        // 1 -> stdout
-                        Some(Arc::new(Stdout)),
-                        // 2 -> stderr
-                        Some(Arc::new(Stdout)),
-                    ],
-                    signals: SignalFlags::empty(),
-                    clock_stop_watch: 0,
-                    user_clock: 0,
-                    kernel_clock: 0,
-                    heap_base: father_inner.heap_base.clone(), //todo 这里存在一个疑问，即共享堆空间，子线程修改堆空间后如何及时更新线程组下其他
-                    heap_end: father_inner.heap_end.clone(), //todo  的线程包括主线程，以及地址空间的修改也需要同步，后续需要修改为线程组使用同一个对象，暂时先别用线程
-                    work_dir: father_inner.work_dir.clone(),
-                    signal_actions: SignalActions::default(),
-                    signals_pending: father_inner.signals_pending,
-                    signal_mask: SignalFlags::empty(),
-                })
-            },
+            tid: pid.0,
+            pid,
+            ns_pids,
+            send_sigchld_when_exit: false,
+            on_cpu: AtomicBool::new(false),
+            child_exit: WaitQueue::new(),
+            inner: SpinNoIrqLock::new(TaskControlBlockInner {
+                is_zombie: false,
+                memory_set: Arc::new(SpinNoIrqLock::new(memory_set)),
+                trap_cx_ppn,
+                task_cx: TaskContext::goto_user_entry(kstack_top),
+                task_status: TaskStatus::Ready,
+                exit_code: None,
+                stop_report: None,
+                continue_report: false,
+                syscall_times: [0; MAX_SYSCALL_NUM],
+                syscall_trace: false,
+                syscall_filter,
+                membarrier: MembarrierCmd::empty(),
+                cred,
+                cgroup: task_inner.cgroup.clone(),
+                rlimits,
+                sched: task_inner.sched.fork(),
+                first_time: None,
+                clear_child_tid: 0,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                threads: Vec::new(),
+                user_stack_top,
+                fd_table: Arc::new(SpinNoIrqLock::new(fd_table)),
+                signals: SignalFlags::empty(),
+                clock_stop_watch: 0,
+                user_clock: 0,
+                kernel_clock: 0,
+                work_dir: task_inner.work_dir.clone(),
+                mnt_ns: task_inner.mnt_ns.clone(),
+                signal_actions: Arc::new(SpinNoIrqLock::new(SignalActions::default())),
+                signals_pending: SignalFlags::empty(),
+                signal_mask: SignalFlags::empty(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                deadlock: DeadlockDetector::new(),
+                real_timer: RealTimer::default(),
+                pgid: task_inner.pgid,
+                sid: task_inner.sid,
+                comm,
+            }),
         });
+        task_inner.children.push(Arc::clone(&child_task));
+        drop(task_inner);
 
-        father_inner.threads.push(Some(Arc::clone(&new_task)));
-
-        let trap_cx_ptr = new_task.get_trap_cx();
-
-        // I don't know if this is correct, too
-        *trap_cx_ptr = *self.get_trap_cx();
-
-        // for child process, fork returns 0
-        trap_cx_ptr.x[10] = 0;
-        // set tp reg
-        trap_cx_ptr.x[4] = tls;
-        // set sp reg
-        trap_cx_ptr.set_sp(
-            new_task
-                .inner_exclusive_access(file!(), line!())
-                .user_stack_top,
-        );
-        // modify kernel_sp in trap_cx
-        trap_cx_ptr.kernel_sp = new_task.kstack.get_top();
-
-        // add new task to scheduler
-        add_task(Arc::clone(&new_task));
-
-        new_task
+        insert_into_pid2process(child_task.pid.0, Arc::clone(&child_task));
+        add_task(Arc::clone(&child_task));
+        info!("spawn: child pid[{}] add to scheduler", child_task.pid.0);
+        Ok(child_task)
     }
 
     /// Only support processes with a single thread or self as the main thread
-    pub fn exec(self: &Arc<Self>, elf_data: &[u8], argv_vec: Vec<String>, envp_vec: Vec<String>) {
+    ///
+    /// 新程序无法加载时返回 [`MemorySet::from_elf`] 的错误，进程保持原样。
+    pub fn exec(
+        self: &Arc<Self>, elf_data: &[u8], argv_vec: Vec<String>, envp_vec: Vec<String>,
+    ) -> Result<(), isize> {
         trace!("[kernel: exec]");
         assert_eq!(self.pid.0, self.tid);
+        let comm = comm_of(&argv_vec);
         // memory_set with elf program headers/trampoline/trap context/user stack
         trace!("[kernel: exec] .. MemorySet::from_elf");
-        let (mut memory_set, user_heap_base, ustack_top, entry_point, auxv) =
-            MemorySet::from_elf(elf_data);
-        let mut task_inner = self.inner_exclusive_access(file!(), line!());
+        let stack_size = self.inner_exclusive_access().rlimits.stack_size();
+        let (mut memory_set, ustack_top, entry_point, auxv) =
+            MemorySet::from_elf(elf_data, stack_size)?;
+        // 即将离开原来的地址空间，vfork 的父进程可能在等
+        self.release_child_tid();
+        let mut task_inner = self.inner_exclusive_access();
+
+        // 描述符表不再与 clone 时共享它的任务共享，关闭设置了 FD_CLOEXEC 的描述符
+        let mut fd_table = task_inner.fd_table.lock().clone();
+        fd_table.close_on_exec();
+        task_inner.fd_table = Arc::new(SpinNoIrqLock::new(fd_table));
 
         // substitute memory_set
-        // set heap position
-        task_inner.heap_base = user_heap_base.into();
-        task_inner.heap_end = user_heap_base.into();
         // then we alloc user resource for main thread again
         // since memory_set has been changed
         trace!("[kernel: exec] .. alloc user resource for main thread again");
@@ -625,7 +850,7 @@ impl TaskControlBlock {
         // 为新地址空间分配用户栈和trap_cx
         //trap_cx由于虚拟地址按照pid划分，所以要把映射复制过来
         let ustack_top = task_inner.user_stack_top;
-        let ustack_bottom = ustack_top - USER_STACK_SIZE + 8;
+        let ustack_bottom = ustack_top - stack_size + 8;
         debug!(
             "[kernel: exec] alloc user stack ustack_bottom={:#x} ustack_top={:#x}",
             ustack_bottom, ustack_top
@@ -656,7 +881,7 @@ impl TaskControlBlock {
         debug!(
             "[kernel: exec] replace memory_set with new one, old: {:#x}, new: {:#x} 
             will dealloc old memory_set here",
-            task_inner.memory_set.token(),
+            task_inner.memory_set.lock().token(),
             memory_set.token()
         );
 
@@ -682,13 +907,14 @@ impl TaskControlBlock {
 
         // push arguments on user stack
         // let mut user_sp = ustack_top;
-        let (user_sp, argc, argv_base, envp_base, aux_base) = task_inner.memory_set.build_stack(
-            ustack_top,
-            argv_vec,
-            envp_vec,
-            auxv,
-            memory_set.page_table.token(),
-        );
+        let (user_sp, argc, argv_base, envp_base, aux_base) =
+            task_inner.memory_set.lock().build_stack(
+                ustack_top,
+                argv_vec,
+                envp_vec,
+                auxv,
+                memory_set.page_table.token(),
+            );
 
         // Enable kernel to visit user space
         unsafe {
@@ -697,7 +923,8 @@ impl TaskControlBlock {
 
         warn!("user_sp after push args: {:#x}", user_sp);
 
-        task_inner.memory_set = memory_set; // todo dealloc page here
+        // 与 CLONE_VM 创建的任务共享的旧地址空间留给它们，否则在这里回收
+        task_inner.memory_set = Arc::new(SpinNoIrqLock::new(memory_set));
 
         warn!("app entry: {:#x}", entry_point);
 
@@ -706,7 +933,7 @@ impl TaskControlBlock {
         let mut trap_cx = TrapContext::app_init_context(
             entry_point,
             user_sp,
-            KERNEL_SPACE.exclusive_access(file!(), line!()).token(),
+            KERNEL_SPACE.lock().token(),
             self.kstack.get_top(),
             trap_handler as usize,
         );
@@ -740,13 +967,28 @@ impl TaskControlBlock {
             "alloc trap_cx again: trap_cx_bottom={:#x} trap_cx_top={:#x}",
             trap_cx_bottom, trap_cx_top
         );
-        task_inner.memory_set.insert_framed_area_with_data(
+        task_inner.memory_set.lock().insert_framed_area_with_data(
             trap_cx_bottom.into(),
             trap_cx_top.into(),
             MapPermission::R | MapPermission::W,
             trap_cx_bytes,
         );
 
+        // 同步对象属于旧程序
+        task_inner.mutex_list.clear();
+        task_inner.semaphore_list.clear();
+        task_inner.condvar_list.clear();
+        task_inner.deadlock = DeadlockDetector::new();
+        task_inner.comm = comm;
+        // 信号处理函数在新程序里不存在，恢复默认动作，忽略的信号仍然忽略，也不再与其他任务共享
+        let mut signal_actions = task_inner.signal_actions.lock().clone();
+        for action in signal_actions.table.iter_mut() {
+            if action.sa_handler != SIG_IGN {
+                *action = SignalAction::default();
+            }
+        }
+        task_inner.signal_actions = Arc::new(SpinNoIrqLock::new(signal_actions));
+
         // 重新设置被调度后的跳转地址以切换地址空间
         task_inner.task_cx = TaskContext::goto_user_entry(self.kstack.get_top());
 
@@ -756,6 +998,7 @@ impl TaskControlBlock {
         }
 
         *self.get_trap_cx() = trap_cx;
+        Ok(())
     }
 
     // /// Create a new init_task
@@ -766,10 +1009,10 @@ impl TaskControlBlock {
     //     alloc_user_res: bool,
     // ) -> Self {
     //     info!("TaskControlBlock init_proc");
-    //     let tid = process.inner_exclusive_access(file!(), line!()).alloc_tid();
+    //     let tid = process.inner_exclusive_access().alloc_tid();
     //     if alloc_user_res {
     //         // todo: 封装为alloc_initproc_res();
-    //         let mut process_inner = process.inner_exclusive_access(file!(), line!());
+    //         let mut process_inner = process.inner_exclusive_access();
     //         // alloc user stack
     //         let ustack_bottom = ustack_top - USER_STACK_SIZE;
     //         debug!(
@@ -802,7 +1045,7 @@ impl TaskControlBlock {
     //             .translate(trap_cx_bottom_va.into())
     //             .unwrap()
     //             .ppn();
-    //         let current_pagetable = &mut KERNEL_SPACE.exclusive_access(file!(), line!()).page_table;
+    //         let current_pagetable = &mut KERNEL_SPACE.lock().page_table;
     //         debug!(
     //             "map trap_cx in current pagetable trap_cx_bottom: {:#x}, trap_cx_bottom_ppn: {:#x}, page_table: {:#x}",
     //             trap_cx_bottom_va.0, trap_cx_bottom_ppn.0, current_pagetable.token()
@@ -816,7 +1059,7 @@ impl TaskControlBlock {
     //     debug!("TaskUserRes allocated");
     //     let trap_cx_ppn = {
     //         //todo: 封装为get_trap_cx_ppn()，解耦合
-    //         let process_inner = process.inner_exclusive_access(file!(), line!());
+    //         let process_inner = process.inner_exclusive_access();
     //         let trap_cx_bottom_va: VirtAddr = trap_cx_bottom_from_tid(tid).into();
     //         debug!(
     //             "trap_cx_ppn = {:#x}",
@@ -834,7 +1077,7 @@ impl TaskControlBlock {
     //             .ppn()
     //     };
     //     let kstack_top = kstack.get_top();
-    //     let process_inner = process.inner_exclusive_access(file!(), line!());
+    //     let process_inner = process.inner_exclusive_access();
     //     let work_dir = Arc::clone(&process_inner.work_dir);
     //     drop(process_inner);
     //     Self {
@@ -844,7 +1087,7 @@ impl TaskControlBlock {
     //         pid: 0,                        //todo
     //         send_sigchld_when_exit: false, //todo
     //         inner: unsafe {
-    //             UPSafeCell::new(TaskControlBlockInner {
+    //             SpinNoIrqLock::new(TaskControlBlockInner {
     //                 trap_cx_ppn,
     //                 task_cx: TaskContext::goto_initproc_entry(kstack_top),
     //                 task_status: TaskStatus::Ready,
@@ -874,35 +1117,83 @@ impl TaskControlBlock {
     //     }
     // }
 
-    /// Deallocate user resource for a task
-    fn dealloc_user_res(&self) {
-        // dealloc tid
-        let mut task_inner = self.inner_exclusive_access(file!(), line!());
-        // dealloc ustack manually
-        let ustack_top = self.inner_exclusive_access(file!(), line!()).user_stack_top;
-        let ustack_bottom_va: VirtAddr = (ustack_top - USER_STACK_SIZE).into();
-        task_inner
-            .memory_set
-            .remove_area_with_start_vpn(ustack_bottom_va.into());
+    /// Deallocate the trap context and the user stack `clone_t` allocated for a task,
+    /// which live in an address space that may outlive it
+    pub fn dealloc_user_res(&self) {
+        let task_inner = self.inner_exclusive_access();
+        let mut memory_set = task_inner.memory_set.lock();
+        // dealloc ustack manually, if the kernel allocated one
+        let ustack_bottom_va: VirtAddr =
+            ustack_bottom_from_tid(THREAD_STACK_BASE, self.pid.0).into();
+        memory_set.remove_area_with_start_vpn(ustack_bottom_va.into());
         // dealloc trap_cx manually
-        let trap_cx_bottom_va: VirtAddr = trap_cx_bottom_from_tid(self.tid).into();
-        task_inner
-            .memory_set
-            .remove_area_with_start_vpn(trap_cx_bottom_va.into());
+        let trap_cx_bottom_va: VirtAddr = trap_cx_bottom_from_tid(self.pid.0).into();
+        memory_set.remove_area_with_start_vpn(trap_cx_bottom_va.into());
     }
 
     /// 设置 `clear_child_tid` 字段的 值
     pub fn set_tid_address(&self, tidptr: usize) {
-        let mut inner = self.inner_exclusive_access(file!(), line!());
+        let mut inner = self.inner_exclusive_access();
         inner.clear_child_tid = tidptr;
     }
 
-    pub fn get_tid(&self) -> usize {
-        let tid = self.tid;
-        tid
+    /// CLONE_CHILD_CLEARTID: when the current task leaves its address space on exit or
+    /// exec, zero the tid at `clear_child_tid` and wake a pthread_join waiting on it
+    ///
+    /// 只做一次。地址空间没有别的任务在用时不会有人等，直接跳过。
+    pub fn release_child_tid(&self) {
+        let mut inner = self.inner_exclusive_access();
+        let tidptr = core::mem::take(&mut inner.clear_child_tid);
+        if tidptr == 0 || Arc::strong_count(&inner.memory_set) == 1 {
+            return;
+        }
+        let token = inner.memory_set.lock().token();
+        // 唤醒等待者时不持有自己的 inner
+        drop(inner);
+        futex::clear_child_tid(token, tidptr);
     }
 }
 
+/// Where a task created by [`TaskControlBlock::clone_task`] starts running
+enum TaskEntry {
+    /// back in user space where the parent trapped, on `stack` (0 to keep or allocate one
+    /// as clone(2) does) with `tls` for CLONE_SETTLS
+    User { stack: usize, tls: usize },
+    /// `entry(arg)` in the kernel, never returning to user space
+    Kernel {
+        entry: extern "C" fn(usize) -> !,
+        arg:   usize,
+    },
+}
+
+/// Store `tid` as a pid_t at the user address `ptr` in `memory_set`, for
+/// CLONE_PARENT_SETTID and CLONE_CHILD_SETTID
+///
+/// 写时复制的页先换成这个地址空间自己的页帧，其他地址空间看不到这次写入。
+fn put_user_tid(memory_set: &mut MemorySet, cgroup: &Arc<Cgroup>, ptr: usize, tid: usize) {
+    let va = VirtAddr::from(ptr);
+    memory_set.page_fault(va.floor(), true, cgroup);
+    let Some(pte) = memory_set.translate(va.floor()) else {
+        return;
+    };
+    if pte.is_valid() && pte.writable() && pte.flags().contains(PTEFlags::U) {
+        *PhysAddr(PhysAddr::from(pte.ppn()).0 + va.page_offset()).get_mut::<u32>() = tid as u32;
+    }
+}
+
+/// The command name of a program started with `argv`, the file name of `argv[0]`
+/// truncated to 15 bytes like Linux's `TASK_COMM_LEN`
+fn comm_of(argv: &[String]) -> String {
+    let name = argv
+        .first()
+        .map_or("", |arg0| arg0.rsplit('/').next().unwrap_or(""));
+    let mut len = name.len().min(15);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    String::from(&name[..len])
+}
+
 #[derive(Copy, Clone, PartialEq)]
 /// The execution status of the current process
 pub enum TaskStatus {
@@ -912,6 +1203,8 @@ pub enum TaskStatus {
     Running,
     /// blocked, waiting
     Blocked,
+    /// stopped by SIGSTOP/SIGTSTP/SIGTTIN/SIGTTOU, waiting for SIGCONT
+    Stopped,
     /// wait father process to release resources
     Zombie,
     /// exit
@@ -937,16 +1230,14 @@ impl TaskControlBlockInner {
     #[allow(unused)]
     /// get the address of app's page table
     pub fn get_user_token(&self) -> usize {
-        self.memory_set.token()
+        self.memory_set.lock().token()
     }
-    /// allocate a new file descriptor
-    pub fn alloc_fd(&mut self) -> usize {
-        if let Some(fd) = (0..self.fd_table.len()).find(|fd| self.fd_table[*fd].is_none()) {
-            fd
-        } else {
-            self.fd_table.push(None);
-            self.fd_table.len() - 1
-        }
+    /// Resolve a page fault at `va` in the address space, new frames are charged to
+    /// the task's cgroup
+    pub fn page_fault(&mut self, va: VirtAddr, write: bool) -> bool {
+        self.memory_set
+            .lock()
+            .page_fault(va.floor(), write, &self.cgroup)
     }
 
     /// the count of tasks(threads) in this process
@@ -972,6 +1263,12 @@ impl TaskControlBlockInner {
         self.kernel_clock += self.clock_stop_watch - last_stop;
         self.kernel_clock
     }
+    /// count kernel clock time when switched out, the time off the CPU is not counted
+    pub fn kernel_clock_time_end(&mut self) {
+        let last_stop = self.clock_stop_watch;
+        self.clock_stop_watch = get_time();
+        self.kernel_clock += self.clock_stop_watch - last_stop;
+    }
     /// get clock time
     pub fn get_process_clock_time(&mut self) -> (i64, i64) {
         let last_stop = self.clock_stop_watch;
@@ -984,23 +1281,53 @@ impl TaskControlBlockInner {
         let mut children_kernel_clock: usize = 0;
         let mut children_user_clock: usize = 0;
         for c in &self.children {
-            children_kernel_clock += c.inner_exclusive_access(file!(), line!()).kernel_clock;
-            children_user_clock += c.inner_exclusive_access(file!(), line!()).user_clock;
+            children_kernel_clock += c.inner_exclusive_access().kernel_clock;
+            children_user_clock += c.inner_exclusive_access().user_clock;
         }
         (children_kernel_clock as i64, children_user_clock as i64)
     }
 
     /// mmap
     pub fn mmap(
-        &mut self, start_addr: usize, len: usize, _prot: usize, flags: usize, fd: usize,
+        &mut self, start_addr: usize, len: usize, prot: usize, flags: usize, fd: usize,
         offset: usize,
     ) -> isize {
         let flags = Flags::from_bits(flags as u32).unwrap();
-        let file = self.fd_table[fd].clone().unwrap();
-        let inode = cast_file_to_inode(file).unwrap();
+        if flags.contains(Flags::MAP_SHARED) && !flags.contains(Flags::MAP_ANONYMOUS) {
+            let Some(entry) = self.fd_table.lock().get(fd).cloned().flatten() else {
+                return EBADF;
+            };
+            // 共享映射要求文件可读，可写的映射还要求以 O_RDWR 打开
+            let access = entry.status_flags.bits() & 0o3;
+            if access == OpenFlags::O_WRONLY.bits() {
+                return EACCES;
+            }
+            let may_write = access == OpenFlags::O_RDWR.bits();
+            // io_uring 的环文件映射的是内核创建环时放进页缓存的页
+            let inode = match cast_file_to_io_ring(entry.file.clone()) {
+                Some(ring) => ring.inode(),
+                None => match cast_file_to_inode(entry.file) {
+                    Some(inode) => inode,
+                    None => return EBADF,
+                },
+            };
+            return self.memory_set.lock().mmap_shared(
+                start_addr,
+                len,
+                offset,
+                inode,
+                MmapProt::from_bits_truncate(prot as u32),
+                may_write,
+                flags.contains(Flags::MAP_FIXED),
+                &self.cgroup,
+            );
+        }
         let (context, length) = if flags.contains(Flags::MAP_ANONYMOUS) {
+            // 匿名映射的 fd 一般是 -1，不能去查 fd 表
             (Vec::new(), len)
         } else {
+            let file = self.fd_table.lock()[fd].clone().unwrap().file;
+            let inode = cast_file_to_inode(file).unwrap();
             let context = inode.read_all();
 
             let file_len = context.len();
@@ -1016,11 +1343,17 @@ impl TaskControlBlockInner {
         };
 
         self.memory_set
-            .mmap(start_addr, length, offset, context, flags)
+            .lock()
+            .mmap(start_addr, length, offset, context, flags, &self.cgroup)
     }
 
     ///munmap
     pub fn munmap(&mut self, start_addr: usize, len: usize) -> isize {
-        self.memory_set.munmap(start_addr, len)
+        self.memory_set.lock().munmap(start_addr, len)
+    }
+
+    /// msync
+    pub fn msync(&self, start_addr: usize, len: usize) -> isize {
+        self.memory_set.lock().msync(start_addr, len)
     }
 }
@@ -1,9 +1,10 @@
 //! RISC-V timer-related functionality
 
-use alloc::{collections::BinaryHeap, sync::Arc};
+use alloc::{sync::Arc, vec::Vec};
 use core::{
     cmp::Ordering,
     ops::{Add, AddAssign, Sub},
+    sync::atomic::{self, AtomicUsize},
 };
 
 use lazy_static::*;
@@ -12,8 +13,15 @@ use riscv::register::time;
 use crate::{
     config::CLOCK_FREQ,
     sbi::set_timer,
-    sync::UPSafeCell,
-    task::{current_task, suspend_current_and_run_next, wakeup_task, TaskControlBlock},
+    sync::SpinNoIrqLock,
+    task::{
+        block_current_and_run_next,
+        current_task,
+        wakeup_task,
+        SignalFlags,
+        TaskControlBlock,
+        TaskStatus,
+    },
 };
 ///纳秒转换关系
 pub const NSEC_PER_SEC: usize = 1_000_000_000;
@@ -27,6 +35,8 @@ const TICKS_PER_SEC: usize = 10;
 
 #[cfg(feature = "visionfive2")]
 const TICKS_PER_SEC: usize = 1;
+/// Clock cycles between two scheduler ticks
+pub const TICK_CYCLES: usize = CLOCK_FREQ / TICKS_PER_SEC;
 /// The number of milliseconds per second
 const MSEC_PER_SEC: usize = 1000;
 
@@ -144,6 +154,10 @@ impl TimeSpec {
     pub fn now() -> Self {
         TimeSpec::from_tick(get_time())
     }
+    /// Clock ticks in the duration, rounded up
+    pub fn to_tick(&self) -> usize {
+        self.tv_sec * CLOCK_FREQ + (self.tv_nsec * CLOCK_FREQ + NSEC_PER_SEC - 1) / NSEC_PER_SEC
+    }
 }
 
 /// Get the current time in ticks
@@ -151,6 +165,31 @@ pub fn get_time() -> usize {
     time::read()
 }
 
+/// CLOCK_REALTIME minus CLOCK_MONOTONIC in nanoseconds, moved by clock_settime
+///
+/// 没有 RTC，启动时墙上时间从 1970 年开始。
+static REALTIME_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// CLOCK_REALTIME, time since the epoch
+pub fn realtime() -> TimeSpec {
+    TimeSpec::from_ns(TimeSpec::now().to_ns() + REALTIME_OFFSET.load(atomic::Ordering::Relaxed))
+}
+
+/// Set CLOCK_REALTIME to `time`, false if it is before the boot
+pub fn set_realtime(time: TimeSpec) -> bool {
+    let Some(offset) = time.to_ns().checked_sub(TimeSpec::now().to_ns()) else {
+        return false;
+    };
+    REALTIME_OFFSET.store(offset, atomic::Ordering::Relaxed);
+    true
+}
+
+/// Clock tick at which CLOCK_REALTIME reads `time`, saturating at 0 for a time before the boot
+pub fn realtime_to_tick(time: TimeSpec) -> usize {
+    let offset = REALTIME_OFFSET.load(atomic::Ordering::Relaxed);
+    TimeSpec::from_ns(time.to_ns().saturating_sub(offset)).to_tick()
+}
+
 /// Get the current time in milliseconds
 pub fn get_time_ms() -> usize {
     time::read() * MSEC_PER_SEC / CLOCK_FREQ
@@ -161,16 +200,15 @@ pub fn get_time_us() -> usize {
     time::read() * MICRO_PER_SEC / CLOCK_FREQ
 }
 
-/// Set the next timer interrupt
-#[cfg(feature = "visionfive2")]
-pub fn set_next_trigger() {
-    set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
-}
-
-/// Set the next timer interrupt
-#[cfg(feature = "qemu")]
+/// Set the next timer interrupt, at the end of the time slice or at the earliest timer
 pub fn set_next_trigger() {
-    set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
+    let mut timers = TIMERS.lock();
+    let slice_end = get_time() + TICK_CYCLES;
+    let next = timers
+        .earliest()
+        .map_or(slice_end, |expire| expire.min(slice_end));
+    timers.next_trigger = next;
+    set_timer(next);
 }
 
 /// sleep for `ms` milliseconds not suspend current task
@@ -189,78 +227,213 @@ pub fn sleep_ms_until(ms: usize, mut f: impl FnMut() -> bool) {
     }
 }
 
-/// condvar for timer
-pub struct TimerCondVar {
-    /// The time when the timer expires, in milliseconds
-    pub expire_ms: usize,
-    /// The task to be woken up when the timer expires
-    pub task:      Arc<TaskControlBlock>,
+/// Clock ticks covered by one slot of the timer wheel (1ms)
+const WHEEL_GRANULARITY: usize = CLOCK_FREQ / MSEC_PER_SEC;
+/// Number of slots of the timer wheel
+const WHEEL_SLOTS: usize = 256;
+
+/// What happens to the task when its timer expires
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TimerKind {
+    /// wake up the sleeping task
+    Wakeup,
+    /// post SIGALRM to the process for its ITIMER_REAL
+    Alarm,
 }
 
-impl PartialEq for TimerCondVar {
-    fn eq(&self, other: &Self) -> bool {
-        self.expire_ms == other.expire_ms
-    }
+/// A task waiting for a point in time
+struct Timer {
+    /// clock tick at which the timer fires
+    expire: usize,
+    task:   Arc<TaskControlBlock>,
+    kind:   TimerKind,
 }
-impl Eq for TimerCondVar {}
-impl PartialOrd for TimerCondVar {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let a = -(self.expire_ms as isize);
-        let b = -(other.expire_ms as isize);
-        Some(a.cmp(&b))
-    }
+
+/// Hashed timer wheel
+///
+/// 每个槽覆盖 1ms，定时器按到期时刻所在的毫秒对槽数取模放进对应的槽，一圈以外的定时器
+/// 和一圈以内的混在同一个槽里，转到该槽时只唤醒已经到期的。时钟中断到来时从上次处理到的
+/// 槽一直转到当前时刻所在的槽。下一次时钟中断设在时间片结束和最早到期的定时器中较早的一个。
+struct TimerWheel {
+    slots:        Vec<Vec<Timer>>,
+    /// the wheel tick (`clock tick / WHEEL_GRANULARITY`) fired up to
+    current:      usize,
+    /// number of pending timers
+    len:          usize,
+    /// clock tick the next timer interrupt is programmed for
+    next_trigger: usize,
 }
 
-impl Ord for TimerCondVar {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+impl TimerWheel {
+    fn new() -> Self {
+        Self {
+            slots:        (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            current:      get_time() / WHEEL_GRANULARITY,
+            len:          0,
+            next_trigger: usize::MAX,
+        }
+    }
+
+    fn add(&mut self, expire: usize, task: Arc<TaskControlBlock>, kind: TimerKind) {
+        // 已经过去的时刻放进当前槽，下一次时钟中断就会处理
+        let tick = (expire / WHEEL_GRANULARITY).max(self.current);
+        self.slots[tick % WHEEL_SLOTS].push(Timer { expire, task, kind });
+        self.len += 1;
+    }
+
+    fn remove(&mut self, task: &Arc<TaskControlBlock>, kind: TimerKind) {
+        for slot in self.slots.iter_mut() {
+            let before = slot.len();
+            slot.retain(|timer| !(timer.kind == kind && Arc::ptr_eq(&timer.task, task)));
+            self.len -= before - slot.len();
+        }
+    }
+
+    /// Take out the timers expired at clock tick `now`
+    fn expire(&mut self, now: usize) -> Vec<Timer> {
+        let mut expired = Vec::new();
+        let now_tick = now / WHEEL_GRANULARITY;
+        let turns = (now_tick.saturating_sub(self.current) + 1).min(WHEEL_SLOTS);
+        for tick in self.current..self.current + turns {
+            let slot = &mut self.slots[tick % WHEEL_SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].expire <= now {
+                    expired.push(slot.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.len -= expired.len();
+        self.current = self.current.max(now_tick);
+        expired
+    }
+
+    /// The earliest expiry among the pending timers
+    fn earliest(&self) -> Option<usize> {
+        self.slots.iter().flatten().map(|timer| timer.expire).min()
     }
 }
 
 lazy_static! {
-    /// TIMERS: global instance: set of timer condvars
-    static ref TIMERS: UPSafeCell<BinaryHeap<TimerCondVar>> =
-        unsafe { UPSafeCell::new(BinaryHeap::<TimerCondVar>::new()) };
+    /// TIMERS: global instance: timer wheel of the sleeping tasks
+    static ref TIMERS: SpinNoIrqLock<TimerWheel> = SpinNoIrqLock::new(TimerWheel::new());
 }
 
-/// Add a timer
-pub fn add_timer(expire_ms: usize, task: Arc<TaskControlBlock>) {
-    trace!("kernel:pid[{}] add_timer", current_task().unwrap().pid.0);
-    let mut timers = TIMERS.exclusive_access(file!(), line!());
-    timers.push(TimerCondVar { expire_ms, task });
+/// Wake up `task` once the clock reaches tick `expire`
+pub fn add_timer(expire: usize, task: Arc<TaskControlBlock>) {
+    trace!("kernel:pid[{}] add_timer", task.pid.0);
+    insert_timer(expire, task, TimerKind::Wakeup);
 }
 
-/// Remove a timer
+/// Remove the wakeup timers of `task`
 pub fn remove_timer(task: Arc<TaskControlBlock>) {
-    //trace!("kernel:pid[{}] remove_timer", current_task().unwrap().process.upgrade().unwrap().getpid());
     trace!("kernel: remove_timer");
-    let mut timers = TIMERS.exclusive_access(file!(), line!());
-    let mut temp = BinaryHeap::<TimerCondVar>::new();
-    for condvar in timers.drain() {
-        if Arc::as_ptr(&task) != Arc::as_ptr(&condvar.task) {
-            temp.push(condvar);
-        }
+    TIMERS.lock().remove(&task, TimerKind::Wakeup);
+}
+
+fn insert_timer(expire: usize, task: Arc<TaskControlBlock>, kind: TimerKind) {
+    let mut timers = TIMERS.lock();
+    timers.add(expire, task, kind);
+    if expire < timers.next_trigger {
+        timers.next_trigger = expire;
+        set_timer(expire);
     }
-    timers.clear();
-    timers.append(&mut temp);
-    trace!("kernel: remove_timer END");
 }
 
-/// Check if the timer has expired
+/// Wake up the tasks whose timers have expired and post SIGALRM for the expired
+/// ITIMER_REAL timers
 pub fn check_timer() {
-    trace!("kernel:pid[{}] check_timer", current_task().unwrap().pid.0);
-    let current_ms = get_time_ms();
-    let mut timers = TIMERS.exclusive_access(file!(), line!());
-    while let Some(timer) = timers.peek() {
-        if timer.expire_ms <= current_ms {
-            wakeup_task(Arc::clone(&timer.task));
-            timers.pop();
-        } else {
-            break;
+    trace!("kernel: check_timer");
+    let now = get_time();
+    let expired = TIMERS.lock().expire(now);
+    for timer in expired {
+        match timer.kind {
+            TimerKind::Wakeup => {
+                // 可能已经被别的事件唤醒（如 futex wake）
+                let status = timer.task.inner_exclusive_access().task_status;
+                if status == TaskStatus::Blocked {
+                    wakeup_task(timer.task);
+                }
+            }
+            TimerKind::Alarm => fire_alarm(timer, now),
         }
     }
 }
 
+/// Post SIGALRM for an expired ITIMER_REAL and re-arm it if it is periodic
+fn fire_alarm(timer: Timer, now: usize) {
+    let mut inner = timer.task.inner_exclusive_access();
+    // setitimer 重新设置过的计时器留下的旧定时器直接丢弃
+    if inner.real_timer.expire != Some(timer.expire) {
+        return;
+    }
+    inner.signals |= SignalFlags::SIGALRM;
+    let interval = inner.real_timer.interval;
+    if interval == 0 {
+        inner.real_timer.expire = None;
+        return;
+    }
+    // 错过的周期不补发，下一次从当前时刻之后的第一个周期开始
+    let mut expire = timer.expire + interval;
+    if expire <= now {
+        expire += (now - expire) / interval * interval + interval;
+    }
+    inner.real_timer.expire = Some(expire);
+    drop(inner);
+    insert_timer(expire, timer.task, TimerKind::Alarm);
+}
+
+/// Arm the ITIMER_REAL of `process`, or disarm it if `new.it_value` is zero, returning the
+/// old setting
+pub fn set_real_timer(process: &Arc<TaskControlBlock>, new: ITimerVal) -> ITimerVal {
+    let now = get_time();
+    let mut inner = process.inner_exclusive_access();
+    let old = inner.real_timer.value(now);
+    let value = new.it_value.to_tick();
+    inner.real_timer = RealTimer {
+        expire:   (value != 0).then(|| now + value),
+        interval: new.it_interval.to_tick(),
+    };
+    drop(inner);
+    TIMERS.lock().remove(process, TimerKind::Alarm);
+    if value != 0 {
+        insert_timer(now + value, process.clone(), TimerKind::Alarm);
+    }
+    old
+}
+
+/// Disarm the ITIMER_REAL of an exiting process
+pub fn clear_real_timer(process: &Arc<TaskControlBlock>) {
+    process.inner_exclusive_access().real_timer.expire = None;
+    TIMERS.lock().remove(process, TimerKind::Alarm);
+}
+
+/// Whether some task sleeps on a timer, the scheduler must wait for it then
+pub fn timers_pending() -> bool {
+    TIMERS.lock().len > 0
+}
+
+/// Wait for the next timer interrupt when every task is asleep
+///
+/// 和 `wait_for_irq` 一样用 wfi 等待，内核态不打开 SIE，时钟中断只让 wfi 返回。
+pub fn wait_for_timer() {
+    unsafe { riscv::asm::wfi() };
+    check_timer();
+    set_next_trigger();
+}
+
+/// Block the current task until clock tick `expire` or until it is woken up by other
+/// means, returning whether `expire` has passed
+pub fn sleep_until(expire: usize) -> bool {
+    let task = current_task().unwrap();
+    add_timer(expire, task.clone());
+    block_current_and_run_next();
+    remove_timer(task);
+    get_time() >= expire
+}
+
 // /* Identifier for system-wide realtime clock.  */
 // # define CLOCK_REALTIME			0
 // /* Monotonic system-wide clock.  */
@@ -313,8 +486,9 @@ pub enum ClockId {
 }
 
 impl ClockId {
-    pub fn from(clock_id: usize) -> Self {
-        match clock_id {
+    /// `None` for an unknown clock id
+    pub fn from(clock_id: usize) -> Option<Self> {
+        let clock = match clock_id {
             CLOCK_REALTIME => ClockId::Realtime,
             CLOCK_MONOTONIC => ClockId::Monotonic,
             CLOCK_PROCESS_CPUTIME_ID => ClockId::ProcessCputimeId,
@@ -326,8 +500,28 @@ impl ClockId {
             CLOCK_REALTIME_ALARM => ClockId::RealtimeAlarm,
             CLOCK_BOOTTIME_ALARM => ClockId::BoottimeAlarm,
             CLOCK_TAI => ClockId::Tai,
-            _ => panic!("clock_id {:?} not supported", clock_id),
-        }
+            _ => return None,
+        };
+        Some(clock)
+    }
+
+    /// Clocks reading CLOCK_REALTIME
+    pub fn is_realtime(&self) -> bool {
+        matches!(
+            self,
+            ClockId::Realtime | ClockId::RealtimeCoarse | ClockId::Tai
+        )
+    }
+
+    /// Clocks reading CLOCK_MONOTONIC, the kernel never suspends
+    pub fn is_monotonic(&self) -> bool {
+        matches!(
+            self,
+            ClockId::Monotonic
+                | ClockId::MonotonicRaw
+                | ClockId::MonotonicCoarse
+                | ClockId::Boottime
+        )
     }
 }
 
@@ -368,8 +562,20 @@ pub struct TimeVal {
     pub tv_usec: usize,
 }
 
+impl TimeVal {
+    /// Clock ticks in the duration, rounded up
+    pub fn to_tick(&self) -> usize {
+        self.tv_sec * CLOCK_FREQ + (self.tv_usec * CLOCK_FREQ + USEC_PER_SEC - 1) / USEC_PER_SEC
+    }
+    pub fn from_tick(tick: usize) -> Self {
+        Self {
+            tv_sec:  tick / CLOCK_FREQ,
+            tv_usec: (tick % CLOCK_FREQ) * USEC_PER_SEC / CLOCK_FREQ,
+        }
+    }
+}
+
 /// [`getitimer`] / [`setitimer`] 指定的类型，用户执行系统调用时获取和输入的计时器
-// todo 还未投入使用
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default)]
 pub struct ITimerVal {
@@ -378,3 +584,28 @@ pub struct ITimerVal {
     /// 计时器当前所剩时间
     pub it_value:    TimeVal,
 }
+
+/// ITIMER_REAL state of a process, kept in the TCB of the thread group leader
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RealTimer {
+    /// clock tick of the next SIGALRM, None when disarmed
+    pub expire:   Option<usize>,
+    /// period in clock ticks, 0 for a one-shot timer
+    pub interval: usize,
+}
+
+impl RealTimer {
+    /// The setting as seen by getitimer at clock tick `now`
+    pub fn value(&self, now: usize) -> ITimerVal {
+        ITimerVal {
+            it_interval: TimeVal::from_tick(self.interval),
+            // 已到期但还没处理的计时器按 1 微秒报告，和未设置区分开
+            it_value:    self.expire.map_or(TimeVal::default(), |expire| {
+                TimeVal::from_tick(expire.saturating_sub(now)).max(TimeVal {
+                    tv_sec:  0,
+                    tv_usec: 1,
+                })
+            }),
+        }
+    }
+}
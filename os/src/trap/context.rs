@@ -17,6 +17,8 @@ pub struct TrapContext {
     pub kernel_sp:    usize,
     /// Virtual address of trap handler entry point in kernel
     pub trap_handler: usize,
+    /// Hart id the kernel keeps in tp, saved on each return to user space
+    pub kernel_tp:    usize,
 }
 
 impl TrapContext {
@@ -40,6 +42,7 @@ impl TrapContext {
             kernel_satp,  // addr of page table
             kernel_sp,    // kernel stack
             trap_handler, // addr of trap_handler function
+            kernel_tp: 0, // set by __restore
         };
         cx.set_sp(sp); // app's user stack pointer
         cx // return initial Trap Context of app
@@ -28,16 +28,26 @@ use riscv::register::{
 
 use crate::{
     config::__breakpoint,
+    drivers::{handle_irq, handle_media_change, poll_block_io, poll_media_change},
+    fs::tty,
+    lang_items::Symbolized,
+    mm::{handle_user_fault, VirtAddr},
+    net,
+    profile::{self, TrapKind},
+    smp,
     syscall::{self, syscall},
     task::{
-        check_signals_of_current,
         current_add_signal,
         current_task,
         current_trap_cx,
         current_trap_cx_user_va,
         current_user_token,
         exit_current_and_run_next,
+        handle_signals,
+        kstack_guard_owner,
+        scheduler_tick,
         suspend_current_and_run_next,
+        try_current_task,
         SignalFlags,
         INITPROC,
     },
@@ -46,6 +56,7 @@ use crate::{
 
 global_asm!(include_str!("trap.S"));
 global_asm!(include_str!("init_entry.S"));
+global_asm!(include_str!("sigreturn.S"));
 
 /// Initialize trap handling
 pub fn init() {
@@ -77,6 +88,13 @@ pub fn enable_timer_interrupt() {
     }
 }
 
+/// enable the software interrupt other harts raise through SBI IPI
+pub fn enable_soft_interrupt() {
+    unsafe {
+        sie::set_ssoft();
+    }
+}
+
 /// trap handler
 #[no_mangle]
 pub fn trap_handler() -> ! {
@@ -85,6 +103,7 @@ pub fn trap_handler() -> ! {
     let stval = stval::read();
     let sepc = sepc::read();
     let call_trap_process_satp = satp::read().bits();
+    let start = profile::start();
     let mut result = -1;
     info!(
         "[kernel] trap triggered, trap_handler: scause = {:?}, stval = {:#x}, sepc = {:#x}",
@@ -99,7 +118,7 @@ pub fn trap_handler() -> ! {
             //进入内核态之前，计算用户态已运行的时间
             current_task()
                 .unwrap()
-                .inner_exclusive_access(file!(), line!())
+                .inner_exclusive_access()
                 .user_clock_time_end();
 
             // jump to next instruction anyway
@@ -112,10 +131,24 @@ pub fn trap_handler() -> ! {
                 cx.x[17],
                 [cx.x[10], cx.x[11], cx.x[12], cx.x[13], cx.x[14], cx.x[15]],
             );
+            // 时钟中断里记下的插拔卡在这里处理，识别新卡时可以睡眠
+            handle_media_change();
+            profile::record_trap(TrapKind::Syscall, start);
             // // cx is changed during sys_exec, so we have to call it again
             // cx = current_trap_cx();
             // cx.x[10] = result as usize;
         }
+        Trap::Exception(
+            fault @ (Exception::StorePageFault
+            | Exception::LoadPageFault
+            | Exception::InstructionPageFault),
+        ) if current_task()
+            .unwrap()
+            .inner_exclusive_access()
+            .page_fault(VirtAddr::from(stval), fault == Exception::StorePageFault) =>
+        {
+            profile::record_trap(TrapKind::PageFault, start);
+        }
         Trap::Exception(Exception::StoreFault)
         | Trap::Exception(Exception::StorePageFault)
         | Trap::Exception(Exception::InstructionFault)
@@ -130,17 +163,40 @@ pub fn trap_handler() -> ! {
                 current_trap_cx().sepc,
             );
             current_add_signal(SignalFlags::SIGSEGV);
+            profile::record_trap(TrapKind::PageFault, start);
         }
         Trap::Exception(Exception::IllegalInstruction) => {
+            profile::record_trap(TrapKind::IllegalInstruction, start);
             exit_current_and_run_next(-1);
             current_add_signal(SignalFlags::SIGILL);
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
-            set_next_trigger();
+            // 先唤醒到期的任务，下一次时钟中断才能按剩下的定时器设置
             check_timer();
-            debug!("Interrupt::SupervisorTimer suspend_current_and_run_next");
+            set_next_trigger();
+            if smp::is_boot_hart() {
+                poll_media_change();
+                poll_block_io();
+                net::poll();
+                tty::poll_input();
+            }
+            // 不计入切换到其他任务运行的时间
+            profile::record_trap(TrapKind::Timer, start);
+            // 时间片用完才让出，定时器提前触发的中断不打断当前任务
+            if scheduler_tick() {
+                debug!("Interrupt::SupervisorTimer suspend_current_and_run_next");
+                suspend_current_and_run_next();
+                debug!("back from timer interrupt");
+            }
+        }
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            handle_irq();
+            profile::record_trap(TrapKind::External, start);
+        }
+        Trap::Interrupt(Interrupt::SupervisorSoft) => {
+            // 别的 hart 放入了就绪任务，让出 CPU 重新调度
+            smp::clear_ipi();
             suspend_current_and_run_next();
-            debug!("back from timer interrupt");
         }
         _ => {
             panic!(
@@ -152,18 +208,20 @@ pub fn trap_handler() -> ! {
             );
         }
     }
-    //check signals
-    if let Some((errno, msg)) = check_signals_of_current() {
-        trace!("[kernel] trap_handler: .. check signals {}", msg);
-        exit_current_and_run_next(errno);
+    // 处理信号，进入用户的信号处理函数时系统调用的返回值存进信号帧，a0 换成信号编号
+    let is_syscall = matches!(scause.cause(), Trap::Exception(Exception::UserEnvCall));
+    if let Some(signo) = handle_signals(is_syscall.then_some(result)) {
+        result = signo as isize;
     }
 
     let leave_trap_process_satp = satp::read().bits();
 
     match scause.cause() {
         Trap::Exception(Exception::UserEnvCall) => {
-            if syscall_num == syscall::SYSCALL_EXECVE as i32 {
-                // cx is changed during sys_exec, so we have to call it again
+            if syscall_num == syscall::SYSCALL_EXECVE as i32
+                || syscall_num == syscall::SYSCALL_RESTORE as i32
+            {
+                // cx is changed during sys_exec and sys_restore, so we have to call it again
                 let cx = current_trap_cx();
                 cx.x[10] = result as usize;
                 match current_task().unwrap().pid.0 {
@@ -218,7 +276,7 @@ pub fn trap_return() -> ! {
     //从内核态返回后，计算内核态运行时间
     current_task()
         .unwrap()
-        .inner_exclusive_access(file!(), line!())
+        .inner_exclusive_access()
         .user_clock_time_start();
 
     let trap_cx_user_va: usize = current_trap_cx_user_va().into();
@@ -250,15 +308,41 @@ pub fn trap_return() -> ! {
     }
 }
 
+/// handle a page fault on a user address from kernel code accessing user memory,
+/// returning to the faulting instruction if the page could be mapped
+#[no_mangle]
+pub fn kernel_page_fault() {
+    let write = scause::read().cause() == Trap::Exception(Exception::StorePageFault);
+    if !handle_user_fault(satp::read().bits(), VirtAddr::from(stval::read()), write) {
+        trap_from_kernel();
+    }
+}
+
 /// handle trap from kernel
 #[no_mangle]
 pub fn trap_from_kernel() -> ! {
+    let stval = stval::read();
     error!(
-        "stval = {:#x}, sepc = {:#x}, satp = {:#x}",
-        stval::read(),
-        sepc::read(),
+        "stval = {:#x}, sepc = {}, satp = {:#x}",
+        stval,
+        Symbolized(sepc::read()),
         satp::read().bits()
     );
+    #[cfg(feature = "guard_heap")]
+    crate::mm::guard_heap::report_fault(stval);
+    // 已经在应急栈上了，panic 的回溯会从这里接回溢出的内核栈
+    if let Some(kstack_id) = kstack_guard_owner(stval) {
+        match try_current_task().filter(|task| task.kstack.0 == kstack_id) {
+            Some(task) => panic!(
+                "kernel stack overflow of pid {} tid {}: access to guard page at {:#x}",
+                task.pid.0, task.tid, stval
+            ),
+            None => panic!(
+                "kernel stack overflow: access to guard page of kernel stack {} at {:#x}",
+                kstack_id, stval
+            ),
+        }
+    }
     panic!("a trap {:?} from kernel!", scause::read().cause());
 }
 
@@ -267,10 +351,7 @@ pub fn initproc_entry() -> ! {
     debug!("entering initproc");
     set_user_trap_entry();
     let trap_cx_user_va: usize = current_trap_cx_user_va().into();
-    let user_satp = INITPROC
-        .inner_exclusive_access(file!(), line!())
-        .memory_set
-        .token();
+    let user_satp = INITPROC.get_user_token();
     debug!(
         "[kernel] initproc_entry, trap_cx_user_va = {:#x}, user_satp = {:#x}",
         trap_cx_user_va, user_satp
@@ -41,6 +41,15 @@ pub struct MachineInfo {
     pub bootargs_len: usize,
 }
 
+impl MachineInfo {
+    /// Kernel command line
+    pub fn bootargs(&self) -> Option<&str> {
+        self.bootargs
+            .as_ref()
+            .and_then(|x| core::str::from_utf8(&x[..self.bootargs_len]).ok())
+    }
+}
+
 impl Debug for MachineInfo {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let index = self.model.iter().position(|&x| x == 0).unwrap_or(32);
@@ -0,0 +1,183 @@
+//! Frame pointer backtrace for user panics, return addresses are resolved
+//! against the `.symtab` of the program's own ELF when it can be read
+
+use alloc::{string::String, vec::Vec};
+use core::arch::asm;
+
+use super::{close, fs::c_path, open, read, sync::SpinLock, OpenFlags};
+
+const BACKTRACE_DEPTH: usize = 32;
+/// 相邻两个栈帧之间的最大距离，超过就认为 fp 已经坏了
+const MAX_FRAME_SIZE: usize = 0x10_0000;
+
+/// argv[0], used to find our own ELF
+static PROGRAM: SpinLock<&'static str> = SpinLock::new("");
+
+pub(crate) fn set_program(path: &'static str) {
+    PROGRAM.with(|program| *program = path);
+}
+
+/// Return addresses of the callers, innermost first
+fn return_addresses() -> Vec<usize> {
+    let mut fp: usize;
+    unsafe { asm!("mv {}, fp", out(reg) fp) };
+    let mut addrs = Vec::new();
+    while fp != 0 && fp % 8 == 0 && addrs.len() < BACKTRACE_DEPTH {
+        // fp - 8 存返回地址，fp - 16 存上一帧的 fp
+        let ra = unsafe { *((fp - 8) as *const usize) };
+        let prev = unsafe { *((fp - 16) as *const usize) };
+        if ra == 0 {
+            break;
+        }
+        addrs.push(ra);
+        if prev <= fp || prev - fp > MAX_FRAME_SIZE {
+            break;
+        }
+        fp = prev;
+    }
+    addrs
+}
+
+fn read_file(path: &str) -> Option<Vec<u8>> {
+    let fd = open(c_path(path).as_str(), OpenFlags::RDONLY);
+    if fd < 0 {
+        return None;
+    }
+    let mut data = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let len = read(fd as usize, &mut buf);
+        if len <= 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..len as usize]);
+    }
+    close(fd as usize);
+    Some(data)
+}
+
+fn u16_at(data: &[u8], off: usize) -> Option<usize> {
+    let bytes = data.get(off..off + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+fn u32_at(data: &[u8], off: usize) -> Option<usize> {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(data.get(off..off + 4)?);
+    Some(u32::from_le_bytes(bytes) as usize)
+}
+
+fn u64_at(data: &[u8], off: usize) -> Option<usize> {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(data.get(off..off + 8)?);
+    Some(u64::from_le_bytes(bytes) as usize)
+}
+
+const SHT_SYMTAB: usize = 2;
+const STT_FUNC: u8 = 2;
+const SYM_SIZE: usize = 24;
+
+/// The `.symtab` and its string table of an ELF64 image
+struct SymbolTable<'a> {
+    symtab: &'a [u8],
+    strtab: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    fn parse(elf: &'a [u8]) -> Option<Self> {
+        if elf.get(..4)? != b"\x7fELF" {
+            return None;
+        }
+        let shoff = u64_at(elf, 0x28)?;
+        let shentsize = u16_at(elf, 0x3a)?;
+        let shnum = u16_at(elf, 0x3c)?;
+        let section = |idx: usize| -> Option<(usize, usize, usize, usize)> {
+            let sh = shoff + idx * shentsize;
+            // (type, offset, size, link)
+            Some((
+                u32_at(elf, sh + 4)?,
+                u64_at(elf, sh + 0x18)?,
+                u64_at(elf, sh + 0x20)?,
+                u32_at(elf, sh + 0x28)?,
+            ))
+        };
+        let (_, offset, size, link) = (0..shnum)
+            .filter_map(section)
+            .find(|(sh_type, ..)| *sh_type == SHT_SYMTAB)?;
+        let (_, str_offset, str_size, _) = section(link)?;
+        Some(Self {
+            symtab: elf.get(offset..offset + size)?,
+            strtab: elf.get(str_offset..str_offset + str_size)?,
+        })
+    }
+
+    /// The function containing `addr` and the offset into it
+    fn lookup(&self, addr: usize) -> Option<(&'a str, usize)> {
+        self.symtab.chunks_exact(SYM_SIZE).find_map(|sym| {
+            let value = u64_at(sym, 8)?;
+            let size = u64_at(sym, 16)?;
+            if sym[4] & 0xf != STT_FUNC || addr < value || addr >= value + size {
+                return None;
+            }
+            let name = self.strtab.get(u32_at(sym, 0)?..)?;
+            let len = name.iter().position(|&b| b == 0)?;
+            Some((core::str::from_utf8(&name[..len]).ok()?, addr - value))
+        })
+    }
+}
+
+/// Demangle legacy Rust symbols (`_ZN3foo3bar17h<hash>E` -> `foo::bar`),
+/// anything else is returned as is
+fn demangle(name: &str) -> String {
+    let mut rest = match name.strip_prefix("_ZN") {
+        Some(rest) => rest,
+        None => return String::from(name),
+    };
+    let mut path = String::new();
+    while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit()) {
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) => len,
+            Err(_) => break,
+        };
+        let segment = match rest.get(digits..digits + len) {
+            Some(segment) => segment,
+            None => break,
+        };
+        rest = &rest[digits + len..];
+        // 最后一段是 h 加 16 位十六进制的哈希
+        if segment.len() == 17 && segment.starts_with('h') && rest == "E" {
+            break;
+        }
+        if !path.is_empty() {
+            path.push_str("::");
+        }
+        path.push_str(segment);
+    }
+    if path.is_empty() {
+        String::from(name)
+    } else {
+        path
+    }
+}
+
+/// Print the call stack of the caller
+pub fn print_backtrace() {
+    let addrs = return_addresses();
+    let program = PROGRAM.with(|program| *program);
+    let elf = if program.is_empty() {
+        None
+    } else {
+        read_file(program)
+    };
+    let symbols = elf.as_deref().and_then(SymbolTable::parse);
+    println!("Backtrace:");
+    for (i, &ra) in addrs.iter().enumerate() {
+        // ra 指向 call 的下一条指令，减一落回调用点所在的函数
+        match symbols.as_ref().and_then(|symbols| symbols.lookup(ra - 1)) {
+            Some((name, offset)) => {
+                println!("  #{:<2} {:#x} {}+{:#x}", i, ra, demangle(name), offset + 1)
+            }
+            None => println!("  #{:<2} {:#x}", i, ra),
+        }
+    }
+}
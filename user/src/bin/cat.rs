@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, fs::c_path, open, read, write, OpenFlags};
+
+fn copy_to_stdout(fd: usize) {
+    let mut buf = [0u8; 512];
+    loop {
+        let len = read(fd, &mut buf);
+        if len <= 0 {
+            break;
+        }
+        write(1, &buf[..len as usize]);
+    }
+}
+
+/// cat [file...], reads stdin without arguments
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc < 2 {
+        copy_to_stdout(0);
+        return 0;
+    }
+    let mut ret = 0;
+    for path in argv.iter().skip(1) {
+        if *path == "-" {
+            copy_to_stdout(0);
+            continue;
+        }
+        let fd = open(c_path(path).as_str(), OpenFlags::RDONLY);
+        if fd < 0 {
+            println!("cat: {}: {}", path, fd);
+            ret = 1;
+            continue;
+        }
+        copy_to_stdout(fd as usize);
+        close(fd as usize);
+    }
+    ret
+}
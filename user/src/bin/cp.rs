@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+extern crate alloc;
+
+use alloc::string::String;
+use user_lib::{
+    close,
+    fs::{c_path, stat},
+    open, read, write, OpenFlags,
+};
+
+/// Copy `src` to `dst`, a directory `dst` receives a file of the same name
+fn copy(src: &str, dst: &str) -> Result<(), isize> {
+    let mut target = String::from(dst);
+    if stat(dst).map(|st| st.is_dir()).unwrap_or(false) {
+        target = String::from(dst.trim_end_matches('/'));
+        target.push('/');
+        target.push_str(src.rsplit('/').next().unwrap_or(src));
+    }
+    let src_fd = open(c_path(src).as_str(), OpenFlags::RDONLY);
+    if src_fd < 0 {
+        return Err(src_fd);
+    }
+    let dst_fd = open(
+        c_path(&target).as_str(),
+        OpenFlags::CREATE | OpenFlags::WRONLY | OpenFlags::TRUNC,
+    );
+    if dst_fd < 0 {
+        close(src_fd as usize);
+        return Err(dst_fd);
+    }
+    let mut buf = [0u8; 512];
+    let mut ret = Ok(());
+    loop {
+        let len = read(src_fd as usize, &mut buf);
+        if len <= 0 {
+            break;
+        }
+        let written = write(dst_fd as usize, &buf[..len as usize]);
+        if written != len {
+            ret = Err(written.min(-1));
+            break;
+        }
+    }
+    close(src_fd as usize);
+    close(dst_fd as usize);
+    ret
+}
+
+/// cp src dst
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc != 3 {
+        println!("usage: cp <src> <dst>");
+        return 1;
+    }
+    match copy(argv[1], argv[2]) {
+        Ok(()) => 0,
+        Err(err) => {
+            println!("cp: cannot copy '{}' to '{}': {}", argv[1], argv[2], err);
+            1
+        }
+    }
+}
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::fs::{read_to_string, statfs};
+
+fn report(fs: &str, target: &str) {
+    match statfs(target) {
+        Ok(st) => {
+            let kb = |blocks: u64| blocks * st.f_bsize / 1024;
+            println!(
+                "{:<16} {:>10} {:>10} {:>10} {}",
+                fs,
+                kb(st.f_blocks),
+                kb(st.f_blocks - st.f_bfree),
+                kb(st.f_bavail),
+                target
+            );
+        }
+        Err(err) => println!("df: {}: {}", target, err),
+    }
+}
+
+/// df [path...], without arguments every mount in /proc/mounts
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    println!(
+        "{:<16} {:>10} {:>10} {:>10} {}",
+        "Filesystem", "1K-blocks", "Used", "Available", "Mounted on"
+    );
+    if argc > 1 {
+        for path in argv.iter().skip(1) {
+            report(path, path);
+        }
+        return 0;
+    }
+    match read_to_string("/proc/mounts") {
+        Ok(mounts) => {
+            for line in mounts.lines() {
+                let mut fields = line.split_whitespace();
+                if let (Some(fs), Some(target)) = (fields.next(), fields.next()) {
+                    report(fs, target);
+                }
+            }
+        }
+        Err(_) => report("rootfs", "/"),
+    }
+    0
+}
@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    set_console_loglevel, syslog, write, SYSLOG_ACTION_CLEAR, SYSLOG_ACTION_READ_ALL,
+    SYSLOG_ACTION_READ_CLEAR,
+};
+
+const LOG_BUF_LEN: usize = 1 << 16;
+
+// 放在 .bss 里，不必为一次读取去扩展堆
+static mut BUF: [u8; LOG_BUF_LEN] = [0; LOG_BUF_LEN];
+
+/// dmesg [-c | -C | -n level]
+#[no_mangle]
+pub fn main(_argc: usize, argv: &[&str]) -> i32 {
+    let action = match argv.get(1) {
+        None => SYSLOG_ACTION_READ_ALL,
+        Some(&"-c") => SYSLOG_ACTION_READ_CLEAR,
+        Some(&"-C") => SYSLOG_ACTION_CLEAR,
+        Some(&"-n") => {
+            let level = argv.get(2).and_then(|level| level.parse::<usize>().ok());
+            return match level.map(set_console_loglevel) {
+                Some(0) => 0,
+                _ => {
+                    println!("dmesg: usage: dmesg -n <1-8>");
+                    -1
+                }
+            };
+        }
+        Some(arg) => {
+            println!("dmesg: unknown option {}", arg);
+            return -1;
+        }
+    };
+    let buf = unsafe { &mut BUF };
+    let len = syslog(action, buf);
+    if len < 0 {
+        println!("dmesg: syslog failed: {}", len);
+        return -1;
+    }
+    write(1, &buf[..len as usize]);
+    0
+}
@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{kill, signal};
+
+/// Signal number from "9", "KILL" or "SIGKILL"
+fn parse_signal(name: &str) -> Option<usize> {
+    if let Ok(signum) = name.parse::<usize>() {
+        return Some(signum);
+    }
+    let name = name.trim_start_matches("SIG");
+    let signum = match name {
+        "HUP" => signal::SIGHUP,
+        "INT" => signal::SIGINT,
+        "QUIT" => signal::SIGQUIT,
+        "ABRT" => signal::SIGABRT,
+        "KILL" => signal::SIGKILL,
+        "USR1" => signal::SIGUSR1,
+        "SEGV" => signal::SIGSEGV,
+        "USR2" => signal::SIGUSR2,
+        "PIPE" => signal::SIGPIPE,
+        "ALRM" => signal::SIGALRM,
+        "TERM" => signal::SIGTERM,
+        "CHLD" => signal::SIGCHLD,
+        "CONT" => signal::SIGCONT,
+        "STOP" => signal::SIGSTOP,
+        "TSTP" => signal::SIGTSTP,
+        _ => return None,
+    };
+    Some(signum)
+}
+
+/// kill [-signal] pid...
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let mut signum = signal::SIGTERM;
+    let mut pids = &argv[1..];
+    if let Some(name) = argv.get(1).and_then(|arg| arg.strip_prefix('-')) {
+        signum = match parse_signal(name) {
+            // kill 的信号位图只有 32 位
+            Some(signum) if (1..=32).contains(&signum) => signum,
+            _ => {
+                println!("kill: unknown signal {}", name);
+                return 1;
+            }
+        };
+        pids = &argv[2..];
+    }
+    if argc < 2 || pids.is_empty() {
+        println!("usage: kill [-signal] <pid>...");
+        return 1;
+    }
+    let mut ret = 0;
+    for pid in pids.iter() {
+        let err = match pid.parse::<usize>() {
+            // 内核的 kill 按信号位图而不是信号编号接收
+            Ok(pid) => kill(pid, (1u32 << (signum - 1)) as i32),
+            Err(_) => -1,
+        };
+        if err < 0 {
+            println!("kill: ({}): {}", pid, err);
+            ret = 1;
+        }
+    }
+    ret
+}
@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use user_lib::fs::{opendir, stat, S_IFDIR, S_IFMT};
+
+fn list(path: &str, long: bool) -> i32 {
+    let dir = match opendir(path) {
+        Ok(dir) => dir,
+        Err(err) => {
+            println!("ls: cannot access '{}': {}", path, err);
+            return 1;
+        }
+    };
+    for entry in dir {
+        if !long {
+            println!("{}", entry.name);
+            continue;
+        }
+        let mut full = String::from(path.trim_end_matches('/'));
+        full.push('/');
+        full.push_str(&entry.name);
+        match stat(&full) {
+            Ok(st) => {
+                let kind = if st.mode & S_IFMT == S_IFDIR { 'd' } else { '-' };
+                println!("{} {:>4} {:>10} {}", kind, st.nlink, st.size, entry.name);
+            }
+            Err(_) => {
+                let kind = if entry.is_dir() { 'd' } else { '?' };
+                println!("{} {:>4} {:>10} {}", kind, '?', '?', entry.name);
+            }
+        }
+    }
+    0
+}
+
+/// ls [-l] [dir...]
+#[no_mangle]
+pub fn main(_argc: usize, argv: &[&str]) -> i32 {
+    let long = argv.iter().skip(1).any(|arg| *arg == "-l");
+    let dirs: Vec<&str> = argv
+        .iter()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .copied()
+        .collect();
+    if dirs.is_empty() {
+        return list(".", long);
+    }
+    let mut ret = 0;
+    for dir in dirs.iter() {
+        if dirs.len() > 1 {
+            println!("{}:", dir);
+        }
+        ret |= list(dir, long);
+    }
+    ret
+}
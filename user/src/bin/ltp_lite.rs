@@ -0,0 +1,459 @@
+#![no_std]
+#![no_main]
+
+//! ltp-lite: syscall conformance checks against Linux semantics
+//!
+//! Every check prints one line `TEST <name> PASS|FAIL got=<ret> want=<expect>`
+//! and the run ends with `SUMMARY total=<n> pass=<n> fail=<n>`, the exit code
+//! is the number of failures.
+
+#[macro_use]
+extern crate user_lib;
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use user_lib::{
+    close, dup, dup2,
+    fs::{c_path, fstat, getcwd, getdents64, mkdir, rmdir, unlink, Stat},
+    get_time, getpid, gettid, kill, open, pipe, read,
+    syscall::{
+        sys_brk, sys_futex, sys_io_uring_enter, sys_io_uring_setup, sys_mmap, sys_mprotect,
+        sys_munmap, sys_sigaction, sys_sigprocmask, sys_waitpid,
+    },
+    syslog,
+    time::{nanosleep, TimeSpec},
+    waitpid, waitpid_options, write, yield_, OpenFlags, SignalFlags, SYSLOG_ACTION_SIZE_BUFFER,
+    WUNTRACED,
+};
+
+const EPERM: isize = -1;
+const ENOENT: isize = -2;
+const ESRCH: isize = -3;
+const EBADF: isize = -9;
+const ECHILD: isize = -10;
+const EAGAIN: isize = -11;
+const EINVAL: isize = -22;
+
+/// 一个肯定没有打开的 fd
+const BAD_FD: usize = 1000;
+
+#[derive(Clone, Copy)]
+enum Expect {
+    /// Any non negative value
+    Ok,
+    Eq(isize),
+    /// Linux errno, negated as the kernel returns it
+    Err(isize),
+}
+
+struct Suite {
+    pass: usize,
+    fail: usize,
+}
+
+impl Suite {
+    fn check(&mut self, name: &str, got: isize, expect: Expect) {
+        let ok = match expect {
+            Expect::Ok => got >= 0,
+            Expect::Eq(want) | Expect::Err(want) => got == want,
+        };
+        let status = if ok { "PASS" } else { "FAIL" };
+        match expect {
+            Expect::Ok => println!("TEST {} {} got={} want=>=0", name, status, got),
+            Expect::Eq(want) | Expect::Err(want) => {
+                println!("TEST {} {} got={} want={}", name, status, got, want)
+            }
+        }
+        if ok {
+            self.pass += 1;
+        } else {
+            self.fail += 1;
+        }
+    }
+
+    fn check_bool(&mut self, name: &str, ok: bool) {
+        self.check(name, if ok { 0 } else { -1 }, Expect::Eq(0));
+    }
+}
+
+fn process(s: &mut Suite) {
+    s.check("getpid", getpid(), Expect::Ok);
+    s.check("gettid", gettid(), Expect::Ok);
+    s.check("sched_yield", yield_(), Expect::Eq(0));
+    s.check("gettimeofday", get_time(), Expect::Ok);
+    // 还没有子进程
+    let mut status = 0;
+    s.check("wait4.no_child", sys_waitpid(-1, &mut status, 0), Expect::Err(ECHILD));
+    let pid = user_lib::fork();
+    if pid == 0 {
+        user_lib::exit(7);
+    }
+    s.check("clone.fork", pid, Expect::Ok);
+    s.check("wait4.child", waitpid(pid as usize, &mut status), Expect::Eq(pid));
+    s.check("kill.no_such_pid", kill(0x7fff_fff0, 0), Expect::Err(ESRCH));
+    s.check("kill.self_zero", kill(getpid() as usize, 0), Expect::Eq(0));
+}
+
+fn files(s: &mut Suite) {
+    let path = "ltp_lite.tmp";
+    let fd = open(
+        c_path(path).as_str(),
+        OpenFlags::CREATE | OpenFlags::RDWR | OpenFlags::TRUNC,
+    );
+    s.check("openat.create", fd, Expect::Ok);
+    if fd < 0 {
+        return;
+    }
+    let fd = fd as usize;
+    let data = b"conformance";
+    s.check("write", write(fd, data), Expect::Eq(data.len() as isize));
+    let mut stat = Stat::default();
+    s.check("fstat", fstat(fd, &mut stat), Expect::Eq(0));
+    s.check("fstat.size", stat.size as isize, Expect::Eq(data.len() as isize));
+    s.check("close", close(fd), Expect::Eq(0));
+    s.check("close.twice", close(fd), Expect::Err(EBADF));
+
+    let fd = open(c_path(path).as_str(), OpenFlags::RDONLY);
+    s.check("openat.existing", fd, Expect::Ok);
+    if fd >= 0 {
+        let mut buf = [0u8; 32];
+        let len = read(fd as usize, &mut buf);
+        s.check("read", len, Expect::Eq(data.len() as isize));
+        s.check_bool("read.content", len > 0 && &buf[..len as usize] == data);
+        s.check("read.eof", read(fd as usize, &mut buf), Expect::Eq(0));
+        let new_fd = dup(fd as usize);
+        s.check("dup", new_fd, Expect::Ok);
+        s.check("dup3", dup2(fd as usize, 20), Expect::Eq(20));
+        close(new_fd as usize);
+        close(20);
+        close(fd as usize);
+    }
+    s.check("unlinkat", unlink(path), Expect::Eq(0));
+    s.check("unlinkat.missing", unlink(path), Expect::Err(ENOENT));
+    s.check(
+        "openat.missing",
+        open(c_path(path).as_str(), OpenFlags::RDONLY),
+        Expect::Err(ENOENT),
+    );
+
+    let mut buf = [0u8; 64];
+    s.check("read.badfd", read(BAD_FD, &mut buf), Expect::Err(EBADF));
+    s.check("write.badfd", write(BAD_FD, b"x"), Expect::Err(EBADF));
+    s.check("fstat.badfd", fstat(BAD_FD, &mut Stat::default()), Expect::Err(EBADF));
+    s.check("dup.badfd", dup(BAD_FD), Expect::Err(EBADF));
+    s.check("dup3.badfd", dup2(BAD_FD, 21), Expect::Err(EBADF));
+    s.check("getdents64.badfd", getdents64(BAD_FD, &mut buf), Expect::Err(EBADF));
+
+    s.check_bool("getcwd", getcwd().is_some());
+    s.check("mkdirat", mkdir("ltp_lite.dir", 0o755), Expect::Eq(0));
+    s.check("unlinkat.dir", rmdir("ltp_lite.dir"), Expect::Eq(0));
+}
+
+fn pipes(s: &mut Suite) {
+    let mut fds = [0usize; 2];
+    s.check("pipe2", pipe(&mut fds), Expect::Eq(0));
+    s.check("pipe2.write", write(fds[1], b"ping"), Expect::Eq(4));
+    let mut buf = [0u8; 4];
+    s.check("pipe2.read", read(fds[0], &mut buf), Expect::Eq(4));
+    s.check_bool("pipe2.content", &buf == b"ping");
+    close(fds[0]);
+    close(fds[1]);
+}
+
+fn memory(s: &mut Suite) {
+    let brk = sys_brk(0);
+    s.check("brk.query", brk, Expect::Ok);
+    s.check("brk.below_base", sys_brk(1), Expect::Err(EINVAL));
+    // PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS
+    s.check("mmap.zero_len", sys_mmap(0, 0, 3, 0x22, usize::MAX, 0), Expect::Err(EINVAL));
+    let addr = sys_mmap(0, 0x1000, 3, 0x22, usize::MAX, 0);
+    s.check("mmap.anon", addr, Expect::Ok);
+    if addr > 0 {
+        unsafe { *(addr as *mut u8) = 0x5a };
+        s.check("munmap", sys_munmap(addr as usize, 0x1000), Expect::Eq(0));
+    }
+    copy_on_write(s);
+    mprotect(s);
+}
+
+/// A private page written by the child after fork keeps its old value in the parent
+fn copy_on_write(s: &mut Suite) {
+    let addr = sys_mmap(0, 0x1000, 3, 0x22, usize::MAX, 0);
+    if addr < 0 {
+        s.check("fork.cow_mmap", addr, Expect::Ok);
+        return;
+    }
+    let value = addr as *mut u32;
+    unsafe { value.write_volatile(1) };
+    let pid = user_lib::fork();
+    if pid == 0 {
+        unsafe { value.write_volatile(2) };
+        let seen = unsafe { value.read_volatile() };
+        user_lib::exit(if seen == 2 { 0 } else { 1 });
+    }
+    let mut status = -1;
+    waitpid(pid as usize, &mut status);
+    s.check("fork.cow_child_write", status as isize, Expect::Eq(0));
+    s.check("fork.cow_parent_value", unsafe { value.read_volatile() } as isize, Expect::Eq(1));
+    sys_munmap(addr as usize, 0x1000);
+}
+
+/// After mprotect(PROT_READ) the page can still be read, a store kills with SIGSEGV
+fn mprotect(s: &mut Suite) {
+    let addr = sys_mmap(0, 0x1000, 3, 0x22, usize::MAX, 0);
+    if addr < 0 {
+        s.check("mprotect.mmap", addr, Expect::Ok);
+        return;
+    }
+    let value = addr as *mut u32;
+    unsafe { value.write_volatile(0x5a) };
+    // PROT_READ
+    s.check("mprotect.read_only", sys_mprotect(addr as usize, 0x1000, 1), Expect::Eq(0));
+    s.check("mprotect.load", unsafe { value.read_volatile() } as isize, Expect::Eq(0x5a));
+    let pid = user_lib::fork();
+    if pid == 0 {
+        unsafe { value.write_volatile(0) };
+        user_lib::exit(0);
+    }
+    // 被信号杀死的进程以负的信号值作为退出码
+    let mut status = 0;
+    waitpid(pid as usize, &mut status);
+    s.check("mprotect.store_faults", status as isize, Expect::Eq(-11));
+    s.check("mprotect.unchanged", unsafe { value.read_volatile() } as isize, Expect::Eq(0x5a));
+    sys_munmap(addr as usize, 0x1000);
+}
+
+/// A `MAP_SHARED` mapping of a file, filled with zeros, `None` if it cannot be made
+fn map_shared_file(s: &mut Suite, path: &str) -> Option<(usize, usize)> {
+    let fd = open(
+        c_path(path).as_str(),
+        OpenFlags::CREATE | OpenFlags::RDWR | OpenFlags::TRUNC,
+    );
+    s.check("map_shared.open", fd, Expect::Ok);
+    if fd < 0 {
+        return None;
+    }
+    let fd = fd as usize;
+    write(fd, &[0u8; 0x1000]);
+    // PROT_READ | PROT_WRITE, MAP_SHARED
+    let addr = sys_mmap(0, 0x1000, 3, 0x01, fd, 0);
+    s.check("map_shared.mmap", addr, Expect::Ok);
+    if addr < 0 {
+        close(fd);
+        unlink(path);
+        return None;
+    }
+    Some((fd, addr as usize))
+}
+
+/// A `MAP_SHARED` store in the child is seen by the parent and stays in the file after munmap
+fn shared_mapping(s: &mut Suite) {
+    let path = "ltp_lite.shm";
+    let Some((fd, addr)) = map_shared_file(s, path) else {
+        return;
+    };
+    let value = addr as *mut u32;
+    let pid = user_lib::fork();
+    if pid == 0 {
+        unsafe { value.write_volatile(0xc0ffee) };
+        user_lib::exit(0);
+    }
+    let mut status = -1;
+    waitpid(pid as usize, &mut status);
+    s.check(
+        "mmap.shared_cross_process",
+        unsafe { value.read_volatile() } as isize,
+        Expect::Eq(0xc0ffee),
+    );
+    s.check("mmap.shared_munmap", sys_munmap(addr, 0x1000), Expect::Eq(0));
+    close(fd);
+    let fd = open(c_path(path).as_str(), OpenFlags::RDONLY);
+    let mut buf = [0u8; 4];
+    if fd >= 0 {
+        read(fd as usize, &mut buf);
+        close(fd as usize);
+    }
+    s.check(
+        "mmap.shared_persists",
+        u32::from_ne_bytes(buf) as isize,
+        Expect::Eq(0xc0ffee),
+    );
+    unlink(path);
+}
+
+/// FUTEX_WAIT in the child returns after the parent's FUTEX_WAKE, through a word in a
+/// `MAP_SHARED` page both processes map
+fn futex(s: &mut Suite) {
+    const FUTEX_WAIT: usize = 0;
+    const FUTEX_WAKE: usize = 1;
+    let path = "ltp_lite.futex";
+    let Some((fd, addr)) = map_shared_file(s, path) else {
+        return;
+    };
+    let word = unsafe { &*(addr as *const AtomicU32) };
+    let uaddr = addr as *const u32;
+    s.check(
+        "futex.wait_value_changed",
+        sys_futex(uaddr, FUTEX_WAIT, 1),
+        Expect::Err(EAGAIN),
+    );
+    s.check("futex.wake_no_waiter", sys_futex(uaddr, FUTEX_WAKE, 1), Expect::Eq(0));
+    let pid = user_lib::fork();
+    if pid == 0 {
+        while word.load(Ordering::Acquire) == 0 {
+            sys_futex(uaddr, FUTEX_WAIT, 0);
+        }
+        user_lib::exit(0);
+    }
+    // 给子进程时间进入等待，没等上时 wake 返回 0，子进程看到新值同样退出
+    nanosleep(&TimeSpec::from_millis(10), None);
+    word.store(1, Ordering::Release);
+    s.check("futex.wake", sys_futex(uaddr, FUTEX_WAKE, 1), Expect::Ok);
+    let mut status = -1;
+    s.check("futex.waiter_exits", waitpid(pid as usize, &mut status), Expect::Eq(pid));
+    s.check("futex.waiter_status", status as isize, Expect::Eq(0));
+    sys_munmap(addr, 0x1000);
+    close(fd);
+    unlink(path);
+}
+
+/// Read from a pipe through an io_uring ring mapped with `MAP_SHARED`
+fn io_uring(s: &mut Suite) {
+    // struct io_uring_params 按 u32 看：sq_off 从第 10 个开始，cq_off 从第 20 个开始
+    let mut params = [0u32; 30];
+    s.check(
+        "io_uring_setup.zero_entries",
+        sys_io_uring_setup(0, params.as_mut_ptr() as *mut u8),
+        Expect::Err(EINVAL),
+    );
+    let ring_fd = sys_io_uring_setup(4, params.as_mut_ptr() as *mut u8);
+    s.check("io_uring_setup", ring_fd, Expect::Ok);
+    if ring_fd < 0 {
+        return;
+    }
+    let ring_fd = ring_fd as usize;
+    let ring_len = params[25] as usize + params[1] as usize * 16;
+    let sqes_len = params[0] as usize * 64;
+    // PROT_READ | PROT_WRITE, MAP_SHARED
+    let ring = sys_mmap(0, ring_len, 3, 0x01, ring_fd, 0);
+    let sqes = sys_mmap(0, sqes_len, 3, 0x01, ring_fd, 0x1000_0000);
+    s.check("io_uring.mmap_rings", ring, Expect::Ok);
+    s.check("io_uring.mmap_sqes", sqes, Expect::Ok);
+    if ring < 0 || sqes < 0 {
+        close(ring_fd);
+        return;
+    }
+    let field = |index: usize| unsafe {
+        &*((ring as usize + params[index] as usize) as *const AtomicU32)
+    };
+    let mut fds = [0usize; 2];
+    pipe(&mut fds);
+    write(fds[1], b"ring");
+    let mut buf = [0u8; 4];
+    unsafe {
+        // IORING_OP_READ，从当前位置读，user_data 为 7
+        let sqe = sqes as *mut u64;
+        sqe.write_volatile(22 | (fds[0] as u64) << 32);
+        sqe.add(1).write_volatile(u64::MAX);
+        sqe.add(2).write_volatile(buf.as_mut_ptr() as u64);
+        sqe.add(3).write_volatile(buf.len() as u64);
+        sqe.add(4).write_volatile(7);
+    }
+    // sq_off.array 和 sq_off.tail
+    field(16).store(0, Ordering::Relaxed);
+    field(11).store(1, Ordering::Release);
+    s.check(
+        "io_uring_enter.getevents",
+        sys_io_uring_enter(ring_fd, 1, 1, 1),
+        Expect::Eq(1),
+    );
+    // cq_off.tail 和 cq_off.cqes
+    s.check("io_uring.cq_tail", field(21).load(Ordering::Acquire) as isize, Expect::Eq(1));
+    let cqe = (ring as usize + params[25] as usize) as *const u64;
+    let (user_data, res) = unsafe { (cqe.read_volatile(), cqe.add(1).read_volatile() as i32) };
+    s.check("io_uring.cqe_user_data", user_data as isize, Expect::Eq(7));
+    s.check("io_uring.cqe_res", res as isize, Expect::Eq(4));
+    s.check_bool("io_uring.read_content", &buf == b"ring");
+    sys_munmap(sqes as usize, sqes_len);
+    sys_munmap(ring as usize, ring_len);
+    close(fds[0]);
+    close(fds[1]);
+    s.check("io_uring.close", close(ring_fd), Expect::Eq(0));
+}
+
+fn signals(s: &mut Suite) {
+    let mut old = 0u64;
+    s.check(
+        "rt_sigprocmask.query",
+        sys_sigprocmask(0, core::ptr::null(), &mut old),
+        Expect::Eq(0),
+    );
+    let action = [0usize; 4];
+    s.check(
+        "rt_sigaction.sigkill",
+        sys_sigaction(9, action.as_ptr() as *const u8, core::ptr::null_mut()),
+        Expect::Err(EINVAL),
+    );
+    s.check(
+        "rt_sigaction.out_of_range",
+        sys_sigaction(65, action.as_ptr() as *const u8, core::ptr::null_mut()),
+        Expect::Err(EINVAL),
+    );
+    // SIGSTOP 停下的子进程由 WUNTRACED 报告，SIGKILL 可以结束停止的进程
+    let pid = user_lib::fork();
+    if pid == 0 {
+        loop {
+            yield_();
+        }
+    }
+    kill(pid as usize, SignalFlags::SIGSTOP.bits());
+    let mut status = 0;
+    s.check(
+        "wait4.wuntraced",
+        waitpid_options(pid, &mut status, WUNTRACED),
+        Expect::Eq(pid),
+    );
+    s.check("wait4.stop_status", status as isize, Expect::Eq((19 << 8) | 0x7f));
+    kill(pid as usize, SignalFlags::SIGKILL.bits());
+    s.check("kill.stopped", waitpid(pid as usize, &mut status), Expect::Eq(pid));
+}
+
+fn misc(s: &mut Suite) {
+    let mut empty = [0u8; 0];
+    s.check(
+        "syslog.size_buffer",
+        syslog(SYSLOG_ACTION_SIZE_BUFFER, &mut empty),
+        Expect::Ok,
+    );
+    s.check("syslog.bad_type", syslog(99, &mut empty), Expect::Err(EINVAL));
+    s.check(
+        "nanosleep",
+        nanosleep(&TimeSpec::from_millis(1), None),
+        Expect::Eq(0),
+    );
+    // 只是为了让 EPERM 有用处：普通进程不能改 SIGSTOP 的处理方式
+    let action = [0usize; 4];
+    let ret = sys_sigaction(19, action.as_ptr() as *const u8, core::ptr::null_mut());
+    s.check_bool("rt_sigaction.sigstop", ret == EINVAL || ret == EPERM);
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut suite = Suite { pass: 0, fail: 0 };
+    process(&mut suite);
+    files(&mut suite);
+    pipes(&mut suite);
+    memory(&mut suite);
+    shared_mapping(&mut suite);
+    futex(&mut suite);
+    io_uring(&mut suite);
+    signals(&mut suite);
+    misc(&mut suite);
+    println!(
+        "SUMMARY total={} pass={} fail={}",
+        suite.pass + suite.fail,
+        suite.pass,
+        suite.fail
+    );
+    suite.fail as i32
+}
@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::fs::mkdir;
+
+/// mkdir dir...
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc < 2 {
+        println!("usage: mkdir <dir>...");
+        return 1;
+    }
+    let mut ret = 0;
+    for path in argv.iter().skip(1) {
+        let err = mkdir(path, 0o755);
+        if err < 0 {
+            println!("mkdir: cannot create directory '{}': {}", path, err);
+            ret = 1;
+        }
+    }
+    ret
+}
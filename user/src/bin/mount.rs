@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    fs::{mount, read_to_string, umount},
+    write,
+};
+
+/// mount [-t fstype] source target | mount -u target | mount
+#[no_mangle]
+pub fn main(_argc: usize, argv: &[&str]) -> i32 {
+    match argv.get(1..).unwrap_or(&[]) {
+        [] => match read_to_string("/proc/mounts") {
+            Ok(mounts) => {
+                write(1, mounts.as_bytes());
+                0
+            }
+            Err(err) => {
+                println!("mount: cannot read /proc/mounts: {}", err);
+                1
+            }
+        },
+        ["-u", target] => {
+            let ret = umount(target);
+            if ret < 0 {
+                println!("mount: umount {} failed: {}", target, ret);
+                return 1;
+            }
+            0
+        }
+        ["-t", fstype, source, target] | [source, target, fstype] => {
+            let ret = mount(source, target, fstype, 0);
+            if ret < 0 {
+                println!("mount: mount {} on {} failed: {}", source, target, ret);
+                return 1;
+            }
+            0
+        }
+        _ => {
+            println!("usage: mount [-t fstype] <source> <target> | mount -u <target>");
+            1
+        }
+    }
+}
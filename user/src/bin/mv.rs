@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::fs::rename;
+
+/// mv old new
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc != 3 {
+        println!("usage: mv <old> <new>");
+        return 1;
+    }
+    let ret = rename(argv[1], argv[2]);
+    if ret < 0 {
+        println!("mv: cannot move '{}' to '{}': {}", argv[1], argv[2], ret);
+        return 1;
+    }
+    0
+}
@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+extern crate alloc;
+
+use alloc::string::String;
+use user_lib::fs::{opendir, read_to_string};
+
+/// 解析 /proc/<pid>/stat：pid (comm) state ppid ...
+fn print_process(pid: &str) {
+    let mut path = String::from("/proc/");
+    path.push_str(pid);
+    path.push_str("/stat");
+    let stat = match read_to_string(&path) {
+        Ok(stat) => stat,
+        Err(_) => return,
+    };
+    let (comm_start, comm_end) = match (stat.find('('), stat.rfind(')')) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => return,
+    };
+    let comm = &stat[comm_start + 1..comm_end];
+    let mut rest = stat[comm_end + 1..].split_whitespace();
+    let state = rest.next().unwrap_or("?");
+    let ppid = rest.next().unwrap_or("?");
+    println!("{:>5} {:>5} {:>5} {}", pid, ppid, state, comm);
+}
+
+/// ps, lists the numeric entries of /proc
+#[no_mangle]
+pub fn main() -> i32 {
+    let dir = match opendir("/proc") {
+        Ok(dir) => dir,
+        Err(_) => {
+            println!("ps: /proc is not mounted");
+            return 1;
+        }
+    };
+    println!("{:>5} {:>5} {:>5} {}", "PID", "PPID", "STAT", "COMMAND");
+    for entry in dir {
+        if !entry.name.is_empty() && entry.name.bytes().all(|b| b.is_ascii_digit()) {
+            print_process(&entry.name);
+        }
+    }
+    0
+}
@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::fs::{rmdir, unlink};
+
+/// rm [-d] file...
+#[no_mangle]
+pub fn main(_argc: usize, argv: &[&str]) -> i32 {
+    let dir = argv.iter().skip(1).any(|arg| *arg == "-d");
+    let mut ret = 0;
+    for path in argv.iter().skip(1).filter(|arg| !arg.starts_with('-')) {
+        let err = if dir { rmdir(path) } else { unlink(path) };
+        if err < 0 {
+            println!("rm: cannot remove '{}': {}", path, err);
+            ret = 1;
+        }
+    }
+    ret
+}
@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use user_lib::{exec, set_syscall_trace};
+
+fn c_string(s: &str) -> String {
+    let mut string = String::from(s);
+    string.push('\0');
+    string
+}
+
+/// strace <program> [args...], the trace goes to the kernel log, see dmesg
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc < 2 {
+        println!("strace: usage: strace <program> [args...]");
+        return -1;
+    }
+    let args: Vec<String> = argv[1..].iter().map(|arg| c_string(arg)).collect();
+    let mut args_addr: Vec<*const u8> = args.iter().map(|arg| arg.as_ptr()).collect();
+    args_addr.push(core::ptr::null());
+    if set_syscall_trace(true) < 0 {
+        println!("strace: syscall tracing is not supported");
+        return -1;
+    }
+    exec(args[0].as_str(), &args_addr);
+    if !argv[1].contains('/') {
+        let path = c_string(&alloc::format!("/{}", argv[1]));
+        exec(path.as_str(), &args_addr);
+    }
+    set_syscall_trace(false);
+    println!("strace: {}: cannot execute", argv[1]);
+    -1
+}
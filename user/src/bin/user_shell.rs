@@ -13,70 +13,378 @@ const DL: u8 = 0x7fu8;
 const BS: u8 = 0x08u8;
 const LINE_START: &str = ">> ";
 
-use alloc::string::String;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use user_lib::console::getchar;
-use user_lib::{close, dup, exec, fork, open, pipe, waitpid, OpenFlags};
+use user_lib::signal::{self, SIGINT, SIGTSTP, SIGTTIN, SIGTTOU};
+use user_lib::{
+    close, dup2, exec, exit, fork, getenv, kill, open, pipe, waitpid_nb, waitpid_options,
+    OpenFlags, SignalFlags, WUNTRACED,
+};
+
+/// One command of a pipeline
+#[derive(Debug, Default)]
+struct Command {
+    args: Vec<String>,
+    input: Option<String>,
+    /// (path, append)
+    output: Option<(String, bool)>,
+}
 
 #[derive(Debug)]
-struct ProcessArguments {
-    input: String,
-    output: String,
-    args_copy: Vec<String>,
-    args_addr: Vec<*const u8>,
+struct Pipeline {
+    commands: Vec<Command>,
+    background: bool,
 }
 
-impl ProcessArguments {
-    pub fn new(command: &str) -> Self {
-        let args: Vec<_> = command.split(' ').collect();
-        let mut args_copy: Vec<String> = args
-            .iter()
-            .filter(|&arg| !arg.is_empty())
-            .map(|&arg| {
-                let mut string = String::new();
-                string.push_str(arg);
-                string.push('\0');
-                string
-            })
-            .collect();
-
-        // redirect input
-        let mut input = String::new();
-        if let Some((idx, _)) = args_copy
-            .iter()
-            .enumerate()
-            .find(|(_, arg)| arg.as_str() == "<\0")
-        {
-            input = args_copy[idx + 1].clone();
-            args_copy.drain(idx..=idx + 1);
+/// Split a line into words and the operators `| < > >> &`, quotes group words
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            } else {
+                word.push(c);
+            }
+            continue;
+        }
+        match c {
+            '\'' | '"' => quote = Some(c),
+            ' ' | '\t' => {
+                if !word.is_empty() {
+                    tokens.push(core::mem::take(&mut word));
+                }
+            }
+            '|' | '<' | '&' | '>' => {
+                if !word.is_empty() {
+                    tokens.push(core::mem::take(&mut word));
+                }
+                if c == '>' && chars.peek() == Some(&'>') {
+                    chars.next();
+                    tokens.push(">>".to_string());
+                } else {
+                    tokens.push(c.to_string());
+                }
+            }
+            _ => word.push(c),
         }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
 
-        // redirect output
-        let mut output = String::new();
-        if let Some((idx, _)) = args_copy
-            .iter()
-            .enumerate()
-            .find(|(_, arg)| arg.as_str() == ">\0")
-        {
-            output = args_copy[idx + 1].clone();
-            args_copy.drain(idx..=idx + 1);
+fn parse(line: &str) -> Result<Pipeline, &'static str> {
+    let tokens = tokenize(line);
+    let mut commands = Vec::new();
+    let mut command = Command::default();
+    let mut background = false;
+    let mut iter = tokens.into_iter();
+    while let Some(token) = iter.next() {
+        if background {
+            return Err("'&' must end the command line");
+        }
+        match token.as_str() {
+            "|" => {
+                if command.args.is_empty() {
+                    return Err("empty command in pipeline");
+                }
+                commands.push(core::mem::take(&mut command));
+            }
+            "<" => command.input = Some(iter.next().ok_or("missing file after '<'")?),
+            ">" => command.output = Some((iter.next().ok_or("missing file after '>'")?, false)),
+            ">>" => command.output = Some((iter.next().ok_or("missing file after '>>'")?, true)),
+            "&" => background = true,
+            _ => command.args.push(token),
+        }
+    }
+    if command.args.is_empty() {
+        return Err("empty command in pipeline");
+    }
+    commands.push(command);
+    let last = commands.len() - 1;
+    for (i, command) in commands.iter().enumerate() {
+        if (i > 0 && command.input.is_some()) || (i < last && command.output.is_some()) {
+            return Err("Inputs/Outputs cannot be correctly binded!");
+        }
+    }
+    Ok(Pipeline {
+        commands,
+        background,
+    })
+}
+
+fn c_string(s: &str) -> String {
+    let mut string = String::from(s);
+    string.push('\0');
+    string
+}
+
+/// Exec `args[0]`, searching PATH when it has no '/', only returns on failure
+fn exec_command(args: &[String]) {
+    let args_copy: Vec<String> = args.iter().map(|arg| c_string(arg)).collect();
+    let mut args_addr: Vec<*const u8> = args_copy.iter().map(|arg| arg.as_ptr()).collect();
+    args_addr.push(core::ptr::null::<u8>());
+    let name = args[0].as_str();
+    if !name.contains('/') {
+        let path = getenv("PATH").unwrap_or_else(|| "/".to_string());
+        for dir in path.split(':').filter(|dir| !dir.is_empty()) {
+            let mut candidate = String::from(dir.trim_end_matches('/'));
+            candidate.push('/');
+            candidate.push_str(name);
+            candidate.push('\0');
+            exec(candidate.as_str(), args_addr.as_slice());
+        }
+    }
+    exec(args_copy[0].as_str(), args_addr.as_slice());
+}
+
+/// Set up redirections and pipe ends in a forked child, then exec
+fn run_child(
+    command: &Command,
+    stdin: Option<usize>,
+    stdout: Option<usize>,
+    pipes: &[[usize; 2]],
+) -> ! {
+    // shell 自己忽略的作业控制信号在子进程里要恢复默认
+    for signum in [SIGINT, SIGTSTP, SIGTTIN, SIGTTOU] {
+        signal::default(signum);
+    }
+    if let Some(fd) = stdin {
+        dup2(fd, 0);
+    }
+    if let Some(fd) = stdout {
+        dup2(fd, 1);
+    }
+    for pipe_fd in pipes.iter() {
+        close(pipe_fd[0]);
+        close(pipe_fd[1]);
+    }
+    if let Some(input) = &command.input {
+        let input_fd = open(c_string(input).as_str(), OpenFlags::RDONLY);
+        if input_fd < 0 {
+            println!("Error when opening file {}", input);
+            exit(-4);
+        }
+        dup2(input_fd as usize, 0);
+        close(input_fd as usize);
+    }
+    if let Some((output, append)) = &command.output {
+        let flags = OpenFlags::CREATE
+            | OpenFlags::WRONLY
+            | if *append {
+                OpenFlags::APPEND
+            } else {
+                OpenFlags::TRUNC
+            };
+        let output_fd = open(c_string(output).as_str(), flags);
+        if output_fd < 0 {
+            println!("Error when opening file {}", output);
+            exit(-4);
+        }
+        dup2(output_fd as usize, 1);
+        close(output_fd as usize);
+    }
+    exec_command(&command.args);
+    println!("{}: command not found", command.args[0]);
+    exit(-4);
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum JobState {
+    Running,
+    Stopped,
+}
+
+/// A pipeline started by the shell, its process group is led by the first
+/// process
+#[derive(Debug)]
+struct Job {
+    id: usize,
+    pgid: usize,
+    pids: Vec<usize>,
+    state: JobState,
+    command: String,
+}
+
+impl Job {
+    fn signal(&self, signal: SignalFlags) {
+        for &pid in self.pids.iter() {
+            kill(pid, signal.bits());
         }
+    }
+}
+
+/// 按 Linux 的 wait status 编码判断子进程是否被暂停
+fn stopped(status: i32) -> bool {
+    status & 0xff == 0x7f
+}
+
+struct Shell {
+    jobs: Vec<Job>,
+}
+
+impl Shell {
+    fn new() -> Self {
+        Self { jobs: Vec::new() }
+    }
+
+    fn next_job_id(&self) -> usize {
+        self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1
+    }
+
+    fn launch(&mut self, pipeline: Pipeline, line: &str) {
+        let count = pipeline.commands.len();
+        let mut pipes: Vec<[usize; 2]> = Vec::new();
+        for _ in 1..count {
+            let mut pipe_fd = [0usize; 2];
+            pipe(&mut pipe_fd);
+            pipes.push(pipe_fd);
+        }
+        let mut pids = Vec::new();
+        for (i, command) in pipeline.commands.iter().enumerate() {
+            let stdin = if i > 0 { Some(pipes[i - 1][0]) } else { None };
+            let stdout = if i < count - 1 { Some(pipes[i][1]) } else { None };
+            let pid = fork();
+            if pid == 0 {
+                run_child(command, stdin, stdout, &pipes);
+            } else if pid < 0 {
+                println!("fork failed");
+                break;
+            }
+            pids.push(pid as usize);
+        }
+        for pipe_fd in pipes.iter() {
+            close(pipe_fd[0]);
+            close(pipe_fd[1]);
+        }
+        if pids.is_empty() {
+            return;
+        }
+        let job = Job {
+            id: self.next_job_id(),
+            pgid: pids[0],
+            pids,
+            state: JobState::Running,
+            command: line.trim().trim_end_matches('&').trim_end().to_string(),
+        };
+        if pipeline.background {
+            println!("[{}] {}", job.id, job.pgid);
+            self.jobs.push(job);
+        } else {
+            self.wait_foreground(job);
+        }
+    }
+
+    /// Wait until every process of `job` exits or one of them stops
+    fn wait_foreground(&mut self, mut job: Job) {
+        while let Some(&pid) = job.pids.first() {
+            let mut status: i32 = 0;
+            let ret = waitpid_options(pid as isize, &mut status, WUNTRACED);
+            if ret == pid as isize && stopped(status) {
+                job.state = JobState::Stopped;
+                println!("");
+                println!("[{}]+  Stopped    {}", job.id, job.command);
+                self.jobs.push(job);
+                return;
+            }
+            job.pids.remove(0);
+        }
+    }
 
-        let mut args_addr: Vec<*const u8> = args_copy.iter().map(|arg| arg.as_ptr()).collect();
-        args_addr.push(core::ptr::null::<u8>());
+    /// Collect finished background processes without blocking
+    fn reap(&mut self) {
+        for job in self.jobs.iter_mut() {
+            job.pids.retain(|&pid| {
+                let mut status: i32 = 0;
+                waitpid_nb(pid, &mut status) == 0
+            });
+        }
+        self.jobs.retain(|job| {
+            if job.pids.is_empty() {
+                println!("[{}]+  Done       {}", job.id, job.command);
+            }
+            !job.pids.is_empty()
+        });
+    }
+
+    /// `%n` or nothing for the most recent job
+    fn find_job(&self, arg: Option<&String>) -> Option<usize> {
+        match arg {
+            None => self.jobs.len().checked_sub(1),
+            Some(arg) => {
+                let id: usize = arg.trim_start_matches('%').parse().ok()?;
+                self.jobs.iter().position(|job| job.id == id)
+            }
+        }
+    }
 
-        Self {
-            input,
-            output,
-            args_copy,
-            args_addr,
+    /// Run a builtin, return false if `args[0]` is not one
+    fn builtin(&mut self, args: &[String]) -> bool {
+        match args[0].as_str() {
+            "exit" => exit(args.get(1).and_then(|code| code.parse().ok()).unwrap_or(0)),
+            "jobs" => {
+                for job in self.jobs.iter() {
+                    let state = match job.state {
+                        JobState::Running => "Running",
+                        JobState::Stopped => "Stopped",
+                    };
+                    println!("[{}]   {}    {}", job.id, state, job.command);
+                }
+            }
+            "fg" | "bg" => match self.find_job(args.get(1)) {
+                Some(idx) => {
+                    if args[0] == "fg" {
+                        let mut job = self.jobs.remove(idx);
+                        println!("{}", job.command);
+                        job.state = JobState::Running;
+                        job.signal(SignalFlags::SIGCONT);
+                        self.wait_foreground(job);
+                    } else {
+                        let job = &mut self.jobs[idx];
+                        println!("[{}]+ {} &", job.id, job.command);
+                        job.state = JobState::Running;
+                        job.signal(SignalFlags::SIGCONT);
+                    }
+                }
+                None => println!("{}: no such job", args[0]),
+            },
+            _ => return false,
         }
+        true
+    }
+
+    fn run_line(&mut self, line: &str) {
+        let pipeline = match parse(line) {
+            Ok(pipeline) => pipeline,
+            Err(msg) => {
+                println!("Invalid command: {}", msg);
+                return;
+            }
+        };
+        if pipeline.commands.len() == 1
+            && !pipeline.background
+            && self.builtin(&pipeline.commands[0].args)
+        {
+            return;
+        }
+        self.launch(pipeline, line);
     }
 }
 
 #[no_mangle]
 pub fn main() -> i32 {
     println!("Rust user shell");
+    // 前台作业的 Ctrl-C/Ctrl-Z 不应该影响 shell 自己
+    for signum in [SIGINT, SIGTSTP, SIGTTIN, SIGTTOU] {
+        signal::ignore(signum);
+    }
+    let mut shell = Shell::new();
     let mut line: String = String::new();
     print!("{}", LINE_START);
     loop {
@@ -84,117 +392,11 @@ pub fn main() -> i32 {
         match c {
             LF | CR => {
                 println!("");
-                if !line.is_empty() {
-                    let splited: Vec<_> = line.as_str().split('|').collect();
-                    let process_arguments_list: Vec<_> = splited
-                        .iter()
-                        .map(|&cmd| ProcessArguments::new(cmd))
-                        .collect();
-                    let mut valid = true;
-                    for (i, process_args) in process_arguments_list.iter().enumerate() {
-                        if i == 0 {
-                            if !process_args.output.is_empty() {
-                                valid = false;
-                            }
-                        } else if i == process_arguments_list.len() - 1 {
-                            if !process_args.input.is_empty() {
-                                valid = false;
-                            }
-                        } else if !process_args.output.is_empty() || !process_args.input.is_empty()
-                        {
-                            valid = false;
-                        }
-                    }
-                    if process_arguments_list.len() == 1 {
-                        valid = true;
-                    }
-                    if !valid {
-                        println!("Invalid command: Inputs/Outputs cannot be correctly binded!");
-                    } else {
-                        // create pipes
-                        let mut pipes_fd: Vec<[usize; 2]> = Vec::new();
-                        if !process_arguments_list.is_empty() {
-                            for _ in 0..process_arguments_list.len() - 1 {
-                                let mut pipe_fd = [0usize; 2];
-                                pipe(&mut pipe_fd);
-                                pipes_fd.push(pipe_fd);
-                            }
-                        }
-                        let mut children: Vec<_> = Vec::new();
-                        for (i, process_argument) in process_arguments_list.iter().enumerate() {
-                            let pid = fork();
-                            if pid == 0 {
-                                let input = &process_argument.input;
-                                let output = &process_argument.output;
-                                let args_copy = &process_argument.args_copy;
-                                let args_addr = &process_argument.args_addr;
-                                // redirect input
-                                if !input.is_empty() {
-                                    let input_fd = open(input.as_str(), OpenFlags::RDONLY);
-                                    if input_fd == -1 {
-                                        println!("Error when opening file {}", input);
-                                        return -4;
-                                    }
-                                    let input_fd = input_fd as usize;
-                                    close(0);
-                                    assert_eq!(dup(input_fd), 0);
-                                    close(input_fd);
-                                }
-                                // redirect output
-                                if !output.is_empty() {
-                                    let output_fd = open(
-                                        output.as_str(),
-                                        OpenFlags::CREATE | OpenFlags::WRONLY,
-                                    );
-                                    if output_fd == -1 {
-                                        println!("Error when opening file {}", output);
-                                        return -4;
-                                    }
-                                    let output_fd = output_fd as usize;
-                                    close(1);
-                                    assert_eq!(dup(output_fd), 1);
-                                    close(output_fd);
-                                }
-                                // receive input from the previous process
-                                if i > 0 {
-                                    close(0);
-                                    let read_end = pipes_fd.get(i - 1).unwrap()[0];
-                                    assert_eq!(dup(read_end), 0);
-                                }
-                                // send output to the next process
-                                if i < process_arguments_list.len() - 1 {
-                                    close(1);
-                                    let write_end = pipes_fd.get(i).unwrap()[1];
-                                    assert_eq!(dup(write_end), 1);
-                                }
-                                // close all pipe ends inherited from the parent process
-                                for pipe_fd in pipes_fd.iter() {
-                                    close(pipe_fd[0]);
-                                    close(pipe_fd[1]);
-                                }
-                                // execute new application
-                                if exec(args_copy[0].as_str(), args_addr.as_slice()) == -1 {
-                                    println!("Error when executing!");
-                                    return -4;
-                                }
-                                unreachable!();
-                            } else {
-                                children.push(pid);
-                            }
-                        }
-                        for pipe_fd in pipes_fd.iter() {
-                            close(pipe_fd[0]);
-                            close(pipe_fd[1]);
-                        }
-                        let mut exit_code: i32 = 0;
-                        for pid in children.into_iter() {
-                            let exit_pid = waitpid(pid as usize, &mut exit_code);
-                            assert_eq!(pid, exit_pid);
-                            //println!("Shell: Process {} exited with code {}", pid, exit_code);
-                        }
-                    }
-                    line.clear();
+                if !line.trim().is_empty() {
+                    shell.run_line(line.as_str());
                 }
+                line.clear();
+                shell.reap();
                 print!("{}", LINE_START);
             }
             BS | DL => {
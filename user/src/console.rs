@@ -1,21 +1,95 @@
+use alloc::string::String;
 use core::fmt::{self, Write};
 
 const STDIN: usize = 0;
 const STDOUT: usize = 1;
 
-use super::{read, write};
+const STDOUT_BUF_SIZE: usize = 1024;
+const STDIN_BUF_SIZE: usize = 1024;
 
-struct Stdout;
+use super::{read, sync::SpinLock, write};
+
+/// Line buffered stdout: flushed on '\n', when full, and by `flush`
+struct Stdout {
+    buf: [u8; STDOUT_BUF_SIZE],
+    len: usize,
+}
+
+impl Stdout {
+    const fn new() -> Self {
+        Self {
+            buf: [0; STDOUT_BUF_SIZE],
+            len: 0,
+        }
+    }
+    fn flush(&mut self) {
+        if self.len > 0 {
+            write(STDOUT, &self.buf[..self.len]);
+            self.len = 0;
+        }
+    }
+}
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        write(STDOUT, s.as_bytes());
+        let mut newline = false;
+        for &byte in s.as_bytes() {
+            if self.len == STDOUT_BUF_SIZE {
+                self.flush();
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+            newline |= byte == b'\n';
+        }
+        if newline {
+            self.flush();
+        }
         Ok(())
     }
 }
 
+/// Buffered stdin
+struct Stdin {
+    buf: [u8; STDIN_BUF_SIZE],
+    pos: usize,
+    len: usize,
+}
+
+impl Stdin {
+    const fn new() -> Self {
+        Self {
+            buf: [0; STDIN_BUF_SIZE],
+            pos: 0,
+            len: 0,
+        }
+    }
+    /// Next byte, `None` on EOF or error
+    fn next_byte(&mut self) -> Option<u8> {
+        if self.pos == self.len {
+            // 读之前先把提示符之类没有换行的输出刷出去
+            flush();
+            let len = read(STDIN, &mut self.buf);
+            if len <= 0 {
+                return None;
+            }
+            self.pos = 0;
+            self.len = len as usize;
+        }
+        self.pos += 1;
+        Some(self.buf[self.pos - 1])
+    }
+}
+
+static STDOUT_BUF: SpinLock<Stdout> = SpinLock::new(Stdout::new());
+static STDIN_BUF: SpinLock<Stdin> = SpinLock::new(Stdin::new());
+
 pub fn print(args: fmt::Arguments) {
-    Stdout.write_fmt(args).unwrap();
+    STDOUT_BUF.with(|stdout| stdout.write_fmt(args).unwrap());
+}
+
+/// Write out buffered stdout
+pub fn flush() {
+    STDOUT_BUF.with(|stdout| stdout.flush());
 }
 
 #[macro_export]
@@ -33,7 +107,21 @@ macro_rules! println {
 }
 
 pub fn getchar() -> u8 {
-    let mut c = [0u8; 1];
-    read(STDIN, &mut c);
-    c[0]
+    STDIN_BUF.with(|stdin| stdin.next_byte().unwrap_or(0))
+}
+
+/// Read a line including the trailing '\n' into `line`, return the number of
+/// bytes read, 0 on EOF
+pub fn read_line(line: &mut String) -> usize {
+    let mut bytes = alloc::vec::Vec::new();
+    STDIN_BUF.with(|stdin| {
+        while let Some(byte) = stdin.next_byte() {
+            bytes.push(byte);
+            if byte == b'\n' {
+                break;
+            }
+        }
+    });
+    line.push_str(&String::from_utf8_lossy(&bytes));
+    bytes.len()
 }
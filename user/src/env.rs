@@ -0,0 +1,99 @@
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use super::sync::SpinLock;
+
+/// 环境变量表，每一项都是 "KEY=VALUE"
+static ENVIRON: SpinLock<Vec<String>> = SpinLock::new(Vec::new());
+
+/// Read the null terminated envp array the kernel left on the user stack
+pub(crate) fn init(envp: usize) {
+    if envp == 0 {
+        return;
+    }
+    let mut vars = Vec::new();
+    for i in 0.. {
+        let str_start =
+            unsafe { ((envp + i * core::mem::size_of::<usize>()) as *const usize).read_volatile() };
+        if str_start == 0 {
+            break;
+        }
+        vars.push(unsafe { c_str(str_start) }.to_string());
+    }
+    ENVIRON.with(|environ| *environ = vars);
+}
+
+/// The null terminated string at `start`, bytes that are not UTF-8 become U+FFFD
+pub(crate) unsafe fn c_str(start: usize) -> &'static str {
+    let len = (0usize..)
+        .find(|i| ((start + *i) as *const u8).read_volatile() == 0)
+        .unwrap();
+    let bytes = core::slice::from_raw_parts(start as *const u8, len);
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        // 只在启动时读参数和环境变量时发生，转换出的拷贝泄漏掉也没关系
+        Err(_) => Box::leak(String::from_utf8_lossy(bytes).into_owned().into_boxed_str()),
+    }
+}
+
+fn split(var: &str) -> (&str, &str) {
+    match var.find('=') {
+        Some(pos) => (&var[..pos], &var[pos + 1..]),
+        None => (var, ""),
+    }
+}
+
+pub fn getenv(key: &str) -> Option<String> {
+    ENVIRON.with(|environ| {
+        environ
+            .iter()
+            .map(|var| split(var))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.to_string())
+    })
+}
+
+/// Set `key` to `value`, an existing value is only replaced when `overwrite`
+/// is set. Return false if `key` is empty or contains '='
+pub fn setenv(key: &str, value: &str, overwrite: bool) -> bool {
+    if key.is_empty() || key.contains('=') {
+        return false;
+    }
+    let mut var = String::from(key);
+    var.push('=');
+    var.push_str(value);
+    ENVIRON.with(|environ| {
+        match environ.iter().position(|v| split(v).0 == key) {
+            Some(i) if overwrite => environ[i] = var,
+            Some(_) => {}
+            None => environ.push(var),
+        }
+    });
+    true
+}
+
+pub fn unsetenv(key: &str) {
+    ENVIRON.with(|environ| environ.retain(|v| split(v).0 != key));
+}
+
+/// A snapshot of all "KEY=VALUE" pairs
+pub fn environ() -> Vec<String> {
+    ENVIRON.with(|environ| environ.clone())
+}
+
+/// The environment as null terminated C strings, for execve
+pub(crate) fn envp_strings() -> Vec<String> {
+    ENVIRON.with(|environ| {
+        environ
+            .iter()
+            .map(|var| {
+                let mut s = var.clone();
+                s.push('\0');
+                s
+            })
+            .collect()
+    })
+}
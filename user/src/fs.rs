@@ -0,0 +1,293 @@
+//! File system helpers, paths are plain `&str` and get their trailing '\0'
+//! appended here
+
+use alloc::{string::String, vec, vec::Vec};
+
+use super::{
+    close, open,
+    syscall::{
+        sys_chdir, sys_fstat, sys_getcwd, sys_getdents64, sys_mkdirat, sys_mount, sys_readlinkat,
+        sys_renameat2, sys_statfs, sys_symlinkat, sys_umount2, sys_unlinkat,
+    },
+    OpenFlags,
+};
+pub use crate::time::TimeSpec;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFCHR: u32 = 0o020000;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+
+/// ino, off, reclen, type 之后才是文件名
+const DIRENT_HEADER: usize = 19;
+const DIRENT_BUF_INIT: usize = 1024;
+const DIRENT_BUF_MAX: usize = 64 * 1024;
+
+/// 与内核 `Stat` 的内存布局一致
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    __pad: u64,
+    pub size: i64,
+    pub blksize: u32,
+    __pad2: i32,
+    pub blocks: u64,
+    pub atime: TimeSpec,
+    pub mtime: TimeSpec,
+    pub ctime: TimeSpec,
+    __unused: u64,
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Statfs {
+    pub f_type: u64,
+    pub f_bsize: u64,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_fsid: u64,
+    pub f_namelen: u64,
+    pub f_frsize: u64,
+    pub f_flags: u64,
+    pub f_spare: [u64; 4],
+}
+
+/// `path` with the trailing '\0' the kernel expects
+pub fn c_path(path: &str) -> String {
+    let mut string = String::from(path);
+    string.push('\0');
+    string
+}
+
+pub fn fstat(fd: usize, stat: &mut Stat) -> isize {
+    sys_fstat(fd, stat as *mut Stat as *mut u8)
+}
+
+pub fn stat(path: &str) -> Result<Stat, isize> {
+    let fd = open(c_path(path).as_str(), OpenFlags::RDONLY);
+    if fd < 0 {
+        return Err(fd);
+    }
+    let mut stat = Stat::default();
+    let ret = fstat(fd as usize, &mut stat);
+    close(fd as usize);
+    if ret < 0 {
+        Err(ret)
+    } else {
+        Ok(stat)
+    }
+}
+
+pub fn statfs(path: &str) -> Result<Statfs, isize> {
+    let mut buf = Statfs::default();
+    match sys_statfs(c_path(path).as_str(), &mut buf as *mut Statfs as *mut u8) {
+        ret if ret < 0 => Err(ret),
+        _ => Ok(buf),
+    }
+}
+
+pub fn getdents64(fd: usize, buf: &mut [u8]) -> isize {
+    sys_getdents64(fd, buf)
+}
+
+pub fn mkdir(path: &str, mode: u32) -> isize {
+    sys_mkdirat(c_path(path).as_str(), mode)
+}
+
+pub fn unlink(path: &str) -> isize {
+    sys_unlinkat(c_path(path).as_str(), 0)
+}
+
+pub fn rmdir(path: &str) -> isize {
+    sys_unlinkat(c_path(path).as_str(), AT_REMOVEDIR)
+}
+
+pub fn symlink(target: &str, path: &str) -> isize {
+    sys_symlinkat(c_path(target).as_str(), c_path(path).as_str())
+}
+
+/// The target of the symbolic link at `path`
+pub fn readlink(path: &str) -> Result<String, isize> {
+    let mut buf = [0u8; 256];
+    match sys_readlinkat(c_path(path).as_str(), &mut buf) {
+        len if len < 0 => Err(len),
+        len => Ok(String::from_utf8_lossy(&buf[..len as usize]).into_owned()),
+    }
+}
+
+pub fn rename(old_path: &str, new_path: &str) -> isize {
+    sys_renameat2(c_path(old_path).as_str(), c_path(new_path).as_str(), 0)
+}
+
+pub fn chdir(path: &str) -> isize {
+    sys_chdir(c_path(path).as_str())
+}
+
+pub fn getcwd() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if sys_getcwd(&mut buf) <= 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    core::str::from_utf8(&buf[..len]).ok().map(String::from)
+}
+
+pub fn mount(source: &str, target: &str, fstype: &str, flags: u32) -> isize {
+    sys_mount(
+        c_path(source).as_str(),
+        c_path(target).as_str(),
+        c_path(fstype).as_str(),
+        flags,
+        core::ptr::null(),
+    )
+}
+
+pub fn umount(target: &str) -> isize {
+    sys_umount2(c_path(target).as_str(), 0)
+}
+
+/// Read the whole file at `path`
+pub fn read_to_string(path: &str) -> Result<String, isize> {
+    let fd = open(c_path(path).as_str(), OpenFlags::RDONLY);
+    if fd < 0 {
+        return Err(fd);
+    }
+    let mut bytes = alloc::vec::Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let len = super::read(fd as usize, &mut buf);
+        if len <= 0 {
+            break;
+        }
+        bytes.extend_from_slice(&buf[..len as usize]);
+    }
+    close(fd as usize);
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// One record returned by `getdents64`
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub ino: u64,
+    /// One of the `DT_*` constants
+    pub d_type: u8,
+    pub name: String,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.d_type == DT_DIR
+    }
+}
+
+/// An open directory, iterating yields its entries
+pub struct Dir {
+    fd: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Dir {
+    /// The kernel has no directory offset and returns 0 once every entry
+    /// fit, so the buffer grows until the whole directory is read at once
+    fn load(fd: usize) -> Result<Vec<u8>, isize> {
+        let mut size = DIRENT_BUF_INIT;
+        loop {
+            let mut buf = vec![0u8; size];
+            let ret = getdents64(fd, &mut buf);
+            if ret < 0 {
+                return Err(ret);
+            }
+            if ret == 0 || size >= DIRENT_BUF_MAX {
+                return Ok(buf);
+            }
+            size *= 2;
+        }
+    }
+
+    pub fn fd(&self) -> usize {
+        self.fd
+    }
+}
+
+impl Iterator for Dir {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<DirEntry> {
+        let record = self.buf.get(self.pos..)?;
+        if record.len() < DIRENT_HEADER {
+            return None;
+        }
+        let reclen = u16::from_le_bytes([record[16], record[17]]) as usize;
+        // 缓冲区剩下的部分是 0，reclen 为 0 说明已经读完
+        if reclen < DIRENT_HEADER {
+            return None;
+        }
+        let mut ino = [0u8; 8];
+        ino.copy_from_slice(&record[..8]);
+        let name = &record[DIRENT_HEADER..reclen.min(record.len())];
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        self.pos += reclen;
+        Some(DirEntry {
+            ino: u64::from_le_bytes(ino),
+            d_type: record[18],
+            name: String::from_utf8_lossy(&name[..len]).into_owned(),
+        })
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        close(self.fd);
+    }
+}
+
+/// Open the directory at `path` and read its entries
+pub fn opendir(path: &str) -> Result<Dir, isize> {
+    let fd = open(c_path(path).as_str(), OpenFlags::RDONLY);
+    if fd < 0 {
+        return Err(fd);
+    }
+    let fd = fd as usize;
+    match Dir::load(fd) {
+        Ok(buf) => Ok(Dir { fd, buf, pos: 0 }),
+        Err(err) => {
+            close(fd);
+            Err(err)
+        }
+    }
+}
+
+/// The next entry of `dir`, `None` at the end
+pub fn readdir(dir: &mut Dir) -> Option<DirEntry> {
+    dir.next()
+}
+
+pub fn closedir(dir: Dir) {
+    drop(dir);
+}
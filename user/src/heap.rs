@@ -0,0 +1,76 @@
+//! The user heap: starts on a static array and grows through brk when the
+//! buddy allocator runs out
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use buddy_system_allocator::{Heap, LockedHeap};
+
+use super::syscall::sys_brk;
+
+const USER_HEAP_SIZE: usize = 32768;
+/// 每次向内核要内存的最小粒度
+const HEAP_GROW_MIN: usize = 0x10000;
+const PAGE_SIZE: usize = 0x1000;
+
+static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
+
+/// Current program break, 0 until the first grow asks the kernel for it
+static BRK_END: AtomicUsize = AtomicUsize::new(0);
+
+struct UserHeap(LockedHeap);
+
+#[global_allocator]
+static HEAP: UserHeap = UserHeap(LockedHeap::empty());
+
+pub(crate) fn init() {
+    unsafe {
+        HEAP.0
+            .lock()
+            .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
+    }
+}
+
+/// Extend the program break so that `layout` fits, false if the kernel refuses
+fn grow(heap: &mut Heap, layout: &Layout) -> bool {
+    let mut start = BRK_END.load(Ordering::Relaxed);
+    if start == 0 {
+        let brk = sys_brk(0);
+        if brk <= 0 {
+            return false;
+        }
+        start = brk as usize;
+    }
+    // 伙伴分配器要求块按自身大小对齐，多要一倍才能保证切得出来
+    let block = layout.size().max(layout.align()).next_power_of_two();
+    let size = (block * 2).max(HEAP_GROW_MIN);
+    let end = (start + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let ret = sys_brk(end);
+    if ret < 0 || (ret as usize) < end {
+        return false;
+    }
+    unsafe { heap.add_to_heap(start, end) };
+    BRK_END.store(end, Ordering::Relaxed);
+    true
+}
+
+unsafe impl GlobalAlloc for UserHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        loop {
+            if let Ok(ptr) = heap.alloc(layout) {
+                return ptr.as_ptr();
+            }
+            if !grow(&mut heap, &layout) {
+                return core::ptr::null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().dealloc(NonNull::new_unchecked(ptr), layout)
+    }
+}
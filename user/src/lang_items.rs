@@ -1,4 +1,9 @@
-use super::{getpid, kill, SignalFlags};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{backtrace::print_backtrace, getpid, kill, SignalFlags};
+
+/// 打印回溯时再次 panic 就直接退出
+static PANICKING: AtomicBool = AtomicBool::new(false);
 
 #[panic_handler]
 fn panic_handler(panic_info: &core::panic::PanicInfo) -> ! {
@@ -13,6 +18,10 @@ fn panic_handler(panic_info: &core::panic::PanicInfo) -> ! {
     } else {
         println!("Panicked: {}", err);
     }
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        print_backtrace();
+    }
+    crate::console::flush();
     kill(getpid() as usize, SignalFlags::SIGABRT.bits());
     unreachable!()
 }
@@ -5,24 +5,29 @@
 
 #[macro_use]
 pub mod console;
+pub mod backtrace;
+mod env;
+pub mod fs;
+mod heap;
 mod lang_items;
-mod syscall;
+pub mod mman;
+pub mod net;
+pub mod seccomp;
+pub mod signal;
+pub mod sync;
+/// Raw syscall wrappers, returning the kernel's value unchanged
+pub mod syscall;
+pub mod thread;
+pub mod time;
 
 extern crate alloc;
 #[macro_use]
 extern crate bitflags;
 
 use alloc::vec::Vec;
-use buddy_system_allocator::LockedHeap;
+pub use env::{environ, getenv, setenv, unsetenv};
 use syscall::*;
 
-const USER_HEAP_SIZE: usize = 32768;
-
-static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
-
-#[global_allocator]
-static HEAP: LockedHeap = LockedHeap::empty();
-
 #[alloc_error_handler]
 pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
     panic!("Heap allocation error, layout = {:?}", layout);
@@ -30,24 +35,17 @@ pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
 
 #[no_mangle]
 #[link_section = ".text.entry"]
-pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
-    unsafe {
-        HEAP.lock()
-            .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
-    }
+pub extern "C" fn _start(argc: usize, argv: usize, envp: usize) -> ! {
+    heap::init();
+    env::init(envp);
     let mut v: Vec<&'static str> = Vec::new();
     for i in 0..argc {
         let str_start =
             unsafe { ((argv + i * core::mem::size_of::<usize>()) as *const usize).read_volatile() };
-        let len = (0usize..)
-            .find(|i| unsafe { ((str_start + *i) as *const u8).read_volatile() == 0 })
-            .unwrap();
-        v.push(
-            core::str::from_utf8(unsafe {
-                core::slice::from_raw_parts(str_start as *const u8, len)
-            })
-            .unwrap(),
-        );
+        v.push(unsafe { env::c_str(str_start) });
+    }
+    if let Some(program) = v.first() {
+        backtrace::set_program(program);
     }
     exit(main(argc, v.as_slice()));
 }
@@ -61,16 +59,26 @@ fn main(_argc: usize, _argv: &[&str]) -> i32 {
 bitflags! {
     pub struct OpenFlags: u32 {
         const RDONLY = 0;
-        const WRONLY = 1 << 0;
-        const RDWR = 1 << 1;
-        const CREATE = 1 << 9;
-        const TRUNC = 1 << 10;
+        const WRONLY = 0o1;
+        const RDWR = 0o2;
+        const CREATE = 0o100;
+        const TRUNC = 0o1000;
+        const APPEND = 0o2000;
+        const CLOEXEC = 0o2000000;
     }
 }
 
 pub fn dup(fd: usize) -> isize {
     sys_dup(fd)
 }
+/// Make `new_fd` refer to the same file as `old_fd`
+pub fn dup2(old_fd: usize, new_fd: usize) -> isize {
+    // dup3 不允许两个描述符相同
+    if old_fd == new_fd {
+        return new_fd as isize;
+    }
+    sys_dup3(old_fd, new_fd, 0)
+}
 pub fn open(path: &str, flags: OpenFlags) -> isize {
     sys_open(path, flags.bits)
 }
@@ -78,7 +86,11 @@ pub fn close(fd: usize) -> isize {
     sys_close(fd)
 }
 pub fn pipe(pipe_fd: &mut [usize]) -> isize {
-    sys_pipe(pipe_fd)
+    let mut fds = [0u32; 2];
+    let ret = sys_pipe(&mut fds);
+    pipe_fd[0] = fds[0] as usize;
+    pipe_fd[1] = fds[1] as usize;
+    ret
 }
 pub fn read(fd: usize, buf: &mut [u8]) -> isize {
     sys_read(fd, buf)
@@ -86,7 +98,27 @@ pub fn read(fd: usize, buf: &mut [u8]) -> isize {
 pub fn write(fd: usize, buf: &[u8]) -> isize {
     sys_write(fd, buf)
 }
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    sys_lseek(fd, offset, whence)
+}
+pub fn pread(fd: usize, buf: &mut [u8], offset: usize) -> isize {
+    sys_pread64(fd, buf, offset)
+}
+pub fn pwrite(fd: usize, buf: &[u8], offset: usize) -> isize {
+    sys_pwrite64(fd, buf, offset)
+}
+/// Write back everything cached for the disk
+pub fn sync() -> isize {
+    sys_sync()
+}
+pub fn fsync(fd: usize) -> isize {
+    sys_fsync(fd)
+}
 pub fn exit(exit_code: i32) -> ! {
+    console::flush();
     sys_exit(exit_code);
 }
 pub fn yield_() -> isize {
@@ -98,15 +130,92 @@ pub fn get_time() -> isize {
 pub fn getpid() -> isize {
     sys_getpid()
 }
+/// `pid` and `pgid` 0 stand for the caller
+pub fn setpgid(pid: usize, pgid: usize) -> isize {
+    sys_setpgid(pid, pgid)
+}
+pub fn getpgid(pid: usize) -> isize {
+    sys_getpgid(pid)
+}
+pub fn setsid() -> isize {
+    sys_setsid()
+}
+pub fn getsid(pid: usize) -> isize {
+    sys_getsid(pid)
+}
+pub fn getuid() -> isize {
+    sys_getuid()
+}
+pub fn geteuid() -> isize {
+    sys_geteuid()
+}
+pub fn getgid() -> isize {
+    sys_getgid()
+}
+pub fn getegid() -> isize {
+    sys_getegid()
+}
+pub fn setuid(uid: u32) -> isize {
+    sys_setuid(uid)
+}
+pub fn setgid(gid: u32) -> isize {
+    sys_setgid(gid)
+}
+/// Fill `groups` with the supplementary groups, an empty slice only counts them
+pub fn getgroups(groups: &mut [u32]) -> isize {
+    sys_getgroups(groups)
+}
+pub fn setgroups(groups: &[u32]) -> isize {
+    sys_setgroups(groups)
+}
 pub fn fork() -> isize {
+    // 避免缓冲中的输出被父子进程各打印一次
+    console::flush();
     sys_fork()
 }
+/// clone flag: the child gets a private copy of the mount table
+pub const CLONE_NEWNS: usize = 0x0002_0000;
+/// clone flag: the child is pid 1 of a new pid namespace
+pub const CLONE_NEWPID: usize = 0x2000_0000;
+/// Fork with `CLONE_*` namespace flags, the new namespaces need root
+pub fn clone(flags: usize) -> isize {
+    console::flush();
+    sys_clone(flags)
+}
+/// Fork into a new pid namespace, the child sees itself as pid 1 and reaps
+/// its own orphans. Needs root.
+pub fn fork_new_pid_ns() -> isize {
+    clone(CLONE_NEWPID)
+}
+/// Exec with the current environment
 pub fn exec(path: &str, args: &[*const u8]) -> isize {
-    sys_exec(path, args)
+    let vars = env::envp_strings();
+    let mut envp: Vec<*const u8> = vars.iter().map(|var| var.as_ptr()).collect();
+    envp.push(core::ptr::null());
+    execve(path, args, &envp)
+}
+pub fn execve(path: &str, args: &[*const u8], envp: &[*const u8]) -> isize {
+    console::flush();
+    sys_execve(path, args, envp)
+}
+/// Run `path` in a new child process without copying this one, returns the child's pid
+pub fn spawn(path: &str) -> isize {
+    console::flush();
+    sys_spawn(path)
+}
+/// Save the process to `path`: 0 after saving, 1 when resumed by [`restore`]
+pub fn checkpoint(path: &str) -> isize {
+    console::flush();
+    sys_checkpoint(path)
+}
+/// Replace the process with the one saved by [`checkpoint`], only returns on failure
+pub fn restore(path: &str) -> isize {
+    console::flush();
+    sys_restore(path)
 }
 pub fn wait(exit_code: &mut i32) -> isize {
     loop {
-        match sys_waitpid(-1, exit_code as *mut _) {
+        match sys_waitpid(-1, exit_code as *mut _, 0) {
             -2 => {
                 yield_();
             }
@@ -118,7 +227,7 @@ pub fn wait(exit_code: &mut i32) -> isize {
 
 pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     loop {
-        match sys_waitpid(pid as isize, exit_code as *mut _) {
+        match sys_waitpid(pid as isize, exit_code as *mut _, 0) {
             -2 => {
                 yield_();
             }
@@ -128,44 +237,69 @@ pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     }
 }
 
+pub const WNOHANG: u32 = 1;
+pub const WUNTRACED: u32 = 2;
+
+/// Return 0 if the child is still running
 pub fn waitpid_nb(pid: usize, exit_code: &mut i32) -> isize {
-    sys_waitpid(pid as isize, exit_code as *mut _)
+    sys_waitpid(pid as isize, exit_code as *mut _, WNOHANG)
+}
+pub fn waitpid_options(pid: isize, exit_code: &mut i32, options: u32) -> isize {
+    sys_waitpid(pid, exit_code as *mut _, options)
 }
 
 bitflags! {
+    /// Signal `n` is bit `n - 1`, as the kernel expects in kill
     pub struct SignalFlags: i32 {
-        const SIGINT    = 1 << 2;
-        const SIGILL    = 1 << 4;
-        const SIGABRT   = 1 << 6;
-        const SIGFPE    = 1 << 8;
-        const SIGSEGV   = 1 << 11;
+        const SIGINT    = 1 << 1;
+        const SIGILL    = 1 << 3;
+        const SIGABRT   = 1 << 5;
+        const SIGFPE    = 1 << 7;
+        const SIGKILL   = 1 << 8;
+        const SIGSEGV   = 1 << 10;
+        const SIGTERM   = 1 << 14;
+        const SIGCONT   = 1 << 17;
+        const SIGSTOP   = 1 << 18;
+        const SIGTSTP   = 1 << 19;
     }
 }
 
+pub const SYSLOG_ACTION_READ: usize = 2;
+pub const SYSLOG_ACTION_READ_ALL: usize = 3;
+pub const SYSLOG_ACTION_READ_CLEAR: usize = 4;
+pub const SYSLOG_ACTION_CLEAR: usize = 5;
+pub const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+pub const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+
+pub fn syslog(log_type: usize, buf: &mut [u8]) -> isize {
+    sys_syslog(log_type, buf.as_mut_ptr(), buf.len())
+}
+pub fn set_console_loglevel(level: usize) -> isize {
+    sys_syslog(SYSLOG_ACTION_CONSOLE_LEVEL, core::ptr::null_mut(), level)
+}
+
+/// prctl option of the chaos kernel, see `set_syscall_trace`
+pub const PR_SET_SYSCALL_TRACE: usize = 0x5343_0001;
+pub const PR_GET_SYSCALL_TRACE: usize = 0x5343_0002;
+
+/// Log every syscall of the calling thread to the kernel log (read it with
+/// dmesg), the setting survives exec but is not inherited by fork
+pub fn set_syscall_trace(enable: bool) -> isize {
+    sys_prctl(PR_SET_SYSCALL_TRACE, enable as usize)
+}
+
 pub fn kill(pid: usize, signal: i32) -> isize {
     sys_kill(pid, signal)
 }
 
+/// Block for `sleep_ms` milliseconds
 pub fn sleep(sleep_ms: usize) {
-    sys_sleep(sleep_ms);
+    time::nanosleep(&time::TimeSpec::from_millis(sleep_ms), None);
 }
 
-pub fn thread_create(entry: usize, arg: usize) -> isize {
-    sys_thread_create(entry, arg)
-}
 pub fn gettid() -> isize {
     sys_gettid()
 }
-pub fn waittid(tid: usize) -> isize {
-    loop {
-        match sys_waittid(tid) {
-            -2 => {
-                yield_();
-            }
-            exit_code => return exit_code,
-        }
-    }
-}
 
 pub fn mutex_create() -> isize {
     sys_mutex_create(false)
@@ -173,8 +307,8 @@ pub fn mutex_create() -> isize {
 pub fn mutex_blocking_create() -> isize {
     sys_mutex_create(true)
 }
-pub fn mutex_lock(mutex_id: usize) {
-    sys_mutex_lock(mutex_id);
+pub fn mutex_lock(mutex_id: usize) -> isize {
+    sys_mutex_lock(mutex_id)
 }
 pub fn mutex_unlock(mutex_id: usize) {
     sys_mutex_unlock(mutex_id);
@@ -185,8 +319,11 @@ pub fn semaphore_create(res_count: usize) -> isize {
 pub fn semaphore_up(sem_id: usize) {
     sys_semaphore_up(sem_id);
 }
-pub fn semaphore_down(sem_id: usize) {
-    sys_semaphore_down(sem_id);
+pub fn semaphore_down(sem_id: usize) -> isize {
+    sys_semaphore_down(sem_id)
+}
+pub fn enable_deadlock_detect(enabled: bool) -> isize {
+    sys_enable_deadlock_detect(enabled as usize)
 }
 pub fn condvar_create() -> isize {
     sys_condvar_create()
@@ -0,0 +1,141 @@
+//! Memory mappings, a `MappedRegion` owns its pages and unmaps them on drop
+
+use core::slice;
+
+use super::syscall::{sys_mmap, sys_munmap};
+
+bitflags! {
+    pub struct ProtFlags: usize {
+        const READ = 0x1;
+        const WRITE = 0x2;
+        const EXEC = 0x4;
+    }
+}
+
+bitflags! {
+    pub struct MapFlags: usize {
+        const SHARED = 0x01;
+        const PRIVATE = 0x02;
+        const FIXED = 0x10;
+        const ANONYMOUS = 0x20;
+    }
+}
+
+/// 匿名映射时传给内核的 fd
+const NO_FD: usize = usize::MAX;
+
+/// A region returned by mmap, unmapped when dropped
+///
+/// Pages of a `SHARED` mapping may be changed by other processes at any
+/// time, the slices only guard against aliasing inside this one.
+pub struct MappedRegion {
+    addr: usize,
+    len: usize,
+    prot: ProtFlags,
+}
+
+impl MappedRegion {
+    /// Map `len` bytes, `fd` is ignored for `ANONYMOUS` mappings
+    pub fn map(
+        len: usize,
+        prot: ProtFlags,
+        flags: MapFlags,
+        fd: Option<usize>,
+        offset: usize,
+    ) -> Result<Self, isize> {
+        let addr = sys_mmap(
+            0,
+            len,
+            prot.bits(),
+            flags.bits(),
+            fd.unwrap_or(NO_FD),
+            offset,
+        );
+        if addr < 0 {
+            return Err(addr);
+        }
+        Ok(Self {
+            addr: addr as usize,
+            len,
+            prot,
+        })
+    }
+
+    /// Zeroed private read/write memory
+    pub fn anonymous(len: usize) -> Result<Self, isize> {
+        Self::map(
+            len,
+            ProtFlags::READ | ProtFlags::WRITE,
+            MapFlags::PRIVATE | MapFlags::ANONYMOUS,
+            None,
+            0,
+        )
+    }
+
+    /// Zeroed read/write memory that stays shared with children after fork
+    pub fn shared_anonymous(len: usize) -> Result<Self, isize> {
+        Self::map(
+            len,
+            ProtFlags::READ | ProtFlags::WRITE,
+            MapFlags::SHARED | MapFlags::ANONYMOUS,
+            None,
+            0,
+        )
+    }
+
+    /// Map `len` bytes of the file `fd` starting at `offset`
+    pub fn file(
+        fd: usize,
+        len: usize,
+        offset: usize,
+        prot: ProtFlags,
+        flags: MapFlags,
+    ) -> Result<Self, isize> {
+        Self::map(len, prot, flags, Some(fd), offset)
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn prot(&self) -> ProtFlags {
+        self.prot
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        assert!(
+            self.prot.contains(ProtFlags::READ),
+            "MappedRegion: mapping is not readable"
+        );
+        unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        assert!(
+            self.prot.contains(ProtFlags::WRITE),
+            "MappedRegion: mapping is not writable"
+        );
+        unsafe { slice::from_raw_parts_mut(self.addr as *mut u8, self.len) }
+    }
+
+    /// Unmap now and report the kernel's answer, drop ignores it
+    pub fn unmap(self) -> isize {
+        let ret = sys_munmap(self.addr, self.len);
+        core::mem::forget(self);
+        ret
+    }
+}
+
+impl Drop for MappedRegion {
+    fn drop(&mut self) {
+        sys_munmap(self.addr, self.len);
+    }
+}
@@ -0,0 +1,357 @@
+//! IPv4 TCP/UDP sockets in the style of `std::net`, plus a minimal DNS
+//! resolver. Errors are the negative errno the kernel returned
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::{
+    close,
+    fs::read_to_string,
+    read,
+    syscall::{
+        sys_accept, sys_bind, sys_connect, sys_getsockname, sys_listen, sys_recvfrom,
+        sys_sendto, sys_shutdown, sys_socket,
+    },
+    write,
+};
+
+const AF_INET: usize = 2;
+const SOCK_STREAM: usize = 1;
+const SOCK_DGRAM: usize = 2;
+const SHUT_RDWR: usize = 2;
+const LISTEN_BACKLOG: usize = 16;
+
+/// QEMU user 网络里的 DNS 服务器，没有 /etc/resolv.conf 时使用
+const DEFAULT_NAMESERVER: Ipv4Addr = Ipv4Addr([10, 0, 2, 3]);
+const DNS_PORT: u16 = 53;
+
+pub type Result<T> = core::result::Result<T, isize>;
+
+fn check(ret: isize) -> Result<usize> {
+    if ret < 0 {
+        Err(ret)
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Self = Self([0, 0, 0, 0]);
+    pub const LOCALHOST: Self = Self([127, 0, 0, 1]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
+    }
+
+    /// Parse dotted decimal
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut octets = [0u8; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts.next()?.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self(octets))
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketAddrV4 {
+    pub ip: Ipv4Addr,
+    pub port: u16,
+}
+
+impl SocketAddrV4 {
+    pub const fn new(ip: Ipv4Addr, port: u16) -> Self {
+        Self { ip, port }
+    }
+
+    fn to_raw(self) -> SockAddrIn {
+        SockAddrIn {
+            family: AF_INET as u16,
+            port: self.port.to_be(),
+            addr: self.ip.0,
+            zero: [0; 8],
+        }
+    }
+
+    fn from_raw(raw: &SockAddrIn) -> Self {
+        Self {
+            ip: Ipv4Addr(raw.addr),
+            port: u16::from_be(raw.port),
+        }
+    }
+}
+
+impl fmt::Display for SocketAddrV4 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+/// struct sockaddr_in
+#[repr(C)]
+#[derive(Default)]
+struct SockAddrIn {
+    family: u16,
+    port: u16,
+    addr: [u8; 4],
+    zero: [u8; 8],
+}
+
+const SOCKADDR_IN_LEN: usize = core::mem::size_of::<SockAddrIn>();
+
+/// An owned socket fd, closed on drop
+struct Socket(usize);
+
+impl Socket {
+    fn new(ty: usize) -> Result<Self> {
+        check(sys_socket(AF_INET, ty, 0)).map(Socket)
+    }
+
+    fn bind(&self, addr: SocketAddrV4) -> Result<()> {
+        let raw = addr.to_raw();
+        check(sys_bind(self.0, &raw as *const _ as *const u8, SOCKADDR_IN_LEN)).map(|_| ())
+    }
+
+    fn connect(&self, addr: SocketAddrV4) -> Result<()> {
+        let raw = addr.to_raw();
+        check(sys_connect(self.0, &raw as *const _ as *const u8, SOCKADDR_IN_LEN)).map(|_| ())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddrV4> {
+        let mut raw = SockAddrIn::default();
+        let mut len = SOCKADDR_IN_LEN as u32;
+        check(sys_getsockname(self.0, &mut raw as *mut _ as *mut u8, &mut len))?;
+        Ok(SocketAddrV4::from_raw(&raw))
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        close(self.0);
+    }
+}
+
+pub struct TcpStream(Socket);
+
+impl TcpStream {
+    pub fn connect(addr: SocketAddrV4) -> Result<Self> {
+        let socket = Socket::new(SOCK_STREAM)?;
+        socket.connect(addr)?;
+        Ok(Self(socket))
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        check(read(self.0 .0, buf))
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize> {
+        check(write(self.0 .0, buf))
+    }
+
+    /// Write all of `buf`, retrying short writes
+    pub fn write_all(&self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf)? {
+                0 => return Err(-1),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddrV4> {
+        self.0.local_addr()
+    }
+
+    pub fn shutdown(&self) -> Result<()> {
+        check(sys_shutdown(self.0 .0, SHUT_RDWR)).map(|_| ())
+    }
+
+    pub fn fd(&self) -> usize {
+        self.0 .0
+    }
+}
+
+pub struct TcpListener(Socket);
+
+impl TcpListener {
+    pub fn bind(addr: SocketAddrV4) -> Result<Self> {
+        let socket = Socket::new(SOCK_STREAM)?;
+        socket.bind(addr)?;
+        check(sys_listen(socket.0, LISTEN_BACKLOG))?;
+        Ok(Self(socket))
+    }
+
+    pub fn accept(&self) -> Result<(TcpStream, SocketAddrV4)> {
+        let mut raw = SockAddrIn::default();
+        let mut len = SOCKADDR_IN_LEN as u32;
+        let fd = check(sys_accept(self.0 .0, &mut raw as *mut _ as *mut u8, &mut len))?;
+        Ok((TcpStream(Socket(fd)), SocketAddrV4::from_raw(&raw)))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddrV4> {
+        self.0.local_addr()
+    }
+}
+
+pub struct UdpSocket(Socket);
+
+impl UdpSocket {
+    pub fn bind(addr: SocketAddrV4) -> Result<Self> {
+        let socket = Socket::new(SOCK_DGRAM)?;
+        socket.bind(addr)?;
+        Ok(Self(socket))
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> Result<usize> {
+        let raw = addr.to_raw();
+        check(sys_sendto(
+            self.0 .0,
+            buf,
+            0,
+            &raw as *const _ as *const u8,
+            SOCKADDR_IN_LEN,
+        ))
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddrV4)> {
+        let mut raw = SockAddrIn::default();
+        let mut len = SOCKADDR_IN_LEN as u32;
+        let n = check(sys_recvfrom(
+            self.0 .0,
+            buf,
+            0,
+            &mut raw as *mut _ as *mut u8,
+            &mut len,
+        ))?;
+        Ok((n, SocketAddrV4::from_raw(&raw)))
+    }
+
+    /// Fix the peer so `send`/`recv` can be used
+    pub fn connect(&self, addr: SocketAddrV4) -> Result<()> {
+        self.0.connect(addr)
+    }
+
+    pub fn send(&self, buf: &[u8]) -> Result<usize> {
+        check(write(self.0 .0, buf))
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        check(read(self.0 .0, buf))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddrV4> {
+        self.0.local_addr()
+    }
+}
+
+/// The first `nameserver` in /etc/resolv.conf
+fn nameserver() -> Ipv4Addr {
+    read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|conf| {
+            conf.lines().find_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some("nameserver"), Some(addr)) => Ipv4Addr::parse(addr),
+                    _ => None,
+                }
+            })
+        })
+        .unwrap_or(DEFAULT_NAMESERVER)
+}
+
+/// A DNS query for the A record of `host`
+fn dns_query(id: u16, host: &str) -> Option<Vec<u8>> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&id.to_be_bytes());
+    // 标准查询，期望递归；1 个问题
+    packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    // QTYPE A, QCLASS IN
+    packet.extend_from_slice(&[0, 1, 0, 1]);
+    Some(packet)
+}
+
+/// Skip a possibly compressed name starting at `pos`
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += len + 1;
+    }
+}
+
+fn be16(msg: &[u8], pos: usize) -> Option<usize> {
+    Some(((*msg.get(pos)? as usize) << 8) | *msg.get(pos + 1)? as usize)
+}
+
+/// The first A record in a DNS answer with the matching `id`
+fn dns_parse(id: u16, msg: &[u8]) -> Option<Ipv4Addr> {
+    if be16(msg, 0)? != id as usize || msg.get(3)? & 0x0f != 0 {
+        return None;
+    }
+    let questions = be16(msg, 4)?;
+    let answers = be16(msg, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let (ty, class, len) = (be16(msg, pos)?, be16(msg, pos + 2)?, be16(msg, pos + 8)?);
+        pos += 10;
+        if ty == 1 && class == 1 && len == 4 {
+            let addr = msg.get(pos..pos + 4)?;
+            return Some(Ipv4Addr([addr[0], addr[1], addr[2], addr[3]]));
+        }
+        pos += len;
+    }
+    None
+}
+
+/// Resolve `host` to an IPv4 address, dotted decimal and "localhost" are
+/// answered without asking the nameserver
+pub fn resolve(host: &str) -> Option<Ipv4Addr> {
+    if let Some(addr) = Ipv4Addr::parse(host) {
+        return Some(addr);
+    }
+    if host == "localhost" {
+        return Some(Ipv4Addr::LOCALHOST);
+    }
+    let id = (super::get_time() as u16) | 1;
+    let query = dns_query(id, host)?;
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket
+        .send_to(&query, SocketAddrV4::new(nameserver(), DNS_PORT))
+        .ok()?;
+    let mut buf = [0u8; 512];
+    let (len, _) = socket.recv_from(&mut buf).ok()?;
+    dns_parse(id, &buf[..len])
+}
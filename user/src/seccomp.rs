@@ -0,0 +1,77 @@
+//! Syscall filters, installed with the kernel's `SECCOMP_SET_MODE_LIST`
+//!
+//! A filter applies to the calling task and its children and is locked by
+//! the next exec, so install it right before exec'ing the program to sandbox.
+
+use super::syscall::sys_seccomp;
+
+const SECCOMP_SET_MODE_STRICT: usize = 0;
+const SECCOMP_SET_MODE_LIST: usize = 0x5343_0001;
+
+const MODE_ALLOW_LIST: u32 = 0;
+const MODE_DENY_LIST: u32 = 1;
+const RET_ERRNO: u32 = 0;
+const RET_KILL: u32 = 1;
+
+/// Bitmap words, covers syscall numbers below 512
+const FILTER_WORDS: usize = 8;
+const EPERM: u32 = 1;
+
+/// 与内核 `SeccompList` 的内存布局一致
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Filter {
+    mode: u32,
+    action: u32,
+    errno: u32,
+    pad: u32,
+    bitmap: [u64; FILTER_WORDS],
+}
+
+impl Filter {
+    fn new(mode: u32, syscalls: &[usize]) -> Self {
+        let mut bitmap = [0u64; FILTER_WORDS];
+        for &id in syscalls {
+            bitmap[id / 64] |= 1 << (id % 64);
+        }
+        Self {
+            mode,
+            action: RET_ERRNO,
+            errno: EPERM,
+            pad: 0,
+            bitmap,
+        }
+    }
+
+    /// Only `syscalls` (plus exit and exit_group) may run
+    pub fn allow_only(syscalls: &[usize]) -> Self {
+        Self::new(MODE_ALLOW_LIST, syscalls)
+    }
+
+    /// Everything but `syscalls` may run
+    pub fn deny(syscalls: &[usize]) -> Self {
+        Self::new(MODE_DENY_LIST, syscalls)
+    }
+
+    /// Rejected syscalls fail with `errno` (positive), EPERM by default
+    pub fn errno(mut self, errno: u32) -> Self {
+        self.action = RET_ERRNO;
+        self.errno = errno;
+        self
+    }
+
+    /// Rejected syscalls kill the task with SIGSYS
+    pub fn kill(mut self) -> Self {
+        self.action = RET_KILL;
+        self
+    }
+
+    pub fn install(&self) -> isize {
+        sys_seccomp(SECCOMP_SET_MODE_LIST, 0, self as *const Filter as *const u8)
+    }
+}
+
+/// Allow only read, write and exit, anything else kills the task
+pub fn strict() -> isize {
+    sys_seccomp(SECCOMP_SET_MODE_STRICT, 0, core::ptr::null())
+}
@@ -0,0 +1,205 @@
+//! Signal handling over sigaction/sigprocmask/sigreturn
+//!
+//! Handlers are Rust closures kept in a lock free table, the kernel is always
+//! pointed at one dispatcher with our own restorer so nobody has to fill in
+//! `SignalAction` by hand.
+
+use alloc::boxed::Box;
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::syscall::{sys_sigaction, sys_sigprocmask};
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+
+/// 与内核的 MAX_SIG 一致
+pub const MAX_SIG: usize = 63;
+
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+const SIG_BLOCK: usize = 0;
+const SIG_UNBLOCK: usize = 1;
+const SIG_SETMASK: usize = 2;
+
+const SA_RESTORER: u32 = 0x04000000;
+
+/// A set of signals, signal `n` is bit `n - 1` as in the kernel's mask
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SigSet(u64);
+
+impl SigSet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+    pub const fn full() -> Self {
+        Self(!0)
+    }
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+    pub fn add(&mut self, signum: usize) -> &mut Self {
+        if (1..=MAX_SIG).contains(&signum) {
+            self.0 |= 1 << (signum - 1);
+        }
+        self
+    }
+    pub fn remove(&mut self, signum: usize) -> &mut Self {
+        if (1..=MAX_SIG).contains(&signum) {
+            self.0 &= !(1 << (signum - 1));
+        }
+        self
+    }
+    pub fn contains(&self, signum: usize) -> bool {
+        (1..=MAX_SIG).contains(&signum) && self.0 & (1 << (signum - 1)) != 0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// 与内核 `SignalAction` 的内存布局一致
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SignalAction {
+    sa_handler: usize,
+    sa_flags: u32,
+    sa_restorer: usize,
+    sa_mask: u64,
+}
+
+type HandlerFn = Box<dyn Fn(usize) + Send + Sync>;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// 每个信号对应一个 `*mut HandlerFn`，0 表示没有注册
+static HANDLERS: [AtomicUsize; MAX_SIG + 1] = [NO_HANDLER; MAX_SIG + 1];
+
+global_asm!(
+    ".section .text",
+    ".globl __signal_restorer",
+    "__signal_restorer:",
+    "li a7, 139",
+    "ecall",
+);
+
+extern "C" {
+    fn __signal_restorer();
+}
+
+extern "C" fn dispatch(signum: usize) {
+    let handler = HANDLERS[signum].load(Ordering::Acquire) as *const HandlerFn;
+    if !handler.is_null() {
+        unsafe { (*handler)(signum) };
+    }
+}
+
+fn set_action(signum: usize, handler: usize, mask: SigSet) -> isize {
+    let mut action = SignalAction {
+        sa_handler: handler,
+        ..Default::default()
+    };
+    if handler != SIG_DFL && handler != SIG_IGN {
+        action.sa_flags = SA_RESTORER;
+        action.sa_restorer = __signal_restorer as usize;
+        action.sa_mask = mask.bits();
+    }
+    sys_sigaction(
+        signum,
+        &action as *const SignalAction as *const u8,
+        core::ptr::null_mut(),
+    )
+}
+
+/// Run `handler` when `signum` arrives, signals in `mask` are blocked while it
+/// runs. A replaced closure is leaked since it may still be running
+pub fn signal_with_mask<F>(signum: usize, mask: SigSet, handler: F) -> isize
+where
+    F: Fn(usize) + Send + Sync + 'static,
+{
+    if !(1..=MAX_SIG).contains(&signum) || signum == SIGKILL || signum == SIGSTOP {
+        return -1;
+    }
+    let handler: HandlerFn = Box::new(handler);
+    let ptr = Box::into_raw(Box::new(handler)) as usize;
+    // 先放好闭包再告诉内核，信号随时可能到来
+    HANDLERS[signum].store(ptr, Ordering::Release);
+    set_action(signum, dispatch as usize, mask)
+}
+
+pub fn signal<F>(signum: usize, handler: F) -> isize
+where
+    F: Fn(usize) + Send + Sync + 'static,
+{
+    signal_with_mask(signum, SigSet::empty(), handler)
+}
+
+/// Restore the default action of `signum`
+pub fn default(signum: usize) -> isize {
+    let ret = set_action(signum, SIG_DFL, SigSet::empty());
+    if ret == 0 {
+        HANDLERS[signum].store(0, Ordering::Release);
+    }
+    ret
+}
+
+pub fn ignore(signum: usize) -> isize {
+    let ret = set_action(signum, SIG_IGN, SigSet::empty());
+    if ret == 0 {
+        HANDLERS[signum].store(0, Ordering::Release);
+    }
+    ret
+}
+
+fn procmask(how: usize, set: Option<&SigSet>) -> SigSet {
+    let mut old = 0u64;
+    sys_sigprocmask(
+        how,
+        set.map_or(core::ptr::null(), |set| &set.0 as *const u64),
+        &mut old as *mut u64,
+    );
+    SigSet(old)
+}
+
+/// Block the signals in `set`, return the previous mask
+pub fn block(set: &SigSet) -> SigSet {
+    procmask(SIG_BLOCK, Some(set))
+}
+
+pub fn unblock(set: &SigSet) -> SigSet {
+    procmask(SIG_UNBLOCK, Some(set))
+}
+
+pub fn set_mask(set: &SigSet) -> SigSet {
+    procmask(SIG_SETMASK, Some(set))
+}
+
+pub fn current_mask() -> SigSet {
+    procmask(SIG_BLOCK, None)
+}
@@ -0,0 +1,155 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use super::{syscall::sys_futex, yield_};
+
+/// A minimal lock for the runtime's own globals, yields while another thread
+/// holds it
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            yield_();
+        }
+        let ret = f(unsafe { &mut *self.data.get() });
+        self.locked.store(false, Ordering::Release);
+        ret
+    }
+}
+
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+const FUTEX_PRIVATE_FLAG: usize = 128;
+
+/// Sleep while `*futex == expected`
+pub(crate) fn futex_wait(futex: &AtomicU32, expected: u32) {
+    sys_futex(
+        futex as *const AtomicU32 as *const u32,
+        FUTEX_WAIT | FUTEX_PRIVATE_FLAG,
+        expected,
+    );
+}
+
+/// Wake at most `count` threads sleeping on `futex`
+pub(crate) fn futex_wake(futex: &AtomicU32, count: u32) {
+    sys_futex(
+        futex as *const AtomicU32 as *const u32,
+        FUTEX_WAKE | FUTEX_PRIVATE_FLAG,
+        count,
+    );
+}
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// 有线程在 futex 上睡眠，解锁时需要唤醒
+const CONTENDED: u32 = 2;
+
+/// A futex based mutex, uncontended lock/unlock never enter the kernel
+pub struct Mutex<T> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+    pub fn lock(&self) -> MutexGuard<T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                futex_wait(&self.state, CONTENDED);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex_wake(&self.state, 1);
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A condition variable, waiters sleep on a sequence number that every notify
+/// bumps so a notify between unlock and sleep is never lost
+pub struct Condvar {
+    seq: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+        }
+    }
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let seq = self.seq.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        futex_wait(&self.seq, seq);
+        mutex.lock()
+    }
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex_wake(&self.seq, 1);
+    }
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex_wake(&self.seq, i32::MAX as u32);
+    }
+}
@@ -1,32 +1,95 @@
 use core::arch::asm;
 
-const SYSCALL_DUP: usize = 24;
-const SYSCALL_OPEN: usize = 56;
+const SYSCALL_GETCWD: usize = 17;
+const SYSCALL_DUP: usize = 23;
+const SYSCALL_DUP3: usize = 24;
+const SYSCALL_MKDIRAT: usize = 34;
+const SYSCALL_UNLINKAT: usize = 35;
+const SYSCALL_SYMLINKAT: usize = 36;
+const SYSCALL_UMOUNT2: usize = 39;
+const SYSCALL_MOUNT: usize = 40;
+const SYSCALL_STATFS: usize = 43;
+const SYSCALL_CHDIR: usize = 49;
+const SYSCALL_OPENAT: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
+const SYSCALL_GETDENTS64: usize = 61;
+const SYSCALL_LSEEK: usize = 62;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_PREAD64: usize = 67;
+const SYSCALL_PWRITE64: usize = 68;
+const SYSCALL_READLINKAT: usize = 78;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
 const SYSCALL_EXIT: usize = 93;
-const SYSCALL_SLEEP: usize = 101;
+const SYSCALL_FUTEX: usize = 98;
+const SYSCALL_NANOSLEEP: usize = 101;
+const SYSCALL_GETITIMER: usize = 102;
+const SYSCALL_SETITIMER: usize = 103;
+const SYSCALL_CLOCK_NANOSLEEP: usize = 115;
+const SYSCALL_SYSLOG: usize = 116;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_KILL: usize = 129;
+const SYSCALL_SIGACTION: usize = 134;
+const SYSCALL_SIGPROCMASK: usize = 135;
+const SYSCALL_SETGID: usize = 144;
+const SYSCALL_SETUID: usize = 146;
+const SYSCALL_SETPGID: usize = 154;
+const SYSCALL_GETPGID: usize = 155;
+const SYSCALL_GETSID: usize = 156;
+const SYSCALL_SETSID: usize = 157;
+const SYSCALL_GETGROUPS: usize = 158;
+const SYSCALL_SETGROUPS: usize = 159;
+const SYSCALL_PRCTL: usize = 167;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
+const SYSCALL_GETUID: usize = 174;
+const SYSCALL_GETEUID: usize = 175;
+const SYSCALL_GETGID: usize = 176;
+const SYSCALL_GETEGID: usize = 177;
+const SYSCALL_MSGGET: usize = 186;
+const SYSCALL_MSGCTL: usize = 187;
+const SYSCALL_MSGRCV: usize = 188;
+const SYSCALL_MSGSND: usize = 189;
+const SYSCALL_SOCKET: usize = 198;
+const SYSCALL_BIND: usize = 200;
+const SYSCALL_LISTEN: usize = 201;
+const SYSCALL_ACCEPT: usize = 202;
+const SYSCALL_CONNECT: usize = 203;
+const SYSCALL_GETSOCKNAME: usize = 204;
+const SYSCALL_SENDTO: usize = 206;
+const SYSCALL_RECVFROM: usize = 207;
+const SYSCALL_SHUTDOWN: usize = 210;
+const SYSCALL_GETTID: usize = 178;
+const SYSCALL_BRK: usize = 214;
+const SYSCALL_MUNMAP: usize = 215;
 const SYSCALL_FORK: usize = 220;
-const SYSCALL_EXEC: usize = 221;
+const SYSCALL_EXECVE: usize = 221;
+const SYSCALL_MMAP: usize = 222;
+const SYSCALL_MPROTECT: usize = 226;
 const SYSCALL_WAITPID: usize = 260;
-const SYSCALL_THREAD_CREATE: usize = 1000;
-const SYSCALL_GETTID: usize = 1001;
-const SYSCALL_WAITTID: usize = 1002;
-const SYSCALL_MUTEX_CREATE: usize = 1010;
-const SYSCALL_MUTEX_LOCK: usize = 1011;
-const SYSCALL_MUTEX_UNLOCK: usize = 1012;
-const SYSCALL_SEMAPHORE_CREATE: usize = 1020;
-const SYSCALL_SEMAPHORE_UP: usize = 1021;
-const SYSCALL_SEMAPHORE_DOWN: usize = 1022;
-const SYSCALL_CONDVAR_CREATE: usize = 1030;
-const SYSCALL_CONDVAR_SIGNAL: usize = 1031;
-const SYSCALL_CONDVAR_WAIT: usize = 1032;
+const SYSCALL_RENAMEAT2: usize = 276;
+const SYSCALL_SECCOMP: usize = 277;
+const SYSCALL_MEMBARRIER: usize = 283;
+const SYSCALL_SPAWN: usize = 400;
+const SYSCALL_IO_URING_SETUP: usize = 425;
+const SYSCALL_IO_URING_ENTER: usize = 426;
+const SYSCALL_CHECKPOINT: usize = 420;
+const SYSCALL_RESTORE: usize = 421;
+const SYSCALL_MUTEX_CREATE: usize = 463;
+const SYSCALL_MUTEX_LOCK: usize = 464;
+const SYSCALL_MUTEX_UNLOCK: usize = 466;
+const SYSCALL_SEMAPHORE_CREATE: usize = 467;
+const SYSCALL_SEMAPHORE_UP: usize = 468;
+const SYSCALL_ENABLE_DEADLOCK_DETECT: usize = 469;
+const SYSCALL_SEMAPHORE_DOWN: usize = 470;
+const SYSCALL_CONDVAR_CREATE: usize = 471;
+const SYSCALL_CONDVAR_SIGNAL: usize = 472;
+const SYSCALL_CONDVAR_WAIT: usize = 473;
+
+const AT_FDCWD: isize = -100;
 
 fn syscall(id: usize, args: [usize; 3]) -> isize {
     let mut ret: isize;
@@ -42,19 +105,151 @@ fn syscall(id: usize, args: [usize; 3]) -> isize {
     ret
 }
 
+fn syscall6(id: usize, args: [usize; 6]) -> isize {
+    let mut ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") args[0] => ret,
+            in("x11") args[1],
+            in("x12") args[2],
+            in("x13") args[3],
+            in("x14") args[4],
+            in("x15") args[5],
+            in("x17") id
+        );
+    }
+    ret
+}
+
 pub fn sys_dup(fd: usize) -> isize {
     syscall(SYSCALL_DUP, [fd, 0, 0])
 }
 
+pub fn sys_dup3(old_fd: usize, new_fd: usize, flags: u32) -> isize {
+    syscall(SYSCALL_DUP3, [old_fd, new_fd, flags as usize])
+}
+
 pub fn sys_open(path: &str, flags: u32) -> isize {
-    syscall(SYSCALL_OPEN, [path.as_ptr() as usize, flags as usize, 0])
+    syscall(
+        SYSCALL_OPENAT,
+        [AT_FDCWD as usize, path.as_ptr() as usize, flags as usize],
+    )
+}
+
+pub fn sys_getcwd(buf: &mut [u8]) -> isize {
+    syscall(SYSCALL_GETCWD, [buf.as_mut_ptr() as usize, buf.len(), 0])
+}
+
+pub fn sys_chdir(path: &str) -> isize {
+    syscall(SYSCALL_CHDIR, [path.as_ptr() as usize, 0, 0])
+}
+
+pub fn sys_mkdirat(path: &str, mode: u32) -> isize {
+    syscall(
+        SYSCALL_MKDIRAT,
+        [AT_FDCWD as usize, path.as_ptr() as usize, mode as usize],
+    )
+}
+
+pub fn sys_unlinkat(path: &str, flags: u32) -> isize {
+    syscall(
+        SYSCALL_UNLINKAT,
+        [AT_FDCWD as usize, path.as_ptr() as usize, flags as usize],
+    )
+}
+
+pub fn sys_symlinkat(target: &str, path: &str) -> isize {
+    syscall(
+        SYSCALL_SYMLINKAT,
+        [
+            target.as_ptr() as usize,
+            AT_FDCWD as usize,
+            path.as_ptr() as usize,
+        ],
+    )
+}
+
+pub fn sys_readlinkat(path: &str, buf: &mut [u8]) -> isize {
+    syscall6(
+        SYSCALL_READLINKAT,
+        [
+            AT_FDCWD as usize,
+            path.as_ptr() as usize,
+            buf.as_mut_ptr() as usize,
+            buf.len(),
+            0,
+            0,
+        ],
+    )
+}
+
+pub fn sys_renameat2(old_path: &str, new_path: &str, flags: u32) -> isize {
+    syscall6(
+        SYSCALL_RENAMEAT2,
+        [
+            AT_FDCWD as usize,
+            old_path.as_ptr() as usize,
+            AT_FDCWD as usize,
+            new_path.as_ptr() as usize,
+            flags as usize,
+            0,
+        ],
+    )
+}
+
+pub fn sys_fstat(fd: usize, stat: *mut u8) -> isize {
+    syscall(SYSCALL_FSTAT, [fd, stat as usize, 0])
+}
+
+pub fn sys_sync() -> isize {
+    syscall(SYSCALL_SYNC, [0, 0, 0])
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    syscall(SYSCALL_FSYNC, [fd, 0, 0])
+}
+
+pub fn sys_statfs(path: &str, buf: *mut u8) -> isize {
+    syscall(SYSCALL_STATFS, [path.as_ptr() as usize, buf as usize, 0])
+}
+
+pub fn sys_getdents64(fd: usize, buf: &mut [u8]) -> isize {
+    syscall(
+        SYSCALL_GETDENTS64,
+        [fd, buf.as_mut_ptr() as usize, buf.len()],
+    )
+}
+
+pub fn sys_mount(
+    source: &str,
+    target: &str,
+    fstype: &str,
+    flags: u32,
+    data: *const u8,
+) -> isize {
+    syscall6(
+        SYSCALL_MOUNT,
+        [
+            source.as_ptr() as usize,
+            target.as_ptr() as usize,
+            fstype.as_ptr() as usize,
+            flags as usize,
+            data as usize,
+            0,
+        ],
+    )
+}
+
+pub fn sys_umount2(target: &str, flags: u32) -> isize {
+    syscall(SYSCALL_UMOUNT2, [target.as_ptr() as usize, flags as usize, 0])
 }
 
 pub fn sys_close(fd: usize) -> isize {
     syscall(SYSCALL_CLOSE, [fd, 0, 0])
 }
 
-pub fn sys_pipe(pipe: &mut [usize]) -> isize {
+pub fn sys_pipe(pipe: &mut [u32; 2]) -> isize {
     syscall(SYSCALL_PIPE, [pipe.as_mut_ptr() as usize, 0, 0])
 }
 
@@ -69,13 +264,86 @@ pub fn sys_write(fd: usize, buffer: &[u8]) -> isize {
     syscall(SYSCALL_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
 }
 
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    syscall(SYSCALL_LSEEK, [fd, offset as usize, whence])
+}
+
+pub fn sys_pread64(fd: usize, buffer: &mut [u8], offset: usize) -> isize {
+    syscall6(
+        SYSCALL_PREAD64,
+        [fd, buffer.as_mut_ptr() as usize, buffer.len(), offset, 0, 0],
+    )
+}
+
+pub fn sys_pwrite64(fd: usize, buffer: &[u8], offset: usize) -> isize {
+    syscall6(
+        SYSCALL_PWRITE64,
+        [fd, buffer.as_ptr() as usize, buffer.len(), offset, 0, 0],
+    )
+}
+
 pub fn sys_exit(exit_code: i32) -> ! {
     syscall(SYSCALL_EXIT, [exit_code as usize, 0, 0]);
     panic!("sys_exit never returns!");
 }
 
-pub fn sys_sleep(sleep_ms: usize) -> isize {
-    syscall(SYSCALL_SLEEP, [sleep_ms, 0, 0])
+pub fn sys_nanosleep(req: *const u8, rem: *mut u8) -> isize {
+    syscall(SYSCALL_NANOSLEEP, [req as usize, rem as usize, 0])
+}
+
+pub fn sys_getitimer(which: usize, curr_value: *mut u8) -> isize {
+    syscall(SYSCALL_GETITIMER, [which, curr_value as usize, 0])
+}
+
+pub fn sys_setitimer(which: usize, new_value: *const u8, old_value: *mut u8) -> isize {
+    syscall(SYSCALL_SETITIMER, [which, new_value as usize, old_value as usize])
+}
+
+pub fn sys_clock_nanosleep(clock: usize, flags: usize, req: *const u8, rem: *mut u8) -> isize {
+    syscall6(
+        SYSCALL_CLOCK_NANOSLEEP,
+        [clock, flags, req as usize, rem as usize, 0, 0],
+    )
+}
+
+pub fn sys_syslog(log_type: usize, buf: *mut u8, len: usize) -> isize {
+    syscall(SYSCALL_SYSLOG, [log_type, buf as usize, len])
+}
+
+pub fn sys_seccomp(operation: usize, flags: usize, args: *const u8) -> isize {
+    syscall(SYSCALL_SECCOMP, [operation, flags, args as usize])
+}
+
+pub fn sys_membarrier(cmd: usize, flags: usize) -> isize {
+    syscall(SYSCALL_MEMBARRIER, [cmd, flags, 0])
+}
+
+/// `params` points to a `struct io_uring_params`
+pub fn sys_io_uring_setup(entries: u32, params: *mut u8) -> isize {
+    syscall(SYSCALL_IO_URING_SETUP, [entries as usize, params as usize, 0])
+}
+
+pub fn sys_io_uring_enter(fd: usize, to_submit: u32, min_complete: u32, flags: u32) -> isize {
+    syscall6(
+        SYSCALL_IO_URING_ENTER,
+        [fd, to_submit as usize, min_complete as usize, flags as usize, 0, 0],
+    )
+}
+
+pub fn sys_spawn(path: &str) -> isize {
+    syscall(SYSCALL_SPAWN, [path.as_ptr() as usize, 0, 0])
+}
+
+pub fn sys_checkpoint(path: &str) -> isize {
+    syscall(SYSCALL_CHECKPOINT, [path.as_ptr() as usize, 0, 0])
+}
+
+pub fn sys_restore(path: &str) -> isize {
+    syscall(SYSCALL_RESTORE, [path.as_ptr() as usize, 0, 0])
+}
+
+pub fn sys_prctl(option: usize, arg2: usize) -> isize {
+    syscall(SYSCALL_PRCTL, [option, arg2, 0])
 }
 
 pub fn sys_yield() -> isize {
@@ -86,6 +354,20 @@ pub fn sys_kill(pid: usize, signal: i32) -> isize {
     syscall(SYSCALL_KILL, [pid, signal as usize, 0])
 }
 
+pub fn sys_sigaction(signum: usize, action: *const u8, old_action: *mut u8) -> isize {
+    syscall(
+        SYSCALL_SIGACTION,
+        [signum, action as usize, old_action as usize],
+    )
+}
+
+pub fn sys_sigprocmask(how: usize, set: *const u64, old_set: *mut u64) -> isize {
+    syscall(
+        SYSCALL_SIGPROCMASK,
+        [how, set as usize, old_set as usize],
+    )
+}
+
 pub fn sys_get_time() -> isize {
     syscall(SYSCALL_GET_TIME, [0, 0, 0])
 }
@@ -94,31 +376,197 @@ pub fn sys_getpid() -> isize {
     syscall(SYSCALL_GETPID, [0, 0, 0])
 }
 
+pub fn sys_setpgid(pid: usize, pgid: usize) -> isize {
+    syscall(SYSCALL_SETPGID, [pid, pgid, 0])
+}
+
+pub fn sys_getpgid(pid: usize) -> isize {
+    syscall(SYSCALL_GETPGID, [pid, 0, 0])
+}
+
+pub fn sys_setsid() -> isize {
+    syscall(SYSCALL_SETSID, [0, 0, 0])
+}
+
+pub fn sys_getsid(pid: usize) -> isize {
+    syscall(SYSCALL_GETSID, [pid, 0, 0])
+}
+
+pub fn sys_getuid() -> isize {
+    syscall(SYSCALL_GETUID, [0, 0, 0])
+}
+
+pub fn sys_geteuid() -> isize {
+    syscall(SYSCALL_GETEUID, [0, 0, 0])
+}
+
+pub fn sys_getgid() -> isize {
+    syscall(SYSCALL_GETGID, [0, 0, 0])
+}
+
+pub fn sys_getegid() -> isize {
+    syscall(SYSCALL_GETEGID, [0, 0, 0])
+}
+
+pub fn sys_setuid(uid: u32) -> isize {
+    syscall(SYSCALL_SETUID, [uid as usize, 0, 0])
+}
+
+pub fn sys_setgid(gid: u32) -> isize {
+    syscall(SYSCALL_SETGID, [gid as usize, 0, 0])
+}
+
+pub fn sys_getgroups(groups: &mut [u32]) -> isize {
+    syscall(SYSCALL_GETGROUPS, [groups.len(), groups.as_mut_ptr() as usize, 0])
+}
+
+pub fn sys_setgroups(groups: &[u32]) -> isize {
+    syscall(SYSCALL_SETGROUPS, [groups.len(), groups.as_ptr() as usize, 0])
+}
+
 pub fn sys_fork() -> isize {
     syscall(SYSCALL_FORK, [0, 0, 0])
 }
 
-pub fn sys_exec(path: &str, args: &[*const u8]) -> isize {
+pub fn sys_clone(flags: usize) -> isize {
+    syscall(SYSCALL_FORK, [flags, 0, 0])
+}
+
+pub fn sys_execve(path: &str, args: &[*const u8], envp: &[*const u8]) -> isize {
     syscall(
-        SYSCALL_EXEC,
-        [path.as_ptr() as usize, args.as_ptr() as usize, 0],
+        SYSCALL_EXECVE,
+        [
+            path.as_ptr() as usize,
+            args.as_ptr() as usize,
+            envp.as_ptr() as usize,
+        ],
     )
 }
 
-pub fn sys_waitpid(pid: isize, exit_code: *mut i32) -> isize {
-    syscall(SYSCALL_WAITPID, [pid as usize, exit_code as usize, 0])
+pub fn sys_waitpid(pid: isize, exit_code: *mut i32, options: u32) -> isize {
+    syscall(
+        SYSCALL_WAITPID,
+        [pid as usize, exit_code as usize, options as usize],
+    )
+}
+
+pub fn sys_gettid() -> isize {
+    syscall(SYSCALL_GETTID, [0, 0, 0])
 }
 
-pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
-    syscall(SYSCALL_THREAD_CREATE, [entry, arg, 0])
+pub fn sys_msgget(key: i32, flags: usize) -> isize {
+    syscall(SYSCALL_MSGGET, [key as usize, flags, 0])
 }
 
-pub fn sys_gettid() -> isize {
-    syscall(SYSCALL_GETTID, [0; 3])
+pub fn sys_msgctl(msqid: usize, cmd: usize, buf: *mut u8) -> isize {
+    syscall(SYSCALL_MSGCTL, [msqid, cmd, buf as usize])
+}
+
+/// `msgp` points to the message type followed by `msgsz` bytes of text
+pub fn sys_msgrcv(msqid: usize, msgp: *mut u8, msgsz: usize, msgtyp: isize, flags: usize) -> isize {
+    syscall6(
+        SYSCALL_MSGRCV,
+        [msqid, msgp as usize, msgsz, msgtyp as usize, flags, 0],
+    )
+}
+
+pub fn sys_msgsnd(msqid: usize, msgp: *const u8, msgsz: usize, flags: usize) -> isize {
+    syscall6(SYSCALL_MSGSND, [msqid, msgp as usize, msgsz, flags, 0, 0])
+}
+
+pub fn sys_futex(uaddr: *const u32, op: usize, val: u32) -> isize {
+    syscall6(SYSCALL_FUTEX, [uaddr as usize, op, val as usize, 0, 0, 0])
+}
+
+pub fn sys_mmap(
+    start: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> isize {
+    syscall6(SYSCALL_MMAP, [start, len, prot, flags, fd, offset])
+}
+
+pub fn sys_socket(domain: usize, ty: usize, protocol: usize) -> isize {
+    syscall(SYSCALL_SOCKET, [domain, ty, protocol])
+}
+
+pub fn sys_bind(fd: usize, addr: *const u8, addr_len: usize) -> isize {
+    syscall(SYSCALL_BIND, [fd, addr as usize, addr_len])
+}
+
+pub fn sys_listen(fd: usize, backlog: usize) -> isize {
+    syscall(SYSCALL_LISTEN, [fd, backlog, 0])
 }
 
-pub fn sys_waittid(tid: usize) -> isize {
-    syscall(SYSCALL_WAITTID, [tid, 0, 0])
+pub fn sys_accept(fd: usize, addr: *mut u8, addr_len: *mut u32) -> isize {
+    syscall(SYSCALL_ACCEPT, [fd, addr as usize, addr_len as usize])
+}
+
+pub fn sys_connect(fd: usize, addr: *const u8, addr_len: usize) -> isize {
+    syscall(SYSCALL_CONNECT, [fd, addr as usize, addr_len])
+}
+
+pub fn sys_getsockname(fd: usize, addr: *mut u8, addr_len: *mut u32) -> isize {
+    syscall(SYSCALL_GETSOCKNAME, [fd, addr as usize, addr_len as usize])
+}
+
+pub fn sys_sendto(
+    fd: usize,
+    buf: &[u8],
+    flags: usize,
+    addr: *const u8,
+    addr_len: usize,
+) -> isize {
+    syscall6(
+        SYSCALL_SENDTO,
+        [
+            fd,
+            buf.as_ptr() as usize,
+            buf.len(),
+            flags,
+            addr as usize,
+            addr_len,
+        ],
+    )
+}
+
+pub fn sys_recvfrom(
+    fd: usize,
+    buf: &mut [u8],
+    flags: usize,
+    addr: *mut u8,
+    addr_len: *mut u32,
+) -> isize {
+    syscall6(
+        SYSCALL_RECVFROM,
+        [
+            fd,
+            buf.as_mut_ptr() as usize,
+            buf.len(),
+            flags,
+            addr as usize,
+            addr_len as usize,
+        ],
+    )
+}
+
+pub fn sys_shutdown(fd: usize, how: usize) -> isize {
+    syscall(SYSCALL_SHUTDOWN, [fd, how, 0])
+}
+
+pub fn sys_brk(addr: usize) -> isize {
+    syscall(SYSCALL_BRK, [addr, 0, 0])
+}
+
+pub fn sys_munmap(start: usize, len: usize) -> isize {
+    syscall(SYSCALL_MUNMAP, [start, len, 0])
+}
+
+pub fn sys_mprotect(start: usize, len: usize, prot: usize) -> isize {
+    syscall(SYSCALL_MPROTECT, [start, len, prot])
 }
 
 pub fn sys_mutex_create(blocking: bool) -> isize {
@@ -145,6 +593,10 @@ pub fn sys_semaphore_down(sem_id: usize) -> isize {
     syscall(SYSCALL_SEMAPHORE_DOWN, [sem_id, 0, 0])
 }
 
+pub fn sys_enable_deadlock_detect(enabled: usize) -> isize {
+    syscall(SYSCALL_ENABLE_DEADLOCK_DETECT, [enabled, 0, 0])
+}
+
 pub fn sys_condvar_create() -> isize {
     syscall(SYSCALL_CONDVAR_CREATE, [0, 0, 0])
 }
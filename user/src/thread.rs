@@ -0,0 +1,169 @@
+//! Threads on top of clone(CLONE_VM | CLONE_THREAD), each with its own mmap'ed
+//! stack, joined through a futex
+
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    arch::global_asm,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use super::{
+    mman::{MapFlags, ProtFlags},
+    sync::futex_wait,
+    syscall::{sys_gettid, sys_mmap, sys_munmap},
+};
+
+const THREAD_STACK_SIZE: usize = 0x4000;
+
+const CLONE_VM: usize = 0x100;
+const CLONE_FS: usize = 0x200;
+const CLONE_FILES: usize = 0x400;
+const CLONE_SIGHAND: usize = 0x800;
+const CLONE_THREAD: usize = 0x10000;
+const CLONE_SYSVSEM: usize = 0x40000;
+
+// __thread_clone(flags, stack_top, entry, arg)
+// 子线程从新栈上取出 entry 和 arg 并调用，entry 返回完成标志的地址，
+// 置位并唤醒 join 的线程后直接退出，这之后不再碰栈，所以 join 可以马上回收栈
+global_asm!(
+    ".section .text",
+    ".globl __thread_clone",
+    "__thread_clone:",
+    "addi a1, a1, -16",
+    "sd a2, 0(a1)",
+    "sd a3, 8(a1)",
+    "li a2, 0",
+    "li a3, 0",
+    "li a4, 0",
+    "li a7, 220",
+    "ecall",
+    "beqz a0, 1f",
+    "ret",
+    "1:",
+    "ld t0, 0(sp)",
+    "ld a0, 8(sp)",
+    "jalr t0",
+    "fence rw, w",
+    "li t0, 1",
+    "sw t0, 0(a0)",
+    "li a1, 129",
+    "li a2, 1",
+    "li a7, 98",
+    "ecall",
+    "li a0, 0",
+    "li a7, 93",
+    "ecall",
+);
+
+extern "C" {
+    fn __thread_clone(
+        flags: usize,
+        stack_top: usize,
+        entry: extern "C" fn(usize) -> usize,
+        arg: usize,
+    ) -> isize;
+}
+
+/// State shared between a thread and its JoinHandle
+struct Packet<T> {
+    done: AtomicU32,
+    result: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+pub struct JoinHandle<T> {
+    tid: usize,
+    stack: usize,
+    packet: Option<Arc<Packet<T>>>,
+}
+
+extern "C" fn thread_entry<F, T>(arg: usize) -> usize
+where
+    F: FnOnce() -> T,
+{
+    let (f, packet) = *unsafe { Box::from_raw(arg as *mut (F, Arc<Packet<T>>)) };
+    let ret = f();
+    unsafe {
+        *packet.result.get() = Some(ret);
+    }
+    // JoinHandle 持有另一个引用，标志位在线程退出前一直有效
+    &packet.done as *const AtomicU32 as usize
+}
+
+/// Spawn a thread running `f`, panics if the stack or the thread can not be
+/// created
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let stack = sys_mmap(
+        0,
+        THREAD_STACK_SIZE,
+        (ProtFlags::READ | ProtFlags::WRITE).bits(),
+        (MapFlags::PRIVATE | MapFlags::ANONYMOUS).bits(),
+        usize::MAX,
+        0,
+    );
+    if stack < 0 {
+        panic!("thread::spawn: mmap stack failed: {}", stack);
+    }
+    let stack = stack as usize;
+    let packet = Arc::new(Packet {
+        done: AtomicU32::new(0),
+        result: UnsafeCell::new(None),
+    });
+    let arg = Box::into_raw(Box::new((f, packet.clone()))) as usize;
+    let flags = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM;
+    let tid = unsafe {
+        __thread_clone(
+            flags,
+            stack + THREAD_STACK_SIZE,
+            thread_entry::<F, T>,
+            arg,
+        )
+    };
+    if tid < 0 {
+        drop(unsafe { Box::from_raw(arg as *mut (F, Arc<Packet<T>>)) });
+        sys_munmap(stack, THREAD_STACK_SIZE);
+        panic!("thread::spawn: clone failed: {}", tid);
+    }
+    JoinHandle {
+        tid: tid as usize,
+        stack,
+        packet: Some(packet),
+    }
+}
+
+impl<T> JoinHandle<T> {
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+    /// Wait for the thread to finish and return what it returned
+    pub fn join(mut self) -> T {
+        let packet = self.packet.take().unwrap();
+        while packet.done.load(Ordering::Acquire) == 0 {
+            futex_wait(&packet.done, 0);
+        }
+        sys_munmap(self.stack, THREAD_STACK_SIZE);
+        let ret = unsafe { (*packet.result.get()).take() };
+        ret.unwrap()
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    /// 没有 join 的线程被分离，它的栈和完成标志要一直留到线程退出，
+    /// 但没人知道那是什么时候，只能泄漏掉
+    fn drop(&mut self) {
+        if let Some(packet) = self.packet.take() {
+            core::mem::forget(packet);
+        }
+    }
+}
+
+/// Thread id of the caller
+pub fn current_tid() -> usize {
+    sys_gettid() as usize
+}
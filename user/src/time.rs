@@ -0,0 +1,98 @@
+//! Sleeping on the kernel's clocks
+
+use super::syscall::{sys_clock_nanosleep, sys_getitimer, sys_nanosleep, sys_setitimer};
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+/// `req` is an absolute time on the clock instead of a duration
+pub const TIMER_ABSTIME: usize = 1;
+/// Interval timer counting real time, delivers SIGALRM
+pub const ITIMER_REAL: usize = 0;
+
+const NSEC_PER_SEC: usize = 1_000_000_000;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
+impl TimeSpec {
+    pub const fn new(sec: usize, nsec: usize) -> Self {
+        Self { sec, nsec }
+    }
+    pub const fn from_millis(ms: usize) -> Self {
+        Self {
+            sec: ms / 1000,
+            nsec: ms % 1000 * 1_000_000,
+        }
+    }
+    pub const fn from_nanos(ns: usize) -> Self {
+        Self {
+            sec: ns / NSEC_PER_SEC,
+            nsec: ns % NSEC_PER_SEC,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TimeVal {
+    pub sec: usize,
+    pub usec: usize,
+}
+
+impl TimeVal {
+    pub const fn from_millis(ms: usize) -> Self {
+        Self {
+            sec: ms / 1000,
+            usec: ms % 1000 * 1000,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ITimerVal {
+    /// period of the timer, zero for a one-shot timer
+    pub interval: TimeVal,
+    /// time until the next expiry, zero when disarmed
+    pub value: TimeVal,
+}
+
+pub fn getitimer(which: usize, curr_value: &mut ITimerVal) -> isize {
+    sys_getitimer(which, curr_value as *mut ITimerVal as *mut u8)
+}
+
+pub fn setitimer(which: usize, new_value: &ITimerVal, old_value: Option<&mut ITimerVal>) -> isize {
+    sys_setitimer(
+        which,
+        new_value as *const ITimerVal as *const u8,
+        old_value.map_or(core::ptr::null_mut(), |old| {
+            old as *mut ITimerVal as *mut u8
+        }),
+    )
+}
+
+/// Block for `req`, the unslept time is stored in `rem` when interrupted
+pub fn nanosleep(req: &TimeSpec, rem: Option<&mut TimeSpec>) -> isize {
+    sys_nanosleep(
+        req as *const TimeSpec as *const u8,
+        rem.map_or(core::ptr::null_mut(), |rem| rem as *mut TimeSpec as *mut u8),
+    )
+}
+
+pub fn clock_nanosleep(
+    clock: usize,
+    flags: usize,
+    req: &TimeSpec,
+    rem: Option<&mut TimeSpec>,
+) -> isize {
+    sys_clock_nanosleep(
+        clock,
+        flags,
+        req as *const TimeSpec as *const u8,
+        rem.map_or(core::ptr::null_mut(), |rem| rem as *mut TimeSpec as *mut u8),
+    )
+}